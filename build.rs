@@ -0,0 +1,75 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc::generate();
+}
+
+/// Generates the service of `schemas/engine.proto` and its client from its description here,
+/// so building doesn't need `protoc`. The messages are written by hand in `src/grpc.rs`.
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    const CODEC: &str = "tonic_prost::ProstCodec";
+
+    fn method(name: &str, route: &str, input: &str, output: &str) -> Method {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{input}"))
+            .output_type(format!("crate::grpc::{output}"))
+            .codec_path(CODEC)
+            .build()
+    }
+
+    pub fn generate() {
+        println!("cargo::rerun-if-changed=build.rs");
+
+        let stream_clients = Method::builder()
+            .name("stream_clients")
+            .route_name("StreamClients")
+            .input_type("crate::grpc::StreamClientsRequest")
+            .output_type("crate::grpc::ClientState")
+            .codec_path(CODEC)
+            .server_streaming()
+            .build();
+
+        let service = Service::builder()
+            .name("Engine")
+            .package("octopussy")
+            .method(method("deposit", "Deposit", "AmountRequest", "ClientState"))
+            .method(method(
+                "withdraw",
+                "Withdraw",
+                "AmountRequest",
+                "ClientState",
+            ))
+            .method(method(
+                "dispute",
+                "Dispute",
+                "TransactionRequest",
+                "ClientState",
+            ))
+            .method(method(
+                "resolve",
+                "Resolve",
+                "TransactionRequest",
+                "ClientState",
+            ))
+            .method(method(
+                "chargeback",
+                "Chargeback",
+                "TransactionRequest",
+                "ClientState",
+            ))
+            .method(method(
+                "get_client",
+                "GetClient",
+                "ClientRequest",
+                "ClientState",
+            ))
+            .method(stream_clients)
+            .build();
+
+        Builder::new().compile(&[service]);
+    }
+}
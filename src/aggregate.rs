@@ -0,0 +1,165 @@
+//! Aggregated, k-anonymous statistics of the client balances, for sharing the results of a
+//! run with partners who must not see individual accounts.
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{amount, export::OutputWriter, transaction::ClientInformation};
+
+/// How clients are grouped, and how large a group has to be to be published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregationPolicy {
+    /// Groups of fewer clients are left out (the `k` of k-anonymity)
+    pub min_clients: u64,
+    /// Bounds of the total balance ranges clients are grouped by, ascending. Bounds `[0,
+    /// 100]` make the groups `< 0`, `0..100` and `>= 100`.
+    pub bounds: Vec<Decimal>,
+}
+
+impl AggregationPolicy {
+    pub fn new(min_clients: u64, mut bounds: Vec<Decimal>) -> Self {
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        Self {
+            min_clients,
+            bounds,
+        }
+    }
+}
+
+/// Clients whose total balance is in `from..to`. Unbounded ends are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BalanceBucket {
+    #[serde(serialize_with = "amount::option::serialize")]
+    pub from: Option<Decimal>,
+    #[serde(serialize_with = "amount::option::serialize")]
+    pub to: Option<Decimal>,
+    pub clients: u64,
+    pub locked: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+}
+
+/// Groups `clients` by total balance as set by `policy`, and sums their balances per group.
+///
+/// Groups of fewer than `policy.min_clients` clients are suppressed rather than merged, and
+/// no overall totals are given, since those would let them be computed back.
+pub fn aggregate<I>(clients: I, policy: &AggregationPolicy) -> Vec<BalanceBucket>
+where
+    I: IntoIterator<Item = ClientInformation>,
+{
+    let bounds = &policy.bounds;
+    let mut buckets: Vec<_> = (0..=bounds.len())
+        .map(|index| BalanceBucket {
+            from: index.checked_sub(1).map(|index| bounds[index]),
+            to: bounds.get(index).copied(),
+            clients: 0,
+            locked: 0,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+        })
+        .collect();
+
+    for client in clients {
+        let bucket = &mut buckets[bounds.partition_point(|&bound| bound <= client.total)];
+        bucket.clients += 1;
+        bucket.locked += u64::from(client.frozen);
+        bucket.available += client.available;
+        bucket.held += client.held;
+        bucket.total += client.total;
+    }
+
+    buckets.retain(|bucket| bucket.clients > 0 && bucket.clients >= policy.min_clients);
+    buckets
+}
+
+/// Writes the buckets to `output`, in place of the per-client rows.
+pub fn write_buckets<O: OutputWriter>(
+    mut output: O,
+    buckets: &[BalanceBucket],
+) -> anyhow::Result<()> {
+    for bucket in buckets {
+        output.write_row(bucket)?;
+    }
+    output.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+
+    fn client(id: u16, total: Decimal, frozen: bool) -> ClientInformation {
+        ClientInformation {
+            id,
+            available: total,
+            held: Decimal::ZERO,
+            total,
+            frozen,
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        let clients = [
+            client(1, dec!(-5), false),
+            client(2, dec!(0), false),
+            client(3, dec!(50), true),
+            client(4, dec!(99.99), false),
+            client(5, dec!(100), false),
+            client(6, dec!(250), false),
+            client(7, dec!(5000), false),
+        ];
+        let policy = AggregationPolicy::new(1, vec![dec!(1000), dec!(0), dec!(100)]);
+
+        let buckets = aggregate(clients, &policy);
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|bucket| (bucket.from, bucket.to, bucket.clients, bucket.total))
+                .collect::<Vec<_>>(),
+            vec![
+                (None, Some(dec!(0)), 1, dec!(-5)),
+                (Some(dec!(0)), Some(dec!(100)), 3, dec!(149.99)),
+                (Some(dec!(100)), Some(dec!(1000)), 2, dec!(350)),
+                (Some(dec!(1000)), None, 1, dec!(5000)),
+            ]
+        );
+        assert_eq!(buckets[1].locked, 1);
+    }
+
+    #[test]
+    fn suppresses_small_groups() {
+        let clients = (1..=5).map(|id| client(id, dec!(10), false)).chain([client(
+            6,
+            dec!(1_000_000),
+            false,
+        )]);
+        let policy = AggregationPolicy::new(3, vec![dec!(0), dec!(100)]);
+
+        let buckets = aggregate(clients, &policy);
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].clients, 5);
+        assert_eq!(buckets[0].total, dec!(50));
+    }
+
+    #[test]
+    fn csv_rows() {
+        let policy = AggregationPolicy::new(1, vec![dec!(0)]);
+        let buckets = aggregate([client(1, dec!(2.5), false)], &policy);
+
+        let mut output = Vec::new();
+        write_buckets(csv::Writer::from_writer(&mut output), &buckets).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "from,to,clients,locked,available,held,total\n0.0000,,1,0,2.5000,0.0000,2.5000\n"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+
+/// Number of decimal places amounts are written with
+pub const DECIMAL_PLACES: u32 = 4;
+
+/// Rounds `amount` to [`DECIMAL_PLACES`] (half to even), without the sign of a negative
+/// zero.
+pub fn round(amount: Decimal) -> Decimal {
+    let mut rounded = amount.round_dp(DECIMAL_PLACES);
+    if rounded.is_zero() {
+        rounded.set_sign_positive(true);
+    }
+
+    rounded
+}
+
+/// The canonical text of an amount in outputs: rounded like [`round`], with exactly
+/// [`DECIMAL_PLACES`] decimal places, and never in scientific notation or as `-0`.
+///
+/// Downstream parsers have choked on `-0` and `1E+2` style values, and on amounts of the
+/// same column having a different number of decimals.
+pub fn format(amount: Decimal) -> String {
+    let mut text = round(amount).to_string();
+    let decimals = match text.find('.') {
+        Some(point) => text.len() - point - 1,
+        None => {
+            text.push('.');
+            0
+        }
+    };
+    for _ in decimals..DECIMAL_PLACES as usize {
+        text.push('0');
+    }
+
+    text
+}
+
+/// Name of the newtype struct [`serialize`] wraps the text of amounts in. Most formats
+/// write the text as it is, the ones which care (eg [`crate::sql::SqlWriter`]) can tell
+/// amounts from other strings with it.
+pub const NEWTYPE: &str = "octopussy::Amount";
+
+/// Serializes a [`Decimal`] as [`format`] does, eg with
+/// `#[serde(serialize_with = "amount::serialize")]` on the amounts of output rows.
+pub fn serialize<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_struct(NEWTYPE, &format(*amount))
+}
+
+/// An amount serialized with [`serialize`], for amounts which aren't a field of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Amount(pub Decimal);
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(&self.0, serializer)
+    }
+}
+
+/// Same as [`serialize`], for optional amounts. `None` stays empty.
+pub mod option {
+    use rust_decimal::Decimal;
+    use serde::Serializer;
+
+    use super::Amount;
+
+    pub fn serialize<S: Serializer>(
+        amount: &Option<Decimal>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => serializer.serialize_some(&Amount(*amount)),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use rust_decimal::dec;
+
+    use super::*;
+
+    #[test]
+    fn canonical_text() {
+        let cases = [
+            (dec!(0), "0.0000"),
+            (dec!(1), "1.0000"),
+            (dec!(1.5), "1.5000"),
+            (dec!(-2.25), "-2.2500"),
+            (dec!(1200.0), "1200.0000"),
+            (dec!(0.00005), "0.0000"),
+            (dec!(0.00015), "0.0002"),
+            (dec!(1.23456789), "1.2346"),
+            (dec!(-0.00004), "0.0000"),
+            (Decimal::from_scientific("1E+2").unwrap(), "100.0000"),
+            (Decimal::from_scientific("1.5e-7").unwrap(), "0.0000"),
+            (Decimal::from_str("-0").unwrap(), "0.0000"),
+            (Decimal::from_str("-0.000").unwrap(), "0.0000"),
+            (Decimal::new(-0, 10), "0.0000"),
+            (Decimal::MAX, "79228162514264337593543950335.0000"),
+            (Decimal::MIN, "-79228162514264337593543950335.0000"),
+            (
+                Decimal::from_str("7922816251426433759354395.0335").unwrap(),
+                "7922816251426433759354395.0335",
+            ),
+        ];
+
+        for (amount, expected) in cases {
+            assert_eq!(format(amount), expected, "{amount:?}");
+        }
+    }
+
+    #[test]
+    fn round_clears_negative_zero() {
+        let zero = round(dec!(-0.00001));
+        assert!(zero.is_zero());
+        assert!(zero.is_sign_positive());
+        assert!(!zero.to_string().starts_with('-'));
+    }
+}
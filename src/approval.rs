@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{Clock, Timestamp},
+    encryption::{self, EncryptionKey},
+    memory_processor::InMemoryTransactionDb,
+    transaction::{ClientId, TransactionError, TransactionId},
+};
+
+pub type ApprovalId = u64;
+
+/// Identity of the operator requesting or approving an admin operation
+pub type Operator = String;
+
+/// A sensitive operation on an account, outside of the regular event flow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "operation")]
+pub enum AdminOperation {
+    /// Lift the freeze of an account
+    Unlock { client: ClientId },
+    /// Correct an account's available balance, recorded as transaction `tx`
+    Adjustment {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
+}
+
+/// A DB admin operations can be applied to.
+pub trait AdminProcessor {
+    fn unlock(&mut self, client_id: ClientId) -> Result<(), TransactionError>;
+
+    fn adjust(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError>;
+
+    fn close_account(&mut self, client_id: ClientId) -> Result<(), TransactionError>;
+
+    fn apply_admin_operation(
+        &mut self,
+        operation: &AdminOperation,
+    ) -> Result<(), TransactionError> {
+        match *operation {
+            AdminOperation::Unlock { client } => self.unlock(client),
+            AdminOperation::Adjustment { client, tx, amount } => self.adjust(tx, client, amount),
+        }
+    }
+}
+
+impl<C: Clock> AdminProcessor for InMemoryTransactionDb<C> {
+    fn unlock(&mut self, client_id: ClientId) -> Result<(), TransactionError> {
+        InMemoryTransactionDb::unlock(self, client_id)
+    }
+
+    fn adjust(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        InMemoryTransactionDb::adjust(self, transaction_id, client_id, amount)
+    }
+
+    fn close_account(&mut self, client_id: ClientId) -> Result<(), TransactionError> {
+        InMemoryTransactionDb::close_account(self, client_id)
+    }
+}
+
+/// Which admin operations need a second operator to approve them before they're applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApprovalPolicy {
+    /// Adjustments of more than this amount (either way) need an approval. Without a
+    /// threshold, all of them do.
+    pub adjustment_threshold: Option<Decimal>,
+}
+
+impl ApprovalPolicy {
+    /// Unlocks always need an approval.
+    pub fn requires_approval(&self, operation: &AdminOperation) -> bool {
+        match operation {
+            AdminOperation::Unlock { .. } => true,
+            AdminOperation::Adjustment { amount, .. } => self
+                .adjustment_threshold
+                .is_none_or(|threshold| amount.abs() > threshold),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingApproval {
+    pub id: ApprovalId,
+    #[serde(flatten)]
+    pub operation: AdminOperation,
+    pub requested_by: Operator,
+    pub requested_at: Timestamp,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ApprovalError {
+    #[error("no pending approval {id}")]
+    NotFound { id: ApprovalId },
+
+    #[error("operator {operator} requested the operation, another operator must approve it")]
+    SameOperator { operator: Operator },
+
+    #[error(transparent)]
+    Transaction(#[from] TransactionError),
+}
+
+/// Outcome of [`ApprovalQueue::submit`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Submitted {
+    /// The operation didn't need an approval and was applied
+    Applied,
+    /// The operation waits for an approval
+    Pending(ApprovalId),
+}
+
+/// Admin operations waiting for a second operator (four-eyes principle).
+///
+/// An operation is only applied once approved by an operator other than the one who
+/// requested it. The queue is meant to be saved next to the state, so pending approvals
+/// survive restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalQueue {
+    next_id: ApprovalId,
+    pending: BTreeMap<ApprovalId, PendingApproval>,
+}
+
+impl ApprovalQueue {
+    /// Loads the queue at `path`, or an empty one if there isn't one yet.
+    pub fn load(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = encryption::read_file(path, key)?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode approvals {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        encryption::write_file(path, &serde_json::to_vec(self)?, key)
+    }
+
+    /// Applies `operation` right away if `policy` allows it, or queues it for approval.
+    ///
+    /// ## Errors
+    /// - If the operation is applied and the DB rejects it, returns
+    ///   [`ApprovalError::Transaction`]
+    pub fn submit<DB: AdminProcessor>(
+        &mut self,
+        policy: &ApprovalPolicy,
+        operation: AdminOperation,
+        operator: Operator,
+        clock: &impl Clock,
+        db: &mut DB,
+    ) -> Result<Submitted, ApprovalError> {
+        if !policy.requires_approval(&operation) {
+            db.apply_admin_operation(&operation)?;
+            return Ok(Submitted::Applied);
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        self.pending.insert(
+            id,
+            PendingApproval {
+                id,
+                operation,
+                requested_by: operator,
+                requested_at: clock.now(),
+            },
+        );
+
+        Ok(Submitted::Pending(id))
+    }
+
+    /// Approves pending approval `id` and applies its operation.
+    ///
+    /// ## Errors
+    /// - If there's no such pending approval, returns [`ApprovalError::NotFound`]
+    /// - If `operator` requested the operation, returns [`ApprovalError::SameOperator`]
+    /// - If the DB rejects the operation, returns [`ApprovalError::Transaction`]. It's
+    ///   dropped from the queue all the same, as approving it again would fail the same way.
+    pub fn approve<DB: AdminProcessor>(
+        &mut self,
+        id: ApprovalId,
+        operator: &str,
+        db: &mut DB,
+    ) -> Result<PendingApproval, ApprovalError> {
+        let approval = self.get(id).ok_or(ApprovalError::NotFound { id })?;
+        if approval.requested_by == operator {
+            return Err(ApprovalError::SameOperator {
+                operator: operator.to_owned(),
+            });
+        }
+
+        let approval = self
+            .pending
+            .remove(&id)
+            .ok_or(ApprovalError::NotFound { id })?;
+        db.apply_admin_operation(&approval.operation)?;
+
+        Ok(approval)
+    }
+
+    /// Drops pending approval `id` without applying it. Any operator can reject, including
+    /// the one who requested it.
+    pub fn reject(&mut self, id: ApprovalId) -> Result<PendingApproval, ApprovalError> {
+        self.pending
+            .remove(&id)
+            .ok_or(ApprovalError::NotFound { id })
+    }
+
+    pub fn get(&self, id: ApprovalId) -> Option<&PendingApproval> {
+        self.pending.get(&id)
+    }
+
+    /// Pending approvals, oldest first
+    pub fn pending(&self) -> impl Iterator<Item = &PendingApproval> {
+        self.pending.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{clock::FixedClock, transaction::TransactionProcessor};
+
+    fn frozen_db() -> InMemoryTransactionDb {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db
+    }
+
+    #[test]
+    fn four_eyes() {
+        let mut db = frozen_db();
+        let mut queue = ApprovalQueue::default();
+        let policy = ApprovalPolicy::default();
+
+        let submitted = queue
+            .submit(
+                &policy,
+                AdminOperation::Unlock { client: 1 },
+                "alice".to_owned(),
+                &FixedClock(100),
+                &mut db,
+            )
+            .unwrap();
+        assert_eq!(submitted, Submitted::Pending(1));
+        assert!(db.client(1).unwrap().frozen);
+
+        assert_eq!(
+            queue.approve(1, "alice", &mut db),
+            Err(ApprovalError::SameOperator {
+                operator: "alice".to_owned()
+            })
+        );
+        assert_eq!(queue.pending().count(), 1);
+
+        let approval = queue.approve(1, "bob", &mut db).unwrap();
+        assert_eq!(approval.requested_by, "alice");
+        assert_eq!(approval.requested_at, 100);
+        assert!(!db.client(1).unwrap().frozen);
+        assert_eq!(queue.get(1), None);
+        assert_eq!(
+            queue.approve(1, "bob", &mut db),
+            Err(ApprovalError::NotFound { id: 1 })
+        );
+    }
+
+    #[test]
+    fn adjustment_threshold() {
+        let mut db = frozen_db();
+        let mut queue = ApprovalQueue::default();
+        let policy = ApprovalPolicy {
+            adjustment_threshold: Some(dec!(100)),
+        };
+        let mut submit = |queue: &mut ApprovalQueue, tx, amount| {
+            queue.submit(
+                &policy,
+                AdminOperation::Adjustment {
+                    client: 1,
+                    tx,
+                    amount,
+                },
+                "alice".to_owned(),
+                &FixedClock(0),
+                &mut db,
+            )
+        };
+
+        assert_eq!(submit(&mut queue, 2, dec!(100)), Ok(Submitted::Applied));
+        assert_eq!(submit(&mut queue, 3, dec!(-150)), Ok(Submitted::Pending(1)));
+        assert_eq!(
+            submit(&mut queue, 2, dec!(5)),
+            Err(ApprovalError::Transaction(
+                TransactionError::DuplicateTransaction {
+                    transaction_id: 2,
+                    client_id: 1
+                }
+            ))
+        );
+
+        assert_eq!(queue.reject(1).unwrap().id, 1);
+        assert_eq!(queue.pending().count(), 0);
+        assert_eq!(db.client(1).unwrap().available, dec!(100));
+    }
+
+    #[test]
+    fn persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("approvals.json");
+        assert_eq!(
+            ApprovalQueue::load(&path, None).unwrap(),
+            ApprovalQueue::default()
+        );
+
+        let mut queue = ApprovalQueue::default();
+        queue
+            .submit(
+                &ApprovalPolicy::default(),
+                AdminOperation::Unlock { client: 1 },
+                "alice".to_owned(),
+                &FixedClock(0),
+                &mut frozen_db(),
+            )
+            .unwrap();
+        queue.save(&path, None).unwrap();
+
+        assert_eq!(ApprovalQueue::load(&path, None).unwrap(), queue);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Timestamp,
+    encryption,
+    snapshot::ClientSnapshot,
+    transaction::{ClientId, TransactionId},
+};
+
+/// A transaction moved out of memory by [`crate::memory_processor::InMemoryTransactionDb::archive`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedTransaction {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    pub charged_back: bool,
+    pub recorded_at: u64,
+    pub created_at: Timestamp,
+    pub seq: u64,
+    #[serde(default)]
+    pub disputes: u32,
+}
+
+/// First line of an archive file
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveHeader {
+    watermark: TransactionId,
+    transactions: u64,
+}
+
+/// A gzipped file of JSON lines holding transactions with ids below a watermark.
+///
+/// Archived transactions are looked up by scanning the file, which is slow but fine for
+/// what it's for: old transactions are rarely disputed. Their ids are kept in memory
+/// though, so duplicates of archived transactions are still caught without reading it.
+#[derive(Debug, Clone)]
+pub struct TransactionArchive {
+    path: PathBuf,
+    watermark: TransactionId,
+    transactions: u64,
+    ids: HashSet<(ClientId, TransactionId)>,
+}
+
+impl TransactionArchive {
+    /// Writes `transactions` to a new archive at `path`. They must all have an id below
+    /// `watermark`.
+    pub fn write<I>(path: &Path, watermark: TransactionId, transactions: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = ArchivedTransaction>,
+    {
+        let mut lines = Vec::new();
+        let mut count = 0;
+        let mut ids = HashSet::new();
+        for transaction in transactions {
+            debug_assert!(transaction.tx < watermark);
+            serde_json::to_writer(&mut lines, &transaction)?;
+            lines.push(b'\n');
+            count += 1;
+            ids.insert((transaction.client, transaction.tx));
+        }
+
+        let header = ArchiveHeader {
+            watermark,
+            transactions: count,
+        };
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, &header)?;
+        encoder.write_all(b"\n")?;
+        encoder.write_all(&lines)?;
+
+        encryption::write_file(path, &encoder.finish()?, None)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            watermark,
+            transactions: count,
+            ids,
+        })
+    }
+
+    /// Opens an archive written earlier, eg to attach it again after a restart. The whole
+    /// file is read once to index the ids of its transactions.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut lines = Self::reader(path)?.lines();
+        let line = lines.next().transpose()?.unwrap_or_default();
+        let header: ArchiveHeader = serde_json::from_str(&line)
+            .with_context(|| format!("{} is not a transaction archive", path.display()))?;
+
+        let mut ids = HashSet::new();
+        for line in lines {
+            let transaction: ArchivedTransaction = serde_json::from_str(&line?)
+                .with_context(|| format!("corrupt archive {}", path.display()))?;
+            ids.insert((transaction.client, transaction.tx));
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            watermark: header.watermark,
+            transactions: header.transactions,
+            ids,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All archived transactions have an id below the watermark
+    pub fn watermark(&self) -> TransactionId {
+        self.watermark
+    }
+
+    pub fn len(&self) -> u64 {
+        self.transactions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions == 0
+    }
+
+    /// Whether the transaction was archived, without reading the file
+    pub fn contains(&self, client_id: ClientId, transaction_id: TransactionId) -> bool {
+        self.ids.contains(&(client_id, transaction_id))
+    }
+
+    /// Looks up a transaction, without reading the file if it's past the watermark.
+    pub fn find(
+        &self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> anyhow::Result<Option<ArchivedTransaction>> {
+        if !self.contains(client_id, transaction_id) {
+            return Ok(None);
+        }
+
+        // Skips the header
+        for line in Self::reader(&self.path)?.lines().skip(1) {
+            let transaction: ArchivedTransaction = serde_json::from_str(&line?)
+                .with_context(|| format!("corrupt archive {}", self.path.display()))?;
+            if transaction.client == client_id && transaction.tx == transaction_id {
+                return Ok(Some(transaction));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn reader(path: &Path) -> anyhow::Result<impl BufRead> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(BufReader::new(GzDecoder::new(BufReader::new(file))))
+    }
+}
+
+/// A client account moved out of memory by
+/// [`crate::memory_processor::InMemoryTransactionDb::close_account`], with its history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClosedAccount {
+    /// The balances the account was closed with
+    pub client: ClientSnapshot,
+    pub closed_at: Timestamp,
+    pub transactions: Vec<ArchivedTransaction>,
+}
+
+/// A directory of closed accounts, one gzipped JSON file per client.
+///
+/// One file per client keeps lookups cheap without an index, and nothing about closed
+/// accounts has to be kept in memory.
+#[derive(Debug, Clone)]
+pub struct AccountArchive {
+    dir: PathBuf,
+}
+
+impl AccountArchive {
+    /// Opens the archive in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn write(&self, account: &ClosedAccount) -> anyhow::Result<()> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, account)?;
+
+        encryption::write_file(&self.path(account.client.id), &encoder.finish()?, None)
+    }
+
+    /// Whether `client_id`'s account was closed
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.path(client_id).exists()
+    }
+
+    pub fn get(&self, client_id: ClientId) -> anyhow::Result<Option<ClosedAccount>> {
+        let path = self.path(client_id);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to open {}", path.display()));
+            }
+        };
+
+        let account = serde_json::from_reader(GzDecoder::new(BufReader::new(file)))
+            .with_context(|| format!("corrupt closed account {}", path.display()))?;
+
+        Ok(Some(account))
+    }
+
+    fn path(&self, client_id: ClientId) -> PathBuf {
+        self.dir.join(format!("{client_id}.json.gz"))
+    }
+}
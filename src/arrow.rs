@@ -0,0 +1,294 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    str::FromStr,
+    time::Instant,
+};
+
+use anyhow::{Context, bail};
+use arrow_array::{
+    Array, ArrayRef, RecordBatch,
+    cast::AsArray,
+    types::{UInt16Type, UInt32Type, UInt64Type},
+};
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_schema::{ArrowError, DataType};
+use rust_decimal::Decimal;
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// Magic bytes the Arrow IPC file format (Feather v2) starts with. Streams don't have any.
+const FILE_MAGIC: &[u8; 6] = b"ARROW1";
+
+/// Same as [`crate::csv::csv_processor`], but takes the events as Arrow record batches, eg
+/// straight from a DataFusion or Polars pipeline.
+///
+/// Columns are looked up by the CSV header names (`type`, `client`, `tx`, and the optional
+/// `amount` and `tenant`). Any integer type works for ids, and amounts can be decimals,
+/// floats or strings.
+pub fn record_batch_processor<I, O, DB>(
+    batches: I,
+    output: O,
+    db: &mut DB,
+) -> anyhow::Result<RunMetrics>
+where
+    I: IntoIterator<Item = Result<RecordBatch, ArrowError>>,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    record_batch_processor_draining(batches, output, db, &DrainSignal::new())
+}
+
+/// Same as [`record_batch_processor`], but stops at the first row after `drain` was
+/// requested, in the middle of a batch if need be, and writes the clients as they are then.
+pub fn record_batch_processor_draining<I, O, DB>(
+    batches: I,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    I: IntoIterator<Item = Result<RecordBatch, ArrowError>>,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+
+    'batches: for batch in batches {
+        for transaction_row in transaction_rows(&batch?)? {
+            if drained(drain, &mut metrics) {
+                break 'batches;
+            }
+            apply_row(db, transaction_row, Instant::now(), &mut metrics)?;
+        }
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Same as [`record_batch_processor`], but reads the batches from an Arrow IPC file
+/// (Feather v2) or stream, told apart by the file's magic bytes.
+pub fn arrow_processor<O, DB>(file: File, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    arrow_processor_draining(file, output, db, &DrainSignal::new())
+}
+
+/// Same as [`arrow_processor`], stopping like [`record_batch_processor_draining`]
+pub fn arrow_processor_draining<O, DB>(
+    mut file: File,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut magic = [0; FILE_MAGIC.len()];
+    let is_file = file.read_exact(&mut magic).is_ok() && &magic == FILE_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+
+    if is_file {
+        record_batch_processor_draining(FileReader::try_new(file, None)?, output, db, drain)
+    } else {
+        record_batch_processor_draining(StreamReader::try_new(file, None)?, output, db, drain)
+    }
+}
+
+/// Maps the columns of `batch` to one [`TransactionRow`] per row.
+pub(crate) fn transaction_rows(batch: &RecordBatch) -> anyhow::Result<Vec<TransactionRow>> {
+    let types = column(batch, "type", &DataType::Utf8)?;
+    let clients = column(batch, "client", &DataType::UInt16)?;
+    let txs = column(batch, "tx", &DataType::UInt32)?;
+    let amounts = optional_column(batch, "amount", &DataType::Utf8)?;
+    let tenants = optional_column(batch, "tenant", &DataType::UInt16)?;
+    let timestamps = optional_column(batch, "timestamp", &DataType::UInt64)?;
+
+    let types = types.as_string::<i32>();
+    let clients = clients.as_primitive::<UInt16Type>();
+    let txs = txs.as_primitive::<UInt32Type>();
+    let amounts = amounts.as_ref().map(|amounts| amounts.as_string::<i32>());
+    let tenants = tenants
+        .as_ref()
+        .map(|tenants| tenants.as_primitive::<UInt16Type>());
+    let timestamps = timestamps
+        .as_ref()
+        .map(|timestamps| timestamps.as_primitive::<UInt64Type>());
+
+    (0..batch.num_rows())
+        .map(|row| {
+            if types.is_null(row) || clients.is_null(row) || txs.is_null(row) {
+                bail!("row {row} is missing its type, client or tx");
+            }
+
+            let amount = match amounts {
+                Some(amounts) if amounts.is_valid(row) => Some(parse_amount(amounts.value(row))?),
+                _ => None,
+            };
+
+            Ok(TransactionRow {
+                transaction_type: types.value(row).to_owned(),
+                client: clients.value(row),
+                tx: txs.value(row),
+                amount,
+                tenant: tenants
+                    .filter(|tenants| tenants.is_valid(row))
+                    .map(|tenants| tenants.value(row)),
+                timestamp: timestamps
+                    .filter(|timestamps| timestamps.is_valid(row))
+                    .map(|timestamps| timestamps.value(row)),
+                sequence: None,
+                metadata: Default::default(),
+            })
+        })
+        .collect()
+}
+
+fn column(batch: &RecordBatch, name: &str, data_type: &DataType) -> anyhow::Result<ArrayRef> {
+    optional_column(batch, name, data_type)?.with_context(|| format!("missing column {name}"))
+}
+
+/// Looks up a column and casts it to `data_type`, failing if a value doesn't fit.
+pub(crate) fn optional_column(
+    batch: &RecordBatch,
+    name: &str,
+    data_type: &DataType,
+) -> anyhow::Result<Option<ArrayRef>> {
+    let Some(column) = batch.column_by_name(name) else {
+        return Ok(None);
+    };
+
+    let options = arrow_cast::CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let column = arrow_cast::cast_with_options(column, data_type, &options)
+        .with_context(|| format!("invalid column {name}"))?;
+
+    Ok(Some(column))
+}
+
+pub(crate) fn parse_amount(amount: &str) -> anyhow::Result<Decimal> {
+    Decimal::from_str(amount)
+        .or_else(|_| Decimal::from_scientific(amount))
+        .with_context(|| format!("invalid amount {amount}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow_array::{StringArray, UInt8Array, UInt64Array};
+    use arrow_ipc::writer::{FileWriter, StreamWriter};
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    fn events() -> RecordBatch {
+        RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec!["deposit", "deposit", "dispute"])) as ArrayRef,
+            ),
+            ("client", Arc::new(UInt8Array::from(vec![1, 2, 2]))),
+            ("tx", Arc::new(UInt64Array::from(vec![1, 2, 2]))),
+            (
+                "amount",
+                Arc::new(StringArray::from(vec![Some("1.5"), Some("2"), None])),
+            ),
+        ])
+        .unwrap()
+    }
+
+    fn process(file: File) -> (RunMetrics, Vec<ClientRow>) {
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        let metrics =
+            arrow_processor(file, csv::Writer::from_writer(&mut output), &mut db).unwrap();
+
+        let mut rows: Vec<ClientRow> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows.sort_by_key(|row| row.client);
+        (metrics, rows)
+    }
+
+    fn expected() -> Vec<ClientRow> {
+        vec![
+            ClientRow {
+                client: 1,
+                available: dec!(1.5),
+                held: dec!(0),
+                total: dec!(1.5),
+                locked: false,
+            },
+            ClientRow {
+                client: 2,
+                available: dec!(0),
+                held: dec!(2),
+                total: dec!(2),
+                locked: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn ipc_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.arrow");
+        let events = events();
+        let mut writer =
+            FileWriter::try_new(File::create(&path).unwrap(), &events.schema()).unwrap();
+        writer.write(&events).unwrap();
+        writer.finish().unwrap();
+
+        let (metrics, rows) = process(File::open(&path).unwrap());
+        assert_eq!(metrics.latencies.count(), 3);
+        assert_eq!(rows, expected());
+    }
+
+    #[test]
+    fn ipc_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.arrows");
+        let events = events();
+        let mut writer =
+            StreamWriter::try_new(File::create(&path).unwrap(), &events.schema()).unwrap();
+        writer.write(&events).unwrap();
+        writer.write(&events.slice(0, 1)).unwrap();
+        writer.finish().unwrap();
+
+        // The second batch repeats the first deposit, which is rejected
+        let (metrics, rows) = process(File::open(&path).unwrap());
+        assert_eq!(metrics.latencies.count(), 4);
+        assert_eq!(metrics.activity.counters((None, 1)).rejections, 1);
+        assert_eq!(rows, expected());
+    }
+
+    #[test]
+    fn err_missing_column() {
+        let batch = RecordBatch::try_from_iter([(
+            "type",
+            Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+        )])
+        .unwrap();
+
+        assert_eq!(
+            transaction_rows(&batch).unwrap_err().to_string(),
+            "missing column client"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{io::BufRead, sync::LazyLock, time::Instant};
+
+use anyhow::{Context, bail};
+use apache_avro::{Reader, Schema, reader::datum::GenericDatumReader, types::Value};
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// Schema of the events, with the same fields as the CSV columns. Amounts are decimal
+/// strings so they don't lose precision.
+pub const TRANSACTION_SCHEMA: &str = include_str!("../schemas/transaction.avsc");
+
+/// Magic bytes an Avro object container file starts with
+const CONTAINER_MAGIC: &[u8; 4] = b"Obj\x01";
+
+/// Magic byte of a message in the Confluent wire format, followed by a 4 byte schema id
+const CONFLUENT_MAGIC: u8 = 0;
+
+static SCHEMA: LazyLock<Schema> =
+    LazyLock::new(|| Schema::parse_str(TRANSACTION_SCHEMA).expect("bundled schema is valid"));
+
+/// Same as [`crate::csv::csv_processor`], but reads Avro encoded events, either:
+///
+/// - an object container file, which embeds the schema it was written with. It's resolved
+///   against [`TRANSACTION_SCHEMA`], so it can eg lack the optional fields or use `int`s for
+///   `tx`.
+/// - concatenated messages in the Confluent wire format (a zero byte, the schema registry id
+///   and the datum), like a dump of a Kafka topic. There's no registry to look ids up in, so
+///   the datums must be written with [`TRANSACTION_SCHEMA`] itself.
+///
+/// The two are told apart by the first bytes of the input.
+pub fn avro_processor<R, O, DB>(reader: R, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    avro_processor_draining(reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`avro_processor`], but stops at the first event after `drain` was requested and
+/// writes the clients as they are then.
+pub fn avro_processor_draining<R, O, DB>(
+    mut reader: R,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+
+    if reader.fill_buf()?.starts_with(CONTAINER_MAGIC) {
+        let events = Reader::builder(reader).reader_schema(&SCHEMA).build()?;
+        for (number, value) in events.enumerate() {
+            if drained(drain, &mut metrics) {
+                break;
+            }
+            let started = Instant::now();
+            let transaction_row =
+                decode(value?).with_context(|| format!("invalid event {}", number + 1))?;
+            apply_row(db, transaction_row, started, &mut metrics)?;
+        }
+    } else {
+        let datums = GenericDatumReader::builder(&SCHEMA).build()?;
+        let mut number = 0;
+        while !reader.fill_buf()?.is_empty() && !drained(drain, &mut metrics) {
+            number += 1;
+            let started = Instant::now();
+
+            let mut header = [0; 5];
+            reader
+                .read_exact(&mut header)
+                .with_context(|| format!("truncated message {number}"))?;
+            if header[0] != CONFLUENT_MAGIC {
+                bail!("message {number} isn't in the Confluent wire format");
+            }
+
+            let transaction_row = datums
+                .read_value(&mut reader)
+                .map_err(anyhow::Error::from)
+                .and_then(decode)
+                .with_context(|| format!("invalid message {number}"))?;
+            apply_row(db, transaction_row, started, &mut metrics)?;
+        }
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Decodes a single message in the Confluent wire format, eg the payload of a Kafka record
+pub(crate) fn decode_message(message: &[u8]) -> anyhow::Result<TransactionRow> {
+    let Some((&CONFLUENT_MAGIC, mut datum)) = message.split_first() else {
+        bail!("the message isn't in the Confluent wire format");
+    };
+    datum = datum.get(4..).context("truncated message")?;
+
+    let datums = GenericDatumReader::builder(&SCHEMA).build()?;
+    decode(datums.read_value(&mut datum)?)
+}
+
+fn decode(value: Value) -> anyhow::Result<TransactionRow> {
+    Ok(apache_avro::from_value(&value)?)
+}
+
+#[cfg(test)]
+mod test {
+    use apache_avro::{Writer, writer::datum::GenericDatumWriter};
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    fn events() -> Vec<Value> {
+        let event = |kind: &str, tx: i64, amount: Option<&str>| {
+            Value::Record(vec![
+                ("type".to_owned(), Value::String(kind.to_owned())),
+                ("client".to_owned(), Value::Int(1)),
+                ("tx".to_owned(), Value::Long(tx)),
+                (
+                    "amount".to_owned(),
+                    match amount {
+                        Some(amount) => Value::Union(1, Box::new(Value::String(amount.to_owned()))),
+                        None => Value::Union(0, Box::new(Value::Null)),
+                    },
+                ),
+                ("tenant".to_owned(), Value::Union(0, Box::new(Value::Null))),
+                (
+                    "timestamp".to_owned(),
+                    Value::Union(0, Box::new(Value::Null)),
+                ),
+            ])
+        };
+
+        vec![
+            event("deposit", 1, Some("2.5")),
+            event("deposit", 2, Some("1")),
+            event("dispute", 2, None),
+        ]
+    }
+
+    fn process(input: &[u8]) -> anyhow::Result<Vec<ClientRow>> {
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = avro_processor(input, csv::Writer::from_writer(&mut output), &mut db)?;
+        assert_eq!(metrics.latencies.count(), 3);
+
+        Ok(csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn expected() -> Vec<ClientRow> {
+        vec![ClientRow {
+            client: 1,
+            available: dec!(2.5),
+            held: dec!(1),
+            total: dec!(3.5),
+            locked: false,
+        }]
+    }
+
+    #[test]
+    fn container_file() {
+        // Written with an older schema, without the optional fields
+        let schema = Schema::parse_str(
+            r#"{"type": "record", "name": "TransactionEvent", "fields": [
+                {"name": "type", "type": "string"},
+                {"name": "client", "type": "int"},
+                {"name": "tx", "type": "int"},
+                {"name": "amount", "type": ["null", "string"]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut writer = Writer::new(&schema, Vec::new()).unwrap();
+        for event in events() {
+            let Value::Record(mut fields) = event else {
+                unreachable!()
+            };
+            fields.truncate(4);
+            fields[2].1 = Value::Int(match fields[2].1 {
+                Value::Long(tx) => tx as i32,
+                _ => unreachable!(),
+            });
+            writer.append_value(Value::Record(fields)).unwrap();
+        }
+
+        assert_eq!(process(&writer.into_inner().unwrap()).unwrap(), expected());
+    }
+
+    #[test]
+    fn confluent_wire_format() {
+        let datums = GenericDatumWriter::builder(&SCHEMA).build().unwrap();
+        let mut input = Vec::new();
+        for event in events() {
+            input.push(CONFLUENT_MAGIC);
+            input.extend(42_u32.to_be_bytes());
+            input.extend(datums.write_value_to_vec(event).unwrap());
+        }
+
+        assert_eq!(process(&input).unwrap(), expected());
+    }
+
+    #[test]
+    fn single_message() {
+        let datums = GenericDatumWriter::builder(&SCHEMA).build().unwrap();
+        let mut message = vec![CONFLUENT_MAGIC, 0, 0, 0, 42];
+        message.extend(datums.write_value_to_vec(events().remove(0)).unwrap());
+
+        let row = decode_message(&message).unwrap();
+        assert_eq!((row.client, row.tx, row.amount), (1, 1, Some(dec!(2.5))));
+        assert!(decode_message(&message[..3]).is_err());
+        assert!(decode_message(b"{}").is_err());
+    }
+
+    #[test]
+    fn err_not_avro() {
+        assert_eq!(
+            process(b"type,client,tx,amount\n").unwrap_err().to_string(),
+            "message 1 isn't in the Confluent wire format"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    clock::Timestamp,
+    encryption::EncryptionKey,
+    memory_processor::{FrozenClients, InMemoryTransactionDb},
+    snapshot::{Snapshot, SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    tenant::StorageFootprint,
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// Identifies a storage backend and its location, written as `<kind>:<location>`.
+///
+/// - `memory:<path>`: in-memory state persisted as a snapshot file
+/// - `redb:<path>`: a redb database file (requires the `redb` feature)
+/// - `lmdb:<dir>`: an LMDB environment directory (requires the `lmdb` feature)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendSpec {
+    Memory(PathBuf),
+    #[cfg(feature = "redb")]
+    Redb(PathBuf),
+    #[cfg(feature = "lmdb")]
+    Lmdb(PathBuf),
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum BackendSpecError {
+    #[error("backend spec {0:?} must look like <kind>:<location>")]
+    Malformed(String),
+    #[error("unsupported backend kind {0:?} (supported: {supported})", supported = SUPPORTED.join(", "))]
+    Unsupported(String),
+}
+
+const SUPPORTED: &[&str] = &[
+    "memory",
+    #[cfg(feature = "redb")]
+    "redb",
+    #[cfg(feature = "lmdb")]
+    "lmdb",
+];
+
+impl FromStr for BackendSpec {
+    type Err = BackendSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A single letter is the drive of a Windows path missing its kind, eg `C:\state.json`
+        let (kind, location) = s
+            .split_once(':')
+            .filter(|(kind, location)| kind.len() > 1 && !location.is_empty())
+            .ok_or_else(|| BackendSpecError::Malformed(s.to_string()))?;
+
+        match kind {
+            "memory" => Ok(Self::Memory(PathBuf::from(location))),
+            #[cfg(feature = "redb")]
+            "redb" => Ok(Self::Redb(PathBuf::from(location))),
+            #[cfg(feature = "lmdb")]
+            "lmdb" => Ok(Self::Lmdb(PathBuf::from(location))),
+            kind => Err(BackendSpecError::Unsupported(kind.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for BackendSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Memory(_) => "memory",
+            #[cfg(feature = "redb")]
+            Self::Redb(_) => "redb",
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(_) => "lmdb",
+        };
+        write!(f, "{kind}:{}", self.location().display())
+    }
+}
+
+impl BackendSpec {
+    /// The file or directory the backend is stored in
+    pub fn location(&self) -> &Path {
+        match self {
+            Self::Memory(path) => path,
+            #[cfg(feature = "redb")]
+            Self::Redb(path) => path,
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(path) => path,
+        }
+    }
+
+    /// Whether the backend has been written yet. Opening a missing backend creates it empty.
+    pub fn exists(&self) -> bool {
+        self.location().exists()
+    }
+
+    /// Opens the backend for whole-state access.
+    ///
+    /// The key encrypts file-based backends at rest, and `format` is the encoding they're
+    /// written in. Database backends manage their own files and ignore both.
+    pub fn open(
+        &self,
+        key: Option<&EncryptionKey>,
+        format: SnapshotFormat,
+    ) -> anyhow::Result<Box<dyn StateStore>> {
+        match self {
+            Self::Memory(path) => Ok(Box::new(
+                SnapshotFile::new(path)
+                    .encrypted(key.cloned())
+                    .with_format(format),
+            )),
+            #[cfg(feature = "redb")]
+            Self::Redb(path) => Ok(Box::new(crate::redb_processor::RedbTransactionDb::open(
+                path,
+            )?)),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(path) => Ok(Box::new(crate::lmdb_processor::LmdbTransactionDb::open(
+                path,
+            )?)),
+        }
+    }
+
+    /// Opens the backend for processing events.
+    ///
+    /// A `memory:` backend starts empty: its state is a snapshot file, which the caller
+    /// restores from and saves to. Database backends start from the state they store, and
+    /// write every event as it's applied.
+    pub fn open_db(&self) -> anyhow::Result<BackendDb> {
+        match self {
+            Self::Memory(_) => Ok(BackendDb::Memory(InMemoryTransactionDb::new())),
+            #[cfg(feature = "redb")]
+            Self::Redb(path) => Ok(BackendDb::Redb(
+                crate::redb_processor::RedbTransactionDb::open(path)?,
+            )),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(path) => Ok(BackendDb::Lmdb(
+                crate::lmdb_processor::LmdbTransactionDb::open(path)?,
+            )),
+        }
+    }
+}
+
+/// The DB of a backend, picked at runtime, see [`BackendSpec::open_db`]
+// There's one per process, boxing the in-memory DB would only add an indirection to every event
+#[allow(clippy::large_enum_variant)]
+pub enum BackendDb {
+    Memory(InMemoryTransactionDb),
+    #[cfg(feature = "redb")]
+    Redb(crate::redb_processor::RedbTransactionDb),
+    #[cfg(feature = "lmdb")]
+    Lmdb(crate::lmdb_processor::LmdbTransactionDb),
+}
+
+impl BackendDb {
+    /// Sizes the client cache of backends which have one to `capacity`, and preloads it with
+    /// the most recently active clients, see
+    /// [`RedbTransactionDb::warmup`](crate::redb_processor::RedbTransactionDb::warmup).
+    /// Returns the number of clients loaded, `0` for backends without a cache.
+    pub fn warmup(&mut self, capacity: usize) -> Result<usize, TransactionError> {
+        #[cfg(feature = "redb")]
+        if let Self::Redb(db) = self {
+            db.set_cache_capacity(capacity);
+            return db.warmup();
+        }
+        #[cfg(not(feature = "redb"))]
+        let _ = capacity;
+
+        Ok(0)
+    }
+}
+
+impl TransactionProcessor for BackendDb {
+    fn process_transaction_event(
+        &mut self,
+        transaction: TransactionEvent,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.process_transaction_event(transaction),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.process_transaction_event(transaction),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.process_transaction_event(transaction),
+        }
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.process_annotated_event(transaction, metadata),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.process_annotated_event(transaction, metadata),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.process_annotated_event(transaction, metadata),
+        }
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        match self {
+            Self::Memory(db) => db.follow_event_time(timestamp),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.follow_event_time(timestamp),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.follow_event_time(timestamp),
+        }
+    }
+
+    fn last_seq(&self) -> u64 {
+        match self {
+            Self::Memory(db) => db.last_seq(),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.last_seq(),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.last_seq(),
+        }
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.deposit(transaction_id, client_id, amount),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.deposit(transaction_id, client_id, amount),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.deposit(transaction_id, client_id, amount),
+        }
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.withdrawal(transaction_id, client_id, amount),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.withdrawal(transaction_id, client_id, amount),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.withdrawal(transaction_id, client_id, amount),
+        }
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.dispute(transaction_id, client_id),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.dispute(transaction_id, client_id),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.dispute(transaction_id, client_id),
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.resolve(transaction_id, client_id),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.resolve(transaction_id, client_id),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.resolve(transaction_id, client_id),
+        }
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        match self {
+            Self::Memory(db) => db.chargeback(transaction_id, client_id),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.chargeback(transaction_id, client_id),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.chargeback(transaction_id, client_id),
+        }
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        let clients: Box<dyn Iterator<Item = ClientInformation> + '_> = match self {
+            Self::Memory(db) => Box::new(db.clients_iter()),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => Box::new(db.clients_iter()),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => Box::new(db.clients_iter()),
+        };
+        clients
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        match self {
+            Self::Memory(db) => db.client(client_id),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.client(client_id),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.client(client_id),
+        }
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        match self {
+            Self::Memory(db) => db.closed_client(client_id),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.closed_client(client_id),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.closed_client(client_id),
+        }
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        match self {
+            Self::Memory(db) => db.frozen_clients(),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.frozen_clients(),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.frozen_clients(),
+        }
+    }
+}
+
+/// Only the in-memory backend can tell: database files aren't split by ledger
+impl StorageFootprint for BackendDb {
+    fn storage_bytes(&self) -> Option<u64> {
+        match self {
+            Self::Memory(db) => db.storage_bytes(),
+            #[cfg(feature = "redb")]
+            Self::Redb(_) => None,
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(_) => None,
+        }
+    }
+}
+
+impl StateStore for BackendDb {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        match self {
+            Self::Memory(db) => db.snapshot(),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.snapshot(),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.snapshot(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        match self {
+            Self::Memory(db) => db.restore(snapshot),
+            #[cfg(feature = "redb")]
+            Self::Redb(db) => db.restore(snapshot),
+            #[cfg(feature = "lmdb")]
+            Self::Lmdb(db) => db.restore(snapshot),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "redb")]
+    use rust_decimal::dec;
+
+    use super::*;
+
+    #[test]
+    fn memory_backends_start_empty() {
+        let spec: BackendSpec = "memory:state.json".parse().unwrap();
+        let db = spec.open_db().unwrap();
+        assert!(matches!(db, BackendDb::Memory(_)));
+        assert_eq!(db.last_seq(), 0);
+    }
+
+    #[test]
+    fn missing_backends_dont_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = BackendSpec::Memory(dir.path().join("state.json"));
+        assert!(!spec.exists());
+
+        spec.open(None, SnapshotFormat::default())
+            .unwrap()
+            .restore(Snapshot::default())
+            .unwrap();
+        assert!(spec.exists());
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn redb_backends_keep_their_state_and_warm_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = BackendSpec::Redb(dir.path().join("octopussy.redb"));
+
+        let mut db = spec.open_db().unwrap();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 2, dec!(5)).unwrap();
+        drop(db);
+
+        let mut db = spec.open_db().unwrap();
+        assert_eq!(db.last_seq(), 2);
+        assert_eq!(db.warmup(1).unwrap(), 1);
+        assert_eq!(db.client(1).unwrap().available, dec!(10));
+        assert_eq!(
+            db.deposit(1, 1, dec!(10)),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encryption::{self, EncryptionKey},
+    snapshot::{Snapshot, SnapshotTotals, StateStore},
+    state_version,
+};
+
+/// Current version of the [`BackupArchive`] layout
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A point-in-time copy of a backend's state.
+///
+/// Backends take their snapshot in a single read transaction (or under a shared borrow for
+/// the in-memory DB), so an archive is consistent even if it's taken while events keep
+/// being applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupArchive {
+    pub version: u32,
+    /// Seconds since the UNIX epoch
+    pub created_at: u64,
+    /// Position in the change stream the snapshot corresponds to, if it's known. Replaying
+    /// the change stream after this position on top of the restored state catches up.
+    pub position: Option<u64>,
+    pub snapshot: Snapshot,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum BackupError {
+    #[error("unsupported backup version {0} (expected {BACKUP_VERSION})")]
+    UnsupportedVersion(u32),
+
+    #[error("restore verification failed: expected {expected:?}, backend has {actual:?}")]
+    VerificationFailed {
+        expected: SnapshotTotals,
+        actual: SnapshotTotals,
+    },
+}
+
+impl BackupArchive {
+    /// Takes a backup of the store.
+    pub fn create<S>(store: &S, position: Option<u64>) -> anyhow::Result<Self>
+    where
+        S: StateStore + ?Sized,
+    {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        Ok(Self {
+            version: BACKUP_VERSION,
+            created_at,
+            position,
+            snapshot: store.snapshot()?,
+        })
+    }
+
+    pub fn read(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Self> {
+        let data = encryption::read_file(path, key)?;
+        let archive: Self = state_version::from_versioned_json(&data, "/snapshot")
+            .with_context(|| format!("failed to decode backup {}", path.display()))?;
+
+        if archive.version != BACKUP_VERSION {
+            return Err(BackupError::UnsupportedVersion(archive.version).into());
+        }
+
+        Ok(archive)
+    }
+
+    /// Writes the archive to a temporary file first and renames it into place, so a
+    /// failed backup never replaces a good one with a truncated file.
+    pub fn write(&self, path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        encryption::write_file(path, &state_version::to_versioned_json(self)?, key)
+    }
+
+    /// Replaces the store's state with the archived one, and checks that the store reads
+    /// back the same counts and balance totals.
+    pub fn restore<S>(self, store: &mut S) -> anyhow::Result<SnapshotTotals>
+    where
+        S: StateStore + ?Sized,
+    {
+        let expected = self.snapshot.totals();
+        store.restore(self.snapshot)?;
+
+        let actual = store.snapshot()?.totals();
+        if actual != expected {
+            return Err(BackupError::VerificationFailed { expected, actual }.into());
+        }
+
+        Ok(actual)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{memory_processor::InMemoryTransactionDb, transaction::TransactionProcessor};
+
+    #[test]
+    fn backup_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.json");
+
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.dispute(2, 1).unwrap();
+
+        BackupArchive::create(&db, Some(3))
+            .unwrap()
+            .write(&path, None)
+            .unwrap();
+
+        // Changes made after the backup don't end up in it
+        db.resolve(2, 1).unwrap();
+
+        let archive = BackupArchive::read(&path, None).unwrap();
+        assert_eq!(archive.position, Some(3));
+
+        let mut restored = InMemoryTransactionDb::new();
+        let totals = archive.restore(&mut restored).unwrap();
+        assert_eq!(totals.held, dec!(5));
+        assert_eq!(restored.client(1).unwrap().available, dec!(10));
+    }
+
+    #[test]
+    fn err_unsupported_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.json");
+
+        let mut archive = BackupArchive::create(&InMemoryTransactionDb::new(), None).unwrap();
+        archive.version = 99;
+        archive.write(&path, None).unwrap();
+
+        let err = BackupArchive::read(&path, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BackupError>(),
+            Some(&BackupError::UnsupportedVersion(99))
+        );
+    }
+}
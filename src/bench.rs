@@ -0,0 +1,597 @@
+//! Load tests of the engine, behind `octopussy bench`.
+//!
+//! A seeded mix of submitted events and balance queries is driven through any [`Target`], a
+//! local [`TransactionProcessor`] or a remote engine, and the latency of every operation
+//! recorded, so capacity planning and latency SLOs rely on the crate's own harness rather
+//! than ad-hoc scripts.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "http")]
+use anyhow::Context;
+use anyhow::anyhow;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    metrics::LatencyHistogram,
+    transaction::{
+        ClientId, TransactionError, TransactionEvent, TransactionId, TransactionProcessor,
+    },
+};
+
+/// Deposits recent enough to be disputed by the workload
+const RECENT_DEPOSITS: usize = 1024;
+
+/// Relative weights of submitted events and balance queries, written `submits:queries`, eg
+/// `80:20`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mix {
+    pub submits: u32,
+    pub queries: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self {
+            submits: 80,
+            queries: 20,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum BenchError {
+    #[error("invalid mix {0:?}, expected `submits:queries` weights, eg 80:20")]
+    InvalidMix(String),
+}
+
+impl FromStr for Mix {
+    type Err = BenchError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || BenchError::InvalidMix(value.to_owned());
+
+        let (submits, queries) = value.split_once(':').ok_or_else(invalid)?;
+        let mix = Self {
+            submits: submits.trim().parse().map_err(|_| invalid())?,
+            queries: queries.trim().parse().map_err(|_| invalid())?,
+        };
+        if mix.submits == 0 && mix.queries == 0 {
+            return Err(invalid());
+        }
+
+        Ok(mix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchConfig {
+    pub operations: u64,
+    pub mix: Mix,
+    /// Events are spread over clients `1..=clients`
+    pub clients: ClientId,
+    /// Seed of the workload, the same seed gives the same operations
+    pub seed: u64,
+}
+
+/// Latencies observed by [`run`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BenchReport {
+    pub submits: LatencyHistogram,
+    pub queries: LatencyHistogram,
+    /// Submitted events the engine rejected, eg withdrawals over the available balance
+    pub rejected: u64,
+    pub elapsed: Duration,
+}
+
+/// Latency percentiles of one kind of operation, in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_us: f64,
+    pub p50_us: f64,
+    pub p90_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+}
+
+impl From<&LatencyHistogram> for LatencySummary {
+    fn from(histogram: &LatencyHistogram) -> Self {
+        let micros = |duration: Duration| duration.as_secs_f64() * 1e6;
+
+        Self {
+            count: histogram.count(),
+            mean_us: micros(histogram.mean()),
+            p50_us: micros(histogram.quantile(0.5)),
+            p90_us: micros(histogram.quantile(0.9)),
+            p99_us: micros(histogram.quantile(0.99)),
+            max_us: micros(histogram.max()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BenchSummary {
+    pub operations: u64,
+    pub elapsed_seconds: f64,
+    pub throughput: f64,
+    pub rejected: u64,
+    pub submits: LatencySummary,
+    pub queries: LatencySummary,
+}
+
+/// An operation whose p99 latency is above the SLO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SloViolation {
+    pub operation: &'static str,
+    pub p99: Duration,
+    pub slo: Duration,
+}
+
+impl fmt::Display for SloViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} p99 <= {:?} is above the {:?} SLO",
+            self.operation, self.p99, self.slo
+        )
+    }
+}
+
+impl BenchReport {
+    pub fn operations(&self) -> u64 {
+        self.submits.count() + self.queries.count()
+    }
+
+    /// Operations per second
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            seconds => self.operations() as f64 / seconds,
+        }
+    }
+
+    pub fn summary(&self) -> BenchSummary {
+        BenchSummary {
+            operations: self.operations(),
+            elapsed_seconds: self.elapsed.as_secs_f64(),
+            throughput: self.throughput(),
+            rejected: self.rejected,
+            submits: (&self.submits).into(),
+            queries: (&self.queries).into(),
+        }
+    }
+
+    /// The operations whose p99 latency is above `slo`. The histogram buckets are coarse,
+    /// so a p99 is only known to be below its bucket's upper bound, which is what's
+    /// compared.
+    pub fn slo_violations(&self, slo: Duration) -> Vec<SloViolation> {
+        [("submit", &self.submits), ("query", &self.queries)]
+            .into_iter()
+            .filter(|(_, histogram)| histogram.count() > 0)
+            .map(|(operation, histogram)| SloViolation {
+                operation,
+                p99: histogram.quantile(0.99),
+                slo,
+            })
+            .filter(|violation| violation.p99 > slo)
+            .collect()
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} operations in {:?} ({:.0} ops/s), {} submits rejected",
+            self.operations(),
+            self.elapsed,
+            self.throughput(),
+            self.rejected
+        )?;
+
+        for (operation, histogram) in [("submit", &self.submits), ("query", &self.queries)] {
+            writeln!(
+                f,
+                "{operation}: {} operations, mean {:?}, p50 <= {:?}, p90 <= {:?}, p99 <= {:?}, max {:?}",
+                histogram.count(),
+                histogram.mean(),
+                histogram.quantile(0.5),
+                histogram.quantile(0.9),
+                histogram.quantile(0.99),
+                histogram.max()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// splitmix64, plenty for picking operations and good enough to not pull in a dependency
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound.max(1)
+    }
+
+    /// True with a `probability` between 0 and 1
+    pub(crate) fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits, as many as an f64 holds exactly
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// Generates the submitted events: mostly deposits and withdrawals, with some disputes of
+/// recent deposits and resolves of them.
+struct Workload {
+    rng: Rng,
+    clients: ClientId,
+    next_tx: TransactionId,
+    recent_deposits: Vec<(ClientId, TransactionId)>,
+    disputed: Vec<(ClientId, TransactionId)>,
+}
+
+impl Workload {
+    fn client(&mut self) -> ClientId {
+        self.rng.below(u64::from(self.clients)) as ClientId + 1
+    }
+
+    fn amount(&mut self) -> Decimal {
+        Decimal::new(self.rng.below(100_000) as i64 + 1, 2)
+    }
+
+    fn event(&mut self) -> TransactionEvent {
+        let roll = self.rng.below(100);
+
+        if roll < 5
+            && let Some(index) = self.pick(self.disputed.len())
+        {
+            let (client, tx) = self.disputed.swap_remove(index);
+            return TransactionEvent::Resolve { tx, client };
+        }
+        if roll < 10
+            && let Some(index) = self.pick(self.recent_deposits.len())
+        {
+            let (client, tx) = self.recent_deposits.swap_remove(index);
+            self.disputed.push((client, tx));
+            return TransactionEvent::Dispute { tx, client };
+        }
+
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        let client = self.client();
+        let amount = self.amount();
+
+        if roll < 30 {
+            return TransactionEvent::Withdrawal { tx, client, amount };
+        }
+        if self.recent_deposits.len() == RECENT_DEPOSITS {
+            let index = self.rng.below(RECENT_DEPOSITS as u64) as usize;
+            self.recent_deposits.swap_remove(index);
+        }
+        self.recent_deposits.push((client, tx));
+        TransactionEvent::Deposit { tx, client, amount }
+    }
+
+    fn pick(&mut self, len: usize) -> Option<usize> {
+        (len > 0).then(|| self.rng.below(len as u64) as usize)
+    }
+}
+
+/// What [`run`] drives the operations through
+pub trait Target {
+    /// Submits `event`, returning whether the engine applied it
+    ///
+    /// ## Errors
+    ///
+    /// If the engine couldn't be reached or couldn't store the event, which stops the run
+    /// rather than counting as a rejection.
+    fn submit(&mut self, event: TransactionEvent) -> anyhow::Result<bool>;
+
+    /// Reads the balances of `client`, who may not exist
+    fn query(&mut self, client: ClientId) -> anyhow::Result<()>;
+}
+
+impl<DB: TransactionProcessor> Target for DB {
+    fn submit(&mut self, event: TransactionEvent) -> anyhow::Result<bool> {
+        match self.process_transaction_event(event) {
+            Ok(()) => Ok(true),
+            Err(TransactionError::Storage(err)) => Err(anyhow!(err)),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn query(&mut self, client: ClientId) -> anyhow::Result<()> {
+        std::hint::black_box(self.client(client));
+        Ok(())
+    }
+}
+
+/// The HTTP API of an engine served by `octopussy serve`, see [`crate::server`]
+#[cfg(feature = "http")]
+pub struct HttpTarget {
+    agent: ureq::Agent,
+    url: String,
+}
+
+#[cfg(feature = "http")]
+impl HttpTarget {
+    /// Targets the API at `url`, eg `http://ledger:8080`, once it answers `GET /ready`
+    pub fn connect(url: &str) -> anyhow::Result<Self> {
+        let target = Self {
+            agent: ureq::Agent::new_with_defaults(),
+            url: url.trim_end_matches('/').to_owned(),
+        };
+        target
+            .agent
+            .get(format!("{}/ready", target.url))
+            .call()
+            .with_context(|| format!("{url} isn't ready"))?;
+
+        Ok(target)
+    }
+}
+
+#[cfg(feature = "http")]
+impl Target for HttpTarget {
+    fn submit(&mut self, event: TransactionEvent) -> anyhow::Result<bool> {
+        let (tx, client, amount) = match event {
+            TransactionEvent::Deposit { tx, client, amount }
+            | TransactionEvent::Withdrawal { tx, client, amount } => (tx, client, Some(amount)),
+            TransactionEvent::Dispute { tx, client }
+            | TransactionEvent::Resolve { tx, client }
+            | TransactionEvent::Chargeback { tx, client } => (tx, client, None),
+        };
+        let row = serde_json::json!({
+            "type": event.kind().as_str(),
+            "client": client,
+            "tx": tx,
+            "amount": amount.map(|amount| amount.to_string()),
+        });
+
+        let url = format!("{}/transactions", self.url);
+        let result = self
+            .agent
+            .post(&url)
+            .header("content-type", "application/json")
+            .send(row.to_string());
+        match result {
+            Ok(mut response) => {
+                response.body_mut().read_to_vec()?;
+                Ok(true)
+            }
+            // Storage errors and draining
+            Err(ureq::Error::StatusCode(503)) => Err(anyhow!("{url} answered 503")),
+            Err(ureq::Error::StatusCode(_)) => Ok(false),
+            Err(err) => Err(anyhow!("{url}: {err}")),
+        }
+    }
+
+    fn query(&mut self, client: ClientId) -> anyhow::Result<()> {
+        let url = format!("{}/clients/{client}", self.url);
+        match self.agent.get(&url).call() {
+            Ok(mut response) => {
+                response.body_mut().read_to_vec()?;
+                Ok(())
+            }
+            Err(ureq::Error::StatusCode(404)) => Ok(()),
+            Err(err) => Err(anyhow!("{url}: {err}")),
+        }
+    }
+}
+
+/// Drives `config.operations` operations through `target`, each one a submitted event or a
+/// balance query according to `config.mix`, and records their latencies.
+///
+/// Transaction ids start at 1, so the engine should be empty, or the deposits of an earlier
+/// run get rejected as duplicates.
+///
+/// ## Errors
+///
+/// If an operation fails, see [`Target::submit`].
+pub fn run<T: Target>(target: &mut T, config: &BenchConfig) -> anyhow::Result<BenchReport> {
+    let mut workload = Workload {
+        rng: Rng(config.seed),
+        clients: config.clients.max(1),
+        next_tx: 1,
+        recent_deposits: Vec::new(),
+        disputed: Vec::new(),
+    };
+    let weights = u64::from(config.mix.submits) + u64::from(config.mix.queries);
+    let mut report = BenchReport::default();
+
+    let started = Instant::now();
+    for _ in 0..config.operations {
+        if workload.rng.below(weights) < u64::from(config.mix.submits) {
+            let event = workload.event();
+            let submitted = Instant::now();
+            let applied = target.submit(event)?;
+            report.submits.record(submitted.elapsed());
+            report.rejected += u64::from(!applied);
+        } else {
+            let client = workload.client();
+            let queried = Instant::now();
+            target.query(client)?;
+            report.queries.record(queried.elapsed());
+        }
+    }
+    report.elapsed = started.elapsed();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn config(mix: Mix) -> BenchConfig {
+        BenchConfig {
+            operations: 10_000,
+            mix,
+            clients: 100,
+            seed: 7,
+        }
+    }
+
+    #[test]
+    fn parse_mix() {
+        assert_eq!(
+            "90:10".parse(),
+            Ok(Mix {
+                submits: 90,
+                queries: 10
+            })
+        );
+        assert_eq!(
+            "0:1".parse(),
+            Ok(Mix {
+                submits: 0,
+                queries: 1
+            })
+        );
+        assert_eq!(
+            "0:0".parse::<Mix>(),
+            Err(BenchError::InvalidMix("0:0".to_owned()))
+        );
+        assert!("80".parse::<Mix>().is_err());
+    }
+
+    #[test]
+    fn runs_the_mix() {
+        let mut db = InMemoryTransactionDb::new();
+        let report = run(&mut db, &config(Mix::default())).unwrap();
+
+        assert_eq!(report.operations(), 10_000);
+        // Roughly 80:20
+        assert!((7_500..8_500).contains(&report.submits.count()));
+        assert!(report.rejected < report.submits.count());
+        assert_eq!(
+            db.last_seq(),
+            report.submits.count() - report.rejected,
+            "every applied event got a sequence number"
+        );
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut first = InMemoryTransactionDb::new();
+        let mut second = InMemoryTransactionDb::new();
+        run(&mut first, &config(Mix::default())).unwrap();
+        run(&mut second, &config(Mix::default())).unwrap();
+
+        let clients = |db: &InMemoryTransactionDb| {
+            let mut clients: Vec<_> = db.clients_iter().collect();
+            clients.sort_by_key(|client| client.id);
+            clients
+        };
+        assert_eq!(clients(&first), clients(&second));
+    }
+
+    /// Runs a short workload through `target`, checking the engine behind it rejects the
+    /// same events as an in-process one
+    #[cfg(any(feature = "grpc", all(feature = "http", feature = "server")))]
+    fn check_target<T: Target>(target: &mut T, db: &std::sync::Mutex<InMemoryTransactionDb>) {
+        let config = BenchConfig {
+            operations: 500,
+            ..config(Mix::default())
+        };
+        let report = run(target, &config).unwrap();
+        let local = run(&mut InMemoryTransactionDb::new(), &config).unwrap();
+
+        assert_eq!(report.operations(), 500);
+        assert_eq!(report.rejected, local.rejected);
+        assert_eq!(
+            db.lock().unwrap().last_seq(),
+            report.submits.count() - report.rejected
+        );
+    }
+
+    #[cfg(all(feature = "http", feature = "server"))]
+    #[test]
+    fn drives_http_targets() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::{
+            drain::DrainSignal,
+            server::{UpdateBroadcast, router},
+        };
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        let router = router(db.clone(), UpdateBroadcast::new(16), DrainSignal::new());
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, router).await
+            })
+        });
+
+        let mut target = HttpTarget::connect(&format!("http://{address}/")).unwrap();
+        check_target(&mut target, &db);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn drives_grpc_targets() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::{
+            grpc::{EngineServer, EngineService},
+            remote::RemoteTransactionProcessor,
+        };
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        let service = EngineServer::new(EngineService::new(db.clone()));
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let incoming = futures::stream::unfold(listener, async |listener| {
+                    let stream = listener.accept().await.map(|(stream, _)| stream);
+                    Some((stream, listener))
+                });
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming)
+                    .await
+            })
+        });
+
+        let mut target = RemoteTransactionProcessor::connect(&format!("http://{address}")).unwrap();
+        check_target(&mut target, &db);
+    }
+
+    #[test]
+    fn slo_violations() {
+        let mut report = BenchReport::default();
+        report.submits.record(Duration::from_millis(3));
+        report.queries.record(Duration::from_micros(1));
+
+        let violations = report.slo_violations(Duration::from_millis(1));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].operation, "submit");
+        assert!(report.slo_violations(Duration::from_millis(5)).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io::Read,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drain::DrainSignal,
+    encryption::{self, EncryptionKey},
+    snapshot::Snapshot,
+    state_version,
+};
+
+/// How often, and where, checkpoints are written while processing.
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    pub path: PathBuf,
+    /// Number of rows between two checkpoints
+    pub every: NonZeroU64,
+    /// Encrypt checkpoints at rest with this key
+    pub key: Option<EncryptionKey>,
+    /// Once drained, processing stops after the row being applied and a checkpoint of it is
+    /// saved
+    pub drain: Option<DrainSignal>,
+    /// The input being processed, if it's a file: a checkpoint taken on another input isn't
+    /// resumed
+    pub input: Option<InputFingerprint>,
+}
+
+/// Number of bytes at the start of an input hashed into its [`InputFingerprint`]
+const FINGERPRINT_BYTES: u64 = 64 * 1024;
+
+/// Identifies an input file, so a checkpoint isn't resumed on another file whose rows at the
+/// same offsets are different ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFingerprint {
+    /// Absolute path of the file
+    pub path: PathBuf,
+    pub size: u64,
+    /// FNV-1a hash of the first 64 KiB, in hex
+    pub head: String,
+}
+
+impl InputFingerprint {
+    /// Fingerprints the file at `path`.
+    ///
+    /// ## Errors
+    ///
+    /// If the file can't be read.
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let read = || -> std::io::Result<Self> {
+            let mut file = File::open(path)?;
+            let size = file.metadata()?.len();
+            let mut head = Vec::new();
+            file.by_ref()
+                .take(FINGERPRINT_BYTES)
+                .read_to_end(&mut head)?;
+
+            Ok(Self {
+                path: std::path::absolute(path)?,
+                size,
+                head: format!("{:016x}", fnv1a(&head)),
+            })
+        };
+
+        read().with_context(|| format!("failed to fingerprint {}", path.display()))
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is the same across releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Progress through an input file, plus the state the DB was in at that point.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of data rows (excluding the header) already applied
+    pub records: u64,
+    /// Byte offset into the input right after the last applied row
+    pub byte_offset: u64,
+    /// Line of the input right after the last applied row, 0 if unknown
+    #[serde(default)]
+    pub line: u64,
+    /// The input it was taken on, if it was a file. Unknown for checkpoints of older
+    /// releases
+    #[serde(default)]
+    pub input: Option<InputFingerprint>,
+    pub snapshot: Snapshot,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint at `path`, or `None` if there isn't one yet.
+    pub fn load(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = encryption::read_file(path, key)?;
+        let checkpoint = state_version::from_versioned_json(&data, "/snapshot")
+            .with_context(|| format!("failed to decode checkpoint {}", path.display()))?;
+
+        Ok(Some(checkpoint))
+    }
+
+    /// Checks the checkpoint was taken on `input`, when both are known.
+    ///
+    /// ## Errors
+    ///
+    /// If it was taken on another input, or on the same file since changed.
+    pub fn check_input(&self, input: Option<&InputFingerprint>) -> anyhow::Result<()> {
+        let (Some(taken_on), Some(input)) = (&self.input, input) else {
+            return Ok(());
+        };
+        anyhow::ensure!(
+            taken_on == input,
+            "the checkpoint was taken on {} ({} bytes), not on {} ({} bytes), or the input changed since",
+            taken_on.path.display(),
+            taken_on.size,
+            input.path.display(),
+            input.size,
+        );
+
+        Ok(())
+    }
+
+    /// Writes the checkpoint next to `path` first and then renames it over, so a crash
+    /// mid-write never leaves a truncated checkpoint behind.
+    pub fn save(&self, path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        encryption::write_file(path, &state_version::to_versioned_json(self)?, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let input = InputFingerprint::of(&path).unwrap();
+        assert_eq!(input.size, 38);
+        assert!(input.path.is_absolute());
+
+        // Same size, other rows
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,2,1,1.0\n").unwrap();
+        assert_ne!(InputFingerprint::of(&path).unwrap(), input);
+    }
+
+    #[test]
+    fn fnv1a_hashes() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,219 @@
+//! Reading inputs from and writing outputs to object storage, `s3://<bucket>/<key>` and
+//! `gs://<bucket>/<key>` URIs, streaming rather than going through local copies.
+//!
+//! Credentials and regions come from the usual environment, eg `AWS_ACCESS_KEY_ID`,
+//! `AWS_REGION` and `AWS_ENDPOINT` for S3 (and S3-compatible stores), or
+//! `GOOGLE_APPLICATION_CREDENTIALS` for Google Cloud Storage.
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, bail};
+use object_store::{
+    ObjectStore, aws::AmazonS3Builder, buffered, gcp::GoogleCloudStorageBuilder, path,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Runtime,
+};
+
+/// Where an object is, parsed from its URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectUri {
+    pub scheme: Scheme,
+    pub bucket: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// `s3://`, Amazon S3 or a compatible store
+    S3,
+    /// `gs://`, Google Cloud Storage
+    Gcs,
+}
+
+impl ObjectUri {
+    /// The object `path` stands for, or `None` if it's a local path.
+    ///
+    /// ## Errors
+    /// - If `path` is an object storage URI without a bucket or a key
+    pub fn parse(path: &Path) -> anyhow::Result<Option<Self>> {
+        let Some(uri) = path.to_str() else {
+            return Ok(None);
+        };
+        let (scheme, rest) = if let Some(rest) = uri.strip_prefix("s3://") {
+            (Scheme::S3, rest)
+        } else if let Some(rest) = uri.strip_prefix("gs://") {
+            (Scheme::Gcs, rest)
+        } else {
+            return Ok(None);
+        };
+
+        match rest.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Some(Self {
+                scheme,
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+            })),
+            _ => bail!("{uri} isn't a <bucket>/<key> URI"),
+        }
+    }
+
+    /// The store of the bucket, configured from the environment
+    fn store(&self) -> anyhow::Result<Arc<dyn ObjectStore>> {
+        let store: Arc<dyn ObjectStore> = match self.scheme {
+            Scheme::S3 => Arc::new(
+                AmazonS3Builder::from_env()
+                    .with_bucket_name(&self.bucket)
+                    .build()?,
+            ),
+            Scheme::Gcs => Arc::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(&self.bucket)
+                    .build()?,
+            ),
+        };
+
+        Ok(store)
+    }
+
+    fn path(&self) -> path::Path {
+        path::Path::from(self.key.as_str())
+    }
+}
+
+fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
+
+/// Reads an object as it's downloaded, a range of it at a time.
+pub struct ObjectReader {
+    runtime: Runtime,
+    reader: buffered::BufReader,
+}
+
+impl ObjectReader {
+    pub fn open(uri: &ObjectUri) -> anyhow::Result<Self> {
+        Self::open_in(uri.store()?, uri.path())
+    }
+
+    /// Opens the object at `path` of `store`
+    pub fn open_in(store: Arc<dyn ObjectStore>, path: path::Path) -> anyhow::Result<Self> {
+        let runtime = runtime()?;
+        let meta = runtime
+            .block_on(store.head(&path))
+            .with_context(|| format!("failed to find object {path}"))?;
+
+        Ok(Self {
+            runtime,
+            reader: buffered::BufReader::new(store, &meta),
+        })
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.reader.read(buf))
+    }
+}
+
+/// Writes an object as it's uploaded, in parts once it's large enough.
+///
+/// The object is only created by [`Self::finish`]: dropping the writer before leaves the
+/// previous version of the object, if any, as it was.
+pub struct ObjectWriter {
+    runtime: Runtime,
+    writer: buffered::BufWriter,
+}
+
+impl ObjectWriter {
+    pub fn create(uri: &ObjectUri) -> anyhow::Result<Self> {
+        Self::create_in(uri.store()?, uri.path())
+    }
+
+    /// Creates the object at `path` of `store`
+    pub fn create_in(store: Arc<dyn ObjectStore>, path: path::Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            runtime: runtime()?,
+            writer: buffered::BufWriter::new(store, path),
+        })
+    }
+
+    /// Completes the upload
+    pub fn finish(mut self) -> io::Result<()> {
+        self.runtime.block_on(self.writer.shutdown())
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.writer.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.block_on(self.writer.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[test]
+    fn parses_uris() {
+        assert_eq!(
+            ObjectUri::parse(Path::new("s3://ledger/2024/01/transactions.csv.gz")).unwrap(),
+            Some(ObjectUri {
+                scheme: Scheme::S3,
+                bucket: "ledger".to_owned(),
+                key: "2024/01/transactions.csv.gz".to_owned(),
+            })
+        );
+        assert_eq!(
+            ObjectUri::parse(Path::new("gs://ledger/balances.csv"))
+                .unwrap()
+                .map(|uri| uri.scheme),
+            Some(Scheme::Gcs)
+        );
+        assert_eq!(
+            ObjectUri::parse(Path::new("transactions.csv")).unwrap(),
+            None
+        );
+        assert!(ObjectUri::parse(Path::new("s3://ledger")).is_err());
+        assert!(ObjectUri::parse(Path::new("gs:///balances.csv")).is_err());
+    }
+
+    #[test]
+    fn streams_objects() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let path = path::Path::from("reports/balances.csv");
+        let data = "client,available\n".repeat(100_000);
+
+        let mut writer = ObjectWriter::create_in(store.clone(), path.clone()).unwrap();
+        writer.write_all(data.as_bytes()).unwrap();
+        // Nothing is there until the upload is complete
+        assert!(ObjectReader::open_in(store.clone(), path.clone()).is_err());
+        writer.finish().unwrap();
+
+        let mut read = String::new();
+        ObjectReader::open_in(store.clone(), path)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+
+        let abandoned = path::Path::from("reports/abandoned.csv");
+        let mut writer = ObjectWriter::create_in(store.clone(), abandoned.clone()).unwrap();
+        writer.write_all(data.as_bytes()).unwrap();
+        drop(writer);
+        assert!(ObjectReader::open_in(store, abandoned).is_err());
+    }
+}
@@ -0,0 +1,663 @@
+//! Hot/standby pairs of engine instances: the instance holding the [`LeaderLock`] processes
+//! events and writes the change stream, and a [`Standby`] replays that stream into its own
+//! backend to stay warm, taking over once it gets the lock.
+//!
+//! The lock is either an advisory lock on a file, held by the operating system for as long
+//! as the leader's process lives so a crashed leader releases it without a lease to expire,
+//! or (with the `redis` feature) a lease in Redis for instances which don't share a
+//! filesystem. Locks held by other coordination services (Postgres advisory locks, an etcd
+//! lease) fit the same trait.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::BufRead,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use fs4::fs_std::FileExt;
+use tracing::{error, warn};
+
+use crate::{
+    drain::DrainSignal,
+    replication::{ChangeRecord, ReplicationError},
+    transaction::{ClientId, TransactionError, TransactionProcessor},
+};
+
+/// Leadership of a cluster, held by at most one instance at a time.
+pub trait LeaderLock {
+    /// Takes the lock if no other instance holds it, returning whether this instance is
+    /// the leader. Calling it again once the lock is held keeps it.
+    fn try_acquire(&mut self) -> anyhow::Result<bool>;
+
+    /// Gives up the lock, eg before a planned shutdown, so the standby takes over right
+    /// away.
+    fn release(&mut self) -> anyhow::Result<()>;
+}
+
+impl<L: LeaderLock + ?Sized> LeaderLock for Box<L> {
+    fn try_acquire(&mut self) -> anyhow::Result<bool> {
+        (**self).try_acquire()
+    }
+
+    fn release(&mut self) -> anyhow::Result<()> {
+        (**self).release()
+    }
+}
+
+/// A [`LeaderLock`] on an advisory lock of a file.
+///
+/// Both instances have to reach the same file, so on separate hosts it has to be on a
+/// shared filesystem whose locks are honoured across hosts (eg NFSv4, not NFSv3 without
+/// its lock manager). Without one, use a [`LeaderLockSpec::Redis`] lease.
+#[derive(Debug)]
+pub struct FileLeaderLock {
+    path: PathBuf,
+    held: Option<File>,
+}
+
+impl FileLeaderLock {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            held: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.is_some()
+    }
+}
+
+impl LeaderLock for FileLeaderLock {
+    fn try_acquire(&mut self) -> anyhow::Result<bool> {
+        if self.held.is_some() {
+            return Ok(true);
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open the lock file {}", self.path.display()))?;
+        if !file.try_lock_exclusive()? {
+            return Ok(false);
+        }
+
+        self.held = Some(file);
+        Ok(true)
+    }
+
+    fn release(&mut self) -> anyhow::Result<()> {
+        if let Some(file) = self.held.take() {
+            file.unlock()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How long a [`RedisLeaderLock`] lease lasts without being renewed
+pub const LEASE: Duration = Duration::from_secs(10);
+
+/// How often a [`HeldLeaderLock`] renews its lock, well within the [`LEASE`]
+pub const LEASE_RENEWAL: Duration = Duration::from_secs(2);
+
+/// Checks the lease is still this instance's before changing it, so an instance whose lease
+/// expired doesn't extend or drop the one of the new leader
+#[cfg(feature = "redis")]
+const RENEW_LEASE: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then \
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2]) else return 0 end";
+#[cfg(feature = "redis")]
+const RELEASE_LEASE: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then \
+    return redis.call('DEL', KEYS[1]) else return 0 end";
+
+/// A [`LeaderLock`] on a lease in Redis, for instances which don't share a filesystem.
+///
+/// The lease is a key holding a token of this instance, which expires after `ttl`.
+/// [`LeaderLock::try_acquire`] renews it while it's held, so it has to be called more often
+/// than that, eg by a [`HeldLeaderLock`]. A leader which stops renewing it, because it
+/// crashed or lost its connection, loses it once it expires.
+#[cfg(feature = "redis")]
+pub struct RedisLeaderLock {
+    connection: redis::Connection,
+    key: String,
+    token: String,
+    ttl: Duration,
+    held: bool,
+}
+
+#[cfg(feature = "redis")]
+impl RedisLeaderLock {
+    /// Connects to the server at `url`, eg `redis://locks:6379`, for the lease of `key`.
+    pub fn connect(url: &str, key: &str, ttl: Duration) -> anyhow::Result<Self> {
+        let connection = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .with_context(|| format!("failed to connect to {url}"))?;
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Ok(Self {
+            connection,
+            key: key.to_owned(),
+            token: format!("{}:{}", std::process::id(), started.as_nanos()),
+            ttl,
+            held: false,
+        })
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    fn ttl_millis(&self) -> u64 {
+        u64::try_from(self.ttl.as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl LeaderLock for RedisLeaderLock {
+    fn try_acquire(&mut self) -> anyhow::Result<bool> {
+        if self.held {
+            let renewed: i64 = redis::cmd("EVAL")
+                .arg(RENEW_LEASE)
+                .arg(1)
+                .arg(&self.key)
+                .arg(&self.token)
+                .arg(self.ttl_millis())
+                .query(&mut self.connection)
+                .with_context(|| format!("failed to renew the lease {}", self.key))?;
+            // Lost, another instance may have been the leader since
+            self.held = renewed == 1;
+            return Ok(self.held);
+        }
+
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&self.key)
+            .arg(&self.token)
+            .arg("NX")
+            .arg("PX")
+            .arg(self.ttl_millis())
+            .query(&mut self.connection)
+            .with_context(|| format!("failed to take the lease {}", self.key))?;
+        self.held = set.is_some();
+
+        Ok(self.held)
+    }
+
+    fn release(&mut self) -> anyhow::Result<()> {
+        if self.held {
+            let _: i64 = redis::cmd("EVAL")
+                .arg(RELEASE_LEASE)
+                .arg(1)
+                .arg(&self.key)
+                .arg(&self.token)
+                .query(&mut self.connection)
+                .with_context(|| format!("failed to release the lease {}", self.key))?;
+            self.held = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Releases the lease, like the operating system releases the lock of a file when its
+/// process exits
+#[cfg(feature = "redis")]
+impl Drop for RedisLeaderLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.release() {
+            warn!("{err:#}");
+        }
+    }
+}
+
+/// Key of the Redis lease of [`LeaderLockSpec`]s which don't name one
+pub const DEFAULT_LEASE_KEY: &str = "octopussy:leader";
+
+/// Where the [`LeaderLock`] of a cluster is held: the path of a lock file, or a `redis://`
+/// (or `rediss://`) URL with the key of the lease after a `#`, eg
+/// `redis://locks:6379#ledger-eu` ([`DEFAULT_LEASE_KEY`] without one).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaderLockSpec {
+    File(PathBuf),
+    #[cfg(feature = "redis")]
+    Redis {
+        url: String,
+        key: String,
+    },
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum LeaderLockSpecError {
+    #[error("{0:?} is a Redis lease, which needs the redis feature")]
+    RedisDisabled(String),
+}
+
+impl FromStr for LeaderLockSpec {
+    type Err = LeaderLockSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !(s.starts_with("redis://") || s.starts_with("rediss://")) {
+            return Ok(Self::File(PathBuf::from(s)));
+        }
+
+        #[cfg(feature = "redis")]
+        {
+            let (url, key) = s.split_once('#').unwrap_or((s, DEFAULT_LEASE_KEY));
+            Ok(Self::Redis {
+                url: url.to_owned(),
+                key: key.to_owned(),
+            })
+        }
+        #[cfg(not(feature = "redis"))]
+        Err(LeaderLockSpecError::RedisDisabled(s.to_owned()))
+    }
+}
+
+impl fmt::Display for LeaderLockSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            #[cfg(feature = "redis")]
+            Self::Redis { url, key } => write!(f, "{url}#{key}"),
+        }
+    }
+}
+
+impl LeaderLockSpec {
+    /// Opens the lock, without taking it
+    pub fn open(&self) -> anyhow::Result<Box<dyn LeaderLock + Send>> {
+        match self {
+            Self::File(path) => Ok(Box::new(FileLeaderLock::new(path))),
+            #[cfg(feature = "redis")]
+            Self::Redis { url, key } => Ok(Box::new(RedisLeaderLock::connect(url, key, LEASE)?)),
+        }
+    }
+
+    /// Takes the lock and holds it until the returned [`HeldLeaderLock`] is dropped,
+    /// requesting `drain` if it's lost in the meantime.
+    ///
+    /// ## Errors
+    ///
+    /// If another instance holds the lock, or it can't be reached.
+    pub fn hold(&self, drain: DrainSignal) -> anyhow::Result<HeldLeaderLock> {
+        let mut lock = self.open()?;
+        if !lock.try_acquire()? {
+            bail!("another instance holds the leader lock {self}");
+        }
+
+        Ok(HeldLeaderLock::new(lock, LEASE_RENEWAL, drain))
+    }
+}
+
+/// A [`LeaderLock`] this instance holds, renewed every `renew` on a background thread so
+/// leases don't expire while it runs.
+///
+/// If the lock is lost anyway, eg because Redis was unreachable for longer than the lease,
+/// `drain` is requested so the instance stops taking events while another one may be the
+/// leader. Dropping it releases the lock, eg once a daemon drained.
+pub struct HeldLeaderLock {
+    stop: DrainSignal,
+    renewals: Option<JoinHandle<()>>,
+}
+
+impl HeldLeaderLock {
+    /// Holds `lock`, which has to be acquired already
+    pub fn new(mut lock: Box<dyn LeaderLock + Send>, renew: Duration, drain: DrainSignal) -> Self {
+        let stop = DrainSignal::new();
+        let renewals = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut renewed = Instant::now();
+                while !stop.is_draining() {
+                    thread::sleep(renew.min(Duration::from_millis(100)));
+                    if renewed.elapsed() < renew {
+                        continue;
+                    }
+                    renewed = Instant::now();
+                    match lock.try_acquire() {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            error!("Lost the leader lock, draining");
+                            drain.drain();
+                            return;
+                        }
+                        Err(err) => warn!("{err:#}"),
+                    }
+                }
+                if let Err(err) = lock.release() {
+                    warn!("Failed to release the leader lock: {err:#}");
+                }
+            }
+        });
+
+        Self {
+            stop,
+            renewals: Some(renewals),
+        }
+    }
+}
+
+impl Drop for HeldLeaderLock {
+    fn drop(&mut self) {
+        self.stop.drain();
+        if let Some(renewals) = self.renewals.take() {
+            let _ = renewals.join();
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum StandbyError {
+    #[error(transparent)]
+    Gap(#[from] ReplicationError),
+
+    /// The leader applied an event the standby rejects, eg because they run with
+    /// different policies
+    #[error("the standby rejected record {seq} the leader applied: {source}")]
+    Rejected { seq: u64, source: TransactionError },
+
+    /// Replaying the event didn't leave the client with the balances of the record, eg
+    /// because the leader runs decorators (screening) changing them further
+    #[error("client {client} diverged from the leader at record {seq}")]
+    Diverged { seq: u64, client: ClientId },
+}
+
+/// A backend kept in sync with the leader by replaying the events of its change stream.
+///
+/// Unlike a [`crate::replication::ReadReplica`], which only keeps balances, the standby
+/// has the full state, transaction history included, so it can take over processing.
+pub struct Standby<DB> {
+    db: DB,
+    /// Part of a line the leader was still writing when the stream was last read
+    partial: String,
+}
+
+impl<DB: TransactionProcessor> Standby<DB> {
+    /// A standby replaying the stream into `db`, which has to be in the state the leader
+    /// started from (eg empty, or restored from the same snapshot).
+    pub fn new(db: DB) -> Self {
+        Self {
+            db,
+            partial: String::new(),
+        }
+    }
+
+    /// Sequence number of the last replayed record
+    pub fn last_seq(&self) -> u64 {
+        self.db.last_seq()
+    }
+
+    /// Replays a change record. Records that were already replayed are ignored.
+    pub fn apply(&mut self, record: ChangeRecord) -> Result<(), StandbyError> {
+        let last_seq = self.db.last_seq();
+        if record.seq <= last_seq {
+            return Ok(());
+        }
+        if record.seq != last_seq + 1 {
+            return Err(ReplicationError::Gap {
+                expected: last_seq + 1,
+                got: record.seq,
+            }
+            .into());
+        }
+
+        let seq = record.seq;
+        let client = record.client;
+        self.db
+            .process_annotated_event(record.event, &record.metadata)
+            .map_err(|source| StandbyError::Rejected { seq, source })?;
+
+        let replayed = self.db.client(client.id);
+        let in_sync = replayed.is_some_and(|replayed| {
+            replayed.available == client.available
+                && replayed.held == client.held
+                && replayed.frozen == client.frozen
+        });
+        if !in_sync || self.db.last_seq() != seq {
+            return Err(StandbyError::Diverged {
+                seq,
+                client: client.id,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Replays the records of a JSON lines change stream until EOF, returning how many
+    /// were new.
+    ///
+    /// A last line without its newline is kept for the next call, so a stream the leader
+    /// is still appending to can be read again from where this call stopped.
+    pub fn catch_up<R: BufRead>(&mut self, mut reader: R) -> anyhow::Result<u64> {
+        let before = self.db.last_seq();
+        loop {
+            if reader.read_line(&mut self.partial)? == 0 || !self.partial.ends_with('\n') {
+                break;
+            }
+
+            let line = std::mem::take(&mut self.partial);
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.apply(serde_json::from_str(&line)?)?;
+        }
+
+        Ok(self.db.last_seq() - before)
+    }
+
+    /// Replays the change stream read by `reader` every `poll` until `lock` is acquired,
+    /// then replays what's left of it and returns the backend to take over with.
+    pub fn run<R, L>(mut self, mut reader: R, lock: &mut L, poll: Duration) -> anyhow::Result<DB>
+    where
+        R: BufRead,
+        L: LeaderLock,
+    {
+        loop {
+            self.catch_up(&mut reader)?;
+            if lock.try_acquire()? {
+                // The leader is gone, anything it wrote is already in the stream
+                self.catch_up(&mut reader)?;
+                return Ok(self.db);
+            }
+            thread::sleep(poll);
+        }
+    }
+
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.db
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{memory_processor::InMemoryTransactionDb, replication::ChangeStreamProcessor};
+
+    fn leader_stream() -> (InMemoryTransactionDb, Vec<u8>) {
+        let mut leader = ChangeStreamProcessor::new(InMemoryTransactionDb::new(), Vec::new());
+        leader.deposit(1, 1, dec!(10)).unwrap();
+        leader.deposit(2, 2, dec!(5)).unwrap();
+        leader.withdrawal(3, 1, dec!(20)).unwrap_err();
+        leader.dispute(2, 2).unwrap();
+        leader.into_inner()
+    }
+
+    #[test]
+    fn lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leader.lock");
+
+        let mut leader = FileLeaderLock::new(&path);
+        let mut standby = FileLeaderLock::new(&path);
+        assert!(leader.try_acquire().unwrap());
+        assert!(leader.try_acquire().unwrap());
+        assert!(!standby.try_acquire().unwrap());
+
+        leader.release().unwrap();
+        assert!(standby.try_acquire().unwrap());
+        assert!(!leader.try_acquire().unwrap());
+
+        // Dropping the lock, eg when the process dies, releases it too
+        drop(standby);
+        assert!(leader.try_acquire().unwrap());
+    }
+
+    #[test]
+    fn parse_lock_spec() {
+        assert_eq!(
+            "/shared/leader.lock".parse(),
+            Ok(LeaderLockSpec::File(PathBuf::from("/shared/leader.lock")))
+        );
+        #[cfg(feature = "redis")]
+        {
+            assert_eq!(
+                "redis://locks:6379#ledger-eu".parse(),
+                Ok(LeaderLockSpec::Redis {
+                    url: "redis://locks:6379".to_owned(),
+                    key: "ledger-eu".to_owned()
+                })
+            );
+            let spec: LeaderLockSpec = "rediss://locks".parse().unwrap();
+            assert_eq!(spec.to_string(), "rediss://locks#octopussy:leader");
+        }
+        #[cfg(not(feature = "redis"))]
+        assert!("redis://locks:6379".parse::<LeaderLockSpec>().is_err());
+    }
+
+    #[test]
+    fn held_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = LeaderLockSpec::File(dir.path().join("leader.lock"));
+
+        let held = spec.hold(DrainSignal::new()).unwrap();
+        let err = spec.hold(DrainSignal::new()).err().unwrap();
+        assert!(err.to_string().contains("another instance holds"));
+
+        drop(held);
+        spec.hold(DrainSignal::new()).unwrap();
+    }
+
+    /// A lease which expires after it was renewed `renewals` times
+    struct Lease {
+        renewals: u32,
+    }
+
+    impl LeaderLock for Lease {
+        fn try_acquire(&mut self) -> anyhow::Result<bool> {
+            self.renewals = self.renewals.saturating_sub(1);
+            Ok(self.renewals > 0)
+        }
+
+        fn release(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drains_when_the_lock_is_lost() {
+        let drain = DrainSignal::new();
+        let held = HeldLeaderLock::new(
+            Box::new(Lease { renewals: 3 }),
+            Duration::from_millis(1),
+            drain.clone(),
+        );
+
+        let started = Instant::now();
+        while !drain.is_draining() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(held);
+    }
+
+    #[test]
+    fn replays_the_stream() {
+        let (leader, stream) = leader_stream();
+
+        let mut standby = Standby::new(InMemoryTransactionDb::new());
+        // The leader is halfway through writing a record
+        let cut = stream.len() - 10;
+        assert_eq!(standby.catch_up(&stream[..cut]).unwrap(), 2);
+        assert_eq!(standby.catch_up(&stream[cut..]).unwrap(), 1);
+        // Re-reading it is a no-op
+        assert_eq!(standby.catch_up(stream.as_slice()).unwrap(), 0);
+
+        let mut db = standby.into_inner();
+        let clients = |db: &InMemoryTransactionDb| {
+            let mut clients: Vec<_> = db.clients_iter().collect();
+            clients.sort_by_key(|client| client.id);
+            clients
+        };
+        assert_eq!(clients(&db), clients(&leader));
+        // With the history, so the standby can carry on where the leader stopped
+        db.chargeback(2, 2).unwrap();
+    }
+
+    #[test]
+    fn divergence() {
+        let (_, stream) = leader_stream();
+        let mut records = stream
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<ChangeRecord>(line).unwrap());
+
+        let mut standby = Standby::new(InMemoryTransactionDb::new());
+        let first = records.next().unwrap();
+        let mut tampered = first.clone();
+        tampered.client.available = dec!(11);
+        assert_eq!(
+            standby.apply(tampered),
+            Err(StandbyError::Diverged { seq: 1, client: 1 })
+        );
+
+        let mut standby = Standby::new(InMemoryTransactionDb::new());
+        assert_eq!(
+            standby.apply(records.next().unwrap()),
+            Err(StandbyError::Gap(ReplicationError::Gap {
+                expected: 1,
+                got: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn takes_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leader.lock");
+        let (_, stream) = leader_stream();
+
+        let mut leader = FileLeaderLock::new(&path);
+        assert!(leader.try_acquire().unwrap());
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            leader.release().unwrap();
+        });
+
+        let mut lock = FileLeaderLock::new(&path);
+        let db = Standby::new(InMemoryTransactionDb::new())
+            .run(stream.as_slice(), &mut lock, Duration::from_millis(5))
+            .unwrap();
+        handle.join().unwrap();
+
+        assert!(lock.is_held());
+        assert_eq!(db.last_seq(), 3);
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::{
+    archive::ArchivedTransaction,
+    transaction::{ClientId, TransactionId},
+};
+
+/// zstd's default level, a good tradeoff for blocks which are rarely read back
+const COMPRESSION_LEVEL: i32 = 0;
+
+/// Transactions of one client, as zstd-compressed JSON
+struct ColdBlock {
+    data: Vec<u8>,
+    len: usize,
+    /// Lowest and highest transaction ids in the block, so most lookups of recent
+    /// transactions don't have to decompress it
+    first: TransactionId,
+    last: TransactionId,
+}
+
+impl ColdBlock {
+    fn encode(transactions: &[ArchivedTransaction]) -> Self {
+        let json = serde_json::to_vec(transactions).expect("transactions serialize to JSON");
+
+        Self {
+            data: zstd::bulk::compress(&json, COMPRESSION_LEVEL)
+                .expect("compressing into memory can't fail"),
+            len: transactions.len(),
+            first: transactions.iter().map(|t| t.tx).min().unwrap_or_default(),
+            last: transactions.iter().map(|t| t.tx).max().unwrap_or_default(),
+        }
+    }
+
+    fn decode(&self) -> Vec<ArchivedTransaction> {
+        let json = zstd::decode_all(self.data.as_slice()).expect("cold blocks are valid zstd");
+        serde_json::from_slice(&json).expect("cold blocks hold archived transactions")
+    }
+}
+
+/// The cold part of an in-memory transaction history: one zstd-compressed block per
+/// client, traded for CPU when a transaction in it has to be read back.
+///
+/// Compressing a client's transactions together works much better than compressing them
+/// one by one, since they share their client id and their amounts tend to repeat.
+#[derive(Default)]
+pub struct ColdHistory {
+    blocks: HashMap<ClientId, ColdBlock>,
+    len: usize,
+    bytes: usize,
+}
+
+impl ColdHistory {
+    /// Number of transactions in all blocks
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the compressed blocks, in bytes
+    pub fn compressed_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds transactions to `client_id`'s block, recompressing it if it already exists.
+    pub fn insert(&mut self, client_id: ClientId, mut transactions: Vec<ArchivedTransaction>) {
+        if transactions.is_empty() {
+            return;
+        }
+
+        transactions.extend(self.take(client_id));
+        transactions.sort_unstable_by_key(|transaction| transaction.tx);
+        self.put(client_id, &transactions);
+    }
+
+    /// Whether `client_id`'s block holds `transaction_id`
+    pub fn contains(&self, client_id: ClientId, transaction_id: TransactionId) -> bool {
+        self.blocks.get(&client_id).is_some_and(|block| {
+            (block.first..=block.last).contains(&transaction_id)
+                && block
+                    .decode()
+                    .iter()
+                    .any(|transaction| transaction.tx == transaction_id)
+        })
+    }
+
+    /// `client_id`'s transactions, ordered by id
+    pub fn get(&self, client_id: ClientId) -> Vec<ArchivedTransaction> {
+        self.blocks
+            .get(&client_id)
+            .map(ColdBlock::decode)
+            .unwrap_or_default()
+    }
+
+    /// Removes `client_id`'s block and returns its transactions, ordered by id.
+    pub fn take(&mut self, client_id: ClientId) -> Vec<ArchivedTransaction> {
+        let Some(block) = self.blocks.remove(&client_id) else {
+            return Vec::new();
+        };
+        self.len -= block.len;
+        self.bytes -= block.data.len();
+
+        block.decode()
+    }
+
+    /// Keeps the transactions for which `keep` returns true and returns the others.
+    ///
+    /// Every block is decompressed, and the ones which changed are compressed again.
+    pub fn retain<F>(&mut self, mut keep: F) -> Vec<ArchivedTransaction>
+    where
+        F: FnMut(&ArchivedTransaction) -> bool,
+    {
+        let clients: Vec<_> = self.blocks.keys().copied().collect();
+        let mut removed = Vec::new();
+
+        for client_id in clients {
+            let (kept, dropped): (Vec<_>, Vec<_>) = self.blocks[&client_id]
+                .decode()
+                .into_iter()
+                .partition(&mut keep);
+            if dropped.is_empty() {
+                continue;
+            }
+
+            self.take(client_id);
+            if !kept.is_empty() {
+                self.put(client_id, &kept);
+            }
+            removed.extend(dropped);
+        }
+
+        removed
+    }
+
+    /// Decompresses every block, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = ArchivedTransaction> + '_ {
+        self.blocks.values().flat_map(ColdBlock::decode)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn put(&mut self, client_id: ClientId, transactions: &[ArchivedTransaction]) {
+        let block = ColdBlock::encode(transactions);
+        self.len += block.len;
+        self.bytes += block.data.len();
+        self.blocks.insert(client_id, block);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+
+    fn transaction(client: ClientId, tx: TransactionId) -> ArchivedTransaction {
+        ArchivedTransaction {
+            client,
+            tx,
+            amount: dec!(10.5),
+            charged_back: false,
+            recorded_at: u64::from(tx),
+            created_at: 1_700_000_000,
+            seq: u64::from(tx),
+            disputes: 0,
+        }
+    }
+
+    #[test]
+    fn insert_and_take() {
+        let mut history = ColdHistory::default();
+        history.insert(1, vec![transaction(1, 4), transaction(1, 2)]);
+        history.insert(1, vec![transaction(1, 3)]);
+        history.insert(2, vec![transaction(2, 1)]);
+
+        assert_eq!(history.len(), 4);
+        assert!(history.contains(1, 3));
+        assert!(!history.contains(1, 1));
+        assert!(!history.contains(2, 2));
+
+        assert_eq!(
+            history.take(1),
+            vec![transaction(1, 2), transaction(1, 3), transaction(1, 4)]
+        );
+        assert_eq!(history.len(), 1);
+        assert!(history.take(1).is_empty());
+    }
+
+    #[test]
+    fn retain() {
+        let mut history = ColdHistory::default();
+        history.insert(1, vec![transaction(1, 1), transaction(1, 2)]);
+        history.insert(2, vec![transaction(2, 3)]);
+
+        let removed = history.retain(|transaction| transaction.tx != 3 && transaction.tx != 1);
+
+        assert_eq!(removed.len(), 2);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.iter().collect::<Vec<_>>(), vec![transaction(1, 2)]);
+    }
+
+    #[test]
+    fn compresses() {
+        let mut history = ColdHistory::default();
+        let transactions: Vec<_> = (0..1000).map(|tx| transaction(1, tx)).collect();
+        let json = serde_json::to_vec(&transactions).unwrap();
+        history.insert(1, transactions);
+
+        assert!(history.compressed_bytes() * 10 < json.len());
+    }
+}
@@ -0,0 +1,35 @@
+use octopussy::{
+    amqp::{AmqpConfig, AmqpSource},
+    encryption::EncryptionKey,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::consumer::run_consumer;
+use crate::AmqpArgs;
+
+pub(crate) fn run(
+    args: &AmqpArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let config = AmqpConfig {
+        uri: args.uri.clone(),
+        queue: args.queue.clone(),
+        prefetch: args.prefetch,
+        format: args.consumer.payload.into(),
+    };
+    let mut source = AmqpSource::connect(&config)?;
+
+    run_consumer(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, save, drain, poll| {
+            info!("Consuming {}", args.queue);
+            source.run(db, save, drain, poll)
+        },
+    )
+}
@@ -0,0 +1,20 @@
+use std::{io::Write, path::Path};
+
+use octopussy::{approval::ApprovalQueue, encryption::EncryptionKey};
+
+use super::print_json;
+
+pub(crate) fn run(store: &Path, key: Option<&EncryptionKey>, json: bool) -> anyhow::Result<()> {
+    let queue = ApprovalQueue::load(store, key)?;
+    if json {
+        return print_json(&queue.pending().collect::<Vec<_>>());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    for approval in queue.pending() {
+        serde_json::to_writer(&mut stdout, approval)?;
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
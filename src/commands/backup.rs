@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use octopussy::{
+    backend::BackendSpec, backup::BackupArchive, encryption::EncryptionKey,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    from: &BackendSpec,
+    archive: &Path,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let store = from.open(key, format)?;
+    let backup = BackupArchive::create(store.as_ref(), None)?;
+    backup.write(archive, key)?;
+
+    let totals = backup.snapshot.totals();
+    if json {
+        print_json(&serde_json::json!({
+            "from": from.to_string(),
+            "archive": archive,
+            "totals": totals,
+        }))?;
+    }
+    info!(
+        "Backed up {} clients and {} transactions to {}",
+        totals.clients,
+        totals.transactions,
+        archive.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use anyhow::bail;
+#[cfg(feature = "http")]
+use octopussy::bench::HttpTarget;
+#[cfg(feature = "grpc")]
+use octopussy::remote::RemoteTransactionProcessor;
+use octopussy::{
+    bench::{self, BenchConfig, BenchReport},
+    memory_processor::InMemoryTransactionDb,
+};
+#[cfg(any(feature = "grpc", feature = "http"))]
+use tracing::info;
+
+use super::print_json;
+#[cfg(any(feature = "grpc", feature = "http"))]
+use crate::TargetApi;
+
+/// Runs the load of `config` in process, or through the engine served at `target`, and
+/// reports it
+pub(crate) fn run(
+    config: &BenchConfig,
+    #[cfg(any(feature = "grpc", feature = "http"))] target: Option<&str>,
+    #[cfg(any(feature = "grpc", feature = "http"))] target_api: TargetApi,
+    slo_p99: Option<Duration>,
+    json: bool,
+) -> anyhow::Result<()> {
+    #[cfg(any(feature = "grpc", feature = "http"))]
+    let remote = target
+        .map(|url| bench_target(target_api, url, config).map(|report| (url, report)))
+        .transpose()?;
+    #[cfg(not(any(feature = "grpc", feature = "http")))]
+    let remote = None;
+    let (target, report) = match remote {
+        Some(remote) => remote,
+        None => (
+            "in-process",
+            bench::run(&mut InMemoryTransactionDb::new(), config)?,
+        ),
+    };
+    print_report(target, &report, slo_p99, json)
+}
+
+/// Drives the load of `config` through the engine served at `url`
+#[cfg(any(feature = "grpc", feature = "http"))]
+fn bench_target(api: TargetApi, url: &str, config: &BenchConfig) -> anyhow::Result<BenchReport> {
+    info!("Driving {} operations through {url}", config.operations);
+    match api {
+        #[cfg(feature = "http")]
+        TargetApi::Http => bench::run(&mut HttpTarget::connect(url)?, config),
+        #[cfg(feature = "grpc")]
+        TargetApi::Grpc => bench::run(&mut RemoteTransactionProcessor::connect(url)?, config),
+    }
+}
+
+/// Prints the report of a run against `target`, and fails if it's above the SLO
+fn print_report(
+    target: &str,
+    report: &BenchReport,
+    slo_p99: Option<Duration>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let violations = slo_p99
+        .map(|slo| report.slo_violations(slo))
+        .unwrap_or_default();
+
+    if json {
+        print_json(&serde_json::json!({
+            "target": target,
+            "summary": report.summary(),
+            "slo_violations": violations.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        }))?;
+    } else {
+        println!("Against {target}");
+        print!("{report}");
+    }
+
+    if !violations.is_empty() {
+        bail!(
+            "{}",
+            violations
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
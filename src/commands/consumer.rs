@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use octopussy::{
+    cursor::OffsetProcessor,
+    drain::DrainSignal,
+    encryption::EncryptionKey,
+    i18n::Message,
+    memory_processor::InMemoryTransactionDb,
+    metrics::RunMetrics,
+    snapshot::{SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    stream::SaveState,
+    transaction::TransactionProcessor,
+};
+use tracing::info;
+
+use super::{drain_on_signals, print_json};
+use crate::ConsumerArgs;
+
+/// The DB of broker consumers, which keeps the positions in the source along the state
+pub(crate) type ConsumerDb = OffsetProcessor<InMemoryTransactionDb>;
+
+/// Runs a broker consumer on the state of `args`, and saves it once the consumer stops.
+/// `consume` gets a function saving the state, for consumers which acknowledge messages only
+/// once it's saved. Kinesis saves its shard checkpoints after the state, with
+/// [`run_consumer_then`]
+#[cfg(any(
+    feature = "amqp",
+    feature = "kafka",
+    feature = "nats",
+    feature = "redis",
+    feature = "sqs"
+))]
+pub(crate) fn run_consumer<F>(
+    args: &ConsumerArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+    consume: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(
+        &mut ConsumerDb,
+        &mut SaveState<'_, ConsumerDb>,
+        &DrainSignal,
+        Duration,
+    ) -> anyhow::Result<RunMetrics>,
+{
+    run_consumer_then(args, key, format, json, consume, || Ok(()))
+}
+
+/// Like [`run_consumer`], calling `saved` once the state was saved, eg to save the positions
+/// in the source which go with it
+pub(crate) fn run_consumer_then<F, S>(
+    args: &ConsumerArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+    consume: F,
+    saved: S,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(
+        &mut ConsumerDb,
+        &mut SaveState<'_, ConsumerDb>,
+        &DrainSignal,
+        Duration,
+    ) -> anyhow::Result<RunMetrics>,
+    S: FnOnce() -> anyhow::Result<()>,
+{
+    // A missing file is an empty state, as on the first run
+    let mut state = args.state.as_ref().map(|path| {
+        let file = SnapshotFile::new(path)
+            .encrypted(key.cloned())
+            .with_format(format);
+        (path, file)
+    });
+    let mut db = OffsetProcessor::new(InMemoryTransactionDb::new());
+    if let Some((_, file)) = &state {
+        db.restore(file.snapshot()?)?;
+        info!("Starting from record {}", db.last_seq());
+    }
+    let mut save = |db: &ConsumerDb| -> anyhow::Result<()> {
+        if let Some((path, file)) = &mut state {
+            file.restore(db.snapshot()?)
+                .with_context(|| format!("failed to save the state to {}", path.display()))?;
+        }
+        Ok(())
+    };
+
+    let drain = drain_on_signals()?;
+    let result = consume(
+        &mut db,
+        &mut save,
+        &drain,
+        Duration::from_millis(args.poll_interval),
+    );
+    // Saved even if the consumer failed, the offsets of what was applied are committed
+    save(&db)?;
+    saved()?;
+    let metrics = result?;
+
+    let latencies = &metrics.latencies;
+    info!(
+        "{}\n{latencies}",
+        Message::new("processed-events").arg("count", latencies.count())
+    );
+    if json {
+        print_json(&serde_json::json!({
+            "events": latencies.count(),
+            "last_seq": db.last_seq(),
+        }))?;
+    }
+
+    Ok(())
+}
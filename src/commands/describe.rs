@@ -0,0 +1,25 @@
+use octopussy::state_machine::{ACCOUNT_MACHINE, TRANSACTION_MACHINE};
+
+use super::print_json;
+use crate::DiagramFormat;
+
+pub(crate) fn run(format: DiagramFormat, json: bool) -> anyhow::Result<()> {
+    let (account, transaction) = match format {
+        DiagramFormat::Dot => (ACCOUNT_MACHINE.to_dot(), TRANSACTION_MACHINE.to_dot()),
+        DiagramFormat::Mermaid => (
+            ACCOUNT_MACHINE.to_mermaid(),
+            TRANSACTION_MACHINE.to_mermaid(),
+        ),
+    };
+
+    if json {
+        return print_json(&serde_json::json!({
+            "account": account,
+            "transaction": transaction,
+        }));
+    }
+
+    println!("{account}");
+    print!("{transaction}");
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use octopussy::{
+    backend::BackendSpec, compression::InputReader, diff::Report, encryption::EncryptionKey,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    expected_path: &Path,
+    actual_path: Option<&Path>,
+    backend: Option<&BackendSpec>,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let read = |path: &Path| {
+        InputReader::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(Report::read)
+            .with_context(|| format!("failed to read {}", path.display()))
+    };
+    let expected = read(expected_path)?;
+    let (actual, name) = match (actual_path, backend) {
+        (Some(path), _) => (read(path)?, path.display().to_string()),
+        (None, Some(backend)) => {
+            let store = backend.open(key, format)?;
+            (Report::from(&store.snapshot()?), backend.to_string())
+        }
+        (None, None) => unreachable!("clap requires a report or a backend"),
+    };
+    let differences = expected.diff(&actual);
+
+    if json {
+        print_json(&serde_json::json!({
+            "expected": expected_path,
+            "actual": name,
+            "same": differences.is_empty(),
+            "differences": differences,
+        }))?;
+    } else {
+        for difference in &differences {
+            println!("{difference}");
+        }
+    }
+    if !differences.is_empty() {
+        bail!(
+            "{name} has {} differences from {}",
+            differences.len(),
+            expected_path.display()
+        );
+    }
+
+    info!(
+        "All {} clients of {name} are the same as in {}",
+        actual.len(),
+        expected_path.display()
+    );
+    Ok(())
+}
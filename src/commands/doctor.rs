@@ -0,0 +1,28 @@
+use anyhow::bail;
+use octopussy::doctor::{self, DoctorConfig, Status};
+
+use super::print_json;
+
+pub(crate) fn run(config: &DoctorConfig, json: bool) -> anyhow::Result<()> {
+    let diagnostics = doctor::run(config);
+    let failed = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.status == Status::Failed)
+        .count();
+
+    if json {
+        print_json(&serde_json::json!({
+            "healthy": failed == 0,
+            "diagnostics": diagnostics,
+        }))?;
+    } else {
+        for diagnostic in &diagnostics {
+            println!("{diagnostic}");
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} checks failed");
+    }
+
+    Ok(())
+}
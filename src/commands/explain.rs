@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use anyhow::Context;
+use octopussy::{
+    compression::InputReader,
+    csv::CsvDialect,
+    explain::{ExplainFilter, explain},
+    memory_processor::InMemoryTransactionDb,
+};
+
+pub(crate) fn run(
+    input: &Path,
+    dialect: CsvDialect,
+    filter: ExplainFilter,
+    json: bool,
+) -> anyhow::Result<()> {
+    let reader =
+        InputReader::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let explanation = explain(
+        dialect.transaction_reader(reader)?,
+        &mut InMemoryTransactionDb::new(),
+        filter,
+    )?;
+
+    if json {
+        explanation.write_json(std::io::stdout().lock())?;
+        println!();
+    } else {
+        print!("{explanation}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::path::Path;
+
+use anyhow::Context;
+use octopussy::{
+    compression::{CompressedWriter, Compression, Destination},
+    generate::{GenerateConfig, generate},
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    config: &GenerateConfig,
+    output: Option<&Path>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let destination = Destination::create(output).with_context(|| {
+        let path = output.unwrap_or(Path::new("-"));
+        format!("failed to create {}", path.display())
+    })?;
+    let compression = output.map_or(Compression::None, Compression::from_extension);
+    let mut destination = CompressedWriter::new(destination, compression)?;
+    let generated = generate(config, csv::Writer::from_writer(&mut destination))?;
+    destination
+        .finish()
+        .and_then(Destination::finish)
+        .context("failed to write the rows")?;
+
+    // The rows are on stdout without `--output`
+    if json && output.is_some() {
+        print_json(&generated)?;
+    }
+    info!(
+        "Generated {} deposits, {} withdrawals, {} disputes, {} resolves and {} chargebacks",
+        generated.deposits,
+        generated.withdrawals,
+        generated.disputes,
+        generated.resolves,
+        generated.chargebacks
+    );
+    Ok(())
+}
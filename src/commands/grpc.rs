@@ -0,0 +1,52 @@
+use anyhow::Context;
+#[cfg(unix)]
+use octopussy::control::DrainSocket;
+use octopussy::{
+    encryption::EncryptionKey,
+    grpc::{EngineServer, EngineService},
+    snapshot_codec::SnapshotFormat,
+    tenant::SingleLedger,
+};
+use tracing::info;
+
+use super::{
+    drain_on_signals, hold_leader_lock,
+    service::{SaveOn, drained, run_service},
+};
+use crate::GrpcArgs;
+
+pub(crate) fn run(
+    args: &GrpcArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    // Released once drained
+    let _leader_lock = hold_leader_lock(args.leader.leader_lock.as_ref(), &drain_on_signals()?)?;
+    let (db, state) = args.backend.open(args.state.as_deref())?;
+    let db = SingleLedger::new(db);
+    let api_calls = db.api_calls();
+    run_service(db, state, SaveOn::Drain, key, format, json, |db, drain| {
+        #[cfg(unix)]
+        let _socket = args
+            .control_socket
+            .as_deref()
+            .map(|path| DrainSocket::bind(path, drain.clone()))
+            .transpose()?;
+        let runtime = tokio::runtime::Runtime::new().context("failed to start the gRPC runtime")?;
+        let served = runtime.block_on(async {
+            info!("Serving gRPC on {}", args.listen);
+            let service = EngineService::new(db.clone())
+                .draining(drain.clone())
+                .counting_api_calls(api_calls);
+            tonic::transport::Server::builder()
+                .add_service(EngineServer::new(service))
+                .serve_with_shutdown(args.listen, drained(drain.clone()))
+                .await
+                .with_context(|| format!("failed to serve gRPC on {}", args.listen))
+        });
+        args.usage
+            .save(db.lock().unwrap_or_else(|err| err.into_inner()).tenants())?;
+        served
+    })
+}
@@ -0,0 +1,34 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::Context;
+use octopussy::transaction::TransactionId;
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    file: &Path,
+    accounts: &Path,
+    first_tx: TransactionId,
+    currency: Option<String>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut mapping = octopussy::iso20022::AccountMapping::load(accounts)?;
+    mapping.currency = currency;
+    let input = File::open(file).with_context(|| format!("failed to open {}", file.display()))?;
+    let entries = octopussy::iso20022::parse(BufReader::new(input))
+        .with_context(|| format!("failed to read {}", file.display()))?;
+    let rows = octopussy::iso20022::to_rows(&entries, &mapping, first_tx)?;
+    info!("Converted {} bank entries", rows.len());
+    if json {
+        return print_json(&rows);
+    }
+
+    let mut csv_writer = csv::Writer::from_writer(std::io::stdout());
+    for row in &rows {
+        csv_writer.serialize(row)?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use octopussy::{
+    encryption::EncryptionKey,
+    kafka::{KafkaConfig, KafkaPublisher, KafkaSource},
+    publish::PublishingProcessor,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::consumer::run_consumer;
+use crate::KafkaArgs;
+
+pub(crate) fn run(
+    args: &KafkaArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let config = KafkaConfig {
+        brokers: args.brokers.clone(),
+        group: args.group.clone(),
+        topic: args.topic.clone(),
+        format: args.consumer.payload.into(),
+        properties: args.properties.clone(),
+    };
+    let mut source = KafkaSource::connect(&config)?;
+    let publisher = args
+        .publish_topic
+        .as_deref()
+        .map(|topic| KafkaPublisher::connect(&config, topic))
+        .transpose()?;
+
+    let position = format!("kafka:{}", args.topic);
+
+    run_consumer(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, _save, drain, poll| {
+            if let Some(offsets) = db.position(&position)? {
+                source.resume(offsets);
+            }
+            info!("Consuming {} from {}", args.topic, args.brokers);
+            let (result, flushed) = match publisher {
+                None => (source.run(db.inner_mut(), drain, poll), Ok(())),
+                Some(publisher) => {
+                    let mut db = PublishingProcessor::new(db.inner_mut(), publisher);
+                    let result = source.run(&mut db, drain, poll);
+                    // Delivers the updates of what was applied, even if the consumer failed
+                    (result, db.flush())
+                }
+            };
+            db.set_position(&position, source.offsets())?;
+            flushed?;
+            result
+        },
+    )
+}
@@ -0,0 +1,47 @@
+use std::cell::RefCell;
+
+use octopussy::{
+    encryption::EncryptionKey,
+    kinesis::{KinesisSource, ShardCheckpoints},
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::consumer::run_consumer_then;
+use crate::KinesisArgs;
+
+pub(crate) fn run(
+    args: &KinesisArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let checkpoints = match &args.checkpoints {
+        Some(path) => ShardCheckpoints::load(path, key)?,
+        None => ShardCheckpoints::default(),
+    };
+    let position = format!("kinesis:{}", args.stream);
+    let reached = RefCell::new(None);
+
+    run_consumer_then(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, _save, drain, poll| {
+            let checkpoints = db.position(&position)?.unwrap_or(checkpoints);
+            let mut source =
+                KinesisSource::connect(&args.stream, args.consumer.payload.into(), checkpoints)?;
+            info!("Consuming {}", args.stream);
+            let result = source.run(db.inner_mut(), drain, poll);
+            db.set_position(&position, source.checkpoints())?;
+            reached.replace(Some(source.checkpoints().clone()));
+            result
+        },
+        // Only once the state is saved, checkpoints ahead of it would skip records
+        || match (&args.checkpoints, reached.take()) {
+            (Some(path), Some(checkpoints)) => checkpoints.save(path, key),
+            _ => Ok(()),
+        },
+    )
+}
@@ -0,0 +1,38 @@
+use anyhow::bail;
+use octopussy::{
+    backend::BackendSpec, encryption::EncryptionKey, migrate::migrate,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    from: &BackendSpec,
+    to: &BackendSpec,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    // Opening a missing backend would migrate an empty state, eg from a mistyped path
+    if !from.exists() {
+        bail!("source backend {from} doesn't exist");
+    }
+    let source = from.open(key, format)?;
+    let mut destination = to.open(key, format)?;
+
+    let totals = migrate(source.as_ref(), destination.as_mut())?;
+    if json {
+        print_json(&serde_json::json!({
+            "from": from.to_string(),
+            "to": to.to_string(),
+            "totals": totals,
+        }))?;
+    }
+    info!(
+        "Migrated {} clients and {} transactions (available {}, held {})",
+        totals.clients, totals.transactions, totals.available, totals.held
+    );
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! The runners of the subcommands, one module each, called by `main` with their parsed
+//! arguments, and the helpers they share
+
+use std::{io::Write, path::Path, sync::OnceLock};
+
+use anyhow::Context;
+use octopussy::{
+    cluster::{HeldLeaderLock, LeaderLockSpec},
+    csv::write_usage_report,
+    drain::DrainSignal,
+    tenant::{StorageFootprint, TenantDb},
+    transaction::TransactionProcessor,
+};
+use serde::Serialize;
+use tracing::info;
+
+#[cfg(feature = "amqp")]
+pub(crate) mod amqp;
+pub(crate) mod approvals;
+pub(crate) mod backup;
+pub(crate) mod bench;
+#[cfg(any(
+    feature = "amqp",
+    feature = "kafka",
+    feature = "kinesis",
+    feature = "nats",
+    feature = "redis",
+    feature = "sqs"
+))]
+mod consumer;
+pub(crate) mod describe;
+pub(crate) mod diff;
+pub(crate) mod doctor;
+pub(crate) mod explain;
+pub(crate) mod generate;
+#[cfg(feature = "grpc")]
+pub(crate) mod grpc;
+#[cfg(feature = "iso20022")]
+pub(crate) mod iso20022;
+#[cfg(feature = "kafka")]
+pub(crate) mod kafka;
+#[cfg(feature = "kinesis")]
+pub(crate) mod kinesis;
+pub(crate) mod migrate;
+#[cfg(feature = "nats")]
+pub(crate) mod nats;
+pub(crate) mod process;
+pub(crate) mod pseudonymize;
+#[cfg(feature = "redis")]
+pub(crate) mod redis;
+pub(crate) mod replica;
+pub(crate) mod restore;
+pub(crate) mod scenario;
+#[cfg(feature = "server")]
+pub(crate) mod serve;
+mod service;
+pub(crate) mod simulate_fees;
+pub(crate) mod split;
+#[cfg(feature = "sqs")]
+pub(crate) mod sqs;
+pub(crate) mod standby;
+pub(crate) mod tcp;
+pub(crate) mod validate;
+pub(crate) mod verify;
+pub(crate) mod watch;
+
+/// The drain every run of the process shares, requested by SIGINT or SIGTERM
+static SIGNALS: OnceLock<DrainSignal> = OnceLock::new();
+
+/// Whether SIGINT or SIGTERM stopped the runs of the process before they got to the end
+pub(crate) fn interrupted() -> bool {
+    SIGNALS.get().is_some_and(DrainSignal::is_draining)
+}
+
+/// Prints the result of a subcommand run with `--json`, on a single line
+pub(crate) fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer(&mut stdout, value)?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+/// Drains runs on SIGINT or SIGTERM (Ctrl-C on Windows), eg when a deploy replaces the
+/// engine: they stop taking events and save what they got to, and the process exits with
+/// [`EXIT_INTERRUPTED`](crate::EXIT_INTERRUPTED). A second signal exits right away.
+pub(crate) fn drain_on_signals() -> anyhow::Result<DrainSignal> {
+    if let Some(drain) = SIGNALS.get() {
+        return Ok(drain.clone());
+    }
+
+    let drain = SIGNALS.get_or_init(DrainSignal::new).clone();
+    let handler = drain.clone();
+    ctrlc::set_handler(move || {
+        if handler.drain() {
+            std::process::exit(130);
+        }
+        info!("Draining, send the signal again to exit right away");
+    })
+    .context("failed to install the signal handler")?;
+
+    Ok(drain)
+}
+
+/// Takes the leader lock of `spec` if there's one, held until the returned lock is dropped.
+/// Losing it requests `drain`.
+pub(crate) fn hold_leader_lock(
+    spec: Option<&LeaderLockSpec>,
+    drain: &DrainSignal,
+) -> anyhow::Result<Option<HeldLeaderLock>> {
+    spec.map(|spec| {
+        let held = spec.hold(drain.clone())?;
+        info!("Holding the leader lock {spec}");
+        Ok(held)
+    })
+    .transpose()
+}
+
+/// Writes the usage of every tenant of `db` to the CSV file at `path`
+pub(crate) fn save_usage_report<DB>(path: &Path, db: &TenantDb<DB>) -> anyhow::Result<()>
+where
+    DB: TransactionProcessor + StorageFootprint,
+{
+    let writer = csv::WriterBuilder::default()
+        .has_headers(true)
+        .from_path(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+
+    write_usage_report(writer, db)
+}
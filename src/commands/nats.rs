@@ -0,0 +1,71 @@
+use octopussy::{
+    cursor::CursorProcessor,
+    encryption::EncryptionKey,
+    nats::{NatsConfig, NatsConnection, NatsPublisher, NatsSource},
+    publish::PublishingProcessor,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::consumer::run_consumer;
+use crate::NatsArgs;
+
+pub(crate) fn run(
+    args: &NatsArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let config = NatsConfig {
+        server: args.server.clone(),
+        stream: args.stream.clone(),
+        durable: args.durable.clone(),
+        subjects: args.subjects.clone(),
+        format: args.consumer.payload.into(),
+    };
+    let connection = NatsConnection::connect(&config.server)?;
+    let publisher = args
+        .publish_subject
+        .as_deref()
+        .map(|subject| NatsPublisher::new(&connection, subject));
+
+    let position = format!("nats:{}", args.stream);
+
+    run_consumer(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, _save, drain, poll| {
+            let sequence = db.position(&position)?;
+            // Without a saved state, there's nothing to rewind the durable consumer to
+            let resume = args
+                .consumer
+                .state
+                .is_some()
+                .then(|| sequence.unwrap_or_default());
+            let mut source = NatsSource::connect(&connection, &config, resume)?;
+            info!("Consuming {} from {}", args.stream, args.server);
+            let (result, flushed, sequence) = match publisher {
+                None => {
+                    let mut db = CursorProcessor::since(db.inner_mut(), sequence);
+                    let result = source.run(&mut db, drain, poll);
+                    (result, Ok(()), db.cursor())
+                }
+                Some(publisher) => {
+                    let db = PublishingProcessor::new(db.inner_mut(), publisher);
+                    let mut db = CursorProcessor::since(db, sequence);
+                    let result = source.run(&mut db, drain, poll);
+                    let sequence = db.cursor();
+                    // Delivers the updates of what was applied, even if the consumer failed
+                    (result, db.into_inner().flush(), sequence)
+                }
+            };
+            if let Some(sequence) = sequence {
+                db.set_position(&position, &sequence)?;
+            }
+            flushed?;
+            result
+        },
+    )
+}
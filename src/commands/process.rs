@@ -0,0 +1,891 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, LineWriter, Write},
+    num::NonZeroUsize,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use octopussy::control::{ControlSocket, ControlledProcessor};
+#[cfg(feature = "webhooks")]
+use octopussy::publish::PublishingProcessor;
+#[cfg(feature = "grpc")]
+use octopussy::remote::RemoteTransactionProcessor;
+#[cfg(feature = "webhooks")]
+use octopussy::webhook::{HttpTransport, RetryPolicy, WebhookPublisher};
+use octopussy::{
+    aggregate::{AggregationPolicy, BalanceBucket, aggregate, write_buckets},
+    approval::AdminProcessor,
+    archive::AccountArchive,
+    checkpoint::{CheckpointConfig, InputFingerprint},
+    clock::{Clock, SimulatedClock},
+    compression::{CompressedWriter, Compression, Destination, InputReader, is_stdin},
+    csv::{
+        CsvDialect, EmitPolicy, csv_processor_checkpointed, csv_processor_checkpointed_seeking,
+        csv_processor_draining, csv_processor_multi_tenant_draining,
+    },
+    delta_stream::DeltaStreamProcessor,
+    detect::{DetectedFormat, detect},
+    dispute_timeline::DisputeTimelineProcessor,
+    drain::DrainSignal,
+    encryption::EncryptionKey,
+    export::OutputWriter,
+    fixed_width::{RecordSpec, fixed_width_processor_draining},
+    flush::{FlushPolicy, LagBoundedWriter},
+    handoff::{check_sequence, first_csv_sequence, first_jsonl_sequence},
+    i18n::Message,
+    json::jsonl_processor_draining,
+    ledger::LedgerProcessor,
+    memory_processor::{CompactionPolicy, InMemoryTransactionDb},
+    metrics::RunMetrics,
+    replication::ChangeStreamProcessor,
+    report::{ActivityHeatmap, RunSummary, TopReport},
+    schema::{OutputColumn, OutputSchema, SchemaWriter, TransactionCounter, TransactionCounts},
+    screening::{FileScreener, ScreeningPolicy, ScreeningProcessor},
+    snapshot::{Snapshot, SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    sql::SqlStatement,
+    state_machine::MaxDisputeCount,
+    tenant::{TenantDb, TenantId},
+    transaction::{ClientInformation, TransactionProcessor},
+};
+use tracing::info;
+
+use super::{hold_leader_lock, print_json, save_usage_report};
+use crate::{InputFormat, OutputFormat, ProcessArgs};
+
+/// Stops the run between two events once `drain` is requested, and writes the balances up to
+/// there
+pub(crate) fn run(
+    mut args: ProcessArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+    drain: &DrainSignal,
+) -> anyhow::Result<()> {
+    let mut inputs = octopussy::inputs::expand(&args.input)?;
+    if inputs.is_empty() {
+        bail!("No file path passed to CLI");
+    }
+    if inputs.len() > 1 && inputs.iter().any(|path| is_stdin(path)) {
+        bail!("stdin can't be read along other inputs");
+    }
+    let file_path = inputs.remove(0);
+
+    // Held until the run is done
+    let _leader_lock = hold_leader_lock(args.leader_lock.as_ref(), drain)?;
+
+    info!("Opening file file: {}", file_path.display());
+    let mut reader = InputReader::open(&file_path)
+        .with_context(|| format!("failed to open {}", file_path.display()))?;
+    if reader.compression() != Compression::None {
+        info!("Decompressing {:?} input", reader.compression());
+    }
+    if args.input_format == InputFormat::Auto {
+        let header = reader
+            .fill_buf()
+            .with_context(|| format!("failed to read {}", file_path.display()))?;
+        let detected = detect(header);
+        info!("Detected {} input", detected.as_str());
+        args.input_format = detected_input_format(detected)?;
+    }
+    // Checkpoints are only checked against single local files, chains and remote inputs
+    // aren't fingerprinted
+    let single_file = inputs.is_empty() && file_path.is_file();
+    if !inputs.is_empty() {
+        let headers = match args.input_format {
+            InputFormat::Csv => !args.dialect.no_headers,
+            InputFormat::Jsonl => false,
+            _ => bail!("several inputs are only supported for CSV and JSON lines input"),
+        };
+        let terminator = args.dialect.terminator.unwrap_or(b'\n');
+        info!("Reading {} more inputs after it", inputs.len());
+        reader = reader.chain(&file_path, inputs, headers, terminator)?;
+    }
+
+    if args.input_format != InputFormat::Csv && (args.checkpoint.is_some() || args.multi_tenant) {
+        bail!("--checkpoint and --multi-tenant only support CSV input");
+    }
+    #[cfg(feature = "pipeline")]
+    if args.pipeline_capacity.is_some() && args.input_format != InputFormat::Csv {
+        bail!("--pipeline-capacity only supports CSV input");
+    }
+    let emit = EmitPolicy {
+        every: args.emit_every,
+        interval: args.emit_interval.map(Duration::from_secs),
+    };
+    if emit.is_enabled() && (args.input_format != InputFormat::Csv || args.multi_tenant) {
+        bail!("--emit-every and --emit-interval only support CSV input, without --multi-tenant");
+    }
+    if is_stdin(&file_path) && args.checkpoint.is_some() {
+        bail!("--checkpoint can't resume stdin, which can't be read again");
+    }
+
+    let output_format = args.output_format.unwrap_or(if json {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Csv
+    });
+    let destination = Destination::create(args.output.as_deref()).with_context(|| {
+        let path = args.output.as_deref().unwrap_or(Path::new("-"));
+        format!("failed to create {}", path.display())
+    })?;
+    let compression = match (args.output_compression, &args.output) {
+        (Some(compression), _) => compression.into(),
+        (None, Some(path)) => Compression::from_extension(path),
+        (None, None) => Compression::None,
+    };
+    let mut destination = CompressedWriter::new(destination, compression)?;
+    // Only the aggregates are written with `--aggregate`, and nothing but the summary goes
+    // to stdout with `report`
+    let discard = args.report && args.output.is_none();
+    let rows: Box<dyn Write + '_> = if args.aggregate.is_some() || discard {
+        Box::new(std::io::sink())
+    } else {
+        Box::new(&mut destination)
+    };
+    let sql_statement = if args.sql_upsert {
+        let key = if args.multi_tenant {
+            vec!["tenant".to_owned(), "client".to_owned()]
+        } else {
+            vec!["client".to_owned()]
+        };
+        SqlStatement::Upsert { key }
+    } else {
+        SqlStatement::Insert
+    };
+    let schema = if args.columns.is_empty() {
+        OutputSchema::balances(args.multi_tenant)
+    } else {
+        OutputSchema::new(args.columns.clone())?
+    };
+    let count_transactions = schema.contains(OutputColumn::TxCount);
+    if count_transactions && args.multi_tenant {
+        bail!("the tx_count column isn't supported with --multi-tenant");
+    }
+    #[cfg(unix)]
+    if args.control_socket.is_some() && args.multi_tenant {
+        bail!("--control-socket isn't supported with --multi-tenant");
+    }
+    if args.dispute_window.is_some() && args.multi_tenant {
+        bail!("--dispute-window isn't supported with --multi-tenant");
+    }
+    if args.account_archive.is_some() && args.multi_tenant {
+        bail!("--account-archive isn't supported with --multi-tenant");
+    }
+    let counts = TransactionCounts::new();
+    let output = SchemaWriter::new(
+        output_format.writer(rows, Some((&args.sql_table, sql_statement.clone())))?,
+        schema,
+    )
+    .with_counts(counts.clone());
+    let record_spec = args
+        .record_spec
+        .as_deref()
+        .map(RecordSpec::load)
+        .transpose()?;
+
+    let checkpoint = match args.checkpoint.clone() {
+        Some(path) => Some(CheckpointConfig {
+            path,
+            every: args.checkpoint_every,
+            key: key.cloned(),
+            drain: Some(drain.clone()),
+            input: single_file
+                .then(|| InputFingerprint::of(&file_path))
+                .transpose()?,
+        }),
+        None => None,
+    };
+
+    let policy = CompactionPolicy {
+        drop_charged_back: args.drop_charged_back,
+        dispute_horizon: args.dispute_horizon,
+        compress_after: args.compress_after,
+        every: args.compact_every,
+    };
+
+    let max_disputes = match args.max_disputes {
+        Some(max) => MaxDisputeCount::Limited(max),
+        None => MaxDisputeCount::Unlimited,
+    };
+
+    let previous = match &args.state_in {
+        Some(path) => Some(load_state(path, &file_path, &args, key, format)?),
+        None => None,
+    };
+    let previous_cursor = previous.as_ref().and_then(|snapshot| snapshot.cursor);
+
+    let RunResult {
+        metrics,
+        top,
+        buckets,
+        summary,
+        state,
+    } = if args.multi_tenant {
+        let mut db = TenantDb::with_factory(move |_| {
+            let mut db = InMemoryTransactionDb::with_compaction_policy(policy.clone());
+            db.set_max_dispute_count(max_disputes);
+            db.set_memory_limit(args.memory_limit);
+            Ok(db)
+        })?;
+        let dialect = CsvDialect::from(&args.dialect);
+        let metrics = csv_processor_multi_tenant_draining(
+            dialect.transaction_reader(reader)?,
+            output,
+            &mut db,
+            drain,
+        )?;
+
+        if let Some(path) = &args.usage_report {
+            save_usage_report(path, &db)?;
+        }
+
+        RunResult::new(&args, metrics, || db.all_clients())
+    } else {
+        let input = Input {
+            reader,
+            output,
+            checkpoint: checkpoint.as_ref(),
+            record_spec: record_spec.as_ref(),
+            dialect: (&args.dialect).into(),
+            emit,
+            drain,
+            #[cfg(feature = "pipeline")]
+            pipeline: args.pipeline_capacity,
+            #[cfg(not(feature = "pipeline"))]
+            pipeline: None,
+        };
+        let counts = count_transactions.then_some(counts);
+        match args.dispute_window {
+            Some(window) => {
+                // Time doesn't go back to before the transactions of the state
+                let start = previous
+                    .iter()
+                    .flat_map(|snapshot| &snapshot.transactions)
+                    .filter_map(|transaction| transaction.created_at)
+                    .max()
+                    .unwrap_or_default();
+                let mut db = InMemoryTransactionDb::with_clock(SimulatedClock::new(start));
+                db.set_compaction_policy(policy);
+                db.set_dispute_window(Some(window));
+                process_single_ledger(db, previous, max_disputes, counts, input, &args)?
+            }
+            None => {
+                let db = InMemoryTransactionDb::with_compaction_policy(policy);
+                process_single_ledger(db, previous, max_disputes, counts, input, &args)?
+            }
+        }
+    };
+    if let Some(buckets) = buckets.as_ref().filter(|_| !discard) {
+        write_buckets(
+            output_format.writer(&mut destination, Some((&args.sql_table, sql_statement)))?,
+            buckets,
+        )?;
+    }
+    if let Some(records) = metrics.drained_at
+        && args.checkpoint.is_some()
+    {
+        // The balances are written by the run which gets to the end, until then `--output`
+        // keeps its previous version
+        drop(destination);
+        info!("Drained after {records} rows, run again with the same --checkpoint to resume");
+        return Ok(());
+    }
+    destination
+        .finish()
+        .and_then(Destination::finish)
+        .context("failed to write the client balances")?;
+    if let Some(records) = metrics.drained_at {
+        info!("Interrupted after {records} rows, the balances written are the ones up to there");
+    }
+    if let (Some(path), Some(mut snapshot)) = (&args.state_out, state) {
+        snapshot.cursor = metrics.last_sequence.or(previous_cursor);
+        SnapshotFile::new(path)
+            .encrypted(key.cloned())
+            .with_format(format)
+            .restore(snapshot)
+            .with_context(|| format!("failed to save the state to {}", path.display()))?;
+    }
+
+    let latencies = &metrics.latencies;
+    info!(
+        "{}\n{latencies}{}",
+        Message::new("processed-events").arg("count", latencies.count()),
+        top.map(|top| top.to_string()).unwrap_or_default()
+    );
+    if let Some(path) = &args.metrics_file {
+        fs::write(path, latencies.to_prometheus())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    if let Some(path) = &args.heatmap {
+        let heatmap = ActivityHeatmap::new(&metrics.activity, args.heatmap_window)?;
+        let writer = csv::Writer::from_path(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        heatmap.write_csv(writer)?;
+    }
+
+    match &summary {
+        Some(summary) if args.report && json => print_json(summary)?,
+        Some(summary) if args.report => print!("{summary}"),
+        Some(summary) => eprint!("{summary}"),
+        None => {}
+    }
+
+    let rejections: u64 = metrics.rejections.values().sum();
+    if args.strict && rejections > 0 {
+        bail!("{rejections} transactions were rejected");
+    }
+
+    Ok(())
+}
+
+/// The `--input-format` of a detected format, if this build supports it
+pub(crate) fn detected_input_format(detected: DetectedFormat) -> anyhow::Result<InputFormat> {
+    let format = match detected {
+        DetectedFormat::Csv => InputFormat::Csv,
+        DetectedFormat::Jsonl => InputFormat::Jsonl,
+        #[cfg(feature = "arrow")]
+        DetectedFormat::Arrow => InputFormat::Arrow,
+        #[cfg(feature = "avro")]
+        DetectedFormat::Avro => InputFormat::Avro,
+        #[cfg(feature = "parquet")]
+        DetectedFormat::Parquet => InputFormat::Parquet,
+        #[cfg(feature = "xml")]
+        DetectedFormat::Xml => InputFormat::Xml,
+        #[allow(unreachable_patterns)]
+        other => bail!(
+            "the input looks like {}, which this build doesn't support",
+            other.as_str()
+        ),
+    };
+
+    Ok(format)
+}
+
+/// Loads the state of the previous run of a chain, and checks that `input` follows it.
+pub(crate) fn load_state(
+    path: &Path,
+    input: &Path,
+    args: &ProcessArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+) -> anyhow::Result<Snapshot> {
+    // A missing snapshot file is an empty state otherwise, and would silently restart
+    // the chain
+    if !path.exists() {
+        bail!("no state to start from at {}", path.display());
+    }
+    let snapshot = SnapshotFile::new(path)
+        .encrypted(key.cloned())
+        .with_format(format)
+        .snapshot()
+        .with_context(|| format!("failed to load the state from {}", path.display()))?;
+
+    if snapshot.cursor.is_some() {
+        if is_stdin(input) {
+            bail!("stdin can't be read twice to check the sequence of the previous run");
+        }
+        let reader = InputReader::open(input)
+            .with_context(|| format!("failed to open {}", input.display()))?;
+        let first = match args.input_format {
+            InputFormat::Csv => {
+                let dialect = CsvDialect::from(&args.dialect);
+                first_csv_sequence(dialect.transaction_reader(reader)?)?
+            }
+            InputFormat::Jsonl => first_jsonl_sequence(BufReader::new(reader))?,
+            _ => bail!(
+                "the sequence of the previous run can only be checked on CSV and JSON lines input"
+            ),
+        };
+        check_sequence(snapshot.cursor, first)?;
+    }
+
+    Ok(snapshot)
+}
+
+/// The input and outputs of a single ledger run
+struct Input<'a, O> {
+    reader: InputReader,
+    output: O,
+    checkpoint: Option<&'a CheckpointConfig>,
+    record_spec: Option<&'a RecordSpec>,
+    dialect: CsvDialect,
+    /// When to write the balances before the end of CSV input
+    emit: EmitPolicy,
+    /// Stops the input between two events
+    drain: &'a DrainSignal,
+    /// How many CSV rows to decode ahead of the ones being applied, if on a thread of their
+    /// own
+    pipeline: Option<NonZeroUsize>,
+}
+
+/// Applies `input` to `db`, the default ledger, once it's set up like the ledgers of
+/// `--multi-tenant` and restored from `previous`
+fn process_single_ledger<C, O>(
+    mut db: InMemoryTransactionDb<C>,
+    previous: Option<Snapshot>,
+    max_disputes: MaxDisputeCount,
+    counts: Option<TransactionCounts>,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    C: Clock,
+    O: OutputWriter,
+{
+    db.set_max_dispute_count(max_disputes);
+    db.set_memory_limit(args.memory_limit);
+    if let Some(dir) = &args.account_archive {
+        db.set_account_archive(AccountArchive::open(dir)?);
+    }
+    if let Some(snapshot) = previous {
+        db.restore(snapshot)?;
+    }
+
+    process_with_remote(db, counts, input, args)
+}
+
+/// Leaves `db` out if the events are applied on a remote engine
+fn process_with_remote<DB, O>(
+    db: DB,
+    counts: Option<TransactionCounts>,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore + AdminProcessor,
+    O: OutputWriter,
+{
+    #[cfg(feature = "grpc")]
+    if let Some(endpoint) = &args.remote {
+        let remote = RemoteTransactionProcessor::connect(endpoint)?;
+        info!("Applying the events on the remote engine {endpoint}");
+        return process_with_counts(remote, counts, input, args);
+    }
+
+    process_with_control(db, counts, input, args)
+}
+
+fn process_with_control<DB, O>(
+    db: DB,
+    counts: Option<TransactionCounts>,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore + AdminProcessor,
+    O: OutputWriter,
+{
+    #[cfg(unix)]
+    if let Some(path) = &args.control_socket {
+        let socket = ControlSocket::bind(path, input.drain.clone())?;
+        let db = ControlledProcessor::new(db, socket);
+        return process_with_counts(db, counts, input, args);
+    }
+
+    process_with_counts(db, counts, input, args)
+}
+
+fn process_with_counts<DB, O>(
+    db: DB,
+    counts: Option<TransactionCounts>,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    match counts {
+        Some(counts) => process_with_webhooks(TransactionCounter::new(db, counts), input, args),
+        None => process_with_webhooks(db, input, args),
+    }
+}
+
+fn process_with_webhooks<DB, O>(
+    db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    #[cfg(feature = "webhooks")]
+    if let Some(url) = &args.webhook_url {
+        let policy = RetryPolicy {
+            attempts: args.webhook_attempts,
+            backoff: Duration::from_millis(args.webhook_backoff),
+            ..RetryPolicy::default()
+        };
+        let transport = HttpTransport::new(url, Duration::from_secs(args.webhook_timeout));
+        let publisher = WebhookPublisher::new(transport, &args.webhook_events, policy);
+        let mut db = PublishingProcessor::new(db, publisher);
+        let result = process_with_screening(&mut db, input, args)?;
+        db.flush()
+            .context("failed to deliver the webhook notifications")?;
+        return Ok(result);
+    }
+
+    process_with_screening(db, input, args)
+}
+
+fn process_with_screening<DB, O>(
+    db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let (Some(list), Some(audit)) = (&args.screening_list, &args.screening_audit) else {
+        return process_with_change_stream(db, input, args);
+    };
+
+    let screener = FileScreener::load(list)?;
+    let audit =
+        File::create(audit).with_context(|| format!("failed to create {}", audit.display()))?;
+    let policy = ScreeningPolicy {
+        large_transaction: args.screening_threshold,
+        on_match: args.on_screening_match.into(),
+    };
+    let db = ScreeningProcessor::new(db, screener, policy, LineWriter::new(audit));
+
+    process_with_change_stream(db, input, args)
+}
+
+fn process_with_change_stream<DB, O>(
+    db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let Some(path) = &args.change_stream else {
+        return process_with_delta_stream(db, input, args);
+    };
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    process_with_delta_stream(
+        ChangeStreamProcessor::new(db, LagBoundedWriter::new(file, stream_flush_policy(args))),
+        input,
+        args,
+    )
+}
+
+fn stream_flush_policy(args: &ProcessArgs) -> FlushPolicy {
+    FlushPolicy {
+        every: args.stream_flush_every,
+        max_lag: args.stream_max_lag.map(Duration::from_millis),
+    }
+}
+
+fn process_with_delta_stream<DB, O>(
+    db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let Some(path) = &args.delta_stream else {
+        return process_with_ledger(db, input, args);
+    };
+
+    let writer: Box<dyn Write + Send> = if path.as_os_str() == "-" {
+        if args.output.is_none() {
+            bail!("--delta-stream - needs --output, the balances are written to stdout otherwise");
+        }
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?,
+        )
+    };
+    process_with_ledger(
+        DeltaStreamProcessor::new(db, LagBoundedWriter::new(writer, stream_flush_policy(args))),
+        input,
+        args,
+    )
+}
+
+fn process_with_ledger<DB, O>(
+    db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let Some(path) = &args.ledger else {
+        return process_with_dispute_timeline(db, input, args);
+    };
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let output = args.ledger_format.writer(BufWriter::new(file), None)?;
+    let mut db = LedgerProcessor::new(db, output);
+    let result = process_with_dispute_timeline(&mut db, input, args)?;
+    db.finish()
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    Ok(result)
+}
+
+fn process_with_dispute_timeline<DB, O>(
+    mut db: DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let Some(path) = &args.dispute_timeline else {
+        return process_ledger(&mut db, input, args);
+    };
+
+    let writer = csv::Writer::from_path(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    let mut db = DisputeTimelineProcessor::new(db, writer);
+    let result = process_ledger(&mut db, input, args)?;
+    db.finish()
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    Ok(result)
+}
+
+fn process_ledger<DB, O>(
+    db: &mut DB,
+    input: Input<'_, O>,
+    args: &ProcessArgs,
+) -> anyhow::Result<RunResult>
+where
+    DB: TransactionProcessor + StateStore,
+    O: OutputWriter,
+{
+    let metrics = process(input, args.input_format, db)?;
+
+    let mut result = RunResult::new(args, metrics, || {
+        db.clients_iter().map(|client| (None, client))
+    });
+    if args.state_out.is_some() {
+        result.state = Some(db.snapshot()?);
+    }
+    Ok(result)
+}
+
+/// What a run leaves to report once the client balances were written
+struct RunResult {
+    metrics: RunMetrics,
+    top: Option<TopReport>,
+    buckets: Option<Vec<BalanceBucket>>,
+    summary: Option<RunSummary>,
+    /// State to hand off to the next run, with `--state-out`
+    state: Option<Snapshot>,
+}
+
+impl RunResult {
+    fn new<F, I>(args: &ProcessArgs, metrics: RunMetrics, clients: F) -> Self
+    where
+        F: Fn() -> I,
+        I: Iterator<Item = (Option<TenantId>, ClientInformation)>,
+    {
+        let top = args
+            .top
+            .map(|n| TopReport::new(n, &args.top_by, clients(), &metrics.activity));
+        let buckets = args.aggregate.map(|min_clients| {
+            let policy = AggregationPolicy::new(min_clients, args.aggregate_buckets.clone());
+            aggregate(clients().map(|(_, client)| client), &policy)
+        });
+        let summary = args.summary.then(|| RunSummary::new(&metrics, clients()));
+
+        Self {
+            metrics,
+            top,
+            buckets,
+            summary,
+            state: None,
+        }
+    }
+}
+
+fn process<O, DB>(
+    input: Input<'_, O>,
+    format: InputFormat,
+    db: &mut DB,
+) -> anyhow::Result<RunMetrics>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor + StateStore,
+{
+    let Input {
+        reader,
+        output,
+        checkpoint,
+        record_spec,
+        dialect,
+        emit,
+        drain,
+        pipeline,
+    } = input;
+
+    match (format, checkpoint) {
+        (InputFormat::Auto, _) => unreachable!("the input format is detected when opening it"),
+        (InputFormat::Jsonl, _) => jsonl_processor_draining(reader, output, db, drain),
+        (InputFormat::FixedWidth, _) => {
+            let spec = record_spec.context("--input-format fixed-width requires --record-spec")?;
+            fixed_width_processor_draining(reader, spec, output, db, drain)
+        }
+        #[cfg(feature = "arrow")]
+        (InputFormat::Arrow, _) => {
+            let file = reader.into_file().context(
+                "Arrow input can't be compressed or read from stdin, it has to be seekable",
+            )?;
+            octopussy::arrow::arrow_processor_draining(file, output, db, drain)
+        }
+        #[cfg(feature = "avro")]
+        (InputFormat::Avro, _) => {
+            octopussy::avro::avro_processor_draining(reader, output, db, drain)
+        }
+        #[cfg(feature = "msgpack")]
+        (InputFormat::Msgpack, _) => {
+            octopussy::msgpack::msgpack_processor_draining(reader, output, db, drain)
+        }
+        #[cfg(feature = "parquet")]
+        (InputFormat::Parquet, _) => {
+            let file = reader.into_file().context(
+                "Parquet input can't be compressed or read from stdin, it has to be seekable",
+            )?;
+            octopussy::parquet::parquet_processor_draining(file, output, db, drain)
+        }
+        #[cfg(feature = "protobuf")]
+        (InputFormat::Protobuf, _) => {
+            octopussy::protobuf::protobuf_processor_draining(reader, output, db, drain)
+        }
+        #[cfg(feature = "xml")]
+        (InputFormat::Xml, _) => octopussy::xml::xml_processor_draining(reader, output, db, drain),
+        // Plain files seek to the end of the checkpoint instead of reading through its rows
+        (InputFormat::Csv, Some(config)) => match reader {
+            InputReader::Plain(file) => csv_processor_checkpointed_seeking(
+                dialect.transaction_reader(file)?,
+                output,
+                db,
+                config,
+            ),
+            reader => {
+                csv_processor_checkpointed(dialect.transaction_reader(reader)?, output, db, config)
+            }
+        },
+        (InputFormat::Csv, None) => {
+            let reader = dialect.transaction_reader(reader)?;
+            match pipeline {
+                #[cfg(feature = "pipeline")]
+                Some(capacity) => octopussy::pipeline::csv_processor_pipelined(
+                    reader, output, db, capacity, drain,
+                ),
+                _ => csv_processor_draining(reader, output, db, emit, drain),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::{Cli, Command};
+
+    fn process_args(args: &[&str]) -> ProcessArgs {
+        let cli = Cli::try_parse_args(std::iter::once("octopussy").chain(args.iter().copied()));
+        let Some(Command::Process(args)) = cli.unwrap().command else {
+            panic!("not a process run");
+        };
+        *args
+    }
+
+    #[test]
+    fn drained_checkpointed_runs_keep_the_previous_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n",
+        )
+        .unwrap();
+        let output = dir.path().join("out.csv");
+        let previous = "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+        fs::write(&output, previous).unwrap();
+        let checkpoint = dir.path().join("run.checkpoint.json");
+
+        let args = [
+            "process",
+            "--checkpoint",
+            checkpoint.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ];
+        let process = process_args(&args);
+        // As if SIGTERM was received before the first row
+        let drain = DrainSignal::new();
+        drain.drain();
+        run(process, None, SnapshotFormat::Json, false, &drain).unwrap();
+
+        assert_eq!(fs::read_to_string(&output).unwrap(), previous);
+        assert!(checkpoint.exists());
+        let files = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 3, "the temporary output is left behind");
+    }
+
+    #[test]
+    fn dispute_windows_follow_event_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        fs::write(
+            &input,
+            "type,client,tx,amount,timestamp
+deposit,1,1,10,1000
+deposit,1,2,5,1000
+dispute,1,1,,1030
+dispute,1,2,,1100
+",
+        )
+        .unwrap();
+        let output = dir.path().join("out.csv");
+
+        let args = [
+            "process",
+            "--dispute-window",
+            "60",
+            "--output",
+            output.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ];
+        let process = process_args(&args);
+        run(
+            process,
+            None,
+            SnapshotFormat::Json,
+            false,
+            &DrainSignal::new(),
+        )
+        .unwrap();
+
+        // Against the wall clock, both disputes would be within the window
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "client,available,held,total,locked\n1,5.0000,10.0000,15.0000,false\n"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use octopussy::{
+    compression::InputReader,
+    csv::CsvDialect,
+    encryption::{self},
+    pseudonymize::{Pseudonymizer, pseudonymize_csv},
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    input: &Path,
+    dialect: CsvDialect,
+    key_file: &Path,
+    output: Option<&Path>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let pseudonymizer = Pseudonymizer::new(&encryption::read_key_file(key_file)?);
+
+    let reader =
+        InputReader::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let writer: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("failed to create {}", path.display())
+            })?))
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let records = pseudonymize_csv(
+        dialect.checked_reader(reader)?,
+        dialect.writer(writer),
+        &pseudonymizer,
+    )?;
+
+    info!("Pseudonymized the clients of {records} records");
+    if json && output.is_some() {
+        print_json(&serde_json::json!({ "records": records }))?;
+    }
+
+    Ok(())
+}
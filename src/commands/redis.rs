@@ -0,0 +1,53 @@
+use anyhow::bail;
+use octopussy::{
+    encryption::EncryptionKey,
+    redis_stream::{RedisStreamConfig, RedisStreamSource, partition_name},
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::consumer::run_consumer;
+use crate::RedisArgs;
+
+pub(crate) fn run(
+    args: &RedisArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let streams = match args.partitions {
+        None => vec![args.stream.clone()],
+        Some(partitions) => {
+            let mut ids = args.partition_ids.clone();
+            if ids.is_empty() {
+                ids = (0..partitions.get()).collect();
+            }
+            if let Some(id) = ids.iter().find(|&&id| id >= partitions.get()) {
+                bail!("partition {id} is out of the {partitions} partitions");
+            }
+            ids.iter()
+                .map(|&id| partition_name(&args.stream, id))
+                .collect()
+        }
+    };
+    let config = RedisStreamConfig {
+        url: args.url.clone(),
+        streams,
+        group: args.group.clone(),
+        consumer: args.consumer_name.clone(),
+        field: args.field.clone(),
+        format: args.consumer.payload.into(),
+    };
+    let mut source = RedisStreamSource::connect(&config)?;
+
+    run_consumer(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, save, drain, poll| {
+            info!("Consuming {} from {}", config.streams.join(", "), args.url);
+            source.run(db, save, drain, poll)
+        },
+    )
+}
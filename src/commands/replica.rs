@@ -0,0 +1,34 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::Context;
+use octopussy::{csv::ClientRow, replication::ReadReplica};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(stream: &Path, json: bool) -> anyhow::Result<()> {
+    let file =
+        File::open(stream).with_context(|| format!("failed to open {}", stream.display()))?;
+
+    let mut replica = ReadReplica::new();
+    replica.follow(BufReader::new(file))?;
+    info!("Replica caught up to record {}", replica.last_seq());
+
+    if json {
+        let clients: Vec<_> = replica.clients().map(ClientRow::from).collect();
+        return print_json(&serde_json::json!({
+            "last_seq": replica.last_seq(),
+            "clients": clients,
+        }));
+    }
+
+    let mut csv_writer = csv::WriterBuilder::default()
+        .has_headers(true)
+        .from_writer(std::io::stdout());
+    for client in replica.clients() {
+        csv_writer.serialize(ClientRow::from(client))?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use anyhow::bail;
+use octopussy::{
+    backend::BackendSpec, backup::BackupArchive, encryption::EncryptionKey,
+    snapshot_codec::SnapshotFormat,
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    archive: &Path,
+    to: &BackendSpec,
+    force: bool,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let backup = BackupArchive::read(archive, key)?;
+    let mut store = to.open(key, format)?;
+
+    let existing = store.snapshot()?;
+    if !existing.is_empty() && !force {
+        bail!(
+            "{to:?} already holds {} clients, pass --force to overwrite them",
+            existing.clients.len()
+        );
+    }
+
+    let totals = backup.restore(store.as_mut())?;
+    if json {
+        print_json(&serde_json::json!({
+            "archive": archive,
+            "to": to.to_string(),
+            "totals": totals,
+        }))?;
+    }
+    info!(
+        "Restored {} clients and {} transactions",
+        totals.clients, totals.transactions
+    );
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+use std::fs;
+use std::path::PathBuf;
+
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+use anyhow::Context;
+use anyhow::bail;
+use octopussy::{
+    memory_processor::InMemoryTransactionDb,
+    scenario::{Scenario, ScenarioResult},
+};
+
+use super::print_json;
+use crate::ScenarioBackend;
+
+pub(crate) fn run(paths: &[PathBuf], backend: ScenarioBackend, json: bool) -> anyhow::Result<()> {
+    let mut results = Vec::new();
+    for path in paths {
+        let scenario = Scenario::load(path)?;
+        let result = run_scenario(&scenario, backend)?;
+        if !json {
+            print!("{result}");
+        }
+        results.push(result);
+    }
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    if json {
+        print_json(&serde_json::json!({
+            "passed": results.len() - failed,
+            "failed": failed,
+            "scenarios": results,
+        }))?;
+    }
+    if failed > 0 {
+        bail!("{failed} of {} scenarios failed", results.len());
+    }
+
+    Ok(())
+}
+
+/// Runs `scenario` on an empty backend.
+fn run_scenario(scenario: &Scenario, backend: ScenarioBackend) -> anyhow::Result<ScenarioResult> {
+    match backend {
+        ScenarioBackend::Memory => Ok(scenario.run(&mut InMemoryTransactionDb::new())),
+        #[cfg(feature = "redb")]
+        ScenarioBackend::Redb => {
+            let scratch = ScratchDir::create()?;
+            let mut db = octopussy::redb_processor::RedbTransactionDb::open(
+                scratch.0.join("scenario.redb"),
+            )?;
+            Ok(scenario.run(&mut db))
+        }
+        #[cfg(feature = "lmdb")]
+        ScenarioBackend::Lmdb => {
+            let scratch = ScratchDir::create()?;
+            let mut db = octopussy::lmdb_processor::LmdbTransactionDb::open(&scratch.0)?;
+            Ok(scenario.run(&mut db))
+        }
+    }
+}
+
+/// A directory for the files of a scenario's backend, removed when dropped
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+struct ScratchDir(PathBuf);
+
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+impl ScratchDir {
+    fn create() -> anyhow::Result<Self> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "octopussy-scenario-{}-{}",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+
+        Ok(Self(path))
+    }
+}
+
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
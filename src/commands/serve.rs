@@ -0,0 +1,60 @@
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use octopussy::control::DrainSocket;
+use octopussy::{
+    encryption::EncryptionKey,
+    publish::PublishingProcessor,
+    server::{UpdateBroadcast, count_api_calls, router},
+    snapshot_codec::SnapshotFormat,
+    tenant::SingleLedger,
+};
+use tracing::info;
+
+use super::{
+    drain_on_signals, hold_leader_lock,
+    service::{SaveOn, drained, run_service},
+};
+use crate::ServeArgs;
+
+pub(crate) fn run(
+    args: &ServeArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    if args.update_buffer == 0 {
+        bail!("--update-buffer must be at least 1");
+    }
+    let updates = UpdateBroadcast::new(args.update_buffer);
+    // Released once drained
+    let _leader_lock = hold_leader_lock(args.leader.leader_lock.as_ref(), &drain_on_signals()?)?;
+    let (db, state) = args.backend.open(args.state.as_deref())?;
+    let db = SingleLedger::new(db);
+    let api_calls = db.api_calls();
+    let db = PublishingProcessor::new(db, updates.clone());
+    run_service(db, state, SaveOn::Drain, key, format, json, |db, drain| {
+        #[cfg(unix)]
+        let _socket = args
+            .control_socket
+            .as_deref()
+            .map(|path| DrainSocket::bind(path, drain.clone()))
+            .transpose()?;
+        let runtime = tokio::runtime::Runtime::new().context("failed to start the HTTP runtime")?;
+        let served = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind(args.listen)
+                .await
+                .with_context(|| format!("failed to listen on {}", args.listen))?;
+            info!("Serving HTTP on {}", args.listen);
+            let app = router(db.clone(), updates, drain.clone());
+            #[cfg(feature = "graphql")]
+            let app = app.merge(octopussy::graphql::routes(db.clone()));
+            axum::serve(listener, count_api_calls(app, api_calls))
+                .with_graceful_shutdown(drained(drain.clone()))
+                .await
+                .with_context(|| format!("failed to serve HTTP on {}", args.listen))
+        });
+        let db = db.lock().unwrap_or_else(|err| err.into_inner());
+        args.usage.save(db.inner().tenants())?;
+        served
+    })
+}
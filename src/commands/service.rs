@@ -0,0 +1,137 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use octopussy::{
+    drain::DrainSignal,
+    encryption::EncryptionKey,
+    snapshot::{SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    transaction::TransactionProcessor,
+};
+use tracing::{info, warn};
+
+use super::{drain_on_signals, print_json};
+
+/// Completes once `drain` is requested, for async servers to shut down gracefully
+#[cfg(any(feature = "grpc", feature = "server"))]
+pub(crate) async fn drained(drain: DrainSignal) {
+    while !drain.is_draining() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// When [`run_service`] saves the state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SaveOn {
+    /// Once `serve` returns
+    Stop,
+    /// As soon as the drain is requested, for services which stop applying events then while
+    /// their connections take a while to close, and again once `serve` returns
+    #[cfg(any(feature = "grpc", feature = "server"))]
+    Drain,
+}
+
+/// Serves `db`, starting from the state in `state`, until stopped with SIGINT or SIGTERM, and
+/// saves it as `save_on` says.
+///
+/// `serve` gets the DB to serve and the signal telling when to stop.
+pub(crate) fn run_service<DB, F>(
+    db: DB,
+    state: Option<&Path>,
+    save_on: SaveOn,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+    serve: F,
+) -> anyhow::Result<()>
+where
+    DB: TransactionProcessor + StateStore + Send,
+    F: FnOnce(Arc<Mutex<DB>>, &DrainSignal) -> anyhow::Result<()>,
+{
+    run_service_then(db, state, save_on, key, format, json, serve, || Ok(()))
+}
+
+/// Like [`run_service`], calling `saved` once the state was saved, eg to save the positions
+/// in the source which go with it
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_service_then<DB, F, S>(
+    mut db: DB,
+    state: Option<&Path>,
+    save_on: SaveOn,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+    serve: F,
+    saved: S,
+) -> anyhow::Result<()>
+where
+    DB: TransactionProcessor + StateStore + Send,
+    F: FnOnce(Arc<Mutex<DB>>, &DrainSignal) -> anyhow::Result<()>,
+    S: FnOnce() -> anyhow::Result<()>,
+{
+    // A missing file is an empty state, as on the first run
+    let state = state.map(|path| {
+        let file = SnapshotFile::new(path)
+            .encrypted(key.cloned())
+            .with_format(format);
+        (path, file)
+    });
+    if let Some((_, file)) = &state {
+        db.restore(file.snapshot()?)?;
+        info!("Starting from record {}", db.last_seq());
+    }
+
+    let db = Arc::new(Mutex::new(db));
+    let drain = drain_on_signals()?;
+    let stopped = DrainSignal::new();
+    let save_on_drain: Option<&Path> = match (save_on, &state) {
+        #[cfg(any(feature = "grpc", feature = "server"))]
+        (SaveOn::Drain, Some((path, _))) => Some(*path),
+        _ => None,
+    };
+    let served = thread::scope(|scope| {
+        if let Some(path) = save_on_drain {
+            let (db, drain, stopped) = (&db, &drain, &stopped);
+            scope.spawn(move || {
+                while !drain.is_draining() && !stopped.is_draining() {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                if !drain.is_draining() {
+                    return;
+                }
+                let snapshot = db.lock().unwrap_or_else(|err| err.into_inner()).snapshot();
+                let mut file = SnapshotFile::new(path)
+                    .encrypted(key.cloned())
+                    .with_format(format);
+                let saved = snapshot.and_then(|snapshot| file.restore(snapshot));
+                match saved {
+                    Ok(()) => info!("Draining, saved the state to {}", path.display()),
+                    Err(err) => warn!("Failed to save the state to {}: {err:#}", path.display()),
+                }
+            });
+        }
+        let served = serve(db.clone(), &drain);
+        stopped.drain();
+        served
+    });
+
+    let db = db.lock().unwrap_or_else(|err| err.into_inner());
+    // Saved even if serving failed, the responses sent reflect what was applied
+    if let Some((path, mut file)) = state {
+        file.restore(db.snapshot()?)
+            .with_context(|| format!("failed to save the state to {}", path.display()))?;
+    }
+    saved()?;
+    served?;
+
+    if json {
+        print_json(&serde_json::json!({ "last_seq": db.last_seq() }))?;
+    }
+
+    Ok(())
+}
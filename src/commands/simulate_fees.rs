@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use anyhow::Context;
+use octopussy::{
+    compression::InputReader,
+    csv::CsvDialect,
+    memory_processor::InMemoryTransactionDb,
+    pricing::{FeeSchedule, simulate_fees},
+};
+use tracing::info;
+
+pub(crate) fn run(
+    input: &Path,
+    dialect: CsvDialect,
+    baseline: &Path,
+    alternative: &Path,
+    json: bool,
+) -> anyhow::Result<()> {
+    let baseline = FeeSchedule::load(baseline)?;
+    let alternative = FeeSchedule::load(alternative)?;
+
+    let reader =
+        InputReader::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let simulation = simulate_fees(
+        dialect.transaction_reader(reader)?,
+        &mut InMemoryTransactionDb::new(),
+        &baseline,
+        &alternative,
+    )?;
+
+    let total = simulation.total();
+    info!(
+        "Revenue over {} charged events: {} under the baseline, {} under the alternative ({})",
+        total.events,
+        total.baseline,
+        total.alternative,
+        total.delta()
+    );
+    if json {
+        simulation.write_json(std::io::stdout().lock())?;
+        println!();
+        return Ok(());
+    }
+
+    simulation.write_csv(csv::Writer::from_writer(std::io::stdout()))
+}
@@ -0,0 +1,70 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    num::NonZeroU16,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use octopussy::{
+    compression::InputReader,
+    csv::CsvDialect,
+    split::{ShardEntry, SplitManifest, split_csv},
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    input: &Path,
+    dialect: CsvDialect,
+    shards: NonZeroU16,
+    output_dir: &Path,
+    json: bool,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("failed to create {}", output_dir.display()))?;
+    let paths: Vec<PathBuf> = (0..shards.get())
+        .map(|shard| output_dir.join(format!("shard-{shard:04}.csv")))
+        .collect();
+    let mut writers = paths
+        .iter()
+        .map(|path| {
+            let file = File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            Ok(dialect.writer(BufWriter::new(file)))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let reader =
+        InputReader::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let stats = split_csv(dialect.checked_reader(reader)?, &mut writers)?;
+
+    let manifest = SplitManifest {
+        input: input.to_owned(),
+        records: stats.iter().map(|shard| shard.records).sum(),
+        shards: paths
+            .into_iter()
+            .zip(stats)
+            .map(|(path, stats)| ShardEntry {
+                path,
+                records: stats.records,
+                clients: stats.clients,
+            })
+            .collect(),
+    };
+    let path = output_dir.join("manifest.json");
+    fs::write(&path, serde_json::to_vec_pretty(&manifest)?)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    info!(
+        "Split {} records into {} shards",
+        manifest.records,
+        manifest.shards.len()
+    );
+    if json {
+        print_json(&manifest)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+#[cfg(feature = "avro")]
+use anyhow::bail;
+use octopussy::{encryption::EncryptionKey, snapshot_codec::SnapshotFormat, sqs::SqsSource};
+use tracing::info;
+
+use super::consumer::run_consumer;
+#[cfg(feature = "avro")]
+use crate::Payload;
+use crate::SqsArgs;
+
+pub(crate) fn run(
+    args: &SqsArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "avro")]
+    if let Payload::Avro = args.consumer.payload {
+        bail!("SQS message bodies are text, they can't hold Avro payloads");
+    }
+    let mut source = SqsSource::connect(&args.queue_url, args.consumer.payload.into())?;
+
+    run_consumer(
+        &args.consumer,
+        key,
+        format,
+        json,
+        |db, save, drain, poll| {
+            info!("Consuming {}", args.queue_url);
+            source.run(db, save, drain, poll)
+        },
+    )
+}
@@ -0,0 +1,57 @@
+use std::{fs::File, io::BufReader, time::Duration};
+
+use anyhow::{Context, bail};
+use octopussy::{
+    cluster::{LeaderLock, Standby},
+    encryption::EncryptionKey,
+    memory_processor::InMemoryTransactionDb,
+    snapshot::{SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    transaction::TransactionProcessor,
+};
+use tracing::info;
+
+use super::print_json;
+use crate::StandbyArgs;
+
+pub(crate) fn run(
+    args: &StandbyArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut db = InMemoryTransactionDb::new();
+    if let Some(path) = &args.state_in {
+        if !path.exists() {
+            bail!("no state to start from at {}", path.display());
+        }
+        let snapshot = SnapshotFile::new(path)
+            .encrypted(key.cloned())
+            .with_format(format)
+            .snapshot()?;
+        db.restore(snapshot)?;
+    }
+
+    let stream = &args.stream;
+    let file =
+        File::open(stream).with_context(|| format!("failed to open {}", stream.display()))?;
+    let mut lock = args.lock.open()?;
+    info!("Standing by for {}", args.lock);
+    let poll = Duration::from_millis(args.poll_interval);
+    let db = Standby::new(db).run(BufReader::new(file), &mut lock, poll)?;
+    info!("Took over at record {}", db.last_seq());
+
+    let state_out = &args.state_out;
+    SnapshotFile::new(state_out)
+        .encrypted(key.cloned())
+        .with_format(format)
+        .restore(db.snapshot()?)
+        .with_context(|| format!("failed to save the state to {}", state_out.display()))?;
+    lock.release()?;
+
+    if json {
+        print_json(&serde_json::json!({ "last_seq": db.last_seq() }))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use octopussy::{
+    encryption::EncryptionKey, line_protocol::LineServer, snapshot_codec::SnapshotFormat,
+    tenant::SingleLedger,
+};
+use tracing::info;
+
+use super::{
+    drain_on_signals, hold_leader_lock,
+    service::{SaveOn, run_service},
+};
+use crate::TcpArgs;
+
+pub(crate) fn run(
+    args: &TcpArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    // Released once drained
+    let _leader_lock = hold_leader_lock(args.leader.leader_lock.as_ref(), &drain_on_signals()?)?;
+    let (db, state) = args.backend.open(args.state.as_deref())?;
+    let db = SingleLedger::new(db);
+    let server = LineServer::bind(args.listen)?.counting_api_calls(db.api_calls());
+    run_service(db, state, SaveOn::Stop, key, format, json, |db, drain| {
+        info!("Listening on {}", server.local_addr()?);
+        let served = server.run(&db, drain, Duration::from_millis(100));
+        args.usage
+            .save(db.lock().unwrap_or_else(|err| err.into_inner()).tenants())?;
+        served
+    })
+}
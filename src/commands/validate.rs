@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use octopussy::{compression::InputReader, csv::CsvDialect, validate::validate};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(input: &Path, dialect: CsvDialect, json: bool) -> anyhow::Result<()> {
+    let reader =
+        InputReader::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+    let validation = validate(dialect.transaction_reader(reader)?)?;
+
+    if json {
+        print_json(&serde_json::json!({
+            "input": input,
+            "valid": validation.is_valid(),
+            "rows": validation.rows,
+            "issues": validation.issues,
+        }))?;
+    } else {
+        for issue in &validation.issues {
+            println!("{issue}");
+        }
+    }
+    if !validation.is_valid() {
+        bail!(
+            "{} of the {} rows of {} are invalid",
+            validation.issues.len(),
+            validation.rows,
+            input.display()
+        );
+    }
+
+    info!(
+        "All {} rows of {} are valid",
+        validation.rows,
+        input.display()
+    );
+    Ok(())
+}
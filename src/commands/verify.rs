@@ -0,0 +1,47 @@
+use anyhow::bail;
+use octopussy::{
+    backend::BackendSpec,
+    encryption::EncryptionKey,
+    i18n::{Localize, Message},
+    snapshot_codec::SnapshotFormat,
+    verify::verify,
+};
+use tracing::info;
+
+use super::print_json;
+
+pub(crate) fn run(
+    backend: &BackendSpec,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let store = backend.open(key, format)?;
+    let discrepancies = verify(store.as_ref())?;
+
+    if json {
+        print_json(&serde_json::json!({
+            "backend": backend.to_string(),
+            "consistent": discrepancies.is_empty(),
+            "discrepancies": discrepancies,
+        }))?;
+    } else {
+        for discrepancy in &discrepancies {
+            println!("{}", discrepancy.message());
+        }
+    }
+    if !discrepancies.is_empty() {
+        bail!(
+            "{}",
+            Message::new("store-inconsistent")
+                .arg("backend", format!("{backend:?}"))
+                .arg("count", discrepancies.len())
+        );
+    }
+
+    info!(
+        "{}",
+        Message::new("store-consistent").arg("backend", format!("{backend:?}"))
+    );
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::{cell::RefCell, time::Duration};
+
+use octopussy::{
+    cursor::OffsetProcessor,
+    encryption::EncryptionKey,
+    i18n::Message,
+    snapshot_codec::SnapshotFormat,
+    watch::{WatchProgress, Watcher},
+};
+use tracing::info;
+
+use super::{
+    drain_on_signals, hold_leader_lock,
+    service::{SaveOn, run_service_then},
+};
+use crate::WatchArgs;
+
+pub(crate) fn run(
+    args: &WatchArgs,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let progress = match &args.progress {
+        Some(path) => WatchProgress::load(path, key)?,
+        None => WatchProgress::default(),
+    };
+    let mut watcher = Watcher::new(&args.path, (&args.dialect).into(), progress)?;
+    let source = format!("watch:{}", args.path.display());
+    let reached = RefCell::new(None);
+    // Released once drained
+    let _leader_lock = hold_leader_lock(args.leader.leader_lock.as_ref(), &drain_on_signals()?)?;
+    let (db, state) = args.backend.open(args.state.as_deref())?;
+
+    run_service_then(
+        OffsetProcessor::new(db),
+        state,
+        SaveOn::Stop,
+        key,
+        format,
+        json,
+        |db, drain| {
+            info!("Watching {}", args.path.display());
+            let mut db = db.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(progress) = db.position(&source)? {
+                watcher.resume(progress);
+            }
+            let poll = Duration::from_millis(args.poll_interval);
+            let result = watcher.run(&mut *db, drain, poll);
+            db.set_position(&source, watcher.progress())?;
+            reached.replace(Some(watcher.progress().clone()));
+
+            let metrics = result?;
+            info!(
+                "{}",
+                Message::new("processed-events").arg("count", metrics.latencies.count())
+            );
+            Ok(())
+        },
+        // Only once the state is saved, progress ahead of it would skip rows
+        || match (&args.progress, reached.take()) {
+            (Some(path), Some(progress)) => progress.save(path, key),
+            _ => Ok(()),
+        },
+    )
+}
@@ -0,0 +1,465 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Stdin, Stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+
+#[cfg(feature = "cloud")]
+use crate::cloud::{ObjectReader, ObjectUri, ObjectWriter};
+#[cfg(feature = "http")]
+use crate::http_input::{self, HttpReader};
+use crate::{encryption, inputs::InputChain};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Tells the compression of a file from its first bytes, or from its extension (`.gz`,
+    /// `.zst`) when they don't say, eg for an empty file.
+    pub fn detect(path: &Path, header: &[u8]) -> Self {
+        if header.starts_with(GZIP_MAGIC) {
+            return Self::Gzip;
+        }
+        if header.starts_with(ZSTD_MAGIC) {
+            return Self::Zstd;
+        }
+
+        if header.is_empty() {
+            Self::from_extension(path)
+        } else {
+            Self::None
+        }
+    }
+
+    /// The compression implied by the extension of `path`, eg `report.csv.zst`
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// An input file, decompressed on the fly if it's gzip or zstd compressed
+pub enum InputReader {
+    Plain(BufReader<File>),
+    Gzip(BufReader<MultiGzDecoder<BufReader<File>>>),
+    Zstd(BufReader<zstd::Decoder<'static, BufReader<File>>>),
+    /// Read as it comes in, uncompressed
+    Stdin(BufReader<Stdin>),
+    /// An object of object storage or a URL, read as it's downloaded and decompressed like
+    /// files are
+    #[cfg(any(feature = "cloud", feature = "http"))]
+    Remote(Box<dyn BufRead + Send>, Compression),
+    /// Several inputs read one after the other, with the compression of the first one
+    Chain(Box<BufReader<InputChain>>, Compression),
+}
+
+impl InputReader {
+    /// Opens `path`, stdin if it's `-`, an object if it's an `s3://` or `gs://` URI (with the
+    /// `cloud` feature), or a URL if it's an `http://` or `https://` one (with the `http`
+    /// feature)
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if is_stdin(path) {
+            return Ok(Self::Stdin(BufReader::new(io::stdin())));
+        }
+        #[cfg(feature = "cloud")]
+        if let Some(uri) = ObjectUri::parse(path).map_err(remote_error)? {
+            let object = ObjectReader::open(&uri).map_err(remote_error)?;
+            return Self::open_remote(path, object);
+        }
+        #[cfg(feature = "http")]
+        if let Some(url) = http_input::url(path) {
+            let body = HttpReader::open(url).map_err(remote_error)?;
+            return Self::open_remote(path, body);
+        }
+
+        let mut reader = BufReader::new(File::open(path)?);
+
+        // Peeking doesn't consume the header, the decoders still need it
+        let compression = Compression::detect(path, reader.fill_buf()?);
+        Ok(match compression {
+            Compression::None => Self::Plain(reader),
+            // Multi-member gzip files are read through like `zcat` does, eg concatenated
+            // daily dumps
+            Compression::Gzip => Self::Gzip(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Zstd => Self::Zstd(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+        })
+    }
+
+    #[cfg(any(feature = "cloud", feature = "http"))]
+    fn open_remote(path: &Path, reader: impl Read + Send + 'static) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let compression = Compression::detect(path, reader.fill_buf()?);
+        let reader: Box<dyn BufRead + Send> = match compression {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+        };
+        Ok(Self::Remote(reader, compression))
+    }
+
+    /// Reads `self`, opened from `path`, followed by the inputs at `rest` as a single input.
+    /// See [`InputChain`] for `headers` and `terminator`.
+    pub fn chain(
+        self,
+        path: &Path,
+        rest: Vec<PathBuf>,
+        headers: bool,
+        terminator: u8,
+    ) -> io::Result<Self> {
+        let compression = self.compression();
+        let chain = InputChain::new(self, path, rest, headers, terminator)?;
+        Ok(Self::Chain(Box::new(BufReader::new(chain)), compression))
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Self::Plain(_) | Self::Stdin(_) => Compression::None,
+            Self::Gzip(_) => Compression::Gzip,
+            Self::Zstd(_) => Compression::Zstd,
+            #[cfg(any(feature = "cloud", feature = "http"))]
+            Self::Remote(_, compression) => *compression,
+            Self::Chain(_, compression) => *compression,
+        }
+    }
+
+    /// The underlying file, for formats which have to seek in it. Compressed files, stdin and
+    /// remote inputs can't be.
+    pub fn into_file(self) -> Option<File> {
+        match self {
+            Self::Plain(reader) => Some(reader.into_inner()),
+            _ => None,
+        }
+    }
+}
+
+impl Read for InputReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(reader) => reader.read(buf),
+            Self::Gzip(reader) => reader.read(buf),
+            Self::Zstd(reader) => reader.read(buf),
+            Self::Stdin(reader) => reader.read(buf),
+            #[cfg(any(feature = "cloud", feature = "http"))]
+            Self::Remote(reader, _) => reader.read(buf),
+            Self::Chain(reader, _) => reader.read(buf),
+        }
+    }
+}
+
+impl BufRead for InputReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Plain(reader) => reader.fill_buf(),
+            Self::Gzip(reader) => reader.fill_buf(),
+            Self::Zstd(reader) => reader.fill_buf(),
+            Self::Stdin(reader) => reader.fill_buf(),
+            #[cfg(any(feature = "cloud", feature = "http"))]
+            Self::Remote(reader, _) => reader.fill_buf(),
+            Self::Chain(reader, _) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Self::Plain(reader) => reader.consume(amount),
+            Self::Gzip(reader) => reader.consume(amount),
+            Self::Zstd(reader) => reader.consume(amount),
+            Self::Stdin(reader) => reader.consume(amount),
+            #[cfg(any(feature = "cloud", feature = "http"))]
+            Self::Remote(reader, _) => reader.consume(amount),
+            Self::Chain(reader, _) => reader.consume(amount),
+        }
+    }
+}
+
+/// The error of an object storage or HTTP operation, with its causes
+#[cfg(any(feature = "cloud", feature = "http"))]
+fn remote_error(err: anyhow::Error) -> io::Error {
+    io::Error::other(format!("{err:#}"))
+}
+
+/// Whether `path` is `-`, which stands for stdin
+pub fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Where an output goes: a file, stdout, or an object of object storage if its path is an
+/// `s3://` or `gs://` URI (with the `cloud` feature).
+///
+/// [`Self::finish`] has to be called once everything was written, files and objects aren't
+/// created otherwise.
+pub enum Destination {
+    Stdout(Stdout),
+    File(AtomicFile),
+    #[cfg(feature = "cloud")]
+    Object(Box<ObjectWriter>),
+}
+
+impl Destination {
+    /// Creates `path`, or writes to stdout without one
+    pub fn create(path: Option<&Path>) -> io::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::Stdout(io::stdout()));
+        };
+        #[cfg(feature = "cloud")]
+        if let Some(uri) = ObjectUri::parse(path).map_err(remote_error)? {
+            let writer = ObjectWriter::create(&uri).map_err(remote_error)?;
+            return Ok(Self::Object(Box::new(writer)));
+        }
+
+        Ok(Self::File(AtomicFile::create(path)?))
+    }
+
+    /// Flushes what was written, moves files into place and completes the upload of objects
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        match self {
+            Self::Stdout(_) => Ok(()),
+            Self::File(file) => file.finish(),
+            #[cfg(feature = "cloud")]
+            Self::Object(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(writer) => writer.write(buf),
+            Self::File(writer) => writer.write(buf),
+            #[cfg(feature = "cloud")]
+            Self::Object(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(writer) => writer.flush(),
+            Self::File(writer) => writer.flush(),
+            #[cfg(feature = "cloud")]
+            Self::Object(writer) => writer.flush(),
+        }
+    }
+}
+
+/// A file written to a temporary file next to it (see [`encryption::tmp_path`]), and only
+/// renamed over it by [`Self::finish`]: readers never see it half written, and a run which
+/// fails leaves the previous version of the file as it was.
+///
+/// The temporary file is removed if the writer is dropped before being finished.
+pub struct AtomicFile {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    finished: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let tmp_path = encryption::tmp_path(path);
+
+        Ok(Self {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            path: path.to_owned(),
+            tmp_path,
+            finished: false,
+        })
+    }
+
+    /// Writes everything to disk and moves the file into place
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        encryption::replace_file(&self.tmp_path, &self.path)?;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// An output, compressed on the fly.
+///
+/// [`Self::finish`] has to be called once everything was written, compressed outputs are
+/// truncated otherwise.
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::Plain(writer),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Writes the end of the compressed stream, flushes and returns the output.
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = match self {
+            Self::Plain(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CSV: &str = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+
+    fn read(path: &Path) -> (Compression, String) {
+        let mut reader = InputReader::open(path).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+
+        (reader.compression(), data)
+    }
+
+    #[test]
+    fn detect() {
+        let path = Path::new("events.csv");
+        assert_eq!(Compression::detect(path, b"type,client"), Compression::None);
+        assert_eq!(
+            Compression::detect(path, &[0x1f, 0x8b, 8, 0]),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::detect(path, &[0x28, 0xb5, 0x2f, 0xfd, 0]),
+            Compression::Zstd
+        );
+
+        assert_eq!(
+            Compression::detect(Path::new("events.csv.gz"), b""),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::detect(Path::new("events.csv.zst"), b"type,client"),
+            Compression::None
+        );
+    }
+
+    #[test]
+    fn reads_compressed_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let plain = dir.path().join("events.csv");
+        std::fs::write(&plain, CSV).unwrap();
+        assert_eq!(read(&plain), (Compression::None, CSV.to_owned()));
+
+        // Two members, as left by `cat monday.csv.gz tuesday.csv.gz`
+        let gzip = dir.path().join("events.csv.gz");
+        let mut data = Vec::new();
+        for part in [&CSV[..22], &CSV[22..]] {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            data.extend(encoder.finish().unwrap());
+        }
+        std::fs::write(&gzip, data).unwrap();
+        assert_eq!(read(&gzip), (Compression::Gzip, CSV.to_owned()));
+
+        // Detected without the extension too
+        let zstd = dir.path().join("events.dump");
+        std::fs::write(&zstd, zstd::encode_all(CSV.as_bytes(), 0).unwrap()).unwrap();
+        assert_eq!(read(&zstd), (Compression::Zstd, CSV.to_owned()));
+        assert!(InputReader::open(&zstd).unwrap().into_file().is_none());
+    }
+
+    #[test]
+    fn writes_compressed_output() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, compression) in [
+            ("report.csv", Compression::None),
+            ("report.csv.gz", Compression::Gzip),
+            ("report.csv.zst", Compression::Zstd),
+        ] {
+            let path = dir.path().join(name);
+            assert_eq!(Compression::from_extension(&path), compression);
+
+            let mut writer =
+                CompressedWriter::new(File::create(&path).unwrap(), compression).unwrap();
+            writer.write_all(CSV.as_bytes()).unwrap();
+            writer.finish().unwrap();
+
+            assert_eq!(read(&path), (compression, CSV.to_owned()));
+        }
+    }
+
+    #[test]
+    fn moves_outputs_into_place_once_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("balances.csv");
+        std::fs::write(&path, "previous").unwrap();
+
+        let mut destination = Destination::create(Some(&path)).unwrap();
+        destination.write_all(CSV.as_bytes()).unwrap();
+        destination.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+        destination.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CSV);
+
+        // A failed run leaves neither a partial output nor its temporary file
+        let mut destination = Destination::create(Some(&path)).unwrap();
+        destination.write_all(b"type,cli").unwrap();
+        drop(destination);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CSV);
+        assert!(!encryption::tmp_path(&path).exists());
+    }
+}
@@ -0,0 +1,633 @@
+//! A local control socket to inspect and administer a batch run while it goes on, without
+//! stopping it.
+//!
+//! Operators connect to the Unix domain socket, eg with `socat - UNIX-CONNECT:<path>`, and
+//! send one command per line, each answered with a line of its own:
+//!
+//! - `client <id>` answers the state of a client, as in the CSV output
+//! - `stats` answers how many events were applied and rejected so far
+//! - `dump` answers a [`Snapshot`] of the whole state
+//! - `unfreeze <id> <operator>` requests to unlock a client, answered with
+//!   `pending <approval>`: unlocks need a second operator, as in an [`ApprovalQueue`]
+//! - `approve <approval> <operator>` approves and applies a requested unlock, answered `ok`
+//! - `pending` answers the unlocks waiting for an approval
+//! - `close <id>` closes a client's account, moving it to the account archive, answered
+//!   with `ok`. Its balances are still answered by `client <id>`
+//! - `drain` asks the run to stop like SIGTERM does, answered with `ok`
+//!
+//! Answers are JSON, apart from `ok`, `pending <approval>` and `error: <reason>`. Commands are
+//! served between two events, so their answers are consistent with the events applied so far,
+//! but they wait while the run is waiting for its input. `drain` is the exception, it's
+//! answered right away.
+//!
+//! Daemons, whose state is read through their API, only serve `drain`, on a [`DrainSocket`].
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+};
+
+use anyhow::{Context, bail};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    approval::{
+        AdminOperation, AdminProcessor, ApprovalId, ApprovalPolicy, ApprovalQueue, Operator,
+        Submitted,
+    },
+    clock::{SystemClock, Timestamp},
+    csv::ClientRow,
+    drain::DrainSignal,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// A command sent to the control socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Client(ClientId),
+    Stats,
+    Dump,
+    Unfreeze {
+        client: ClientId,
+        operator: Operator,
+    },
+    Approve {
+        id: ApprovalId,
+        operator: Operator,
+    },
+    Pending,
+    Close(ClientId),
+    Drain,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        fn arg<T: FromStr>(arg: Option<&str>, name: &str) -> Result<T, String>
+        where
+            T::Err: fmt::Display,
+        {
+            let arg = arg.ok_or_else(|| format!("missing {name}"))?;
+            arg.parse()
+                .map_err(|err| format!("invalid {name} {arg:?}: {err}"))
+        }
+
+        let mut words = line.split_whitespace();
+        let command = match words.next().unwrap_or_default() {
+            "client" => Self::Client(arg(words.next(), "client id")?),
+            "stats" => Self::Stats,
+            "dump" => Self::Dump,
+            "unfreeze" => Self::Unfreeze {
+                client: arg(words.next(), "client id")?,
+                operator: arg(words.next(), "operator")?,
+            },
+            "approve" => Self::Approve {
+                id: arg(words.next(), "approval id")?,
+                operator: arg(words.next(), "operator")?,
+            },
+            "pending" => Self::Pending,
+            "close" => Self::Close(arg(words.next(), "client id")?),
+            "drain" => Self::Drain,
+            other => return Err(format!("unknown command {other:?}")),
+        };
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected argument {extra:?}"));
+        }
+
+        Ok(command)
+    }
+}
+
+/// Answer of the `stats` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ControlStats {
+    /// Events applied or rejected so far
+    pub events: u64,
+    pub rejected: u64,
+    pub clients: usize,
+    pub last_seq: u64,
+}
+
+type Request = (ControlCommand, mpsc::Sender<String>);
+
+/// A listening control socket, removed when dropped.
+///
+/// Connections are accepted and read on threads of their own, which hand the commands over
+/// to the [`ControlledProcessor`] serving them.
+pub struct ControlSocket {
+    path: PathBuf,
+    requests: mpsc::Receiver<Request>,
+}
+
+impl ControlSocket {
+    /// Listens at `path`, replacing the socket a run which didn't exit cleanly left behind.
+    /// `drain` commands request `drain`.
+    ///
+    /// ## Errors
+    /// - If another run is listening at `path`
+    pub fn bind(path: &Path, drain: DrainSignal) -> anyhow::Result<Self> {
+        let (sender, requests) = mpsc::channel();
+        listen(path, Some(sender), drain)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            requests,
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A control socket of a daemon, which only serves `drain`, removed when dropped
+pub struct DrainSocket {
+    path: PathBuf,
+}
+
+impl DrainSocket {
+    /// Listens at `path` like [`ControlSocket::bind`]
+    pub fn bind(path: &Path, drain: DrainSignal) -> anyhow::Result<Self> {
+        listen(path, None, drain)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for DrainSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Accepts connections at `path` on a thread of its own, handing their commands to
+/// `requests`, or only serving `drain` without it
+fn listen(
+    path: &Path,
+    requests: Option<mpsc::Sender<Request>>,
+    drain: DrainSignal,
+) -> anyhow::Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("another run is listening at {}", path.display());
+        }
+        fs::remove_file(path)
+            .with_context(|| format!("failed to remove the stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to listen at {}", path.display()))?;
+    info!("Listening for control commands at {}", path.display());
+
+    thread::spawn(move || accept(&listener, requests.as_ref(), &drain));
+
+    Ok(())
+}
+
+fn accept(listener: &UnixListener, requests: Option<&mpsc::Sender<Request>>, drain: &DrainSignal) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept a control connection: {err}");
+                continue;
+            }
+        };
+        let requests = requests.cloned();
+        let drain = drain.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(stream, requests.as_ref(), &drain) {
+                warn!("Lost a control connection: {err}");
+            }
+        });
+    }
+}
+
+/// Answers the commands of the lines of `stream`
+fn serve(
+    stream: UnixStream,
+    requests: Option<&mpsc::Sender<Request>>,
+    drain: &DrainSignal,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let answer = match line.parse() {
+            // Answered right away, a run waiting for its input has to be told to stop too
+            Ok(ControlCommand::Drain) => {
+                if !drain.drain() {
+                    info!("Draining, requested on the control socket");
+                }
+                "ok".to_owned()
+            }
+            Ok(command) => match requests {
+                None => "error: only drain is served by daemons".to_owned(),
+                Some(requests) => {
+                    let (reply, answer) = mpsc::channel();
+                    // Both fail once the run is done and the processor is dropped
+                    requests
+                        .send((command, reply))
+                        .ok()
+                        .and_then(|()| answer.recv().ok())
+                        .unwrap_or_else(|| "error: the run is over".to_owned())
+                }
+            },
+            Err(err) => format!("error: {err}"),
+        };
+        writeln!(writer, "{answer}")?;
+    }
+
+    Ok(())
+}
+
+/// Serves the commands of a [`ControlSocket`] against the DB it wraps, before applying every
+/// event.
+///
+/// Unlocks are applied through an [`ApprovalQueue`] of its own, which only lives as long as
+/// the run: pending unlocks which weren't approved by then are dropped.
+pub struct ControlledProcessor<DB> {
+    inner: DB,
+    socket: ControlSocket,
+    approvals: ApprovalQueue,
+    stats: ControlStats,
+}
+
+impl<DB> ControlledProcessor<DB>
+where
+    DB: TransactionProcessor + StateStore + AdminProcessor,
+{
+    pub fn new(inner: DB, socket: ControlSocket) -> Self {
+        Self {
+            inner,
+            socket,
+            approvals: ApprovalQueue::default(),
+            stats: ControlStats {
+                events: 0,
+                rejected: 0,
+                clients: 0,
+                last_seq: 0,
+            },
+        }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.inner
+    }
+
+    /// Answers the commands received so far
+    pub fn serve_commands(&mut self) {
+        while let Ok((command, reply)) = self.socket.requests.try_recv() {
+            let answer = self
+                .answer(command)
+                .unwrap_or_else(|err| format!("error: {err:#}"));
+            // The operator may have hung up already
+            let _ = reply.send(answer);
+        }
+    }
+
+    fn answer(&mut self, command: ControlCommand) -> anyhow::Result<String> {
+        let answer = match command {
+            ControlCommand::Client(client_id) => {
+                let client = self.inner.find_client(client_id)?;
+                serde_json::to_string(&ClientRow::from(client))?
+            }
+            ControlCommand::Stats => {
+                let stats = ControlStats {
+                    clients: self.inner.clients_iter().count(),
+                    last_seq: self.inner.last_seq(),
+                    ..self.stats
+                };
+                serde_json::to_string(&stats)?
+            }
+            ControlCommand::Dump => {
+                let snapshot: Snapshot = self.inner.snapshot()?;
+                serde_json::to_string(&snapshot)?
+            }
+            ControlCommand::Unfreeze { client, operator } => {
+                let submitted = self.approvals.submit(
+                    &ApprovalPolicy::default(),
+                    AdminOperation::Unlock { client },
+                    operator.clone(),
+                    &SystemClock,
+                    &mut self.inner,
+                )?;
+                match submitted {
+                    Submitted::Applied => "ok".to_owned(),
+                    Submitted::Pending(id) => {
+                        info!("Operator {operator} requested to unlock client {client} ({id})");
+                        format!("pending {id}")
+                    }
+                }
+            }
+            ControlCommand::Approve { id, operator } => {
+                let approval = self.approvals.approve(id, &operator, &mut self.inner)?;
+                info!(
+                    "Operator {operator} approved {:?} requested by {}",
+                    approval.operation, approval.requested_by
+                );
+                "ok".to_owned()
+            }
+            ControlCommand::Pending => {
+                serde_json::to_string(&self.approvals.pending().collect::<Vec<_>>())?
+            }
+            ControlCommand::Close(client_id) => {
+                self.inner.close_account(client_id)?;
+                info!("Closed the account of client {client_id}");
+                "ok".to_owned()
+            }
+            ControlCommand::Drain => unreachable!("drains are requested by the connections"),
+        };
+
+        Ok(answer)
+    }
+
+    fn apply<F>(&mut self, apply: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut DB) -> Result<(), TransactionError>,
+    {
+        self.serve_commands();
+        let result = apply(&mut self.inner);
+        self.stats.events += 1;
+        if result.is_err() {
+            self.stats.rejected += 1;
+        }
+
+        result
+    }
+}
+
+impl<DB> TransactionProcessor for ControlledProcessor<DB>
+where
+    DB: TransactionProcessor + StateStore + AdminProcessor,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.deposit(transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.withdrawal(transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.dispute(transaction_id, client_id))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.resolve(transaction_id, client_id))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.chargeback(transaction_id, client_id))
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.apply(|db| db.process_annotated_event(transaction, metadata))
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB> StateStore for ControlledProcessor<DB>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{archive::AccountArchive, memory_processor::InMemoryTransactionDb};
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("client 7".parse(), Ok(ControlCommand::Client(7)));
+        assert_eq!(" stats ".parse(), Ok(ControlCommand::Stats));
+        assert_eq!("drain".parse(), Ok(ControlCommand::Drain));
+        assert_eq!(
+            "unfreeze 7 alice".parse(),
+            Ok(ControlCommand::Unfreeze {
+                client: 7,
+                operator: "alice".to_owned()
+            })
+        );
+        assert_eq!(
+            "approve 1 bob".parse(),
+            Ok(ControlCommand::Approve {
+                id: 1,
+                operator: "bob".to_owned()
+            })
+        );
+
+        assert_eq!("close 7".parse(), Ok(ControlCommand::Close(7)));
+
+        assert!("client".parse::<ControlCommand>().is_err());
+        assert!("client seven".parse::<ControlCommand>().is_err());
+        assert!("stats now".parse::<ControlCommand>().is_err());
+        assert!("freeze 7".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn serves_commands_between_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let drain = DrainSignal::new();
+        let socket = ControlSocket::bind(&path, drain.clone()).unwrap();
+        assert!(ControlSocket::bind(&path, DrainSignal::new()).is_err());
+        let mut db = ControlledProcessor::new(InMemoryTransactionDb::new(), socket);
+
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db.deposit(2, 1, dec!(1)).unwrap_err();
+
+        let operator = thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = UnixStream::connect(path).unwrap();
+                stream
+                    .write_all(
+                        b"client 1\nstats\nunfreeze 1 alice\napprove 1 alice\napprove 1 bob\n\
+                          client 1\nbogus\ndrain\n",
+                    )
+                    .unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut answers = String::new();
+                io::Read::read_to_string(&mut stream, &mut answers).unwrap();
+                answers
+            }
+        });
+        // Commands are only served when the next event comes in
+        while !operator.is_finished() {
+            db.serve_commands();
+            thread::yield_now();
+        }
+        let answers = operator.join().unwrap();
+
+        let answers: Vec<_> = answers.lines().collect();
+        assert_eq!(
+            answers[..3],
+            [
+                r#"{"client":1,"available":"0.0000","held":"0.0000","total":"0.0000","locked":true}"#,
+                r#"{"events":4,"rejected":1,"clients":1,"last_seq":3}"#,
+                "pending 1",
+            ]
+        );
+        assert!(answers[3].starts_with("error: operator alice requested"));
+        assert_eq!(answers[4], "ok");
+        assert!(answers[5].ends_with(r#""locked":false}"#));
+        assert!(answers[6].starts_with("error: unknown command"));
+        assert_eq!(answers[7], "ok");
+        assert!(drain.is_draining());
+
+        db.deposit(2, 1, dec!(1)).unwrap();
+        drop(db);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn closed_accounts_are_still_answered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let socket = ControlSocket::bind(&path, DrainSignal::new()).unwrap();
+        let mut inner = InMemoryTransactionDb::new();
+        inner.set_account_archive(AccountArchive::open(dir.path().join("closed")).unwrap());
+        let mut db = ControlledProcessor::new(inner, socket);
+        db.deposit(1, 1, dec!(10)).unwrap();
+
+        let operator = thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = UnixStream::connect(path).unwrap();
+                stream
+                    .write_all(
+                        b"close 1
+close 1
+client 1
+",
+                    )
+                    .unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut answers = String::new();
+                io::Read::read_to_string(&mut stream, &mut answers).unwrap();
+                answers
+            }
+        });
+        while !operator.is_finished() {
+            db.serve_commands();
+            thread::yield_now();
+        }
+        let answers = operator.join().unwrap();
+
+        let answers: Vec<_> = answers.lines().collect();
+        assert_eq!(answers[0], "ok");
+        assert_eq!(answers[1], "error: client 1 does not exist");
+        assert_eq!(
+            answers[2],
+            r#"{"client":1,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false}"#
+        );
+        assert_eq!(db.inner().clients_iter().count(), 0);
+    }
+
+    #[test]
+    fn daemons_only_drain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let drain = DrainSignal::new();
+        let socket = DrainSocket::bind(&path, drain.clone()).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"stats\ndrain\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut answers = String::new();
+        io::Read::read_to_string(&mut stream, &mut answers).unwrap();
+
+        assert_eq!(answers, "error: only drain is served by daemons\nok\n");
+        assert!(drain.is_draining());
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -1,19 +1,131 @@
+use std::{
+    borrow::Cow,
+    num::NonZeroU64,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
-use crate::transaction::{ClientId, TransactionEvent, TransactionId, TransactionProcessor};
-
-/// Maximum decimal places to include when formatting the CSV
-const DECIMAL_PLACES: u32 = 4;
+use crate::{
+    amount,
+    checkpoint::{Checkpoint, CheckpointConfig},
+    clock::Timestamp,
+    drain::DrainSignal,
+    export::OutputWriter,
+    i18n::{Localize, Message},
+    metrics::RunMetrics,
+    snapshot::StateStore,
+    tenant::{StorageFootprint, TenantDb, TenantEvent, TenantId, TenantProcessor, TenantUsage},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
 
 #[derive(Debug, Deserialize)]
 pub struct TransactionRow {
     #[serde(rename = "type")]
-    transaction_type: String,
-    client: ClientId,
-    tx: TransactionId,
-    amount: Option<Decimal>,
+    pub(crate) transaction_type: String,
+    pub(crate) client: ClientId,
+    pub(crate) tx: TransactionId,
+    /// Can be left out of headerless rows of disputes, resolves and chargebacks
+    #[serde(default)]
+    pub(crate) amount: Option<Decimal>,
+    /// Optional column, rows without it go to the default ledger
+    #[serde(default)]
+    pub(crate) tenant: Option<TenantId>,
+    /// Optional column, seconds since the UNIX epoch. Used for the activity heatmap, and
+    /// drives the clock of the time-based rules when it follows events, see
+    /// [`crate::clock::Clock::follow`]
+    #[serde(default)]
+    pub(crate) timestamp: Option<Timestamp>,
+    /// Optional column, position of the row in the chain of inputs, see
+    /// [`crate::handoff`]
+    #[serde(default)]
+    pub(crate) sequence: Option<u64>,
+    /// Other columns, see [`TransactionReader::with_metadata`]
+    #[serde(skip)]
+    pub(crate) metadata: Metadata,
+}
+
+/// Columns of a header row, in the order [`CsvDialect::strict_headers`] expects them. The
+/// ones after `amount` are optional.
+pub const COLUMNS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "tenant",
+    "timestamp",
+    "sequence",
+];
+const REQUIRED_COLUMNS: usize = 4;
+
+/// A header row which doesn't match [`COLUMNS`]
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("duplicate columns in the header: {}", .0.join(", "))]
+    Duplicate(Vec<String>),
+    #[error("unknown columns in the header: {}", .0.join(", "))]
+    Unknown(Vec<String>),
+    #[error("missing columns in the header: {}", .0.join(", "))]
+    Missing(Vec<&'static str>),
+    #[error("columns out of order in the header: found {}, expected {}", .found.join(","), .expected.join(","))]
+    Reordered {
+        found: Vec<String>,
+        expected: Vec<&'static str>,
+    },
+}
+
+/// Checks that `headers` are the columns of [`COLUMNS`], in that order, with or without the
+/// optional ones.
+pub fn validate_headers(headers: &csv::StringRecord) -> Result<(), HeaderError> {
+    let found: Vec<String> = headers.iter().map(str::to_owned).collect();
+
+    let mut duplicate: Vec<String> = found
+        .iter()
+        .enumerate()
+        .filter(|(index, column)| found[..*index].contains(column))
+        .map(|(_, column)| column.clone())
+        .collect();
+    duplicate.dedup();
+    if !duplicate.is_empty() {
+        return Err(HeaderError::Duplicate(duplicate));
+    }
+
+    let unknown: Vec<String> = found
+        .iter()
+        .filter(|column| !COLUMNS.contains(&column.as_str()))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(HeaderError::Unknown(unknown));
+    }
+
+    let missing: Vec<&'static str> = COLUMNS[..REQUIRED_COLUMNS]
+        .iter()
+        .filter(|&&column| !found.iter().any(|found| found == column))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(HeaderError::Missing(missing));
+    }
+
+    // Every column is known and there once, so they're in order if they're sorted by
+    // their position in the schema
+    let expected: Vec<&'static str> = COLUMNS
+        .into_iter()
+        .filter(|&column| found.iter().any(|found| found == column))
+        .collect();
+    if found != expected {
+        return Err(HeaderError::Reordered { found, expected });
+    }
+
+    Ok(())
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -62,48 +174,807 @@ impl TryFrom<TransactionRow> for TransactionEvent {
     }
 }
 
+impl TryFrom<TransactionRow> for TenantEvent {
+    type Error = CsvDecodeError;
+
+    fn try_from(mut row: TransactionRow) -> Result<Self, Self::Error> {
+        Ok(TenantEvent {
+            tenant: row.tenant,
+            metadata: std::mem::take(&mut row.metadata),
+            event: row.try_into()?,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ClientRow {
     pub client: ClientId,
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl From<ClientInformation> for ClientRow {
+    fn from(client: ClientInformation) -> Self {
+        Self {
+            client: client.id,
+            available: amount::round(client.available),
+            held: amount::round(client.held),
+            total: amount::round(client.total),
+            locked: client.frozen,
+        }
+    }
+}
+
+/// A [`ClientRow`] of a multi-tenant run. The tenant is empty for the default ledger.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TenantClientRow {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    #[serde(serialize_with = "amount::serialize")]
     pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
     pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
     pub total: Decimal,
     pub locked: bool,
 }
 
-pub fn csv_processor<R, W, DB>(
-    mut csv_reader: csv::Reader<R>,
+impl TenantClientRow {
+    fn new(tenant: Option<TenantId>, client: ClientInformation) -> Self {
+        let row = ClientRow::from(client);
+        Self {
+            tenant,
+            client: row.client,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+        }
+    }
+}
+
+/// A line of the usage report of a multi-tenant run, see [`TenantUsage`]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TenantUsageRow {
+    pub tenant: Option<TenantId>,
+    pub events: u64,
+    pub rejected: u64,
+    pub transactions: u64,
+    pub clients: u64,
+    pub api_calls: u64,
+    pub storage_bytes: Option<u64>,
+}
+
+impl TenantUsageRow {
+    fn new(tenant: Option<TenantId>, usage: TenantUsage) -> Self {
+        Self {
+            tenant,
+            events: usage.events,
+            rejected: usage.rejected,
+            transactions: usage.transactions,
+            clients: usage.clients,
+            api_calls: usage.api_calls,
+            storage_bytes: usage.storage_bytes,
+        }
+    }
+}
+
+/// Writes the usage of every tenant of `db`, one row per tenant.
+pub fn write_usage_report<W, DB>(
     mut csv_writer: csv::Writer<W>,
-    db: &mut DB,
+    db: &TenantDb<DB>,
 ) -> anyhow::Result<()>
 where
-    R: std::io::Read,
     W: std::io::Write,
-    DB: TransactionProcessor,
+    DB: TransactionProcessor + StorageFootprint,
 {
-    for row in csv_reader.deserialize() {
-        let transaction_row: TransactionRow = row?;
-        let transaction: TransactionEvent = transaction_row.try_into()?;
+    for (tenant, usage) in db.usage_report() {
+        csv_writer.serialize(TenantUsageRow::new(tenant, usage))?;
+    }
+    csv_writer.flush()?;
 
-        info!("Processing transaction event: {:?}", transaction);
-        if let Err(err) = db.process_transaction_event(transaction) {
-            error!("transaction error: {err}")
+    Ok(())
+}
+
+/// How the fields and records of a CSV input are separated, eg tabs for TSV or semicolons
+/// for spreadsheets exported with a European locale. The default is plain RFC 4180.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    /// Quote character, or `None` if quotes are regular characters
+    pub quote: Option<u8>,
+    /// Record terminator, or `None` for any of `\n`, `\r` and `\r\n`
+    pub terminator: Option<u8>,
+    /// Whether the first record is a header. Without one, columns are read by position:
+    /// `type,client,tx,amount`, then the optional `tenant`, `timestamp` and `sequence`, and rows can
+    /// leave out trailing columns, eg `dispute,1,2`.
+    pub headers: bool,
+    /// Whether the header has to match [`COLUMNS`], see [`Self::checked_reader`]. Unknown
+    /// columns are ignored otherwise, and problems only show up once a row fails to decode.
+    pub strict_headers: bool,
+    /// How amounts are written, eg `1.234,56` by partners in many European locales
+    pub decimal: DecimalFormat,
+    /// Whether extra columns are kept as the events' [`Metadata`], see
+    /// [`TransactionReader::with_metadata`]
+    pub metadata: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: Some(b'"'),
+            terminator: None,
+            headers: true,
+            strict_headers: false,
+            decimal: DecimalFormat::default(),
+            metadata: false,
         }
     }
+}
 
-    for client in db.clients_iter() {
-        let row = ClientRow {
-            client: client.id,
-            available: client.available.round_dp(DECIMAL_PLACES),
-            held: client.held.round_dp(DECIMAL_PLACES),
-            total: client.total.round_dp(DECIMAL_PLACES),
-            locked: client.frozen,
+impl CsvDialect {
+    /// A reader of transaction rows in this dialect, for [`csv_processor`] and friends.
+    /// Whitespace around fields is trimmed.
+    pub fn reader<R: std::io::Read>(&self, reader: R) -> csv::Reader<R> {
+        csv::ReaderBuilder::default()
+            .has_headers(self.headers)
+            .flexible(!self.headers)
+            .trim(csv::Trim::All)
+            .delimiter(self.delimiter)
+            .quoting(self.quote.is_some())
+            .quote(self.quote.unwrap_or(b'"'))
+            .terminator(
+                self.terminator
+                    .map_or(csv::Terminator::CRLF, csv::Terminator::Any),
+            )
+            .from_reader(reader)
+    }
+
+    /// A writer of CSV files in this dialect, eg to rewrite an input in the dialect it was
+    /// read in.
+    pub fn writer<W: std::io::Write>(&self, writer: W) -> csv::Writer<W> {
+        csv::WriterBuilder::default()
+            .has_headers(false)
+            .flexible(!self.headers)
+            .delimiter(self.delimiter)
+            .quote_style(match self.quote {
+                Some(_) => csv::QuoteStyle::Necessary,
+                None => csv::QuoteStyle::Never,
+            })
+            .quote(self.quote.unwrap_or(b'"'))
+            .terminator(csv::Terminator::Any(self.terminator.unwrap_or(b'\n')))
+            .from_writer(writer)
+    }
+
+    /// Same as [`Self::reader`], but with [`Self::strict_headers`] the header row is read
+    /// and checked with [`validate_headers`] right away, before any row is processed.
+    pub fn checked_reader<R: std::io::Read>(&self, reader: R) -> anyhow::Result<csv::Reader<R>> {
+        let mut csv_reader = self.reader(reader);
+        if self.headers && self.strict_headers {
+            validate_headers(csv_reader.headers()?)?;
+        }
+
+        Ok(csv_reader)
+    }
+
+    /// A [`Self::checked_reader`] decoding rows with this dialect's [`DecimalFormat`], for
+    /// [`csv_processor`] and friends.
+    pub fn transaction_reader<R: std::io::Read>(
+        &self,
+        reader: R,
+    ) -> anyhow::Result<TransactionReader<R>> {
+        anyhow::ensure!(
+            Some(self.decimal.decimal_separator) != self.decimal.thousands_separator,
+            "the decimal and thousands separators have to be different"
+        );
+
+        Ok(
+            TransactionReader::new(self.checked_reader(reader)?, self.decimal)
+                .with_metadata(self.metadata),
+        )
+    }
+}
+
+/// Separators of the amounts of an input. The default is a plain `1234.56`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecimalFormat {
+    pub decimal_separator: u8,
+    /// Dropped from amounts wherever it is, eg `.` for `1.234,56`
+    pub thousands_separator: Option<u8>,
+}
+
+impl Default for DecimalFormat {
+    fn default() -> Self {
+        Self {
+            decimal_separator: b'.',
+            thousands_separator: None,
+        }
+    }
+}
+
+impl DecimalFormat {
+    /// Rewrites `amount` as a plain `1234.56`, the way [`Decimal`] parses it.
+    pub fn normalize<'a>(&self, amount: &'a [u8]) -> Cow<'a, [u8]> {
+        if *self == Self::default() {
+            return Cow::Borrowed(amount);
+        }
+
+        Cow::Owned(
+            amount
+                .iter()
+                .filter(|&&byte| Some(byte) != self.thousands_separator)
+                .map(|&byte| match byte {
+                    _ if byte == self.decimal_separator => b'.',
+                    // A plain decimal point in a `1234,56` amount would make it a different
+                    // amount, make sure it doesn't parse
+                    b'.' => b'?',
+                    _ => byte,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Reads [`TransactionRow`]s from a CSV reader, by column name if it has headers and by
+/// position otherwise, with amounts written in a [`DecimalFormat`].
+pub struct TransactionReader<R> {
+    csv: csv::Reader<R>,
+    decimal: DecimalFormat,
+    /// The header and the index of the amount column, read along with the first row
+    columns: Option<(Option<csv::ByteRecord>, Option<usize>)>,
+    metadata: bool,
+}
+
+impl<R> From<csv::Reader<R>> for TransactionReader<R> {
+    fn from(csv: csv::Reader<R>) -> Self {
+        Self::new(csv, DecimalFormat::default())
+    }
+}
+
+impl<R> TransactionReader<R> {
+    pub fn new(csv: csv::Reader<R>, decimal: DecimalFormat) -> Self {
+        Self {
+            csv,
+            decimal,
+            columns: None,
+            metadata: false,
+        }
+    }
+
+    /// Keeps the non-empty values of columns other than [`COLUMNS`] as the metadata of the
+    /// rows, instead of ignoring them. Only inputs with a header have such columns.
+    pub fn with_metadata(mut self, metadata: bool) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+impl<R: std::io::Read> TransactionReader<R> {
+    /// Reads the header, if there's one, and finds the amount column. Done by the first
+    /// [`Self::read_record`] otherwise.
+    pub fn read_headers(&mut self) -> anyhow::Result<()> {
+        if self.columns.is_some() {
+            return Ok(());
+        }
+
+        let headers = if self.csv.has_headers() {
+            Some(self.csv.byte_headers()?.clone())
+        } else {
+            None
         };
+        let amount = match &headers {
+            Some(headers) => headers.iter().position(|column| column == b"amount"),
+            None => Some(3),
+        };
+        self.columns = Some((headers, amount));
 
-        csv_writer.serialize(row)?;
+        Ok(())
     }
 
-    csv_writer.flush()?;
+    /// Reads the next row into `record`, returns false once the input is exhausted.
+    pub fn read_record(&mut self, record: &mut csv::ByteRecord) -> anyhow::Result<bool> {
+        self.read_headers()?;
+        Ok(self.csv.read_byte_record(record)?)
+    }
+
+    /// Position after the last row read
+    pub fn position(&self) -> &csv::Position {
+        self.csv.position()
+    }
+
+    /// Decodes a row read by [`Self::read_record`].
+    pub fn decode(&self, record: &csv::ByteRecord) -> anyhow::Result<TransactionRow> {
+        let (headers, amount) = self
+            .columns
+            .as_ref()
+            .map_or((None, None), |(headers, amount)| {
+                (headers.as_ref(), *amount)
+            });
+
+        let mut row: TransactionRow = match amount {
+            Some(amount) if self.decimal != DecimalFormat::default() => {
+                let mut normalized =
+                    csv::ByteRecord::with_capacity(record.as_slice().len(), record.len());
+                for (index, field) in record.iter().enumerate() {
+                    if index == amount {
+                        normalized.push_field(&self.decimal.normalize(field));
+                    } else {
+                        normalized.push_field(field);
+                    }
+                }
+                normalized.set_position(record.position().cloned());
+                normalized.deserialize(headers)?
+            }
+            _ => record.deserialize(headers)?,
+        };
+
+        if self.metadata
+            && let Some(headers) = headers
+        {
+            row.metadata = headers
+                .iter()
+                .zip(record)
+                .filter(|(column, value)| {
+                    !value.is_empty() && !COLUMNS.iter().any(|known| known.as_bytes() == *column)
+                })
+                .map(|(column, value)| {
+                    (
+                        String::from_utf8_lossy(column).into_owned(),
+                        String::from_utf8_lossy(value).into_owned(),
+                    )
+                })
+                .collect();
+        }
+
+        Ok(row)
+    }
+
+    /// Iterates over the remaining rows.
+    pub fn rows(&mut self) -> impl Iterator<Item = anyhow::Result<TransactionRow>> + '_ {
+        let mut record = csv::ByteRecord::new();
+        std::iter::from_fn(move || match self.read_record(&mut record) {
+            Ok(true) => Some(self.decode(&record)),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        })
+    }
+}
+
+impl<R: std::io::Read + std::io::Seek> TransactionReader<R> {
+    /// Reads the header, then moves to `position`, which has to be right after a row.
+    pub fn seek(&mut self, position: csv::Position) -> anyhow::Result<()> {
+        self.read_headers()?;
+        Ok(self.csv.seek(position)?)
+    }
+}
+
+/// Applies every row of `csv_reader` to `db`, then writes the clients to `output`: a
+/// [`csv::Writer`], or a [`crate::export::FormatWriter`] for other formats.
+///
+/// Rows are read by column name if `csv_reader` has headers, and by position otherwise (see
+/// [`CsvDialect::headers`]).
+pub fn csv_processor<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut DB,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    process_rows(
+        &mut csv_reader.into(),
+        db,
+        Start::Skip(0),
+        &mut metrics,
+        |_, _| Ok(ControlFlow::Continue(())),
+    )?;
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// When [`csv_processor_emitting`] writes the state of the clients before the end of its
+/// input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmitPolicy {
+    /// Every this many rows
+    pub every: Option<NonZeroU64>,
+    /// At the first row at least this long after the state was last written. Nothing is
+    /// written while no rows come in, the state didn't change then.
+    pub interval: Option<Duration>,
+}
+
+impl EmitPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.every.is_some() || self.interval.is_some()
+    }
+}
+
+/// Same as [`csv_processor`], but also writes the state of every client while processing,
+/// as `policy` says, eg for an endless stream read from stdin.
+///
+/// Every time, a row is written for every client and the output is flushed, so readers take
+/// the last row of a client as its current state.
+pub fn csv_processor_emitting<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut DB,
+    policy: EmitPolicy,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    csv_processor_draining(csv_reader, output, db, policy, &DrainSignal::new())
+}
+
+/// Same as [`csv_processor_emitting`], but stops at the first row after `drain` was
+/// requested, eg on SIGTERM, and writes the clients as they are then.
+///
+/// [`RunMetrics::drained_at`] tells whether it stopped before the end of the input.
+pub fn csv_processor_draining<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    mut output: O,
+    db: &mut DB,
+    policy: EmitPolicy,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    let mut emitted = Instant::now();
+    let (records, _, drained) = process_rows(
+        &mut csv_reader.into(),
+        db,
+        Start::Skip(0),
+        &mut metrics,
+        |db, position| {
+            let due = policy
+                .every
+                .is_some_and(|every| position.record() % every.get() == 0)
+                || policy
+                    .interval
+                    .is_some_and(|interval| emitted.elapsed() >= interval);
+            if due {
+                write_client_rows(&mut output, db)?;
+                output.flush()?;
+                emitted = Instant::now();
+            }
+
+            match drain.is_draining() {
+                true => Ok(ControlFlow::Break(())),
+                false => Ok(ControlFlow::Continue(())),
+            }
+        },
+    )?;
+    if drained {
+        metrics.drained_at = Some(records);
+    }
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Same as [`csv_processor`], but routes rows to the ledger of their `tenant` column and
+/// writes the clients of every ledger, with a leading `tenant` column.
+pub fn csv_processor_multi_tenant<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut TenantDb<DB>,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    csv_processor_multi_tenant_draining(csv_reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`csv_processor_multi_tenant`], but stops at the first row after `drain` was
+/// requested and writes the clients as they are then, like [`csv_processor_draining`].
+pub fn csv_processor_multi_tenant_draining<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    mut output: O,
+    db: &mut TenantDb<DB>,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    let (records, _, drained) = process_rows(
+        &mut csv_reader.into(),
+        db,
+        Start::Skip(0),
+        &mut metrics,
+        |_, _| match drain.is_draining() {
+            true => Ok(ControlFlow::Break(())),
+            false => Ok(ControlFlow::Continue(())),
+        },
+    )?;
+    if drained {
+        metrics.drained_at = Some(records);
+    }
+
+    for (tenant, client) in db.all_clients() {
+        output.write_row(&TenantClientRow::new(tenant, client))?;
+    }
+    output.finish()?;
+
+    Ok(metrics)
+}
+
+/// Same as [`csv_processor`], but periodically saves a [`Checkpoint`] of the processed
+/// offset and DB state to `config.path`.
+///
+/// If a checkpoint already exists, the DB state is restored from it and the rows it
+/// covers are skipped, so an interrupted run can pick up where it left off. Those rows are
+/// read through again, see [`csv_processor_checkpointed_seeking`] to skip them at once.
+///
+/// When drained, the state is saved in the checkpoint and no clients are written: they're
+/// written by the run which resumes from it and gets to the end of the input.
+///
+/// ## Errors
+///
+/// If the checkpoint was taken on another input than `config.input`.
+pub fn csv_processor_checkpointed<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut DB,
+    config: &CheckpointConfig,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor + StateStore,
+{
+    checkpointed(csv_reader.into(), output, db, config, |_, checkpoint| {
+        Ok(Start::Skip(checkpoint.records))
+    })
+}
+
+/// Same as [`csv_processor_checkpointed`], but seeks right after the last row of the
+/// checkpoint instead of reading through the rows it covers, for inputs which can seek.
+///
+/// ## Errors
+///
+/// If the checkpoint was taken on another input than `config.input`.
+pub fn csv_processor_checkpointed_seeking<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut DB,
+    config: &CheckpointConfig,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read + std::io::Seek,
+    O: OutputWriter,
+    DB: TransactionProcessor + StateStore,
+{
+    checkpointed(
+        csv_reader.into(),
+        output,
+        db,
+        config,
+        |csv_reader, checkpoint| {
+            let mut position = csv::Position::new();
+            position
+                .set_byte(checkpoint.byte_offset)
+                .set_line(checkpoint.line)
+                .set_record(checkpoint.records);
+            csv_reader.seek(position)?;
+
+            Ok(Start::Seeked(checkpoint.records))
+        },
+    )
+}
+
+/// Runs [`csv_processor_checkpointed`], moving `csv_reader` past the rows of an existing
+/// checkpoint with `resume`.
+fn checkpointed<R, O, DB, F>(
+    mut csv_reader: TransactionReader<R>,
+    output: O,
+    db: &mut DB,
+    config: &CheckpointConfig,
+    resume: F,
+) -> anyhow::Result<RunMetrics>
+where
+    R: std::io::Read,
+    O: OutputWriter,
+    DB: TransactionProcessor + StateStore,
+    F: FnOnce(&mut TransactionReader<R>, &Checkpoint) -> anyhow::Result<Start>,
+{
+    let start = match Checkpoint::load(&config.path, config.key.as_ref())? {
+        Some(checkpoint) => {
+            checkpoint
+                .check_input(config.input.as_ref())
+                .with_context(|| format!("can't resume from {}", config.path.display()))?;
+            info!(
+                "Resuming from checkpoint at record {} (byte {})",
+                checkpoint.records, checkpoint.byte_offset
+            );
+            let start = resume(&mut csv_reader, &checkpoint)?;
+            db.restore(checkpoint.snapshot)?;
+            start
+        }
+        None => Start::Skip(0),
+    };
+
+    let save = |db: &DB, position: &csv::Position, records: u64| {
+        Checkpoint {
+            records,
+            byte_offset: position.byte(),
+            line: position.line(),
+            input: config.input.clone(),
+            snapshot: db.snapshot()?,
+        }
+        .save(&config.path, config.key.as_ref())
+    };
+
+    let mut metrics = RunMetrics::new();
+    let (records, position, drained) =
+        process_rows(&mut csv_reader, db, start, &mut metrics, |db, position| {
+            let records = position.record();
+            if records % config.every.get() == 0 {
+                save(db, position, records)?;
+            }
+
+            match &config.drain {
+                Some(drain) if drain.is_draining() => Ok(ControlFlow::Break(())),
+                _ => Ok(ControlFlow::Continue(())),
+            }
+        })?;
+
+    save(db, &position, records)?;
+    if drained {
+        metrics.drained_at = Some(records);
+        return Ok(metrics);
+    }
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Where [`process_rows`] starts in its input
+#[derive(Debug, Clone, Copy)]
+enum Start {
+    /// Reads through this many rows without applying them
+    Skip(u64),
+    /// The reader was already moved past this many rows
+    Seeked(u64),
+}
+
+/// Feeds every row after `start` to the DB, calling `after_row` once each row is applied,
+/// until it breaks. Returns the number of rows read, the position after the
+/// last one and whether `after_row` stopped the processing.
+///
+/// The time from parsing each row to applying it, and whether it was rejected, are
+/// recorded in `metrics`.
+///
+/// The position's record number counts data rows only, so it can be fed back as `start`.
+fn process_rows<R, DB, F>(
+    csv_reader: &mut TransactionReader<R>,
+    db: &mut DB,
+    start: Start,
+    metrics: &mut RunMetrics,
+    mut after_row: F,
+) -> anyhow::Result<(u64, csv::Position, bool)>
+where
+    R: std::io::Read,
+    DB: TenantProcessor,
+    F: FnMut(&DB, &csv::Position) -> anyhow::Result<ControlFlow<()>>,
+{
+    // So the initial position is after the header
+    csv_reader.read_headers()?;
+
+    let (mut records, skip) = match start {
+        Start::Skip(skip) => (0, skip),
+        Start::Seeked(records) => (records, 0),
+    };
+    let mut record = csv::ByteRecord::new();
+    let mut position = csv_reader.position().clone();
+    position.set_record(records);
+
+    while csv_reader.read_record(&mut record)? {
+        records += 1;
+        position = csv_reader.position().clone();
+        position.set_record(records);
+
+        if records <= skip {
+            continue;
+        }
+
+        let started = Instant::now();
+        let transaction_row = csv_reader.decode(&record)?;
+        apply_row(db, transaction_row, started, metrics)?;
+
+        if after_row(db, &position)?.is_break() {
+            return Ok((records, position, true));
+        }
+    }
+
+    Ok((records, position, false))
+}
+
+/// Applies a decoded row to the DB, logging it if it's rejected. `started` is when the row
+/// started being parsed.
+pub(crate) fn apply_row<DB: TenantProcessor>(
+    db: &mut DB,
+    transaction_row: TransactionRow,
+    started: Instant,
+    metrics: &mut RunMetrics,
+) -> anyhow::Result<()> {
+    let timestamp = transaction_row.timestamp;
+    if transaction_row.sequence.is_some() {
+        metrics.last_sequence = transaction_row.sequence;
+    }
+    let transaction: TenantEvent = transaction_row.try_into()?;
+    let client = (transaction.tenant, transaction.event.client());
+    let kind = transaction.event.kind();
+
+    info!("Processing transaction event: {:?}", transaction);
+    if let Some(timestamp) = timestamp {
+        db.follow_event_time(timestamp);
+    }
+    let result = db.process_tenant_event(transaction);
+    if let Err(err) = &result {
+        error!(
+            "{}",
+            Message::new("transaction-error").arg("error", err.message())
+        )
+    }
+    metrics.record(client, kind, started.elapsed(), &result);
+    if let Some(timestamp) = timestamp {
+        metrics.activity.record_at(client, timestamp);
+    }
+
+    Ok(())
+}
+
+/// Whether `drain` was requested, in which case a run stops before its next event, and
+/// records in `metrics` how many it applied. For the processors of other formats than CSV.
+pub(crate) fn drained(drain: &DrainSignal, metrics: &mut RunMetrics) -> bool {
+    let drained = drain.is_draining();
+    if drained {
+        metrics.drained_at = Some(metrics.latencies.count());
+    }
+
+    drained
+}
+
+pub(crate) fn write_clients<O, DB>(mut output: O, db: &DB) -> anyhow::Result<()>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    write_client_rows(&mut output, db)?;
+    output.finish()?;
+
+    Ok(())
+}
+
+fn write_client_rows<O, DB>(output: &mut O, db: &DB) -> anyhow::Result<()>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    for client in db.clients_iter() {
+        output.write_row(&ClientRow::from(client))?;
+    }
 
     Ok(())
 }
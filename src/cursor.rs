@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    clock::Timestamp,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// Position of an event in its source, eg a Kafka partition offset or a NATS stream
+/// sequence. Positions must increase along the source.
+pub type Cursor = u64;
+
+/// Positions in their sources of the events applied to a state, as JSON by source, eg
+/// `kafka:transactions` for the offsets of the partitions of that topic.
+pub type SourceOffsets = BTreeMap<String, String>;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum CursorError {
+    #[error("event at cursor {cursor} was already applied (last applied cursor is {last})")]
+    AlreadyApplied { cursor: Cursor, last: Cursor },
+
+    /// The event was consumed (the cursor moved past it) but the DB rejected it
+    #[error(transparent)]
+    Rejected(#[from] TransactionError),
+}
+
+/// Outcome of [`CursorProcessor::apply_since`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CursorSummary {
+    pub applied: u64,
+    pub rejected: u64,
+    /// Events at or before the last applied cursor, which were ignored
+    pub skipped: u64,
+}
+
+/// Wraps a [`TransactionProcessor`] and tracks the source position of the last event
+/// applied to it, so redelivered events are never applied twice.
+///
+/// Integrating with an at-least-once source then only needs the cursor to be stored with
+/// the state: [`StateStore::snapshot`] includes it, and [`StateStore::restore`] picks it
+/// back up, so after a crash the source can be replayed from any earlier offset.
+pub struct CursorProcessor<DB> {
+    inner: DB,
+    cursor: Option<Cursor>,
+}
+
+impl<DB: TransactionProcessor> CursorProcessor<DB> {
+    pub fn new(inner: DB) -> Self {
+        Self {
+            inner,
+            cursor: None,
+        }
+    }
+
+    /// Resumes after `cursor`, the cursor of the last event `inner` already includes, eg
+    /// as saved along its state by an [`OffsetProcessor`].
+    pub fn since(inner: DB, cursor: Option<Cursor>) -> Self {
+        Self { inner, cursor }
+    }
+
+    /// Cursor of the last applied event, or `None` if nothing was applied yet
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.inner
+    }
+
+    /// Applies the event found at `cursor` in the source.
+    ///
+    /// ## Errors
+    /// - If `cursor` isn't past the last applied cursor, returns
+    ///   [`CursorError::AlreadyApplied`] and leaves the DB untouched
+    /// - If the DB rejects the event, returns [`CursorError::Rejected`]. The event still
+    ///   counts as consumed, so the cursor moves past it, unless it was rejected by a
+    ///   [`TransactionError::Storage`] error: it can be applied again once redelivered.
+    pub fn apply(&mut self, cursor: Cursor, event: TransactionEvent) -> Result<(), CursorError> {
+        if let Some(last) = self.cursor
+            && cursor <= last
+        {
+            return Err(CursorError::AlreadyApplied { cursor, last });
+        }
+
+        let result = self.inner.process_transaction_event(event);
+        if !matches!(result, Err(TransactionError::Storage(_))) {
+            self.cursor = Some(cursor);
+        }
+
+        Ok(result?)
+    }
+
+    /// Applies a batch of `(cursor, event)` pairs read from the source, skipping the ones
+    /// which were already applied. Replaying a source from an earlier position is safe.
+    pub fn apply_since<I>(&mut self, events: I) -> CursorSummary
+    where
+        I: IntoIterator<Item = (Cursor, TransactionEvent)>,
+    {
+        let mut summary = CursorSummary::default();
+
+        for (cursor, event) in events {
+            match self.apply(cursor, event) {
+                Ok(()) => summary.applied += 1,
+                Err(CursorError::AlreadyApplied { .. }) => summary.skipped += 1,
+                Err(CursorError::Rejected(_)) => summary.rejected += 1,
+            }
+        }
+
+        summary
+    }
+}
+
+impl<DB: StateStore> StateStore for CursorProcessor<DB> {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        Ok(Snapshot {
+            cursor: self.cursor,
+            ..self.inner.snapshot()?
+        })
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let cursor = snapshot.cursor;
+        self.inner.restore(snapshot)?;
+        self.cursor = cursor;
+
+        Ok(())
+    }
+}
+
+/// Wraps a [`TransactionProcessor`] and keeps the positions its sources reached along its
+/// state, for sources with positions of their own, eg the offsets of every partition of a
+/// Kafka topic or the shard checkpoints of a Kinesis stream.
+///
+/// [`StateStore::snapshot`] includes the positions and [`StateStore::restore`] picks them
+/// back up, so the state and the positions are saved at once: after a crash the source
+/// resumes right after the last event the saved state includes, no matter how far it
+/// committed its positions elsewhere. Events it's still handed twice, eg because it couldn't
+/// seek, are rejected by the duplicate transaction checks.
+pub struct OffsetProcessor<DB> {
+    inner: DB,
+    offsets: SourceOffsets,
+}
+
+impl<DB: TransactionProcessor> OffsetProcessor<DB> {
+    pub fn new(inner: DB) -> Self {
+        Self {
+            inner,
+            offsets: SourceOffsets::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut DB {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.inner
+    }
+
+    pub fn offsets(&self) -> &SourceOffsets {
+        &self.offsets
+    }
+
+    /// Position reached in `source`, if it was saved
+    pub fn position<T: DeserializeOwned>(&self, source: &str) -> anyhow::Result<Option<T>> {
+        self.offsets
+            .get(source)
+            .map(|position| serde_json::from_str(position))
+            .transpose()
+            .with_context(|| format!("invalid position in {source}"))
+    }
+
+    /// Records the position reached in `source` by the events applied so far
+    pub fn set_position<T: Serialize>(&mut self, source: &str, position: &T) -> anyhow::Result<()> {
+        self.offsets
+            .insert(source.to_owned(), serde_json::to_string(position)?);
+        Ok(())
+    }
+}
+
+impl<DB: TransactionProcessor> TransactionProcessor for OffsetProcessor<DB> {
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.deposit(transaction_id, client_id, amount)
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.withdrawal(transaction_id, client_id, amount)
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.dispute(transaction_id, client_id)
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.resolve(transaction_id, client_id)
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.chargeback(transaction_id, client_id)
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.inner.process_annotated_event(transaction, metadata)
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB: StateStore> StateStore for OffsetProcessor<DB> {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        Ok(Snapshot {
+            offsets: self.offsets.clone(),
+            ..self.inner.snapshot()?
+        })
+    }
+
+    fn restore(&mut self, mut snapshot: Snapshot) -> anyhow::Result<()> {
+        let offsets = std::mem::take(&mut snapshot.offsets);
+        self.inner.restore(snapshot)?;
+        self.offsets = offsets;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn deposit(tx: u32) -> TransactionEvent {
+        TransactionEvent::Deposit {
+            tx,
+            client: 1,
+            amount: dec!(1),
+        }
+    }
+
+    #[test]
+    fn redelivered_events_are_skipped() {
+        let mut db = CursorProcessor::new(InMemoryTransactionDb::new());
+        db.apply(10, deposit(1)).unwrap();
+        assert_eq!(
+            db.apply(10, deposit(1)),
+            Err(CursorError::AlreadyApplied {
+                cursor: 10,
+                last: 10
+            })
+        );
+
+        // Replaying from an earlier offset only applies what's new
+        let summary = db.apply_since([
+            (9, deposit(1)),
+            (10, deposit(1)),
+            (11, deposit(2)),
+            (12, deposit(2)),
+        ]);
+        assert_eq!(
+            summary,
+            CursorSummary {
+                applied: 1,
+                rejected: 1,
+                skipped: 2,
+            }
+        );
+        assert_eq!(db.cursor(), Some(12));
+        assert_eq!(db.inner().client(1).unwrap().available, dec!(2));
+    }
+
+    #[test]
+    fn cursor_survives_restore() {
+        let mut db = CursorProcessor::new(InMemoryTransactionDb::new());
+        db.apply(3, deposit(1)).unwrap();
+
+        let mut restored = CursorProcessor::new(InMemoryTransactionDb::new());
+        restored.restore(db.snapshot().unwrap()).unwrap();
+        assert_eq!(restored.cursor(), Some(3));
+
+        let summary = restored.apply_since([(3, deposit(1)), (4, deposit(2))]);
+        assert_eq!(summary.applied, 1);
+        assert_eq!(summary.skipped, 1);
+    }
+
+    #[test]
+    fn offsets_survive_restore() {
+        let mut db = OffsetProcessor::new(InMemoryTransactionDb::new());
+        db.process_transaction_event(deposit(1)).unwrap();
+        db.set_position("kafka:transactions", &BTreeMap::from([(0, 41)]))
+            .unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(snapshot.offsets["kafka:transactions"], r#"{"0":41}"#);
+        let mut restored = OffsetProcessor::new(InMemoryTransactionDb::new());
+        restored.restore(snapshot).unwrap();
+
+        let position: Option<BTreeMap<i32, i64>> = restored.position("kafka:transactions").unwrap();
+        assert_eq!(position, Some(BTreeMap::from([(0, 41)])));
+        assert_eq!(
+            restored.position::<u64>("kinesis:transactions").unwrap(),
+            None
+        );
+        assert_eq!(restored.client(1).unwrap().available, dec!(1));
+    }
+}
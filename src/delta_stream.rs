@@ -0,0 +1,285 @@
+use std::io::Write;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount,
+    clock::Timestamp,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// How much an applied event moved a client's balances
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceDelta {
+    /// Sequence number the DB assigned to the event
+    pub seq: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Event type, eg `deposit`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(serialize_with = "amount::serialize")]
+    pub delta_available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub delta_held: Decimal,
+}
+
+/// Wraps a [`TransactionProcessor`] and writes a [`BalanceDelta`] as a line of JSON for
+/// every event it applies successfully, so a downstream aggregator can keep running totals
+/// by tailing the stream. Rejected events are not part of it.
+///
+/// Unlike the [`crate::replication::ChangeStreamProcessor`]'s records, deltas don't carry
+/// the resulting balances: summing them gives the balances. The writer should be line
+/// buffered (eg a [`std::io::LineWriter`]) for consumers to see deltas as they're applied.
+pub struct DeltaStreamProcessor<DB, W> {
+    inner: DB,
+    writer: W,
+}
+
+impl<DB, W> DeltaStreamProcessor<DB, W>
+where
+    DB: TransactionProcessor,
+    W: Write,
+{
+    pub fn new(inner: DB, writer: W) -> Self {
+        Self { inner, writer }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, W) {
+        (self.inner, self.writer)
+    }
+
+    /// Applies `event` with `apply`, and writes the balance changes it caused.
+    fn apply<F>(&mut self, event: TransactionEvent, apply: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut DB) -> Result<(), TransactionError>,
+    {
+        let client_id = event.client();
+        let (available, held) = self
+            .inner
+            .client(client_id)
+            .map_or((Decimal::ZERO, Decimal::ZERO), |client| {
+                (client.available, client.held)
+            });
+
+        apply(&mut self.inner)?;
+
+        let Some(client) = self.inner.client(client_id) else {
+            return Err(TransactionError::ClientNotFound { client_id });
+        };
+        let delta = BalanceDelta {
+            seq: self.inner.last_seq(),
+            client: client_id,
+            tx: event.tx(),
+            event_type: event.kind().as_str().to_owned(),
+            delta_available: client.available - available,
+            delta_held: client.held - held,
+        };
+
+        let write = |writer: &mut W| -> std::io::Result<()> {
+            serde_json::to_writer(&mut *writer, &delta)?;
+            writer.write_all(b"\n")
+        };
+        write(&mut self.writer)
+            .map_err(|err| TransactionError::Storage(format!("delta stream: {err}")))
+    }
+}
+
+impl<DB, W> TransactionProcessor for DeltaStreamProcessor<DB, W>
+where
+    DB: TransactionProcessor,
+    W: Write,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Deposit {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.deposit(transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Withdrawal {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.withdrawal(transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Dispute {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.dispute(transaction_id, client_id))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Resolve {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.resolve(transaction_id, client_id))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Chargeback {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.chargeback(transaction_id, client_id))
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.apply(transaction.clone(), |db| {
+            db.process_annotated_event(transaction, metadata)
+        })
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB, W> StateStore for DeltaStreamProcessor<DB, W>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn deltas(output: &[u8]) -> Vec<BalanceDelta> {
+        output
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn streams_deltas() {
+        let mut db = DeltaStreamProcessor::new(InMemoryTransactionDb::new(), Vec::new());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.withdrawal(3, 1, dec!(100)).unwrap_err();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+
+        let (db, output) = db.into_inner();
+        let deltas = deltas(&output);
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|delta| (
+                    delta.event_type.as_str(),
+                    delta.delta_available,
+                    delta.delta_held
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("deposit", dec!(10), dec!(0)),
+                ("withdrawal", dec!(-3), dec!(0)),
+                ("dispute", dec!(-10), dec!(10)),
+                ("chargeback", dec!(0), dec!(-10)),
+            ]
+        );
+
+        // They add up to the final balances
+        let client = db.client(1).unwrap();
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|delta| delta.delta_available)
+                .sum::<Decimal>(),
+            client.available
+        );
+        assert_eq!(deltas.last().unwrap().seq, db.last_seq());
+    }
+
+    #[test]
+    fn record_format() {
+        let mut db = DeltaStreamProcessor::new(InMemoryTransactionDb::new(), Vec::new());
+        db.deposit(7, 3, dec!(1.5)).unwrap();
+
+        let (_, output) = db.into_inner();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"{"seq":1,"client":3,"tx":7,"type":"deposit","delta_available":"1.5000","delta_held":"0.0000"}
+"#
+        );
+    }
+}
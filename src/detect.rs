@@ -0,0 +1,91 @@
+//! Detection of the format of an input from its first bytes, so processing runs don't need
+//! to be told what they're reading.
+
+/// UTF-8 byte order mark, which some exports start text files with
+const BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+const ARROW_FILE_MAGIC: &[u8] = b"ARROW1";
+/// The continuation marker every message of an Arrow IPC stream starts with
+const ARROW_STREAM_MAGIC: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+const AVRO_MAGIC: &[u8] = b"Obj\x01";
+
+/// An input format recognizable from the first bytes of a file, once decompressed (see
+/// [`crate::compression::InputReader`]).
+///
+/// Formats without a recognizable header, like fixed-width records, MessagePack, protobuf or
+/// Avro in the Confluent wire format, can't be told apart from CSV and have to be named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedFormat {
+    Csv,
+    Jsonl,
+    Arrow,
+    Avro,
+    Parquet,
+    Xml,
+}
+
+impl DetectedFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Csv => "CSV",
+            Self::Jsonl => "JSON lines",
+            Self::Arrow => "Arrow",
+            Self::Avro => "Avro",
+            Self::Parquet => "Parquet",
+            Self::Xml => "XML",
+        }
+    }
+}
+
+/// Tells the format of an input from its first bytes, falling back to CSV.
+///
+/// Binary formats are recognized by their magic bytes. Text is JSON lines if it starts with
+/// an object, and XML if it starts with a tag, ignoring a byte order mark and leading
+/// whitespace.
+pub fn detect(header: &[u8]) -> DetectedFormat {
+    if header.starts_with(PARQUET_MAGIC) {
+        return DetectedFormat::Parquet;
+    }
+    if header.starts_with(ARROW_FILE_MAGIC) || header.starts_with(ARROW_STREAM_MAGIC) {
+        return DetectedFormat::Arrow;
+    }
+    if header.starts_with(AVRO_MAGIC) {
+        return DetectedFormat::Avro;
+    }
+
+    let text = header.strip_prefix(BOM).unwrap_or(header);
+    match text.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'{') => DetectedFormat::Jsonl,
+        Some(b'<') => DetectedFormat::Xml,
+        _ => DetectedFormat::Csv,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_formats() {
+        let cases: [(&[u8], DetectedFormat); 11] = [
+            (b"type,client,tx,amount\n", DetectedFormat::Csv),
+            (b"deposit;1;1;1.5\n", DetectedFormat::Csv),
+            (b"", DetectedFormat::Csv),
+            (b"{\"type\":\"deposit\"}\n", DetectedFormat::Jsonl),
+            (
+                b"\xef\xbb\xbf\n  {\"type\":\"deposit\"}",
+                DetectedFormat::Jsonl,
+            ),
+            (b"<?xml version=\"1.0\"?>", DetectedFormat::Xml),
+            (b"\xef\xbb\xbf<transactions>", DetectedFormat::Xml),
+            (b"PAR1\x15\x04", DetectedFormat::Parquet),
+            (b"ARROW1\0\0", DetectedFormat::Arrow),
+            (b"\xff\xff\xff\xff\x78\0\0\0", DetectedFormat::Arrow),
+            (b"Obj\x01\x04\x14avro", DetectedFormat::Avro),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(detect(header), expected, "{:?}", header.escape_ascii());
+        }
+    }
+}
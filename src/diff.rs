@@ -0,0 +1,254 @@
+//! Comparing client reports, behind `octopussy diff`: the output of a run against a golden
+//! one, or against the state of a backend, eg to check that an engine change doesn't change
+//! any balance.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::Read,
+};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    amount, csv::TenantClientRow, snapshot::Snapshot, tenant::TenantId, transaction::ClientId,
+};
+
+/// The balances of a client in a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Balances {
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// The clients of a report by tenant and client, the tenant being `None` for the default
+/// ledger and in single-tenant reports
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report(BTreeMap<(Option<TenantId>, ClientId), Balances>);
+
+impl Report {
+    /// Reads a client report as written by a run, with or without its tenant column.
+    ///
+    /// ## Errors
+    /// - If a row can't be read, or a client is in the report twice
+    pub fn read<R: Read>(reader: R) -> anyhow::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+
+        let mut report = Self::default();
+        for row in reader.deserialize() {
+            let row: TenantClientRow = row?;
+            let balances = Balances {
+                available: row.available,
+                held: row.held,
+                total: row.total,
+                locked: row.locked,
+            };
+            if report
+                .0
+                .insert((row.tenant, row.client), balances)
+                .is_some()
+            {
+                bail!(
+                    "client {} is in the report twice",
+                    Client(row.tenant, row.client)
+                );
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The clients of `actual` which aren't the same in `self`, or are only in one of the
+    /// two, in order. Amounts are compared by value, so `1.5` is the same as `1.5000`.
+    pub fn diff(&self, actual: &Self) -> Vec<ClientDifference> {
+        let keys: BTreeSet<_> = self.0.keys().chain(actual.0.keys()).collect();
+        keys.into_iter()
+            .filter_map(|&(tenant, client)| {
+                let expected = self.0.get(&(tenant, client)).copied();
+                let actual = actual.0.get(&(tenant, client)).copied();
+                (expected != actual).then_some(ClientDifference {
+                    tenant,
+                    client,
+                    expected,
+                    actual,
+                })
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&Snapshot> for Report {
+    /// The client report a run would write for the state of `snapshot`
+    fn from(snapshot: &Snapshot) -> Self {
+        Self(
+            snapshot
+                .clients
+                .iter()
+                .map(|client| {
+                    let balances = Balances {
+                        available: amount::round(client.available),
+                        held: amount::round(client.held),
+                        total: amount::round(client.available + client.held),
+                        locked: client.frozen,
+                    };
+                    ((None, client.id), balances)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A client which isn't the same in two reports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientDifference {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    /// `None` if the client is only in the actual report
+    pub expected: Option<Balances>,
+    /// `None` if the client is missing from the actual report
+    pub actual: Option<Balances>,
+}
+
+impl fmt::Display for ClientDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}: ", Client(self.tenant, self.client))?;
+        let (expected, actual) = match (self.expected, self.actual) {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (Some(_), None) => return write!(f, "missing"),
+            (None, _) => return write!(f, "unexpected"),
+        };
+
+        let amounts = [
+            ("available", expected.available, actual.available),
+            ("held", expected.held, actual.held),
+            ("total", expected.total, actual.total),
+        ];
+        let mut changes = Vec::new();
+        for (field, expected, actual) in amounts {
+            if expected != actual {
+                changes.push(format!(
+                    "{field} {} != {}",
+                    amount::format(expected),
+                    amount::format(actual)
+                ));
+            }
+        }
+        if expected.locked != actual.locked {
+            changes.push(format!("locked {} != {}", expected.locked, actual.locked));
+        }
+
+        write!(f, "{}", changes.join(", "))
+    }
+}
+
+/// A client, with its tenant if it has one
+struct Client(Option<TenantId>, ClientId);
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(tenant) => write!(f, "{} of tenant {tenant}", self.1),
+            None => write!(f, "{}", self.1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::snapshot::ClientSnapshot;
+
+    fn report(csv: &str) -> Report {
+        Report::read(csv.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn reports_differing_clients() {
+        let expected = report(
+            "client,available,held,total,locked\n\
+            1,1.5000,0.0000,1.5000,false\n\
+            2,2.0000,0.0000,2.0000,false\n\
+            3,0.0000,0.0000,0.0000,true\n",
+        );
+        let actual = report(
+            "client, available, held, total, locked\n\
+            1, 1.5, 0, 1.5, false\n\
+            3, 0.0000, 1.0000, 1.0000, false\n\
+            4, 4.0000, 0.0000, 4.0000, false\n",
+        );
+
+        let differences = expected.diff(&actual);
+        let lines: Vec<_> = differences.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "client 2: missing",
+                "client 3: held 0.0000 != 1.0000, total 0.0000 != 1.0000, locked true != false",
+                "client 4: unexpected",
+            ]
+        );
+        assert!(expected.diff(&expected).is_empty());
+
+        let json = serde_json::to_value(&differences[1]).unwrap();
+        assert_eq!(json["expected"]["held"], "0.0000");
+        assert_eq!(json["actual"]["locked"], false);
+    }
+
+    #[test]
+    fn reads_tenant_reports_and_snapshots() {
+        let tenants = report(
+            "tenant,client,available,held,total,locked\n\
+            ,1,1.0000,0.0000,1.0000,false\n\
+            7,1,2.0000,0.0000,2.0000,false\n",
+        );
+        assert_eq!(tenants.len(), 2);
+        assert_eq!(
+            tenants.diff(&report(
+                "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+            ))[0]
+                .to_string(),
+            "client 1 of tenant 7: missing"
+        );
+        assert!(
+            Report::read(
+                "client,available,held,total,locked\n1,0,0,0,false\n1,0,0,0,false\n".as_bytes()
+            )
+            .is_err()
+        );
+
+        let snapshot = Snapshot {
+            clients: vec![ClientSnapshot {
+                id: 1,
+                available: dec!(0.75),
+                held: dec!(0.25),
+                frozen: false,
+            }],
+            ..Snapshot::default()
+        };
+        let live = Report::from(&snapshot);
+        assert!(
+            report("client,available,held,total,locked\n1,0.7500,0.2500,1.0000,false\n")
+                .diff(&live)
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount,
+    clock::{Clock, SystemClock, Timestamp},
+    export::OutputWriter,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, EventKind, Metadata, TransactionError, TransactionEvent,
+        TransactionId, TransactionProcessor,
+    },
+};
+
+/// A currently disputed transaction: the event which recorded it, the dispute, and the
+/// balances of its client after each of them and at the end of the run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisputeTimelineRow {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Type of the original event, `deposit` or `withdrawal`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(serialize_with = "amount::serialize")]
+    pub amount: Decimal,
+    pub original_seq: u64,
+    pub original_at: Timestamp,
+    #[serde(serialize_with = "amount::serialize")]
+    pub original_available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub original_held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub original_total: Decimal,
+    pub dispute_seq: u64,
+    pub disputed_at: Timestamp,
+    #[serde(serialize_with = "amount::serialize")]
+    pub dispute_available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub dispute_held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub dispute_total: Decimal,
+    /// Events applied between the original event and the dispute, of any client
+    pub elapsed_events: u64,
+    /// Seconds between the original event and the dispute
+    pub elapsed_seconds: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// An applied event and the balances of its client right after it
+#[derive(Debug, Clone)]
+struct Point {
+    seq: u64,
+    at: Timestamp,
+    client: ClientInformation,
+}
+
+/// A deposit or withdrawal, and its ongoing dispute if any
+struct Timeline {
+    kind: EventKind,
+    amount: Decimal,
+    original: Point,
+    dispute: Option<Point>,
+}
+
+/// Wraps a [`TransactionProcessor`] and keeps track of the deposits and withdrawals it
+/// applies, so [`DisputeTimelineProcessor::finish`] can write a [`DisputeTimelineRow`] per
+/// transaction which is still disputed, eg a CSV for the disputes team to work from.
+///
+/// Times are read from the clock as events are applied. Transactions are only known from
+/// the moment the processor wraps the DB, so disputes of transactions recorded before it
+/// (eg restored from a snapshot) are left out.
+pub struct DisputeTimelineProcessor<DB, O, C = SystemClock> {
+    inner: DB,
+    output: O,
+    clock: C,
+    /// Keyed like the DB's transaction history, charged back transactions are dropped
+    timelines: BTreeMap<(ClientId, TransactionId), Timeline>,
+}
+
+impl<DB, O> DisputeTimelineProcessor<DB, O>
+where
+    DB: TransactionProcessor,
+    O: OutputWriter,
+{
+    pub fn new(inner: DB, output: O) -> Self {
+        Self::with_clock(inner, output, SystemClock)
+    }
+}
+
+impl<DB, O, C> DisputeTimelineProcessor<DB, O, C>
+where
+    DB: TransactionProcessor,
+    O: OutputWriter,
+    C: Clock,
+{
+    pub fn with_clock(inner: DB, output: O, clock: C) -> Self {
+        Self {
+            inner,
+            output,
+            clock,
+            timelines: BTreeMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, O) {
+        (self.inner, self.output)
+    }
+
+    /// Writes a row per disputed transaction, ordered by client and transaction, once
+    /// every event was applied.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        for (&(client_id, tx), timeline) in &self.timelines {
+            let Some(dispute) = &timeline.dispute else {
+                continue;
+            };
+            let Some(client) = self.inner.client(client_id) else {
+                return Err(TransactionError::ClientNotFound { client_id }.into());
+            };
+
+            let original = &timeline.original;
+            self.output.write_row(&DisputeTimelineRow {
+                client: client_id,
+                tx,
+                event_type: timeline.kind.as_str().to_owned(),
+                amount: timeline.amount,
+                original_seq: original.seq,
+                original_at: original.at,
+                original_available: original.client.available,
+                original_held: original.client.held,
+                original_total: original.client.total,
+                dispute_seq: dispute.seq,
+                disputed_at: dispute.at,
+                dispute_available: dispute.client.available,
+                dispute_held: dispute.client.held,
+                dispute_total: dispute.client.total,
+                elapsed_events: dispute.seq - original.seq,
+                elapsed_seconds: dispute.at.saturating_sub(original.at),
+                available: client.available,
+                held: client.held,
+                total: client.total,
+                locked: client.frozen,
+            })?;
+        }
+
+        self.output.finish()
+    }
+
+    /// Applies `event` with `apply`, and updates the timeline of its transaction.
+    fn apply<F>(&mut self, event: TransactionEvent, apply: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut DB) -> Result<(), TransactionError>,
+    {
+        apply(&mut self.inner)?;
+
+        let client_id = event.client();
+        let Some(client) = self.inner.client(client_id) else {
+            return Err(TransactionError::ClientNotFound { client_id });
+        };
+        let point = Point {
+            seq: self.inner.last_seq(),
+            at: self.clock.now(),
+            client,
+        };
+        let key = (client_id, event.tx());
+        match event {
+            TransactionEvent::Deposit { amount, .. }
+            | TransactionEvent::Withdrawal { amount, .. } => {
+                let timeline = Timeline {
+                    kind: event.kind(),
+                    amount,
+                    original: point,
+                    dispute: None,
+                };
+                self.timelines.insert(key, timeline);
+            }
+            TransactionEvent::Dispute { .. } => {
+                if let Some(timeline) = self.timelines.get_mut(&key) {
+                    timeline.dispute = Some(point);
+                }
+            }
+            TransactionEvent::Resolve { .. } => {
+                if let Some(timeline) = self.timelines.get_mut(&key) {
+                    timeline.dispute = None;
+                }
+            }
+            // Charged back transactions can't be disputed again
+            TransactionEvent::Chargeback { .. } => {
+                self.timelines.remove(&key);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<DB, O, C> TransactionProcessor for DisputeTimelineProcessor<DB, O, C>
+where
+    DB: TransactionProcessor,
+    O: OutputWriter,
+    C: Clock,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Deposit {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.deposit(transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Withdrawal {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.withdrawal(transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Dispute {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.dispute(transaction_id, client_id))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Resolve {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.resolve(transaction_id, client_id))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Chargeback {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.chargeback(transaction_id, client_id))
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.apply(transaction.clone(), |db| {
+            db.process_annotated_event(transaction, metadata)
+        })
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB, O, C> StateStore for DisputeTimelineProcessor<DB, O, C>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{clock::SimulatedClock, memory_processor::InMemoryTransactionDb};
+
+    #[test]
+    fn writes_open_disputes() {
+        let clock = SimulatedClock::new(1_000);
+        let mut output = Vec::new();
+        let mut db = DisputeTimelineProcessor::with_clock(
+            InMemoryTransactionDb::new(),
+            csv::Writer::from_writer(&mut output),
+            clock.clone(),
+        );
+        db.deposit(1, 1, dec!(10)).unwrap();
+        clock.advance(60);
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.deposit(3, 2, dec!(5)).unwrap();
+        clock.advance(30);
+        db.dispute(1, 1).unwrap();
+        // Resolved and charged back disputes are left out
+        db.dispute(3, 2).unwrap();
+        db.resolve(3, 2).unwrap();
+        db.deposit(4, 2, dec!(1)).unwrap();
+        db.dispute(4, 2).unwrap();
+        db.chargeback(4, 2).unwrap();
+        clock.advance(10);
+        db.deposit(5, 1, dec!(2)).unwrap();
+        db.finish().unwrap();
+        drop(db);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,type,amount,original_seq,original_at,original_available,original_held,\
+original_total,dispute_seq,disputed_at,dispute_available,dispute_held,dispute_total,\
+elapsed_events,elapsed_seconds,available,held,total,locked
+1,1,deposit,10.0000,1,1000,10.0000,0.0000,10.0000,4,1090,-3.0000,10.0000,7.0000,3,90,-1.0000,\
+10.0000,9.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn redisputed_after_resolve() {
+        let clock = SimulatedClock::new(0);
+        let mut output = Vec::new();
+        let mut db = DisputeTimelineProcessor::with_clock(
+            InMemoryTransactionDb::new(),
+            csv::Writer::from_writer(&mut output),
+            clock.clone(),
+        );
+        db.withdrawal(1, 1, dec!(1)).unwrap_err();
+        db.deposit(2, 1, dec!(4)).unwrap();
+        db.dispute(2, 1).unwrap();
+        db.resolve(2, 1).unwrap();
+        clock.advance(5);
+        db.dispute(2, 1).unwrap();
+        db.finish().unwrap();
+        drop(db);
+
+        let rows: Vec<DisputeTimelineRow> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].tx, rows[0].dispute_seq), (2, 4));
+        assert_eq!((rows[0].elapsed_events, rows[0].elapsed_seconds), (3, 5));
+    }
+}
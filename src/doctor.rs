@@ -0,0 +1,490 @@
+//! Self-checks of a deployment, run by `octopussy doctor`.
+//!
+//! Every check turns into a [`Diagnostic`] rather than an error, so one broken piece doesn't
+//! hide the state of the others, and failures come with a hint on what to do about them.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    backend::BackendSpec,
+    checkpoint::Checkpoint,
+    encryption::{self, EncryptionKey},
+    snapshot::Snapshot,
+    snapshot_codec::SnapshotFormat,
+    verify::verify_snapshot,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Warning => "warning",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// What was checked, eg `backend memory:state.json`
+    pub check: String,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about a warning or a failure
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    fn ok(check: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            status: Status::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warning(
+        check: impl Into<String>,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            status: Status::Warning,
+            hint: Some(hint.into()),
+            ..Self::ok(check, detail)
+        }
+    }
+
+    fn failed(
+        check: impl Into<String>,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            status: Status::Failed,
+            ..Self::warning(check, detail, hint)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            self.status.as_str(),
+            self.check,
+            self.detail
+        )?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n    hint: {hint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What [`run`] checks.
+#[derive(Debug, Clone)]
+pub struct DoctorConfig {
+    /// `--encryption-key-file`, the key is read from the environment otherwise
+    pub key_file: Option<PathBuf>,
+    pub backends: Vec<BackendSpec>,
+    pub checkpoint: Option<PathBuf>,
+    pub snapshot_format: SnapshotFormat,
+    /// Free disk space below which the disk check warns, in bytes
+    pub min_free_space: u64,
+}
+
+/// Runs every check, in order: the encryption key, each backend and the consistency of its
+/// state, the checkpoint, and the free space on the disks they're on.
+pub fn run(config: &DoctorConfig) -> Vec<Diagnostic> {
+    let (mut diagnostics, key) = match check_encryption_key(config.key_file.as_deref()) {
+        Ok((diagnostic, key)) => (vec![diagnostic], key),
+        // Everything else needs the key, there's no point going on
+        Err(diagnostic) => return vec![diagnostic],
+    };
+
+    for backend in &config.backends {
+        diagnostics.extend(check_backend(backend, key.as_ref(), config.snapshot_format));
+    }
+    if let Some(checkpoint) = &config.checkpoint {
+        diagnostics.extend(check_checkpoint(checkpoint, key.as_ref()));
+    }
+
+    let mut paths: Vec<_> = config
+        .backends
+        .iter()
+        .map(BackendSpec::location)
+        .chain(config.checkpoint.as_deref())
+        .collect();
+    if paths.is_empty() {
+        paths.push(Path::new("."));
+    }
+    let mut dirs: Vec<_> = paths.into_iter().map(existing_dir).collect();
+    dirs.dedup();
+    for dir in dirs {
+        diagnostics.push(check_disk_space(&dir, config.min_free_space));
+    }
+
+    diagnostics
+}
+
+fn check_encryption_key(
+    key_file: Option<&Path>,
+) -> Result<(Diagnostic, Option<EncryptionKey>), Diagnostic> {
+    const CHECK: &str = "encryption key";
+
+    match key_file {
+        Some(path) => match EncryptionKey::from_file(path) {
+            Ok(key) => Ok((
+                Diagnostic::ok(CHECK, format!("read from {}", path.display())),
+                Some(key),
+            )),
+            Err(err) => Err(Diagnostic::failed(
+                CHECK,
+                format!("{err:#}"),
+                "the key file must hold 32 raw bytes or 64 hex characters",
+            )),
+        },
+        None => match EncryptionKey::from_env() {
+            Ok(Some(key)) => Ok((
+                Diagnostic::ok(CHECK, "read from OCTOPUSSY_ENCRYPTION_KEY"),
+                Some(key),
+            )),
+            Ok(None) => Ok((
+                Diagnostic::ok(CHECK, "none configured, files are written unencrypted"),
+                None,
+            )),
+            Err(err) => Err(Diagnostic::failed(
+                CHECK,
+                format!("OCTOPUSSY_ENCRYPTION_KEY: {err}"),
+                "set it to 64 hex characters, or unset it to run without encryption",
+            )),
+        },
+    }
+}
+
+fn check_backend(
+    backend: &BackendSpec,
+    key: Option<&EncryptionKey>,
+    format: SnapshotFormat,
+) -> Vec<Diagnostic> {
+    let check = format!("backend {backend}");
+    let location = backend.location();
+    let mut diagnostics = Vec::new();
+
+    if let Some(diagnostic) = check_interrupted_write(&check, location) {
+        diagnostics.push(diagnostic);
+    }
+
+    // Opening a database which doesn't exist would create it
+    if !location.exists() {
+        diagnostics.push(Diagnostic::warning(
+            check,
+            format!("{} doesn't exist yet", location.display()),
+            "it will start out empty, check the path if it should already hold state",
+        ));
+        return diagnostics;
+    }
+
+    let snapshot = backend.open(key, format).and_then(|store| store.snapshot());
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            diagnostics.push(Diagnostic::failed(
+                check,
+                format!("{err:#}"),
+                "check the path and its permissions, that the key is the one the files were \
+                 written with, and that no other process holds the database open",
+            ));
+            return diagnostics;
+        }
+    };
+
+    diagnostics.push(Diagnostic::ok(check, summary(&snapshot)));
+    diagnostics.push(check_consistency(
+        format!("consistency of {backend}"),
+        &snapshot,
+    ));
+
+    diagnostics
+}
+
+fn check_checkpoint(path: &Path, key: Option<&EncryptionKey>) -> Vec<Diagnostic> {
+    let check = format!("checkpoint {}", path.display());
+    let mut diagnostics = Vec::new();
+
+    if let Some(diagnostic) = check_interrupted_write(&check, path) {
+        diagnostics.push(diagnostic);
+    }
+
+    match Checkpoint::load(path, key) {
+        Ok(Some(checkpoint)) => {
+            diagnostics.push(Diagnostic::ok(
+                &check,
+                format!(
+                    "{} rows applied, {}",
+                    checkpoint.records,
+                    summary(&checkpoint.snapshot)
+                ),
+            ));
+            diagnostics.push(check_consistency(
+                format!("consistency of {check}"),
+                &checkpoint.snapshot,
+            ));
+        }
+        Ok(None) => diagnostics.push(Diagnostic::warning(
+            check,
+            "doesn't exist yet",
+            "the next run will start from the beginning of the input",
+        )),
+        Err(err) => diagnostics.push(Diagnostic::failed(
+            check,
+            format!("{err:#}"),
+            "check that the key is the one it was written with, or delete it to start the \
+             run over",
+        )),
+    }
+
+    diagnostics
+}
+
+/// Compares the stored balances of `snapshot` with its transactions.
+fn check_consistency(check: String, snapshot: &Snapshot) -> Diagnostic {
+    let discrepancies = verify_snapshot(snapshot);
+    match discrepancies.first() {
+        None => Diagnostic::ok(check, "balances match the transaction journal"),
+        Some(first) => Diagnostic::warning(
+            check,
+            format!(
+                "{} discrepancies, the first being {first}",
+                discrepancies.len()
+            ),
+            "expected if the history was compacted or archived, otherwise restore from a \
+             backup; `verify` lists them all",
+        ),
+    }
+}
+
+/// Files are written to `<path>.tmp` and renamed over `path`, a leftover temporary file
+/// means the process died mid-write.
+fn check_interrupted_write(check: &str, path: &Path) -> Option<Diagnostic> {
+    let tmp_path = encryption::tmp_path(path);
+    tmp_path.exists().then(|| {
+        Diagnostic::warning(
+            check,
+            format!("an interrupted write left {} behind", tmp_path.display()),
+            format!(
+                "{} still holds the last complete state, delete the temporary file",
+                path.display()
+            ),
+        )
+    })
+}
+
+fn check_disk_space(dir: &Path, min_free_space: u64) -> Diagnostic {
+    let check = format!("disk space in {}", dir.display());
+    match fs4::available_space(dir) {
+        Ok(free) if free < min_free_space => Diagnostic::warning(
+            check,
+            format!("{} MiB free", free / MIB),
+            format!(
+                "less than {} MiB, free some space before snapshots and checkpoints fail to write",
+                min_free_space / MIB
+            ),
+        ),
+        Ok(free) => Diagnostic::ok(check, format!("{} MiB free", free / MIB)),
+        Err(err) => Diagnostic::failed(check, err.to_string(), "check that the directory exists"),
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// `mebibytes` in bytes, eg for `--min-free-space`, saturating rather than overflowing on
+/// sizes which don't fit in a `u64`.
+pub fn mebibytes(mebibytes: u64) -> u64 {
+    mebibytes.saturating_mul(MIB)
+}
+
+fn summary(snapshot: &Snapshot) -> String {
+    format!(
+        "{} clients, {} transactions",
+        snapshot.clients.len(),
+        snapshot.transactions.len()
+    )
+}
+
+/// The closest ancestor of `path` which exists, since backends may not have been created yet
+fn existing_dir(path: &Path) -> PathBuf {
+    path.ancestors()
+        .skip(1)
+        .chain([Path::new(".")])
+        .find(|dir| !dir.as_os_str().is_empty() && dir.is_dir())
+        .unwrap_or(Path::new("."))
+        .to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        memory_processor::InMemoryTransactionDb,
+        snapshot::{ClientSnapshot, SnapshotFile, StateStore},
+        transaction::TransactionProcessor,
+    };
+
+    fn config(backends: Vec<BackendSpec>, checkpoint: Option<PathBuf>) -> DoctorConfig {
+        DoctorConfig {
+            key_file: None,
+            backends,
+            checkpoint,
+            snapshot_format: SnapshotFormat::Json,
+            min_free_space: 0,
+        }
+    }
+
+    fn statuses(diagnostics: &[Diagnostic]) -> Vec<(&str, Status)> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.check.as_str(), diagnostic.status))
+            .collect()
+    }
+
+    #[test]
+    fn healthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        SnapshotFile::new(&path)
+            .restore(db.snapshot().unwrap())
+            .unwrap();
+
+        let spec: BackendSpec = format!("memory:{}", path.display()).parse().unwrap();
+        let diagnostics = run(&config(vec![spec.clone()], None));
+
+        let backend = format!("backend {spec}");
+        let consistency = format!("consistency of {spec}");
+        let disk = format!("disk space in {}", dir.path().display());
+        assert_eq!(
+            statuses(&diagnostics),
+            vec![
+                ("encryption key", Status::Ok),
+                (backend.as_str(), Status::Ok),
+                (consistency.as_str(), Status::Ok),
+                (disk.as_str(), Status::Ok),
+            ]
+        );
+        assert_eq!(diagnostics[1].detail, "1 clients, 1 transactions");
+    }
+
+    #[test]
+    fn problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        // The balance doesn't match the transaction
+        let mut snapshot = InMemoryTransactionDb::new().snapshot().unwrap();
+        snapshot.clients.push(ClientSnapshot {
+            id: 1,
+            available: dec!(5),
+            held: dec!(0),
+            frozen: false,
+        });
+        SnapshotFile::new(&path).restore(snapshot).unwrap();
+        std::fs::write(dir.path().join("state.json.tmp"), "{").unwrap();
+        let checkpoint = dir.path().join("checkpoint.json");
+        std::fs::write(&checkpoint, "not json").unwrap();
+
+        let spec: BackendSpec = format!("memory:{}", path.display()).parse().unwrap();
+        let mut config = config(vec![spec], Some(checkpoint));
+        config.min_free_space = mebibytes(u64::MAX);
+        let diagnostics = run(&config);
+
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.status)
+                .collect::<Vec<_>>(),
+            vec![
+                Status::Ok,
+                Status::Warning,
+                Status::Ok,
+                Status::Warning,
+                Status::Failed,
+                Status::Warning,
+            ]
+        );
+        assert!(diagnostics[1].detail.contains("interrupted write"));
+        assert!(diagnostics[3].detail.starts_with("1 discrepancies"));
+    }
+
+    #[test]
+    fn min_free_space_saturates() {
+        assert_eq!(mebibytes(1024), 1024 * MIB);
+        assert_eq!(mebibytes(u64::MAX), u64::MAX);
+
+        let dir = tempfile::tempdir().unwrap();
+        let diagnostic = check_disk_space(dir.path(), mebibytes(u64::MAX));
+        assert_eq!(diagnostic.status, Status::Warning);
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec: BackendSpec = format!("memory:{}", dir.path().join("new/state.json").display())
+            .parse()
+            .unwrap();
+        let diagnostics = run(&config(
+            vec![spec],
+            Some(dir.path().join("checkpoint.json")),
+        ));
+
+        assert_eq!(diagnostics[1].status, Status::Warning);
+        assert_eq!(diagnostics[2].status, Status::Warning);
+        // Both are checked for space in the closest directory which exists
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(
+            diagnostics[3].check,
+            format!("disk space in {}", dir.path().display())
+        );
+    }
+
+    #[test]
+    fn err_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("octopussy.key");
+        std::fs::write(&key_file, "too short").unwrap();
+
+        let diagnostics = run(&DoctorConfig {
+            key_file: Some(key_file),
+            ..config(Vec::new(), None)
+        });
+
+        assert_eq!(
+            statuses(&diagnostics),
+            vec![("encryption key", Status::Failed)]
+        );
+        assert_eq!(
+            diagnostics[0].to_string().lines().last().unwrap(),
+            "    hint: the key file must hold 32 raw bytes or 64 hex characters"
+        );
+    }
+}
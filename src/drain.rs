@@ -0,0 +1,28 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Asks a run to drain: stop taking new events, finish the one being applied and save a
+/// checkpoint, so a new version of the engine can pick up from there without replaying or
+/// losing anything.
+///
+/// Clones share the same state, so one can be handed to a signal handler while the run
+/// polls another.
+#[derive(Debug, Clone, Default)]
+pub struct DrainSignal(Arc<AtomicBool>);
+
+impl DrainSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the drain. Returns whether it was already requested.
+    pub fn drain(&self) -> bool {
+        self.0.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
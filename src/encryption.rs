@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use anyhow::Context;
+use tracing::warn;
+
+/// Environment variable holding the hex encoded key, used when no key file is passed
+pub const KEY_ENV: &str = "OCTOPUSSY_ENCRYPTION_KEY";
+
+/// Prefix of encrypted files, followed by the nonce and the AES-GCM ciphertext
+const MAGIC: &[u8; 8] = b"OCTOENC1";
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+
+/// Attempts at moving a written file into place, see [`replace_file`]
+const REPLACE_ATTEMPTS: u32 = 8;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    #[error("invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("file is encrypted, but no encryption key was configured")]
+    KeyRequired,
+
+    #[error("decryption failed, the key is wrong or the file was tampered with")]
+    DecryptionFailed,
+}
+
+/// An AES-256-GCM key for encrypting snapshots, checkpoints and backups at rest.
+#[derive(Clone)]
+pub struct EncryptionKey(Key<Aes256Gcm>);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes.into())
+    }
+
+    /// Parses a key written as 64 hex characters.
+    pub fn from_hex(hex: &str) -> Result<Self, EncryptionError> {
+        let mut bytes = [0; KEY_SIZE];
+        hex::decode_to_slice(hex.trim(), &mut bytes).map_err(|err| {
+            EncryptionError::InvalidKey(format!("expected {} hex characters: {err}", KEY_SIZE * 2))
+        })?;
+
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Reads a key file holding either the 32 raw key bytes or their hex encoding.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        read_key_file(path).map(Self::from_bytes)
+    }
+
+    /// Reads the key from [`KEY_ENV`], if it's set.
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        match std::env::var(KEY_ENV) {
+            Ok(hex) => Self::from_hex(&hex).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .expect("AES-GCM encryption of an in-memory buffer can't fail");
+
+        let mut data = Vec::with_capacity(MAGIC.len() + NONCE_SIZE + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        data
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let Some(data) = data.strip_prefix(MAGIC) else {
+            return Err(EncryptionError::DecryptionFailed);
+        };
+        if data.len() < NONCE_SIZE {
+            return Err(EncryptionError::DecryptionFailed);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        Aes256Gcm::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::DecryptionFailed)
+    }
+}
+
+/// Reads a file holding either 32 raw key bytes or their hex encoding, the format of
+/// encryption keys and of other keys derived the same way, eg for pseudonymization.
+pub fn read_key_file(path: &Path) -> anyhow::Result<[u8; KEY_SIZE]> {
+    let contents =
+        fs::read(path).with_context(|| format!("failed to read key {}", path.display()))?;
+
+    match <[u8; KEY_SIZE]>::try_from(contents.as_slice()) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Ok(
+            EncryptionKey::from_hex(&String::from_utf8_lossy(&contents))?
+                .0
+                .into(),
+        ),
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Reads a file written by [`write_file`], decrypting it if needed.
+///
+/// Plaintext files are still accepted when a key is configured, so existing files can be
+/// read once after turning encryption on. They are written back encrypted.
+pub fn read_file(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+
+    match (is_encrypted(&data), key) {
+        (true, Some(key)) => Ok(key
+            .decrypt(&data)
+            .with_context(|| format!("failed to decrypt {}", path.display()))?),
+        (true, None) => Err(EncryptionError::KeyRequired)
+            .with_context(|| format!("failed to read {}", path.display())),
+        (false, Some(_)) => {
+            warn!("{} is not encrypted", path.display());
+            Ok(data)
+        }
+        (false, None) => Ok(data),
+    }
+}
+
+/// Writes `contents` (encrypted if a key is passed) to a temporary file first and renames
+/// it into place, so a crash mid-write never leaves a truncated file behind.
+pub fn write_file(path: &Path, contents: &[u8], key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+    let tmp_path = tmp_path(path);
+
+    let encrypted;
+    let contents = match key {
+        Some(key) => {
+            encrypted = key.encrypt(contents);
+            &encrypted
+        }
+        None => contents,
+    };
+
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    replace_file(&tmp_path, path)
+        .with_context(|| format!("failed to move {} into place", path.display()))?;
+
+    Ok(())
+}
+
+/// Where [`write_file`] writes `path` before moving it into place
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+/// Renames `from` over `to`, replacing `to` if it exists.
+///
+/// On Windows a file can't be replaced while another process has it open, which virus
+/// scanners, the search indexer and editors all briefly do, and the rename fails with
+/// `PermissionDenied`. Those locks are short-lived, so the rename is retried with a backoff
+/// before giving up.
+pub fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut delay = Duration::from_millis(10);
+    for _ in 1..REPLACE_ATTEMPTS {
+        match fs::rename(from, to) {
+            Err(err) if cfg!(windows) && err.kind() == ErrorKind::PermissionDenied => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    fs::rename(from, to)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey::from_bytes([byte; KEY_SIZE])
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        write_file(&path, b"{\"clients\":[]}", Some(&key(1))).unwrap();
+        let raw = fs::read(&path).unwrap();
+        assert!(is_encrypted(&raw));
+        assert!(!raw.windows(7).any(|window| window == b"clients"));
+
+        let data = read_file(&path, Some(&key(1))).unwrap();
+        assert_eq!(data, b"{\"clients\":[]}");
+    }
+
+    #[test]
+    fn err_wrong_or_missing_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        write_file(&path, b"secret", Some(&key(1))).unwrap();
+
+        let err = read_file(&path, Some(&key(2))).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EncryptionError>(),
+            Some(&EncryptionError::DecryptionFailed)
+        );
+
+        let err = read_file(&path, None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EncryptionError>(),
+            Some(&EncryptionError::KeyRequired)
+        );
+    }
+
+    #[test]
+    fn key_from_hex() {
+        let hex = "01".repeat(KEY_SIZE);
+        let data = key(1).encrypt(b"data");
+        let parsed = EncryptionKey::from_hex(&hex).unwrap();
+        assert_eq!(parsed.decrypt(&data).unwrap(), b"data");
+
+        assert!(matches!(
+            EncryptionKey::from_hex("abcd"),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+    }
+}
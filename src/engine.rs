@@ -0,0 +1,284 @@
+//! A facade over a backend, its policies and the run metrics, for embedding the engine in
+//! another program rather than running the CLI.
+//!
+//! ```
+//! use octopussy::{
+//!     engine::{Engine, EngineConfig},
+//!     transaction::TransactionEvent,
+//! };
+//! use rust_decimal::dec;
+//!
+//! let mut engine = Engine::new(EngineConfig::default());
+//! engine.add_observer(|event: &TransactionEvent, outcome: &Result<_, _>| {
+//!     println!("{event:?}: {outcome:?}");
+//! });
+//!
+//! engine
+//!     .submit(TransactionEvent::Deposit { tx: 1, client: 1, amount: dec!(10) })
+//!     .unwrap();
+//! assert_eq!(engine.report()[0].available, dec!(10));
+//! ```
+
+use std::time::Instant;
+
+use crate::{
+    csv::{ClientRow, TransactionReader, write_clients},
+    export::OutputWriter,
+    memory_processor::{CompactionPolicy, InMemoryTransactionDb},
+    metrics::RunMetrics,
+    snapshot::{Snapshot, StateStore},
+    state_machine::MaxDisputeCount,
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionEvent, TransactionProcessor,
+    },
+};
+
+/// Policies of the in-memory backend of [`Engine::new`]
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    pub compaction: CompactionPolicy,
+    pub max_disputes: MaxDisputeCount,
+    /// Reject deposits and withdrawals once the state would use more than roughly this
+    /// many bytes
+    pub memory_limit: Option<usize>,
+}
+
+/// Notified of every event submitted to an [`Engine`], once it was applied or rejected.
+///
+/// Closures taking the event and its outcome are observers too.
+pub trait Observer {
+    fn on_event(&mut self, event: &TransactionEvent, outcome: &Result<(), TransactionError>);
+}
+
+impl<F> Observer for F
+where
+    F: FnMut(&TransactionEvent, &Result<(), TransactionError>),
+{
+    fn on_event(&mut self, event: &TransactionEvent, outcome: &Result<(), TransactionError>) {
+        self(event, outcome)
+    }
+}
+
+/// A transaction DB with the bookkeeping the CLI does around it: per-event latencies and
+/// client activity in [`RunMetrics`], and [`Observer`]s of the submitted events.
+pub struct Engine<DB = InMemoryTransactionDb> {
+    db: DB,
+    metrics: RunMetrics,
+    observers: Vec<Box<dyn Observer>>,
+}
+
+impl Engine<InMemoryTransactionDb> {
+    /// An engine on an empty in-memory backend.
+    pub fn new(config: EngineConfig) -> Self {
+        let mut db = InMemoryTransactionDb::with_compaction_policy(config.compaction);
+        db.set_max_dispute_count(config.max_disputes);
+        db.set_memory_limit(config.memory_limit);
+
+        Self::with_backend(db)
+    }
+}
+
+impl<DB: TransactionProcessor> Engine<DB> {
+    /// An engine on any backend, eg a persistent one, or one wrapped in decorators like the
+    /// [`crate::replication::ChangeStreamProcessor`].
+    pub fn with_backend(db: DB) -> Self {
+        Self {
+            db,
+            metrics: RunMetrics::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    /// Adds an observer, called after the ones added before it.
+    pub fn add_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Applies an event, returning why it was rejected if it was.
+    pub fn submit(&mut self, event: TransactionEvent) -> Result<(), TransactionError> {
+        let client = (None, event.client());
+        let kind = event.kind();
+
+        let started = Instant::now();
+        let outcome = self.db.process_transaction_event(event.clone());
+        self.metrics
+            .record(client, kind, started.elapsed(), &outcome);
+
+        for observer in &mut self.observers {
+            observer.on_event(&event, &outcome);
+        }
+
+        outcome
+    }
+
+    /// Submits every row of a CSV input, see [`crate::csv::CsvDialect::transaction_reader`].
+    /// Rejected events don't stop it, the returned counts are of the applied and rejected
+    /// events.
+    pub fn submit_csv<R: std::io::Read>(
+        &mut self,
+        reader: impl Into<TransactionReader<R>>,
+    ) -> anyhow::Result<(u64, u64)> {
+        let (mut applied, mut rejected) = (0, 0);
+        for row in reader.into().rows() {
+            match self.submit(row?.try_into()?) {
+                Ok(()) => applied += 1,
+                Err(_) => rejected += 1,
+            }
+        }
+
+        Ok((applied, rejected))
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.db.client(client_id)
+    }
+
+    /// The balances of every client, by client id.
+    pub fn report(&self) -> Vec<ClientRow> {
+        let mut rows: Vec<ClientRow> = self.db.clients_iter().map(ClientRow::from).collect();
+        rows.sort_by_key(|row| row.client);
+        rows
+    }
+
+    /// Writes the balances of every client, as the CLI does, eg to a
+    /// [`crate::export::FormatWriter`].
+    pub fn write_report<O: OutputWriter>(&self, output: O) -> anyhow::Result<()> {
+        write_clients(output, &self.db)
+    }
+
+    pub fn metrics(&self) -> &RunMetrics {
+        &self.metrics
+    }
+
+    pub fn backend(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn into_backend(self) -> DB {
+        self.db
+    }
+}
+
+impl<DB: TransactionProcessor + StateStore> Engine<DB> {
+    pub fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.db.snapshot()
+    }
+
+    /// Replaces the whole state of the backend, eg to pick up from a snapshot of another
+    /// engine. Metrics are kept.
+    pub fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.db.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::CsvDialect, transaction::EventKind};
+
+    #[test]
+    fn submit_and_report() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let observed = seen.clone();
+        engine.add_observer(move |event: &TransactionEvent, outcome: &Result<_, _>| {
+            observed.borrow_mut().push((event.tx(), outcome.is_ok()));
+        });
+
+        engine
+            .submit(TransactionEvent::Deposit {
+                tx: 1,
+                client: 2,
+                amount: dec!(10),
+            })
+            .unwrap();
+        engine
+            .submit(TransactionEvent::Deposit {
+                tx: 2,
+                client: 1,
+                amount: dec!(1),
+            })
+            .unwrap();
+        assert_eq!(
+            engine.submit(TransactionEvent::Withdrawal {
+                tx: 3,
+                client: 1,
+                amount: dec!(5),
+            }),
+            Err(TransactionError::InsufficientFunds {
+                client_id: 1,
+                transaction_id: 3,
+                available: dec!(1),
+                amount: dec!(5),
+            })
+        );
+
+        assert_eq!(*seen.borrow(), vec![(1, true), (2, true), (3, false)]);
+        assert_eq!(
+            engine
+                .report()
+                .iter()
+                .map(|row| (row.client, row.available))
+                .collect::<Vec<_>>(),
+            vec![(1, dec!(1)), (2, dec!(10))]
+        );
+        assert_eq!(
+            engine
+                .metrics()
+                .latencies
+                .histogram(EventKind::Deposit)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn config() {
+        let mut engine = Engine::new(EngineConfig {
+            max_disputes: MaxDisputeCount::Limited(1),
+            ..EngineConfig::default()
+        });
+
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\nresolve,1,1,\ndispute,1,1,\n";
+        let counts = engine
+            .submit_csv(
+                CsvDialect::default()
+                    .transaction_reader(input.as_bytes())
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(counts, (3, 1));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .submit(TransactionEvent::Deposit {
+                tx: 1,
+                client: 1,
+                amount: dec!(3),
+            })
+            .unwrap();
+
+        let mut other = Engine::new(EngineConfig::default());
+        other.restore(engine.snapshot().unwrap()).unwrap();
+        assert_eq!(other.report(), engine.report());
+        assert_eq!(
+            other.submit(TransactionEvent::Deposit {
+                tx: 1,
+                client: 1,
+                amount: dec!(3),
+            }),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::{collections::BTreeSet, fmt, io::Write};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    csv::{ClientRow, TransactionReader},
+    snapshot::{StateStore, TransactionSnapshot},
+    state_machine::TransactionStatus,
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// Which events [`explain`] reports. Events matching either field are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExplainFilter {
+    pub client: Option<ClientId>,
+    pub tx: Option<TransactionId>,
+}
+
+impl ExplainFilter {
+    fn matches(&self, event: &TransactionEvent) -> bool {
+        self.client == Some(event.client()) || self.tx == Some(event.tx())
+    }
+}
+
+/// An event touching the explained client or transaction, and what it did to the balances.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExplainedEvent {
+    /// Data row of the event in the input, starting at 1
+    pub row: u64,
+    pub event: TransactionEvent,
+    pub result: Result<(), TransactionError>,
+    /// The client's balances right before the event, if it existed
+    pub before: Option<ClientInformation>,
+    pub after: Option<ClientInformation>,
+}
+
+/// The events touching a client or transaction, and the state they ended up in.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Explanation {
+    pub events: Vec<ExplainedEvent>,
+    /// Final balances of every client the events belong to
+    pub clients: Vec<ClientInformation>,
+    /// Final state of the explained transaction, for every client that recorded it
+    pub transactions: Vec<TransactionSnapshot>,
+}
+
+/// Replays every row of `csv_reader` on `db`, keeping track of the ones matching `filter`.
+///
+/// This is meant for investigations: unlike [`crate::csv::csv_processor`] nothing is
+/// logged or written, and the effect of each matching event is kept instead.
+pub fn explain<R, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    db: &mut DB,
+    filter: ExplainFilter,
+) -> anyhow::Result<Explanation>
+where
+    R: std::io::Read,
+    DB: TransactionProcessor + StateStore,
+{
+    let mut explanation = Explanation::default();
+
+    for (row, transaction_row) in csv_reader.into().rows().enumerate() {
+        let event: TransactionEvent = transaction_row?.try_into()?;
+        if !filter.matches(&event) {
+            let _ = db.process_transaction_event(event);
+            continue;
+        }
+
+        let before = db.client(event.client());
+        let result = db.process_transaction_event(event.clone());
+        let after = db.client(event.client());
+
+        explanation.events.push(ExplainedEvent {
+            row: row as u64 + 1,
+            event,
+            result,
+            before,
+            after,
+        });
+    }
+
+    let clients: BTreeSet<_> = explanation
+        .events
+        .iter()
+        .map(|explained| explained.event.client())
+        .collect();
+    explanation.clients = clients
+        .into_iter()
+        .filter_map(|client_id| db.client(client_id))
+        .collect();
+
+    if let Some(tx) = filter.tx {
+        let mut snapshot = db.snapshot()?;
+        snapshot.normalize();
+        explanation.transactions = snapshot
+            .transactions
+            .into_iter()
+            .filter(|transaction| transaction.tx == tx)
+            .collect();
+    }
+
+    Ok(explanation)
+}
+
+#[derive(Serialize)]
+struct ExplainedEventJson<'a> {
+    row: u64,
+    event: &'a TransactionEvent,
+    /// Why the event was rejected, if it was
+    error: Option<String>,
+    before: Option<ClientRow>,
+    after: Option<ClientRow>,
+}
+
+#[derive(Serialize)]
+struct ExplanationJson<'a> {
+    events: Vec<ExplainedEventJson<'a>>,
+    clients: Vec<ClientRow>,
+    transactions: &'a [TransactionSnapshot],
+}
+
+impl Explanation {
+    /// Writes the explanation as a single JSON object, with the events' rejection reasons
+    /// as text.
+    pub fn write_json<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let json = ExplanationJson {
+            events: self
+                .events
+                .iter()
+                .map(|explained| ExplainedEventJson {
+                    row: explained.row,
+                    event: &explained.event,
+                    error: explained.result.as_ref().err().map(ToString::to_string),
+                    before: explained.before.clone().map(ClientRow::from),
+                    after: explained.after.clone().map(ClientRow::from),
+                })
+                .collect(),
+            clients: self.clients.iter().cloned().map(ClientRow::from).collect(),
+            transactions: &self.transactions,
+        };
+        serde_json::to_writer(writer, &json)?;
+
+        Ok(())
+    }
+}
+
+fn signed(change: Decimal) -> String {
+    if change.is_sign_negative() {
+        change.to_string()
+    } else {
+        format!("+{change}")
+    }
+}
+
+impl fmt::Display for ExplainedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "row {}: {} tx {} client {}",
+            self.row,
+            self.event.kind().as_str(),
+            self.event.tx(),
+            self.event.client()
+        )?;
+        if let TransactionEvent::Deposit { amount, .. }
+        | TransactionEvent::Withdrawal { amount, .. } = self.event
+        {
+            write!(f, " amount {amount}")?;
+        }
+
+        if let Err(err) = &self.result {
+            return write!(f, ": rejected, {err}");
+        }
+
+        let (available, held) = match &self.before {
+            Some(before) => (before.available, before.held),
+            None => (Decimal::ZERO, Decimal::ZERO),
+        };
+        let Some(after) = &self.after else {
+            return Ok(());
+        };
+
+        write!(
+            f,
+            ": available {available} -> {} ({}), held {held} -> {} ({})",
+            after.available,
+            signed(after.available - available),
+            after.held,
+            signed(after.held - held),
+        )?;
+        if after.frozen && !self.before.as_ref().is_some_and(|before| before.frozen) {
+            write!(f, ", account frozen")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.events.is_empty() {
+            writeln!(f, "no matching events")?;
+        }
+        for event in &self.events {
+            writeln!(f, "{event}")?;
+        }
+
+        for client in &self.clients {
+            writeln!(
+                f,
+                "client {}: available {}, held {}, total {}{}",
+                client.id,
+                client.available,
+                client.held,
+                client.total,
+                if client.frozen { ", frozen" } else { "" }
+            )?;
+        }
+
+        for transaction in &self.transactions {
+            writeln!(
+                f,
+                "tx {} of client {}: amount {}, {:?}, disputed {} times",
+                transaction.tx,
+                transaction.client,
+                transaction.amount,
+                TransactionStatus::from_flags(transaction.disputed, transaction.charged_back),
+                transaction.disputes
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,7,42,10
+deposit,8,43,5
+withdrawal,7,44,3
+dispute,7,42,
+withdrawal,7,45,1
+chargeback,7,42,
+";
+
+    fn run(filter: ExplainFilter) -> Explanation {
+        let reader = csv::ReaderBuilder::default()
+            .trim(csv::Trim::All)
+            .from_reader(INPUT.as_bytes());
+        explain(reader, &mut InMemoryTransactionDb::new(), filter).unwrap()
+    }
+
+    #[test]
+    fn explain_transaction() {
+        let explanation = run(ExplainFilter {
+            client: None,
+            tx: Some(42),
+        });
+
+        assert_eq!(
+            explanation
+                .events
+                .iter()
+                .map(|event| event.row)
+                .collect::<Vec<_>>(),
+            vec![1, 4, 6]
+        );
+        assert_eq!(
+            explanation.events[1].to_string(),
+            "row 4: dispute tx 42 client 7: available 7 -> -3 (-10), held 0 -> 10 (+10)"
+        );
+        assert_eq!(
+            explanation.events[2].to_string(),
+            "row 6: chargeback tx 42 client 7: available -3 -> -3 (+0), held 10 -> 0 (-10), account frozen"
+        );
+        assert_eq!(explanation.clients.len(), 1);
+        assert_eq!(explanation.clients[0].available, dec!(-3));
+        assert!(explanation.transactions[0].charged_back);
+    }
+
+    #[test]
+    fn explain_client() {
+        let explanation = run(ExplainFilter {
+            client: Some(7),
+            tx: None,
+        });
+
+        assert_eq!(explanation.events.len(), 5);
+        assert!(explanation.transactions.is_empty());
+        assert_eq!(
+            explanation.events[3].result,
+            Err(TransactionError::InsufficientFunds {
+                client_id: 7,
+                transaction_id: 45,
+                available: dec!(-3),
+                amount: dec!(1),
+            })
+        );
+
+        let mut output = Vec::new();
+        explanation.write_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["events"][3]["event"]["type"], "withdrawal");
+        assert_eq!(
+            json["events"][3]["error"],
+            explanation.events[3]
+                .result
+                .as_ref()
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(json["events"][4]["after"]["locked"], true);
+        assert_eq!(json["clients"][0]["available"], "-3.0000");
+    }
+}
@@ -0,0 +1,393 @@
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csv::ClientRow,
+    sql::{SqlStatement, SqlWriter},
+    table::TableWriter,
+    transaction::{ClientInformation, TransactionProcessor},
+};
+
+/// Output formats supported when exporting client state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    /// A single JSON array
+    Json,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Picks the export format from an HTTP `Accept` header value.
+    ///
+    /// Media ranges are tried in order of their `q` weight (ties keep the order they were
+    /// listed in). A wildcard falls back to CSV. Returns `None` if nothing acceptable is
+    /// supported, which a server would turn into a `406 Not Acceptable`.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next().filter(|s| !s.is_empty())?;
+                let q = parts
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                Some((media_type, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(media_type, _)| Self::from_media_type(media_type))
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "text/csv" | "text/*" | "*/*" => Some(Self::Csv),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Some(Self::Ndjson)
+            }
+            "application/json" => Some(Self::Json),
+            #[cfg(feature = "parquet")]
+            "application/vnd.apache.parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+
+    /// The `Content-Type` to send alongside an export in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Json => "application/json",
+            #[cfg(feature = "parquet")]
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+/// Where the client report (or any other rows) gets written to.
+///
+/// Implemented for [`csv::Writer`], and by [`FormatWriter`] for every [`ExportFormat`].
+pub trait OutputWriter {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()>;
+
+    /// Writes out the rows written so far, for readers of an output which isn't finished
+    /// yet. Formats which can only be written as a whole, eg tables, keep them until
+    /// [`Self::finish`].
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once all rows were written.
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+impl<W: Write> OutputWriter for csv::Writer<W> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        Ok(self.serialize(row)?)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(csv::Writer::flush(self)?)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(self.flush()?)
+    }
+}
+
+/// Writes rows in the format picked at runtime.
+pub struct FormatWriter<W: Write> {
+    inner: Inner<W>,
+}
+
+enum Inner<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Ndjson(W),
+    Json {
+        writer: W,
+        written: usize,
+    },
+    #[cfg(feature = "parquet")]
+    Parquet(crate::parquet::ParquetWriter<W>),
+    Sql(SqlWriter<W>),
+    Table(TableWriter<W>),
+}
+
+impl<W: Write> FormatWriter<W> {
+    pub fn new(format: ExportFormat, writer: W) -> Self {
+        let inner = match format {
+            ExportFormat::Csv => Inner::Csv(Box::new(
+                csv::WriterBuilder::default()
+                    .has_headers(true)
+                    .from_writer(writer),
+            )),
+            ExportFormat::Ndjson => Inner::Ndjson(writer),
+            ExportFormat::Json => Inner::Json { writer, written: 0 },
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => Inner::Parquet(crate::parquet::ParquetWriter::new(writer)),
+        };
+
+        Self { inner }
+    }
+
+    /// Writes the rows as SQL statements on `table`, see [`SqlWriter`].
+    pub fn sql(writer: W, table: &str, statement: SqlStatement) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: Inner::Sql(SqlWriter::new(writer, table, statement)?),
+        })
+    }
+
+    /// Writes the rows as an aligned table, see [`TableWriter`].
+    pub fn table(writer: W) -> Self {
+        Self {
+            inner: Inner::Table(TableWriter::new(writer)),
+        }
+    }
+}
+
+impl<W: Write> OutputWriter for FormatWriter<W> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Inner::Csv(csv_writer) => csv_writer.write_row(row)?,
+            Inner::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writer.write_all(b"\n")?;
+            }
+            Inner::Json { writer, written } => {
+                writer.write_all(if *written == 0 { b"[" } else { b"," })?;
+                serde_json::to_writer(&mut *writer, row)?;
+                *written += 1;
+            }
+            #[cfg(feature = "parquet")]
+            Inner::Parquet(parquet_writer) => parquet_writer.write_row(row)?,
+            Inner::Sql(sql_writer) => sql_writer.write_row(row)?,
+            Inner::Table(table_writer) => table_writer.write_row(row)?,
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Inner::Csv(csv_writer) => OutputWriter::flush(csv_writer.as_mut())?,
+            Inner::Ndjson(writer) | Inner::Json { writer, .. } => writer.flush()?,
+            #[cfg(feature = "parquet")]
+            Inner::Parquet(_) => {}
+            Inner::Sql(sql_writer) => sql_writer.flush()?,
+            Inner::Table(_) => {}
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        match &mut self.inner {
+            Inner::Csv(csv_writer) => csv_writer.finish()?,
+            Inner::Ndjson(writer) => writer.flush()?,
+            Inner::Json { writer, written } => {
+                writer.write_all(if *written == 0 { b"[]\n" } else { b"]\n" })?;
+                writer.flush()?;
+            }
+            #[cfg(feature = "parquet")]
+            Inner::Parquet(parquet_writer) => parquet_writer.finish()?,
+            Inner::Sql(sql_writer) => sql_writer.finish()?,
+            Inner::Table(table_writer) => table_writer.finish()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// A window over the clients, ordered by client id.
+///
+/// Deserializes from `?offset=&limit=` query parameters, both optional.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Page {
+    pub offset: usize,
+    /// Maximum number of clients to return. `None` means everything after `offset`.
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// Number of client rows written for the requested page
+    pub written: usize,
+    /// Number of clients known to the DB, regardless of paging
+    pub total: usize,
+}
+
+impl ExportSummary {
+    /// What exporting `page` of `total` clients writes, to know it before writing anything
+    pub fn of_page(page: Page, total: usize) -> Self {
+        let written = total
+            .saturating_sub(page.offset)
+            .min(page.limit.unwrap_or(usize::MAX));
+
+        Self { written, total }
+    }
+
+    /// Offset of the next page, if there is one.
+    pub fn next_offset(&self, page: Page) -> Option<usize> {
+        let next = page.offset + self.written;
+        (next < self.total).then_some(next)
+    }
+}
+
+/// Streams a page of client state to `writer` in the requested format.
+///
+/// Clients are sorted by id so consecutive pages are stable as long as no new clients
+/// show up in between.
+pub fn export_clients<W, DB>(
+    db: &DB,
+    format: ExportFormat,
+    page: Page,
+    writer: W,
+) -> anyhow::Result<ExportSummary>
+where
+    W: Write,
+    DB: TransactionProcessor,
+{
+    export_client_list(db.clients_iter().collect(), format, page, writer)
+}
+
+/// Like [`export_clients`], for clients already read from the DB, eg from a
+/// [`crate::memory_processor::FrozenClients`] so the DB isn't held while they're written.
+pub fn export_client_list<W: Write>(
+    mut clients: Vec<ClientInformation>,
+    format: ExportFormat,
+    page: Page,
+    writer: W,
+) -> anyhow::Result<ExportSummary> {
+    clients.sort_by_key(|client| client.id);
+
+    let total = clients.len();
+    let rows = clients
+        .into_iter()
+        .skip(page.offset)
+        .take(page.limit.unwrap_or(usize::MAX))
+        .map(ClientRow::from);
+
+    let mut writer = FormatWriter::new(format, writer);
+    let mut written = 0;
+    for row in rows {
+        writer.write_row(&row)?;
+        written += 1;
+    }
+    writer.finish()?;
+
+    Ok(ExportSummary { written, total })
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[test]
+    fn accept_negotiation() {
+        assert_eq!(
+            ExportFormat::from_accept("text/csv"),
+            Some(ExportFormat::Csv)
+        );
+        assert_eq!(
+            ExportFormat::from_accept("application/x-ndjson"),
+            Some(ExportFormat::Ndjson)
+        );
+        assert_eq!(
+            ExportFormat::from_accept("text/csv;q=0.5, application/jsonl"),
+            Some(ExportFormat::Ndjson)
+        );
+        assert_eq!(
+            ExportFormat::from_accept("application/xml, */*;q=0.1"),
+            Some(ExportFormat::Csv)
+        );
+        assert_eq!(ExportFormat::from_accept("application/xml"), None);
+        assert_eq!(ExportFormat::from_accept("text/csv;q=0"), None);
+    }
+
+    #[test]
+    fn paged_ndjson() {
+        let mut db = InMemoryTransactionDb::new();
+        for client in 1..=5 {
+            db.deposit(client as u32, client, dec!(1.5)).unwrap();
+        }
+
+        let page = Page {
+            offset: 1,
+            limit: Some(2),
+        };
+        let mut out = Vec::new();
+        let summary = export_clients(&db, ExportFormat::Ndjson, page, &mut out).unwrap();
+
+        assert_eq!(
+            summary,
+            ExportSummary {
+                written: 2,
+                total: 5
+            }
+        );
+        assert_eq!(summary.next_offset(page), Some(3));
+        assert_eq!(ExportSummary::of_page(page, 5), summary);
+
+        let lines: Vec<ClientRow> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].client, 2);
+        assert_eq!(lines[1].client, 3);
+        assert_eq!(lines[1].available, dec!(1.5));
+    }
+
+    #[test]
+    fn last_page_csv() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(1)).unwrap();
+        db.deposit(2, 2, dec!(2)).unwrap();
+
+        let page = Page {
+            offset: 1,
+            limit: Some(10),
+        };
+        let mut out = Vec::new();
+        let summary = export_clients(&db, ExportFormat::Csv, page, &mut out).unwrap();
+
+        assert_eq!(summary.next_offset(page), None);
+        assert_eq!(ExportSummary::of_page(page, 2), summary);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n2,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn json_array() {
+        let mut db = InMemoryTransactionDb::new();
+        let mut out = Vec::new();
+        export_clients(&db, ExportFormat::Json, Page::default(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[]\n");
+
+        db.deposit(1, 1, dec!(1)).unwrap();
+        db.deposit(2, 2, dec!(2.5)).unwrap();
+        let mut out = Vec::new();
+        export_clients(&db, ExportFormat::Json, Page::default(), &mut out).unwrap();
+
+        let rows: Vec<ClientRow> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].available, dec!(2.5));
+    }
+}
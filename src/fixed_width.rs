@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    io::BufRead,
+    path::Path,
+    str::{self, FromStr},
+    time::Instant,
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::{EventKind, TransactionProcessor},
+};
+
+/// Where a field sits in a record: its offset from the start of the line and its length,
+/// both in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldSpec {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl FieldSpec {
+    /// The field's value with its padding trimmed. Lines are often stripped of trailing
+    /// spaces, so the part of the field past the end of the line counts as blank.
+    fn value<'a>(&self, line: &'a [u8]) -> Result<&'a str, FixedWidthError> {
+        let start = self.start.min(line.len());
+        let end = (self.start + self.len).min(line.len());
+        let value = str::from_utf8(&line[start..end]).map_err(|_| FixedWidthError::NotText)?;
+
+        Ok(value.trim())
+    }
+}
+
+/// Layout of the records of a fixed-width file, as read from a JSON file, eg
+///
+/// ```json
+/// {
+///     "type": {"start": 0, "len": 1},
+///     "client": {"start": 1, "len": 5},
+///     "tx": {"start": 6, "len": 10},
+///     "amount": {"start": 16, "len": 12},
+///     "implied_decimals": 2,
+///     "types": {"D": "deposit", "W": "withdrawal"},
+///     "skip_prefixes": ["HDR", "TRL"]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordSpec {
+    pub r#type: FieldSpec,
+    pub client: FieldSpec,
+    pub tx: FieldSpec,
+    pub amount: FieldSpec,
+    #[serde(default)]
+    pub tenant: Option<FieldSpec>,
+    #[serde(default)]
+    pub timestamp: Option<FieldSpec>,
+
+    /// Decimal places implied by amounts without a decimal point, eg with 2 `000012345`
+    /// is 123.45. Amounts with a decimal point are read as is.
+    #[serde(default)]
+    pub implied_decimals: u32,
+
+    /// Codes of the type field mapped to event types, eg `"D": "deposit"`. Without any, the
+    /// field holds the event type itself.
+    #[serde(default)]
+    pub types: BTreeMap<String, String>,
+
+    /// Lines starting with one of these are skipped, eg header and trailer records
+    #[serde(default)]
+    pub skip_prefixes: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum FixedWidthError {
+    #[error("record is not valid UTF-8")]
+    NotText,
+    #[error("invalid {field} {value:?}")]
+    InvalidNumber { field: &'static str, value: String },
+    #[error("unknown type code {0:?}")]
+    UnknownCode(String),
+    #[error("type code {code:?} maps to unknown event type {event_type:?}")]
+    UnknownEventType { code: String, event_type: String },
+}
+
+impl RecordSpec {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let spec: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid record spec {}", path.display()))?;
+        spec.validate()
+            .with_context(|| format!("invalid record spec {}", path.display()))?;
+
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), FixedWidthError> {
+        for (code, event_type) in &self.types {
+            if !EventKind::ALL
+                .iter()
+                .any(|kind| kind.as_str() == event_type)
+            {
+                return Err(FixedWidthError::UnknownEventType {
+                    code: code.clone(),
+                    event_type: event_type.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `line` holds a transaction rather than a header, trailer or nothing
+    fn is_record(&self, line: &[u8]) -> bool {
+        !line.trim_ascii().is_empty()
+            && !self
+                .skip_prefixes
+                .iter()
+                .any(|prefix| line.starts_with(prefix.as_bytes()))
+    }
+
+    pub fn parse(&self, line: &[u8]) -> Result<TransactionRow, FixedWidthError> {
+        let code = self.r#type.value(line)?;
+        let transaction_type = if self.types.is_empty() {
+            code.to_owned()
+        } else {
+            self.types
+                .get(code)
+                .ok_or_else(|| FixedWidthError::UnknownCode(code.to_owned()))?
+                .clone()
+        };
+
+        Ok(TransactionRow {
+            transaction_type,
+            client: number(line, self.client, "client")?,
+            tx: number(line, self.tx, "tx")?,
+            amount: self.amount(line)?,
+            tenant: self
+                .tenant
+                .map(|field| number(line, field, "tenant"))
+                .transpose()?,
+            timestamp: self
+                .timestamp
+                .map(|field| number(line, field, "timestamp"))
+                .transpose()?,
+            sequence: None,
+            metadata: Default::default(),
+        })
+    }
+
+    /// Blank amounts are missing ones, as for disputes
+    fn amount(&self, line: &[u8]) -> Result<Option<Decimal>, FixedWidthError> {
+        let value = self.amount.value(line)?;
+        if value.is_empty() {
+            return Ok(None);
+        }
+
+        let invalid = || FixedWidthError::InvalidNumber {
+            field: "amount",
+            value: value.to_owned(),
+        };
+        let (digits, negative) = split_sign(value).ok_or_else(invalid)?;
+        let mut amount = Decimal::from_str(&digits).map_err(|_| invalid())?;
+        if !digits.contains('.') {
+            amount
+                .set_scale(self.implied_decimals)
+                .map_err(|_| invalid())?;
+        }
+        amount.set_sign_negative(negative);
+
+        Ok(Some(amount.normalize()))
+    }
+}
+
+fn number<T: FromStr>(
+    line: &[u8],
+    field: FieldSpec,
+    name: &'static str,
+) -> Result<T, FixedWidthError> {
+    let value = field.value(line)?;
+    value.parse().map_err(|_| FixedWidthError::InvalidNumber {
+        field: name,
+        value: value.to_owned(),
+    })
+}
+
+/// Splits the sign off an amount: a leading or trailing `+` or `-`, or a COBOL zoned
+/// decimal overpunch on the last digit (`{` and `A`-`I` for positive 0-9, `}` and `J`-`R`
+/// for negative ones).
+fn split_sign(value: &str) -> Option<(String, bool)> {
+    if let Some(rest) = value.strip_prefix('-').or_else(|| value.strip_suffix('-')) {
+        return Some((rest.trim().to_owned(), true));
+    }
+    if let Some(rest) = value.strip_prefix('+').or_else(|| value.strip_suffix('+')) {
+        return Some((rest.trim().to_owned(), false));
+    }
+
+    let (digit, negative) = match *value.as_bytes().last()? {
+        b'0'..=b'9' => return Some((value.to_owned(), false)),
+        b'{' => (0, false),
+        b'}' => (0, true),
+        byte @ b'A'..=b'I' => (byte - b'A' + 1, false),
+        byte @ b'J'..=b'R' => (byte - b'J' + 1, true),
+        _ => return None,
+    };
+
+    // The last byte is ASCII, so it's a whole character
+    let rest = &value[..value.len() - 1];
+    Some((format!("{rest}{digit}"), negative))
+}
+
+/// Same as [`crate::csv::csv_processor`], but reads fixed-width records laid out according
+/// to `spec`, one per line.
+pub fn fixed_width_processor<R, O, DB>(
+    reader: R,
+    spec: &RecordSpec,
+    output: O,
+    db: &mut DB,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    fixed_width_processor_draining(reader, spec, output, db, &DrainSignal::new())
+}
+
+/// Same as [`fixed_width_processor`], but stops at the first record after `drain` was
+/// requested and writes the clients as they are then.
+pub fn fixed_width_processor_draining<R, O, DB>(
+    reader: R,
+    spec: &RecordSpec,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if !spec.is_record(line) {
+            continue;
+        }
+        if drained(drain, &mut metrics) {
+            break;
+        }
+
+        let started = Instant::now();
+        let transaction_row = spec
+            .parse(line)
+            .with_context(|| format!("invalid record on line {}", index + 1))?;
+        apply_row(db, transaction_row, started, &mut metrics)?;
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    fn spec() -> RecordSpec {
+        serde_json::from_str(
+            r#"{
+                "type": {"start": 0, "len": 1},
+                "client": {"start": 1, "len": 5},
+                "tx": {"start": 6, "len": 10},
+                "amount": {"start": 16, "len": 12},
+                "implied_decimals": 2,
+                "types": {"D": "deposit", "W": "withdrawal", "X": "dispute"},
+                "skip_prefixes": ["HDR", "TRL"]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn processes_records() {
+        let input = "HDR20240101 NIGHTLY BATCH\r
+D000010000000001000000012345\r
+D000010000000002        10.5\r
+W00001000000000300000000100{
+X000010000000002
+TRL0000004
+";
+        let mut output = Vec::new();
+        fixed_width_processor(
+            input.as_bytes(),
+            &spec(),
+            csv::Writer::from_writer(&mut output),
+            &mut InMemoryTransactionDb::new(),
+        )
+        .unwrap();
+
+        let rows: Vec<ClientRow> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![ClientRow {
+                client: 1,
+                available: dec!(113.45),
+                held: dec!(10.5),
+                total: dec!(123.95),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn amounts() {
+        let spec = spec();
+        let amount = |value: &str| spec.amount(format!("{:16}{value:>12}", "").as_bytes());
+
+        assert_eq!(amount("000000012345"), Ok(Some(dec!(123.45))));
+        assert_eq!(amount("1.5"), Ok(Some(dec!(1.5))));
+        assert_eq!(amount("-100"), Ok(Some(dec!(-1))));
+        assert_eq!(amount("100+"), Ok(Some(dec!(1))));
+        assert_eq!(amount("1234E"), Ok(Some(dec!(123.45))));
+        assert_eq!(amount("1234N"), Ok(Some(dec!(-123.45))));
+        assert_eq!(amount(""), Ok(None));
+        assert!(amount("12€").is_err());
+        assert_eq!(
+            amount("12,50"),
+            Err(FixedWidthError::InvalidNumber {
+                field: "amount",
+                value: "12,50".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn err_unknown_code() {
+        assert_eq!(
+            spec().parse(b"Z000010000000001").unwrap_err(),
+            FixedWidthError::UnknownCode("Z".to_owned())
+        );
+
+        let mut spec = spec();
+        spec.types.insert("R".to_owned(), "refund".to_owned());
+        assert_eq!(
+            spec.validate(),
+            Err(FixedWidthError::UnknownEventType {
+                code: "R".to_owned(),
+                event_type: "refund".to_owned()
+            })
+        );
+    }
+}
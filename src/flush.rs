@@ -0,0 +1,210 @@
+use std::{
+    io::{self, Write},
+    num::NonZeroU64,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    thread,
+    time::Duration,
+};
+
+/// When a [`LagBoundedWriter`] writes the records it buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Number of buffered records which triggers a flush, `1` flushes every record like a
+    /// [`std::io::LineWriter`]
+    pub every: NonZeroU64,
+    /// Longest a complete record may stay buffered, however slowly the next ones arrive
+    pub max_lag: Option<Duration>,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            every: NonZeroU64::MIN,
+            max_lag: None,
+        }
+    }
+}
+
+/// Buffers newline-terminated records, eg of the change or delta streams, and writes them out
+/// every [`FlushPolicy::every`] records, and at the latest [`FlushPolicy::max_lag`] after they
+/// were written.
+///
+/// Flushing every record keeps consumers of the stream up to date, but costs a write per
+/// event, which can't keep up with bursts. Batching alone leaves the tail of a burst buffered
+/// until the next one. With a max lag, a background thread writes out what's buffered on
+/// every tick, so readers of the stream lag at most that much behind.
+///
+/// Only complete records are written out by the policy, readers never see half a line.
+/// [`Write::flush`] and dropping the writer write everything.
+pub struct LagBoundedWriter<W: Write> {
+    shared: Arc<Mutex<Buffered<W>>>,
+    every: u64,
+}
+
+struct Buffered<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    /// Complete records in `buffer`
+    records: u64,
+    /// Error of a background flush, returned by the next write
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Buffered<W> {
+    /// Writes out the buffered complete records
+    fn flush_records(&mut self) -> io::Result<()> {
+        let Some(end) = self.buffer.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(());
+        };
+
+        self.writer.write_all(&self.buffer[..=end])?;
+        self.writer.flush()?;
+        self.buffer.drain(..=end);
+        self.records = 0;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Send + 'static> LagBoundedWriter<W> {
+    pub fn new(writer: W, policy: FlushPolicy) -> Self {
+        let shared = Arc::new(Mutex::new(Buffered {
+            writer,
+            buffer: Vec::new(),
+            records: 0,
+            error: None,
+        }));
+
+        if let Some(max_lag) = policy.max_lag {
+            let shared = Arc::downgrade(&shared);
+            thread::spawn(move || flush_periodically(&shared, max_lag));
+        }
+
+        Self {
+            shared,
+            every: policy.every.get(),
+        }
+    }
+}
+
+impl<W: Write> LagBoundedWriter<W> {
+    fn lock(&self) -> MutexGuard<'_, Buffered<W>> {
+        self.shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Writes out the complete records of `shared` every `max_lag`, until the writer is dropped
+fn flush_periodically<W: Write>(shared: &Weak<Mutex<Buffered<W>>>, max_lag: Duration) {
+    loop {
+        thread::sleep(max_lag);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        let mut buffered = shared.lock().unwrap_or_else(|err| err.into_inner());
+        if buffered.records > 0
+            && let Err(err) = buffered.flush_records()
+        {
+            buffered.error.get_or_insert(err);
+        }
+    }
+}
+
+impl<W: Write> Write for LagBoundedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let every = self.every;
+        let mut buffered = self.lock();
+        if let Some(err) = buffered.error.take() {
+            return Err(err);
+        }
+
+        buffered.buffer.extend_from_slice(buf);
+        buffered.records += buf.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        if buffered.records >= every {
+            buffered.flush_records()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut buffered = self.lock();
+        if let Some(err) = buffered.error.take() {
+            return Err(err);
+        }
+
+        let Buffered { writer, buffer, .. } = &mut *buffered;
+        writer.write_all(buffer)?;
+        buffer.clear();
+        buffered.records = 0;
+        buffered.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for LagBoundedWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A sink the test can read while the writer owns it
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Sink {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushes_every_n_records() {
+        let sink = Sink::default();
+        let policy = FlushPolicy {
+            every: NonZeroU64::new(2).unwrap(),
+            max_lag: None,
+        };
+        let mut writer = LagBoundedWriter::new(sink.clone(), policy);
+
+        writer.write_all(b"{\"seq\":1}\n{\"seq\"").unwrap();
+        assert_eq!(sink.contents(), "");
+        writer.write_all(b":2}\n{\"seq\":3").unwrap();
+        assert_eq!(sink.contents(), "{\"seq\":1}\n{\"seq\":2}\n");
+
+        drop(writer);
+        assert_eq!(sink.contents(), "{\"seq\":1}\n{\"seq\":2}\n{\"seq\":3");
+    }
+
+    #[test]
+    fn bounds_the_lag() {
+        let sink = Sink::default();
+        let policy = FlushPolicy {
+            every: NonZeroU64::new(1000).unwrap(),
+            max_lag: Some(Duration::from_millis(10)),
+        };
+        let mut writer = LagBoundedWriter::new(sink.clone(), policy);
+
+        writer.write_all(b"{\"seq\":1}\n{\"se").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(sink.contents(), "{\"seq\":1}\n");
+
+        writer.write_all(b"q\":2}\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(sink.contents(), "{\"seq\":1}\n{\"seq\":2}\n");
+    }
+}
@@ -0,0 +1,257 @@
+//! Synthetic transaction files, behind `octopussy generate`: large inputs with realistic
+//! disputes and chargebacks, the same for the same seed, eg as fixtures for benchmarks and
+//! load tests.
+
+use std::{cmp::Reverse, collections::BinaryHeap, io::Write};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    bench::Rng,
+    transaction::{ClientId, TransactionId},
+};
+
+/// Most rows between a deposit and its dispute, and between a dispute and its settlement
+const FOLLOW_UP_WINDOW: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenerateConfig {
+    /// Rows written, after the header
+    pub rows: u64,
+    /// Rows are spread over clients `1..=clients`
+    pub clients: ClientId,
+    /// Share of the deposits which get disputed later
+    pub dispute_ratio: f64,
+    /// Share of the disputes settled with a chargeback, the others are resolved
+    pub chargeback_ratio: f64,
+    /// The same seed generates the same rows
+    pub seed: u64,
+}
+
+/// Rows written by [`generate`], by type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Generated {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+}
+
+/// A row following an earlier one, due once `due` rows were written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct FollowUp {
+    due: u64,
+    kind: FollowUpKind,
+    client: ClientId,
+    tx: TransactionId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FollowUpKind {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl FollowUpKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+        }
+    }
+}
+
+/// Writes `config.rows` rows of `type,client,tx,amount` CSV to `writer`.
+///
+/// Most rows are deposits and withdrawals of amounts spread over several orders of
+/// magnitude, some of the withdrawals being over the client's balance. Disputed deposits
+/// are disputed within [`FOLLOW_UP_WINDOW`] rows of the deposit by its client, and resolved
+/// or charged back within as many rows after that. Follow-ups which would come after the
+/// last row are left out.
+///
+/// ## Errors
+/// - If there are more rows than transaction ids, ie over [`TransactionId::MAX`]
+/// - If writing fails
+pub fn generate<W: Write>(
+    config: &GenerateConfig,
+    mut writer: csv::Writer<W>,
+) -> anyhow::Result<Generated> {
+    // Every row could be a deposit or withdrawal of its own
+    if config.rows > u64::from(TransactionId::MAX) {
+        bail!(
+            "can't generate {} rows, at most {} transactions have an id",
+            config.rows,
+            TransactionId::MAX
+        );
+    }
+
+    let mut rng = Rng(config.seed);
+    let clients = u64::from(config.clients.max(1));
+    let mut follow_ups: BinaryHeap<Reverse<FollowUp>> = BinaryHeap::new();
+    let mut generated = Generated::default();
+    let mut last_tx: TransactionId = 0;
+
+    writer.write_record(["type", "client", "tx", "amount"])?;
+    for row in 0..config.rows {
+        if let Some(Reverse(follow_up)) = follow_ups.peek().copied()
+            && follow_up.due <= row
+        {
+            follow_ups.pop();
+            let FollowUp {
+                kind, client, tx, ..
+            } = follow_up;
+            writer.write_record([kind.as_str(), &client.to_string(), &tx.to_string(), ""])?;
+
+            match kind {
+                FollowUpKind::Dispute => {
+                    generated.disputes += 1;
+                    let kind = match rng.chance(config.chargeback_ratio) {
+                        true => FollowUpKind::Chargeback,
+                        false => FollowUpKind::Resolve,
+                    };
+                    follow_ups.push(Reverse(FollowUp {
+                        due: row + 1 + rng.below(FOLLOW_UP_WINDOW),
+                        kind,
+                        client,
+                        tx,
+                    }));
+                }
+                FollowUpKind::Resolve => generated.resolves += 1,
+                FollowUpKind::Chargeback => generated.chargebacks += 1,
+            }
+            continue;
+        }
+
+        let client = rng.below(clients) as ClientId + 1;
+        last_tx += 1;
+        let tx = last_tx;
+        // Withdrawals are an order of magnitude smaller than deposits, so most go through
+        let withdrawal = rng.below(100) < 30;
+        let (kind, magnitude) = match withdrawal {
+            true => ("withdrawal", 1 + rng.below(4)),
+            false => ("deposit", 2 + rng.below(4)),
+        };
+        let amount = Decimal::new(rng.below(10u64.pow(magnitude as u32)) as i64 + 1, 2);
+        writer.write_record([
+            kind,
+            &client.to_string(),
+            &tx.to_string(),
+            &amount.to_string(),
+        ])?;
+
+        if withdrawal {
+            generated.withdrawals += 1;
+            continue;
+        }
+        generated.deposits += 1;
+        if rng.chance(config.dispute_ratio) {
+            follow_ups.push(Reverse(FollowUp {
+                due: row + 1 + rng.below(FOLLOW_UP_WINDOW),
+                kind: FollowUpKind::Dispute,
+                client,
+                tx,
+            }));
+        }
+    }
+    writer.flush()?;
+
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        csv::{CsvDialect, csv_processor},
+        memory_processor::InMemoryTransactionDb,
+        transaction::TransactionProcessor,
+    };
+
+    fn config(seed: u64) -> GenerateConfig {
+        GenerateConfig {
+            rows: 20_000,
+            clients: 50,
+            dispute_ratio: 0.1,
+            chargeback_ratio: 0.25,
+            seed,
+        }
+    }
+
+    fn generate_csv(config: &GenerateConfig) -> (String, Generated) {
+        let mut output = Vec::new();
+        let generated = generate(config, csv::Writer::from_writer(&mut output)).unwrap();
+        (String::from_utf8(output).unwrap(), generated)
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let (first, _) = generate_csv(&config(7));
+        assert_eq!(generate_csv(&config(7)).0, first);
+        assert_ne!(generate_csv(&config(8)).0, first);
+    }
+
+    #[test]
+    fn follows_the_ratios() {
+        let config = config(7);
+        let (output, generated) = generate_csv(&config);
+        assert_eq!(output.lines().count() as u64, config.rows + 1);
+        assert_eq!(
+            generated.deposits
+                + generated.withdrawals
+                + generated.disputes
+                + generated.resolves
+                + generated.chargebacks,
+            config.rows
+        );
+
+        let disputed = generated.disputes as f64 / generated.deposits as f64;
+        assert!((0.08..0.12).contains(&disputed), "{disputed}");
+        let charged_back =
+            generated.chargebacks as f64 / (generated.chargebacks + generated.resolves) as f64;
+        assert!((0.2..0.3).contains(&charged_back), "{charged_back}");
+    }
+
+    #[test]
+    fn disputes_earlier_deposits_of_the_same_client() {
+        let (output, _) = generate_csv(&config(3));
+        let mut deposits = HashMap::new();
+        for line in output.lines().skip(1) {
+            let fields: Vec<_> = line.split(',').collect();
+            match fields[0] {
+                "deposit" => {
+                    deposits.insert(fields[2], fields[1]);
+                }
+                "dispute" | "resolve" | "chargeback" => {
+                    assert_eq!(deposits.get(fields[2]), Some(&fields[1]), "{line}");
+                }
+                _ => {}
+            }
+        }
+
+        let mut db = InMemoryTransactionDb::new();
+        let reader = CsvDialect::default()
+            .transaction_reader(output.as_bytes())
+            .unwrap();
+        let metrics = csv_processor(reader, csv::Writer::from_writer(Vec::new()), &mut db).unwrap();
+        assert_eq!(metrics.latencies.count(), config(3).rows);
+        assert!(db.clients_iter().any(|client| client.frozen));
+    }
+
+    #[test]
+    fn err_more_rows_than_transaction_ids() {
+        let config = GenerateConfig {
+            rows: u64::from(TransactionId::MAX) + 1,
+            ..config(1)
+        };
+        let mut output = Vec::new();
+        assert!(generate(&config, csv::Writer::from_writer(&mut output)).is_err());
+        assert!(output.is_empty());
+    }
+}
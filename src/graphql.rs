@@ -0,0 +1,456 @@
+//! A GraphQL query layer over the state of a transaction engine, served at `POST /graphql` by
+//! `octopussy serve`, so analysts can look at the clients, balances, disputes and transaction
+//! history of a running engine without exporting its state.
+//!
+//! Clients are read through the accessors of the DB, one at a time or filtered while holding
+//! its lock. The DB has no accessors for transactions: queries reading them run against a
+//! [`Snapshot`] of the DB taken the first time they need it, at the cost of copying its state.
+
+use std::sync::{Arc, OnceLock};
+
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Enum, InputObject, Object, Schema,
+    SimpleObject,
+};
+use axum::{Json, Router, extract::State, routing::post};
+
+use crate::{
+    amount,
+    server::{SharedDb, lock},
+    snapshot::{Snapshot, StateStore, TransactionSnapshot},
+    state_machine::TransactionStatus,
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+    },
+};
+
+/// Most items a page may hold
+pub const MAX_PAGE: usize = 1000;
+
+pub type EngineSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> EngineSchema {
+    Schema::new(Query, EmptyMutation, EmptySubscription)
+}
+
+/// The route of the GraphQL endpoint, querying `db`
+pub fn routes<DB>(db: SharedDb<DB>) -> Router
+where
+    DB: TransactionProcessor + StateStore + Send + 'static,
+{
+    Router::new()
+        .route("/graphql", post(execute))
+        .with_state((schema(), Arc::new(db) as Arc<dyn EngineState>))
+}
+
+async fn execute(
+    State((schema, db)): State<(EngineSchema, Arc<dyn EngineState>)>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(StateView::new(db))).await)
+}
+
+/// What queries read from the DB, whichever its type
+trait EngineState: Send + Sync {
+    /// The client, or the balances it was closed with if its account was closed
+    fn client(&self, id: ClientId) -> Result<Option<ClientInformation>, TransactionError>;
+
+    /// The clients by id, only the locked or unlocked ones with `locked`
+    fn clients(&self, locked: Option<bool>) -> Vec<ClientInformation>;
+
+    fn snapshot(&self) -> anyhow::Result<Snapshot>;
+}
+
+impl<DB> EngineState for SharedDb<DB>
+where
+    DB: TransactionProcessor + StateStore + Send,
+{
+    fn client(&self, id: ClientId) -> Result<Option<ClientInformation>, TransactionError> {
+        match lock(self).find_client(id) {
+            Ok(client) => Ok(Some(client)),
+            Err(TransactionError::ClientNotFound { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn clients(&self, locked: Option<bool>) -> Vec<ClientInformation> {
+        let mut clients: Vec<_> = lock(self)
+            .clients_iter()
+            .filter(|client| locked.is_none_or(|locked| locked == client.frozen))
+            .collect();
+        clients.sort_by_key(|client| client.id);
+
+        clients
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        lock(self).snapshot()
+    }
+}
+
+/// The state a query runs against
+struct StateView {
+    db: Arc<dyn EngineState>,
+    /// By client, then transaction id, read from a snapshot the first time they're needed
+    transactions: OnceLock<Result<Vec<TransactionSnapshot>, String>>,
+}
+
+impl StateView {
+    fn new(db: Arc<dyn EngineState>) -> Self {
+        Self {
+            db,
+            transactions: OnceLock::new(),
+        }
+    }
+
+    fn transactions(&self) -> async_graphql::Result<&[TransactionSnapshot]> {
+        let transactions = self.transactions.get_or_init(|| {
+            let mut snapshot = self
+                .db
+                .snapshot()
+                .map_err(|err| format!("failed to read the transactions: {err:#}"))?;
+            snapshot.normalize();
+
+            Ok(snapshot.transactions)
+        });
+
+        match transactions {
+            Ok(transactions) => Ok(transactions),
+            Err(err) => Err(async_graphql::Error::new(err)),
+        }
+    }
+
+    fn client_transactions(
+        &self,
+        client: ClientId,
+    ) -> async_graphql::Result<&[TransactionSnapshot]> {
+        let transactions = self.transactions()?;
+        let start = transactions.partition_point(|tx| tx.client < client);
+        let end = transactions.partition_point(|tx| tx.client <= client);
+
+        Ok(&transactions[start..end])
+    }
+}
+
+/// Whether a transaction added or took funds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Default, InputObject)]
+pub struct TransactionFilter {
+    pub client: Option<ClientId>,
+    #[graphql(name = "type")]
+    pub transaction_type: Option<TransactionType>,
+    pub status: Option<TransactionStatus>,
+}
+
+impl TransactionFilter {
+    fn matches(&self, tx: &Transaction) -> bool {
+        self.client.is_none_or(|client| client == tx.client)
+            && self
+                .transaction_type
+                .is_none_or(|transaction_type| transaction_type == tx.transaction_type)
+            && self.status.is_none_or(|status| status == tx.status)
+    }
+}
+
+/// A recorded deposit or withdrawal
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Transaction {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[graphql(name = "type")]
+    pub transaction_type: TransactionType,
+    /// Always positive, the type tells which way the funds went
+    pub amount: String,
+    pub status: TransactionStatus,
+    /// Times the transaction was disputed
+    pub disputes: u32,
+    /// Sequence number of the event which recorded it
+    pub seq: u64,
+}
+
+impl From<&TransactionSnapshot> for Transaction {
+    fn from(tx: &TransactionSnapshot) -> Self {
+        Self {
+            client: tx.client,
+            tx: tx.tx,
+            transaction_type: if tx.amount.is_sign_negative() {
+                TransactionType::Withdrawal
+            } else {
+                TransactionType::Deposit
+            },
+            amount: amount::format(tx.amount.abs()),
+            status: TransactionStatus::from_flags(tx.disputed, tx.charged_back),
+            disputes: tx.disputes,
+            seq: tx.seq,
+        }
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct Client {
+    pub id: ClientId,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+}
+
+impl From<ClientInformation> for Client {
+    fn from(client: ClientInformation) -> Self {
+        Self {
+            id: client.id,
+            available: amount::format(client.available),
+            held: amount::format(client.held),
+            total: amount::format(client.total),
+            locked: client.frozen,
+        }
+    }
+}
+
+#[ComplexObject]
+impl Client {
+    /// The recorded transactions of the client, by id
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: TransactionFilter,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 100)] limit: usize,
+    ) -> async_graphql::Result<TransactionPage> {
+        let view = ctx.data_unchecked::<StateView>();
+        let transactions = view
+            .client_transactions(self.id)?
+            .iter()
+            .map(Transaction::from)
+            .filter(|tx| filter.matches(tx));
+
+        let (total_count, nodes) = page(transactions, offset, limit)?;
+        Ok(TransactionPage { total_count, nodes })
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ClientPage {
+    /// Clients matching the filters, across all the pages
+    pub total_count: usize,
+    pub nodes: Vec<Client>,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct TransactionPage {
+    /// Transactions matching the filters, across all the pages
+    pub total_count: usize,
+    pub nodes: Vec<Transaction>,
+}
+
+/// `limit` items of `items` from `offset`, and how many there are in total
+fn page<T>(
+    items: impl Iterator<Item = T>,
+    offset: usize,
+    limit: usize,
+) -> async_graphql::Result<(usize, Vec<T>)> {
+    if limit > MAX_PAGE {
+        return Err(format!("limit must be at most {MAX_PAGE}").into());
+    }
+
+    let mut total_count = 0;
+    let mut nodes = Vec::new();
+    for item in items {
+        if total_count >= offset && nodes.len() < limit {
+            nodes.push(item);
+        }
+        total_count += 1;
+    }
+
+    Ok((total_count, nodes))
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn client(
+        &self,
+        ctx: &Context<'_>,
+        id: ClientId,
+    ) -> async_graphql::Result<Option<Client>> {
+        let view = ctx.data_unchecked::<StateView>();
+        Ok(view.db.client(id)?.map(Client::from))
+    }
+
+    /// Clients by id, only the locked or unlocked ones with `locked`
+    async fn clients(
+        &self,
+        ctx: &Context<'_>,
+        locked: Option<bool>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 100)] limit: usize,
+    ) -> async_graphql::Result<ClientPage> {
+        let view = ctx.data_unchecked::<StateView>();
+        let clients = view.db.clients(locked).into_iter().map(Client::from);
+
+        let (total_count, nodes) = page(clients, offset, limit)?;
+        Ok(ClientPage { total_count, nodes })
+    }
+
+    async fn transaction(
+        &self,
+        ctx: &Context<'_>,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> async_graphql::Result<Option<Transaction>> {
+        let view = ctx.data_unchecked::<StateView>();
+        Ok(view
+            .client_transactions(client)?
+            .iter()
+            .find(|transaction| transaction.tx == tx)
+            .map(Transaction::from))
+    }
+
+    /// Recorded transactions by client, then id
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: TransactionFilter,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 100)] limit: usize,
+    ) -> async_graphql::Result<TransactionPage> {
+        let view = ctx.data_unchecked::<StateView>();
+        let transactions = match filter.client {
+            Some(client) => view.client_transactions(client)?,
+            None => view.transactions()?,
+        };
+        let transactions = transactions
+            .iter()
+            .map(Transaction::from)
+            .filter(|tx| filter.matches(tx));
+
+        let (total_count, nodes) = page(transactions, offset, limit)?;
+        Ok(TransactionPage { total_count, nodes })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+    use serde_json::json;
+
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{archive::AccountArchive, memory_processor::InMemoryTransactionDb};
+
+    fn query(db: &SharedDb<InMemoryTransactionDb>, query: &str) -> serde_json::Value {
+        let request = async_graphql::Request::new(query).data(StateView::new(Arc::new(db.clone())));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let response = runtime.block_on(schema().execute(request));
+
+        serde_json::to_value(response).unwrap()
+    }
+
+    fn db() -> SharedDb<InMemoryTransactionDb> {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(2.5)).unwrap();
+        db.deposit(3, 2, dec!(4)).unwrap();
+        db.dispute(3, 2).unwrap();
+        db.chargeback(3, 2).unwrap();
+        db.deposit(4, 3, dec!(1)).unwrap();
+        db.dispute(4, 3).unwrap();
+        Arc::new(Mutex::new(db))
+    }
+
+    #[test]
+    fn clients() {
+        let db = db();
+
+        assert_eq!(
+            query(&db, "{ client(id: 3) { id available held total locked } }"),
+            json!({"data": {"client": {
+                "id": 3, "available": "0.0000", "held": "1.0000", "total": "1.0000", "locked": false
+            }}})
+        );
+        assert_eq!(
+            query(&db, "{ client(id: 9) { id } }"),
+            json!({"data": {"client": null}})
+        );
+        assert_eq!(
+            query(&db, "{ clients(locked: true) { totalCount nodes { id } } }"),
+            json!({"data": {"clients": {"totalCount": 1, "nodes": [{"id": 2}]}}})
+        );
+        assert_eq!(
+            query(
+                &db,
+                "{ clients(offset: 1, limit: 1) { totalCount nodes { id } } }"
+            ),
+            json!({"data": {"clients": {"totalCount": 3, "nodes": [{"id": 2}]}}})
+        );
+        assert_eq!(
+            query(
+                &db,
+                "{ client(id: 1) { transactions { nodes { tx type amount status } } } }"
+            ),
+            json!({"data": {"client": {"transactions": {"nodes": [
+                {"tx": 1, "type": "DEPOSIT", "amount": "10.0000", "status": "SETTLED"},
+                {"tx": 2, "type": "WITHDRAWAL", "amount": "2.5000", "status": "SETTLED"},
+            ]}}}})
+        );
+    }
+
+    #[test]
+    fn closed_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        db.set_account_archive(AccountArchive::open(dir.path()).unwrap());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.close_account(1).unwrap();
+        let db = Arc::new(Mutex::new(db));
+
+        assert_eq!(
+            query(&db, "{ client(id: 1) { id total locked } }"),
+            json!({"data": {"client": {"id": 1, "total": "10.0000", "locked": false}}})
+        );
+        assert_eq!(
+            query(&db, "{ clients { totalCount } }"),
+            json!({"data": {"clients": {"totalCount": 0}}})
+        );
+    }
+
+    #[test]
+    fn transactions() {
+        let db = db();
+
+        assert_eq!(
+            query(
+                &db,
+                "{ transactions(filter: {status: DISPUTED}) { totalCount nodes { client tx disputes } } }"
+            ),
+            json!({"data": {"transactions": {
+                "totalCount": 1, "nodes": [{"client": 3, "tx": 4, "disputes": 1}]
+            }}})
+        );
+        assert_eq!(
+            query(
+                &db,
+                "{ transactions(filter: {client: 1, type: WITHDRAWAL}) { nodes { tx } } }"
+            ),
+            json!({"data": {"transactions": {"nodes": [{"tx": 2}]}}})
+        );
+        assert_eq!(
+            query(&db, "{ transaction(client: 2, tx: 3) { status } }"),
+            json!({"data": {"transaction": {"status": "CHARGED_BACK"}}})
+        );
+
+        let response = query(&db, "{ transactions(limit: 1001) { totalCount } }");
+        assert_eq!(
+            response["errors"][0]["message"],
+            "limit must be at most 1000"
+        );
+    }
+}
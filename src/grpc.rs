@@ -0,0 +1,425 @@
+//! A gRPC service exposing a transaction engine, the `octopussy.Engine` service of
+//! [`ENGINE_PROTO`].
+
+use std::{
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use futures::Stream;
+use prost::Message;
+use rust_decimal::Decimal;
+use tonic::{Code, Request, Response, Status};
+
+use crate::{
+    amount,
+    drain::DrainSignal,
+    tenant::ApiCalls,
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionEvent, TransactionProcessor,
+    },
+};
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/octopussy.Engine.rs"));
+}
+
+pub use generated::{
+    engine_client::EngineClient,
+    engine_server::{Engine, EngineServer},
+};
+
+/// Schema of the service, kept in sync with the messages here and the service generated by
+/// `build.rs` by hand so building doesn't need `protoc`.
+pub const ENGINE_PROTO: &str = include_str!("../schemas/engine.proto");
+
+/// `octopussy.AmountRequest` in [`ENGINE_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct AmountRequest {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(uint32, tag = "2")]
+    pub tx: u32,
+    #[prost(string, tag = "3")]
+    pub amount: String,
+}
+
+/// `octopussy.TransactionRequest` in [`ENGINE_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct TransactionRequest {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(uint32, tag = "2")]
+    pub tx: u32,
+}
+
+/// `octopussy.ClientRequest` in [`ENGINE_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct ClientRequest {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+}
+
+/// `octopussy.StreamClientsRequest` in [`ENGINE_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamClientsRequest {}
+
+/// `octopussy.ClientState` in [`ENGINE_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct ClientState {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(string, tag = "2")]
+    pub available: String,
+    #[prost(string, tag = "3")]
+    pub held: String,
+    #[prost(string, tag = "4")]
+    pub total: String,
+    #[prost(bool, tag = "5")]
+    pub locked: bool,
+}
+
+impl From<ClientInformation> for ClientState {
+    fn from(client: ClientInformation) -> Self {
+        Self {
+            client: client.id.into(),
+            available: amount::format(client.available),
+            held: amount::format(client.held),
+            total: amount::format(client.total),
+            locked: client.frozen,
+        }
+    }
+}
+
+impl TryFrom<ClientState> for ClientInformation {
+    type Error = Status;
+
+    fn try_from(state: ClientState) -> Result<Self, Status> {
+        Ok(Self {
+            id: client_id(state.client)?,
+            available: parse_amount(&state.available)?,
+            held: parse_amount(&state.held)?,
+            total: parse_amount(&state.total)?,
+            frozen: state.locked,
+        })
+    }
+}
+
+/// The status a rejected event fails with, with the [`TransactionError`] as JSON in its
+/// details so clients can tell why
+pub fn status(err: TransactionError) -> Status {
+    let code = match err {
+        TransactionError::ClientNotFound { .. }
+        | TransactionError::TransactionNotFound { .. }
+        | TransactionError::UnknownTenant { .. } => Code::NotFound,
+        TransactionError::AlreadyDisputed { .. }
+        | TransactionError::AlreadyChargedBack { .. }
+        | TransactionError::DuplicateTransaction { .. } => Code::AlreadyExists,
+        TransactionError::InsufficientFunds { .. }
+        | TransactionError::AccountFrozen { .. }
+        | TransactionError::NotFrozen { .. }
+        | TransactionError::AccountClosed { .. }
+        | TransactionError::FundsHeld { .. }
+        | TransactionError::NotDisputed { .. }
+        | TransactionError::DisputeWindowExpired { .. }
+        | TransactionError::DisputeLimitReached { .. } => Code::FailedPrecondition,
+        TransactionError::Sanctioned { .. } => Code::PermissionDenied,
+        TransactionError::CapacityExceeded { .. } => Code::ResourceExhausted,
+        TransactionError::Storage(_) => Code::Unavailable,
+    };
+    let details = serde_json::to_vec(&err).unwrap_or_default();
+
+    Status::with_details(code, err.to_string(), details.into())
+}
+
+/// The [`TransactionError`] of a status from [`status`], if it's one
+pub fn transaction_error(status: &Status) -> Option<TransactionError> {
+    serde_json::from_slice(status.details()).ok()
+}
+
+fn client_id(client: u32) -> Result<ClientId, Status> {
+    client
+        .try_into()
+        .map_err(|_| Status::invalid_argument(format!("client id {client} is out of range")))
+}
+
+fn parse_amount(amount: &str) -> Result<Decimal, Status> {
+    Decimal::from_str(amount)
+        .map_err(|err| Status::invalid_argument(format!("invalid amount {amount:?}: {err}")))
+}
+
+/// Serves a [`TransactionProcessor`] as the `octopussy.Engine` service.
+///
+/// Events are applied one at a time, in the order the requests take the lock of the DB. The
+/// DB is shared so the caller can still reach it, eg to save it once the server stopped.
+pub struct EngineService<DB> {
+    db: Arc<Mutex<DB>>,
+    drain: DrainSignal,
+    api_calls: Option<ApiCalls>,
+}
+
+impl<DB> EngineService<DB>
+where
+    DB: TransactionProcessor + Send + 'static,
+{
+    pub fn new(db: Arc<Mutex<DB>>) -> Self {
+        Self {
+            db,
+            drain: DrainSignal::new(),
+            api_calls: None,
+        }
+    }
+
+    /// Rejects events as `UNAVAILABLE` once `drain` is requested, so the state doesn't change
+    /// anymore while the server shuts down. Clients can still be read.
+    pub fn draining(mut self, drain: DrainSignal) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Counts every call towards the API calls of the default tenant
+    pub fn counting_api_calls(mut self, calls: ApiCalls) -> Self {
+        self.api_calls = Some(calls);
+        self
+    }
+
+    fn record_api_call(&self) {
+        if let Some(calls) = &self.api_calls {
+            calls.record_api_call(None);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DB> {
+        self.db.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Applies `event` and returns the state of its client
+    fn apply(&self, event: TransactionEvent) -> Result<Response<ClientState>, Status> {
+        let client_id = event.client();
+        let mut db = self.lock();
+        // Checked with the lock held, nothing is applied once the drain was requested
+        if self.drain.is_draining() {
+            return Err(Status::unavailable("draining, no new events are applied"));
+        }
+        db.process_transaction_event(event).map_err(status)?;
+
+        let client = db.find_client(client_id).map_err(status)?;
+        Ok(Response::new(client.into()))
+    }
+}
+
+type ClientStream = Pin<Box<dyn Stream<Item = Result<ClientState, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<DB> Engine for EngineService<DB>
+where
+    DB: TransactionProcessor + Send + 'static,
+{
+    async fn deposit(
+        &self,
+        request: Request<AmountRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let request = request.into_inner();
+        self.apply(TransactionEvent::Deposit {
+            tx: request.tx,
+            client: client_id(request.client)?,
+            amount: parse_amount(&request.amount)?,
+        })
+    }
+
+    async fn withdraw(
+        &self,
+        request: Request<AmountRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let request = request.into_inner();
+        self.apply(TransactionEvent::Withdrawal {
+            tx: request.tx,
+            client: client_id(request.client)?,
+            amount: parse_amount(&request.amount)?,
+        })
+    }
+
+    async fn dispute(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let request = request.into_inner();
+        self.apply(TransactionEvent::Dispute {
+            tx: request.tx,
+            client: client_id(request.client)?,
+        })
+    }
+
+    async fn resolve(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let request = request.into_inner();
+        self.apply(TransactionEvent::Resolve {
+            tx: request.tx,
+            client: client_id(request.client)?,
+        })
+    }
+
+    async fn chargeback(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let request = request.into_inner();
+        self.apply(TransactionEvent::Chargeback {
+            tx: request.tx,
+            client: client_id(request.client)?,
+        })
+    }
+
+    async fn get_client(
+        &self,
+        request: Request<ClientRequest>,
+    ) -> Result<Response<ClientState>, Status> {
+        self.record_api_call();
+        let client_id = client_id(request.into_inner().client)?;
+        let client = self.lock().find_client(client_id).map_err(status)?;
+
+        Ok(Response::new(client.into()))
+    }
+
+    type StreamClientsStream = ClientStream;
+
+    /// Streams the clients as they were when the request came in
+    async fn stream_clients(
+        &self,
+        _: Request<StreamClientsRequest>,
+    ) -> Result<Response<ClientStream>, Status> {
+        self.record_api_call();
+        let clients: Vec<_> = self
+            .lock()
+            .clients_iter()
+            .map(|client| Ok(client.into()))
+            .collect();
+
+        Ok(Response::new(Box::pin(futures::stream::iter(clients))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{StreamExt, executor::block_on};
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn service() -> EngineService<InMemoryTransactionDb> {
+        EngineService::new(Arc::new(Mutex::new(InMemoryTransactionDb::new())))
+    }
+
+    fn deposit(client: u32, tx: u32, amount: &str) -> Request<AmountRequest> {
+        Request::new(AmountRequest {
+            client,
+            tx,
+            amount: amount.to_owned(),
+        })
+    }
+
+    #[test]
+    fn applies_events() {
+        let service = service();
+        let state = block_on(service.deposit(deposit(1, 1, "10.5")))
+            .unwrap()
+            .into_inner();
+        assert_eq!(state.available, "10.5000");
+
+        let state =
+            block_on(service.dispute(Request::new(TransactionRequest { client: 1, tx: 1 })))
+                .unwrap()
+                .into_inner();
+        assert_eq!(
+            state,
+            ClientState {
+                client: 1,
+                available: "0.0000".to_owned(),
+                held: "10.5000".to_owned(),
+                total: "10.5000".to_owned(),
+                locked: false,
+            }
+        );
+
+        block_on(service.deposit(deposit(2, 2, "1"))).unwrap();
+        let clients: Vec<_> = block_on(async {
+            let stream = service
+                .stream_clients(Request::new(StreamClientsRequest {}))
+                .await
+                .unwrap()
+                .into_inner();
+            stream.map(|client| client.unwrap().client).collect().await
+        });
+        assert_eq!(clients.len(), 2);
+    }
+
+    #[test]
+    fn counts_api_calls() {
+        let calls = ApiCalls::new();
+        let service = service().counting_api_calls(calls.clone());
+        block_on(service.deposit(deposit(1, 1, "10"))).unwrap();
+        block_on(service.deposit(deposit(1, 2, "ten"))).unwrap_err();
+        block_on(service.get_client(Request::new(ClientRequest { client: 1 }))).unwrap();
+
+        assert_eq!(calls.get(None), 3);
+    }
+
+    #[test]
+    fn rejections() {
+        let service = service();
+        let code = |result: Result<Response<ClientState>, Status>| result.unwrap_err().code();
+
+        assert_eq!(
+            code(block_on(
+                service.get_client(Request::new(ClientRequest { client: 1 }))
+            )),
+            Code::NotFound
+        );
+        assert_eq!(
+            code(block_on(service.deposit(deposit(1, 1, "ten")))),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            code(block_on(service.deposit(deposit(70_000, 1, "1")))),
+            Code::InvalidArgument
+        );
+
+        block_on(service.deposit(deposit(1, 1, "1"))).unwrap();
+        assert_eq!(
+            code(block_on(service.withdraw(deposit(1, 2, "5")))),
+            Code::FailedPrecondition
+        );
+        let duplicate = block_on(service.deposit(deposit(1, 1, "1"))).unwrap_err();
+        assert_eq!(duplicate.code(), Code::AlreadyExists);
+        assert_eq!(
+            transaction_error(&duplicate),
+            Some(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_events_when_draining() {
+        let drain = DrainSignal::new();
+        let service = service().draining(drain.clone());
+        block_on(service.deposit(deposit(1, 1, "1"))).unwrap();
+
+        drain.drain();
+        let err = block_on(service.deposit(deposit(1, 2, "1"))).unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        let state = block_on(service.get_client(Request::new(ClientRequest { client: 1 })))
+            .unwrap()
+            .into_inner();
+        assert_eq!(state.available, "1.0000");
+    }
+}
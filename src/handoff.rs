@@ -0,0 +1,122 @@
+//! State handoff between back-to-back batch runs, eg a nightly chain where Monday's final
+//! state is Tuesday's starting state.
+//!
+//! Inputs of a chain number their rows with a `sequence` column. The state saved after a
+//! run keeps the sequence of its last row as the snapshot's cursor, and the next run checks
+//! that its input starts right after it, so a skipped or replayed file is caught before
+//! anything is applied.
+
+use std::io::BufRead;
+
+use crate::csv::{TransactionReader, TransactionRow};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum HandoffError {
+    #[error(
+        "the input starts at sequence {found}, but the previous run ended at {previous}, expected {}",
+        previous + 1
+    )]
+    Gap { previous: u64, found: u64 },
+
+    #[error(
+        "the previous run ended at sequence {previous}, but the input has no sequence to check it follows"
+    )]
+    Unsequenced { previous: u64 },
+}
+
+/// Checks that an input starting at `first` follows a run which ended at `previous`.
+///
+/// A state without a sequence (eg the first run of a chain) can be followed by any input.
+pub fn check_sequence(previous: Option<u64>, first: Option<u64>) -> Result<(), HandoffError> {
+    match (previous, first) {
+        (None, _) => Ok(()),
+        (Some(previous), None) => Err(HandoffError::Unsequenced { previous }),
+        (Some(previous), Some(found)) if Some(found) != previous.checked_add(1) => {
+            Err(HandoffError::Gap { previous, found })
+        }
+        (Some(_), Some(_)) => Ok(()),
+    }
+}
+
+/// Sequence of the first row of a CSV input, if it has one.
+pub fn first_csv_sequence<R: std::io::Read>(
+    reader: impl Into<TransactionReader<R>>,
+) -> anyhow::Result<Option<u64>> {
+    let mut reader = reader.into();
+    let row = reader.rows().next().transpose()?;
+
+    Ok(row.and_then(|row| row.sequence))
+}
+
+/// Sequence of the first event of a JSON lines input, if it has one.
+pub fn first_jsonl_sequence<R: BufRead>(reader: R) -> anyhow::Result<Option<u64>> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let row: TransactionRow = serde_json::from_str(&line)?;
+        return Ok(row.sequence);
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequences() {
+        assert_eq!(check_sequence(None, None), Ok(()));
+        assert_eq!(check_sequence(None, Some(7)), Ok(()));
+        assert_eq!(check_sequence(Some(10), Some(11)), Ok(()));
+
+        assert_eq!(
+            check_sequence(Some(10), Some(12)),
+            Err(HandoffError::Gap {
+                previous: 10,
+                found: 12
+            })
+        );
+        // Replaying the previous file
+        assert_eq!(
+            check_sequence(Some(10), Some(1)),
+            Err(HandoffError::Gap {
+                previous: 10,
+                found: 1
+            })
+        );
+        assert_eq!(
+            check_sequence(Some(10), None),
+            Err(HandoffError::Unsequenced { previous: 10 })
+        );
+        assert_eq!(
+            HandoffError::Gap {
+                previous: 10,
+                found: 12
+            }
+            .to_string(),
+            "the input starts at sequence 12, but the previous run ended at 10, expected 11"
+        );
+    }
+
+    #[test]
+    fn first_sequence() {
+        let csv = "type,client,tx,amount,sequence\ndeposit,1,1,1,41\ndeposit,1,2,1,42\n";
+        assert_eq!(
+            first_csv_sequence(::csv::Reader::from_reader(csv.as_bytes())).unwrap(),
+            Some(41)
+        );
+        let csv = "type,client,tx,amount\ndeposit,1,1,1\n";
+        assert_eq!(
+            first_csv_sequence(::csv::Reader::from_reader(csv.as_bytes())).unwrap(),
+            None
+        );
+
+        let jsonl = "\n{\"type\":\"dispute\",\"client\":1,\"tx\":1,\"sequence\":8}\n";
+        assert_eq!(first_jsonl_sequence(jsonl.as_bytes()).unwrap(), Some(8));
+        assert_eq!(first_jsonl_sequence("".as_bytes()).unwrap(), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Reading inputs from `http://` and `https://` URLs, eg the signed URLs partners publish
+//! their transaction files at, streaming the response body rather than downloading it first.
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use tracing::warn;
+use ureq::{Agent, BodyReader, http::Response};
+
+/// Consecutive failed attempts at resuming a transfer before giving up
+pub const RESUME_ATTEMPTS: u32 = 5;
+
+/// Wait before resuming, doubled after every failed attempt
+const RESUME_BACKOFF: Duration = Duration::from_millis(500);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The URL `path` stands for, if it's an `http://` or `https://` one
+pub fn url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+}
+
+/// Reads the body of a `GET` of a URL as it comes in.
+///
+/// When the transfer fails midway, eg the connection is reset, and the server supports range
+/// requests, the transfer is resumed right after the last byte read with a `Range` request,
+/// up to [`RESUME_ATTEMPTS`] times in a row. The resumed response has to be of the same
+/// version of the file (`If-Range`, with its `ETag` or `Last-Modified` date), a file which
+/// changed in between fails the read rather than mixing two versions.
+pub struct HttpReader {
+    agent: Agent,
+    url: String,
+    body: BodyReader<'static>,
+    /// Bytes of the body read so far
+    read: u64,
+    /// `ETag` or `Last-Modified` date of the response, if it can be resumed
+    validator: Option<String>,
+}
+
+impl HttpReader {
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        let config = Agent::config_builder()
+            .timeout_connect(Some(CONNECT_TIMEOUT))
+            .timeout_recv_response(Some(RESPONSE_TIMEOUT))
+            .build();
+        let agent = Agent::new_with_config(config);
+        let response = agent
+            .get(url)
+            .call()
+            .with_context(|| format!("failed to get {url}"))?;
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let resumable = header("accept-ranges").is_some_and(|ranges| ranges == "bytes");
+        let validator = header("etag")
+            .or_else(|| header("last-modified"))
+            .filter(|_| resumable);
+
+        Ok(Self {
+            agent,
+            url: url.to_owned(),
+            body: response.into_body().into_reader(),
+            read: 0,
+            validator,
+        })
+    }
+
+    /// Requests the rest of the body, after what was read
+    fn resume(&mut self) -> anyhow::Result<()> {
+        let Some(validator) = &self.validator else {
+            bail!("the server doesn't support resuming transfers");
+        };
+        let response = self
+            .agent
+            .get(&self.url)
+            .header("range", format!("bytes={}-", self.read))
+            .header("if-range", validator)
+            .call()?;
+        check_range(&response, self.read)?;
+
+        self.body = response.into_body().into_reader();
+        Ok(())
+    }
+}
+
+/// Checks that `response` is the rest of the body from byte `start`
+fn check_range<B>(response: &Response<B>, start: u64) -> anyhow::Result<()> {
+    if response.status() != 206 {
+        bail!("the file changed since the transfer started");
+    }
+    let range = response
+        .headers()
+        .get("content-range")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !range.starts_with(&format!("bytes {start}-")) {
+        bail!("asked for the bytes from {start}, got range {range:?}");
+    }
+
+    Ok(())
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.body.read(buf) {
+                Ok(read) => {
+                    self.read += read as u64;
+                    return Ok(read);
+                }
+                Err(err) => err,
+            };
+            if self.validator.is_none() || attempt == RESUME_ATTEMPTS {
+                return Err(err);
+            }
+
+            attempt += 1;
+            let backoff = RESUME_BACKOFF.saturating_mul(2u32.pow(attempt - 1));
+            warn!(
+                "Transfer of {} failed after {} bytes, resuming in {backoff:?}: {err}",
+                self.url, self.read
+            );
+            thread::sleep(backoff);
+            if let Err(err) = self.resume() {
+                warn!("Failed to resume the transfer of {}: {err:#}", self.url);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Serves `body` to range requests, dropping the connection after `cut` bytes of the
+    /// first response
+    fn serve(body: &'static [u8], cut: usize, etag: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (request, stream) in listener.incoming().enumerate() {
+                respond(stream.unwrap(), body, (request == 0).then_some(cut), etag);
+            }
+        });
+
+        format!("http://{address}/transactions.csv")
+    }
+
+    fn respond(mut stream: TcpStream, body: &[u8], cut: Option<usize>, etag: &str) {
+        let mut range = None;
+        let mut if_range = None;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(start) = line.strip_prefix("range: bytes=") {
+                range = Some(start.trim_end_matches('-').parse::<usize>().unwrap());
+            }
+            if let Some(validator) = line.strip_prefix("if-range: ") {
+                if_range = Some(validator.to_owned());
+            }
+        }
+
+        let start = range.filter(|_| if_range.as_deref() == Some(etag));
+        let head = match start {
+            Some(start) => format!(
+                "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes {start}-{}/{}\r\n",
+                body.len() - 1,
+                body.len()
+            ),
+            None => "HTTP/1.1 200 OK\r\n".to_owned(),
+        };
+        let body = &body[start.unwrap_or(0)..];
+        write!(
+            stream,
+            "{head}accept-ranges: bytes\r\netag: {etag}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream
+            .write_all(&body[..cut.unwrap_or(body.len())])
+            .unwrap();
+    }
+
+    #[test]
+    fn resumes_transfers() {
+        const BODY: &[u8] = b"type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n";
+        let url = serve(BODY, 30, "\"v1\"");
+
+        let mut read = Vec::new();
+        HttpReader::open(&url)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, BODY);
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            url(Path::new(
+                "https://partner.example.com/tx.csv?signature=abc"
+            )),
+            Some("https://partner.example.com/tx.csv?signature=abc")
+        );
+        assert_eq!(url(Path::new("transactions.csv")), None);
+        assert_eq!(url(Path::new("s3://ledger/transactions.csv")), None);
+    }
+}
@@ -0,0 +1,643 @@
+//! Localized user-facing messages.
+//!
+//! Every message has an id, and each [`Locale`] has a catalog mapping ids to templates with
+//! `{name}` placeholders. Messages missing from a catalog fall back to English. The library
+//! types keep their English `Display` impls, this is only used for what the CLI prints.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{transaction::TransactionError, verify::Discrepancy};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+    Es,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 3] = [Self::En, Self::De, Self::Es];
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::En => EN,
+            Self::De => DE,
+            Self::Es => ES,
+        }
+    }
+
+    /// Renders `message` in this locale.
+    pub fn format(self, message: &Message) -> String {
+        let template = [self, Self::En]
+            .into_iter()
+            .find_map(|locale| {
+                locale
+                    .catalog()
+                    .iter()
+                    .find(|(id, _)| *id == message.id)
+                    .map(|(_, template)| *template)
+            })
+            .unwrap_or(message.id);
+
+        message
+            .args
+            .iter()
+            .fold(template.to_owned(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), value)
+            })
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("unsupported locale {0}, expected one of en, de, es")]
+pub struct UnsupportedLocale(String);
+
+/// Parses a language tag, ignoring the region: `de`, `de-AT` and `de_DE.UTF-8` are all
+/// [`Locale::De`].
+impl FromStr for Locale {
+    type Err = UnsupportedLocale;
+
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        let language = tag.split(['-', '_', '.']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Ok(Self::En),
+            "de" => Ok(Self::De),
+            "es" => Ok(Self::Es),
+            _ => Err(UnsupportedLocale(tag.to_owned())),
+        }
+    }
+}
+
+static LOCALE: AtomicU8 = AtomicU8::new(Locale::En as u8);
+
+/// Sets the locale [`Message`]s are displayed in, for the whole process.
+pub fn set_locale(locale: Locale) {
+    LOCALE.store(locale as u8, Ordering::Relaxed);
+}
+
+pub fn locale() -> Locale {
+    let current = LOCALE.load(Ordering::Relaxed);
+    Locale::ALL
+        .into_iter()
+        .find(|&locale| locale as u8 == current)
+        .unwrap_or_default()
+}
+
+/// A catalog message id and the values of its placeholders.
+///
+/// Displays in the locale set with [`set_locale`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    id: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(id: &'static str) -> Self {
+        Self {
+            id,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, name: &'static str, value: impl fmt::Display) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    /// Id of the message in the catalogs, the same in every locale
+    pub fn id(&self) -> &'static str {
+        self.id
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&locale().format(self))
+    }
+}
+
+/// Types with a user-facing message in the catalogs.
+pub trait Localize {
+    fn message(&self) -> Message;
+}
+
+impl Localize for TransactionError {
+    fn message(&self) -> Message {
+        match self {
+            Self::ClientNotFound { client_id } => {
+                Message::new("client-not-found").arg("client_id", client_id)
+            }
+            Self::InsufficientFunds {
+                client_id,
+                transaction_id,
+                available,
+                amount,
+            } => Message::new("insufficient-funds")
+                .arg("client_id", client_id)
+                .arg("transaction_id", transaction_id)
+                .arg("available", available)
+                .arg("amount", amount),
+            Self::AccountFrozen { client_id } => {
+                Message::new("account-frozen").arg("client_id", client_id)
+            }
+            Self::NotFrozen { client_id } => Message::new("not-frozen").arg("client_id", client_id),
+            Self::AccountClosed { client_id } => {
+                Message::new("account-closed").arg("client_id", client_id)
+            }
+            Self::FundsHeld { client_id } => Message::new("funds-held").arg("client_id", client_id),
+            Self::AlreadyDisputed { transaction_id, .. } => {
+                Message::new("already-disputed").arg("transaction_id", transaction_id)
+            }
+            Self::NotDisputed { transaction_id, .. } => {
+                Message::new("not-disputed").arg("transaction_id", transaction_id)
+            }
+            Self::AlreadyChargedBack { transaction_id, .. } => {
+                Message::new("already-charged-back").arg("transaction_id", transaction_id)
+            }
+            Self::TransactionNotFound { transaction_id, .. } => {
+                Message::new("transaction-not-found").arg("transaction_id", transaction_id)
+            }
+            Self::DisputeWindowExpired { transaction_id, .. } => {
+                Message::new("dispute-window-expired").arg("transaction_id", transaction_id)
+            }
+            Self::DisputeLimitReached { transaction_id, .. } => {
+                Message::new("dispute-limit-reached").arg("transaction_id", transaction_id)
+            }
+            Self::DuplicateTransaction { transaction_id, .. } => {
+                Message::new("duplicate-transaction").arg("transaction_id", transaction_id)
+            }
+            Self::CapacityExceeded { limit } => {
+                Message::new("capacity-exceeded").arg("limit", limit)
+            }
+            Self::UnknownTenant { tenant } => Message::new("unknown-tenant").arg("tenant", tenant),
+            Self::Sanctioned { client_id, entry } => Message::new("sanctioned")
+                .arg("client_id", client_id)
+                .arg("entry", entry),
+            Self::Storage(reason) => Message::new("storage").arg("reason", reason),
+        }
+    }
+}
+
+impl Localize for Discrepancy {
+    fn message(&self) -> Message {
+        match self {
+            Self::Balance {
+                client,
+                expected_available,
+                available,
+                expected_held,
+                held,
+                transactions,
+            } => Message::new("discrepancy-balance")
+                .arg("client", client)
+                .arg("expected_available", expected_available)
+                .arg("available", available)
+                .arg("expected_held", expected_held)
+                .arg("held", held)
+                .arg("transactions", format!("{transactions:?}")),
+            Self::UnknownClient { client, tx } => Message::new("discrepancy-unknown-client")
+                .arg("client", client)
+                .arg("tx", tx),
+            Self::NotFrozen { client, tx } => Message::new("discrepancy-not-frozen")
+                .arg("client", client)
+                .arg("tx", tx),
+        }
+    }
+}
+
+const EN: &[(&str, &str)] = &[
+    ("client-not-found", "client {client_id} does not exist"),
+    (
+        "insufficient-funds",
+        "client {client_id} does not have sufficient funds ({available}) to process withdrawal transaction {transaction_id} for {amount}",
+    ),
+    ("account-frozen", "client {client_id}'s account is frozen"),
+    ("not-frozen", "client {client_id}'s account is not frozen"),
+    ("account-closed", "client {client_id}'s account is closed"),
+    (
+        "funds-held",
+        "client {client_id}'s account can't be closed while funds are held",
+    ),
+    (
+        "already-disputed",
+        "transaction {transaction_id} is already disputed",
+    ),
+    (
+        "not-disputed",
+        "transaction {transaction_id} is not disputed",
+    ),
+    (
+        "already-charged-back",
+        "transaction {transaction_id} was already charged back",
+    ),
+    (
+        "transaction-not-found",
+        "transaction {transaction_id} does not exist",
+    ),
+    (
+        "dispute-window-expired",
+        "transaction {transaction_id} is too old to be disputed",
+    ),
+    (
+        "dispute-limit-reached",
+        "transaction {transaction_id} was already disputed the maximum number of times",
+    ),
+    (
+        "duplicate-transaction",
+        "duplicate transaction {transaction_id}",
+    ),
+    ("capacity-exceeded", "memory limit of {limit} bytes reached"),
+    ("unknown-tenant", "tenant {tenant} does not exist"),
+    (
+        "sanctioned",
+        "client {client_id} matches screening list entry {entry}",
+    ),
+    ("storage", "storage error: {reason}"),
+    ("transaction-error", "transaction error: {error}"),
+    (
+        "discrepancy-balance",
+        "client {client}: available is {available} (expected {expected_available}), held is {held} (expected {expected_held}), from transactions {transactions}",
+    ),
+    (
+        "discrepancy-unknown-client",
+        "client {client}: transaction {tx} belongs to an unknown client",
+    ),
+    (
+        "discrepancy-not-frozen",
+        "client {client}: transaction {tx} was charged back but the account isn't frozen",
+    ),
+    ("processed-events", "Processed {count} events"),
+    ("store-consistent", "{backend} is consistent"),
+    ("store-inconsistent", "{backend} has {count} discrepancies"),
+    (
+        "summary-events",
+        "{count} events, {accepted} accepted and {rejected} rejected",
+    ),
+    ("summary-rejections", "Rejected events by reason:"),
+    (
+        "summary-clients",
+        "{count} clients, {frozen} of them frozen",
+    ),
+    (
+        "summary-balances",
+        "Balances: {available} available, {held} held, {total} in total",
+    ),
+    ("top-available", "Top {count} clients by available funds:"),
+    ("top-held", "Top {count} clients by held funds:"),
+    ("top-chargebacks", "Top {count} clients by chargebacks:"),
+    ("top-rejections", "Top {count} clients by rejected events:"),
+    ("top-client", "client {client}: {value}"),
+    (
+        "top-tenant-client",
+        "tenant {tenant} client {client}: {value}",
+    ),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("client-not-found", "Kunde {client_id} existiert nicht"),
+    (
+        "insufficient-funds",
+        "Kunde {client_id} hat nicht genug Guthaben ({available}) für die Abhebung {transaction_id} über {amount}",
+    ),
+    (
+        "account-frozen",
+        "das Konto von Kunde {client_id} ist gesperrt",
+    ),
+    (
+        "not-frozen",
+        "das Konto von Kunde {client_id} ist nicht gesperrt",
+    ),
+    (
+        "account-closed",
+        "das Konto von Kunde {client_id} ist geschlossen",
+    ),
+    (
+        "funds-held",
+        "das Konto von Kunde {client_id} kann nicht geschlossen werden, solange Guthaben zurückgehalten wird",
+    ),
+    (
+        "already-disputed",
+        "Transaktion {transaction_id} wird bereits angefochten",
+    ),
+    (
+        "not-disputed",
+        "Transaktion {transaction_id} wird nicht angefochten",
+    ),
+    (
+        "already-charged-back",
+        "Transaktion {transaction_id} wurde bereits zurückgebucht",
+    ),
+    (
+        "transaction-not-found",
+        "Transaktion {transaction_id} existiert nicht",
+    ),
+    (
+        "dispute-window-expired",
+        "Transaktion {transaction_id} ist zu alt, um angefochten zu werden",
+    ),
+    (
+        "dispute-limit-reached",
+        "Transaktion {transaction_id} wurde bereits so oft wie erlaubt angefochten",
+    ),
+    (
+        "duplicate-transaction",
+        "doppelte Transaktion {transaction_id}",
+    ),
+    (
+        "capacity-exceeded",
+        "Speicherlimit von {limit} Bytes erreicht",
+    ),
+    ("unknown-tenant", "Mandant {tenant} existiert nicht"),
+    (
+        "sanctioned",
+        "Kunde {client_id} steht auf der Sanktionsliste ({entry})",
+    ),
+    ("storage", "Speicherfehler: {reason}"),
+    ("transaction-error", "Transaktionsfehler: {error}"),
+    (
+        "discrepancy-balance",
+        "Kunde {client}: verfügbar ist {available} (erwartet {expected_available}), zurückgehalten ist {held} (erwartet {expected_held}), aus den Transaktionen {transactions}",
+    ),
+    (
+        "discrepancy-unknown-client",
+        "Kunde {client}: Transaktion {tx} gehört zu einem unbekannten Kunden",
+    ),
+    (
+        "discrepancy-not-frozen",
+        "Kunde {client}: Transaktion {tx} wurde zurückgebucht, aber das Konto ist nicht gesperrt",
+    ),
+    ("processed-events", "{count} Ereignisse verarbeitet"),
+    ("store-consistent", "{backend} ist konsistent"),
+    ("store-inconsistent", "{backend} hat {count} Abweichungen"),
+    (
+        "summary-events",
+        "{count} Ereignisse, {accepted} angenommen und {rejected} abgelehnt",
+    ),
+    ("summary-rejections", "Abgelehnte Ereignisse nach Grund:"),
+    ("summary-clients", "{count} Kunden, davon {frozen} gesperrt"),
+    (
+        "summary-balances",
+        "Guthaben: {available} verfügbar, {held} zurückgehalten, {total} insgesamt",
+    ),
+    (
+        "top-available",
+        "Top {count} Kunden nach verfügbarem Guthaben:",
+    ),
+    (
+        "top-held",
+        "Top {count} Kunden nach zurückgehaltenem Guthaben:",
+    ),
+    ("top-chargebacks", "Top {count} Kunden nach Rückbuchungen:"),
+    (
+        "top-rejections",
+        "Top {count} Kunden nach abgelehnten Ereignissen:",
+    ),
+    ("top-client", "Kunde {client}: {value}"),
+    (
+        "top-tenant-client",
+        "Mandant {tenant} Kunde {client}: {value}",
+    ),
+];
+
+const ES: &[(&str, &str)] = &[
+    ("client-not-found", "el cliente {client_id} no existe"),
+    (
+        "insufficient-funds",
+        "el cliente {client_id} no tiene fondos suficientes ({available}) para procesar el retiro {transaction_id} de {amount}",
+    ),
+    (
+        "account-frozen",
+        "la cuenta del cliente {client_id} está congelada",
+    ),
+    (
+        "not-frozen",
+        "la cuenta del cliente {client_id} no está congelada",
+    ),
+    (
+        "account-closed",
+        "la cuenta del cliente {client_id} está cerrada",
+    ),
+    (
+        "funds-held",
+        "la cuenta del cliente {client_id} no se puede cerrar mientras haya fondos retenidos",
+    ),
+    (
+        "already-disputed",
+        "la transacción {transaction_id} ya está en disputa",
+    ),
+    (
+        "not-disputed",
+        "la transacción {transaction_id} no está en disputa",
+    ),
+    (
+        "already-charged-back",
+        "la transacción {transaction_id} ya tuvo un contracargo",
+    ),
+    (
+        "transaction-not-found",
+        "la transacción {transaction_id} no existe",
+    ),
+    (
+        "dispute-window-expired",
+        "la transacción {transaction_id} es demasiado antigua para disputarla",
+    ),
+    (
+        "dispute-limit-reached",
+        "la transacción {transaction_id} ya fue disputada el número máximo de veces",
+    ),
+    (
+        "duplicate-transaction",
+        "transacción {transaction_id} duplicada",
+    ),
+    (
+        "capacity-exceeded",
+        "se alcanzó el límite de memoria de {limit} bytes",
+    ),
+    ("unknown-tenant", "el inquilino {tenant} no existe"),
+    (
+        "sanctioned",
+        "el cliente {client_id} figura en la lista de sanciones ({entry})",
+    ),
+    ("storage", "error de almacenamiento: {reason}"),
+    ("transaction-error", "error de transacción: {error}"),
+    (
+        "discrepancy-balance",
+        "cliente {client}: el saldo disponible es {available} (se esperaba {expected_available}), el retenido es {held} (se esperaba {expected_held}), según las transacciones {transactions}",
+    ),
+    (
+        "discrepancy-unknown-client",
+        "cliente {client}: la transacción {tx} pertenece a un cliente desconocido",
+    ),
+    (
+        "discrepancy-not-frozen",
+        "cliente {client}: la transacción {tx} tuvo un contracargo pero la cuenta no está congelada",
+    ),
+    ("processed-events", "{count} eventos procesados"),
+    ("store-consistent", "{backend} es consistente"),
+    (
+        "store-inconsistent",
+        "{backend} tiene {count} discrepancias",
+    ),
+    (
+        "summary-events",
+        "{count} eventos, {accepted} aceptados y {rejected} rechazados",
+    ),
+    ("summary-rejections", "Eventos rechazados por motivo:"),
+    (
+        "summary-clients",
+        "{count} clientes, {frozen} de ellos congelados",
+    ),
+    (
+        "summary-balances",
+        "Saldos: {available} disponibles, {held} retenidos, {total} en total",
+    ),
+    (
+        "top-available",
+        "Los {count} clientes con más fondos disponibles:",
+    ),
+    ("top-held", "Los {count} clientes con más fondos retenidos:"),
+    (
+        "top-chargebacks",
+        "Los {count} clientes con más contracargos:",
+    ),
+    (
+        "top-rejections",
+        "Los {count} clientes con más eventos rechazados:",
+    ),
+    ("top-client", "cliente {client}: {value}"),
+    (
+        "top-tenant-client",
+        "inquilino {tenant} cliente {client}: {value}",
+    ),
+];
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+
+    fn errors() -> Vec<TransactionError> {
+        vec![
+            TransactionError::ClientNotFound { client_id: 1 },
+            TransactionError::InsufficientFunds {
+                client_id: 1,
+                transaction_id: 2,
+                available: dec!(1.5),
+                amount: dec!(3),
+            },
+            TransactionError::AccountFrozen { client_id: 1 },
+            TransactionError::AccountClosed { client_id: 1 },
+            TransactionError::FundsHeld { client_id: 1 },
+            TransactionError::AlreadyDisputed {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::NotDisputed {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::AlreadyChargedBack {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::TransactionNotFound {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::DisputeWindowExpired {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::DisputeLimitReached {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 2,
+            },
+            TransactionError::CapacityExceeded { limit: 1024 },
+            TransactionError::NotFrozen { client_id: 1 },
+            TransactionError::UnknownTenant { tenant: 3 },
+            TransactionError::Sanctioned {
+                client_id: 1,
+                entry: "SDN-1".to_owned(),
+            },
+            TransactionError::Storage("disk full".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn english_matches_display() {
+        for err in errors() {
+            assert_eq!(Locale::En.format(&err.message()), err.to_string());
+        }
+
+        let discrepancy = Discrepancy::Balance {
+            client: 1,
+            expected_available: dec!(15),
+            available: dec!(14),
+            expected_held: dec!(0),
+            held: dec!(0),
+            transactions: vec![1, 2],
+        };
+        assert_eq!(
+            Locale::En.format(&discrepancy.message()),
+            discrepancy.to_string()
+        );
+    }
+
+    #[test]
+    fn catalogs_are_complete() {
+        for locale in Locale::ALL {
+            for (id, _) in EN {
+                assert!(
+                    locale.catalog().iter().any(|(other, _)| other == id),
+                    "{locale:?} is missing {id}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn localized() {
+        let err = TransactionError::InsufficientFunds {
+            client_id: 1,
+            transaction_id: 2,
+            available: dec!(1.5),
+            amount: dec!(3),
+        };
+        assert_eq!(
+            Locale::De.format(&err.message()),
+            "Kunde 1 hat nicht genug Guthaben (1.5) für die Abhebung 2 über 3"
+        );
+
+        let message = Message::new("transaction-error").arg(
+            "error",
+            Locale::Es.format(&TransactionError::ClientNotFound { client_id: 7 }.message()),
+        );
+        assert_eq!(
+            Locale::Es.format(&message),
+            "error de transacción: el cliente 7 no existe"
+        );
+    }
+
+    #[test]
+    fn parse_locale() {
+        assert_eq!("de".parse(), Ok(Locale::De));
+        assert_eq!("es-MX".parse(), Ok(Locale::Es));
+        assert_eq!("en_US.UTF-8".parse(), Ok(Locale::En));
+        assert_eq!(
+            "fr".parse::<Locale>(),
+            Err(UnsupportedLocale("fr".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Reading several input files as a single one, eg the daily files of a month applied in
+//! order by a single run, and expanding the glob patterns naming them.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, BufRead, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+
+use crate::compression::InputReader;
+
+/// Expands the glob patterns of `inputs` (eg `transactions-2024-*.csv`) into the files they
+/// match, by name, leaving the other paths as they are.
+///
+/// URIs and URLs (eg `s3://` or `https://` ones) are never expanded, object storage can't be
+/// listed that way. On Windows, patterns may mix `/` and `\` separators and use a lower case
+/// drive letter, the paths they match are written with `\` and an upper case one.
+///
+/// ## Errors
+/// - If a pattern is invalid or matches no files
+pub fn expand(inputs: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        let Some(pattern) = input.to_str().filter(|input| is_pattern(input)) else {
+            paths.push(input.clone());
+            continue;
+        };
+        let pattern = normalize(pattern);
+
+        let start = paths.len();
+        for path in glob::glob(&pattern).with_context(|| format!("invalid pattern {pattern}"))? {
+            paths.push(path?);
+        }
+        if paths.len() == start {
+            bail!("{pattern} matches no files");
+        }
+    }
+
+    Ok(paths)
+}
+
+fn is_pattern(input: &str) -> bool {
+    !input.contains("://") && input.contains(['*', '?', '['])
+}
+
+/// `pattern` with the separators and drive letter of Windows paths, so its matches are the
+/// same paths however it's written, eg `c:/data/*.csv` and `C:\data\*.csv`. Patterns are
+/// taken as they are elsewhere.
+fn normalize(pattern: &str) -> Cow<'_, str> {
+    if !cfg!(windows) {
+        return Cow::Borrowed(pattern);
+    }
+
+    let mut normalized = pattern.replace('/', "\\");
+    if matches!(normalized.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic()) {
+        normalized[..1].make_ascii_uppercase();
+    }
+
+    Cow::Owned(normalized)
+}
+
+/// Reads inputs one after the other, as if they were a single one.
+///
+/// With headers, the header row of every input after the first is left out, and has to be
+/// the same as the first one's: rows are read by column name, so inputs with different
+/// columns can't be mixed. An input which doesn't end with a terminator gets one, so its last
+/// row isn't joined with the first row of the next one.
+pub struct InputChain {
+    current: InputReader,
+    /// Path of the input being read
+    path: PathBuf,
+    /// Inputs left to read, in order
+    rest: VecDeque<PathBuf>,
+    /// Header row of the first input, with its terminator, if inputs have one
+    header: Option<Vec<u8>>,
+    terminator: u8,
+    /// Bytes to read before going on with `current`
+    pending: Vec<u8>,
+    /// Last byte read from `current`, or from the header of the first input
+    last: Option<u8>,
+}
+
+impl InputChain {
+    /// Reads `first`, already opened from `path`, followed by `rest`. Records end with
+    /// `terminator`, and `headers` says whether every input starts with a header row.
+    pub fn new(
+        mut first: InputReader,
+        path: &Path,
+        rest: Vec<PathBuf>,
+        headers: bool,
+        terminator: u8,
+    ) -> io::Result<Self> {
+        let header = match headers {
+            true => {
+                let mut header = Vec::new();
+                first.read_until(terminator, &mut header)?;
+                Some(header)
+            }
+            false => None,
+        };
+
+        Ok(Self {
+            current: first,
+            path: path.to_owned(),
+            rest: rest.into(),
+            pending: header.clone().unwrap_or_default(),
+            // The header is read like the rows, and ended like them if it has no terminator
+            last: header.as_ref().and_then(|header| header.last().copied()),
+            header,
+            terminator,
+        })
+    }
+
+    /// Opens the next input, returns whether there was one
+    fn advance(&mut self) -> io::Result<bool> {
+        let Some(path) = self.rest.pop_front() else {
+            return Ok(false);
+        };
+        let mut reader = InputReader::open(&path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+
+        if let Some(expected) = &self.header {
+            let mut header = Vec::new();
+            reader.read_until(self.terminator, &mut header)?;
+            // An empty input has no header to compare
+            if !header.is_empty() && trim(&header) != trim(expected) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the header of {} isn't the same as the one of {}",
+                        path.display(),
+                        self.path.display()
+                    ),
+                ));
+            }
+        }
+
+        if self.last.is_some_and(|last| last != self.terminator) {
+            self.pending.push(self.terminator);
+        }
+        self.current = reader;
+        self.path = path;
+        self.last = None;
+
+        Ok(true)
+    }
+}
+
+/// `line` without its line ending, so inputs ending lines differently still compare equal
+fn trim(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|&byte| byte != b'\n' && byte != b'\r')
+        .map_or(0, |end| end + 1);
+    &line[..end]
+}
+
+impl Read for InputChain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let read = self.pending.len().min(buf.len());
+                buf[..read].copy_from_slice(&self.pending[..read]);
+                self.pending.drain(..read);
+                return Ok(read);
+            }
+
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.last = buf[..read].last().copied().or(self.last);
+                return Ok(read);
+            }
+            if !self.advance()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    fn chain(paths: &[PathBuf], headers: bool) -> io::Result<String> {
+        let first = InputReader::open(&paths[0])?;
+        let mut chain = InputChain::new(first, &paths[0], paths[1..].to_vec(), headers, b'\n')?;
+        let mut read = String::new();
+        chain.read_to_string(&mut read)?;
+
+        Ok(read)
+    }
+
+    #[test]
+    fn reads_inputs_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            ("2024-01-01.csv", "type,client,tx,amount\ndeposit,1,1,10\n"),
+            // Without a final newline, and with Windows line endings
+            ("2024-01-02.csv", "type,client,tx,amount\r\ndeposit,1,2,5"),
+            ("2024-01-03.csv", ""),
+            (
+                "2024-01-04.csv",
+                "type,client,tx,amount\nwithdrawal,1,3,1\n",
+            ),
+        ];
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        let paths = expand(&[dir.path().join("2024-01-*.csv")]).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert_eq!(
+            chain(&paths, true).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,1\n"
+        );
+        assert_eq!(
+            chain(&paths[..2], false).unwrap(),
+            "type,client,tx,amount\ndeposit,1,1,10\ntype,client,tx,amount\r\ndeposit,1,2,5"
+        );
+
+        fs::write(dir.path().join("2024-01-05.csv"), "type,client,amount,tx\n").unwrap();
+        let paths = expand(&[dir.path().join("2024-01-0[45].csv")]).unwrap();
+        assert!(chain(&paths, true).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn normalizes_windows_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.csv"), "").unwrap();
+        let expected = [dir.path().join("a.csv")];
+        let pattern = dir.path().join("*.csv").to_str().unwrap().to_owned();
+
+        // As written in Git Bash or a config file
+        let slashes = pattern.replace('\\', "/");
+        let slashes = slashes[..1].to_ascii_lowercase() + &slashes[1..];
+        assert_eq!(expand(&[PathBuf::from(slashes)]).unwrap(), expected);
+
+        let mixed = format!("{}/*.csv", dir.path().display());
+        assert_eq!(expand(&[PathBuf::from(mixed)]).unwrap(), expected);
+    }
+
+    #[test]
+    fn expands_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("b.csv"), "").unwrap();
+        fs::write(dir.path().join("a.csv"), "").unwrap();
+
+        let literal = dir.path().join("z.csv");
+        let paths = expand(&[dir.path().join("*.csv"), literal.clone()]).unwrap();
+        assert_eq!(
+            paths,
+            [dir.path().join("a.csv"), dir.path().join("b.csv"), literal]
+        );
+        assert!(expand(&[dir.path().join("*.tsv")]).is_err());
+        assert_eq!(
+            expand(&[PathBuf::from("https://example.com/tx.csv?page=[1]")]).unwrap(),
+            [PathBuf::from("https://example.com/tx.csv?page=[1]")]
+        );
+    }
+}
@@ -0,0 +1,601 @@
+use std::{collections::HashMap, io::BufRead, path::Path, str::FromStr};
+
+use anyhow::Context;
+use quick_xml::{Reader, XmlVersion, escape::resolve_predefined_entity, events::Event};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Timestamp,
+    transaction::{ClientId, EventKind, TransactionId},
+};
+
+/// A booked credit or debit of a bank account, from a statement (camt.053) or a payment
+/// initiation (pain.001).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankEntry {
+    /// IBAN, or other id, of the account: the statement's account, or the debtor account of a
+    /// payment. Spaces are removed.
+    pub account: String,
+    /// The bank's reference of the entry, or else the entry reference or end-to-end id
+    pub reference: Option<String>,
+    /// [`EventKind::Deposit`] for credits, [`EventKind::Withdrawal`] for debits
+    pub kind: EventKind,
+    pub amount: Decimal,
+    pub currency: String,
+    /// Booking date (requested execution date of payments), at midnight UTC
+    pub booked: Option<Timestamp>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Iso20022Error {
+    #[error("invalid XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+
+    #[error("not a camt.053 statement or a pain.001 payment initiation: root element is {0}")]
+    UnknownDocument(String),
+
+    #[error("{element} is missing {field}")]
+    MissingField {
+        element: &'static str,
+        field: &'static str,
+    },
+
+    #[error("invalid {field} {value:?}")]
+    InvalidValue { field: &'static str, value: String },
+
+    #[error("account {0} isn't mapped to a client")]
+    UnknownAccount(String),
+
+    #[error("entry {reference} is in {currency}, expected {expected}")]
+    UnexpectedCurrency {
+        reference: String,
+        currency: String,
+        expected: String,
+    },
+
+    #[error("transaction ids past {first_tx} run out")]
+    TransactionIdOverflow { first_tx: TransactionId },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Document {
+    /// camt.053 `BkToCstmrStmt`
+    Statement,
+    /// pain.001 `CstmrCdtTrfInitn`
+    PaymentInitiation,
+}
+
+/// Fields of the entry being read
+#[derive(Debug, Default)]
+struct PartialEntry {
+    amount: Option<String>,
+    currency: Option<String>,
+    indicator: Option<String>,
+    status: Option<String>,
+    servicer_reference: Option<String>,
+    entry_reference: Option<String>,
+    end_to_end_id: Option<String>,
+}
+
+impl PartialEntry {
+    fn finish(
+        self,
+        element: &'static str,
+        account: Option<&str>,
+        booked: Option<Timestamp>,
+        kind: EventKind,
+    ) -> Result<BankEntry, Iso20022Error> {
+        let missing = |field| Iso20022Error::MissingField { element, field };
+
+        let amount = self.amount.ok_or(missing("an amount"))?;
+        let amount = Decimal::from_str(&amount).map_err(|_| Iso20022Error::InvalidValue {
+            field: "amount",
+            value: amount,
+        })?;
+
+        Ok(BankEntry {
+            account: account.ok_or(missing("an account"))?.to_owned(),
+            reference: self
+                .servicer_reference
+                .or(self.entry_reference)
+                .or(self.end_to_end_id.filter(|id| id != "NOTPROVIDED")),
+            kind,
+            amount,
+            currency: self.currency.ok_or(missing("a currency"))?,
+            booked,
+        })
+    }
+}
+
+/// Reads the entries of a camt.053 bank statement or a pain.001 payment initiation, told
+/// apart by their root element. Any message version works, as only the elements common to
+/// all of them are read.
+///
+/// Statement entries which aren't booked (eg pending) are skipped. Every credit transfer of
+/// a payment initiation is a debit of its debtor account.
+pub fn parse<R: BufRead>(reader: R) -> Result<Vec<BankEntry>, Iso20022Error> {
+    let mut xml = Reader::from_reader(reader);
+    xml.config_mut().trim_text(true);
+
+    let mut buffer = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut root = None;
+    let mut document = None;
+
+    let mut account: Option<String> = None;
+    let mut booked = None;
+    let mut entry = PartialEntry::default();
+    let mut entries = Vec::new();
+
+    loop {
+        match xml.read_event_into(&mut buffer)? {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_owned();
+                if path.is_empty() {
+                    root = Some(name.clone());
+                } else if path.len() == 1 {
+                    document = Some(match name.as_str() {
+                        "BkToCstmrStmt" => Document::Statement,
+                        "CstmrCdtTrfInitn" => Document::PaymentInitiation,
+                        _ => return Err(Iso20022Error::UnknownDocument(name)),
+                    });
+                }
+                let amount = match document {
+                    Some(Document::Statement) => {
+                        name == "Amt" && path.last().is_some_and(|parent| parent == "Ntry")
+                    }
+                    Some(Document::PaymentInitiation) => name == "InstdAmt",
+                    None => false,
+                };
+                if amount {
+                    entry.currency = element
+                        .try_get_attribute("Ccy")
+                        .map_err(quick_xml::Error::from)?
+                        .map(|currency| currency.normalized_value(XmlVersion::Implicit1_0))
+                        .transpose()?
+                        .map(|currency| currency.into_owned());
+                }
+
+                path.push(name);
+                text.clear();
+            }
+            Event::Text(content) => text.push_str(&content.xml10_content()),
+            Event::GeneralRef(reference) => match reference.resolve_char_ref()? {
+                Some(char) => text.push(char),
+                None => text.push_str(resolve_predefined_entity(&reference).unwrap_or_default()),
+            },
+            Event::End(_) => {
+                let at = |suffix: &[&str]| {
+                    path.len() >= suffix.len()
+                        && path[path.len() - suffix.len()..]
+                            .iter()
+                            .zip(suffix)
+                            .all(|(name, expected)| name == expected)
+                };
+                let value = || Some(text.trim().to_owned());
+
+                match document {
+                    Some(Document::Statement) => {
+                        if at(&["Stmt", "Acct", "Id", "IBAN"])
+                            || at(&["Stmt", "Acct", "Id", "Othr", "Id"])
+                        {
+                            account = Some(text.replace(' ', ""));
+                        } else if at(&["Ntry", "Amt"]) {
+                            entry.amount = value();
+                        } else if at(&["Ntry", "CdtDbtInd"]) {
+                            entry.indicator = value();
+                        } else if (at(&["Ntry", "Sts"]) && !text.is_empty())
+                            || at(&["Ntry", "Sts", "Cd"])
+                        {
+                            entry.status = value();
+                        } else if at(&["Ntry", "BookgDt", "Dt"]) || at(&["Ntry", "BookgDt", "DtTm"])
+                        {
+                            booked = Some(parse_date(&text)?);
+                        } else if at(&["Ntry", "AcctSvcrRef"]) {
+                            entry.servicer_reference = value();
+                        } else if at(&["Ntry", "NtryRef"]) {
+                            entry.entry_reference = value();
+                        } else if at(&["Ntry", "NtryDtls", "TxDtls", "Refs", "EndToEndId"]) {
+                            entry.end_to_end_id = entry.end_to_end_id.or(value());
+                        } else if at(&["Stmt", "Ntry"]) {
+                            let finished = std::mem::take(&mut entry);
+                            let kind = match finished.indicator.as_deref() {
+                                Some("CRDT") => EventKind::Deposit,
+                                Some("DBIT") => EventKind::Withdrawal,
+                                Some(other) => {
+                                    return Err(Iso20022Error::InvalidValue {
+                                        field: "credit/debit indicator",
+                                        value: other.to_owned(),
+                                    });
+                                }
+                                None => {
+                                    return Err(Iso20022Error::MissingField {
+                                        element: "Ntry",
+                                        field: "a credit/debit indicator",
+                                    });
+                                }
+                            };
+                            if finished
+                                .status
+                                .as_deref()
+                                .is_none_or(|status| status == "BOOK")
+                            {
+                                entries.push(finished.finish(
+                                    "Ntry",
+                                    account.as_deref(),
+                                    booked.take(),
+                                    kind,
+                                )?);
+                            }
+                            booked = None;
+                        } else if at(&["Stmt"]) {
+                            account = None;
+                        }
+                    }
+                    Some(Document::PaymentInitiation) => {
+                        if at(&["PmtInf", "DbtrAcct", "Id", "IBAN"])
+                            || at(&["PmtInf", "DbtrAcct", "Id", "Othr", "Id"])
+                        {
+                            account = Some(text.replace(' ', ""));
+                        } else if (at(&["PmtInf", "ReqdExctnDt"]) && !text.is_empty())
+                            || at(&["PmtInf", "ReqdExctnDt", "Dt"])
+                            || at(&["PmtInf", "ReqdExctnDt", "DtTm"])
+                        {
+                            booked = Some(parse_date(&text)?);
+                        } else if at(&["CdtTrfTxInf", "Amt", "InstdAmt"]) {
+                            entry.amount = value();
+                        } else if at(&["CdtTrfTxInf", "PmtId", "EndToEndId"]) {
+                            entry.end_to_end_id = value();
+                        } else if at(&["CdtTrfTxInf", "PmtId", "InstrId"]) {
+                            entry.entry_reference = value();
+                        } else if at(&["PmtInf", "CdtTrfTxInf"]) {
+                            entries.push(std::mem::take(&mut entry).finish(
+                                "CdtTrfTxInf",
+                                account.as_deref(),
+                                booked,
+                                EventKind::Withdrawal,
+                            )?);
+                        } else if at(&["PmtInf"]) {
+                            account = None;
+                            booked = None;
+                        }
+                    }
+                    None => {}
+                }
+
+                path.pop();
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    if document.is_none() {
+        return Err(Iso20022Error::UnknownDocument(root.unwrap_or_default()));
+    }
+
+    Ok(entries)
+}
+
+/// Midnight UTC of an ISO 8601 date, or of the date part of a date-time
+fn parse_date(date: &str) -> Result<Timestamp, Iso20022Error> {
+    let invalid = || Iso20022Error::InvalidValue {
+        field: "date",
+        value: date.to_owned(),
+    };
+
+    let mut parts = date.get(..10).ok_or_else(invalid)?.splitn(3, '-');
+    let mut part = || -> Result<i64, Iso20022Error> {
+        parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let (year, month, day) = (part()?, part()?, part()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Days since the epoch of a proleptic Gregorian date, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400).map_err(|_| invalid())
+}
+
+/// Which client each bank account belongs to, from a CSV file with `account` and `client`
+/// columns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountMapping {
+    clients: HashMap<String, ClientId>,
+    /// Only accept entries in this currency
+    pub currency: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MappedAccount {
+    account: String,
+    client: ClientId,
+}
+
+impl AccountMapping {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = csv::ReaderBuilder::default()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: std::io::Read>(mut reader: csv::Reader<R>) -> anyhow::Result<Self> {
+        let clients = reader
+            .deserialize()
+            .map(|row| row.map(|row: MappedAccount| (row.account.replace(' ', ""), row.client)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            clients,
+            currency: None,
+        })
+    }
+}
+
+/// A [`BankEntry`] as a row of the CSV input, plus the bank's reference to reconcile it by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BankTransactionRow {
+    #[serde(rename = "type")]
+    pub transaction_type: &'static str,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    pub timestamp: Option<Timestamp>,
+    pub reference: Option<String>,
+}
+
+/// Maps bank entries to deposits and withdrawals of the clients owning the accounts. Banks
+/// don't number entries like the engine does, so they get consecutive transaction ids from
+/// `first_tx` on.
+pub fn to_rows(
+    entries: &[BankEntry],
+    mapping: &AccountMapping,
+    first_tx: TransactionId,
+) -> Result<Vec<BankTransactionRow>, Iso20022Error> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let client = *mapping
+                .clients
+                .get(&entry.account)
+                .ok_or_else(|| Iso20022Error::UnknownAccount(entry.account.clone()))?;
+            if let Some(expected) = &mapping.currency
+                && *expected != entry.currency
+            {
+                return Err(Iso20022Error::UnexpectedCurrency {
+                    reference: entry.reference.clone().unwrap_or_default(),
+                    currency: entry.currency.clone(),
+                    expected: expected.clone(),
+                });
+            }
+            let tx = TransactionId::try_from(index)
+                .ok()
+                .and_then(|index| first_tx.checked_add(index))
+                .ok_or(Iso20022Error::TransactionIdOverflow { first_tx })?;
+
+            Ok(BankTransactionRow {
+                transaction_type: entry.kind.as_str(),
+                client,
+                tx,
+                amount: entry.amount,
+                timestamp: entry.booked,
+                reference: entry.reference.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        csv::{TransactionRow, csv_processor},
+        memory_processor::InMemoryTransactionDb,
+        transaction::TransactionProcessor,
+    };
+
+    const STATEMENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+  <BkToCstmrStmt>
+    <GrpHdr><MsgId>STMT-1</MsgId></GrpHdr>
+    <Stmt>
+      <Acct><Id><IBAN>DE89 3704 0044 0532 0130 00</IBAN></Id></Acct>
+      <Ntry>
+        <NtryRef>1</NtryRef>
+        <Amt Ccy="EUR">150.25</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts>BOOK</Sts>
+        <BookgDt><Dt>2026-10-01</Dt></BookgDt>
+        <AcctSvcrRef>BANK-REF-1</AcctSvcrRef>
+        <NtryDtls><TxDtls><Refs><EndToEndId>E2E-1</EndToEndId></Refs>
+          <AmtDtls><TxAmt><Amt Ccy="USD">170</Amt></TxAmt></AmtDtls>
+        </TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">50</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <BookgDt><DtTm>2026-10-02T10:00:00+02:00</DtTm></BookgDt>
+        <NtryDtls><TxDtls><Refs><EndToEndId>A&amp;B</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">999</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts>PDNG</Sts>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>"#;
+
+    const PAYMENT_INITIATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <GrpHdr><MsgId>PAY-1</MsgId><NbOfTxs>2</NbOfTxs></GrpHdr>
+    <PmtInf>
+      <PmtInfId>BATCH-1</PmtInfId>
+      <ReqdExctnDt><Dt>2026-10-03</Dt></ReqdExctnDt>
+      <DbtrAcct><Id><IBAN>DE89370400440532013000</IBAN></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>NOTPROVIDED</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">20.5</InstdAmt></Amt>
+      </CdtTrfTxInf>
+      <CdtTrfTxInf>
+        <PmtId><InstrId>INSTR-2</InstrId><EndToEndId>E2E-2</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">10</InstdAmt></Amt>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>"#;
+
+    const OCT_1: Timestamp = 1_790_812_800;
+    const DAY: Timestamp = 86_400;
+
+    fn mapping() -> AccountMapping {
+        let accounts = "account,client\nDE89370400440532013000,7\n";
+        AccountMapping::from_reader(csv::Reader::from_reader(accounts.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn statement() {
+        let entries = parse(STATEMENT.as_bytes()).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                BankEntry {
+                    account: "DE89370400440532013000".to_owned(),
+                    reference: Some("BANK-REF-1".to_owned()),
+                    kind: EventKind::Deposit,
+                    amount: dec!(150.25),
+                    currency: "EUR".to_owned(),
+                    booked: Some(OCT_1),
+                },
+                BankEntry {
+                    account: "DE89370400440532013000".to_owned(),
+                    reference: Some("A&B".to_owned()),
+                    kind: EventKind::Withdrawal,
+                    amount: dec!(50),
+                    currency: "EUR".to_owned(),
+                    booked: Some(OCT_1 + DAY),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn payment_initiation() {
+        let entries = parse(PAYMENT_INITIATION.as_bytes()).unwrap();
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.kind,
+                    entry.amount,
+                    entry.reference.as_deref(),
+                    entry.booked,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    EventKind::Withdrawal,
+                    dec!(20.5),
+                    None,
+                    Some(OCT_1 + 2 * DAY)
+                ),
+                (
+                    EventKind::Withdrawal,
+                    dec!(10),
+                    Some("INSTR-2"),
+                    Some(OCT_1 + 2 * DAY)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_can_be_processed() {
+        let rows = to_rows(&parse(STATEMENT.as_bytes()).unwrap(), &mapping(), 100).unwrap();
+        let mut csv_writer = csv::Writer::from_writer(Vec::new());
+        for row in &rows {
+            csv_writer.serialize(row).unwrap();
+        }
+        let input = csv_writer.into_inner().unwrap();
+        assert!(
+            String::from_utf8_lossy(&input)
+                .starts_with("type,client,tx,amount,timestamp,reference\ndeposit,7,100,150.25,")
+        );
+
+        let mut db = InMemoryTransactionDb::new();
+        csv_processor(
+            csv::Reader::from_reader(input.as_slice()),
+            csv::Writer::from_writer(Vec::new()),
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(db.client(7).unwrap().available, dec!(100.25));
+
+        let parsed: Vec<TransactionRow> = csv::Reader::from_reader(input.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parsed[1].timestamp, Some(OCT_1 + DAY));
+    }
+
+    #[test]
+    fn err_unmapped_account_or_currency() {
+        let entries = parse(STATEMENT.as_bytes()).unwrap();
+
+        assert!(matches!(
+            to_rows(&entries, &AccountMapping::default(), 1),
+            Err(Iso20022Error::UnknownAccount(account)) if account == "DE89370400440532013000"
+        ));
+
+        let mapping = AccountMapping {
+            currency: Some("USD".to_owned()),
+            ..mapping()
+        };
+        assert_eq!(
+            to_rows(&entries, &mapping, 1).unwrap_err().to_string(),
+            "entry BANK-REF-1 is in EUR, expected USD"
+        );
+    }
+
+    #[test]
+    fn err_unknown_document() {
+        let err =
+            parse(r#"<Document><FIToFICstmrCdtTrf></FIToFICstmrCdtTrf></Document>"#.as_bytes())
+                .unwrap_err();
+        assert!(matches!(err, Iso20022Error::UnknownDocument(_)));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2000-03-01").unwrap(), 951_868_800);
+        assert_eq!(parse_date("2026-10-01").unwrap(), OCT_1);
+        assert!(parse_date("2026-13-01").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{io::BufRead, time::Instant};
+
+use anyhow::Context;
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// Same as [`crate::csv::csv_processor`], but reads newline-delimited JSON events, eg
+/// `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`.
+///
+/// Events have the same fields as the CSV columns. Blank lines are skipped.
+pub fn jsonl_processor<R, O, DB>(reader: R, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    jsonl_processor_draining(reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`jsonl_processor`], but stops at the first event after `drain` was requested, eg
+/// on SIGTERM, and writes the clients as they are then.
+pub fn jsonl_processor_draining<R, O, DB>(
+    reader: R,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if drained(drain, &mut metrics) {
+            break;
+        }
+
+        let started = Instant::now();
+        let transaction_row: TransactionRow = serde_json::from_str(&line)
+            .with_context(|| format!("invalid event on line {}", number + 1))?;
+        apply_row(db, transaction_row, started, &mut metrics)?;
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    #[test]
+    fn processes_events() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+{"type":"deposit","client":2,"tx":2,"amount":2}
+
+{"type":"withdrawal","client":1,"tx":3,"amount":"0.5"}
+{"type":"dispute","client":2,"tx":2}
+{"type":"withdrawal","client":2,"tx":4,"amount":"1"}
+"#;
+
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = jsonl_processor(
+            input.as_bytes(),
+            csv::Writer::from_writer(&mut output),
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(metrics.latencies.count(), 5);
+        assert_eq!(metrics.activity.counters((None, 2)).rejections, 1);
+
+        let mut rows: Vec<ClientRow> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows.sort_by_key(|row| row.client);
+        assert_eq!(
+            rows,
+            vec![
+                ClientRow {
+                    client: 1,
+                    available: dec!(1),
+                    held: dec!(0),
+                    total: dec!(1),
+                    locked: false,
+                },
+                ClientRow {
+                    client: 2,
+                    available: dec!(0),
+                    held: dec!(2),
+                    total: dec!(2),
+                    locked: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn err_invalid_line() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1\"}\nnot json\n";
+
+        let mut db = InMemoryTransactionDb::new();
+        let err = jsonl_processor(
+            input.as_bytes(),
+            csv::Writer::from_writer(Vec::new()),
+            &mut db,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid event on line 2");
+    }
+
+    #[test]
+    fn stops_when_drained() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1\"}\nnot json\n";
+        let drain = DrainSignal::new();
+        drain.drain();
+
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = jsonl_processor_draining(
+            input.as_bytes(),
+            csv::Writer::from_writer(Vec::new()),
+            &mut db,
+            &drain,
+        )
+        .unwrap();
+        assert_eq!(metrics.drained_at, Some(0));
+        assert_eq!(metrics.latencies.count(), 0);
+    }
+}
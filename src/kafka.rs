@@ -0,0 +1,359 @@
+//! A consumer applying the events of a Kafka topic, to run the engine as a long-lived
+//! stream processor, and a producer publishing the resulting client updates.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use rdkafka::{
+    ClientConfig, ClientContext, Message, Offset,
+    consumer::{BaseConsumer, CommitMode, Consumer},
+    error::KafkaError,
+    producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext},
+    types::RDKafkaErrorCode,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    drain::DrainSignal,
+    metrics::RunMetrics,
+    publish::Publisher,
+    stream::{Disposition, PayloadFormat, apply_message},
+    transaction::{ClientId, TransactionProcessor},
+};
+
+/// Longest [`KafkaPublisher::flush`] waits for the outstanding updates to be delivered
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a [`KafkaSource`] waits to rewind a partition
+const SEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far a [`KafkaSource`] got in the partitions of its topic: the offset of the last
+/// message applied (or rejected by the rules) per partition.
+///
+/// Meant to be saved along the state the messages were applied to, see
+/// [`crate::cursor::OffsetProcessor`]: the offsets the group commits to the brokers can get
+/// ahead of the saved state, eg when the process is killed.
+pub type PartitionOffsets = BTreeMap<i32, i64>;
+
+/// What a [`KafkaSource`] does with a message, given the [`PartitionOffsets`] it resumed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Apply,
+    /// The state already includes the message, which was consumed again
+    Skip,
+    /// The messages from this offset on, which the state doesn't include, were skipped
+    Rewind(i64),
+}
+
+impl Resume {
+    /// What to do with the message at `offset` of `partition`, `first` if it's the first one
+    /// of the partition consumed by this run
+    fn of(offsets: &PartitionOffsets, partition: i32, offset: i64, first: bool) -> Self {
+        match offsets.get(&partition) {
+            Some(&last) if offset <= last => Self::Skip,
+            // Only the committed offset the group resumed from can be ahead of the state,
+            // later gaps are compacted messages or transaction markers
+            Some(&last) if offset > last + 1 && first => Self::Rewind(last + 1),
+            _ => Self::Apply,
+        }
+    }
+}
+
+/// Where to consume events from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaConfig {
+    /// Bootstrap servers, comma separated
+    pub brokers: String,
+    /// Consumer group, whose committed offsets runs resume from
+    pub group: String,
+    pub topic: String,
+    pub format: PayloadFormat,
+    /// Extra librdkafka properties, eg `security.protocol` and the `sasl.*` ones
+    pub properties: Vec<(String, String)>,
+}
+
+impl KafkaConfig {
+    fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", &self.group)
+            .set("auto.offset.reset", "earliest")
+            // Offsets are stored by hand once their event was applied, and committed in
+            // the background from there
+            .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false");
+        for (key, value) in &self.properties {
+            config.set(key, value);
+        }
+
+        config
+    }
+}
+
+/// Consumes events from a Kafka topic and applies them, storing the offset of a message only
+/// once its event was applied (or rejected by the rules).
+///
+/// Delivery is at least once: after a crash, the events since the last committed offsets
+/// are consumed again. Deposits and withdrawals are then rejected as duplicates, so a DB
+/// which was persisted along the offsets doesn't apply them twice.
+///
+/// Resumed from the [`PartitionOffsets`] saved with the state, it's exactly once: messages
+/// the state already includes are skipped, and partitions whose committed offset is ahead
+/// of the state are rewound to the first message it doesn't include.
+pub struct KafkaSource {
+    consumer: BaseConsumer,
+    format: PayloadFormat,
+    offsets: PartitionOffsets,
+    /// Partitions consumed from by this run
+    consumed: BTreeSet<i32>,
+}
+
+impl KafkaSource {
+    pub fn connect(config: &KafkaConfig) -> anyhow::Result<Self> {
+        let consumer: BaseConsumer = config
+            .client_config()
+            .create()
+            .context("failed to create the Kafka consumer")?;
+        consumer
+            .subscribe(&[&config.topic])
+            .with_context(|| format!("failed to subscribe to {}", config.topic))?;
+
+        Ok(Self {
+            consumer,
+            format: config.format,
+            offsets: PartitionOffsets::new(),
+            consumed: BTreeSet::new(),
+        })
+    }
+
+    /// Resumes from `offsets`, saved with the state the messages are applied to.
+    pub fn resume(&mut self, offsets: PartitionOffsets) {
+        self.offsets = offsets;
+    }
+
+    /// Offsets of the messages done with so far, including the ones resumed from
+    pub fn offsets(&self) -> &PartitionOffsets {
+        &self.offsets
+    }
+
+    /// Applies the events of the topic to `db` until `drain` is requested, then commits the
+    /// offsets of the applied ones.
+    ///
+    /// Messages which don't decode are logged and skipped. A storage error stops the run
+    /// without storing the offset of its message, so it's consumed again on the next run.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+
+        while !drain.is_draining() {
+            let Some(message) = self.consumer.poll(poll) else {
+                continue;
+            };
+            let message = message.context("failed to consume from Kafka")?;
+            let (partition, offset) = (message.partition(), message.offset());
+            let position = format!("{} partition {partition} offset {offset}", message.topic());
+
+            let first = self.consumed.insert(partition);
+            match Resume::of(&self.offsets, partition, offset, first) {
+                Resume::Apply => {}
+                Resume::Skip => {
+                    info!("Skipping {position}, the state already includes it");
+                    self.consumer
+                        .store_offset_from_message(&message)
+                        .with_context(|| format!("failed to store the offset of {position}"))?;
+                    continue;
+                }
+                Resume::Rewind(next) => {
+                    warn!("The state stops before {position}, rewinding to offset {next}");
+                    self.consumer
+                        .seek(
+                            message.topic(),
+                            partition,
+                            Offset::Offset(next),
+                            SEEK_TIMEOUT,
+                        )
+                        .with_context(|| format!("failed to rewind to offset {next}"))?;
+                    continue;
+                }
+            }
+
+            match apply_message(
+                db,
+                self.format,
+                message.payload().unwrap_or_default(),
+                &mut metrics,
+            ) {
+                Disposition::Done => {}
+                Disposition::Invalid(err) => error!("Skipping {position}: {err:#}"),
+                Disposition::Retry(err) => {
+                    self.commit()?;
+                    return Err(err.context(format!("failed to apply {position}")));
+                }
+            }
+            self.offsets.insert(partition, offset);
+            self.consumer
+                .store_offset_from_message(&message)
+                .with_context(|| format!("failed to store the offset of {position}"))?;
+        }
+
+        info!("Draining, committing the offsets of the applied events");
+        self.commit()?;
+
+        Ok(metrics)
+    }
+
+    fn commit(&self) -> anyhow::Result<()> {
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            // Nothing was consumed since the last commit
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+            result => result.context("failed to commit the offsets"),
+        }
+    }
+}
+
+/// Publishes client updates to a Kafka topic, keyed by client so the updates of a client
+/// land in the same partition, in order.
+///
+/// Updates are sent in the background, a failed delivery is returned by the next
+/// [`Publisher::publish`] or [`Publisher::flush`].
+pub struct KafkaPublisher {
+    producer: BaseProducer<DeliveryContext>,
+    topic: String,
+    failed: Arc<Mutex<Option<KafkaError>>>,
+}
+
+/// Keeps the first failed delivery for the [`KafkaPublisher`] to report
+struct DeliveryContext {
+    failed: Arc<Mutex<Option<KafkaError>>>,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((err, _)) = result {
+            let mut failed = self.failed.lock().unwrap_or_else(|err| err.into_inner());
+            failed.get_or_insert_with(|| err.clone());
+        }
+    }
+}
+
+impl KafkaPublisher {
+    /// Publishes to `topic` of the brokers of `config`, with its extra properties.
+    pub fn connect(config: &KafkaConfig, topic: &str) -> anyhow::Result<Self> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &config.brokers)
+            // Retries can't reorder the updates of a client
+            .set("enable.idempotence", "true");
+        for (key, value) in &config.properties {
+            client_config.set(key, value);
+        }
+
+        let failed = Arc::default();
+        let context = DeliveryContext {
+            failed: Arc::clone(&failed),
+        };
+        let producer = client_config
+            .create_with_context(context)
+            .context("failed to create the Kafka producer")?;
+
+        Ok(Self {
+            producer,
+            topic: topic.to_owned(),
+            failed,
+        })
+    }
+
+    fn check_deliveries(&self) -> anyhow::Result<()> {
+        let mut failed = self.failed.lock().unwrap_or_else(|err| err.into_inner());
+        match failed.take() {
+            Some(err) => Err(err).with_context(|| format!("failed to publish to {}", self.topic)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Publisher for KafkaPublisher {
+    fn publish(&mut self, client: ClientId, payload: &[u8]) -> anyhow::Result<()> {
+        self.check_deliveries()?;
+
+        let key = client.to_string();
+        let mut record = BaseRecord::to(&self.topic).key(&key).payload(payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => break,
+                // Wait for deliveries to make room
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), unsent)) => {
+                    record = unsent;
+                    self.producer.poll(Duration::from_millis(100));
+                }
+                Err((err, _)) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to publish to {}", self.topic));
+                }
+            }
+        }
+        // Serves the delivery callbacks
+        self.producer.poll(Duration::ZERO);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.producer
+            .flush(FLUSH_TIMEOUT)
+            .with_context(|| format!("failed to flush the updates to {}", self.topic))?;
+        self.check_deliveries()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resumes_from_the_state() {
+        let offsets = PartitionOffsets::from([(0, 41)]);
+
+        assert_eq!(Resume::of(&offsets, 0, 40, true), Resume::Skip);
+        assert_eq!(Resume::of(&offsets, 0, 41, false), Resume::Skip);
+        assert_eq!(Resume::of(&offsets, 0, 42, true), Resume::Apply);
+        // The group committed offsets the state doesn't include
+        assert_eq!(Resume::of(&offsets, 0, 50, true), Resume::Rewind(42));
+        assert_eq!(Resume::of(&offsets, 0, 50, false), Resume::Apply);
+        // Partitions the state knows nothing of start from the committed offsets
+        assert_eq!(Resume::of(&offsets, 1, 7, true), Resume::Apply);
+    }
+
+    #[test]
+    fn stores_offsets_by_hand() {
+        let config = KafkaConfig {
+            brokers: "localhost:9092".to_owned(),
+            group: "octopussy".to_owned(),
+            topic: "transactions".to_owned(),
+            format: PayloadFormat::Json,
+            properties: vec![
+                ("security.protocol".to_owned(), "SASL_SSL".to_owned()),
+                ("auto.offset.reset".to_owned(), "latest".to_owned()),
+            ],
+        }
+        .client_config();
+
+        assert_eq!(config.get("enable.auto.offset.store"), Some("false"));
+        assert_eq!(config.get("group.id"), Some("octopussy"));
+        assert_eq!(config.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(config.get("auto.offset.reset"), Some("latest"));
+    }
+}
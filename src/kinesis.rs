@@ -0,0 +1,341 @@
+//! A consumer applying the records of the shards of a Kinesis data stream, checkpointing how
+//! far it got in every shard.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use aws_config::BehaviorVersion;
+use aws_sdk_kinesis::{Client, types::ShardIteratorType};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+use tracing::{error, info};
+
+use crate::{
+    drain::DrainSignal,
+    encryption::{self, EncryptionKey},
+    metrics::RunMetrics,
+    stream::{Disposition, PayloadFormat, apply_message},
+    transaction::TransactionProcessor,
+};
+
+/// Records read at once from a shard
+const BATCH: i32 = 1000;
+
+/// How far a [`KinesisSource`] got in the shards of its stream.
+///
+/// Meant to be saved along the state the records were applied to: restarting from both
+/// resumes right after the last applied record of every shard.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardCheckpoints {
+    /// Sequence number of the last record applied (or rejected by the rules) per shard
+    #[serde(default)]
+    pub sequences: BTreeMap<String, String>,
+    /// Shards closed by a resharding whose records were all applied
+    #[serde(default)]
+    pub finished: BTreeSet<String>,
+}
+
+impl ShardCheckpoints {
+    /// Loads the checkpoints at `path`, or empty ones if there aren't any yet.
+    pub fn load(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = encryption::read_file(path, key)?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode shard checkpoints {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        encryption::write_file(path, &serde_json::to_vec(self)?, key)
+    }
+
+    /// Where to start reading `shard`: after its checkpoint, or at its oldest record
+    fn start(&self, shard: &str) -> (ShardIteratorType, Option<&str>) {
+        match self.sequences.get(shard) {
+            Some(sequence) => (ShardIteratorType::AfterSequenceNumber, Some(sequence)),
+            None => (ShardIteratorType::TrimHorizon, None),
+        }
+    }
+
+    /// Whether a shard with these parents can be read, ie the records of its parents which
+    /// are still in the stream were all applied first.
+    fn parents_finished<'a>(
+        &self,
+        parents: impl IntoIterator<Item = &'a str>,
+        listed: &BTreeSet<String>,
+    ) -> bool {
+        parents
+            .into_iter()
+            .all(|parent| !listed.contains(parent) || self.finished.contains(parent))
+    }
+}
+
+/// A shard being read
+struct ShardReader {
+    shard: String,
+    iterator: String,
+}
+
+/// Consumes the records of a Kinesis data stream from all its shards, moving the checkpoint
+/// of a shard past a record only once its event was applied (or rejected by the rules).
+///
+/// Shards are read in their resharding order: the children of a split or merge only once
+/// their parents were read to the end, so the records of a client are applied in order.
+/// Records which don't decode are logged and skipped.
+pub struct KinesisSource {
+    runtime: Runtime,
+    client: Client,
+    stream: String,
+    format: PayloadFormat,
+    checkpoints: ShardCheckpoints,
+    /// Shards of the stream, listed when connecting or created by a resharding since
+    listed: BTreeSet<String>,
+    /// Shards waiting for their parents, with them
+    waiting: BTreeMap<String, Vec<String>>,
+    readers: Vec<ShardReader>,
+}
+
+impl KinesisSource {
+    /// Connects with the default AWS configuration (environment, profile or instance role)
+    /// and starts reading `stream` from `checkpoints`.
+    pub fn connect(
+        stream: &str,
+        format: PayloadFormat,
+        checkpoints: ShardCheckpoints,
+    ) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("failed to start the AWS runtime")?;
+        let config = runtime.block_on(aws_config::defaults(BehaviorVersion::latest()).load());
+
+        let mut source = Self {
+            runtime,
+            client: Client::new(&config),
+            stream: stream.to_owned(),
+            format,
+            checkpoints,
+            listed: BTreeSet::new(),
+            waiting: BTreeMap::new(),
+            readers: Vec::new(),
+        };
+        for (shard, parents) in source.list_shards()? {
+            source.listed.insert(shard.clone());
+            source.waiting.insert(shard, parents);
+        }
+        source.start_ready_shards()?;
+
+        Ok(source)
+    }
+
+    pub fn checkpoints(&self) -> &ShardCheckpoints {
+        &self.checkpoints
+    }
+
+    /// The shards of the stream, with their parents
+    fn list_shards(&self) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+        let mut shards = Vec::new();
+        let mut next_token = None;
+        loop {
+            // The stream name and a token are mutually exclusive
+            let request = match next_token.take() {
+                Some(token) => self.client.list_shards().next_token(token),
+                None => self.client.list_shards().stream_name(&self.stream),
+            };
+            let output = self
+                .runtime
+                .block_on(request.send())
+                .with_context(|| format!("failed to list the shards of {}", self.stream))?;
+
+            for shard in output.shards() {
+                let parents = shard
+                    .parent_shard_id()
+                    .into_iter()
+                    .chain(shard.adjacent_parent_shard_id())
+                    .map(str::to_owned)
+                    .collect();
+                shards.push((shard.shard_id().to_owned(), parents));
+            }
+            match output.next_token() {
+                Some(token) => next_token = Some(token.to_owned()),
+                None => return Ok(shards),
+            }
+        }
+    }
+
+    /// Starts reading the waiting shards whose parents were read to the end
+    fn start_ready_shards(&mut self) -> anyhow::Result<()> {
+        let ready: Vec<String> = self
+            .waiting
+            .iter()
+            .filter(|(shard, parents)| {
+                !self.checkpoints.finished.contains(*shard)
+                    && self
+                        .checkpoints
+                        .parents_finished(parents.iter().map(String::as_str), &self.listed)
+            })
+            .map(|(shard, _)| shard.clone())
+            .collect();
+
+        for shard in ready {
+            self.waiting.remove(&shard);
+            let (kind, sequence) = self.checkpoints.start(&shard);
+            let request = self
+                .client
+                .get_shard_iterator()
+                .stream_name(&self.stream)
+                .shard_id(&shard)
+                .shard_iterator_type(kind)
+                .set_starting_sequence_number(sequence.map(str::to_owned));
+            let output = self
+                .runtime
+                .block_on(request.send())
+                .with_context(|| format!("failed to start reading shard {shard}"))?;
+            let iterator = output
+                .shard_iterator()
+                .with_context(|| format!("shard {shard} has no iterator"))?;
+
+            info!("Reading shard {shard}");
+            self.readers.push(ShardReader {
+                shard,
+                iterator: iterator.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Applies the records of the stream to `db` until `drain` is requested, polling every
+    /// shard in turn and waiting `poll` when none had new records.
+    ///
+    /// A storage error stops the run without moving the checkpoint of the shard past its
+    /// record, so it's read again on the next run.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+
+        while !drain.is_draining() {
+            let mut read_any = false;
+            let mut index = 0;
+            while index < self.readers.len() {
+                let reader = &self.readers[index];
+                let output = self
+                    .runtime
+                    .block_on(
+                        self.client
+                            .get_records()
+                            .shard_iterator(&reader.iterator)
+                            .limit(BATCH)
+                            .send(),
+                    )
+                    .with_context(|| format!("failed to read shard {}", reader.shard))?;
+
+                for record in output.records() {
+                    read_any = true;
+                    let sequence = record.sequence_number();
+                    let position = format!("shard {} record {sequence}", reader.shard);
+                    match apply_message(db, self.format, record.data().as_ref(), &mut metrics) {
+                        Disposition::Done => {}
+                        Disposition::Invalid(err) => error!("Skipping {position}: {err:#}"),
+                        Disposition::Retry(err) => {
+                            return Err(err.context(format!("failed to apply {position}")));
+                        }
+                    }
+                    self.checkpoints
+                        .sequences
+                        .insert(reader.shard.clone(), sequence.to_owned());
+                }
+
+                match output.next_shard_iterator() {
+                    Some(iterator) => {
+                        self.readers[index].iterator = iterator.to_owned();
+                        index += 1;
+                    }
+                    // Closed by a resharding and read to the end
+                    None => {
+                        let reader = self.readers.remove(index);
+                        info!("Finished shard {}", reader.shard);
+                        self.checkpoints.finished.insert(reader.shard);
+                        for child in output.child_shards() {
+                            if !self.listed.contains(child.shard_id()) {
+                                self.listed.insert(child.shard_id().to_owned());
+                                self.waiting.insert(
+                                    child.shard_id().to_owned(),
+                                    child.parent_shards().to_vec(),
+                                );
+                            }
+                        }
+                        self.start_ready_shards()?;
+                    }
+                }
+            }
+
+            if !read_any {
+                thread::sleep(poll);
+            }
+        }
+        info!("Draining, the checkpoints are where the last applied records are");
+
+        Ok(metrics)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shards.json");
+        assert_eq!(
+            ShardCheckpoints::load(&path, None).unwrap(),
+            ShardCheckpoints::default()
+        );
+
+        let mut checkpoints = ShardCheckpoints::default();
+        checkpoints
+            .sequences
+            .insert("shardId-000000000000".to_owned(), "4959".to_owned());
+        checkpoints
+            .finished
+            .insert("shardId-000000000001".to_owned());
+        checkpoints.save(&path, None).unwrap();
+        let checkpoints = ShardCheckpoints::load(&path, None).unwrap();
+
+        assert_eq!(
+            checkpoints.start("shardId-000000000000"),
+            (ShardIteratorType::AfterSequenceNumber, Some("4959"))
+        );
+        assert_eq!(
+            checkpoints.start("shardId-000000000002"),
+            (ShardIteratorType::TrimHorizon, None)
+        );
+    }
+
+    #[test]
+    fn reads_children_after_their_parents() {
+        let listed: BTreeSet<String> = ["parent", "adjacent", "child"].map(str::to_owned).into();
+        let mut checkpoints = ShardCheckpoints::default();
+
+        assert!(!checkpoints.parents_finished(["parent", "adjacent"], &listed));
+        checkpoints.finished.insert("parent".to_owned());
+        assert!(!checkpoints.parents_finished(["parent", "adjacent"], &listed));
+        checkpoints.finished.insert("adjacent".to_owned());
+        assert!(checkpoints.parents_finished(["parent", "adjacent"], &listed));
+
+        // Parents past the retention of the stream aren't listed anymore
+        assert!(checkpoints.parents_finished(["expired"], &listed));
+    }
+}
@@ -0,0 +1,290 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount,
+    clock::Timestamp,
+    export::OutputWriter,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// An accepted transaction and the balances of its client right after it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Sequence number the DB assigned to the event
+    pub seq: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Event type, eg `deposit`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// Amount of deposits and withdrawals, empty for the events referring to one
+    #[serde(serialize_with = "amount::option::serialize")]
+    pub amount: Option<Decimal>,
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Wraps a [`TransactionProcessor`] and writes a [`LedgerEntry`] to an [`OutputWriter`] for
+/// every event it applies successfully, eg a CSV ledger for accounting to ingest next to
+/// the final client balances. Rejected events are not part of it.
+///
+/// Some formats are only complete once [`LedgerProcessor::finish`] was called, eg the
+/// closing bracket of a JSON array.
+pub struct LedgerProcessor<DB, O> {
+    inner: DB,
+    output: O,
+}
+
+impl<DB, O> LedgerProcessor<DB, O>
+where
+    DB: TransactionProcessor,
+    O: OutputWriter,
+{
+    pub fn new(inner: DB, output: O) -> Self {
+        Self { inner, output }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, O) {
+        (self.inner, self.output)
+    }
+
+    /// Finishes the ledger, once every event was applied.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        self.output.finish()
+    }
+
+    /// Applies `event` with `apply`, and writes the resulting balances.
+    fn apply<F>(&mut self, event: TransactionEvent, apply: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut DB) -> Result<(), TransactionError>,
+    {
+        apply(&mut self.inner)?;
+
+        let client_id = event.client();
+        let Some(client) = self.inner.client(client_id) else {
+            return Err(TransactionError::ClientNotFound { client_id });
+        };
+        let amount = match event {
+            TransactionEvent::Deposit { amount, .. } => Some(amount),
+            TransactionEvent::Withdrawal { amount, .. } => Some(amount),
+            _ => None,
+        };
+        let entry = LedgerEntry {
+            seq: self.inner.last_seq(),
+            client: client_id,
+            tx: event.tx(),
+            event_type: event.kind().as_str().to_owned(),
+            amount,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.frozen,
+        };
+
+        self.output
+            .write_row(&entry)
+            .map_err(|err| TransactionError::Storage(format!("ledger: {err}")))
+    }
+}
+
+impl<DB, O> TransactionProcessor for LedgerProcessor<DB, O>
+where
+    DB: TransactionProcessor,
+    O: OutputWriter,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Deposit {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.deposit(transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Withdrawal {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.withdrawal(transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Dispute {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.dispute(transaction_id, client_id))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Resolve {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.resolve(transaction_id, client_id))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Chargeback {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.chargeback(transaction_id, client_id))
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.apply(transaction.clone(), |db| {
+            db.process_annotated_event(transaction, metadata)
+        })
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB, O> StateStore for LedgerProcessor<DB, O>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        export::{ExportFormat, FormatWriter},
+        memory_processor::InMemoryTransactionDb,
+    };
+
+    #[test]
+    fn writes_balances() {
+        let mut output = Vec::new();
+        let mut db = LedgerProcessor::new(
+            InMemoryTransactionDb::new(),
+            csv::Writer::from_writer(&mut output),
+        );
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.withdrawal(3, 1, dec!(100)).unwrap_err();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db.deposit(4, 2, dec!(1.5)).unwrap();
+        db.finish().unwrap();
+        drop(db);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "seq,client,tx,type,amount,available,held,total,locked
+1,1,1,deposit,10.0000,10.0000,0.0000,10.0000,false
+2,1,2,withdrawal,3.0000,7.0000,0.0000,7.0000,false
+3,1,1,dispute,,-3.0000,10.0000,7.0000,false
+4,1,1,chargeback,,-3.0000,0.0000,-3.0000,true
+5,2,4,deposit,1.5000,1.5000,0.0000,1.5000,false
+"
+        );
+    }
+
+    #[test]
+    fn json_array() {
+        let mut output = Vec::new();
+        let mut db = LedgerProcessor::new(
+            InMemoryTransactionDb::new(),
+            FormatWriter::new(ExportFormat::Json, &mut output),
+        );
+        db.deposit(7, 3, dec!(2)).unwrap();
+        db.finish().unwrap();
+        drop(db);
+
+        let entries: Vec<LedgerEntry> = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            entries,
+            vec![LedgerEntry {
+                seq: 1,
+                client: 3,
+                tx: 7,
+                event_type: "deposit".to_owned(),
+                amount: Some(dec!(2)),
+                available: dec!(2),
+                held: dec!(0),
+                total: dec!(2),
+                locked: false,
+            }]
+        );
+    }
+}
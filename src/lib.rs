@@ -1,3 +1,107 @@
+pub mod aggregate;
+pub mod amount;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod approval;
+pub mod archive;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "avro")]
+pub mod avro;
+pub mod backend;
+pub mod backup;
+pub mod bench;
+pub mod checkpoint;
+pub mod clock;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+pub mod cluster;
+pub mod cold_history;
+pub mod compression;
+#[cfg(unix)]
+pub mod control;
 pub mod csv;
+pub mod cursor;
+pub mod delta_stream;
+pub mod detect;
+pub mod diff;
+pub mod dispute_timeline;
+pub mod doctor;
+pub mod drain;
+pub mod encryption;
+pub mod engine;
+pub mod explain;
+pub mod export;
+pub mod fixed_width;
+pub mod flush;
+pub mod generate;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handoff;
+#[cfg(feature = "http")]
+pub mod http_input;
+pub mod i18n;
+pub mod inputs;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+pub mod json;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "kinesis")]
+pub mod kinesis;
+pub mod ledger;
+pub mod line_protocol;
+#[cfg(feature = "lmdb")]
+pub mod lmdb_processor;
 pub mod memory_processor;
+pub mod metrics;
+pub mod migrate;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(feature = "pipeline")]
+pub mod pipeline;
+pub mod pricing;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+pub mod pseudonymize;
+pub mod publish;
+#[cfg(any(feature = "redb", feature = "lmdb"))]
+mod record;
+#[cfg(feature = "redb")]
+pub mod redb_processor;
+#[cfg(feature = "redis")]
+pub mod redis_stream;
+#[cfg(feature = "grpc")]
+pub mod remote;
+pub mod replication;
+pub mod report;
+pub mod scenario;
+pub mod schema;
+pub mod screening;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod snapshot_codec;
+pub mod split;
+pub mod sql;
+#[cfg(feature = "sqs")]
+pub mod sqs;
+pub mod state_machine;
+pub mod state_version;
+pub mod stream;
+pub mod table;
+pub mod tenant;
 pub mod transaction;
+pub mod validate;
+pub mod verify;
+pub mod watch;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+#[cfg(feature = "xml")]
+pub mod xml;
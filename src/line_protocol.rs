@@ -0,0 +1,244 @@
+//! Ingestion of newline-delimited events over TCP, for legacy systems which can only pipe
+//! records to a socket, eg with netcat.
+//!
+//! Every line is an event, either a JSON object with the same fields as the CSV columns or a
+//! headerless CSV row (`deposit,1,1,1.5`), and is answered with a line of its own: `ok` once
+//! it was applied, or `error: <reason>` if it was rejected or doesn't decode. A CSV header
+//! line is answered with `ok` and otherwise ignored, and blank lines aren't answered.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tracing::{error, info, warn};
+
+use crate::{
+    csv::{CsvDialect, TransactionRow},
+    drain::DrainSignal,
+    tenant::ApiCalls,
+    transaction::{TransactionEvent, TransactionProcessor},
+};
+
+/// Longest line accepted, connections sending longer ones are answered with an error and
+/// closed
+pub const MAX_LINE: usize = 64 * 1024;
+
+/// Decodes the event of a line, `None` if it's a CSV header.
+pub fn decode_line(line: &str) -> anyhow::Result<Option<TransactionEvent>> {
+    let line = line.trim();
+    let row: TransactionRow = if line.starts_with('{') {
+        serde_json::from_str(line)?
+    } else {
+        let dialect = CsvDialect {
+            headers: false,
+            ..CsvDialect::default()
+        };
+        let mut reader = dialect.transaction_reader(line.as_bytes())?;
+        let mut record = csv::ByteRecord::new();
+        reader.read_record(&mut record)?;
+        if record.get(0) == Some(b"type".as_slice()) {
+            return Ok(None);
+        }
+        reader.decode(&record)?
+    };
+
+    Ok(Some(row.try_into()?))
+}
+
+/// A TCP listener applying the events of every connection as they come in.
+///
+/// Connections are served concurrently, each on its own thread, and their events are applied
+/// one at a time in the order they take the lock of the DB. The events of a connection are
+/// applied in order.
+pub struct LineServer {
+    listener: TcpListener,
+    api_calls: Option<ApiCalls>,
+}
+
+impl LineServer {
+    pub fn bind(address: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(address).context("failed to listen")?;
+        // Accepting is polled, to notice when to drain
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            api_calls: None,
+        })
+    }
+
+    /// Counts every line answered towards the API calls of the default tenant
+    pub fn counting_api_calls(mut self, calls: ApiCalls) -> Self {
+        self.api_calls = Some(calls);
+        self
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Applies the events of every connection to `db` until `drain` is requested, checking
+    /// for it every `poll`.
+    ///
+    /// Once draining, no connection is accepted anymore, and the open ones are closed after
+    /// the line they're in the middle of, if any.
+    pub fn run<DB>(&self, db: &Mutex<DB>, drain: &DrainSignal, poll: Duration) -> anyhow::Result<()>
+    where
+        DB: TransactionProcessor + Send,
+    {
+        thread::scope(|scope| {
+            while !drain.is_draining() {
+                let (stream, peer) = match self.listener.accept() {
+                    Ok(connection) => connection,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(poll);
+                        continue;
+                    }
+                    Err(err) => return Err(err).context("failed to accept a connection"),
+                };
+
+                info!("Accepted a connection from {peer}");
+                let api_calls = self.api_calls.as_ref();
+                scope.spawn(move || match serve(stream, db, api_calls, drain, poll) {
+                    Ok(()) => info!("Closed the connection from {peer}"),
+                    Err(err) => warn!("Lost the connection from {peer}: {err}"),
+                });
+            }
+            info!("Draining, closing the connections");
+
+            Ok(())
+        })
+    }
+}
+
+/// Applies the events of the lines of `stream`, answering every one
+fn serve<DB: TransactionProcessor>(
+    stream: TcpStream,
+    db: &Mutex<DB>,
+    api_calls: Option<&ApiCalls>,
+    drain: &DrainSignal,
+    poll: Duration,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(poll))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    loop {
+        let limit = (MAX_LINE + 1 - line.len()) as u64;
+        match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+            // Closed by the peer, after a last line without a newline, if any
+            Ok(0) if line.is_empty() => return Ok(()),
+            Ok(_) if line.len() > MAX_LINE => {
+                writeln!(writer, "error: lines are limited to {MAX_LINE} bytes")?;
+                return Ok(());
+            }
+            Ok(_) => {}
+            // Bytes read before the timeout are kept in `line`
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if drain.is_draining() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        if !text.trim().is_empty() {
+            if let Some(calls) = api_calls {
+                calls.record_api_call(None);
+            }
+            let result = decode_line(&text).and_then(|event| {
+                if let Some(event) = event {
+                    let mut db = db.lock().unwrap_or_else(|err| err.into_inner());
+                    db.process_transaction_event(event)?;
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => writeln!(writer, "ok")?,
+                Err(err) => {
+                    error!("Rejected {:?}: {err:#}", text.trim());
+                    writeln!(writer, "error: {err:#}")?;
+                }
+            }
+        }
+        line.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[test]
+    fn decodes_lines() {
+        let deposit = TransactionEvent::Deposit {
+            tx: 2,
+            client: 1,
+            amount: dec!(1.5),
+        };
+        assert_eq!(
+            decode_line("deposit, 1, 2, 1.5\n").unwrap(),
+            Some(deposit.clone())
+        );
+        assert_eq!(
+            decode_line(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "1.5"}"#).unwrap(),
+            Some(deposit)
+        );
+        assert_eq!(
+            decode_line("dispute,1,2").unwrap(),
+            Some(TransactionEvent::Dispute { tx: 2, client: 1 })
+        );
+        assert_eq!(decode_line("type,client,tx,amount").unwrap(), None);
+
+        assert!(decode_line("deposit,1,2").is_err());
+        assert!(decode_line("{").is_err());
+    }
+
+    #[test]
+    fn answers_every_line() {
+        let calls = ApiCalls::new();
+        let server = LineServer::bind("127.0.0.1:0")
+            .unwrap()
+            .counting_api_calls(calls.clone());
+        let address = server.local_addr().unwrap();
+        let db = Mutex::new(InMemoryTransactionDb::new());
+        let drain = DrainSignal::new();
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.run(&db, &drain, Duration::from_millis(10)));
+
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(
+                    b"type,client,tx,amount\ndeposit,1,1,10\n\nwithdrawal,1,2,20\ndispute,1,1",
+                )
+                .unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut answers = String::new();
+            stream.read_to_string(&mut answers).unwrap();
+
+            let answers: Vec<_> = answers.lines().collect();
+            assert_eq!(answers.len(), 4);
+            assert_eq!(answers[..2], ["ok", "ok"]);
+            assert!(answers[2].starts_with("error: "));
+            assert_eq!(answers[3], "ok");
+            drain.drain();
+        });
+
+        let client = db.into_inner().unwrap().client(1).unwrap();
+        assert_eq!(client.held, dec!(10));
+        assert_eq!(calls.get(None), 4);
+    }
+}
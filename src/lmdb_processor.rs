@@ -0,0 +1,496 @@
+use std::{fs, path::Path};
+
+use heed::{Database, Env, EnvOpenOptions, RwTxn, types::Bytes};
+use rust_decimal::Decimal;
+use tracing::error;
+
+use crate::{
+    record::{self, ClientRecord, RecordStore, TransactionRecord},
+    snapshot::{Snapshot, StateStore},
+    state_machine::{DisputeEvent, MaxDisputeCount},
+    state_version::{self, STATE_VERSION, StateVersion},
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+    },
+};
+
+/// Upper bound of the memory map, and so of the database size. LMDB only reserves address
+/// space for it, the file grows as data is written.
+const MAP_SIZE: usize = 16 * 1024 * 1024 * 1024;
+
+/// Key of the last applied sequence number in the `meta` database
+const META_SEQ: &[u8] = b"seq";
+const META_STATE_VERSION: &[u8] = b"state_version";
+
+fn storage(err: heed::Error) -> TransactionError {
+    TransactionError::Storage(err.to_string())
+}
+
+/// Big-endian, so that keys sort by client id.
+fn client_key(client_id: ClientId) -> [u8; 2] {
+    client_id.to_be_bytes()
+}
+
+/// Big-endian `client | tx`, so a client's transactions are adjacent in the B-tree.
+fn transaction_key(client_id: ClientId, transaction_id: TransactionId) -> [u8; 6] {
+    let mut key = [0; 6];
+    key[..2].copy_from_slice(&client_id.to_be_bytes());
+    key[2..].copy_from_slice(&transaction_id.to_be_bytes());
+    key
+}
+
+fn read_seq(meta: &Database<Bytes, Bytes>, txn: &heed::RoTxn) -> Result<u64, TransactionError> {
+    let Some(value) = meta.get(txn, META_SEQ).map_err(storage)? else {
+        return Ok(0);
+    };
+
+    let value: [u8; 8] = value
+        .try_into()
+        .map_err(|_| TransactionError::Storage(format!("corrupt seq of {} bytes", value.len())))?;
+    Ok(u64::from_be_bytes(value))
+}
+
+fn decode_transaction_key(key: &[u8]) -> Result<(ClientId, TransactionId), TransactionError> {
+    let key: [u8; 6] = key
+        .try_into()
+        .map_err(|_| TransactionError::Storage(format!("corrupt key of {} bytes", key.len())))?;
+
+    Ok((
+        ClientId::from_be_bytes([key[0], key[1]]),
+        TransactionId::from_be_bytes([key[2], key[3], key[4], key[5]]),
+    ))
+}
+
+/// A [`TransactionProcessor`] persisting its state in an [LMDB](http://www.lmdb.tech/doc/)
+/// environment through [heed](https://docs.rs/heed).
+///
+/// The transaction history lives in a memory-mapped B-tree, so the lookups done by
+/// disputes, resolves and chargebacks on old transactions are served straight from the
+/// page cache without copying. Writes are slower than reads, every event is applied in
+/// its own write transaction.
+pub struct LmdbTransactionDb {
+    env: Env,
+    clients: Database<Bytes, Bytes>,
+    transactions: Database<Bytes, Bytes>,
+    meta: Database<Bytes, Bytes>,
+    max_disputes: MaxDisputeCount,
+}
+
+/// The databases of a write transaction.
+struct LmdbRecords<'txn, 'env> {
+    txn: &'txn mut RwTxn<'env>,
+    clients: Database<Bytes, Bytes>,
+    transactions: Database<Bytes, Bytes>,
+    meta: Database<Bytes, Bytes>,
+}
+
+impl RecordStore for LmdbRecords<'_, '_> {
+    fn client(&self, client_id: ClientId) -> Result<Option<ClientRecord>, TransactionError> {
+        self.clients
+            .get(self.txn, &client_key(client_id))
+            .map_err(storage)?
+            .map(ClientRecord::decode)
+            .transpose()
+    }
+
+    fn transaction(
+        &self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, TransactionError> {
+        self.transactions
+            .get(self.txn, &transaction_key(client_id, transaction_id))
+            .map_err(storage)?
+            .map(TransactionRecord::decode)
+            .transpose()
+    }
+
+    fn put_client(
+        &mut self,
+        client_id: ClientId,
+        client: &ClientRecord,
+    ) -> Result<(), TransactionError> {
+        self.clients
+            .put(self.txn, &client_key(client_id), &client.encode())
+            .map_err(storage)
+    }
+
+    fn put_transaction(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        transaction: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.transactions
+            .put(
+                self.txn,
+                &transaction_key(client_id, transaction_id),
+                &transaction.encode(),
+            )
+            .map_err(storage)
+    }
+
+    fn next_seq(&mut self) -> Result<u64, TransactionError> {
+        let seq = read_seq(&self.meta, self.txn)? + 1;
+        self.meta
+            .put(self.txn, META_SEQ, &seq.to_be_bytes())
+            .map_err(storage)?;
+
+        Ok(seq)
+    }
+}
+
+impl LmdbTransactionDb {
+    /// Opens the environment in the `path` directory, creating it if it doesn't exist yet.
+    ///
+    /// The same directory must not be opened twice at the same time, LMDB doesn't support
+    /// multiple environments over the same files within a process.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TransactionError> {
+        let path = path.as_ref();
+        fs::create_dir_all(path).map_err(|err| {
+            TransactionError::Storage(format!("failed to create {}: {err}", path.display()))
+        })?;
+
+        // SAFETY: the files are only mapped by this environment (see above) and nothing
+        // else is expected to modify them while they are.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(3)
+                .open(path)
+                .map_err(storage)?
+        };
+
+        let mut txn = env.write_txn().map_err(storage)?;
+        let clients = env
+            .create_database(&mut txn, Some("clients"))
+            .map_err(storage)?;
+        let transactions = env
+            .create_database(&mut txn, Some("transactions"))
+            .map_err(storage)?;
+        let meta: Database<Bytes, Bytes> = env
+            .create_database(&mut txn, Some("meta"))
+            .map_err(storage)?;
+
+        // Records from older releases are decoded as they are, but a newer release may have
+        // written records this one can't read
+        let version = meta
+            .get(&txn, META_STATE_VERSION)
+            .map_err(storage)?
+            .map(|version| {
+                let version: [u8; 4] = version.try_into().map_err(|_| {
+                    TransactionError::Storage(format!(
+                        "corrupt state version of {} bytes",
+                        version.len()
+                    ))
+                })?;
+                Ok(StateVersion::from_be_bytes(version))
+            })
+            .transpose()?;
+        state_version::check_stored(version)
+            .map_err(|err| TransactionError::Storage(err.to_string()))?;
+        meta.put(&mut txn, META_STATE_VERSION, &STATE_VERSION.to_be_bytes())
+            .map_err(storage)?;
+        txn.commit().map_err(storage)?;
+
+        Ok(Self {
+            env,
+            clients,
+            transactions,
+            meta,
+            max_disputes: MaxDisputeCount::default(),
+        })
+    }
+
+    /// Sets how many times a transaction can be disputed.
+    pub fn set_max_dispute_count(&mut self, max_disputes: MaxDisputeCount) {
+        self.max_disputes = max_disputes;
+    }
+
+    /// Applies an event to the records inside a write transaction, committing only if it
+    /// succeeds.
+    fn apply<F>(&mut self, f: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut LmdbRecords) -> Result<ClientRecord, TransactionError>,
+    {
+        let mut txn = self.env.write_txn().map_err(storage)?;
+        f(&mut LmdbRecords {
+            txn: &mut txn,
+            clients: self.clients,
+            transactions: self.transactions,
+            meta: self.meta,
+        })?;
+        txn.commit().map_err(storage)
+    }
+
+    fn read_clients(&self) -> Result<Vec<ClientInformation>, TransactionError> {
+        let txn = self.env.read_txn().map_err(storage)?;
+
+        self.clients
+            .iter(&txn)
+            .map_err(storage)?
+            .map(|entry| {
+                let (key, value) = entry.map_err(storage)?;
+                let id = ClientId::from_be_bytes([key[0], key[1]]);
+                Ok(ClientRecord::decode(value)?.information(id))
+            })
+            .collect()
+    }
+
+    fn read_client(&self, client_id: ClientId) -> Result<Option<ClientRecord>, TransactionError> {
+        let txn = self.env.read_txn().map_err(storage)?;
+
+        self.clients
+            .get(&txn, &client_key(client_id))
+            .map_err(storage)?
+            .map(ClientRecord::decode)
+            .transpose()
+    }
+}
+
+impl TransactionProcessor for LmdbTransactionDb {
+    fn last_seq(&self) -> u64 {
+        let seq = self
+            .env
+            .read_txn()
+            .map_err(storage)
+            .and_then(|txn| read_seq(&self.meta, &txn));
+
+        seq.unwrap_or_else(|err| {
+            error!("failed to read the sequence number: {err}");
+            0
+        })
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(|records| record::deposit(records, transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(|records| record::withdrawal(records, transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(|records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Dispute,
+                max_disputes,
+            )
+        })
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(|records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Resolve,
+                max_disputes,
+            )
+        })
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(|records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Chargeback,
+                max_disputes,
+            )
+        })
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.read_client(client_id)
+            .unwrap_or_else(|err| {
+                error!("failed to read client {client_id}: {err}");
+                None
+            })
+            .map(|client| client.information(client_id))
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.read_clients()
+            .unwrap_or_else(|err| {
+                error!("failed to read clients: {err}");
+                Vec::new()
+            })
+            .into_iter()
+    }
+}
+
+impl StateStore for LmdbTransactionDb {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        let txn = self.env.read_txn()?;
+        let mut snapshot = Snapshot {
+            seq: read_seq(&self.meta, &txn)?,
+            ..Default::default()
+        };
+
+        for entry in self.clients.iter(&txn)? {
+            let (key, value) = entry?;
+            let id = ClientId::from_be_bytes([key[0], key[1]]);
+            snapshot
+                .clients
+                .push(ClientRecord::decode(value)?.to_snapshot(id));
+        }
+
+        for entry in self.transactions.iter(&txn)? {
+            let (key, value) = entry?;
+            let (client, tx) = decode_transaction_key(key)?;
+            snapshot
+                .transactions
+                .push(TransactionRecord::decode(value)?.to_snapshot(client, tx));
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let mut txn = self.env.write_txn()?;
+        self.clients.clear(&mut txn)?;
+        self.transactions.clear(&mut txn)?;
+        self.meta
+            .put(&mut txn, META_SEQ, &snapshot.seq.to_be_bytes())?;
+        self.meta
+            .put(&mut txn, META_STATE_VERSION, &STATE_VERSION.to_be_bytes())?;
+
+        for client in &snapshot.clients {
+            let record = ClientRecord::from_snapshot(client);
+            self.clients
+                .put(&mut txn, &client_key(client.id), &record.encode())?;
+        }
+
+        for transaction in &snapshot.transactions {
+            let record = TransactionRecord::from_snapshot(transaction);
+            self.transactions.put(
+                &mut txn,
+                &transaction_key(transaction.client, transaction.tx),
+                &record.encode(),
+            )?;
+        }
+        txn.commit()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn open_temp() -> (tempfile::TempDir, LmdbTransactionDb) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = LmdbTransactionDb::open(dir.path()).unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn dispute_old_transaction() {
+        let (_dir, mut db) = open_temp();
+        for tx in 1..=100 {
+            db.deposit(tx, 1, dec!(1)).unwrap();
+        }
+
+        db.dispute(3, 1).unwrap();
+        assert_eq!(
+            db.dispute(3, 1),
+            Err(TransactionError::AlreadyDisputed {
+                client_id: 1,
+                transaction_id: 3
+            })
+        );
+        db.chargeback(3, 1).unwrap();
+
+        let client = db.client(1).unwrap();
+        assert_eq!(client.available, dec!(99));
+        assert_eq!(client.held, dec!(0));
+        assert!(client.frozen);
+        assert_eq!(
+            db.deposit(101, 1, dec!(1)),
+            Err(TransactionError::AccountFrozen { client_id: 1 })
+        );
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut db = LmdbTransactionDb::open(dir.path()).unwrap();
+            db.deposit(1, 1, dec!(10)).unwrap();
+            db.withdrawal(2, 1, dec!(4)).unwrap();
+        }
+
+        let mut db = LmdbTransactionDb::open(dir.path()).unwrap();
+        assert_eq!(db.client(1).unwrap().available, dec!(6));
+        db.dispute(1, 1).unwrap();
+        assert_eq!(db.client(1).unwrap().held, dec!(10));
+    }
+
+    #[test]
+    fn matches_memory_snapshot() {
+        fn apply(db: &mut impl TransactionProcessor) {
+            db.deposit(1, 1, dec!(10)).unwrap();
+            db.deposit(2, 2, dec!(3.5)).unwrap();
+            db.withdrawal(3, 1, dec!(2)).unwrap();
+            db.dispute(2, 2).unwrap();
+            db.dispute(3, 1).unwrap();
+            db.resolve(3, 1).unwrap();
+        }
+
+        let (_dir, mut lmdb) = open_temp();
+        let mut memory = InMemoryTransactionDb::new();
+        apply(&mut lmdb);
+        apply(&mut memory);
+
+        let mut expected = memory.snapshot().unwrap();
+        expected.normalize();
+        // Only the memory backend keeps the age of transactions
+        expected.recorded = 0;
+        for transaction in &mut expected.transactions {
+            transaction.recorded_at = 0;
+            transaction.created_at = None;
+        }
+        let snapshot = lmdb.snapshot().unwrap();
+        assert_eq!(snapshot, expected);
+
+        // And back again, through a restore
+        let (_dir, mut restored) = open_temp();
+        restored.restore(snapshot).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), expected);
+    }
+}
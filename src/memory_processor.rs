@@ -1,9 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, mem, num::NonZeroU64, path::Path};
 
 use rust_decimal::Decimal;
 
-use crate::transaction::{
-    ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+use crate::{
+    archive::{AccountArchive, ArchivedTransaction, ClosedAccount, TransactionArchive},
+    clock::{Clock, SystemClock, Timestamp},
+    cold_history::ColdHistory,
+    snapshot::{ClientSnapshot, Snapshot, StateStore, TransactionSnapshot},
+    state_machine::{
+        ACCOUNT_MACHINE, AccountEvent, AccountStatus, DisputeEvent, Guard, MaxDisputeCount,
+        TransactionStatus, Transition, account_transition, dispute_transition,
+    },
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+    },
 };
 
 /// A simplified transaction representation.
@@ -20,9 +30,123 @@ struct TransactionState {
 
     // Whether the transaction is disputed or not
     disputed: bool,
+
+    /// Whether the dispute ended in a chargeback, which makes the transaction final
+    charged_back: bool,
+
+    /// Value of [`InMemoryTransactionDb::recorded`] when the transaction was recorded
+    recorded_at: u64,
+
+    /// Time at which the transaction was recorded, according to the DB's [`Clock`]
+    created_at: Timestamp,
+
+    /// Sequence number of the event which recorded the transaction
+    seq: u64,
+
+    /// Number of times the transaction was disputed
+    disputes: u32,
 }
 
-#[derive(Default)]
+impl TransactionState {
+    fn status(&self) -> TransactionStatus {
+        TransactionStatus::from_flags(self.disputed, self.charged_back)
+    }
+
+    fn set_status(&mut self, status: TransactionStatus) {
+        (self.disputed, self.charged_back) = status.flags();
+    }
+
+    fn archived(&self, client: ClientId, tx: TransactionId) -> ArchivedTransaction {
+        ArchivedTransaction {
+            client,
+            tx,
+            amount: self.amount,
+            charged_back: self.charged_back,
+            recorded_at: self.recorded_at,
+            created_at: self.created_at,
+            seq: self.seq,
+            disputes: self.disputes,
+        }
+    }
+
+    /// Archived transactions are never disputed
+    fn from_archived(archived: &ArchivedTransaction) -> Self {
+        Self {
+            amount: archived.amount,
+            disputed: false,
+            charged_back: archived.charged_back,
+            recorded_at: archived.recorded_at,
+            created_at: archived.created_at,
+            seq: archived.seq,
+            disputes: archived.disputes,
+        }
+    }
+}
+
+// Approximate heap cost of a client and of a recorded transaction. Entries are counted
+// twice to account for the maps' spare capacity and bookkeeping.
+const CLIENT_SIZE: usize = 2 * mem::size_of::<(ClientId, ClientState)>();
+const TRANSACTION_SIZE: usize = 2 * mem::size_of::<((ClientId, TransactionId), TransactionState)>();
+
+/// Decides which transactions [`InMemoryTransactionDb::compact`] drops from the history.
+///
+/// Dropped transactions can no longer be disputed, and their ids are no longer checked
+/// for duplicates.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionPolicy {
+    /// Drop transactions which were charged back, since they can't change anymore
+    pub drop_charged_back: bool,
+
+    /// Transactions followed by more than this many newer transactions can no longer be
+    /// disputed, and are dropped unless they're currently disputed.
+    pub dispute_horizon: Option<u64>,
+
+    /// Transactions followed by more than this many newer transactions are compressed into
+    /// per-client blocks, unless they're currently disputed. They can still be disputed and
+    /// are still checked for duplicates, at the cost of decompressing their client's block.
+    pub compress_after: Option<u64>,
+
+    /// Compact automatically after every N recorded transactions
+    pub every: Option<NonZeroU64>,
+}
+
+/// Client balances as of the moment [`InMemoryTransactionDb::freeze`] was called.
+///
+/// Cheap to clone, and can be sent to other threads.
+#[derive(Clone)]
+pub struct FrozenClients {
+    clients: imbl::HashMap<ClientId, ClientState>,
+    seq: u64,
+}
+
+impl FrozenClients {
+    /// Sequence number of the last event applied before freezing
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.information(client_id))
+    }
+
+    pub fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> + '_ {
+        self.clients
+            .iter()
+            .map(|(&id, client)| client.information(id))
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct ClientState {
     available: Decimal,
     held: Decimal,
@@ -54,21 +178,563 @@ impl ClientState {
     pub fn frozen(&self) -> bool {
         self.frozen
     }
+
+    fn information(&self, id: ClientId) -> ClientInformation {
+        ClientInformation {
+            id,
+            available: self.available(),
+            held: self.held(),
+            total: self.total(),
+            frozen: self.frozen(),
+        }
+    }
 }
 
 #[derive(Default)]
-pub struct InMemoryTransactionDb {
-    clients: HashMap<ClientId, ClientState>,
+pub struct InMemoryTransactionDb<C = SystemClock> {
+    /// A persistent map, so [`InMemoryTransactionDb::freeze`] is cheap
+    clients: imbl::HashMap<ClientId, ClientState>,
     transaction_history: HashMap<(ClientId, TransactionId), TransactionState>,
+    /// Transactions moved out of `transaction_history` by [`CompactionPolicy::compress_after`]
+    cold_history: ColdHistory,
+
+    /// Number of transactions recorded so far, used to age transactions
+    recorded: u64,
+    /// Sequence number of the last applied event
+    seq: u64,
+    compaction: CompactionPolicy,
+
+    clock: C,
+
+    /// How long after being recorded a transaction can still be disputed, in seconds
+    dispute_window: Option<u64>,
+
+    /// Archives holding transactions evicted from `transaction_history`
+    archives: Vec<TransactionArchive>,
+
+    max_disputes: MaxDisputeCount,
+
+    /// Approximate number of heap bytes the DB may use, see
+    /// [`InMemoryTransactionDb::memory_usage`]
+    memory_limit: Option<usize>,
+
+    /// Where [`InMemoryTransactionDb::close_account`] moves closed accounts to
+    closed_accounts: Option<AccountArchive>,
 }
 
 impl InMemoryTransactionDb {
     pub fn new() -> Self {
         InMemoryTransactionDb::default()
     }
+
+    pub fn with_compaction_policy(policy: CompactionPolicy) -> Self {
+        InMemoryTransactionDb {
+            compaction: policy,
+            ..Default::default()
+        }
+    }
+
+    /// Creates an empty DB which rejects deposits and withdrawals with
+    /// [`TransactionError::CapacityExceeded`] once it would use more than roughly `bytes`
+    /// of memory.
+    pub fn with_memory_limit(bytes: usize) -> Self {
+        InMemoryTransactionDb {
+            memory_limit: Some(bytes),
+            ..Default::default()
+        }
+    }
 }
 
-impl InMemoryTransactionDb {
+impl<C: Clock> InMemoryTransactionDb<C> {
+    /// Creates an empty DB reading the time from `clock`.
+    pub fn with_clock(clock: C) -> Self {
+        InMemoryTransactionDb {
+            clients: imbl::HashMap::new(),
+            transaction_history: HashMap::new(),
+            cold_history: ColdHistory::default(),
+            recorded: 0,
+            seq: 0,
+            compaction: CompactionPolicy::default(),
+            clock,
+            dispute_window: None,
+            archives: Vec::new(),
+            max_disputes: MaxDisputeCount::default(),
+            memory_limit: None,
+            closed_accounts: None,
+        }
+    }
+
+    pub fn set_compaction_policy(&mut self, policy: CompactionPolicy) {
+        self.compaction = policy;
+    }
+
+    /// Sets how many seconds after being recorded a transaction can still be disputed.
+    /// `None` (the default) means forever.
+    pub fn set_dispute_window(&mut self, window: Option<u64>) {
+        self.dispute_window = window;
+    }
+
+    /// Sets how many times a transaction can be disputed. Unlimited by default.
+    pub fn set_max_dispute_count(&mut self, max_disputes: MaxDisputeCount) {
+        self.max_disputes = max_disputes;
+    }
+
+    /// Sets the approximate number of heap bytes the DB may use. `None` (the default) means
+    /// unbounded.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// Approximate number of heap bytes used by client balances and the transaction
+    /// history. Compacting, compressing or archiving transactions brings it down.
+    pub fn memory_usage(&self) -> usize {
+        self.clients.len() * CLIENT_SIZE
+            + self.transaction_history.len() * TRANSACTION_SIZE
+            + self.cold_history.compressed_bytes()
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Returns an immutable view of the current client balances.
+    ///
+    /// The view shares its structure with the live state, so taking one is O(1) and
+    /// processing keeps going while it's read (eg by a reporting thread): only the parts
+    /// changed afterwards get copied.
+    pub fn freeze(&self) -> FrozenClients {
+        FrozenClients {
+            clients: self.clients.clone(),
+            seq: self.seq,
+        }
+    }
+
+    /// Number of transactions currently kept in the history, compressed or not
+    pub fn transaction_count(&self) -> usize {
+        self.transaction_history.len() + self.cold_history.len()
+    }
+
+    /// Number of transactions of the history which are compressed
+    pub fn compressed_transaction_count(&self) -> usize {
+        self.cold_history.len()
+    }
+
+    /// Drops the transactions which can no longer change according to the
+    /// [`CompactionPolicy`], then compresses the cold ones. Returns the number of dropped
+    /// transactions.
+    pub fn compact(&mut self) -> usize {
+        let policy = &self.compaction;
+        let horizon = policy
+            .dispute_horizon
+            .map(|horizon| self.recorded.saturating_sub(horizon));
+        let keep = |charged_back: bool, disputed: bool, recorded_at: u64| {
+            if charged_back {
+                return !policy.drop_charged_back;
+            }
+
+            match horizon {
+                Some(horizon) => disputed || recorded_at >= horizon,
+                None => true,
+            }
+        };
+
+        let before = self.transaction_history.len();
+        self.transaction_history.retain(|_, transaction| {
+            keep(
+                transaction.charged_back,
+                transaction.disputed,
+                transaction.recorded_at,
+            )
+        });
+        let mut dropped = before - self.transaction_history.len();
+
+        // Looking into the cold history means decompressing all of it, so only do it when
+        // the policy can drop something
+        if policy.drop_charged_back || horizon.is_some() {
+            dropped += self
+                .cold_history
+                .retain(|transaction| {
+                    keep(transaction.charged_back, false, transaction.recorded_at)
+                })
+                .len();
+        }
+
+        if let Some(after) = self.compaction.compress_after {
+            self.compress(self.recorded.saturating_sub(after));
+        }
+
+        dropped
+    }
+
+    /// Compresses the transactions recorded before `horizon` (a value of the recorded
+    /// transactions counter) into their client's cold block. Disputed transactions stay
+    /// uncompressed since they're still in flight, charged back ones are final and get
+    /// compressed. Returns the number of compressed transactions.
+    fn compress(&mut self, horizon: u64) -> usize {
+        let mut cold: HashMap<ClientId, Vec<ArchivedTransaction>> = HashMap::new();
+        self.transaction_history
+            .retain(|&(client_id, transaction_id), transaction| {
+                if transaction.status() == TransactionStatus::Disputed
+                    || transaction.recorded_at >= horizon
+                {
+                    return true;
+                }
+
+                cold.entry(client_id)
+                    .or_default()
+                    .push(transaction.archived(client_id, transaction_id));
+                false
+            });
+
+        let mut compressed = 0;
+        for (client_id, transactions) in cold {
+            compressed += transactions.len();
+            self.cold_history.insert(client_id, transactions);
+        }
+
+        compressed
+    }
+
+    /// Moves `client_id`'s compressed transactions back into the history.
+    fn decompress(&mut self, client_id: ClientId) {
+        for transaction in self.cold_history.take(client_id) {
+            self.transaction_history.insert(
+                (client_id, transaction.tx),
+                TransactionState::from_archived(&transaction),
+            );
+        }
+    }
+
+    /// Moves the transactions with an id below `watermark` into a compressed archive at
+    /// `path`, and drops them from memory.
+    ///
+    /// The archive stays attached to the DB: a dispute referencing an archived transaction
+    /// loads it back, and replaying one is still rejected as a duplicate. Disputed
+    /// transactions are kept in memory since they're still in flight, while charged back and
+    /// compressed ones are archived too.
+    pub fn archive(
+        &mut self,
+        watermark: TransactionId,
+        path: &Path,
+    ) -> anyhow::Result<&TransactionArchive> {
+        let mut transactions: Vec<_> = self
+            .transaction_history
+            .iter()
+            .filter(|((_, transaction_id), transaction)| {
+                *transaction_id < watermark && transaction.status() != TransactionStatus::Disputed
+            })
+            .map(|(&(client_id, transaction_id), transaction)| {
+                transaction.archived(client_id, transaction_id)
+            })
+            .chain(
+                self.cold_history
+                    .iter()
+                    .filter(|transaction| transaction.tx < watermark),
+            )
+            .collect();
+        transactions.sort_unstable_by_key(|transaction| (transaction.tx, transaction.client));
+
+        let archive = TransactionArchive::write(path, watermark, transactions.iter().cloned())?;
+
+        for transaction in &transactions {
+            self.transaction_history
+                .remove(&(transaction.client, transaction.tx));
+        }
+        self.cold_history
+            .retain(|transaction| transaction.tx >= watermark);
+        self.archives.push(archive);
+
+        Ok(self.archives.last().expect("archive was just added"))
+    }
+
+    /// Attaches an archive written by [`InMemoryTransactionDb::archive`], eg after
+    /// restoring the DB from a snapshot.
+    pub fn attach_archive(&mut self, archive: TransactionArchive) {
+        self.archives.push(archive);
+    }
+
+    pub fn archives(&self) -> &[TransactionArchive] {
+        &self.archives
+    }
+
+    /// Moves a transaction back from the cold history or the archives into memory, if it's
+    /// not there already. Decompressing a transaction decompresses its client's whole block,
+    /// since disputes tend to come in bursts.
+    fn load_archived(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Result<(), TransactionError> {
+        if self
+            .transaction_history
+            .contains_key(&(client_id, transaction_id))
+        {
+            return Ok(());
+        }
+
+        if self.cold_history.contains(client_id, transaction_id) {
+            self.decompress(client_id);
+            return Ok(());
+        }
+
+        for archive in &self.archives {
+            let archived = archive
+                .find(client_id, transaction_id)
+                .map_err(|err| TransactionError::Storage(format!("{err:#}")))?;
+
+            if let Some(archived) = archived {
+                self.transaction_history.insert(
+                    (client_id, transaction_id),
+                    TransactionState::from_archived(&archived),
+                );
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets where [`InMemoryTransactionDb::close_account`] moves closed accounts to.
+    pub fn set_account_archive(&mut self, archive: AccountArchive) {
+        self.closed_accounts = Some(archive);
+    }
+
+    /// Closes `client_id`'s account: its balances and transaction history are moved to the
+    /// account archive and dropped from memory, so only active accounts take up space.
+    ///
+    /// A closed account is no longer part of [`TransactionProcessor::clients_iter`] or of
+    /// snapshots, but can still be looked up with [`InMemoryTransactionDb::closed_account`].
+    /// Deposits to it are rejected with [`TransactionError::AccountClosed`].
+    ///
+    /// ## Errors
+    /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
+    /// - If some of its funds are held by disputes, returns [`TransactionError::FundsHeld`]
+    /// - If no account archive was set, or writing to it fails, returns
+    ///   [`TransactionError::Storage`]
+    pub fn close_account(&mut self, client_id: ClientId) -> Result<(), TransactionError> {
+        let archive = self.closed_accounts.as_ref().ok_or_else(|| {
+            TransactionError::Storage("no account archive to close accounts into".to_owned())
+        })?;
+        let client = self
+            .clients
+            .get(&client_id)
+            .ok_or(TransactionError::ClientNotFound { client_id })?;
+
+        if !client.held.is_zero() {
+            return Err(TransactionError::FundsHeld { client_id });
+        }
+
+        let mut transactions: Vec<_> = self
+            .transaction_history
+            .iter()
+            .filter(|((id, _), _)| *id == client_id)
+            .map(|(&(client, tx), transaction)| transaction.archived(client, tx))
+            .chain(self.cold_history.get(client_id))
+            .collect();
+        transactions.sort_unstable_by_key(|transaction| transaction.tx);
+
+        let account = ClosedAccount {
+            client: ClientSnapshot {
+                id: client_id,
+                available: client.available,
+                held: client.held,
+                frozen: client.frozen,
+            },
+            closed_at: self.clock.now(),
+            transactions,
+        };
+        archive
+            .write(&account)
+            .map_err(|err| TransactionError::Storage(format!("{err:#}")))?;
+
+        self.clients.remove(&client_id);
+        self.cold_history.take(client_id);
+        for transaction in &account.transactions {
+            self.transaction_history
+                .remove(&(client_id, transaction.tx));
+        }
+
+        Ok(())
+    }
+
+    /// Looks up an account closed with [`InMemoryTransactionDb::close_account`].
+    pub fn closed_account(&self, client_id: ClientId) -> anyhow::Result<Option<ClosedAccount>> {
+        match &self.closed_accounts {
+            Some(archive) => archive.get(client_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Lifts the freeze of a client's account, eg once a chargeback was investigated.
+    ///
+    /// ## Errors
+    /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
+    /// - If the account isn't frozen, returns [`TransactionError::NotFrozen`]
+    pub fn unlock(&mut self, client_id: ClientId) -> Result<(), TransactionError> {
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or(TransactionError::ClientNotFound { client_id })?;
+
+        let transition = ACCOUNT_MACHINE
+            .transition(
+                AccountStatus::from_frozen(client.frozen),
+                AccountEvent::Unlock,
+            )
+            .ok_or(TransactionError::NotFrozen { client_id })?;
+        client.frozen = transition.to == AccountStatus::Frozen;
+
+        Ok(())
+    }
+
+    /// Corrects a client's available balance by `amount` (negative to debit it), even if
+    /// the account is frozen. The adjustment is recorded as a transaction, so it shows up
+    /// in the history and balances can still be recomputed from it.
+    ///
+    /// ## Errors
+    /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
+    /// - In case of duplicate transactions, returns [`TransactionError::DuplicateTransaction`]
+    pub fn adjust(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.ensure_transaction_uniqe(transaction_id, client_id)?;
+        if !self.clients.contains_key(&client_id) {
+            return Err(TransactionError::ClientNotFound { client_id });
+        }
+        self.ensure_capacity(client_id)?;
+
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or(TransactionError::ClientNotFound { client_id })?;
+        let transition = account_transition(
+            AccountStatus::from_frozen(client.frozen),
+            AccountEvent::Adjustment,
+            client_id,
+        )?;
+
+        client.available += amount;
+        client.frozen = transition.to == AccountStatus::Frozen;
+        self.record_transaction(transaction_id, client_id, amount);
+
+        Ok(())
+    }
+
+    /// Used as a pre-flight check before a deposit creates a client. If the client's
+    /// account was closed it returns [`TransactionError::AccountClosed`]
+    fn ensure_not_closed(&self, client_id: ClientId) -> Result<(), TransactionError> {
+        match &self.closed_accounts {
+            Some(archive)
+                if !self.clients.contains_key(&client_id) && archive.contains(client_id) =>
+            {
+                Err(TransactionError::AccountClosed { client_id })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Merges the state of `other` into this DB, eg to combine the outputs of shards that
+    /// were processed independently.
+    ///
+    /// Balances of clients present in both DBs are added up, and a client frozen in either
+    /// stays frozen. Disputes can only reference transactions of their own shard, so
+    /// shards should be split by client.
+    ///
+    /// `other`'s events are ordered after this DB's: their sequence numbers continue from
+    /// [`TransactionProcessor::last_seq`].
+    ///
+    /// ## Errors
+    /// - If both DBs recorded the same transaction, returns
+    ///   [`TransactionError::DuplicateTransaction`] and leaves this DB unchanged
+    pub fn merge<D>(
+        &mut self,
+        mut other: InMemoryTransactionDb<D>,
+    ) -> Result<(), TransactionError> {
+        for transaction in other.cold_history.iter() {
+            other.transaction_history.insert(
+                (transaction.client, transaction.tx),
+                TransactionState::from_archived(&transaction),
+            );
+        }
+
+        for &(client_id, transaction_id) in other.transaction_history.keys() {
+            self.ensure_transaction_uniqe(transaction_id, client_id)?;
+        }
+
+        for (id, other_client) in other.clients {
+            let client = self.clients.entry(id).or_default();
+            client.available += other_client.available;
+            client.held += other_client.held;
+            client.frozen |= other_client.frozen;
+        }
+
+        for (key, mut transaction) in other.transaction_history {
+            transaction.recorded_at += self.recorded;
+            transaction.seq += self.seq;
+            self.transaction_history.insert(key, transaction);
+        }
+
+        self.recorded += other.recorded;
+        self.seq += other.seq;
+        self.archives.extend(other.archives);
+
+        Ok(())
+    }
+
+    fn record_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) {
+        self.seq += 1;
+        self.transaction_history.insert(
+            (client_id, transaction_id),
+            TransactionState {
+                amount,
+                disputed: false,
+                charged_back: false,
+                recorded_at: self.recorded,
+                created_at: self.clock.now(),
+                seq: self.seq,
+                disputes: 0,
+            },
+        );
+        self.recorded += 1;
+
+        if let Some(every) = self.compaction.every
+            && self.recorded.is_multiple_of(every.get())
+        {
+            self.compact();
+        }
+    }
+}
+
+impl<C: Clock> InMemoryTransactionDb<C> {
+    /// Looks up the transitions `event` takes in both state machines, returning the
+    /// transaction's transition and whether the account is frozen afterwards.
+    fn transitions(
+        client: &ClientState,
+        transaction: &TransactionState,
+        event: DisputeEvent,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Result<(&'static Transition<TransactionStatus, DisputeEvent>, bool), TransactionError>
+    {
+        let transition =
+            dispute_transition(transaction.status(), event, client_id, transaction_id)?;
+        let account = account_transition(
+            AccountStatus::from_frozen(client.frozen),
+            event.into(),
+            client_id,
+        )?;
+
+        Ok((transition, account.to == AccountStatus::Frozen))
+    }
+
     /// Used as a pre-flight check before processing deposit/withdrawal. If the transaction was
     /// already recorded it returns [`TransactionError::DuplicateTransaction`]
     pub fn ensure_transaction_uniqe(
@@ -79,6 +745,11 @@ impl InMemoryTransactionDb {
         if self
             .transaction_history
             .contains_key(&(client_id, transaction_id))
+            || self.cold_history.contains(client_id, transaction_id)
+            || self
+                .archives
+                .iter()
+                .any(|archive| archive.contains(client_id, transaction_id))
         {
             Err(TransactionError::DuplicateTransaction {
                 client_id,
@@ -88,9 +759,36 @@ impl InMemoryTransactionDb {
             Ok(())
         }
     }
+
+    /// Used as a pre-flight check before recording a transaction. If it would take the DB
+    /// over its memory limit, returns [`TransactionError::CapacityExceeded`]
+    fn ensure_capacity(&self, client_id: ClientId) -> Result<(), TransactionError> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+
+        let mut needed = TRANSACTION_SIZE;
+        if !self.clients.contains_key(&client_id) {
+            needed += CLIENT_SIZE;
+        }
+
+        if self.memory_usage() + needed > limit {
+            Err(TransactionError::CapacityExceeded { limit })
+        } else {
+            Ok(())
+        }
+    }
 }
 
-impl TransactionProcessor for InMemoryTransactionDb {
+impl<C: Clock> TransactionProcessor for InMemoryTransactionDb<C> {
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.clock.follow(timestamp);
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
     fn deposit(
         &mut self,
         transaction_id: TransactionId,
@@ -98,22 +796,23 @@ impl TransactionProcessor for InMemoryTransactionDb {
         amount: Decimal,
     ) -> Result<(), TransactionError> {
         self.ensure_transaction_uniqe(transaction_id, client_id)?;
+        self.ensure_not_closed(client_id)?;
+        // Checked before the client is created, so a rejected deposit doesn't leave one
+        let frozen = self
+            .clients
+            .get(&client_id)
+            .is_some_and(|client| client.frozen);
+        let transition = account_transition(
+            AccountStatus::from_frozen(frozen),
+            AccountEvent::Deposit,
+            client_id,
+        )?;
+        self.ensure_capacity(client_id)?;
 
         let client = self.clients.entry(client_id).or_default();
-
-        if client.frozen {
-            return Err(TransactionError::AccountFrozen { client_id });
-        }
-
-        self.transaction_history.insert(
-            (client_id, transaction_id),
-            TransactionState {
-                amount,
-                disputed: false,
-            },
-        );
-
         client.available += amount;
+        client.frozen = transition.to == AccountStatus::Frozen;
+        self.record_transaction(transaction_id, client_id, amount);
 
         Ok(())
     }
@@ -125,17 +824,24 @@ impl TransactionProcessor for InMemoryTransactionDb {
         amount: Decimal,
     ) -> Result<(), TransactionError> {
         self.ensure_transaction_uniqe(transaction_id, client_id)?;
+        // Withdrawals never create a client, so only their transaction has to fit
+        if !self.clients.contains_key(&client_id) {
+            return Err(TransactionError::ClientNotFound { client_id });
+        }
+        self.ensure_capacity(client_id)?;
 
         let client = self
             .clients
             .get_mut(&client_id)
             .ok_or(TransactionError::ClientNotFound { client_id })?;
 
-        if client.frozen {
-            return Err(TransactionError::AccountFrozen { client_id });
-        }
+        let transition = account_transition(
+            AccountStatus::from_frozen(client.frozen),
+            AccountEvent::Withdrawal,
+            client_id,
+        )?;
 
-        if client.available < amount {
+        if transition.is_guarded_by(Guard::SufficientFunds) && client.available < amount {
             return Err(TransactionError::InsufficientFunds {
                 client_id,
                 transaction_id,
@@ -144,15 +850,9 @@ impl TransactionProcessor for InMemoryTransactionDb {
             });
         }
 
-        self.transaction_history.insert(
-            (client_id, transaction_id),
-            TransactionState {
-                amount: -amount,
-                disputed: false,
-            },
-        );
-
         client.available -= amount;
+        client.frozen = transition.to == AccountStatus::Frozen;
+        self.record_transaction(transaction_id, client_id, -amount);
 
         Ok(())
     }
@@ -162,6 +862,8 @@ impl TransactionProcessor for InMemoryTransactionDb {
         transaction_id: TransactionId,
         client_id: ClientId,
     ) -> Result<(), TransactionError> {
+        self.load_archived(client_id, transaction_id)?;
+
         let client = self
             .clients
             .get_mut(&client_id)
@@ -175,16 +877,39 @@ impl TransactionProcessor for InMemoryTransactionDb {
                 transaction_id,
             })?;
 
-        if transaction.disputed {
-            return Err(TransactionError::AlreadyDisputed {
+        let (transition, frozen) = Self::transitions(
+            client,
+            transaction,
+            DisputeEvent::Dispute,
+            client_id,
+            transaction_id,
+        )?;
+
+        if transition.is_guarded_by(Guard::WithinDisputeWindow)
+            && let Some(window) = self.dispute_window
+            && self.clock.now().saturating_sub(transaction.created_at) > window
+        {
+            return Err(TransactionError::DisputeWindowExpired {
+                client_id,
+                transaction_id,
+            });
+        }
+
+        if transition.is_guarded_by(Guard::BelowDisputeLimit)
+            && !self.max_disputes.allows(transaction.disputes)
+        {
+            return Err(TransactionError::DisputeLimitReached {
                 client_id,
                 transaction_id,
             });
         }
 
-        transaction.disputed = true;
+        transaction.disputes += 1;
+        transaction.set_status(transition.to);
+        client.frozen = frozen;
         client.available -= transaction.amount;
         client.held += transaction.amount;
+        self.seq += 1;
 
         Ok(())
     }
@@ -194,6 +919,10 @@ impl TransactionProcessor for InMemoryTransactionDb {
         transaction_id: TransactionId,
         client_id: ClientId,
     ) -> Result<(), TransactionError> {
+        // Charged back transactions may have been compressed or archived, they're loaded
+        // back to be rejected as such
+        self.load_archived(client_id, transaction_id)?;
+
         let client = self
             .clients
             .get_mut(&client_id)
@@ -207,16 +936,19 @@ impl TransactionProcessor for InMemoryTransactionDb {
                 transaction_id,
             })?;
 
-        if !transaction.disputed {
-            return Err(TransactionError::NotDisputed {
-                client_id,
-                transaction_id,
-            });
-        }
+        let (transition, frozen) = Self::transitions(
+            client,
+            transaction,
+            DisputeEvent::Resolve,
+            client_id,
+            transaction_id,
+        )?;
 
-        transaction.disputed = false;
+        transaction.set_status(transition.to);
+        client.frozen = frozen;
         client.available += transaction.amount;
         client.held -= transaction.amount;
+        self.seq += 1;
 
         Ok(())
     }
@@ -226,6 +958,8 @@ impl TransactionProcessor for InMemoryTransactionDb {
         transaction_id: TransactionId,
         client_id: ClientId,
     ) -> Result<(), TransactionError> {
+        self.load_archived(client_id, transaction_id)?;
+
         let client = self
             .clients
             .get_mut(&client_id)
@@ -239,27 +973,148 @@ impl TransactionProcessor for InMemoryTransactionDb {
                 transaction_id,
             })?;
 
-        if !transaction.disputed {
-            return Err(TransactionError::NotDisputed {
-                client_id,
-                transaction_id,
-            });
-        }
+        let (transition, frozen) = Self::transitions(
+            client,
+            transaction,
+            DisputeEvent::Chargeback,
+            client_id,
+            transaction_id,
+        )?;
 
+        transaction.set_status(transition.to);
+        client.frozen = frozen;
         client.held -= transaction.amount;
-        client.frozen = true;
+        self.seq += 1;
 
         Ok(())
     }
 
     fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
-        self.clients.iter().map(|(&id, client)| ClientInformation {
-            id,
-            available: client.available(),
-            held: client.held(),
-            total: client.total(),
-            frozen: client.frozen(),
-        })
+        self.clients
+            .iter()
+            .map(|(&id, client)| client.information(id))
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.information(client_id))
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        Some(self.freeze())
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        let account = self
+            .closed_account(client_id)
+            .map_err(|err| TransactionError::Storage(format!("{err:#}")))?;
+
+        Ok(account.map(|account| ClientInformation {
+            id: client_id,
+            available: account.client.available,
+            held: account.client.held,
+            total: account.client.available + account.client.held,
+            frozen: account.client.frozen,
+        }))
+    }
+}
+
+impl<C: Clock> StateStore for InMemoryTransactionDb<C> {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        let mut snapshot = Snapshot {
+            clients: self
+                .clients
+                .iter()
+                .map(|(&id, client)| ClientSnapshot {
+                    id,
+                    available: client.available,
+                    held: client.held,
+                    frozen: client.frozen,
+                })
+                .collect(),
+            transactions: self
+                .transaction_history
+                .iter()
+                .map(|(&(client, tx), transaction)| TransactionSnapshot {
+                    client,
+                    tx,
+                    amount: transaction.amount,
+                    disputed: transaction.disputed,
+                    charged_back: transaction.charged_back,
+                    seq: transaction.seq,
+                    disputes: transaction.disputes,
+                    recorded_at: transaction.recorded_at,
+                    created_at: Some(transaction.created_at),
+                })
+                .chain(
+                    self.cold_history
+                        .iter()
+                        .map(|transaction| TransactionSnapshot {
+                            client: transaction.client,
+                            tx: transaction.tx,
+                            amount: transaction.amount,
+                            disputed: false,
+                            charged_back: transaction.charged_back,
+                            seq: transaction.seq,
+                            disputes: transaction.disputes,
+                            recorded_at: transaction.recorded_at,
+                            created_at: Some(transaction.created_at),
+                        }),
+                )
+                .collect(),
+            seq: self.seq,
+            recorded: self.recorded,
+            cursor: None,
+            offsets: Default::default(),
+        };
+        snapshot.normalize();
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.clients = snapshot
+            .clients
+            .into_iter()
+            .map(|client| {
+                let state = ClientState {
+                    available: client.available,
+                    held: client.held,
+                    frozen: client.frozen,
+                };
+                (client.id, state)
+            })
+            .collect();
+
+        // Transactions keep their age, so compaction and dispute windows carry on like they
+        // would have without the snapshot. Snapshots of backends which don't keep the time
+        // transactions were recorded at start their dispute window over
+        self.recorded = snapshot.recorded;
+        self.seq = snapshot.seq;
+        self.cold_history.clear();
+        let now = self.clock.now();
+        self.transaction_history = snapshot
+            .transactions
+            .into_iter()
+            .map(|transaction| {
+                let state = TransactionState {
+                    amount: transaction.amount,
+                    disputed: transaction.disputed,
+                    charged_back: transaction.charged_back,
+                    recorded_at: transaction.recorded_at,
+                    created_at: transaction.created_at.unwrap_or(now),
+                    seq: transaction.seq,
+                    disputes: transaction.disputes,
+                };
+                ((transaction.client, transaction.tx), state)
+            })
+            .collect();
+
+        Ok(())
     }
 }
 
@@ -268,6 +1123,7 @@ mod test {
     use rust_decimal::dec;
 
     use super::*;
+    use crate::clock::SimulatedClock;
 
     #[test]
     fn deposit() {
@@ -487,6 +1343,237 @@ mod test {
         assert_eq!(res, Err(TransactionError::AccountFrozen { client_id: 1 }));
     }
 
+    #[test]
+    fn unlock_and_adjust() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        assert_eq!(
+            db.unlock(1),
+            Err(TransactionError::NotFrozen { client_id: 1 })
+        );
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+
+        // Adjustments go through on frozen accounts
+        db.adjust(2, 1, dec!(-2.5)).unwrap();
+        assert_eq!(db.client(1).unwrap().available, dec!(-2.5));
+        assert_eq!(
+            db.adjust(2, 1, dec!(1)),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 2
+            })
+        );
+
+        db.unlock(1).unwrap();
+        assert!(!db.client(1).unwrap().frozen);
+        db.deposit(3, 1, dec!(5)).unwrap();
+        assert_eq!(
+            db.unlock(2),
+            Err(TransactionError::ClientNotFound { client_id: 2 })
+        );
+    }
+
+    #[test]
+    fn archive_and_dispute_archived() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.jsonl.gz");
+
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.deposit(3, 2, dec!(7)).unwrap();
+        db.dispute(2, 1).unwrap();
+
+        // tx 2 is disputed, so only tx 1 goes to the archive
+        let archive = db.archive(3, &path).unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(db.transaction_count(), 2);
+
+        db.dispute(1, 1).unwrap();
+        assert_eq!(db.transaction_count(), 3);
+        let client = db.client(1).unwrap();
+        assert_eq!(client.available, dec!(0));
+        assert_eq!(client.held, dec!(15));
+
+        // Archives can be attached to another DB
+        let mut restored = InMemoryTransactionDb::new();
+        restored.deposit(9, 1, dec!(10)).unwrap();
+        restored.attach_archive(TransactionArchive::open(&path).unwrap());
+        restored.dispute(1, 1).unwrap();
+        assert_eq!(restored.client(1).unwrap().held, dec!(10));
+        assert_eq!(
+            restored.dispute(4, 1),
+            Err(TransactionError::TransactionNotFound {
+                client_id: 1,
+                transaction_id: 4
+            })
+        );
+    }
+
+    #[test]
+    fn redispute_after_resolve() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+
+        for _ in 0..3 {
+            db.dispute(1, 1).unwrap();
+            db.resolve(1, 1).unwrap();
+        }
+        assert_eq!(db.transaction_history.get(&(1, 1)).unwrap().disputes, 3);
+        assert_eq!(db.client(1).unwrap().available, dec!(10));
+    }
+
+    #[test]
+    fn err_dispute_limit_reached() {
+        let mut db = InMemoryTransactionDb::new();
+        db.set_max_dispute_count(MaxDisputeCount::Limited(2));
+        db.deposit(1, 1, dec!(10)).unwrap();
+
+        db.dispute(1, 1).unwrap();
+        db.resolve(1, 1).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.resolve(1, 1).unwrap();
+        assert_eq!(
+            db.dispute(1, 1),
+            Err(TransactionError::DisputeLimitReached {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+
+        // The count survives a snapshot
+        let mut restored = InMemoryTransactionDb::new();
+        restored.set_max_dispute_count(MaxDisputeCount::Limited(2));
+        restored.restore(db.snapshot().unwrap()).unwrap();
+        assert!(restored.dispute(1, 1).is_err());
+        assert_eq!(restored.client(1).unwrap().held, dec!(0));
+    }
+
+    #[test]
+    fn err_capacity_exceeded() {
+        let mut db = InMemoryTransactionDb::with_memory_limit(CLIENT_SIZE + 2 * TRANSACTION_SIZE);
+        db.deposit(1, 1, dec!(10)).unwrap();
+        // Unknown clients aren't charged for a client they wouldn't create
+        assert_eq!(
+            db.withdrawal(9, 2, dec!(1)),
+            Err(TransactionError::ClientNotFound { client_id: 2 })
+        );
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        assert_eq!(
+            db.deposit(3, 1, dec!(1)),
+            Err(TransactionError::CapacityExceeded {
+                limit: CLIENT_SIZE + 2 * TRANSACTION_SIZE
+            })
+        );
+
+        // Disputes don't record anything, so they still go through
+        db.dispute(1, 1).unwrap();
+        assert_eq!(db.client(1).unwrap().held, dec!(10));
+
+        db.set_compaction_policy(CompactionPolicy {
+            dispute_horizon: Some(0),
+            ..Default::default()
+        });
+        db.compact();
+        db.deposit(3, 1, dec!(1)).unwrap();
+
+        // Deposits which would be rejected anyway aren't reported as over capacity
+        let dir = tempfile::tempdir().unwrap();
+        let limit = 2 * CLIENT_SIZE + 2 * TRANSACTION_SIZE;
+        let mut db = InMemoryTransactionDb::with_memory_limit(limit);
+        db.set_account_archive(AccountArchive::open(dir.path()).unwrap());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db.deposit(2, 2, dec!(5)).unwrap();
+        db.close_account(2).unwrap();
+        db.deposit(3, 3, dec!(5)).unwrap();
+        assert_eq!(
+            db.deposit(4, 4, dec!(1)),
+            Err(TransactionError::CapacityExceeded { limit })
+        );
+
+        assert_eq!(
+            db.deposit(4, 2, dec!(1)),
+            Err(TransactionError::AccountClosed { client_id: 2 })
+        );
+        assert_eq!(
+            db.deposit(4, 1, dec!(1)),
+            Err(TransactionError::AccountFrozen { client_id: 1 })
+        );
+    }
+
+    #[test]
+    fn close_account() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut db = InMemoryTransactionDb::new();
+        db.set_account_archive(AccountArchive::open(dir.path()).unwrap());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.deposit(3, 2, dec!(7)).unwrap();
+        db.dispute(2, 1).unwrap();
+
+        assert_eq!(
+            db.close_account(1),
+            Err(TransactionError::FundsHeld { client_id: 1 })
+        );
+        db.resolve(2, 1).unwrap();
+        db.close_account(1).unwrap();
+
+        assert!(db.client(1).is_none());
+        assert_eq!(db.clients_iter().count(), 1);
+        assert_eq!(db.transaction_count(), 1);
+
+        let closed = db.closed_account(1).unwrap().unwrap();
+        assert_eq!(closed.client.available, dec!(15));
+        assert_eq!(
+            closed.transactions.iter().map(|t| t.tx).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(closed.transactions[1].disputes, 1);
+        assert!(db.closed_account(2).unwrap().is_none());
+
+        assert_eq!(
+            db.deposit(4, 1, dec!(1)),
+            Err(TransactionError::AccountClosed { client_id: 1 })
+        );
+        assert_eq!(
+            db.dispute(1, 1),
+            Err(TransactionError::ClientNotFound { client_id: 1 })
+        );
+    }
+
+    #[test]
+    fn err_already_charged_back() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+
+        // Charging back twice would take the amount off the held balance again
+        assert_eq!(
+            db.chargeback(1, 1),
+            Err(TransactionError::AlreadyChargedBack {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+        // Resolving it would make the charged back amount available again
+        for result in [db.resolve(1, 1), db.dispute(1, 1)] {
+            assert_eq!(
+                result,
+                Err(TransactionError::AlreadyChargedBack {
+                    client_id: 1,
+                    transaction_id: 1
+                })
+            );
+        }
+        let client = db.client(1).unwrap();
+        assert_eq!((client.available, client.held), (dec!(0), dec!(0)));
+    }
+
     #[test]
     fn total() {
         let mut db = InMemoryTransactionDb::new();
@@ -503,4 +1590,324 @@ mod test {
         let client_2 = db.clients.get(&2).unwrap();
         assert_eq!(client_2.total(), dec!(15));
     }
+
+    #[test]
+    fn compact_charged_back() {
+        let mut db = InMemoryTransactionDb::with_compaction_policy(CompactionPolicy {
+            drop_charged_back: true,
+            ..Default::default()
+        });
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.dispute(2, 1).unwrap();
+        assert_eq!(db.compact(), 0);
+
+        db.chargeback(2, 1).unwrap();
+        assert_eq!(db.compact(), 1);
+        assert_eq!(db.transaction_count(), 1);
+        assert_eq!(
+            db.dispute(2, 1),
+            Err(TransactionError::TransactionNotFound {
+                client_id: 1,
+                transaction_id: 2
+            })
+        );
+    }
+
+    #[test]
+    fn compact_dispute_horizon() {
+        let mut db = InMemoryTransactionDb::with_compaction_policy(CompactionPolicy {
+            dispute_horizon: Some(2),
+            every: NonZeroU64::new(1),
+            ..Default::default()
+        });
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(10)).unwrap();
+        db.dispute(2, 1).unwrap();
+        db.deposit(3, 1, dec!(10)).unwrap();
+        db.deposit(4, 1, dec!(10)).unwrap();
+
+        // tx 1 is past the horizon, tx 2 is too but is still disputed
+        assert!(!db.transaction_history.contains_key(&(1, 1)));
+        assert!(db.transaction_history.contains_key(&(1, 2)));
+        assert!(db.transaction_history.contains_key(&(1, 3)));
+        assert!(db.transaction_history.contains_key(&(1, 4)));
+
+        db.resolve(2, 1).unwrap();
+        db.deposit(5, 1, dec!(10)).unwrap();
+        assert!(!db.transaction_history.contains_key(&(1, 2)));
+
+        let client_1 = db.clients.get(&1).unwrap();
+        assert_eq!(client_1.available, dec!(50));
+    }
+
+    #[test]
+    fn archive_rejects_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.jsonl.gz");
+
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.archive(2, &path).unwrap();
+        assert_eq!(db.transaction_count(), 0);
+
+        let duplicate = Err(TransactionError::DuplicateTransaction {
+            client_id: 1,
+            transaction_id: 1,
+        });
+        assert_eq!(db.deposit(1, 1, dec!(10)), duplicate);
+        assert_eq!(db.withdrawal(1, 1, dec!(1)), duplicate);
+        assert_eq!(db.client(1).unwrap().available, dec!(10));
+
+        // Reopened archives index their ids too
+        let mut restored = InMemoryTransactionDb::new();
+        restored.attach_archive(TransactionArchive::open(&path).unwrap());
+        assert_eq!(restored.deposit(1, 1, dec!(10)), duplicate);
+        restored.deposit(1, 2, dec!(10)).unwrap();
+    }
+
+    #[test]
+    fn archive_charged_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.jsonl.gz");
+
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+
+        // Charged back transactions are final, so they're archived
+        assert_eq!(db.archive(2, &path).unwrap().len(), 1);
+        assert_eq!(db.transaction_count(), 0);
+        assert_eq!(
+            db.dispute(1, 1),
+            Err(TransactionError::AlreadyChargedBack {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn compress_charged_back() {
+        let mut db = InMemoryTransactionDb::with_compaction_policy(CompactionPolicy {
+            compress_after: Some(1),
+            ..Default::default()
+        });
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 2, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.dispute(2, 2).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db.deposit(3, 3, dec!(5)).unwrap();
+        db.compact();
+
+        // tx 2 is still disputed, tx 1 was charged back and is final
+        assert_eq!(db.compressed_transaction_count(), 1);
+        assert!(db.cold_history.contains(1, 1));
+        assert_eq!(
+            db.resolve(1, 1),
+            Err(TransactionError::AlreadyChargedBack {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn compress_cold_history() {
+        let mut db = InMemoryTransactionDb::with_compaction_policy(CompactionPolicy {
+            compress_after: Some(1),
+            every: NonZeroU64::new(1),
+            ..Default::default()
+        });
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(10)).unwrap();
+        db.deposit(3, 2, dec!(5)).unwrap();
+
+        assert_eq!(db.compressed_transaction_count(), 2);
+        assert_eq!(db.transaction_count(), 3);
+        assert_eq!(db.snapshot().unwrap().transactions.len(), 3);
+        assert_eq!(
+            db.deposit(1, 1, dec!(1)),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+
+        // Disputing a compressed transaction decompresses its client's block
+        db.dispute(1, 1).unwrap();
+        assert_eq!(db.compressed_transaction_count(), 0);
+        assert_eq!(db.client(1).unwrap().held, dec!(10));
+
+        // The disputed transaction stays uncompressed
+        db.deposit(4, 2, dec!(5)).unwrap();
+        assert_eq!(db.compressed_transaction_count(), 2);
+        db.resolve(1, 1).unwrap();
+        assert_eq!(db.client(1).unwrap().available, dec!(20));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.deposit(3, 2, dec!(5)).unwrap();
+        db.dispute(3, 2).unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(snapshot.clients.len(), 2);
+        assert_eq!(snapshot.transactions.len(), 3);
+
+        let mut restored = InMemoryTransactionDb::new();
+        restored.restore(snapshot.clone()).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+        // The restored DB keeps processing like the original would
+        restored.resolve(3, 2).unwrap();
+        assert_eq!(restored.clients.get(&2).unwrap().available, dec!(5));
+    }
+
+    #[test]
+    fn err_dispute_window_expired() {
+        let clock = SimulatedClock::new(1_000);
+        let mut db = InMemoryTransactionDb::with_clock(clock.clone());
+        db.set_dispute_window(Some(60));
+
+        db.deposit(1, 1, dec!(10)).unwrap();
+        clock.advance(30);
+        db.deposit(2, 1, dec!(10)).unwrap();
+
+        clock.advance(60);
+        assert_eq!(
+            db.dispute(1, 1),
+            Err(TransactionError::DisputeWindowExpired {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+        db.dispute(2, 1).unwrap();
+
+        let client_1 = db.clients.get(&1).unwrap();
+        assert_eq!(client_1.available, dec!(10));
+        assert_eq!(client_1.held, dec!(10));
+    }
+
+    #[test]
+    fn restore_keeps_dispute_window() {
+        let clock = SimulatedClock::new(1_000);
+        let mut db = InMemoryTransactionDb::with_clock(clock.clone());
+        db.set_dispute_window(Some(60));
+        db.deposit(1, 1, dec!(10)).unwrap();
+        clock.advance(90);
+        db.deposit(2, 1, dec!(10)).unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(snapshot.transactions[0].created_at, Some(1_000));
+        assert_eq!(snapshot.recorded, 2);
+
+        let mut restored = InMemoryTransactionDb::with_clock(clock.clone());
+        restored.set_dispute_window(Some(60));
+        restored
+            .restore(serde_json::from_value(serde_json::to_value(&snapshot).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+        // Transaction 1 had already left the window before the snapshot
+        assert_eq!(
+            restored.dispute(1, 1),
+            Err(TransactionError::DisputeWindowExpired {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+        restored.dispute(2, 1).unwrap();
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(20)).unwrap_err();
+        db.withdrawal(3, 1, dec!(5)).unwrap();
+        db.dispute(1, 1).unwrap();
+        assert_eq!(db.last_seq(), 3);
+        assert_eq!(db.transaction_history[&(1, 1)].seq, 1);
+        assert_eq!(db.transaction_history[&(1, 3)].seq, 2);
+
+        // Numbering continues after a restore
+        let mut restored = InMemoryTransactionDb::new();
+        restored.restore(db.snapshot().unwrap()).unwrap();
+        restored.resolve(1, 1).unwrap();
+        assert_eq!(restored.last_seq(), 4);
+        assert_eq!(restored.snapshot().unwrap().transactions[1].seq, 2);
+    }
+
+    #[test]
+    fn merge_shards() {
+        let mut shard_1 = InMemoryTransactionDb::new();
+        shard_1.deposit(1, 1, dec!(10)).unwrap();
+        shard_1.deposit(2, 2, dec!(5)).unwrap();
+
+        let mut shard_2 = InMemoryTransactionDb::new();
+        shard_2.deposit(3, 2, dec!(7)).unwrap();
+        shard_2.dispute(3, 2).unwrap();
+        shard_2.deposit(4, 3, dec!(1)).unwrap();
+
+        shard_1.merge(shard_2).unwrap();
+        assert_eq!(shard_1.last_seq(), 5);
+        assert_eq!(shard_1.transaction_history[&(2, 3)].seq, 3);
+
+        let client_2 = shard_1.clients.get(&2).unwrap();
+        assert_eq!(client_2.available, dec!(5));
+        assert_eq!(client_2.held, dec!(7));
+        assert_eq!(shard_1.clients.len(), 3);
+
+        // The merged DB keeps processing like a single one would
+        shard_1.resolve(3, 2).unwrap();
+        assert_eq!(shard_1.clients.get(&2).unwrap().available, dec!(12));
+    }
+
+    #[test]
+    fn err_merge_conflicting_transaction() {
+        let mut shard_1 = InMemoryTransactionDb::new();
+        shard_1.deposit(1, 1, dec!(10)).unwrap();
+
+        let mut shard_2 = InMemoryTransactionDb::new();
+        shard_2.deposit(2, 2, dec!(3)).unwrap();
+        shard_2.deposit(1, 1, dec!(5)).unwrap();
+
+        assert_eq!(
+            shard_1.merge(shard_2),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+        assert_eq!(shard_1.clients.len(), 1);
+        assert_eq!(shard_1.clients.get(&1).unwrap().available, dec!(10));
+    }
+
+    #[test]
+    fn freeze_is_isolated_from_live_state() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        let frozen = db.freeze();
+
+        let reader = {
+            let frozen = frozen.clone();
+            std::thread::spawn(move || frozen.clients_iter().map(|c| c.available).sum())
+        };
+
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.deposit(3, 2, dec!(1)).unwrap();
+
+        let total: Decimal = reader.join().unwrap();
+        assert_eq!(total, dec!(10));
+        assert_eq!(frozen.seq(), 1);
+        assert_eq!(frozen.len(), 1);
+        assert_eq!(frozen.client(1).unwrap().available, dec!(10));
+        assert_eq!(db.freeze().client(1).unwrap().available, dec!(15));
+    }
 }
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fmt::Write,
+    time::Duration,
+};
+
+use crate::{
+    clock::Timestamp,
+    i18n::Localize,
+    tenant::TenantId,
+    transaction::{ClientId, EventKind, TransactionError},
+};
+
+/// Upper bounds of the histogram buckets, in nanoseconds. Observations above the last one
+/// go in an overflow (`+Inf`) bucket.
+const BUCKETS: [u64; 12] = [
+    1_000,
+    5_000,
+    10_000,
+    25_000,
+    50_000,
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    5_000_000,
+    10_000_000,
+    100_000_000,
+];
+
+/// A latency histogram with fixed buckets, cheap enough to update for every event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// One counter per bucket, plus the overflow bucket. Not cumulative.
+    buckets: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| nanos <= bound as u128)
+            .unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.sum.as_nanos() / count as u128) as u64),
+        }
+    }
+
+    /// Upper bound of the bucket holding the `q`-th quantile (`0.0..=1.0`). Since buckets
+    /// are coarse, this overestimates the real quantile; it's never above the max though.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match BUCKETS.get(bucket) {
+                    Some(&bound) => Duration::from_nanos(bound).min(self.max),
+                    None => self.max,
+                };
+            }
+        }
+
+        self.max
+    }
+}
+
+/// Parse-to-apply latency of events, per event type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventLatencies {
+    histograms: [LatencyHistogram; EventKind::ALL.len()],
+}
+
+impl EventLatencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, kind: EventKind, latency: Duration) {
+        self.histograms[kind as usize].record(latency);
+    }
+
+    pub fn histogram(&self, kind: EventKind) -> &LatencyHistogram {
+        &self.histograms[kind as usize]
+    }
+
+    /// Total number of recorded events, of all types
+    pub fn count(&self) -> u64 {
+        self.histograms.iter().map(LatencyHistogram::count).sum()
+    }
+
+    /// Renders the histograms in the Prometheus text exposition format, as
+    /// `octopussy_event_latency_seconds{type="..."}`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP octopussy_event_latency_seconds Time from parsing an event to applying it"
+        );
+        let _ = writeln!(out, "# TYPE octopussy_event_latency_seconds histogram");
+
+        for kind in EventKind::ALL {
+            let histogram = self.histogram(kind);
+            let kind = kind.as_str();
+
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let le = Duration::from_nanos(*bound).as_secs_f64();
+                let _ = writeln!(
+                    out,
+                    "octopussy_event_latency_seconds_bucket{{type=\"{kind}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "octopussy_event_latency_seconds_bucket{{type=\"{kind}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "octopussy_event_latency_seconds_sum{{type=\"{kind}\"}} {}",
+                histogram.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "octopussy_event_latency_seconds_count{{type=\"{kind}\"}} {}",
+                histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+/// The run summary: one line per event type which was seen.
+impl fmt::Display for EventLatencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for kind in EventKind::ALL {
+            let histogram = self.histogram(kind);
+            if histogram.count == 0 {
+                continue;
+            }
+
+            writeln!(
+                f,
+                "{}: {} events, mean {:?}, p50 <= {:?}, p99 <= {:?}, max {:?}",
+                kind.as_str(),
+                histogram.count,
+                histogram.mean(),
+                histogram.quantile(0.5),
+                histogram.quantile(0.99),
+                histogram.max
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A client of a run, along with the tenant it belongs to (`None` for the default ledger)
+pub type ClientKey = (Option<TenantId>, ClientId);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientCounters {
+    /// Chargebacks applied to the client's transactions
+    pub chargebacks: u64,
+    /// Events of the client which were rejected
+    pub rejections: u64,
+}
+
+/// Per-client counters of a run, feeding the top-N report and the activity heatmap.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientActivity {
+    clients: HashMap<ClientKey, ClientCounters>,
+    /// Number of events of each client per timestamp, for events which had one
+    timeline: HashMap<ClientKey, BTreeMap<Timestamp, u64>>,
+}
+
+impl ClientActivity {
+    pub fn record(&mut self, client: ClientKey, kind: EventKind, applied: bool) {
+        if applied && kind != EventKind::Chargeback {
+            return;
+        }
+
+        let counters = self.clients.entry(client).or_default();
+        if applied {
+            counters.chargebacks += 1;
+        } else {
+            counters.rejections += 1;
+        }
+    }
+
+    /// Records an event carrying a timestamp, whether it was applied or not.
+    pub fn record_at(&mut self, client: ClientKey, timestamp: Timestamp) {
+        *self
+            .timeline
+            .entry(client)
+            .or_default()
+            .entry(timestamp)
+            .or_default() += 1;
+    }
+
+    pub fn counters(&self, client: ClientKey) -> ClientCounters {
+        self.clients.get(&client).copied().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ClientKey, ClientCounters)> + '_ {
+        self.clients
+            .iter()
+            .map(|(&client, &counters)| (client, counters))
+    }
+
+    /// The number of events of each client per timestamp, see [`Self::record_at`].
+    pub fn timeline(&self) -> impl Iterator<Item = (ClientKey, &BTreeMap<Timestamp, u64>)> + '_ {
+        self.timeline
+            .iter()
+            .map(|(&client, events)| (client, events))
+    }
+}
+
+/// Everything measured while processing an input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunMetrics {
+    pub latencies: EventLatencies,
+    pub activity: ClientActivity,
+    /// Rows processed before the run was drained, if it was. Checkpointed runs don't write
+    /// the clients then, they're resumed from their checkpoint.
+    pub drained_at: Option<u64>,
+    /// Sequence of the last row processed, from the input's optional `sequence` column
+    pub last_sequence: Option<u64>,
+    /// Rejected events by why they were, the message id of their error (eg
+    /// `insufficient-funds`)
+    pub rejections: BTreeMap<&'static str, u64>,
+}
+
+impl RunMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an event which was either applied, or rejected by the processor.
+    pub fn record(
+        &mut self,
+        client: ClientKey,
+        kind: EventKind,
+        latency: Duration,
+        outcome: &Result<(), TransactionError>,
+    ) {
+        self.latencies.record(kind, latency);
+        self.activity.record(client, kind, outcome.is_ok());
+        if let Err(err) = outcome {
+            *self.rejections.entry(err.message().id()).or_default() += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        for micros in [2, 3, 4, 7, 200] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.mean(), Duration::from_nanos(43_200));
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(5));
+        assert_eq!(histogram.quantile(0.8), Duration::from_micros(10));
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(200));
+    }
+
+    #[test]
+    fn prometheus() {
+        let mut latencies = EventLatencies::new();
+        latencies.record(EventKind::Deposit, Duration::from_micros(3));
+        latencies.record(EventKind::Deposit, Duration::from_millis(500));
+
+        let text = latencies.to_prometheus();
+        assert!(text.contains(
+            "octopussy_event_latency_seconds_bucket{type=\"deposit\",le=\"0.000005\"} 1\n"
+        ));
+        assert!(
+            text.contains(
+                "octopussy_event_latency_seconds_bucket{type=\"deposit\",le=\"+Inf\"} 2\n"
+            )
+        );
+        assert!(text.contains("octopussy_event_latency_seconds_count{type=\"dispute\"} 0\n"));
+    }
+}
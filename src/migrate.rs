@@ -0,0 +1,100 @@
+use tracing::info;
+
+use crate::snapshot::{SnapshotTotals, StateStore};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum MigrationError {
+    #[error(
+        "destination already holds {clients} clients and {transactions} transactions, refusing to overwrite"
+    )]
+    DestinationNotEmpty { clients: usize, transactions: usize },
+
+    #[error("verification failed: expected {expected:?}, destination has {actual:?}")]
+    VerificationFailed {
+        expected: SnapshotTotals,
+        actual: SnapshotTotals,
+    },
+}
+
+/// Copies the full state of `source` into `destination`.
+///
+/// The destination must be empty. After the copy, the destination is read back and its
+/// client/transaction counts and balance totals are compared against the source.
+///
+/// ## Errors
+/// - If the destination has state, returns [`MigrationError::DestinationNotEmpty`]
+/// - If the copy doesn't match the source, returns [`MigrationError::VerificationFailed`]
+pub fn migrate<S, D>(source: &S, destination: &mut D) -> anyhow::Result<SnapshotTotals>
+where
+    S: StateStore + ?Sized,
+    D: StateStore + ?Sized,
+{
+    let existing = destination.snapshot()?;
+    if !existing.is_empty() {
+        let totals = existing.totals();
+        return Err(MigrationError::DestinationNotEmpty {
+            clients: totals.clients,
+            transactions: totals.transactions,
+        }
+        .into());
+    }
+
+    let snapshot = source.snapshot()?;
+    let expected = snapshot.totals();
+    info!("Migrating {expected:?}");
+
+    destination.restore(snapshot)?;
+
+    let actual = destination.snapshot()?.totals();
+    if actual != expected {
+        return Err(MigrationError::VerificationFailed { expected, actual }.into());
+    }
+
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{memory_processor::InMemoryTransactionDb, transaction::TransactionProcessor};
+
+    #[test]
+    fn migrate_between_memory_dbs() {
+        let mut source = InMemoryTransactionDb::new();
+        source.deposit(1, 1, dec!(10)).unwrap();
+        source.deposit(2, 2, dec!(4)).unwrap();
+        source.dispute(2, 2).unwrap();
+
+        let mut destination = InMemoryTransactionDb::new();
+        let totals = migrate(&source, &mut destination).unwrap();
+
+        assert_eq!(
+            totals,
+            SnapshotTotals {
+                clients: 2,
+                transactions: 2,
+                available: dec!(10),
+                held: dec!(4),
+            }
+        );
+        assert_eq!(source.snapshot().unwrap(), destination.snapshot().unwrap());
+    }
+
+    #[test]
+    fn err_destination_not_empty() {
+        let source = InMemoryTransactionDb::new();
+        let mut destination = InMemoryTransactionDb::new();
+        destination.deposit(1, 1, dec!(1)).unwrap();
+
+        let err = migrate(&source, &mut destination).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MigrationError>(),
+            Some(&MigrationError::DestinationNotEmpty {
+                clients: 1,
+                transactions: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::{io::BufRead, time::Instant};
+
+use anyhow::Context;
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// Same as [`crate::csv::csv_processor`], but reads a stream of concatenated MessagePack
+/// events.
+///
+/// Events are maps with the same keys as the CSV columns, or arrays of the values in the
+/// column order (`type`, `client`, `tx`, `amount`, then optionally `tenant` and
+/// `timestamp`), which is how `rmp_serde` encodes structs by default. Amounts can be strings
+/// or floats.
+pub fn msgpack_processor<R, O, DB>(reader: R, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    msgpack_processor_draining(reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`msgpack_processor`], but stops at the first event after `drain` was requested
+/// and writes the clients as they are then.
+pub fn msgpack_processor_draining<R, O, DB>(
+    mut reader: R,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    let mut number = 0;
+
+    while !reader.fill_buf()?.is_empty() && !drained(drain, &mut metrics) {
+        number += 1;
+        let started = Instant::now();
+        let transaction_row: TransactionRow =
+            rmp_serde::from_read(&mut reader).with_context(|| format!("invalid event {number}"))?;
+        apply_row(db, transaction_row, started, &mut metrics)?;
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+    use serde::Serialize;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    #[derive(Serialize)]
+    struct Event<'a> {
+        r#type: &'a str,
+        client: u16,
+        tx: u32,
+        amount: Option<&'a str>,
+    }
+
+    fn process(input: &[u8]) -> anyhow::Result<Vec<ClientRow>> {
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = msgpack_processor(input, csv::Writer::from_writer(&mut output), &mut db)?;
+        assert_eq!(metrics.latencies.count(), 3);
+
+        Ok(csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()?)
+    }
+
+    #[test]
+    fn maps_and_arrays() {
+        let mut input = Vec::new();
+        let deposit = Event {
+            r#type: "deposit",
+            client: 1,
+            tx: 1,
+            amount: Some("2.5"),
+        };
+        input.extend(rmp_serde::to_vec_named(&deposit).unwrap());
+        input.extend(rmp_serde::to_vec(&("deposit", 1, 2, 1.0)).unwrap());
+        let dispute = Event {
+            r#type: "dispute",
+            client: 1,
+            tx: 2,
+            amount: None,
+        };
+        input.extend(rmp_serde::to_vec(&dispute).unwrap());
+
+        assert_eq!(
+            process(&input).unwrap(),
+            vec![ClientRow {
+                client: 1,
+                available: dec!(2.5),
+                held: dec!(1),
+                total: dec!(3.5),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn err_truncated() {
+        let mut input = rmp_serde::to_vec_named(&Event {
+            r#type: "deposit",
+            client: 1,
+            tx: 1,
+            amount: Some("2.5"),
+        })
+        .unwrap();
+        input.pop();
+
+        assert_eq!(process(&input).unwrap_err().to_string(), "invalid event 1");
+    }
+}
@@ -0,0 +1,360 @@
+//! A durable consumer applying the events of a NATS JetStream stream, and a publisher of the
+//! resulting client updates.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, anyhow, bail};
+use async_nats::jetstream::{
+    self, AckKind, ErrorCode,
+    consumer::{AckPolicy, DeliverPolicy, pull},
+    context::PublishAckFuture,
+    stream::ConsumerErrorKind,
+};
+use futures::StreamExt;
+use tokio::runtime::Runtime;
+use tracing::{error, info};
+
+use crate::{
+    cursor::CursorProcessor,
+    drain::DrainSignal,
+    metrics::RunMetrics,
+    publish::Publisher,
+    stream::{Disposition, PayloadFormat, apply_message_at},
+    transaction::{ClientId, TransactionProcessor},
+};
+
+/// Published updates a [`NatsPublisher`] waits the acknowledgements of at once
+const MAX_PENDING_ACKS: usize = 256;
+
+/// Where to consume events from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatsConfig {
+    /// Server URL, eg `nats://localhost:4222`
+    pub server: String,
+    pub stream: String,
+    /// Durable consumer, whose acknowledged messages runs resume after. Created if missing.
+    pub durable: String,
+    /// Only consume the messages of these subjects of the stream, all of them if empty
+    pub subjects: Vec<String>,
+    pub format: PayloadFormat,
+}
+
+impl NatsConfig {
+    /// Config of the durable consumer, delivering the messages after the stream sequence
+    /// `after` if given, and all of them otherwise
+    fn consumer_config(&self, after: Option<u64>) -> pull::Config {
+        let deliver_policy = match after {
+            Some(after) => DeliverPolicy::ByStartSequence {
+                start_sequence: after + 1,
+            },
+            None => DeliverPolicy::All,
+        };
+
+        pull::Config {
+            durable_name: Some(self.durable.clone()),
+            ack_policy: AckPolicy::Explicit,
+            deliver_policy,
+            filter_subjects: self.subjects.clone(),
+            ..pull::Config::default()
+        }
+    }
+}
+
+/// Connection to a NATS server, shared by the [`NatsSource`] and [`NatsPublisher`] of a run.
+///
+/// The client is asynchronous, it runs on a runtime of its own so the engine doesn't have to.
+#[derive(Clone)]
+pub struct NatsConnection {
+    runtime: Arc<Runtime>,
+    jetstream: jetstream::Context,
+}
+
+impl NatsConnection {
+    pub fn connect(server: &str) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .context("failed to start the NATS runtime")?;
+        let client = runtime
+            .block_on(async_nats::connect(server))
+            .with_context(|| format!("failed to connect to {server}"))?;
+
+        Ok(Self {
+            runtime: Arc::new(runtime),
+            jetstream: jetstream::new(client),
+        })
+    }
+}
+
+/// Consumes events from a JetStream stream with a durable pull consumer, acknowledging a
+/// message only once its event was applied (or rejected by the rules).
+///
+/// Delivery is at least once: messages which weren't acknowledged, eg because of a crash, are
+/// delivered again once their ack wait expired. Messages are acknowledged before the state
+/// they were applied to is saved though, so a source resuming a saved state rewinds its
+/// consumer to the messages the state doesn't include, see [`NatsSource::connect`].
+/// Messages at or before the stream sequence the state was saved at are acknowledged without
+/// being applied again, see [`apply_message_at`].
+pub struct NatsSource {
+    connection: NatsConnection,
+    messages: pull::Stream,
+    format: PayloadFormat,
+}
+
+impl NatsSource {
+    /// Consumes the stream of `config` where its durable consumer stands, or after the stream
+    /// sequence `resume` if given, the last one the saved state includes (`0` if none).
+    ///
+    /// The durable consumer is then recreated at `resume`: the messages it acknowledged past
+    /// it were applied to a state which wasn't saved, eg because the process was killed.
+    ///
+    /// ## Errors
+    ///
+    /// If the stream doesn't hold the messages right after `resume` anymore, eg because they
+    /// were acknowledged from a work queue stream or aged out: they would be missing from
+    /// the state.
+    pub fn connect(
+        connection: &NatsConnection,
+        config: &NatsConfig,
+        resume: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let messages = connection.runtime.block_on(async {
+            let mut stream = connection
+                .jetstream
+                .get_stream(&config.stream)
+                .await
+                .with_context(|| format!("failed to get the stream {}", config.stream))?;
+            if let Some(after) = resume {
+                let first = stream
+                    .info()
+                    .await
+                    .with_context(|| format!("failed to get the stream {}", config.stream))?
+                    .state
+                    .first_sequence;
+                if after > 0 && first > after + 1 {
+                    bail!(
+                        "the stream {} starts at sequence {first}, the messages after sequence \
+                         {after} the state stops at are gone",
+                        config.stream
+                    );
+                }
+
+                info!("Rewinding {} to sequence {}", config.durable, after + 1);
+                match stream.delete_consumer(&config.durable).await {
+                    Ok(_) => {}
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            ConsumerErrorKind::JetStream(err)
+                                if err.error_code() == ErrorCode::CONSUMER_NOT_FOUND
+                        ) => {}
+                    Err(err) => {
+                        return Err(anyhow!(err))
+                            .with_context(|| format!("failed to rewind {}", config.durable));
+                    }
+                }
+            }
+            let consumer = stream
+                .get_or_create_consumer(&config.durable, config.consumer_config(resume))
+                .await
+                .with_context(|| format!("failed to get the consumer {}", config.durable))?;
+            consumer
+                .messages()
+                .await
+                .with_context(|| format!("failed to consume from {}", config.stream))
+        })?;
+
+        Ok(Self {
+            connection: connection.clone(),
+            messages,
+            format: config.format,
+        })
+    }
+
+    /// Applies the events of the stream to `db` until `drain` is requested.
+    ///
+    /// Messages which don't decode are logged and terminated, so they aren't delivered again.
+    /// A storage error negatively acknowledges its message, so it's delivered again, and stops
+    /// the run. The cursor of `db` is the stream sequence of the last message applied.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut CursorProcessor<DB>,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+        let runtime = Arc::clone(&self.connection.runtime);
+
+        while !drain.is_draining() {
+            let Ok(next) = runtime.block_on(tokio::time::timeout(poll, self.messages.next()))
+            else {
+                continue;
+            };
+            let message = next
+                .context("the consumer was deleted")?
+                .context("failed to consume from NATS")?;
+            let info = message
+                .info()
+                .map_err(|err| anyhow!(err))
+                .with_context(|| format!("failed to read the metadata of {}", message.subject))?;
+            let (sequence, position) = (
+                info.stream_sequence,
+                format!("{} sequence {}", info.stream, info.stream_sequence),
+            );
+
+            let ack =
+                match apply_message_at(db, sequence, self.format, &message.payload, &mut metrics) {
+                    Disposition::Done => AckKind::Ack,
+                    Disposition::Invalid(err) => {
+                        error!("Skipping {position}: {err:#}");
+                        AckKind::Term
+                    }
+                    Disposition::Retry(err) => {
+                        runtime
+                            .block_on(message.ack_with(AckKind::Nak(None)))
+                            .map_err(|err| anyhow!(err))
+                            .with_context(|| format!("failed to reject {position}"))?;
+                        return Err(err.context(format!("failed to apply {position}")));
+                    }
+                };
+            runtime
+                .block_on(message.ack_with(ack))
+                .map_err(|err| anyhow!(err))
+                .with_context(|| format!("failed to acknowledge {position}"))?;
+        }
+        info!("Draining, unacknowledged messages will be delivered again");
+
+        Ok(metrics)
+    }
+}
+
+/// Publishes client updates to JetStream, on `<subject>.<client>` so subscribers can pick the
+/// clients they follow. The stream capturing them has to bind `<subject>.>`.
+///
+/// Acknowledgements of the stream are awaited in batches, a failed publish is returned by the
+/// next [`Publisher::publish`] or [`Publisher::flush`].
+pub struct NatsPublisher {
+    connection: NatsConnection,
+    subject: String,
+    pending: Vec<PublishAckFuture>,
+}
+
+impl NatsPublisher {
+    pub fn new(connection: &NatsConnection, subject: &str) -> Self {
+        Self {
+            connection: connection.clone(),
+            subject: subject.to_owned(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Publisher for NatsPublisher {
+    fn publish(&mut self, client: ClientId, payload: &[u8]) -> anyhow::Result<()> {
+        if self.pending.len() >= MAX_PENDING_ACKS {
+            self.flush()?;
+        }
+
+        let subject = format!("{}.{client}", self.subject);
+        let ack = self
+            .connection
+            .runtime
+            .block_on(
+                self.connection
+                    .jetstream
+                    .publish(subject.clone(), payload.to_vec().into()),
+            )
+            .with_context(|| format!("failed to publish to {subject}"))?;
+        self.pending.push(ack);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for ack in self.pending.drain(..) {
+            self.connection
+                .runtime
+                .block_on(ack.into_future())
+                .with_context(|| format!("failed to publish to {}", self.subject))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        cursor::OffsetProcessor, memory_processor::InMemoryTransactionDb, snapshot::StateStore,
+    };
+
+    fn config() -> NatsConfig {
+        NatsConfig {
+            server: "nats://localhost:4222".to_owned(),
+            stream: "TRANSACTIONS".to_owned(),
+            durable: "octopussy".to_owned(),
+            subjects: vec!["transactions.eu".to_owned()],
+            format: PayloadFormat::Json,
+        }
+    }
+
+    #[test]
+    fn durable_consumer() {
+        let config = config().consumer_config(None);
+
+        assert_eq!(config.durable_name.as_deref(), Some("octopussy"));
+        assert_eq!(config.ack_policy, AckPolicy::Explicit);
+        assert_eq!(config.deliver_policy, DeliverPolicy::All);
+        assert_eq!(config.filter_subjects, ["transactions.eu"]);
+    }
+
+    #[test]
+    fn resuming_redelivers_what_the_state_lost() {
+        let stream: Vec<_> = (1..=4)
+            .map(|tx| format!(r#"{{"type":"deposit","client":1,"tx":{tx},"amount":"1"}}"#))
+            .collect();
+        // Applies (and acknowledges) the messages the consumer delivers after `resume`
+        let consume = |db: &mut OffsetProcessor<InMemoryTransactionDb>, resume, upto| {
+            let start = match config().consumer_config(resume).deliver_policy {
+                DeliverPolicy::ByStartSequence { start_sequence } => start_sequence,
+                _ => 1,
+            };
+            let sequence = db.position("nats:TRANSACTIONS").unwrap();
+            let mut cursor = CursorProcessor::since(db.inner_mut(), sequence);
+            let mut metrics = RunMetrics::new();
+            for sequence in start..=upto {
+                let payload = stream[sequence as usize - 1].as_bytes();
+                let disposition = apply_message_at(
+                    &mut cursor,
+                    sequence,
+                    PayloadFormat::Json,
+                    payload,
+                    &mut metrics,
+                );
+                assert!(matches!(disposition, Disposition::Done));
+            }
+            let sequence = cursor.cursor();
+            db.set_position("nats:TRANSACTIONS", &sequence.unwrap())
+                .unwrap();
+        };
+
+        let mut db = OffsetProcessor::new(InMemoryTransactionDb::new());
+        consume(&mut db, Some(0), 2);
+        let saved = db.snapshot().unwrap();
+        // The process is killed after acknowledging 3 and 4, before saving the state
+        consume(&mut db, None, 4);
+
+        let mut db = OffsetProcessor::new(InMemoryTransactionDb::new());
+        db.restore(saved).unwrap();
+        let resume = db.position("nats:TRANSACTIONS").unwrap();
+        assert_eq!(resume, Some(2));
+        consume(&mut db, resume, 4);
+
+        assert_eq!(db.client(1).unwrap().available, dec!(4));
+        assert_eq!(db.position::<u64>("nats:TRANSACTIONS").unwrap(), Some(4));
+    }
+}
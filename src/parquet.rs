@@ -0,0 +1,308 @@
+use std::{fs::File, io::Write, str::FromStr, sync::Arc};
+
+use anyhow::{Context, bail};
+use arrow_array::{
+    Array, ArrayRef, RecordBatch,
+    builder::{BooleanBuilder, Decimal128Builder, Int64Builder, StringBuilder, UInt64Builder},
+};
+use arrow_schema::{Field, Schema};
+use parquet::arrow::{ArrowWriter, arrow_reader::ParquetRecordBatchReaderBuilder};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    arrow::record_batch_processor_draining, drain::DrainSignal, export::OutputWriter,
+    metrics::RunMetrics, transaction::TransactionProcessor,
+};
+
+/// Same as [`crate::csv::csv_processor`], but reads the events from a Parquet file.
+///
+/// Columns are mapped like in [`crate::arrow::record_batch_processor`].
+pub fn parquet_processor<O, DB>(file: File, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    parquet_processor_draining(file, output, db, &DrainSignal::new())
+}
+
+/// Same as [`parquet_processor`], stopping like [`record_batch_processor_draining`]
+pub fn parquet_processor_draining<O, DB>(
+    file: File,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+
+    record_batch_processor_draining(reader, output, db, drain)
+}
+
+/// Buffers rows and writes them as a Parquet file once done.
+///
+/// The schema is derived from the rows: their fields become columns, in order, typed after
+/// the values (integers, booleans, strings, and strings holding decimals, like amounts).
+pub struct ParquetWriter<W: Write> {
+    writer: Option<W>,
+    rows: Vec<Map<String, Value>>,
+}
+
+impl<W: Write> ParquetWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+            rows: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> OutputWriter for ParquetWriter<W> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        match serde_json::to_value(row)? {
+            Value::Object(row) => self.rows.push(row),
+            _ => bail!("only structs can be written to Parquet"),
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let Some(mut writer) = self.writer.take() else {
+            bail!("Parquet file was already written");
+        };
+
+        let names: Vec<String> = self
+            .rows
+            .first()
+            .map(|row| row.keys().cloned().collect())
+            .unwrap_or_default();
+
+        let (fields, columns): (Vec<_>, Vec<_>) = names
+            .iter()
+            .map(|name| {
+                let values: Vec<&Value> = self
+                    .rows
+                    .iter()
+                    .map(|row| row.get(name).unwrap_or(&Value::Null))
+                    .collect();
+                let column = ColumnType::infer(&values).build(&values)?;
+                let field = Field::new(name, column.data_type().clone(), column.null_count() > 0);
+
+                Ok((field, column))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+
+        let schema = Arc::new(Schema::new(fields));
+        // The Arrow writer needs a `Send` writer, and the file is small
+        let mut file = Vec::new();
+        let mut parquet_writer = ArrowWriter::try_new(&mut file, schema.clone(), None)?;
+        if !self.rows.is_empty() {
+            parquet_writer.write(&RecordBatch::try_new(schema, columns)?)?;
+        }
+        parquet_writer.close()?;
+
+        writer.write_all(&file)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Column type of the JSON values of a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Null,
+    Bool,
+    UInt,
+    Int,
+    Decimal { scale: u32 },
+    String,
+}
+
+impl ColumnType {
+    fn infer(values: &[&Value]) -> Self {
+        values
+            .iter()
+            .map(|value| match value {
+                Value::Null => Self::Null,
+                Value::Bool(_) => Self::Bool,
+                Value::Number(number) if number.is_u64() => Self::UInt,
+                Value::Number(number) if number.is_i64() => Self::Int,
+                Value::String(string) => match Decimal::from_str(string) {
+                    Ok(decimal) => Self::Decimal {
+                        scale: decimal.scale(),
+                    },
+                    Err(_) => Self::String,
+                },
+                _ => Self::String,
+            })
+            .fold(Self::Null, |column, value| match (column, value) {
+                (Self::Null, other) | (other, Self::Null) => other,
+                (Self::UInt | Self::Int, Self::UInt | Self::Int) if column != value => Self::Int,
+                (Self::Decimal { scale }, Self::Decimal { scale: other }) => Self::Decimal {
+                    scale: scale.max(other),
+                },
+                (column, value) if column == value => column,
+                _ => Self::String,
+            })
+    }
+
+    fn build(self, values: &[&Value]) -> anyhow::Result<ArrayRef> {
+        Ok(match self {
+            Self::Null | Self::String => {
+                let mut builder = StringBuilder::new();
+                for value in values {
+                    match value {
+                        Value::Null => builder.append_null(),
+                        Value::String(string) => builder.append_value(string),
+                        other => builder.append_value(other.to_string()),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            Self::Bool => {
+                let mut builder = BooleanBuilder::new();
+                for value in values {
+                    builder.append_option(value.as_bool());
+                }
+                Arc::new(builder.finish())
+            }
+            Self::UInt => {
+                let mut builder = UInt64Builder::new();
+                for value in values {
+                    builder.append_option(value.as_u64());
+                }
+                Arc::new(builder.finish())
+            }
+            Self::Int => {
+                let mut builder = Int64Builder::new();
+                for value in values {
+                    builder.append_option(value.as_i64());
+                }
+                Arc::new(builder.finish())
+            }
+            Self::Decimal { scale } => {
+                let mut builder = Decimal128Builder::new()
+                    .with_precision_and_scale(38, scale as i8)
+                    .context("invalid decimal scale")?;
+                for value in values {
+                    let decimal = value.as_str().map(Decimal::from_str).transpose()?;
+                    builder.append_option(decimal.map(|mut decimal| {
+                        decimal.rescale(scale);
+                        decimal.mantissa()
+                    }));
+                }
+                Arc::new(builder.finish())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow_array::{Decimal128Array, StringArray, UInt8Array, UInt64Array, cast::AsArray};
+    use arrow_schema::DataType;
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        arrow::{optional_column, parse_amount},
+        memory_processor::InMemoryTransactionDb,
+    };
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("events.parquet");
+        let output = dir.path().join("clients.parquet");
+
+        // Ids and amounts in other types than the engine uses
+        let events = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                ])) as ArrayRef,
+            ),
+            ("client", Arc::new(UInt8Array::from(vec![1, 2, 1, 2]))),
+            ("tx", Arc::new(UInt64Array::from(vec![1, 2, 3, 2]))),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![Some(15_000), Some(20_000), Some(5_000), None])
+                        .with_precision_and_scale(10, 4)
+                        .unwrap(),
+                ),
+            ),
+        ])
+        .unwrap();
+        let mut writer =
+            ArrowWriter::try_new(File::create(&input).unwrap(), events.schema(), None).unwrap();
+        writer.write(&events).unwrap();
+        writer.close().unwrap();
+
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = parquet_processor(
+            File::open(&input).unwrap(),
+            ParquetWriter::new(File::create(&output).unwrap()),
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(metrics.latencies.count(), 4);
+
+        let clients = ParquetRecordBatchReaderBuilder::try_new(File::open(&output).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let schema = clients.schema();
+        let names: Vec<_> = schema.fields().iter().map(|field| field.name()).collect();
+        assert_eq!(names, ["client", "available", "held", "total", "locked"]);
+        assert!(matches!(
+            schema.field(1).data_type(),
+            DataType::Decimal128(38, _)
+        ));
+
+        let strings = |name: &str| -> Vec<String> {
+            let column = optional_column(&clients, name, &DataType::Utf8)
+                .unwrap()
+                .unwrap();
+            let column = column.as_string::<i32>();
+            (0..column.len())
+                .map(|row| column.value(row).to_owned())
+                .collect()
+        };
+        let mut rows: Vec<_> = strings("client")
+            .into_iter()
+            .zip(strings("available"))
+            .zip(strings("held"))
+            .map(|((client, available), held)| {
+                (
+                    client,
+                    parse_amount(&available).unwrap(),
+                    parse_amount(&held).unwrap(),
+                )
+            })
+            .collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![
+                ("1".to_owned(), dec!(1), dec!(0)),
+                ("2".to_owned(), dec!(0), dec!(2))
+            ]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Decoding the input ahead of applying it, so a slow backend and a fast source work in
+//! parallel without the source running away from it.
+//!
+//! The decoder stage reads and decodes rows on a blocking task, and hands them to the
+//! processor stage through a bounded channel: once the channel is full, the decoder waits for
+//! the backend to catch up, so memory stays bounded by the capacity of the channel however
+//! far behind the backend is.
+
+use std::{io::Read, num::NonZeroUsize, time::Instant};
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+
+use crate::{
+    csv::{TransactionReader, TransactionRow, apply_row, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// A decoded row, and when it started being parsed
+type Decoded = anyhow::Result<(TransactionRow, Instant)>;
+
+/// Same as [`crate::csv::csv_processor`], but decodes the rows on a task of their own, up to
+/// `capacity` rows ahead of the ones being applied.
+///
+/// Like [`crate::csv::csv_processor_draining`], stops at the first row after `drain` was
+/// requested and writes the clients as they are then. The rows decoded ahead are dropped.
+///
+/// The latencies recorded in the metrics include the time rows waited in the channel.
+pub fn csv_processor_pipelined<R, O, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    output: O,
+    db: &mut DB,
+    capacity: NonZeroUsize,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: Read + Send + 'static,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .context("failed to start the pipeline runtime")?;
+    let (rows, mut decoded) = mpsc::channel(capacity.get());
+    let mut csv_reader = csv_reader.into();
+    let decoder = runtime.spawn_blocking(move || decode(&mut csv_reader, &rows));
+
+    let mut metrics = RunMetrics::new();
+    let applied = runtime.block_on(async {
+        let mut records = 0;
+        while let Some(row) = decoded.recv().await {
+            let (row, started) = row?;
+            apply_row(db, row, started, &mut metrics)?;
+            records += 1;
+
+            if drain.is_draining() {
+                metrics.drained_at = Some(records);
+                break;
+            }
+        }
+
+        anyhow::Ok(())
+    });
+    if applied.is_ok() && metrics.drained_at.is_none() {
+        // The decoder is done once it hung up, either at the end of the input or because it
+        // panicked
+        runtime
+            .block_on(decoder)
+            .context("the decoder stage panicked")?;
+    } else {
+        // Stops the decoder, its next send fails once the receiver is gone. It may be waiting
+        // for input instead, eg on stdin, so it isn't waited for
+        drop(decoded);
+        runtime.shutdown_background();
+    }
+    applied?;
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Decodes the rows of `csv_reader` into `rows`, waiting whenever it's full. Stops at the
+/// first row which doesn't decode, after sending its error.
+fn decode<R: Read>(csv_reader: &mut TransactionReader<R>, rows: &mpsc::Sender<Decoded>) {
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let started = Instant::now();
+        let row = match csv_reader.read_record(&mut record) {
+            Ok(false) => return,
+            Ok(true) => csv_reader.decode(&record).map(|row| (row, started)),
+            Err(err) => Err(err),
+        };
+        let failed = row.is_err();
+        if rows.blocking_send(row).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        csv::{CsvDialect, csv_processor},
+        memory_processor::InMemoryTransactionDb,
+    };
+
+    fn process(input: &'static str, capacity: usize) -> anyhow::Result<(String, RunMetrics)> {
+        let reader = CsvDialect::default().transaction_reader(input.as_bytes())?;
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = csv_processor_pipelined(
+            reader,
+            csv::Writer::from_writer(&mut output),
+            &mut db,
+            NonZeroUsize::new(capacity).unwrap(),
+            &DrainSignal::new(),
+        )?;
+        assert_eq!(db.client(1).map(|client| client.available), Some(dec!(7)));
+
+        Ok((String::from_utf8(output)?, metrics))
+    }
+
+    #[test]
+    fn applies_every_row() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,3\n\
+            withdrawal,1,3,100\n\
+            deposit,2,4,1\n";
+        let (output, metrics) = process(input, 1).unwrap();
+
+        let mut expected = Vec::new();
+        csv_processor(
+            CsvDialect::default()
+                .transaction_reader(input.as_bytes())
+                .unwrap(),
+            csv::Writer::from_writer(&mut expected),
+            &mut InMemoryTransactionDb::new(),
+        )
+        .unwrap();
+        let mut lines: Vec<_> = output.lines().collect();
+        let expected = String::from_utf8(expected).unwrap();
+        let mut expected: Vec<_> = expected.lines().collect();
+        lines.sort();
+        expected.sort();
+        assert_eq!(lines, expected);
+        assert_eq!(metrics.latencies.count(), 4);
+        assert_eq!(metrics.activity.counters((None, 1)).rejections, 1);
+    }
+
+    #[test]
+    fn stops_at_invalid_rows() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            withdrawal,1,2,3\n\
+            deposit,one,3,1\n\
+            deposit,1,4,1\n";
+        assert!(process(input, 2).is_err());
+    }
+}
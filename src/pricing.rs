@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount,
+    csv::TransactionReader,
+    transaction::{ClientId, TransactionEvent, TransactionId, TransactionProcessor},
+};
+
+/// Decimal places fees are rounded to, same as the balances written out
+const FEE_DECIMAL_PLACES: u32 = 4;
+
+/// Fee charged on one type of event: a flat part plus a percentage of the amount involved,
+/// clamped to `min` and `max`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeRule {
+    pub flat: Decimal,
+    /// Percent of the amount, eg `0.5` for 0.5%
+    pub percent: Decimal,
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+}
+
+impl FeeRule {
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        let mut fee = self.flat + amount.abs() * self.percent / Decimal::ONE_HUNDRED;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+
+        fee.round_dp(FEE_DECIMAL_PLACES).normalize()
+    }
+}
+
+/// Fees per event type, as read from a JSON file, eg
+/// `{"deposit": {"percent": "0.1"}, "chargeback": {"flat": "15"}}`.
+///
+/// Disputes and chargebacks are charged on the amount of the disputed transaction. Resolves
+/// are free, and events missing from the file cost nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub deposit: FeeRule,
+    pub withdrawal: FeeRule,
+    pub dispute: FeeRule,
+    pub chargeback: FeeRule,
+}
+
+impl FeeSchedule {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("invalid fee schedule {}", path.display()))
+    }
+
+    fn rule(&self, event: &TransactionEvent) -> Option<&FeeRule> {
+        match event {
+            TransactionEvent::Deposit { .. } => Some(&self.deposit),
+            TransactionEvent::Withdrawal { .. } => Some(&self.withdrawal),
+            TransactionEvent::Dispute { .. } => Some(&self.dispute),
+            TransactionEvent::Chargeback { .. } => Some(&self.chargeback),
+            TransactionEvent::Resolve { .. } => None,
+        }
+    }
+}
+
+/// Fees a client would have paid under each schedule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientRevenue {
+    /// Number of events the client was charged for
+    pub events: u64,
+    pub baseline: Decimal,
+    pub alternative: Decimal,
+}
+
+impl ClientRevenue {
+    /// Revenue gained (or lost, if negative) by switching to the alternative schedule
+    pub fn delta(&self) -> Decimal {
+        self.alternative - self.baseline
+    }
+}
+
+#[derive(Serialize)]
+struct RevenueRow {
+    client: ClientId,
+    events: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    baseline: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    alternative: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    delta: Decimal,
+}
+
+impl RevenueRow {
+    fn new(client: ClientId, revenue: &ClientRevenue) -> Self {
+        Self {
+            client,
+            events: revenue.events,
+            baseline: revenue.baseline,
+            alternative: revenue.alternative,
+            delta: revenue.delta(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RevenueTotal {
+    events: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    baseline: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    alternative: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    delta: Decimal,
+}
+
+#[derive(Serialize)]
+struct SimulationJson {
+    total: RevenueTotal,
+    clients: Vec<RevenueRow>,
+}
+
+/// Outcome of [`simulate_fees`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeSimulation {
+    pub clients: BTreeMap<ClientId, ClientRevenue>,
+}
+
+impl FeeSimulation {
+    pub fn total(&self) -> ClientRevenue {
+        self.clients
+            .values()
+            .fold(ClientRevenue::default(), |total, client| ClientRevenue {
+                events: total.events + client.events,
+                baseline: total.baseline + client.baseline,
+                alternative: total.alternative + client.alternative,
+            })
+    }
+
+    /// Writes a row per client with its revenue under both schedules and the delta.
+    pub fn write_csv<W: Write>(&self, mut csv_writer: csv::Writer<W>) -> anyhow::Result<()> {
+        for (&client, revenue) in &self.clients {
+            csv_writer.serialize(RevenueRow::new(client, revenue))?;
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the total and the same rows as [`Self::write_csv`] as a single JSON object.
+    pub fn write_json<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let total = self.total();
+        let json = SimulationJson {
+            total: RevenueTotal {
+                events: total.events,
+                baseline: total.baseline,
+                alternative: total.alternative,
+                delta: total.delta(),
+            },
+            clients: self
+                .clients
+                .iter()
+                .map(|(&client, revenue)| RevenueRow::new(client, revenue))
+                .collect(),
+        };
+        serde_json::to_writer(writer, &json)?;
+
+        Ok(())
+    }
+}
+
+/// Replays every row of `csv_reader` on `db` and prices the events it applied under both
+/// fee schedules, so a pricing change can be evaluated on real traffic before rolling it
+/// out. Rejected events aren't charged.
+///
+/// Fees are only computed, they're not taken off the balances, so both schedules see the
+/// exact same history.
+pub fn simulate_fees<R, DB>(
+    csv_reader: impl Into<TransactionReader<R>>,
+    db: &mut DB,
+    baseline: &FeeSchedule,
+    alternative: &FeeSchedule,
+) -> anyhow::Result<FeeSimulation>
+where
+    R: std::io::Read,
+    DB: TransactionProcessor,
+{
+    let mut simulation = FeeSimulation::default();
+    // Amounts of the deposits and withdrawals seen, to price disputes and chargebacks
+    let mut amounts: HashMap<(ClientId, TransactionId), Decimal> = HashMap::new();
+
+    for transaction_row in csv_reader.into().rows() {
+        let event: TransactionEvent = transaction_row?.try_into()?;
+        let client = event.client();
+        let tx = event.tx();
+        let amount = match event {
+            TransactionEvent::Deposit { amount, .. }
+            | TransactionEvent::Withdrawal { amount, .. } => Some(amount),
+            _ => amounts.get(&(client, tx)).copied(),
+        };
+
+        if db.process_transaction_event(event.clone()).is_err() {
+            continue;
+        }
+        if let TransactionEvent::Deposit { amount, .. }
+        | TransactionEvent::Withdrawal { amount, .. } = event
+        {
+            amounts.insert((client, tx), amount);
+        }
+
+        let (Some(baseline), Some(alternative)) = (baseline.rule(&event), alternative.rule(&event))
+        else {
+            continue;
+        };
+        let amount = amount.unwrap_or_default();
+        let revenue = simulation.clients.entry(client).or_default();
+        revenue.events += 1;
+        revenue.baseline += baseline.fee(amount);
+        revenue.alternative += alternative.fee(amount);
+    }
+
+    Ok(simulation)
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[test]
+    fn fee_rule() {
+        let rule = FeeRule {
+            flat: dec!(0.3),
+            percent: dec!(2.9),
+            min: Some(dec!(0.5)),
+            max: Some(dec!(10)),
+        };
+
+        assert_eq!(rule.fee(dec!(1)), dec!(0.5));
+        assert_eq!(rule.fee(dec!(-100)), dec!(3.2));
+        assert_eq!(rule.fee(dec!(1000)), dec!(10));
+        assert_eq!(FeeRule::default().fee(dec!(1000)), dec!(0));
+    }
+
+    #[test]
+    fn simulates_fees() {
+        let input = "type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,50
+withdrawal,2,3,80
+dispute,1,1,
+chargeback,1,1,
+resolve,2,2,
+";
+        let baseline: FeeSchedule =
+            serde_json::from_str(r#"{"deposit": {"flat": "1"}, "chargeback": {"flat": "15"}}"#)
+                .unwrap();
+        let alternative: FeeSchedule = serde_json::from_str(
+            r#"{"deposit": {"percent": "0.5"}, "chargeback": {"percent": "20"}}"#,
+        )
+        .unwrap();
+
+        let simulation = simulate_fees(
+            csv::Reader::from_reader(input.as_bytes()),
+            &mut InMemoryTransactionDb::new(),
+            &baseline,
+            &alternative,
+        )
+        .unwrap();
+
+        // The rejected withdrawal isn't charged, and resolves are free
+        assert_eq!(
+            simulation.clients,
+            BTreeMap::from([
+                (
+                    1,
+                    ClientRevenue {
+                        events: 3,
+                        baseline: dec!(16),
+                        alternative: dec!(20.5),
+                    }
+                ),
+                (
+                    2,
+                    ClientRevenue {
+                        events: 1,
+                        baseline: dec!(1),
+                        alternative: dec!(0.25),
+                    }
+                ),
+            ])
+        );
+        assert_eq!(simulation.total().delta(), dec!(3.75));
+
+        let mut output = Vec::new();
+        simulation
+            .write_csv(csv::Writer::from_writer(&mut output))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,events,baseline,alternative,delta
+1,3,16.0000,20.5000,4.5000
+2,1,1.0000,0.2500,-0.7500
+"
+        );
+
+        let mut output = Vec::new();
+        simulation.write_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["total"]["delta"], "3.7500");
+        assert_eq!(json["clients"][1]["client"], 2);
+        assert_eq!(json["clients"][1]["alternative"], "0.2500");
+    }
+
+    #[test]
+    fn err_unknown_event_type() {
+        assert!(serde_json::from_str::<FeeSchedule>(r#"{"refund": {"flat": "1"}}"#).is_err());
+    }
+}
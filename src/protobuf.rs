@@ -0,0 +1,261 @@
+use std::{
+    io::{self, BufRead},
+    str::FromStr,
+    time::Instant,
+};
+
+use anyhow::{Context, bail};
+use prost::Message;
+use rust_decimal::Decimal;
+
+use crate::{
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    transaction::{EventKind, TransactionProcessor},
+};
+
+/// Schema of the events, kept in sync with [`TransactionEventMessage`] by hand so building
+/// doesn't need `protoc`.
+pub const TRANSACTION_PROTO: &str = include_str!("../schemas/transaction.proto");
+
+/// Most bytes a single message may take, so a corrupt length can't exhaust memory
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// `octopussy.TransactionEvent` in [`TRANSACTION_PROTO`]
+#[derive(Clone, PartialEq, Message)]
+pub struct TransactionEventMessage {
+    #[prost(enumeration = "EventType", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(uint32, tag = "3")]
+    pub tx: u32,
+    #[prost(string, optional, tag = "4")]
+    pub amount: Option<String>,
+    #[prost(uint32, optional, tag = "5")]
+    pub tenant: Option<u32>,
+    #[prost(uint64, optional, tag = "6")]
+    pub timestamp: Option<u64>,
+}
+
+/// `octopussy.EventType` in [`TRANSACTION_PROTO`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum EventType {
+    Unspecified = 0,
+    Deposit = 1,
+    Withdrawal = 2,
+    Dispute = 3,
+    Resolve = 4,
+    Chargeback = 5,
+}
+
+impl From<EventKind> for EventType {
+    fn from(kind: EventKind) -> Self {
+        match kind {
+            EventKind::Deposit => Self::Deposit,
+            EventKind::Withdrawal => Self::Withdrawal,
+            EventKind::Dispute => Self::Dispute,
+            EventKind::Resolve => Self::Resolve,
+            EventKind::Chargeback => Self::Chargeback,
+        }
+    }
+}
+
+impl TryFrom<TransactionEventMessage> for TransactionRow {
+    type Error = anyhow::Error;
+
+    fn try_from(message: TransactionEventMessage) -> Result<Self, Self::Error> {
+        let kind = match EventType::try_from(message.r#type) {
+            Ok(EventType::Deposit) => EventKind::Deposit,
+            Ok(EventType::Withdrawal) => EventKind::Withdrawal,
+            Ok(EventType::Dispute) => EventKind::Dispute,
+            Ok(EventType::Resolve) => EventKind::Resolve,
+            Ok(EventType::Chargeback) => EventKind::Chargeback,
+            Ok(EventType::Unspecified) | Err(_) => {
+                bail!("unknown transaction event type {}", message.r#type)
+            }
+        };
+
+        Ok(TransactionRow {
+            transaction_type: kind.as_str().to_owned(),
+            client: message
+                .client
+                .try_into()
+                .context("client id out of range")?,
+            tx: message.tx,
+            amount: message
+                .amount
+                .as_deref()
+                .map(Decimal::from_str)
+                .transpose()
+                .context("invalid amount")?,
+            tenant: message
+                .tenant
+                .map(TryInto::try_into)
+                .transpose()
+                .context("tenant id out of range")?,
+            timestamp: message.timestamp,
+            sequence: None,
+            metadata: Default::default(),
+        })
+    }
+}
+
+/// Same as [`crate::csv::csv_processor`], but reads a stream of length-delimited protobuf
+/// messages: each [`TRANSACTION_PROTO`] `TransactionEvent` is preceded by its length as a
+/// varint, like `writeDelimitedTo` in the Java library writes them.
+pub fn protobuf_processor<R, O, DB>(reader: R, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    protobuf_processor_draining(reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`protobuf_processor`], but stops at the first message after `drain` was
+/// requested and writes the clients as they are then.
+pub fn protobuf_processor_draining<R, O, DB>(
+    mut reader: R,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    let mut buffer = Vec::new();
+    let mut number = 0;
+
+    while let Some(len) =
+        read_length(&mut reader).with_context(|| format!("truncated message {}", number + 1))?
+        && !drained(drain, &mut metrics)
+    {
+        number += 1;
+        let started = Instant::now();
+        if len > MAX_MESSAGE_LEN {
+            bail!("message {number} is {len} bytes long, more than {MAX_MESSAGE_LEN}");
+        }
+
+        buffer.resize(len, 0);
+        reader
+            .read_exact(&mut buffer)
+            .with_context(|| format!("truncated message {number}"))?;
+        let transaction_row = TransactionEventMessage::decode(buffer.as_slice())
+            .map_err(anyhow::Error::from)
+            .and_then(TransactionRow::try_from)
+            .with_context(|| format!("invalid message {number}"))?;
+        apply_row(db, transaction_row, started, &mut metrics)?;
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Reads the varint length of the next message, or `None` at the end of the stream.
+fn read_length<R: BufRead>(reader: &mut R) -> io::Result<Option<usize>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut len = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        len |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len.try_into().unwrap_or(usize::MAX)));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "message length is not a valid varint",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    fn event(kind: EventKind, tx: u32, amount: Option<&str>) -> TransactionEventMessage {
+        TransactionEventMessage {
+            r#type: EventType::from(kind).into(),
+            client: 1,
+            tx,
+            amount: amount.map(str::to_owned),
+            tenant: None,
+            timestamp: Some(1_700_000_000),
+        }
+    }
+
+    fn encode(events: &[TransactionEventMessage]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for event in events {
+            event.encode_length_delimited(&mut input).unwrap();
+        }
+        input
+    }
+
+    fn process(input: &[u8]) -> anyhow::Result<Vec<ClientRow>> {
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        protobuf_processor(input, csv::Writer::from_writer(&mut output), &mut db)?;
+
+        Ok(csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()?)
+    }
+
+    #[test]
+    fn length_delimited_stream() {
+        let input = encode(&[
+            event(EventKind::Deposit, 1, Some("2.5")),
+            event(EventKind::Deposit, 2, Some("1")),
+            event(EventKind::Dispute, 2, None),
+        ]);
+
+        assert_eq!(
+            process(&input).unwrap(),
+            vec![ClientRow {
+                client: 1,
+                available: dec!(2.5),
+                held: dec!(1),
+                total: dec!(3.5),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn err_unknown_type() {
+        let mut message = event(EventKind::Deposit, 1, Some("1"));
+        message.r#type = 0;
+
+        assert_eq!(
+            format!("{:#}", process(&encode(&[message])).unwrap_err()),
+            "invalid message 1: unknown transaction event type 0"
+        );
+    }
+
+    #[test]
+    fn err_truncated() {
+        let mut input = encode(&[event(EventKind::Deposit, 1, Some("1"))]);
+        input.pop();
+
+        assert_eq!(
+            process(&input).unwrap_err().to_string(),
+            "truncated message 1"
+        );
+    }
+}
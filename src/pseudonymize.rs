@@ -0,0 +1,174 @@
+//! Deterministic pseudonymization of client ids, so production inputs can be shared for
+//! debugging without giving away which accounts they're about.
+
+use aes_gcm::aes::{
+    Aes256,
+    cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+
+use crate::transaction::ClientId;
+
+/// Feistel rounds of the permutation, 4 make it a strong pseudorandom permutation
+const ROUNDS: u8 = 4;
+
+/// Maps client ids to pseudonyms with a keyed permutation of all the [`ClientId`]s.
+///
+/// The same key always gives the same pseudonym, so rewritten disputes, resolves and
+/// chargebacks still point at the client of their deposit, and files rewritten separately
+/// with one key can be processed together. Being a permutation, no two clients get the same
+/// pseudonym, which a truncated keyed hash couldn't guarantee with 16 bit ids. Without the
+/// key, pseudonyms can't be mapped back.
+pub struct Pseudonymizer {
+    cipher: Aes256,
+}
+
+impl Pseudonymizer {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256::new(key.into()),
+        }
+    }
+
+    /// A balanced Feistel network over the two bytes of `client`, with AES as round function
+    pub fn client(&self, client: ClientId) -> ClientId {
+        let [mut left, mut right] = client.to_be_bytes();
+        for round in 0..ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+
+        ClientId::from_be_bytes([left, right])
+    }
+
+    fn round(&self, round: u8, half: u8) -> u8 {
+        let mut block = GenericArray::from([0; 16]);
+        block[0] = round;
+        block[1] = half;
+        self.cipher.encrypt_block(&mut block);
+
+        block[0]
+    }
+}
+
+/// Copies every record of `reader` to `writer`, replacing the client column with its
+/// pseudonym. Other columns are copied as is. The header, if any, is kept, and tells which
+/// column is the client's. Without one, it's the second column, as when processing.
+///
+/// Returns the number of records rewritten.
+pub fn pseudonymize_csv<R, W>(
+    mut reader: csv::Reader<R>,
+    mut writer: csv::Writer<W>,
+    pseudonymizer: &Pseudonymizer,
+) -> anyhow::Result<u64>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    let column = if reader.has_headers() {
+        let headers = reader.byte_headers()?;
+        writer.write_byte_record(headers)?;
+        headers
+            .iter()
+            .position(|header| header == b"client")
+            .ok_or_else(|| anyhow::anyhow!("the input has no client column"))?
+    } else {
+        1
+    };
+
+    let mut records = 0;
+    let mut record = csv::StringRecord::new();
+    let mut rewritten = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        records += 1;
+
+        rewritten.clear();
+        for (index, field) in record.iter().enumerate() {
+            if index != column {
+                rewritten.push_field(field);
+                continue;
+            }
+
+            let client: ClientId = field.parse().map_err(|err| {
+                anyhow::anyhow!("invalid client {field:?} in record {records}: {err}")
+            })?;
+            rewritten.push_field(&pseudonymizer.client(client).to_string());
+        }
+        writer.write_record(&rewritten)?;
+    }
+    writer.flush()?;
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::csv::CsvDialect;
+
+    #[test]
+    fn permutation() {
+        let pseudonymizer = Pseudonymizer::new(&[7; 32]);
+
+        let pseudonyms: HashSet<ClientId> = (0..=ClientId::MAX)
+            .map(|client| pseudonymizer.client(client))
+            .collect();
+        assert_eq!(pseudonyms.len(), usize::from(ClientId::MAX) + 1);
+
+        assert_eq!(
+            pseudonymizer.client(1),
+            Pseudonymizer::new(&[7; 32]).client(1)
+        );
+        assert_ne!(
+            pseudonymizer.client(1),
+            Pseudonymizer::new(&[8; 32]).client(1)
+        );
+    }
+
+    fn rewrite(input: &str, dialect: CsvDialect) -> String {
+        let mut output = Vec::new();
+        pseudonymize_csv(
+            dialect.reader(input.as_bytes()),
+            dialect.writer(&mut output),
+            &Pseudonymizer::new(&[1; 32]),
+        )
+        .unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn rewrites_clients() {
+        let pseudonymizer = Pseudonymizer::new(&[1; 32]);
+        let (first, second) = (pseudonymizer.client(1), pseudonymizer.client(2));
+
+        let input = "tx,client,type,amount\n1,1,deposit,1.5\n2,2,deposit,2\n1,1,dispute,\n";
+        assert_eq!(
+            rewrite(input, CsvDialect::default()),
+            format!(
+                "tx,client,type,amount\n1,{first},deposit,1.5\n2,{second},deposit,2\n1,{first},dispute,\n"
+            )
+        );
+
+        let dialect = CsvDialect {
+            delimiter: b';',
+            headers: false,
+            ..CsvDialect::default()
+        };
+        assert_eq!(
+            rewrite("deposit;2;1;1\ndispute;2;1\n", dialect),
+            format!("deposit;{second};1;1\ndispute;{second};1\n")
+        );
+    }
+
+    #[test]
+    fn invalid_client() {
+        let err = pseudonymize_csv(
+            CsvDialect::default().reader("type,client,tx\ndeposit,x,1\n".as_bytes()),
+            CsvDialect::default().writer(Vec::new()),
+            &Pseudonymizer::new(&[1; 32]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid client \"x\" in record 1"));
+    }
+}
@@ -0,0 +1,371 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amount,
+    clock::Timestamp,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// A change of a client's state, published as JSON by a [`PublishingProcessor`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ClientUpdate {
+    /// The balances of the client right after an applied event
+    Balances {
+        /// Sequence number the DB assigned to the event
+        seq: u64,
+        client: ClientId,
+        tx: TransactionId,
+        /// Event type, eg `deposit`
+        #[serde(rename = "type")]
+        event_type: String,
+        #[serde(serialize_with = "amount::serialize")]
+        available: Decimal,
+        #[serde(serialize_with = "amount::serialize")]
+        held: Decimal,
+        #[serde(serialize_with = "amount::serialize")]
+        total: Decimal,
+        locked: bool,
+    },
+    /// The chargeback of `tx` froze the account of the client
+    AccountFrozen {
+        seq: u64,
+        client: ClientId,
+        tx: TransactionId,
+    },
+}
+
+/// Where a [`PublishingProcessor`] sends its updates, eg a Kafka topic.
+pub trait Publisher {
+    /// Publishes an encoded update of `client`. The updates of a client have to be delivered
+    /// in the order they were published.
+    fn publish(&mut self, client: ClientId, payload: &[u8]) -> anyhow::Result<()>;
+
+    /// Waits until everything published so far was delivered.
+    fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+/// Wraps a [`TransactionProcessor`] and publishes the [`ClientUpdate`]s of every event it
+/// applies successfully, so downstream consumers can react to state changes as they happen:
+/// the client's balances, and an [`ClientUpdate::AccountFrozen`] when a chargeback froze the
+/// account. Rejected events don't change anything and aren't published.
+///
+/// A failure to publish is a [`TransactionError::Storage`] error, after the event was
+/// applied.
+pub struct PublishingProcessor<DB, P> {
+    inner: DB,
+    publisher: P,
+}
+
+impl<DB, P> PublishingProcessor<DB, P>
+where
+    DB: TransactionProcessor,
+    P: Publisher,
+{
+    pub fn new(inner: DB, publisher: P) -> Self {
+        Self { inner, publisher }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, P) {
+        (self.inner, self.publisher)
+    }
+
+    /// Waits until every update was delivered, eg before committing what was consumed.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.publisher.flush()
+    }
+
+    /// Applies `event` with `apply`, and publishes the updates it caused.
+    fn apply<F>(&mut self, event: TransactionEvent, apply: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut DB) -> Result<(), TransactionError>,
+    {
+        let client_id = event.client();
+        let was_frozen = self
+            .inner
+            .client(client_id)
+            .is_some_and(|client| client.frozen);
+
+        apply(&mut self.inner)?;
+
+        let Some(client) = self.inner.client(client_id) else {
+            return Err(TransactionError::ClientNotFound { client_id });
+        };
+        let seq = self.inner.last_seq();
+        let mut updates = vec![ClientUpdate::Balances {
+            seq,
+            client: client_id,
+            tx: event.tx(),
+            event_type: event.kind().as_str().to_owned(),
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.frozen,
+        }];
+        if client.frozen && !was_frozen {
+            updates.push(ClientUpdate::AccountFrozen {
+                seq,
+                client: client_id,
+                tx: event.tx(),
+            });
+        }
+
+        for update in updates {
+            let publish = |publisher: &mut P| -> anyhow::Result<()> {
+                publisher.publish(client_id, &serde_json::to_vec(&update)?)
+            };
+            publish(&mut self.publisher)
+                .map_err(|err| TransactionError::Storage(format!("publisher: {err:#}")))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<DB, P> TransactionProcessor for PublishingProcessor<DB, P>
+where
+    DB: TransactionProcessor,
+    P: Publisher,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Deposit {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.deposit(transaction_id, client_id, amount))
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Withdrawal {
+            tx: transaction_id,
+            client: client_id,
+            amount,
+        };
+        self.apply(event, |db| db.withdrawal(transaction_id, client_id, amount))
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Dispute {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.dispute(transaction_id, client_id))
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Resolve {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.resolve(transaction_id, client_id))
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let event = TransactionEvent::Chargeback {
+            tx: transaction_id,
+            client: client_id,
+        };
+        self.apply(event, |db| db.chargeback(transaction_id, client_id))
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.apply(transaction.clone(), |db| {
+            db.process_annotated_event(transaction, metadata)
+        })
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB, P> StateStore for PublishingProcessor<DB, P>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[derive(Default)]
+    struct Published {
+        updates: Vec<(ClientId, ClientUpdate)>,
+        fail: bool,
+    }
+
+    impl Publisher for Published {
+        fn publish(&mut self, client: ClientId, payload: &[u8]) -> anyhow::Result<()> {
+            if self.fail {
+                anyhow::bail!("broker unavailable");
+            }
+            self.updates
+                .push((client, serde_json::from_slice(payload)?));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn publishes_updates() {
+        let mut db = PublishingProcessor::new(InMemoryTransactionDb::new(), Published::default());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(20)).unwrap_err();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+
+        let (_, published) = db.into_inner();
+        assert_eq!(
+            published.updates,
+            vec![
+                (
+                    1,
+                    ClientUpdate::Balances {
+                        seq: 1,
+                        client: 1,
+                        tx: 1,
+                        event_type: "deposit".to_owned(),
+                        available: dec!(10),
+                        held: dec!(0),
+                        total: dec!(10),
+                        locked: false,
+                    }
+                ),
+                (
+                    1,
+                    ClientUpdate::Balances {
+                        seq: 2,
+                        client: 1,
+                        tx: 1,
+                        event_type: "dispute".to_owned(),
+                        available: dec!(0),
+                        held: dec!(10),
+                        total: dec!(10),
+                        locked: false,
+                    }
+                ),
+                (
+                    1,
+                    ClientUpdate::Balances {
+                        seq: 3,
+                        client: 1,
+                        tx: 1,
+                        event_type: "chargeback".to_owned(),
+                        available: dec!(0),
+                        held: dec!(0),
+                        total: dec!(0),
+                        locked: true,
+                    }
+                ),
+                (
+                    1,
+                    ClientUpdate::AccountFrozen {
+                        seq: 3,
+                        client: 1,
+                        tx: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn serialized_update() {
+        let update = ClientUpdate::AccountFrozen {
+            seq: 3,
+            client: 1,
+            tx: 7,
+        };
+        assert_eq!(
+            serde_json::to_string(&update).unwrap(),
+            r#"{"kind":"account-frozen","seq":3,"client":1,"tx":7}"#
+        );
+    }
+
+    #[test]
+    fn publish_failure() {
+        let published = Published {
+            fail: true,
+            ..Published::default()
+        };
+        let mut db = PublishingProcessor::new(InMemoryTransactionDb::new(), published);
+        assert!(matches!(
+            db.deposit(1, 1, dec!(1)),
+            Err(TransactionError::Storage(_))
+        ));
+    }
+}
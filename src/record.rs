@@ -0,0 +1,335 @@
+//! Encoding and event handling shared by the persistent key-value backends.
+//!
+//! The backends only have to provide a [`RecordStore`] over one of their write
+//! transactions, the rules for applying events live here.
+
+use rust_decimal::Decimal;
+
+use crate::{
+    snapshot::{ClientSnapshot, TransactionSnapshot},
+    state_machine::{
+        AccountEvent, AccountStatus, DisputeEvent, Guard, MaxDisputeCount, TransactionStatus,
+        account_transition, dispute_transition,
+    },
+    transaction::{ClientId, ClientInformation, TransactionError, TransactionId},
+};
+
+/// Encoded client value: `available (16 bytes) | held (16 bytes) | frozen (1 byte)`.
+///
+/// Decimals are stored using [`Decimal::serialize`], which is a stable representation,
+/// so the file format doesn't depend on serde or any other encoding crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ClientRecord {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub frozen: bool,
+}
+
+impl ClientRecord {
+    pub const SIZE: usize = 33;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..16].copy_from_slice(&self.available.serialize());
+        bytes[16..32].copy_from_slice(&self.held.serialize());
+        bytes[32] = self.frozen as u8;
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, TransactionError> {
+        if bytes.len() != Self::SIZE {
+            return Err(corrupt("client record", bytes.len()));
+        }
+
+        Ok(Self {
+            available: decode_decimal(&bytes[..16]),
+            held: decode_decimal(&bytes[16..32]),
+            frozen: bytes[32] != 0,
+        })
+    }
+
+    pub fn information(&self, id: ClientId) -> ClientInformation {
+        ClientInformation {
+            id,
+            available: self.available,
+            held: self.held,
+            total: self.available + self.held,
+            frozen: self.frozen,
+        }
+    }
+
+    pub fn to_snapshot(self, id: ClientId) -> ClientSnapshot {
+        ClientSnapshot {
+            id,
+            available: self.available,
+            held: self.held,
+            frozen: self.frozen,
+        }
+    }
+
+    pub fn from_snapshot(client: &ClientSnapshot) -> Self {
+        Self {
+            available: client.available,
+            held: client.held,
+            frozen: client.frozen,
+        }
+    }
+}
+
+/// Encoded transaction value:
+/// `amount (16 bytes) | flags (1 byte) | seq (8 bytes) | disputes (4 bytes)`.
+///
+/// The flags are `disputed` in the lowest bit and `charged_back` in the next one, `seq` and
+/// `disputes` are big-endian. Like the in-memory backend, withdrawals are stored with a
+/// negative amount.
+pub(crate) struct TransactionRecord {
+    pub amount: Decimal,
+    pub disputed: bool,
+    pub charged_back: bool,
+    pub seq: u64,
+    /// Number of times the transaction was disputed
+    pub disputes: u32,
+}
+
+impl TransactionRecord {
+    pub const SIZE: usize = 29;
+    /// Records written before dispute counts were added
+    const SEQ_SIZE: usize = 25;
+    /// Records written before sequence numbers were added, they decode with a `seq` of 0
+    const LEGACY_SIZE: usize = 17;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..16].copy_from_slice(&self.amount.serialize());
+        bytes[16] = self.disputed as u8 | (self.charged_back as u8) << 1;
+        bytes[17..25].copy_from_slice(&self.seq.to_be_bytes());
+        bytes[25..].copy_from_slice(&self.disputes.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, TransactionError> {
+        if ![Self::SIZE, Self::SEQ_SIZE, Self::LEGACY_SIZE].contains(&bytes.len()) {
+            return Err(corrupt("transaction record", bytes.len()));
+        }
+
+        let disputed = bytes[16] & 1 != 0;
+        let seq = bytes.get(17..25).map_or(0, |seq| {
+            u64::from_be_bytes(seq.try_into().expect("8 bytes"))
+        });
+        // Older records only know whether a dispute is open
+        let disputes = bytes.get(25..29).map_or(disputed as u32, |disputes| {
+            u32::from_be_bytes(disputes.try_into().expect("4 bytes"))
+        });
+
+        Ok(Self {
+            amount: decode_decimal(&bytes[..16]),
+            disputed,
+            charged_back: bytes[16] & 2 != 0,
+            seq,
+            disputes,
+        })
+    }
+
+    pub fn to_snapshot(&self, client: ClientId, tx: TransactionId) -> TransactionSnapshot {
+        TransactionSnapshot {
+            client,
+            tx,
+            amount: self.amount,
+            disputed: self.disputed,
+            charged_back: self.charged_back,
+            seq: self.seq,
+            disputes: self.disputes,
+            recorded_at: 0,
+            created_at: None,
+        }
+    }
+
+    pub fn from_snapshot(transaction: &TransactionSnapshot) -> Self {
+        Self {
+            amount: transaction.amount,
+            disputed: transaction.disputed,
+            charged_back: transaction.charged_back,
+            seq: transaction.seq,
+            disputes: transaction.disputes,
+        }
+    }
+}
+
+fn decode_decimal(bytes: &[u8]) -> Decimal {
+    let mut buf = [0; 16];
+    buf.copy_from_slice(bytes);
+    Decimal::deserialize(buf)
+}
+
+fn corrupt(what: &str, len: usize) -> TransactionError {
+    TransactionError::Storage(format!("corrupt {what} of {len} bytes"))
+}
+
+/// Record access within a single write transaction of a backend.
+pub(crate) trait RecordStore {
+    fn client(&self, client_id: ClientId) -> Result<Option<ClientRecord>, TransactionError>;
+
+    fn transaction(
+        &self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, TransactionError>;
+
+    fn put_client(
+        &mut self,
+        client_id: ClientId,
+        client: &ClientRecord,
+    ) -> Result<(), TransactionError>;
+
+    fn put_transaction(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        transaction: &TransactionRecord,
+    ) -> Result<(), TransactionError>;
+
+    /// Assigns the sequence number of the event being applied. Since the write transaction
+    /// is dropped when the event is rejected, rejected events don't use up a number.
+    fn next_seq(&mut self) -> Result<u64, TransactionError>;
+}
+
+/// Applies a deposit and returns the updated client.
+pub(crate) fn deposit<S: RecordStore>(
+    store: &mut S,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    amount: Decimal,
+) -> Result<ClientRecord, TransactionError> {
+    record_transaction(store, transaction_id, client_id, amount, false)
+}
+
+/// Applies a withdrawal and returns the updated client.
+pub(crate) fn withdrawal<S: RecordStore>(
+    store: &mut S,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    amount: Decimal,
+) -> Result<ClientRecord, TransactionError> {
+    record_transaction(store, transaction_id, client_id, amount, true)
+}
+
+fn record_transaction<S: RecordStore>(
+    store: &mut S,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    amount: Decimal,
+    withdrawal: bool,
+) -> Result<ClientRecord, TransactionError> {
+    if store.transaction(client_id, transaction_id)?.is_some() {
+        return Err(TransactionError::DuplicateTransaction {
+            client_id,
+            transaction_id,
+        });
+    }
+
+    // Deposits lazily create clients, withdrawals need an existing one
+    let mut client = match store.client(client_id)? {
+        Some(client) => client,
+        None if withdrawal => return Err(TransactionError::ClientNotFound { client_id }),
+        None => ClientRecord::default(),
+    };
+
+    let event = if withdrawal {
+        AccountEvent::Withdrawal
+    } else {
+        AccountEvent::Deposit
+    };
+    let transition =
+        account_transition(AccountStatus::from_frozen(client.frozen), event, client_id)?;
+
+    if transition.is_guarded_by(Guard::SufficientFunds) && client.available < amount {
+        return Err(TransactionError::InsufficientFunds {
+            client_id,
+            transaction_id,
+            amount,
+            available: client.available,
+        });
+    }
+
+    let amount = if withdrawal { -amount } else { amount };
+    client.available += amount;
+    client.frozen = transition.to == AccountStatus::Frozen;
+
+    let transaction = TransactionRecord {
+        amount,
+        disputed: false,
+        charged_back: false,
+        seq: store.next_seq()?,
+        disputes: 0,
+    };
+    store.put_transaction(client_id, transaction_id, &transaction)?;
+    store.put_client(client_id, &client)?;
+
+    Ok(client)
+}
+
+/// Applies a dispute, resolve or chargeback and returns the updated client.
+pub(crate) fn update_disputed<S: RecordStore>(
+    store: &mut S,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+    event: DisputeEvent,
+    max_disputes: MaxDisputeCount,
+) -> Result<ClientRecord, TransactionError> {
+    let mut client = store
+        .client(client_id)?
+        .ok_or(TransactionError::ClientNotFound { client_id })?;
+
+    let mut transaction = store.transaction(client_id, transaction_id)?.ok_or(
+        TransactionError::TransactionNotFound {
+            client_id,
+            transaction_id,
+        },
+    )?;
+
+    let transition = dispute_transition(
+        TransactionStatus::from_flags(transaction.disputed, transaction.charged_back),
+        event,
+        client_id,
+        transaction_id,
+    )?;
+    let account = account_transition(
+        AccountStatus::from_frozen(client.frozen),
+        event.into(),
+        client_id,
+    )?;
+
+    if transition.is_guarded_by(Guard::BelowDisputeLimit)
+        && !max_disputes.allows(transaction.disputes)
+    {
+        return Err(TransactionError::DisputeLimitReached {
+            client_id,
+            transaction_id,
+        });
+    }
+
+    // The KV backends don't track when transactions were recorded, so they have no
+    // dispute window and `Guard::WithinDisputeWindow` always holds.
+    match event {
+        DisputeEvent::Dispute => {
+            transaction.disputes += 1;
+            client.available -= transaction.amount;
+            client.held += transaction.amount;
+        }
+        DisputeEvent::Resolve => {
+            client.available += transaction.amount;
+            client.held -= transaction.amount;
+        }
+        DisputeEvent::Chargeback => {
+            client.held -= transaction.amount;
+        }
+    }
+    (transaction.disputed, transaction.charged_back) = transition.to.flags();
+    client.frozen = account.to == AccountStatus::Frozen;
+
+    store.put_client(client_id, &client)?;
+    store.put_transaction(client_id, transaction_id, &transaction)?;
+    store.next_seq()?;
+
+    Ok(client)
+}
@@ -0,0 +1,617 @@
+use std::{collections::HashMap, path::Path};
+
+use redb::{Database, ReadableTable, Table, TableDefinition, WriteTransaction};
+use rust_decimal::Decimal;
+use tracing::error;
+
+use crate::{
+    record::{self, ClientRecord, RecordStore, TransactionRecord},
+    snapshot::{Snapshot, StateStore},
+    state_machine::{DisputeEvent, MaxDisputeCount},
+    state_version::{self, STATE_VERSION, StateVersion},
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+    },
+};
+
+const CLIENTS: TableDefinition<ClientId, &[u8]> = TableDefinition::new("clients");
+const TRANSACTIONS: TableDefinition<(ClientId, TransactionId), &[u8]> =
+    TableDefinition::new("transactions");
+/// The activity tick of each client's most recently applied event
+const ACTIVITY: TableDefinition<ClientId, u64> = TableDefinition::new("activity");
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+const META_TICK: &str = "activity_tick";
+const META_SEQ: &str = "seq";
+const META_STATE_VERSION: &str = "state_version";
+
+fn storage(err: impl Into<redb::Error>) -> TransactionError {
+    TransactionError::Storage(err.into().to_string())
+}
+
+/// A [`TransactionProcessor`] persisting its state in a [redb](https://www.redb.org) file.
+///
+/// redb is a pure-Rust embedded key-value store, so unlike other persistent backends it
+/// doesn't need any C dependencies. Every event is applied in its own write transaction.
+///
+/// Client balances can be kept in a write-through cache (see
+/// [`RedbTransactionDb::set_cache_capacity`]), which [`RedbTransactionDb::warmup`] fills
+/// with the most recently active clients after a restart.
+pub struct RedbTransactionDb {
+    db: Database,
+    cache: HashMap<ClientId, ClientRecord>,
+    cache_capacity: usize,
+    max_disputes: MaxDisputeCount,
+}
+
+/// The tables of a write transaction, with client reads going through the cache.
+struct RedbRecords<'txn, 'db> {
+    txn: &'txn WriteTransaction,
+    clients: Table<'txn, ClientId, &'static [u8]>,
+    transactions: Table<'txn, (ClientId, TransactionId), &'static [u8]>,
+    cache: &'db HashMap<ClientId, ClientRecord>,
+}
+
+impl RecordStore for RedbRecords<'_, '_> {
+    fn client(&self, client_id: ClientId) -> Result<Option<ClientRecord>, TransactionError> {
+        if let Some(client) = self.cache.get(&client_id) {
+            return Ok(Some(*client));
+        }
+
+        self.clients
+            .get(client_id)
+            .map_err(storage)?
+            .map(|value| ClientRecord::decode(value.value()))
+            .transpose()
+    }
+
+    fn transaction(
+        &self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    ) -> Result<Option<TransactionRecord>, TransactionError> {
+        self.transactions
+            .get((client_id, transaction_id))
+            .map_err(storage)?
+            .map(|value| TransactionRecord::decode(value.value()))
+            .transpose()
+    }
+
+    /// Writes the client back and records it as the most recently active one.
+    fn put_client(
+        &mut self,
+        client_id: ClientId,
+        client: &ClientRecord,
+    ) -> Result<(), TransactionError> {
+        self.clients
+            .insert(client_id, client.encode().as_slice())
+            .map_err(storage)?;
+
+        let mut meta = self.txn.open_table(META).map_err(storage)?;
+        let tick = meta
+            .get(META_TICK)
+            .map_err(storage)?
+            .map_or(0, |tick| tick.value())
+            + 1;
+        meta.insert(META_TICK, tick).map_err(storage)?;
+
+        let mut activity = self.txn.open_table(ACTIVITY).map_err(storage)?;
+        activity.insert(client_id, tick).map_err(storage)?;
+
+        Ok(())
+    }
+
+    fn put_transaction(
+        &mut self,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        transaction: &TransactionRecord,
+    ) -> Result<(), TransactionError> {
+        self.transactions
+            .insert((client_id, transaction_id), transaction.encode().as_slice())
+            .map_err(storage)?;
+
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> Result<u64, TransactionError> {
+        let mut meta = self.txn.open_table(META).map_err(storage)?;
+        let seq = meta
+            .get(META_SEQ)
+            .map_err(storage)?
+            .map_or(0, |seq| seq.value())
+            + 1;
+        meta.insert(META_SEQ, seq).map_err(storage)?;
+
+        Ok(seq)
+    }
+}
+
+impl RedbTransactionDb {
+    /// Opens the database at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TransactionError> {
+        let db = Database::create(path).map_err(storage)?;
+
+        // Make sure the tables exist, so read transactions don't have to special case them
+        let txn = db.begin_write().map_err(storage)?;
+        txn.open_table(CLIENTS).map_err(storage)?;
+        txn.open_table(TRANSACTIONS).map_err(storage)?;
+        txn.open_table(ACTIVITY).map_err(storage)?;
+        {
+            // Records from older releases are decoded as they are, but a newer release may
+            // have written records this one can't read
+            let mut meta = txn.open_table(META).map_err(storage)?;
+            let version = meta.get(META_STATE_VERSION).map_err(storage)?;
+            let version = version.map(|version| version.value() as StateVersion);
+            state_version::check_stored(version)
+                .map_err(|err| TransactionError::Storage(err.to_string()))?;
+            meta.insert(META_STATE_VERSION, STATE_VERSION as u64)
+                .map_err(storage)?;
+        }
+        txn.commit().map_err(storage)?;
+
+        Ok(Self {
+            db,
+            cache: HashMap::new(),
+            cache_capacity: 0,
+            max_disputes: MaxDisputeCount::default(),
+        })
+    }
+
+    /// Sets how many times a transaction can be disputed.
+    pub fn set_max_dispute_count(&mut self, max_disputes: MaxDisputeCount) {
+        self.max_disputes = max_disputes;
+    }
+
+    /// Sets how many clients the balance cache may hold. `0` (the default) disables it.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache_capacity = capacity;
+        if self.cache.len() > capacity {
+            self.cache.clear();
+        }
+    }
+
+    /// Number of clients currently held in the cache.
+    pub fn cached_clients(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Preloads the most recently active clients into the cache, up to its capacity.
+    ///
+    /// Meant to be called right after opening the DB, before accepting traffic, so the
+    /// first events after a deploy don't all pay for a cold lookup. Returns the number of
+    /// clients loaded.
+    pub fn warmup(&mut self) -> Result<usize, TransactionError> {
+        let txn = self.db.begin_read().map_err(storage)?;
+        let activity = txn.open_table(ACTIVITY).map_err(storage)?;
+        let clients = txn.open_table(CLIENTS).map_err(storage)?;
+
+        let mut hottest = activity
+            .iter()
+            .map_err(storage)?
+            .map(|entry| {
+                let (id, tick) = entry.map_err(storage)?;
+                Ok((id.value(), tick.value()))
+            })
+            .collect::<Result<Vec<_>, TransactionError>>()?;
+        hottest.sort_by_key(|&(_, tick)| std::cmp::Reverse(tick));
+
+        self.cache.clear();
+        for (client_id, _) in hottest.into_iter().take(self.cache_capacity) {
+            if let Some(value) = clients.get(client_id).map_err(storage)? {
+                self.cache
+                    .insert(client_id, ClientRecord::decode(value.value())?);
+            }
+        }
+
+        Ok(self.cache.len())
+    }
+
+    /// Applies an event to the records inside a write transaction, committing only if it
+    /// succeeds, and then updates the cache with the resulting client.
+    fn apply<F>(&mut self, client_id: ClientId, f: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce(&mut RedbRecords) -> Result<ClientRecord, TransactionError>,
+    {
+        let txn = self.db.begin_write().map_err(storage)?;
+        let client = {
+            let mut records = RedbRecords {
+                txn: &txn,
+                clients: txn.open_table(CLIENTS).map_err(storage)?,
+                transactions: txn.open_table(TRANSACTIONS).map_err(storage)?,
+                cache: &self.cache,
+            };
+            f(&mut records)?
+        };
+        txn.commit().map_err(storage)?;
+
+        if self.cache.contains_key(&client_id) || self.cache.len() < self.cache_capacity {
+            self.cache.insert(client_id, client);
+        }
+
+        Ok(())
+    }
+
+    fn read_clients(&self) -> Result<Vec<ClientInformation>, TransactionError> {
+        let txn = self.db.begin_read().map_err(storage)?;
+        let clients = txn.open_table(CLIENTS).map_err(storage)?;
+
+        clients
+            .iter()
+            .map_err(storage)?
+            .map(|entry| {
+                let (id, value) = entry.map_err(storage)?;
+                let client = ClientRecord::decode(value.value())?;
+                Ok(client.information(id.value()))
+            })
+            .collect()
+    }
+
+    fn read_seq(&self) -> Result<u64, TransactionError> {
+        let txn = self.db.begin_read().map_err(storage)?;
+        let meta = txn.open_table(META).map_err(storage)?;
+
+        Ok(meta
+            .get(META_SEQ)
+            .map_err(storage)?
+            .map_or(0, |seq| seq.value()))
+    }
+
+    fn read_client(&self, client_id: ClientId) -> Result<Option<ClientRecord>, TransactionError> {
+        if let Some(client) = self.cache.get(&client_id) {
+            return Ok(Some(*client));
+        }
+
+        let txn = self.db.begin_read().map_err(storage)?;
+        let clients = txn.open_table(CLIENTS).map_err(storage)?;
+        clients
+            .get(client_id)
+            .map_err(storage)?
+            .map(|value| ClientRecord::decode(value.value()))
+            .transpose()
+    }
+}
+
+impl TransactionProcessor for RedbTransactionDb {
+    fn last_seq(&self) -> u64 {
+        self.read_seq().unwrap_or_else(|err| {
+            error!("failed to read the sequence number: {err}");
+            0
+        })
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(client_id, |records| {
+            record::deposit(records, transaction_id, client_id, amount)
+        })
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(client_id, |records| {
+            record::withdrawal(records, transaction_id, client_id, amount)
+        })
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(client_id, |records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Dispute,
+                max_disputes,
+            )
+        })
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(client_id, |records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Resolve,
+                max_disputes,
+            )
+        })
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let max_disputes = self.max_disputes;
+        self.apply(client_id, |records| {
+            record::update_disputed(
+                records,
+                transaction_id,
+                client_id,
+                DisputeEvent::Chargeback,
+                max_disputes,
+            )
+        })
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.read_client(client_id)
+            .unwrap_or_else(|err| {
+                error!("failed to read client {client_id}: {err}");
+                None
+            })
+            .map(|client| client.information(client_id))
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.read_clients()
+            .unwrap_or_else(|err| {
+                error!("failed to read clients: {err}");
+                Vec::new()
+            })
+            .into_iter()
+    }
+}
+
+impl StateStore for RedbTransactionDb {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        let txn = self.db.begin_read()?;
+        let clients = txn.open_table(CLIENTS)?;
+        let transactions = txn.open_table(TRANSACTIONS)?;
+        let meta = txn.open_table(META)?;
+
+        let mut snapshot = Snapshot {
+            seq: meta.get(META_SEQ)?.map_or(0, |seq| seq.value()),
+            ..Default::default()
+        };
+
+        for entry in clients.iter()? {
+            let (id, value) = entry?;
+            let client = ClientRecord::decode(value.value())?;
+            snapshot.clients.push(client.to_snapshot(id.value()));
+        }
+
+        for entry in transactions.iter()? {
+            let (key, value) = entry?;
+            let (client, tx) = key.value();
+            let transaction = TransactionRecord::decode(value.value())?;
+            snapshot
+                .transactions
+                .push(transaction.to_snapshot(client, tx));
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut clients = txn.open_table(CLIENTS)?;
+            let mut transactions = txn.open_table(TRANSACTIONS)?;
+
+            clients.retain(|_, _| false)?;
+            transactions.retain(|_, _| false)?;
+            txn.open_table(ACTIVITY)?.retain(|_, _| false)?;
+            let mut meta = txn.open_table(META)?;
+            meta.retain(|_, _| false)?;
+            meta.insert(META_SEQ, snapshot.seq)?;
+            meta.insert(META_STATE_VERSION, STATE_VERSION as u64)?;
+
+            for client in &snapshot.clients {
+                let record = ClientRecord::from_snapshot(client);
+                clients.insert(client.id, record.encode().as_slice())?;
+            }
+
+            for transaction in &snapshot.transactions {
+                let record = TransactionRecord::from_snapshot(transaction);
+                transactions.insert(
+                    (transaction.client, transaction.tx),
+                    record.encode().as_slice(),
+                )?;
+            }
+        }
+        txn.commit()?;
+        self.cache.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        memory_processor::InMemoryTransactionDb,
+        snapshot::{ClientSnapshot, TransactionSnapshot},
+    };
+
+    fn open_temp() -> (tempfile::TempDir, RedbTransactionDb) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RedbTransactionDb::open(dir.path().join("octopussy.redb")).unwrap();
+        (dir, db)
+    }
+
+    fn client(db: &RedbTransactionDb, id: ClientId) -> ClientInformation {
+        db.clients_iter().find(|client| client.id == id).unwrap()
+    }
+
+    #[test]
+    fn err_newer_state_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopussy.redb");
+        drop(RedbTransactionDb::open(&path).unwrap());
+
+        {
+            let db = Database::create(&path).unwrap();
+            let txn = db.begin_write().unwrap();
+            txn.open_table(META)
+                .unwrap()
+                .insert(META_STATE_VERSION, STATE_VERSION as u64 + 1)
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        assert!(matches!(
+            RedbTransactionDb::open(&path),
+            Err(TransactionError::Storage(message)) if message.contains("newer release")
+        ));
+    }
+
+    #[test]
+    fn deposit_withdraw() {
+        let (_dir, mut db) = open_temp();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(4)).unwrap();
+
+        assert_eq!(client(&db, 1).available, dec!(6));
+        assert_eq!(
+            db.withdrawal(3, 1, dec!(7)),
+            Err(TransactionError::InsufficientFunds {
+                client_id: 1,
+                transaction_id: 3,
+                available: dec!(6),
+                amount: dec!(7)
+            })
+        );
+        assert_eq!(
+            db.deposit(1, 1, dec!(1)),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn dispute_chargeback() {
+        let (_dir, mut db) = open_temp();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.dispute(2, 1).unwrap();
+
+        let client_1 = client(&db, 1);
+        assert_eq!(client_1.available, dec!(10));
+        assert_eq!(client_1.held, dec!(5));
+
+        assert_eq!(
+            db.dispute(2, 1),
+            Err(TransactionError::AlreadyDisputed {
+                client_id: 1,
+                transaction_id: 2
+            })
+        );
+
+        db.chargeback(2, 1).unwrap();
+        let client_1 = client(&db, 1);
+        assert_eq!(client_1.held, dec!(0));
+        assert!(client_1.frozen);
+
+        assert_eq!(
+            db.deposit(3, 1, dec!(1)),
+            Err(TransactionError::AccountFrozen { client_id: 1 })
+        );
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopussy.redb");
+
+        {
+            let mut db = RedbTransactionDb::open(&path).unwrap();
+            db.deposit(1, 1, dec!(10)).unwrap();
+            db.dispute(1, 1).unwrap();
+        }
+
+        let mut db = RedbTransactionDb::open(&path).unwrap();
+        db.resolve(1, 1).unwrap();
+        assert_eq!(client(&db, 1).available, dec!(10));
+    }
+
+    #[test]
+    fn warmup_loads_most_recent_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopussy.redb");
+
+        {
+            let mut db = RedbTransactionDb::open(&path).unwrap();
+            db.deposit(1, 1, dec!(1)).unwrap();
+            db.deposit(2, 2, dec!(2)).unwrap();
+            db.deposit(3, 3, dec!(3)).unwrap();
+            db.deposit(4, 1, dec!(1)).unwrap();
+        }
+
+        let mut db = RedbTransactionDb::open(&path).unwrap();
+        db.set_cache_capacity(2);
+        assert_eq!(db.warmup().unwrap(), 2);
+
+        let mut cached: Vec<_> = db.cache.keys().copied().collect();
+        cached.sort();
+        assert_eq!(cached, vec![1, 3]);
+        assert_eq!(db.cache[&1].available, dec!(2));
+
+        // Cached clients are kept in sync with the writes
+        db.withdrawal(5, 1, dec!(0.5)).unwrap();
+        assert_eq!(db.cache[&1].available, dec!(1.5));
+        assert_eq!(client(&db, 1).available, dec!(1.5));
+
+        // A failed write doesn't leak into the cache
+        db.withdrawal(6, 1, dec!(10)).unwrap_err();
+        assert_eq!(db.cache[&1].available, dec!(1.5));
+    }
+
+    #[test]
+    fn matches_memory_snapshot() {
+        let (_dir, mut redb) = open_temp();
+        let mut memory = InMemoryTransactionDb::new();
+
+        for db in [&mut redb as &mut dyn StateStore, &mut memory] {
+            let mut snapshot = Snapshot::default();
+            snapshot.clients.push(ClientSnapshot {
+                id: 7,
+                available: dec!(1.5),
+                held: dec!(2),
+                frozen: false,
+            });
+            snapshot.transactions.push(TransactionSnapshot {
+                client: 7,
+                tx: 1,
+                amount: dec!(2),
+                disputed: true,
+                charged_back: false,
+                seq: 1,
+                disputes: 1,
+                recorded_at: 0,
+                created_at: None,
+            });
+            db.restore(snapshot).unwrap();
+        }
+
+        // Only the memory backend keeps the time transactions were recorded at
+        let mut expected = memory.snapshot().unwrap();
+        for transaction in &mut expected.transactions {
+            transaction.created_at = None;
+        }
+        assert_eq!(redb.snapshot().unwrap(), expected);
+    }
+}
@@ -0,0 +1,182 @@
+//! A consumer applying the events of Redis Streams through a consumer group, so several
+//! workers can share them.
+
+use std::{num::NonZeroU16, time::Duration};
+
+use anyhow::Context;
+use redis::{
+    Commands, Connection,
+    streams::{StreamId, StreamReadOptions, StreamReadReply},
+};
+use tracing::{error, info};
+
+use crate::{
+    drain::DrainSignal,
+    metrics::RunMetrics,
+    split::shard_of,
+    stream::{Disposition, PayloadFormat, PendingAcks, SaveState, apply_message},
+    transaction::{ClientId, TransactionProcessor},
+};
+
+/// The stream of `client` when the events of `stream` are partitioned in `partitions`
+/// streams, `<stream>:<partition>`.
+///
+/// Producers add the events of a client to its stream, and every worker consumes a set of
+/// the partitions, so the events of a client are applied in order by a single worker. A
+/// consumer group alone hands the entries of a stream to its consumers round-robin, which
+/// could apply a dispute before the deposit it refers to.
+pub fn partition_stream(stream: &str, client: ClientId, partitions: NonZeroU16) -> String {
+    partition_name(stream, shard_of(client, partitions))
+}
+
+/// The stream of `partition` of `stream`, see [`partition_stream`].
+pub fn partition_name(stream: &str, partition: u16) -> String {
+    format!("{stream}:{partition}")
+}
+
+/// Where to consume events from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisStreamConfig {
+    /// Server URL, eg `redis://localhost:6379`
+    pub url: String,
+    /// Streams this worker consumes, eg partitions named by [`partition_stream`]
+    pub streams: Vec<String>,
+    /// Consumer group, created at the start of the streams if missing
+    pub group: String,
+    /// Name of this worker in the group, which has to stay the same across restarts to
+    /// pick up its pending entries
+    pub consumer: String,
+    /// Field of the entries holding the encoded event
+    pub field: String,
+    pub format: PayloadFormat,
+}
+
+/// Consumes events from Redis Streams as a member of a consumer group, acknowledging entries
+/// with `XACK` only once their events were applied (or rejected by the rules) and the state
+/// they were applied to was saved.
+///
+/// Delivery is at least once: entries which weren't acknowledged, eg because of a crash, stay
+/// pending for the consumer, and are read again first on the next run. Deposits and
+/// withdrawals the saved state already includes are then rejected as duplicates, so they
+/// aren't applied twice.
+pub struct RedisStreamSource {
+    connection: Connection,
+    config: RedisStreamConfig,
+    /// Entries to read next, `0` for the pending ones and `>` for new ones
+    next_id: &'static str,
+}
+
+impl RedisStreamSource {
+    /// Entries read at once
+    const BATCH: usize = 100;
+
+    pub fn connect(config: &RedisStreamConfig) -> anyhow::Result<Self> {
+        let mut connection = redis::Client::open(config.url.as_str())
+            .and_then(|client| client.get_connection())
+            .with_context(|| format!("failed to connect to {}", config.url))?;
+
+        for stream in &config.streams {
+            match connection.xgroup_create_mkstream(stream, &config.group, "0") {
+                Err(err) if err.code() == Some("BUSYGROUP") => {}
+                result => result.with_context(|| {
+                    format!("failed to create the group {} of {stream}", config.group)
+                })?,
+            }
+        }
+
+        Ok(Self {
+            connection,
+            config: config.clone(),
+            next_id: "0",
+        })
+    }
+
+    /// Applies the events of the streams to `db` until `drain` is requested, starting with the
+    /// entries left pending by the previous run. The entries of every read are acknowledged
+    /// once `save` saved `db`.
+    ///
+    /// Entries without a decodable event are logged and acknowledged. A storage error stops
+    /// the run without acknowledging its entry, so it's read again on the next run.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        save: &mut SaveState<'_, DB>,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+        let options = StreamReadOptions::default()
+            .group(&self.config.group, &self.config.consumer)
+            .count(Self::BATCH)
+            .block(poll.as_millis().try_into().unwrap_or(usize::MAX));
+
+        while !drain.is_draining() {
+            let ids = vec![self.next_id; self.config.streams.len()];
+            let reply: Option<StreamReadReply> = self
+                .connection
+                .xread_options(&self.config.streams, &ids, &options)
+                .context("failed to read from Redis")?;
+            let keys = reply.map(|reply| reply.keys).unwrap_or_default();
+            if self.next_id == "0" && keys.iter().all(|key| key.ids.is_empty()) {
+                info!("Read the pending entries, reading new ones");
+                self.next_id = ">";
+            }
+
+            let mut acks = PendingAcks::new();
+            let mut failed = None;
+            'read: for key in keys {
+                for entry in key.ids {
+                    let position = format!("{} entry {}", key.key, entry.id);
+                    let payload = self.payload(&entry);
+                    match apply_message(db, self.config.format, &payload, &mut metrics) {
+                        Disposition::Done => {}
+                        Disposition::Invalid(err) => error!("Skipping {position}: {err:#}"),
+                        Disposition::Retry(err) => {
+                            failed = Some(err.context(format!("failed to apply {position}")));
+                            break 'read;
+                        }
+                    }
+                    acks.push((key.key.clone(), entry.id));
+                }
+            }
+
+            // The entries before a failed one are done with too
+            acks.flush(db, save, |(stream, id)| {
+                let _: usize = self
+                    .connection
+                    .xack(&stream, &self.config.group, &[&id])
+                    .with_context(|| format!("failed to acknowledge {stream} entry {id}"))?;
+                Ok(())
+            })?;
+            if let Some(err) = failed {
+                return Err(err);
+            }
+        }
+        info!("Draining, unacknowledged entries stay pending");
+
+        Ok(metrics)
+    }
+
+    /// The encoded event of `entry`, empty if it has none so it's invalid
+    fn payload(&self, entry: &StreamId) -> Vec<u8> {
+        entry.get(&self.config.field).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partitions_by_client() {
+        let partitions = NonZeroU16::new(4).unwrap();
+        let stream = partition_stream("transactions", 7, partitions);
+
+        assert_eq!(stream, format!("transactions:{}", shard_of(7, partitions)));
+        assert_eq!(partition_stream("transactions", 7, partitions), stream);
+        assert_eq!(
+            partition_stream("transactions", 7, NonZeroU16::MIN),
+            "transactions:0"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! A [`TransactionProcessor`] applying events on a remote engine, served by `octopussy grpc`,
+//! so front-ends like the CSV one can feed a central ledger service instead of local state.
+
+use anyhow::{Context, bail};
+use rust_decimal::Decimal;
+use tokio::runtime::Runtime;
+use tonic::{Code, Status, transport::Channel};
+use tracing::error;
+
+use crate::{
+    grpc::{
+        AmountRequest, ClientRequest, ClientState, EngineClient, StreamClientsRequest,
+        TransactionRequest, transaction_error,
+    },
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionId, TransactionProcessor,
+    },
+};
+
+/// The error of a failed call: the rejection of the remote engine, or a
+/// [`TransactionError::Storage`] error if it couldn't tell, eg because it's unreachable.
+fn rejection(status: &Status) -> TransactionError {
+    transaction_error(status)
+        .unwrap_or_else(|| TransactionError::Storage(format!("remote engine: {status}")))
+}
+
+/// Forwards every event to the `octopussy.Engine` gRPC service of a remote engine, one call
+/// at a time, and reads the clients from it.
+///
+/// Rejections of the remote engine are returned as they are, other failures are
+/// [`TransactionError::Storage`] errors. The state lives on the remote engine, so it can't be
+/// snapshotted or restored from here.
+///
+/// The remote engine doesn't share its sequence numbers: [`TransactionProcessor::last_seq`]
+/// counts the events applied through this processor.
+pub struct RemoteTransactionProcessor {
+    runtime: Runtime,
+    client: EngineClient<Channel>,
+    endpoint: String,
+    seq: u64,
+}
+
+impl RemoteTransactionProcessor {
+    /// Connects to the engine at `endpoint`, eg `http://ledger:50051`.
+    pub fn connect(endpoint: &str) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("failed to start the gRPC runtime")?;
+        let client = runtime
+            .block_on(EngineClient::connect(endpoint.to_owned()))
+            .with_context(|| format!("failed to connect to {endpoint}"))?;
+
+        Ok(Self {
+            runtime,
+            client,
+            endpoint: endpoint.to_owned(),
+            seq: 0,
+        })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Applies an event with `call`, which returns the state of its client
+    fn apply<F>(&mut self, call: F) -> Result<(), TransactionError>
+    where
+        F: AsyncFnOnce(&mut EngineClient<Channel>) -> Result<tonic::Response<ClientState>, Status>,
+    {
+        let mut client = self.client.clone();
+        self.runtime
+            .block_on(call(&mut client))
+            .map_err(|status| rejection(&status))?;
+        self.seq += 1;
+
+        Ok(())
+    }
+
+    /// The amount is sent as it is: the remote engine does its own rounding, if any
+    fn amount_request(
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> AmountRequest {
+        AmountRequest {
+            client: client_id.into(),
+            tx: transaction_id,
+            amount: amount.to_string(),
+        }
+    }
+
+    fn transaction_request(
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> TransactionRequest {
+        TransactionRequest {
+            client: client_id.into(),
+            tx: transaction_id,
+        }
+    }
+
+    fn read_clients(&self) -> Result<Vec<ClientInformation>, Status> {
+        let mut client = self.client.clone();
+        self.runtime.block_on(async {
+            let mut stream = client
+                .stream_clients(StreamClientsRequest {})
+                .await?
+                .into_inner();
+            let mut clients = Vec::new();
+            while let Some(state) = stream.message().await? {
+                clients.push(state.try_into()?);
+            }
+
+            Ok(clients)
+        })
+    }
+}
+
+impl TransactionProcessor for RemoteTransactionProcessor {
+    fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let request = Self::amount_request(transaction_id, client_id, amount);
+        self.apply(async |client| client.deposit(request).await)
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let request = Self::amount_request(transaction_id, client_id, amount);
+        self.apply(async |client| client.withdraw(request).await)
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let request = Self::transaction_request(transaction_id, client_id);
+        self.apply(async |client| client.dispute(request).await)
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let request = Self::transaction_request(transaction_id, client_id);
+        self.apply(async |client| client.resolve(request).await)
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        let request = Self::transaction_request(transaction_id, client_id);
+        self.apply(async |client| client.chargeback(request).await)
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.read_clients()
+            .unwrap_or_else(|status| {
+                error!("failed to read clients from {}: {status}", self.endpoint);
+                Vec::new()
+            })
+            .into_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        let mut client = self.client.clone();
+        let request = ClientRequest {
+            client: client_id.into(),
+        };
+        let state = match self.runtime.block_on(client.get_client(request)) {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::NotFound => return None,
+            Err(status) => {
+                error!(
+                    "failed to read client {client_id} from {}: {status}",
+                    self.endpoint
+                );
+                return None;
+            }
+        };
+
+        state
+            .try_into()
+            .inspect_err(|status| error!("invalid client from {}: {status}", self.endpoint))
+            .ok()
+    }
+}
+
+impl StateStore for RemoteTransactionProcessor {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        bail!(
+            "the state of the remote engine {} can't be exported",
+            self.endpoint
+        )
+    }
+
+    fn restore(&mut self, _: Snapshot) -> anyhow::Result<()> {
+        bail!(
+            "the state of the remote engine {} can't be replaced",
+            self.endpoint
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        grpc::{EngineServer, EngineService},
+        memory_processor::InMemoryTransactionDb,
+    };
+
+    /// Serves `db` on its own runtime, like `octopussy grpc`, and connects to it
+    fn serve(db: Arc<Mutex<InMemoryTransactionDb>>) -> RemoteTransactionProcessor {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let service = EngineServer::new(EngineService::new(db));
+        thread::spawn(move || {
+            let runtime = Runtime::new().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let incoming = futures::stream::unfold(listener, async |listener| {
+                    let stream = listener.accept().await.map(|(stream, _)| stream);
+                    Some((stream, listener))
+                });
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming)
+                    .await
+            })
+        });
+
+        RemoteTransactionProcessor::connect(&format!("http://{address}")).unwrap()
+    }
+
+    #[test]
+    fn forwards_events() {
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        let mut remote = serve(db.clone());
+        remote.deposit(1, 1, dec!(10.5)).unwrap();
+        remote.deposit(2, 1, dec!(2)).unwrap();
+        remote.withdrawal(3, 1, dec!(0.5)).unwrap();
+        remote.dispute(2, 1).unwrap();
+        assert_eq!(remote.last_seq(), 4);
+        assert_eq!(
+            remote.withdrawal(4, 1, dec!(20)),
+            Err(TransactionError::InsufficientFunds {
+                client_id: 1,
+                transaction_id: 4,
+                available: dec!(10),
+                amount: dec!(20),
+            })
+        );
+        assert_eq!(remote.last_seq(), 4);
+
+        let client = remote.client(1).unwrap();
+        assert_eq!(client.held, dec!(2));
+        assert_eq!(client.total, dec!(12));
+        assert_eq!(remote.client(2), None);
+        assert_eq!(remote.clients_iter().count(), 1);
+        assert_eq!(db.lock().unwrap().last_seq(), 4);
+        assert!(remote.snapshot().is_err());
+    }
+
+    #[test]
+    fn forwards_exact_amounts() {
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        let mut remote = serve(db.clone());
+        remote.deposit(1, 1, dec!(1.23456)).unwrap();
+        remote.withdrawal(2, 1, dec!(0.00001)).unwrap();
+
+        assert_eq!(db.lock().unwrap().client(1).unwrap().total, dec!(1.23455));
+    }
+}
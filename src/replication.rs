@@ -0,0 +1,377 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::BackupArchive,
+    clock::Timestamp,
+    memory_processor::FrozenClients,
+    snapshot::{ClientSnapshot, Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// One entry of the change stream: an applied event and the client's balances right
+/// after it was applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    /// Position in the stream, starting at 1 and increasing by exactly one per record
+    pub seq: u64,
+    pub event: TransactionEvent,
+    pub client: ClientSnapshot,
+    /// Extra columns the event was read with, if they were kept
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
+
+/// Wraps a [`TransactionProcessor`] and writes a [`ChangeRecord`] as a line of JSON for
+/// every event it applies successfully. Rejected events are not part of the stream.
+///
+/// Records carry the sequence number the DB assigned to the event, so a stream written by
+/// a DB restored from a backup continues right after the backup's position.
+///
+/// The stream is meant to be consumed by a [`ReadReplica`].
+pub struct ChangeStreamProcessor<DB, W> {
+    inner: DB,
+    writer: W,
+}
+
+impl<DB, W> ChangeStreamProcessor<DB, W>
+where
+    DB: TransactionProcessor,
+    W: Write,
+{
+    pub fn new(inner: DB, writer: W) -> Self {
+        Self { inner, writer }
+    }
+
+    /// Sequence number of the last record written
+    pub fn seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, W) {
+        (self.inner, self.writer)
+    }
+
+    fn emit(
+        &mut self,
+        event: TransactionEvent,
+        metadata: Metadata,
+    ) -> Result<(), TransactionError> {
+        let client_id = event.client();
+        let Some(client) = self.inner.client(client_id) else {
+            return Err(TransactionError::ClientNotFound { client_id });
+        };
+
+        let record = ChangeRecord {
+            seq: self.inner.last_seq(),
+            event,
+            client: ClientSnapshot {
+                id: client.id,
+                available: client.available,
+                held: client.held,
+                frozen: client.frozen,
+            },
+            metadata,
+        };
+
+        let write = |writer: &mut W| -> std::io::Result<()> {
+            serde_json::to_writer(&mut *writer, &record)?;
+            writer.write_all(b"\n")
+        };
+        write(&mut self.writer)
+            .map_err(|err| TransactionError::Storage(format!("change stream: {err}")))
+    }
+}
+
+impl<DB, W> TransactionProcessor for ChangeStreamProcessor<DB, W>
+where
+    DB: TransactionProcessor,
+    W: Write,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.deposit(transaction_id, client_id, amount)?;
+        self.emit(
+            TransactionEvent::Deposit {
+                tx: transaction_id,
+                client: client_id,
+                amount,
+            },
+            Metadata::new(),
+        )
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.withdrawal(transaction_id, client_id, amount)?;
+        self.emit(
+            TransactionEvent::Withdrawal {
+                tx: transaction_id,
+                client: client_id,
+                amount,
+            },
+            Metadata::new(),
+        )
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.dispute(transaction_id, client_id)?;
+        self.emit(
+            TransactionEvent::Dispute {
+                tx: transaction_id,
+                client: client_id,
+            },
+            Metadata::new(),
+        )
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.resolve(transaction_id, client_id)?;
+        self.emit(
+            TransactionEvent::Resolve {
+                tx: transaction_id,
+                client: client_id,
+            },
+            Metadata::new(),
+        )
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.chargeback(transaction_id, client_id)?;
+        self.emit(
+            TransactionEvent::Chargeback {
+                tx: transaction_id,
+                client: client_id,
+            },
+            Metadata::new(),
+        )
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.inner
+            .process_annotated_event(transaction.clone(), metadata)?;
+        self.emit(transaction, metadata.clone())
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB, W> ChangeStreamProcessor<DB, W>
+where
+    DB: TransactionProcessor + StateStore,
+{
+    /// Takes a backup tagged with the current stream position, so a replica restored from
+    /// it knows which records to skip.
+    pub fn backup(&self) -> anyhow::Result<BackupArchive> {
+        BackupArchive::create(&self.inner, Some(self.inner.last_seq()))
+    }
+}
+
+impl<DB, W> StateStore for ChangeStreamProcessor<DB, W>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ReplicationError {
+    #[error("change stream gap: expected record {expected}, got {got}")]
+    Gap { expected: u64, got: u64 },
+}
+
+/// Client balances maintained purely from a change stream, for reporting queries that
+/// shouldn't load the primary.
+#[derive(Debug, Default)]
+pub struct ReadReplica {
+    clients: BTreeMap<ClientId, ClientSnapshot>,
+    last_seq: u64,
+}
+
+impl ReadReplica {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number of the last applied record
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Applies a change record.
+    ///
+    /// Records that were already applied are ignored, so a stream can safely be re-read
+    /// from the start.
+    ///
+    /// ## Errors
+    /// - If records were skipped, returns [`ReplicationError::Gap`]
+    pub fn apply(&mut self, record: ChangeRecord) -> Result<(), ReplicationError> {
+        if record.seq <= self.last_seq {
+            return Ok(());
+        }
+
+        if record.seq != self.last_seq + 1 {
+            return Err(ReplicationError::Gap {
+                expected: self.last_seq + 1,
+                got: record.seq,
+            });
+        }
+
+        self.clients.insert(record.client.id, record.client);
+        self.last_seq = record.seq;
+
+        Ok(())
+    }
+
+    /// Applies every record of a JSON lines change stream until EOF.
+    pub fn follow<R: BufRead>(&mut self, reader: R) -> anyhow::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            self.apply(serde_json::from_str(&line)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Clients ordered by id
+    pub fn clients(&self) -> impl Iterator<Item = ClientInformation> + '_ {
+        self.clients.values().map(|client| ClientInformation {
+            id: client.id,
+            available: client.available,
+            held: client.held,
+            total: client.available + client.held,
+            frozen: client.frozen,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[test]
+    fn replica_follows_primary() {
+        let mut primary = ChangeStreamProcessor::new(InMemoryTransactionDb::new(), Vec::new());
+        primary.deposit(1, 1, dec!(10)).unwrap();
+        primary.deposit(2, 2, dec!(5)).unwrap();
+        primary.withdrawal(3, 1, dec!(20)).unwrap_err();
+        primary.dispute(2, 2).unwrap();
+        primary.chargeback(2, 2).unwrap();
+        assert_eq!(primary.seq(), 4);
+
+        let (primary, stream) = primary.into_inner();
+        let mut replica = ReadReplica::new();
+        replica.follow(stream.as_slice()).unwrap();
+
+        let mut expected: Vec<_> = primary.clients_iter().collect();
+        expected.sort_by_key(|client| client.id);
+        assert_eq!(replica.clients().collect::<Vec<_>>(), expected);
+        assert_eq!(replica.last_seq(), 4);
+
+        // Re-reading the same stream is a no-op
+        replica.follow(stream.as_slice()).unwrap();
+        assert_eq!(replica.last_seq(), 4);
+    }
+
+    #[test]
+    fn err_gap() {
+        let mut replica = ReadReplica::new();
+        let record = ChangeRecord {
+            seq: 2,
+            event: TransactionEvent::Deposit {
+                tx: 1,
+                client: 1,
+                amount: dec!(1),
+            },
+            client: ClientSnapshot {
+                id: 1,
+                available: dec!(1),
+                held: dec!(0),
+                frozen: false,
+            },
+            metadata: Metadata::new(),
+        };
+
+        assert_eq!(
+            replica.apply(record),
+            Err(ReplicationError::Gap {
+                expected: 1,
+                got: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,511 @@
+use std::{collections::BTreeMap, fmt, io::Write, num::NonZeroU64, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    amount,
+    clock::Timestamp,
+    i18n::Message,
+    metrics::{ClientActivity, ClientKey, RunMetrics},
+    tenant::TenantId,
+    transaction::{ClientId, ClientInformation},
+};
+
+/// What clients are ranked by in a [`TopReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopMetric {
+    Available,
+    Held,
+    Chargebacks,
+    Rejections,
+}
+
+impl TopMetric {
+    pub const ALL: [TopMetric; 4] = [
+        Self::Available,
+        Self::Held,
+        Self::Chargebacks,
+        Self::Rejections,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Available => "available",
+            Self::Held => "held",
+            Self::Chargebacks => "chargebacks",
+            Self::Rejections => "rejections",
+        }
+    }
+
+    fn message_id(self) -> &'static str {
+        match self {
+            Self::Available => "top-available",
+            Self::Held => "top-held",
+            Self::Chargebacks => "top-chargebacks",
+            Self::Rejections => "top-rejections",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("unknown top-N metric {0}, expected one of available, held, chargebacks, rejections")]
+pub struct UnknownTopMetric(String);
+
+impl FromStr for TopMetric {
+    type Err = UnknownTopMetric;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|metric| metric.as_str() == name)
+            .ok_or_else(|| UnknownTopMetric(name.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopEntry {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub value: Decimal,
+}
+
+/// The clients with the largest balances, and the most chargebacks and rejected events, of
+/// a run. Meant as a compact section of the run summary for ops to review.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopReport {
+    pub lists: Vec<(TopMetric, Vec<TopEntry>)>,
+}
+
+impl TopReport {
+    /// Ranks the clients by each of `metrics`, keeping the `n` first. Clients with a value of
+    /// zero or less aren't listed, and ties are ordered by tenant and client id.
+    ///
+    /// Chargebacks and rejections come from `activity`, so they also cover clients which
+    /// don't exist anymore, or never did.
+    pub fn new<I>(n: usize, metrics: &[TopMetric], clients: I, activity: &ClientActivity) -> Self
+    where
+        I: IntoIterator<Item = (Option<TenantId>, ClientInformation)>,
+    {
+        let clients: Vec<_> = clients.into_iter().collect();
+
+        let lists = metrics
+            .iter()
+            .map(|&metric| {
+                let mut entries: Vec<(ClientKey, Decimal)> = match metric {
+                    TopMetric::Available => clients
+                        .iter()
+                        .map(|(tenant, client)| ((*tenant, client.id), client.available))
+                        .collect(),
+                    TopMetric::Held => clients
+                        .iter()
+                        .map(|(tenant, client)| ((*tenant, client.id), client.held))
+                        .collect(),
+                    TopMetric::Chargebacks => activity
+                        .iter()
+                        .map(|(client, counters)| (client, counters.chargebacks.into()))
+                        .collect(),
+                    TopMetric::Rejections => activity
+                        .iter()
+                        .map(|(client, counters)| (client, counters.rejections.into()))
+                        .collect(),
+                };
+
+                entries.retain(|(_, value)| value.is_sign_positive() && !value.is_zero());
+                entries.sort_unstable_by(|(a, a_value), (b, b_value)| {
+                    b_value.cmp(a_value).then(a.cmp(b))
+                });
+                entries.truncate(n);
+
+                let entries = entries
+                    .into_iter()
+                    .map(|((tenant, client), value)| TopEntry {
+                        tenant,
+                        client,
+                        value,
+                    })
+                    .collect();
+                (metric, entries)
+            })
+            .collect();
+
+        Self { lists }
+    }
+}
+
+impl fmt::Display for TopReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (metric, entries) in &self.lists {
+            writeln!(
+                f,
+                "{}",
+                Message::new(metric.message_id()).arg("count", entries.len())
+            )?;
+            for entry in entries {
+                let message = match entry.tenant {
+                    Some(tenant) => Message::new("top-tenant-client").arg("tenant", tenant),
+                    None => Message::new("top-client"),
+                };
+                writeln!(
+                    f,
+                    "  {}",
+                    message
+                        .arg("client", entry.client)
+                        .arg("value", entry.value)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Totals of a run, for ops to check it at a glance: how many events were applied or
+/// rejected and why, and how many clients it left with how much money.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RunSummary {
+    pub events: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Rejected events by the message id of their error, eg `insufficient-funds`
+    pub rejections: BTreeMap<&'static str, u64>,
+    pub clients: u64,
+    /// Clients whose account is frozen, after a chargeback
+    pub frozen: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    pub available: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub held: Decimal,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: Decimal,
+}
+
+impl RunSummary {
+    /// Sums up a run which processed the events of `metrics` and left `clients`
+    pub fn new<I>(metrics: &RunMetrics, clients: I) -> Self
+    where
+        I: IntoIterator<Item = (Option<TenantId>, ClientInformation)>,
+    {
+        let events = metrics.latencies.count();
+        let rejected = metrics.rejections.values().sum();
+        let mut summary = Self {
+            events,
+            accepted: events.saturating_sub(rejected),
+            rejected,
+            rejections: metrics.rejections.clone(),
+            ..Self::default()
+        };
+        for (_, client) in clients {
+            summary.clients += 1;
+            summary.frozen += u64::from(client.frozen);
+            summary.available += client.available;
+            summary.held += client.held;
+            summary.total += client.total;
+        }
+
+        summary
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}",
+            Message::new("summary-events")
+                .arg("count", self.events)
+                .arg("accepted", self.accepted)
+                .arg("rejected", self.rejected)
+        )?;
+        if !self.rejections.is_empty() {
+            writeln!(f, "{}", Message::new("summary-rejections"))?;
+            for (reason, count) in &self.rejections {
+                writeln!(f, "  {reason}: {count}")?;
+            }
+        }
+        writeln!(
+            f,
+            "{}",
+            Message::new("summary-clients")
+                .arg("count", self.clients)
+                .arg("frozen", self.frozen)
+        )?;
+        writeln!(
+            f,
+            "{}",
+            Message::new("summary-balances")
+                .arg("available", amount::format(self.available))
+                .arg("held", amount::format(self.held))
+                .arg("total", amount::format(self.total))
+        )
+    }
+}
+
+/// Most time windows an [`ActivityHeatmap`] spans, so a stray timestamp can't blow up the
+/// matrix.
+pub const MAX_HEATMAP_WINDOWS: usize = 10_000;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum HeatmapError {
+    #[error(
+        "the timestamps span {windows} windows, more than {MAX_HEATMAP_WINDOWS}: use a larger window"
+    )]
+    TooManyWindows { windows: u64 },
+}
+
+/// Number of events of each client per time window, from the events which had a timestamp.
+///
+/// Windows are contiguous from the first to the last one with events, so gaps show up as
+/// columns of zeroes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActivityHeatmap {
+    pub window: NonZeroU64,
+    /// Start of the first window
+    pub start: Timestamp,
+    /// Events per window, for each client
+    pub clients: BTreeMap<ClientKey, Vec<u64>>,
+}
+
+impl ActivityHeatmap {
+    pub fn new(activity: &ClientActivity, window: NonZeroU64) -> Result<Self, HeatmapError> {
+        let bucket = |timestamp: Timestamp| timestamp / window;
+
+        let timestamps = activity
+            .timeline()
+            .flat_map(|(_, events)| {
+                events
+                    .first_key_value()
+                    .into_iter()
+                    .chain(events.last_key_value())
+            })
+            .map(|(&timestamp, _)| bucket(timestamp));
+        let (first, last) = timestamps.fold((u64::MAX, 0), |(first, last), bucket| {
+            (first.min(bucket), last.max(bucket))
+        });
+        if first > last {
+            return Ok(Self {
+                window,
+                start: 0,
+                clients: BTreeMap::new(),
+            });
+        }
+
+        let windows = last - first + 1;
+        if windows > MAX_HEATMAP_WINDOWS as u64 {
+            return Err(HeatmapError::TooManyWindows { windows });
+        }
+
+        let clients = activity
+            .timeline()
+            .map(|(client, events)| {
+                let mut counts = vec![0; windows as usize];
+                for (&timestamp, count) in events {
+                    counts[(bucket(timestamp) - first) as usize] += count;
+                }
+                (client, counts)
+            })
+            .collect();
+
+        Ok(Self {
+            window,
+            start: first * window.get(),
+            clients,
+        })
+    }
+
+    /// Start of each window
+    pub fn windows(&self) -> impl Iterator<Item = Timestamp> + '_ {
+        let windows = self.clients.values().next().map_or(0, Vec::len) as u64;
+        (0..windows).map(|window| self.start + window * self.window.get())
+    }
+
+    /// Writes the heatmap as a CSV matrix: a row per client, and a column per window named
+    /// after its start. There's a leading `tenant` column if any client belongs to a tenant.
+    pub fn write_csv<W: Write>(&self, mut csv_writer: csv::Writer<W>) -> anyhow::Result<()> {
+        let tenants = self.clients.keys().any(|(tenant, _)| tenant.is_some());
+
+        let mut header: Vec<String> = Vec::new();
+        if tenants {
+            header.push("tenant".to_owned());
+        }
+        header.push("client".to_owned());
+        header.extend(self.windows().map(|start| start.to_string()));
+        csv_writer.write_record(&header)?;
+
+        for (&(tenant, client), counts) in &self.clients {
+            let mut record = Vec::with_capacity(header.len());
+            if tenants {
+                record.push(tenant.map(|tenant| tenant.to_string()).unwrap_or_default());
+            }
+            record.push(client.to_string());
+            record.extend(counts.iter().map(u64::to_string));
+            csv_writer.write_record(&record)?;
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::transaction::{EventKind, TransactionError};
+
+    fn client(id: ClientId, available: Decimal, held: Decimal) -> ClientInformation {
+        ClientInformation {
+            id,
+            available,
+            held,
+            total: available + held,
+            frozen: false,
+        }
+    }
+
+    #[test]
+    fn ranks_clients() {
+        let clients = vec![
+            (None, client(1, dec!(10), dec!(0))),
+            (None, client(2, dec!(30), dec!(5))),
+            (Some(1), client(1, dec!(30), dec!(0))),
+            (None, client(3, dec!(-4), dec!(0))),
+        ];
+        let mut activity = ClientActivity::default();
+        activity.record((None, 3), EventKind::Chargeback, true);
+        activity.record((None, 1), EventKind::Dispute, true);
+        activity.record((None, 1), EventKind::Withdrawal, false);
+        activity.record((None, 9), EventKind::Dispute, false);
+        activity.record((None, 9), EventKind::Resolve, false);
+
+        let report = TopReport::new(2, &TopMetric::ALL, clients, &activity);
+        let entries = |metric| {
+            report
+                .lists
+                .iter()
+                .find(|(listed, _)| *listed == metric)
+                .map(|(_, entries)| {
+                    entries
+                        .iter()
+                        .map(|entry| (entry.tenant, entry.client, entry.value))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap()
+        };
+
+        assert_eq!(
+            entries(TopMetric::Available),
+            vec![(None, 2, dec!(30)), (Some(1), 1, dec!(30))]
+        );
+        assert_eq!(entries(TopMetric::Held), vec![(None, 2, dec!(5))]);
+        assert_eq!(entries(TopMetric::Chargebacks), vec![(None, 3, dec!(1))]);
+        assert_eq!(
+            entries(TopMetric::Rejections),
+            vec![(None, 9, dec!(2)), (None, 1, dec!(1))]
+        );
+    }
+
+    #[test]
+    fn sums_up_runs() {
+        let mut metrics = RunMetrics::new();
+        let latency = std::time::Duration::from_micros(1);
+        metrics.record((None, 1), EventKind::Deposit, latency, &Ok(()));
+        metrics.record((None, 2), EventKind::Deposit, latency, &Ok(()));
+        metrics.record((None, 2), EventKind::Chargeback, latency, &Ok(()));
+        for tx in [3, 4] {
+            let insufficient = TransactionError::InsufficientFunds {
+                client_id: 1,
+                transaction_id: tx,
+                available: dec!(10),
+                amount: dec!(20),
+            };
+            metrics.record(
+                (None, 1),
+                EventKind::Withdrawal,
+                latency,
+                &Err(insufficient),
+            );
+        }
+        let frozen = TransactionError::AccountFrozen { client_id: 2 };
+        metrics.record((None, 2), EventKind::Deposit, latency, &Err(frozen));
+
+        let mut charged_back = client(2, dec!(0), dec!(0));
+        charged_back.frozen = true;
+        let clients = vec![(None, client(1, dec!(10), dec!(2.5))), (None, charged_back)];
+        let summary = RunSummary::new(&metrics, clients);
+
+        assert_eq!(
+            summary,
+            RunSummary {
+                events: 6,
+                accepted: 3,
+                rejected: 3,
+                rejections: BTreeMap::from([("account-frozen", 1), ("insufficient-funds", 2)]),
+                clients: 2,
+                frozen: 1,
+                available: dec!(10),
+                held: dec!(2.5),
+                total: dec!(12.5),
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "6 events, 3 accepted and 3 rejected\n\
+            Rejected events by reason:\n  \
+            account-frozen: 1\n  \
+            insufficient-funds: 2\n\
+            2 clients, 1 of them frozen\n\
+            Balances: 10.0000 available, 2.5000 held, 12.5000 in total\n"
+        );
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap()["held"],
+            serde_json::json!("2.5000")
+        );
+    }
+
+    #[test]
+    fn heatmap() {
+        let mut activity = ClientActivity::default();
+        for (client, timestamp) in [(1, 3_600), (1, 3_700), (2, 7_300), (1, 14_500)] {
+            activity.record_at((None, client), timestamp);
+        }
+        activity.record_at((Some(4), 1), 10_000);
+
+        let heatmap = ActivityHeatmap::new(&activity, NonZeroU64::new(3_600).unwrap()).unwrap();
+        let mut output = Vec::new();
+        heatmap
+            .write_csv(csv::Writer::from_writer(&mut output))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant,client,3600,7200,10800,14400
+,1,2,0,0,1
+,2,0,1,0,0
+4,1,0,1,0,0
+"
+        );
+    }
+
+    #[test]
+    fn err_heatmap_too_many_windows() {
+        let mut activity = ClientActivity::default();
+        activity.record_at((None, 1), 0);
+        activity.record_at((None, 1), 1_000_000);
+
+        assert_eq!(
+            ActivityHeatmap::new(&activity, NonZeroU64::new(60).unwrap()),
+            Err(HeatmapError::TooManyWindows { windows: 16_667 })
+        );
+    }
+
+    #[test]
+    fn parse_metric() {
+        assert_eq!("held".parse(), Ok(TopMetric::Held));
+        assert_eq!(
+            "frozen".parse::<TopMetric>(),
+            Err(UnknownTopMetric("frozen".to_owned()))
+        );
+    }
+}
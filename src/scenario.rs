@@ -0,0 +1,373 @@
+use std::{fmt, path::Path};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    i18n::Localize,
+    transaction::{ClientId, TransactionEvent, TransactionProcessor},
+};
+
+/// A regression case written as a TOML file: events to apply in order, the errors some of
+/// them are expected to be rejected with, and the balances the clients should end up with.
+///
+/// ```toml
+/// name = "chargebacks freeze the account"
+///
+/// [[events]]
+/// type = "deposit"
+/// client = 1
+/// tx = 1
+/// amount = "10.5"
+///
+/// [[events]]
+/// type = "withdrawal"
+/// client = 1
+/// tx = 2
+/// amount = "20"
+/// error = "insufficient-funds"
+///
+/// [[clients]]
+/// client = 1
+/// available = "10.5"
+/// locked = false
+/// ```
+///
+/// Errors are the message ids of the catalogs, eg `insufficient-funds` or `account-frozen`.
+/// Only the balances a client lists are checked.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Scenario {
+    /// Defaults to the file name
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub events: Vec<ScenarioEvent>,
+    #[serde(default)]
+    pub clients: Vec<ExpectedClient>,
+}
+
+/// An event of a [`Scenario`], and the error it should be rejected with if any
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ScenarioEvent {
+    #[serde(flatten)]
+    pub event: TransactionEvent,
+    pub error: Option<String>,
+}
+
+/// Balances a client should end up with, unset fields aren't checked
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ExpectedClient {
+    pub client: ClientId,
+    pub available: Option<Decimal>,
+    pub held: Option<Decimal>,
+    pub total: Option<Decimal>,
+    pub locked: Option<bool>,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut scenario: Self = toml::from_str(&data)
+            .with_context(|| format!("invalid scenario {}", path.display()))?;
+        if scenario.name.is_empty() {
+            scenario.name = path.display().to_string();
+        }
+
+        Ok(scenario)
+    }
+
+    /// Applies the events to `db`, which should be empty, and checks the outcome.
+    pub fn run<DB: TransactionProcessor>(&self, db: &mut DB) -> ScenarioResult {
+        let mut failures = Vec::new();
+
+        for (index, step) in self.events.iter().enumerate() {
+            let event = index + 1;
+            let error = db
+                .process_transaction_event(step.event.clone())
+                .err()
+                .map(|err| err.message().id());
+
+            match (error, step.error.as_deref()) {
+                (Some(actual), Some(expected)) if actual != expected => {
+                    failures.push(ScenarioFailure::WrongError {
+                        event,
+                        expected: expected.to_owned(),
+                        actual: actual.to_owned(),
+                    });
+                }
+                (Some(actual), None) => failures.push(ScenarioFailure::UnexpectedError {
+                    event,
+                    actual: actual.to_owned(),
+                }),
+                (None, Some(expected)) => failures.push(ScenarioFailure::MissingError {
+                    event,
+                    expected: expected.to_owned(),
+                }),
+                _ => {}
+            }
+        }
+
+        for expected in &self.clients {
+            let Some(client) = db.client(expected.client) else {
+                failures.push(ScenarioFailure::MissingClient {
+                    client: expected.client,
+                });
+                continue;
+            };
+
+            let balances = [
+                ("available", expected.available, client.available),
+                ("held", expected.held, client.held),
+                ("total", expected.total, client.total),
+            ];
+            for (field, expected_amount, actual) in balances {
+                if let Some(expected_amount) = expected_amount
+                    && expected_amount != actual
+                {
+                    failures.push(ScenarioFailure::Balance {
+                        client: expected.client,
+                        field,
+                        expected: expected_amount.to_string(),
+                        actual: actual.to_string(),
+                    });
+                }
+            }
+            if let Some(locked) = expected.locked
+                && locked != client.frozen
+            {
+                failures.push(ScenarioFailure::Balance {
+                    client: expected.client,
+                    field: "locked",
+                    expected: locked.to_string(),
+                    actual: client.frozen.to_string(),
+                });
+            }
+        }
+
+        ScenarioResult {
+            name: self.name.clone(),
+            failures,
+        }
+    }
+}
+
+/// A difference between what a [`Scenario`] expected and what happened. Events are
+/// numbered from 1, in the order of the scenario.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ScenarioFailure {
+    UnexpectedError {
+        event: usize,
+        actual: String,
+    },
+    MissingError {
+        event: usize,
+        expected: String,
+    },
+    WrongError {
+        event: usize,
+        expected: String,
+        actual: String,
+    },
+    MissingClient {
+        client: ClientId,
+    },
+    Balance {
+        client: ClientId,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for ScenarioFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedError { event, actual } => {
+                write!(f, "event {event} was rejected with {actual}")
+            }
+            Self::MissingError { event, expected } => {
+                write!(f, "event {event} was applied, expected {expected}")
+            }
+            Self::WrongError {
+                event,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "event {event} was rejected with {actual}, expected {expected}"
+            ),
+            Self::MissingClient { client } => write!(f, "client {client} does not exist"),
+            Self::Balance {
+                client,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "client {client}: {field} is {actual}, expected {expected}"
+            ),
+        }
+    }
+}
+
+/// The outcome of a [`Scenario`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScenarioResult {
+    pub name: String,
+    pub failures: Vec<ScenarioFailure>,
+}
+
+impl ScenarioResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for ScenarioResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return writeln!(f, "ok {}", self.name);
+        }
+
+        writeln!(f, "FAILED {}", self.name)?;
+        for failure in &self.failures {
+            writeln!(f, "  {failure}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    const SCENARIO: &str = r#"
+name = "chargebacks freeze the account"
+
+[[events]]
+type = "deposit"
+client = 1
+tx = 1
+amount = "10.5"
+
+[[events]]
+type = "withdrawal"
+client = 1
+tx = 2
+amount = 20
+error = "insufficient-funds"
+
+[[events]]
+type = "dispute"
+client = 1
+tx = 1
+
+[[events]]
+type = "chargeback"
+client = 1
+tx = 1
+
+[[events]]
+type = "deposit"
+client = 1
+tx = 3
+amount = 1.25
+error = "account-frozen"
+
+[[clients]]
+client = 1
+available = "0"
+total = "0.00"
+locked = true
+"#;
+
+    #[test]
+    fn passes() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        assert_eq!(scenario.events.len(), 5);
+        assert_eq!(
+            scenario.events[4].event,
+            TransactionEvent::Deposit {
+                tx: 3,
+                client: 1,
+                amount: dec!(1.25),
+            }
+        );
+
+        let result = scenario.run(&mut InMemoryTransactionDb::new());
+        assert!(result.passed(), "{result}");
+    }
+
+    #[test]
+    fn reports_failures() {
+        let scenario: Scenario = toml::from_str(
+            r#"
+[[events]]
+type = "deposit"
+client = 1
+tx = 1
+amount = "5"
+error = "duplicate-transaction"
+
+[[events]]
+type = "withdrawal"
+client = 1
+tx = 1
+amount = "1"
+
+[[events]]
+type = "resolve"
+client = 1
+tx = 1
+error = "transaction-not-found"
+
+[[clients]]
+client = 1
+available = "4"
+held = "0"
+
+[[clients]]
+client = 2
+"#,
+        )
+        .unwrap();
+
+        let result = scenario.run(&mut InMemoryTransactionDb::new());
+        assert_eq!(
+            result.failures,
+            vec![
+                ScenarioFailure::MissingError {
+                    event: 1,
+                    expected: "duplicate-transaction".to_owned(),
+                },
+                ScenarioFailure::UnexpectedError {
+                    event: 2,
+                    actual: "duplicate-transaction".to_owned(),
+                },
+                ScenarioFailure::WrongError {
+                    event: 3,
+                    expected: "transaction-not-found".to_owned(),
+                    actual: "not-disputed".to_owned(),
+                },
+                ScenarioFailure::Balance {
+                    client: 1,
+                    field: "available",
+                    expected: "4".to_owned(),
+                    actual: "5".to_owned(),
+                },
+                ScenarioFailure::MissingClient { client: 2 },
+            ]
+        );
+        assert_eq!(
+            result.to_string().lines().nth(4),
+            Some("  client 1: available is 5, expected 4")
+        );
+    }
+}
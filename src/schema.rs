@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, bail};
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer, ser::SerializeStruct};
+use serde_json::Value;
+
+use crate::{
+    amount::Amount,
+    clock::Timestamp,
+    export::OutputWriter,
+    memory_processor::FrozenClients,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// A column of the client balances output, see [`OutputSchema`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColumn {
+    /// Only in the rows of multi-tenant runs
+    Tenant,
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    /// Number of deposits and withdrawals of the client applied during the run, see
+    /// [`TransactionCounter`]
+    TxCount,
+}
+
+impl OutputColumn {
+    pub const ALL: [OutputColumn; 7] = [
+        Self::Tenant,
+        Self::Client,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+        Self::TxCount,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tenant => "tenant",
+            Self::Client => "client",
+            Self::Available => "available",
+            Self::Held => "held",
+            Self::Total => "total",
+            Self::Locked => "locked",
+            Self::TxCount => "tx_count",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error(
+    "unknown output column {0}, expected one of tenant, client, available, held, total, locked, tx_count"
+)]
+pub struct UnknownOutputColumn(String);
+
+impl FromStr for OutputColumn {
+    type Err = UnknownOutputColumn;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|column| column.as_str() == name)
+            .ok_or_else(|| UnknownOutputColumn(name.to_owned()))
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum OutputSchemaError {
+    #[error("the output needs at least one column")]
+    Empty,
+    #[error("output column {0} is listed more than once")]
+    Duplicate(&'static str),
+}
+
+/// Which columns the client balances are written with, and in which order, for downstream
+/// loaders which are picky about the layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputSchema {
+    columns: Vec<OutputColumn>,
+}
+
+impl OutputSchema {
+    pub fn new(columns: Vec<OutputColumn>) -> Result<Self, OutputSchemaError> {
+        if columns.is_empty() {
+            return Err(OutputSchemaError::Empty);
+        }
+        for (index, column) in columns.iter().enumerate() {
+            if columns[..index].contains(column) {
+                return Err(OutputSchemaError::Duplicate(column.as_str()));
+            }
+        }
+
+        Ok(Self { columns })
+    }
+
+    /// The columns of a [`crate::csv::ClientRow`], or of a [`crate::csv::TenantClientRow`]
+    /// for multi-tenant runs
+    pub fn balances(multi_tenant: bool) -> Self {
+        let columns = if multi_tenant {
+            &OutputColumn::ALL[..6]
+        } else {
+            &OutputColumn::ALL[1..6]
+        };
+
+        Self {
+            columns: columns.to_vec(),
+        }
+    }
+
+    pub fn columns(&self) -> &[OutputColumn] {
+        &self.columns
+    }
+
+    pub fn contains(&self, column: OutputColumn) -> bool {
+        self.columns.contains(&column)
+    }
+}
+
+/// Number of deposits and withdrawals applied per client.
+///
+/// Clones share the same counts, so a [`TransactionCounter`] can update them while a
+/// [`SchemaWriter`] reads them for the `tx_count` column.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionCounts(Arc<Mutex<HashMap<ClientId, u64>>>);
+
+impl TransactionCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, client: ClientId) -> u64 {
+        let counts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        counts.get(&client).copied().unwrap_or_default()
+    }
+
+    fn increment(&self, client: ClientId) {
+        let mut counts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        *counts.entry(client).or_default() += 1;
+    }
+}
+
+/// Writes rows with the columns of an [`OutputSchema`], in its order.
+///
+/// Rows need the fields of a [`crate::csv::ClientRow`] (and the tenant of a
+/// [`crate::csv::TenantClientRow`] for the `tenant` column). `tx_count` is looked up in the
+/// counts set with [`SchemaWriter::with_counts`].
+pub struct SchemaWriter<O> {
+    inner: O,
+    schema: OutputSchema,
+    counts: Option<TransactionCounts>,
+}
+
+impl<O: OutputWriter> SchemaWriter<O> {
+    pub fn new(inner: O, schema: OutputSchema) -> Self {
+        Self {
+            inner,
+            schema,
+            counts: None,
+        }
+    }
+
+    pub fn with_counts(mut self, counts: TransactionCounts) -> Self {
+        self.counts = Some(counts);
+        self
+    }
+}
+
+impl<O: OutputWriter> OutputWriter for SchemaWriter<O> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        let Value::Object(row) = serde_json::to_value(row)? else {
+            bail!("only structs can be written with an output schema");
+        };
+
+        let mut fields = Vec::with_capacity(self.schema.columns.len());
+        for &column in &self.schema.columns {
+            let value = match column {
+                OutputColumn::TxCount => {
+                    let counts = self
+                        .counts
+                        .as_ref()
+                        .context("the tx_count column isn't available for this output")?;
+                    let client = row
+                        .get("client")
+                        .and_then(Value::as_u64)
+                        .and_then(|client| ClientId::try_from(client).ok())
+                        .context("the tx_count column needs a client column")?;
+                    Value::from(counts.get(client))
+                }
+                column => row
+                    .get(column.as_str())
+                    .cloned()
+                    .with_context(|| format!("the rows have no {} column", column.as_str()))?,
+            };
+            fields.push((column, value));
+        }
+
+        self.inner.write_row(&SchemaRow(fields))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.inner.finish()
+    }
+}
+
+/// Serializes as a struct rather than a map, which CSV writers don't support, with the
+/// balances serialized as amounts again
+struct SchemaRow(Vec<(OutputColumn, Value)>);
+
+impl Serialize for SchemaRow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut row = serializer.serialize_struct("SchemaRow", self.0.len())?;
+        for (column, value) in &self.0 {
+            let amount = match (column, value) {
+                (
+                    OutputColumn::Available | OutputColumn::Held | OutputColumn::Total,
+                    Value::String(amount),
+                ) => Decimal::from_str(amount).ok(),
+                _ => None,
+            };
+            match amount {
+                Some(amount) => row.serialize_field(column.as_str(), &Amount(amount))?,
+                None => row.serialize_field(column.as_str(), value)?,
+            }
+        }
+        row.end()
+    }
+}
+
+/// Wraps a [`TransactionProcessor`] and counts the deposits and withdrawals of every
+/// client it applies successfully, for the `tx_count` column of a [`SchemaWriter`].
+///
+/// Only events applied through it are counted, not the ones of a restored state.
+pub struct TransactionCounter<DB> {
+    inner: DB,
+    counts: TransactionCounts,
+}
+
+impl<DB: TransactionProcessor> TransactionCounter<DB> {
+    pub fn new(inner: DB, counts: TransactionCounts) -> Self {
+        Self { inner, counts }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.inner
+    }
+}
+
+impl<DB: TransactionProcessor> TransactionProcessor for TransactionCounter<DB> {
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.deposit(transaction_id, client_id, amount)?;
+        self.counts.increment(client_id);
+        Ok(())
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.inner.withdrawal(transaction_id, client_id, amount)?;
+        self.counts.increment(client_id);
+        Ok(())
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.dispute(transaction_id, client_id)
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.resolve(transaction_id, client_id)
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.chargeback(transaction_id, client_id)
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        let client = transaction.client();
+        let counted = matches!(
+            transaction,
+            TransactionEvent::Deposit { .. } | TransactionEvent::Withdrawal { .. }
+        );
+        self.inner.process_annotated_event(transaction, metadata)?;
+        if counted {
+            self.counts.increment(client);
+        }
+        Ok(())
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.inner.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.inner.frozen_clients()
+    }
+}
+
+impl<DB: StateStore> StateStore for TransactionCounter<DB> {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        csv::{ClientRow, write_clients},
+        export::{ExportFormat, FormatWriter},
+        memory_processor::InMemoryTransactionDb,
+    };
+
+    #[test]
+    fn parses_columns() {
+        let columns: Vec<OutputColumn> = "client,tx_count,total"
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            columns,
+            vec![
+                OutputColumn::Client,
+                OutputColumn::TxCount,
+                OutputColumn::Total
+            ]
+        );
+        assert_eq!(
+            "amount".parse::<OutputColumn>(),
+            Err(UnknownOutputColumn("amount".to_owned()))
+        );
+
+        assert_eq!(OutputSchema::new(vec![]), Err(OutputSchemaError::Empty));
+        assert_eq!(
+            OutputSchema::new(vec![OutputColumn::Held, OutputColumn::Held]),
+            Err(OutputSchemaError::Duplicate("held"))
+        );
+    }
+
+    #[test]
+    fn reorders_and_counts() {
+        let counts = TransactionCounts::new();
+        let mut db = TransactionCounter::new(InMemoryTransactionDb::new(), counts.clone());
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.withdrawal(3, 1, dec!(30)).unwrap_err();
+        db.dispute(1, 1).unwrap();
+        db.process_annotated_event(
+            TransactionEvent::Deposit {
+                tx: 4,
+                client: 2,
+                amount: dec!(1),
+            },
+            &Metadata::new(),
+        )
+        .unwrap();
+
+        let schema = OutputSchema::new(vec![
+            OutputColumn::Total,
+            OutputColumn::Client,
+            OutputColumn::TxCount,
+        ])
+        .unwrap();
+        let mut output = Vec::new();
+        let writer = SchemaWriter::new(FormatWriter::new(ExportFormat::Csv, &mut output), schema)
+            .with_counts(counts);
+        let mut db = db.into_inner();
+        db.deposit(5, 2, dec!(1)).unwrap();
+        write_clients(writer, &db).unwrap();
+
+        let mut lines: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines[1..].sort_unstable();
+        assert_eq!(
+            lines,
+            vec!["total,client,tx_count", "2.0000,2,1", "7.0000,1,2"]
+        );
+    }
+
+    #[test]
+    fn missing_columns() {
+        let row = ClientRow {
+            client: 1,
+            available: dec!(1),
+            held: dec!(0),
+            total: dec!(1),
+            locked: false,
+        };
+
+        let schema = OutputSchema::new(vec![OutputColumn::Tenant]).unwrap();
+        let mut writer = SchemaWriter::new(csv::Writer::from_writer(Vec::new()), schema);
+        assert!(writer.write_row(&row).is_err());
+
+        let schema = OutputSchema::new(vec![OutputColumn::TxCount]).unwrap();
+        let mut writer = SchemaWriter::new(csv::Writer::from_writer(Vec::new()), schema);
+        assert!(writer.write_row(&row).is_err());
+    }
+}
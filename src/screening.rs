@@ -0,0 +1,434 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Timestamp,
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, Metadata, TransactionError, TransactionEvent, TransactionId,
+        TransactionProcessor,
+    },
+};
+
+/// Checks clients against a sanctions (eg OFAC) or other watch list.
+pub trait Screener {
+    /// Returns the list entry the client matches, if any.
+    fn screen(&self, client_id: ClientId) -> anyhow::Result<Option<String>>;
+}
+
+/// A screening list loaded from a CSV file with `client` and `entry` columns, `entry` being
+/// the id of the matching list entry (eg an SDN number).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileScreener {
+    listed: HashMap<ClientId, String>,
+}
+
+#[derive(Deserialize)]
+struct ListedClient {
+    client: ClientId,
+    entry: String,
+}
+
+impl FileScreener {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = csv::ReaderBuilder::default()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: std::io::Read>(mut reader: csv::Reader<R>) -> anyhow::Result<Self> {
+        let listed = reader
+            .deserialize()
+            .map(|row| row.map(|row: ListedClient| (row.client, row.entry)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { listed })
+    }
+}
+
+impl Screener for FileScreener {
+    fn screen(&self, client_id: ClientId) -> anyhow::Result<Option<String>> {
+        Ok(self.listed.get(&client_id).cloned())
+    }
+}
+
+/// What happens to a client matching the screening list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreeningAction {
+    /// Apply the event, then freeze the account: no further deposits or withdrawals
+    #[default]
+    Freeze,
+    /// Reject the event, the account is left as is
+    Reject,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScreeningPolicy {
+    /// Deposits and withdrawals of at least this amount are screened, besides the first
+    /// event of every client
+    pub large_transaction: Option<Decimal>,
+    pub on_match: ScreeningAction,
+}
+
+/// Why a client was screened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "trigger")]
+pub enum ScreeningTrigger {
+    FirstSeen,
+    LargeTransaction { amount: Decimal },
+}
+
+/// One entry of the screening audit trail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreeningRecord {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(flatten)]
+    pub trigger: ScreeningTrigger,
+    /// The list entry the client matched, if any
+    pub entry: Option<String>,
+    /// What was done about the match
+    pub action: Option<ScreeningAction>,
+    /// Extra columns the screened event was read with, if they were kept
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
+
+/// Wraps a [`TransactionProcessor`] and screens the clients of deposits and withdrawals
+/// with a [`Screener`]: on their first event, and on large transactions (see
+/// [`ScreeningPolicy`]). Every screening is written as a line of JSON to the audit trail.
+///
+/// Accounts frozen by screening are tracked here rather than by the wrapped DB: they're
+/// reported as frozen by [`TransactionProcessor::client`] and in snapshots, so they stay
+/// frozen once restored. If the list can't be consulted, the event is rejected.
+pub struct ScreeningProcessor<DB, S, W> {
+    inner: DB,
+    screener: S,
+    policy: ScreeningPolicy,
+    audit: W,
+    frozen: BTreeSet<ClientId>,
+}
+
+impl<DB, S, W> ScreeningProcessor<DB, S, W>
+where
+    DB: TransactionProcessor,
+    S: Screener,
+    W: Write,
+{
+    pub fn new(inner: DB, screener: S, policy: ScreeningPolicy, audit: W) -> Self {
+        Self {
+            inner,
+            screener,
+            policy,
+            audit,
+            frozen: BTreeSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (DB, W) {
+        (self.inner, self.audit)
+    }
+
+    /// Screens the client of a deposit or withdrawal if needed, and returns the action to
+    /// take.
+    fn screen(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+        metadata: &Metadata,
+    ) -> Result<Option<(ScreeningAction, String)>, TransactionError> {
+        if self.frozen.contains(&client_id) {
+            return Err(TransactionError::AccountFrozen { client_id });
+        }
+
+        let trigger = if self.inner.client(client_id).is_none() {
+            ScreeningTrigger::FirstSeen
+        } else if self
+            .policy
+            .large_transaction
+            .is_some_and(|threshold| amount >= threshold)
+        {
+            ScreeningTrigger::LargeTransaction { amount }
+        } else {
+            return Ok(None);
+        };
+
+        let entry = self
+            .screener
+            .screen(client_id)
+            .map_err(|err| TransactionError::Storage(format!("screening: {err:#}")))?;
+        let action = entry.as_ref().map(|_| self.policy.on_match);
+
+        let record = ScreeningRecord {
+            client: client_id,
+            tx: transaction_id,
+            trigger,
+            entry: entry.clone(),
+            action,
+            metadata: metadata.clone(),
+        };
+        let write = |writer: &mut W| -> std::io::Result<()> {
+            serde_json::to_writer(&mut *writer, &record)?;
+            writer.write_all(b"\n")
+        };
+        write(&mut self.audit)
+            .map_err(|err| TransactionError::Storage(format!("screening audit trail: {err}")))?;
+
+        Ok(action.zip(entry))
+    }
+
+    fn apply(
+        &mut self,
+        client_id: ClientId,
+        screened: Option<(ScreeningAction, String)>,
+        event: impl FnOnce(&mut DB) -> Result<(), TransactionError>,
+    ) -> Result<(), TransactionError> {
+        match screened {
+            None => event(&mut self.inner),
+            Some((ScreeningAction::Reject, entry)) => {
+                Err(TransactionError::Sanctioned { client_id, entry })
+            }
+            Some((ScreeningAction::Freeze, _)) => {
+                event(&mut self.inner)?;
+                self.frozen.insert(client_id);
+                Ok(())
+            }
+        }
+    }
+
+    fn frozen_by_screening(&self, mut client: ClientInformation) -> ClientInformation {
+        client.frozen |= self.frozen.contains(&client.id);
+        client
+    }
+}
+
+impl<DB, S, W> TransactionProcessor for ScreeningProcessor<DB, S, W>
+where
+    DB: TransactionProcessor,
+    S: Screener,
+    W: Write,
+{
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let screened = self.screen(transaction_id, client_id, amount, &Metadata::new())?;
+        self.apply(client_id, screened, |db| {
+            db.deposit(transaction_id, client_id, amount)
+        })
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        let screened = self.screen(transaction_id, client_id, amount, &Metadata::new())?;
+        self.apply(client_id, screened, |db| {
+            db.withdrawal(transaction_id, client_id, amount)
+        })
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.dispute(transaction_id, client_id)
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.resolve(transaction_id, client_id)
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.inner.chargeback(transaction_id, client_id)
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        match transaction {
+            TransactionEvent::Deposit { tx, client, amount }
+            | TransactionEvent::Withdrawal { tx, client, amount } => {
+                let screened = self.screen(tx, client, amount, metadata)?;
+                self.apply(client, screened, |db| {
+                    db.process_annotated_event(transaction, metadata)
+                })
+            }
+            _ => self.inner.process_annotated_event(transaction, metadata),
+        }
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        self.inner.follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.inner.last_seq()
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.inner
+            .clients_iter()
+            .map(|client| self.frozen_by_screening(client))
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.inner
+            .client(client_id)
+            .map(|client| self.frozen_by_screening(client))
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        let client = self.inner.closed_client(client_id)?;
+        Ok(client.map(|client| self.frozen_by_screening(client)))
+    }
+}
+
+impl<DB, S, W> StateStore for ScreeningProcessor<DB, S, W>
+where
+    DB: StateStore,
+{
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        let mut snapshot = self.inner.snapshot()?;
+        for client in &mut snapshot.clients {
+            client.frozen |= self.frozen.contains(&client.id);
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.frozen.clear();
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn processor(
+        on_match: ScreeningAction,
+    ) -> ScreeningProcessor<InMemoryTransactionDb, FileScreener, Vec<u8>> {
+        let list = "client,entry\n2,SDN-1234\n";
+        let screener =
+            FileScreener::from_reader(csv::Reader::from_reader(list.as_bytes())).unwrap();
+        let policy = ScreeningPolicy {
+            large_transaction: Some(dec!(1000)),
+            on_match,
+        };
+
+        ScreeningProcessor::new(InMemoryTransactionDb::new(), screener, policy, Vec::new())
+    }
+
+    fn audit_trail(
+        db: ScreeningProcessor<InMemoryTransactionDb, FileScreener, Vec<u8>>,
+    ) -> Vec<ScreeningRecord> {
+        let (_, audit) = db.into_inner();
+        serde_json::Deserializer::from_slice(&audit)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn freeze() {
+        let mut db = processor(ScreeningAction::Freeze);
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+        db.deposit(3, 2, dec!(10)).unwrap();
+        assert_eq!(
+            db.withdrawal(4, 2, dec!(1)),
+            Err(TransactionError::AccountFrozen { client_id: 2 })
+        );
+        assert!(db.client(2).unwrap().frozen);
+        assert!(
+            db.snapshot()
+                .unwrap()
+                .clients
+                .iter()
+                .any(|client| client.frozen)
+        );
+
+        assert_eq!(
+            audit_trail(db),
+            vec![
+                ScreeningRecord {
+                    client: 1,
+                    tx: 1,
+                    trigger: ScreeningTrigger::FirstSeen,
+                    entry: None,
+                    action: None,
+                    metadata: Metadata::new(),
+                },
+                ScreeningRecord {
+                    client: 2,
+                    tx: 3,
+                    trigger: ScreeningTrigger::FirstSeen,
+                    entry: Some("SDN-1234".to_owned()),
+                    action: Some(ScreeningAction::Freeze),
+                    metadata: Metadata::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_large_transaction() {
+        let mut db = processor(ScreeningAction::Reject);
+        assert_eq!(
+            db.deposit(1, 2, dec!(10)),
+            Err(TransactionError::Sanctioned {
+                client_id: 2,
+                entry: "SDN-1234".to_owned()
+            })
+        );
+        assert_eq!(db.client(2), None);
+
+        db.deposit(2, 1, dec!(10)).unwrap();
+        db.deposit(3, 1, dec!(2000)).unwrap();
+
+        let audit = audit_trail(db);
+        assert_eq!(audit.len(), 3);
+        assert_eq!(
+            audit[2].trigger,
+            ScreeningTrigger::LargeTransaction { amount: dec!(2000) }
+        );
+    }
+}
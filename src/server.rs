@@ -0,0 +1,741 @@
+//! An HTTP API to submit events to a transaction engine and read the balances of its clients,
+//! for services which can't hand over files.
+//!
+//! - `POST /transactions` applies an event, a JSON object with the same fields as the CSV
+//!   columns, and returns the state of its client
+//! - `GET /clients/{id}` returns the state of a client
+//! - `GET /clients` returns the state of every client, by id, in the format negotiated from
+//!   the `Accept` header (a JSON array without one), and a page of them with
+//!   `?offset=&limit=`, with a `Link: <...>; rel="next"` header to the next page if there is
+//!   one
+//! - `GET /ready` answers `200` while the server takes events, and `503` once it's draining,
+//!   for load balancers to stop sending it traffic. Events submitted while draining are
+//!   rejected with `503` too
+//! - `GET /updates` is a WebSocket streaming the [`ClientUpdate`](crate::publish::ClientUpdate)s of the applied events as
+//!   JSON text messages, only those of a client with `?client={id}`
+//!
+//! Clients are [`ClientRow`]s, as in the CSV output. Errors are a JSON object with an `error`
+//! message, and a status matching the [`TransactionError`].
+
+use std::{
+    convert::Infallible,
+    io::{self, BufWriter, Write},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use axum::{
+    Json, Router,
+    body::{Body, Bytes},
+    extract::{
+        FromRef, Path, Query, Request, State,
+        ws::{CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade, close_code},
+    },
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{ACCEPT, CONTENT_TYPE, LINK},
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use crate::{
+    csv::{ClientRow, TransactionRow},
+    drain::DrainSignal,
+    export::{ExportFormat, ExportSummary, Page, export_client_list},
+    memory_processor::FrozenClients,
+    publish::Publisher,
+    tenant::ApiCalls,
+    transaction::{
+        ClientId, ClientInformation, TransactionError, TransactionEvent, TransactionProcessor,
+    },
+};
+
+/// The status a rejected event fails with
+pub fn status_code(err: &TransactionError) -> StatusCode {
+    match err {
+        TransactionError::ClientNotFound { .. }
+        | TransactionError::TransactionNotFound { .. }
+        | TransactionError::UnknownTenant { .. } => StatusCode::NOT_FOUND,
+        TransactionError::AlreadyDisputed { .. }
+        | TransactionError::AlreadyChargedBack { .. }
+        | TransactionError::DuplicateTransaction { .. } => StatusCode::CONFLICT,
+        TransactionError::InsufficientFunds { .. }
+        | TransactionError::AccountFrozen { .. }
+        | TransactionError::NotFrozen { .. }
+        | TransactionError::AccountClosed { .. }
+        | TransactionError::FundsHeld { .. }
+        | TransactionError::NotDisputed { .. }
+        | TransactionError::DisputeWindowExpired { .. }
+        | TransactionError::DisputeLimitReached { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        TransactionError::Sanctioned { .. } => StatusCode::FORBIDDEN,
+        TransactionError::CapacityExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+        TransactionError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// A failed request, answered with `{"error": message}`
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl From<TransactionError> for ApiError {
+    fn from(err: TransactionError) -> Self {
+        Self {
+            status: status_code(&err),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.message });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// The DB the handlers share, applying events one at a time in the order the requests take
+/// its lock
+pub(crate) type SharedDb<DB> = Arc<Mutex<DB>>;
+
+/// An encoded [`crate::publish::ClientUpdate`]
+#[derive(Debug, Clone)]
+struct Update {
+    client: ClientId,
+    json: Utf8Bytes,
+}
+
+/// Hands the updates published by a [`crate::publish::PublishingProcessor`] to the
+/// subscribers of `GET /updates`.
+///
+/// Subscribers which fall more than `capacity` updates behind are disconnected, with a close
+/// code telling them to try again, rather than slowing down the engine or missing updates
+/// silently.
+#[derive(Debug, Clone)]
+pub struct UpdateBroadcast {
+    sender: broadcast::Sender<Update>,
+}
+
+impl UpdateBroadcast {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+}
+
+impl Publisher for UpdateBroadcast {
+    fn publish(&mut self, client: ClientId, payload: &[u8]) -> anyhow::Result<()> {
+        let json = String::from_utf8(payload.to_vec())?.into();
+        // Fails only when nobody is subscribed, and there's nobody to miss the update
+        let _ = self.sender.send(Update { client, json });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the handlers of the DB share
+struct ApiState<DB> {
+    db: SharedDb<DB>,
+    drain: DrainSignal,
+}
+
+impl<DB> Clone for ApiState<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            drain: self.drain.clone(),
+        }
+    }
+}
+
+impl<DB> FromRef<ApiState<DB>> for SharedDb<DB> {
+    fn from_ref(state: &ApiState<DB>) -> Self {
+        state.db.clone()
+    }
+}
+
+impl<DB> FromRef<ApiState<DB>> for DrainSignal {
+    fn from_ref(state: &ApiState<DB>) -> Self {
+        state.drain.clone()
+    }
+}
+
+/// The routes of the API, serving `db` and streaming the updates of `updates`, which `db`
+/// should publish to. Once `drain` is requested, the server isn't ready anymore and rejects
+/// events, so the state doesn't change while it shuts down.
+///
+/// The DB is shared so the caller can still reach it, eg to save it once the server stopped.
+pub fn router<DB>(db: SharedDb<DB>, updates: UpdateBroadcast, drain: DrainSignal) -> Router
+where
+    DB: TransactionProcessor + Send + 'static,
+{
+    Router::new()
+        .route("/transactions", post(submit::<DB>))
+        .route("/clients", get(clients::<DB>))
+        .route("/clients/{id}", get(client::<DB>))
+        .route("/ready", get(ready))
+        .with_state(ApiState { db, drain })
+        .merge(
+            Router::new()
+                .route("/updates", get(subscribe))
+                .with_state(updates),
+        )
+}
+
+/// Counts every request to `router` towards the API calls of the default tenant, see
+/// [`ApiCalls`]. Readiness probes aren't calls made on behalf of anyone, and aren't counted.
+pub fn count_api_calls(router: Router, calls: ApiCalls) -> Router {
+    router.layer(middleware::from_fn_with_state(calls, count_api_call))
+}
+
+async fn count_api_call(State(calls): State<ApiCalls>, request: Request, next: Next) -> Response {
+    if request.uri().path() != "/ready" {
+        calls.record_api_call(None);
+    }
+    next.run(request).await
+}
+
+pub(crate) fn lock<DB>(db: &SharedDb<DB>) -> MutexGuard<'_, DB> {
+    db.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn find<DB: TransactionProcessor>(db: &DB, client_id: ClientId) -> Result<ClientRow, ApiError> {
+    Ok(db.find_client(client_id)?.into())
+}
+
+async fn submit<DB: TransactionProcessor>(
+    State(db): State<SharedDb<DB>>,
+    State(drain): State<DrainSignal>,
+    Json(row): Json<TransactionRow>,
+) -> Result<Json<ClientRow>, ApiError> {
+    let event = TransactionEvent::try_from(row).map_err(|err| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: err.to_string(),
+    })?;
+    let client_id = event.client();
+
+    let mut db = lock(&db);
+    // Checked with the lock held, nothing is applied once the drain was requested
+    if drain.is_draining() {
+        return Err(ApiError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: "draining, no new events are applied".to_owned(),
+        });
+    }
+    db.process_transaction_event(event)?;
+    Ok(Json(find(&*db, client_id)?))
+}
+
+async fn client<DB: TransactionProcessor>(
+    State(db): State<SharedDb<DB>>,
+    Path(client_id): Path<ClientId>,
+) -> Result<Json<ClientRow>, ApiError> {
+    Ok(Json(find(&*lock(&db), client_id)?))
+}
+
+async fn ready(State(drain): State<DrainSignal>) -> Response {
+    match drain.is_draining() {
+        true => (StatusCode::SERVICE_UNAVAILABLE, "draining\n").into_response(),
+        false => (StatusCode::OK, "ready\n").into_response(),
+    }
+}
+
+/// Size of the chunks the body of `GET /clients` is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks of `GET /clients` encoded ahead of a slow reader, before encoding waits for it
+const CHUNK_BUFFER: usize = 16;
+
+/// The clients `GET /clients` exports, read while holding the lock
+enum ClientsCopy {
+    /// An O(1) copy, read once the lock is released
+    Frozen(FrozenClients),
+    /// DBs which can't take one are read under the lock
+    Read(Vec<ClientInformation>),
+}
+
+impl ClientsCopy {
+    fn of<DB: TransactionProcessor>(db: &DB) -> Self {
+        match db.frozen_clients() {
+            Some(clients) => Self::Frozen(clients),
+            None => Self::Read(db.clients_iter().collect()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Frozen(clients) => clients.len(),
+            Self::Read(clients) => clients.len(),
+        }
+    }
+
+    fn into_vec(self) -> Vec<ClientInformation> {
+        match self {
+            Self::Frozen(clients) => clients.clients_iter().collect(),
+            Self::Read(clients) => clients,
+        }
+    }
+}
+
+async fn clients<DB>(
+    State(db): State<SharedDb<DB>>,
+    Query(page): Query<Page>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError>
+where
+    DB: TransactionProcessor + Send + 'static,
+{
+    // Without an `Accept` header the clients are JSON, like the bodies of the other routes
+    let format = match headers.get(ACCEPT) {
+        None => ExportFormat::Json,
+        Some(accept) => accept
+            .to_str()
+            .ok()
+            .and_then(ExportFormat::from_accept)
+            .ok_or_else(|| ApiError {
+                status: StatusCode::NOT_ACCEPTABLE,
+                message: format!("clients can't be exported as any of {accept:?}"),
+            })?,
+    };
+
+    // Only the copy is taken under the lock. The rows are encoded from it as the reader
+    // takes them, so at most `CHUNK_BUFFER` chunks are held for a slow one
+    let clients = ClientsCopy::of(&*lock(&db));
+    let next = ExportSummary::of_page(page, clients.len()).next_offset(page);
+    let (sender, receiver) = mpsc::channel(CHUNK_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(CHUNK_SIZE, ChunkSender(sender));
+        // Only fails once the client went away
+        let _ = export_client_list(clients.into_vec(), format, page, writer);
+    });
+    let chunks = futures::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, Infallible>(chunk), receiver))
+    });
+
+    let mut response = (
+        [(CONTENT_TYPE, format.content_type())],
+        Body::from_stream(chunks),
+    )
+        .into_response();
+    if let Some(offset) = next {
+        let limit = page
+            .limit
+            .map(|limit| format!("&limit={limit}"))
+            .unwrap_or_default();
+        let link = format!("</clients?offset={offset}{limit}>; rel=\"next\"");
+        response.headers_mut().insert(
+            LINK,
+            HeaderValue::try_from(link).expect("the link is ASCII"),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Hands what's written to it to the body of a response, waiting while it's
+/// `CHUNK_BUFFER` chunks behind
+struct ChunkSender(mpsc::Sender<Bytes>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Bytes::copy_from_slice(buf))
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateFilter {
+    client: Option<ClientId>,
+}
+
+async fn subscribe(
+    State(updates): State<UpdateBroadcast>,
+    Query(filter): Query<UpdateFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let receiver = updates.sender.subscribe();
+    upgrade.on_upgrade(move |socket| stream_updates(socket, receiver, filter.client))
+}
+
+/// Sends the updates of `receiver` to `socket` until the subscriber leaves
+async fn stream_updates(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<Update>,
+    client: Option<ClientId>,
+) {
+    loop {
+        let update = tokio::select! {
+            update = receiver.recv() => update,
+            // Nothing is expected from the subscriber but closing the socket
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let update = match update {
+            Ok(update) => update,
+            Err(RecvError::Lagged(missed)) => {
+                let close = CloseFrame {
+                    code: close_code::AGAIN,
+                    reason: format!("missed {missed} updates").into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                return;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if client.is_none_or(|client| client == update.client)
+            && socket.send(Message::Text(update.json)).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        archive::AccountArchive,
+        memory_processor::InMemoryTransactionDb,
+        publish::{ClientUpdate, PublishingProcessor},
+    };
+
+    fn test_router() -> Router {
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        router(db, UpdateBroadcast::new(16), DrainSignal::new())
+    }
+
+    fn call(router: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        runtime.block_on(async {
+            let response = router.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        })
+    }
+
+    #[test]
+    fn applies_events() {
+        let router = test_router();
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}"#;
+
+        assert_eq!(
+            call(&router, "POST", "/transactions", deposit),
+            (
+                StatusCode::OK,
+                r#"{"client":1,"available":"10.5000","held":"0.0000","total":"10.5000","locked":false}"#
+                    .to_owned()
+            )
+        );
+        let (status, _) = call(
+            &router,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 2, "tx": 2, "amount": "1"}"#,
+        );
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(&router, "GET", "/clients/2", "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(r#"{"client":2,"#));
+        let (_, body) = call(&router, "GET", "/clients", "");
+        let clients: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0]["client"], 1);
+    }
+
+    #[test]
+    fn answers_for_closed_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        db.set_account_archive(AccountArchive::open(dir.path()).unwrap());
+        db.deposit(1, 1, rust_decimal::dec!(3)).unwrap();
+        db.close_account(1).unwrap();
+        let router = router(
+            Arc::new(Mutex::new(db)),
+            UpdateBroadcast::new(16),
+            DrainSignal::new(),
+        );
+
+        assert_eq!(
+            call(&router, "GET", "/clients/1", ""),
+            (
+                StatusCode::OK,
+                r#"{"client":1,"available":"3.0000","held":"0.0000","total":"3.0000","locked":false}"#
+                    .to_owned()
+            )
+        );
+        let (status, _) = call(&router, "GET", "/clients/2", "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn counts_api_calls() {
+        let calls = ApiCalls::new();
+        let router = count_api_calls(test_router(), calls.clone());
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#;
+
+        call(&router, "POST", "/transactions", deposit);
+        call(&router, "GET", "/clients/1", "");
+        call(&router, "GET", "/ready", "");
+        assert_eq!(calls.get(None), 2);
+        assert_eq!(calls.get(Some(1)), 0);
+    }
+
+    /// `GET uri` with `accept`, returning the status, content type and body of the response
+    fn get_clients(router: &Router, uri: &str, accept: &str) -> (StatusCode, String, Vec<u8>) {
+        let request = Request::get(uri)
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        runtime.block_on(async {
+            let response = router.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_owned())
+                .unwrap_or_default();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, content_type, body.to_vec())
+        })
+    }
+
+    fn router_with_clients(clients: ClientId) -> Router {
+        let mut db = InMemoryTransactionDb::new();
+        for client in 1..=clients {
+            db.deposit(u32::from(client), client, rust_decimal::dec!(1.5))
+                .unwrap();
+        }
+        router(
+            Arc::new(Mutex::new(db)),
+            UpdateBroadcast::new(16),
+            DrainSignal::new(),
+        )
+    }
+
+    #[test]
+    fn exports_clients_in_accepted_format() {
+        let router = router_with_clients(2);
+
+        let (status, content_type, body) = get_clients(&router, "/clients", "text/csv");
+        assert_eq!(
+            (status, content_type.as_str()),
+            (StatusCode::OK, "text/csv")
+        );
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n\
+             2,1.5000,0.0000,1.5000,false\n"
+        );
+
+        let (status, content_type, body) = get_clients(&router, "/clients", "application/x-ndjson");
+        assert_eq!(
+            (status, content_type.as_str()),
+            (StatusCode::OK, "application/x-ndjson")
+        );
+        let rows: Vec<ClientRow> = String::from_utf8(body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+
+        let (status, content_type, body) =
+            get_clients(&router, "/clients", "text/csv;q=0.5, application/json");
+        assert_eq!(
+            (status, content_type.as_str()),
+            (StatusCode::OK, "application/json")
+        );
+        let rows: Vec<ClientRow> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rows[1].client, 2);
+
+        let (status, _, body) = get_clients(&router, "/clients", "application/xml");
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert!(body.starts_with(br#"{"error":"#));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn exports_clients_as_parquet() {
+        let router = router_with_clients(2);
+
+        let (status, content_type, body) =
+            get_clients(&router, "/clients", "application/vnd.apache.parquet");
+        assert_eq!(
+            (status, content_type.as_str()),
+            (StatusCode::OK, "application/vnd.apache.parquet")
+        );
+        assert!(body.starts_with(b"PAR1") && body.ends_with(b"PAR1"));
+    }
+
+    #[test]
+    fn pages_clients() {
+        let router = router_with_clients(5);
+        let clients = |uri| {
+            let (status, _, body) = get_clients(&router, uri, "application/json");
+            assert_eq!(status, StatusCode::OK, "{uri}");
+            serde_json::from_slice::<Vec<ClientRow>>(&body)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.client)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(clients("/clients?offset=1&limit=2"), vec![2, 3]);
+        assert_eq!(clients("/clients?limit=2"), vec![1, 2]);
+        assert_eq!(clients("/clients?offset=3"), vec![4, 5]);
+        assert_eq!(clients("/clients?offset=9"), Vec::<ClientId>::new());
+        let (status, _, _) = get_clients(&router, "/clients?limit=-1", "text/csv");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let next = |uri| {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let response = runtime
+                .block_on(
+                    router
+                        .clone()
+                        .oneshot(Request::get(uri).body(Body::empty()).unwrap()),
+                )
+                .unwrap();
+            response
+                .headers()
+                .get(LINK)
+                .map(|link| link.to_str().unwrap().to_owned())
+        };
+        assert_eq!(
+            next("/clients?offset=1&limit=2").as_deref(),
+            Some(r#"</clients?offset=3&limit=2>; rel="next""#)
+        );
+        assert_eq!(next("/clients?offset=3&limit=2"), None);
+        assert_eq!(next("/clients"), None);
+    }
+
+    #[test]
+    fn streams_large_exports() {
+        // Many more chunks than are buffered ahead of the reader
+        let router = router_with_clients(40_000);
+
+        let (status, _, body) = get_clients(&router, "/clients", "text/csv");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.len() > CHUNK_BUFFER * CHUNK_SIZE);
+        assert_eq!(body.iter().filter(|&&byte| byte == b'\n').count(), 40_001);
+    }
+
+    #[test]
+    fn rejections() {
+        let router = test_router();
+        let status = |method, uri, body| call(&router, method, uri, body).0;
+
+        assert_eq!(status("GET", "/clients/1", ""), StatusCode::NOT_FOUND);
+        assert_eq!(status("GET", "/clients/70000", ""), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(
+                "POST",
+                "/transactions",
+                r#"{"type": "deposit", "client": 1, "tx": 1}"#
+            ),
+            StatusCode::BAD_REQUEST
+        );
+
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#;
+        assert_eq!(status("POST", "/transactions", deposit), StatusCode::OK);
+        assert_eq!(
+            status("POST", "/transactions", deposit),
+            StatusCode::CONFLICT
+        );
+        let (code, body) = call(
+            &router,
+            "POST",
+            "/transactions",
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "5"}"#,
+        );
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.starts_with(r#"{"error":"#));
+    }
+
+    #[test]
+    fn not_ready_when_draining() {
+        let drain = DrainSignal::new();
+        let db = Arc::new(Mutex::new(InMemoryTransactionDb::new()));
+        let router = router(db, UpdateBroadcast::new(16), drain.clone());
+        let deposit =
+            |tx| format!(r#"{{"type": "deposit", "client": 1, "tx": {tx}, "amount": "1"}}"#);
+
+        assert_eq!(
+            call(&router, "GET", "/ready", ""),
+            (StatusCode::OK, "ready\n".to_owned())
+        );
+        assert_eq!(
+            call(&router, "POST", "/transactions", &deposit(1)).0,
+            StatusCode::OK
+        );
+
+        drain.drain();
+        assert_eq!(
+            call(&router, "GET", "/ready", ""),
+            (StatusCode::SERVICE_UNAVAILABLE, "draining\n".to_owned())
+        );
+        let (status, body) = call(&router, "POST", "/transactions", &deposit(2));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.starts_with(r#"{"error":"draining"#));
+        // Balances can still be read
+        let (status, body) = call(&router, "GET", "/clients/1", "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""available":"1.0000""#));
+    }
+
+    #[test]
+    fn broadcasts_updates() {
+        let updates = UpdateBroadcast::new(16);
+        let mut receiver = updates.sender.subscribe();
+        let mut db = PublishingProcessor::new(InMemoryTransactionDb::new(), updates);
+
+        db.deposit(1, 7, rust_decimal::dec!(2)).unwrap();
+        let update = receiver.try_recv().unwrap();
+        assert_eq!(update.client, 7);
+        let update: ClientUpdate = serde_json::from_str(update.json.as_str()).unwrap();
+        assert!(matches!(update, ClientUpdate::Balances { seq: 1, .. }));
+
+        assert!(db.withdrawal(2, 7, rust_decimal::dec!(5)).is_err());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Timestamp,
+    cursor::SourceOffsets,
+    encryption::{self, EncryptionKey},
+    snapshot_codec::{self, SnapshotFormat},
+    transaction::{ClientId, TransactionId},
+};
+
+/// A full copy of a backend's state: every client balance plus the transaction history
+/// needed to process future disputes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub clients: Vec<ClientSnapshot>,
+    pub transactions: Vec<TransactionSnapshot>,
+    /// Sequence number of the last applied event
+    #[serde(default)]
+    pub seq: u64,
+    /// Number of transactions the backend recorded, which the `recorded_at` of transactions
+    /// count up to, see [`TransactionSnapshot::recorded_at`]
+    #[serde(default)]
+    pub recorded: u64,
+    /// Source position of the last applied event, see [`crate::cursor::CursorProcessor`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u64>,
+    /// Positions in their sources of the events applied to the state, by source, see
+    /// [`crate::cursor::OffsetProcessor`]
+    #[serde(default, skip_serializing_if = "SourceOffsets::is_empty")]
+    pub offsets: SourceOffsets,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSnapshot {
+    pub id: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub frozen: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionSnapshot {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Positive for deposits, negative for withdrawals
+    pub amount: Decimal,
+    pub disputed: bool,
+    #[serde(default)]
+    pub charged_back: bool,
+    /// Sequence number of the event which recorded the transaction
+    #[serde(default)]
+    pub seq: u64,
+    /// Number of times the transaction was disputed
+    #[serde(default)]
+    pub disputes: u32,
+    /// Number of transactions the backend had recorded before this one, which compaction
+    /// ages transactions by
+    #[serde(default)]
+    pub recorded_at: u64,
+    /// Time at which the transaction was recorded, which dispute windows start from. Left out
+    /// by backends which don't keep it, restored transactions without one count as recorded
+    /// when they're restored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<Timestamp>,
+}
+
+/// Aggregates used to sanity check that two snapshots describe the same state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SnapshotTotals {
+    pub clients: usize,
+    pub transactions: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+impl Snapshot {
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty() && self.transactions.is_empty()
+    }
+
+    pub fn totals(&self) -> SnapshotTotals {
+        SnapshotTotals {
+            clients: self.clients.len(),
+            transactions: self.transactions.len(),
+            available: self.clients.iter().map(|c| c.available).sum(),
+            held: self.clients.iter().map(|c| c.held).sum(),
+        }
+    }
+
+    /// Sorts clients and transactions by their keys, so that equal states compare equal
+    /// regardless of which backend produced them.
+    pub fn normalize(&mut self) {
+        self.clients.sort_by_key(|c| c.id);
+        self.transactions.sort_by_key(|t| (t.client, t.tx));
+    }
+}
+
+/// A backend whose full state can be exported and replaced wholesale.
+///
+/// This is what makes backups, migrations between backends and state handoffs possible
+/// without replaying the original event stream.
+pub trait StateStore {
+    /// Exports the full state of the backend.
+    fn snapshot(&self) -> anyhow::Result<Snapshot>;
+
+    /// Replaces the full state of the backend with the snapshot contents.
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()>;
+}
+
+impl<S: StateStore + ?Sized> StateStore for &mut S {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        (**self).snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        (**self).restore(snapshot)
+    }
+}
+
+/// A snapshot of the in-memory backend persisted as a file, JSON unless another
+/// [`SnapshotFormat`] is set.
+///
+/// A missing file is treated as an empty state.
+pub struct SnapshotFile {
+    path: PathBuf,
+    key: Option<EncryptionKey>,
+    format: SnapshotFormat,
+}
+
+impl SnapshotFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            key: None,
+            format: SnapshotFormat::default(),
+        }
+    }
+
+    /// Encrypts the file at rest with `key`.
+    pub fn encrypted(self, key: Option<EncryptionKey>) -> Self {
+        Self { key, ..self }
+    }
+
+    /// Writes the file in `format`. Files are read in whichever format they were written.
+    pub fn with_format(self, format: SnapshotFormat) -> Self {
+        Self { format, ..self }
+    }
+}
+
+impl StateStore for SnapshotFile {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        if !self.path.exists() {
+            return Ok(Snapshot::default());
+        }
+
+        let data = encryption::read_file(&self.path, self.key.as_ref())?;
+        let snapshot = snapshot_codec::decode(&data)
+            .with_context(|| format!("failed to decode snapshot {}", self.path.display()))?;
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, mut snapshot: Snapshot) -> anyhow::Result<()> {
+        snapshot.normalize();
+
+        encryption::write_file(
+            &self.path,
+            &self.format.codec().encode(&snapshot)?,
+            self.key.as_ref(),
+        )
+    }
+}
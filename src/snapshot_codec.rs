@@ -0,0 +1,498 @@
+//! Encodings of [`Snapshot`] files.
+//!
+//! JSON is human-inspectable and can be upgraded from older releases, which is what
+//! disaster recovery tooling wants. bincode and postcard are compact binary encodings for
+//! production. The encoding of a file is detected when it's read, so switching formats only
+//! affects the files written from then on.
+
+use std::str::FromStr;
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    clock::Timestamp,
+    cursor::SourceOffsets,
+    snapshot::{ClientSnapshot, Snapshot, TransactionSnapshot},
+    state_version::{self, STATE_VERSION, StateVersion, StateVersionError},
+    transaction::{ClientId, TransactionId},
+};
+
+/// Encodes and decodes whole snapshots.
+pub trait SnapshotCodec {
+    fn encode(&self, snapshot: &Snapshot) -> anyhow::Result<Vec<u8>>;
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Snapshot>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    Bincode,
+    Postcard,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("unknown snapshot format {0}, expected one of json, bincode, postcard")]
+pub struct UnknownSnapshotFormat(String);
+
+impl FromStr for SnapshotFormat {
+    type Err = UnknownSnapshotFormat;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            "postcard" => Ok(Self::Postcard),
+            _ => Err(UnknownSnapshotFormat(name.to_owned())),
+        }
+    }
+}
+
+impl SnapshotFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Bincode => "bincode",
+            Self::Postcard => "postcard",
+        }
+    }
+
+    pub fn codec(self) -> &'static dyn SnapshotCodec {
+        match self {
+            Self::Json => &JsonCodec,
+            Self::Bincode => &BincodeCodec,
+            Self::Postcard => &PostcardCodec,
+        }
+    }
+
+    /// Format of an encoded snapshot. Anything without a binary header is taken for JSON.
+    pub fn detect(data: &[u8]) -> Self {
+        match data.strip_prefix(MAGIC) {
+            Some([BINCODE_TAG, ..]) => Self::Bincode,
+            Some([POSTCARD_TAG, ..]) => Self::Postcard,
+            _ => Self::Json,
+        }
+    }
+}
+
+/// Decodes a snapshot in any of the [`SnapshotFormat`]s.
+pub fn decode(data: &[u8]) -> anyhow::Result<Snapshot> {
+    SnapshotFormat::detect(data).codec().decode(data)
+}
+
+/// The `state_version` header of [`crate::state_version`], so files written by older
+/// releases are upgraded when read.
+pub struct JsonCodec;
+
+impl SnapshotCodec for JsonCodec {
+    fn encode(&self, snapshot: &Snapshot) -> anyhow::Result<Vec<u8>> {
+        Ok(state_version::to_versioned_json(snapshot)?)
+    }
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Snapshot> {
+        state_version::from_versioned_json(data, "")
+    }
+}
+
+/// Binary snapshots start with these, then a format tag and the state version
+const MAGIC: &[u8; 4] = b"OCTS";
+const BINCODE_TAG: u8 = b'B';
+const POSTCARD_TAG: u8 = b'P';
+const HEADER_LEN: usize = MAGIC.len() + 1 + size_of::<StateVersion>();
+
+pub struct BincodeCodec;
+
+impl SnapshotCodec for BincodeCodec {
+    fn encode(&self, snapshot: &Snapshot) -> anyhow::Result<Vec<u8>> {
+        let mut data = header(BINCODE_TAG);
+        bincode::serde::encode_into_std_write(
+            CompactSnapshot::from(snapshot),
+            &mut data,
+            bincode::config::standard(),
+        )?;
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Snapshot> {
+        fn decode<T: DeserializeOwned>(payload: &[u8]) -> anyhow::Result<T> {
+            let (snapshot, _) =
+                bincode::serde::decode_from_slice(payload, bincode::config::standard())?;
+            Ok(snapshot)
+        }
+
+        let snapshot = match check_header(data, BINCODE_TAG)? {
+            (V3, payload) => CompactSnapshotV4::from(decode::<CompactSnapshotV3>(payload)?).into(),
+            (V4, payload) => decode::<CompactSnapshotV4>(payload)?.into(),
+            (_, payload) => decode::<CompactSnapshot>(payload)?,
+        };
+        Ok(snapshot.into())
+    }
+}
+
+pub struct PostcardCodec;
+
+impl SnapshotCodec for PostcardCodec {
+    fn encode(&self, snapshot: &Snapshot) -> anyhow::Result<Vec<u8>> {
+        let data = header(POSTCARD_TAG);
+        Ok(postcard::to_extend(&CompactSnapshot::from(snapshot), data)?)
+    }
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Snapshot> {
+        let snapshot = match check_header(data, POSTCARD_TAG)? {
+            (V3, payload) => {
+                CompactSnapshotV4::from(postcard::from_bytes::<CompactSnapshotV3>(payload)?).into()
+            }
+            (V4, payload) => postcard::from_bytes::<CompactSnapshotV4>(payload)?.into(),
+            (_, payload) => postcard::from_bytes::<CompactSnapshot>(payload)?,
+        };
+        Ok(snapshot.into())
+    }
+}
+
+fn header(tag: u8) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend(MAGIC);
+    header.push(tag);
+    header.extend(STATE_VERSION.to_le_bytes());
+    header
+}
+
+/// Oldest version of binary snapshots which can still be decoded, with [`CompactSnapshotV3`]
+const V3: StateVersion = 3;
+/// Version of [`CompactSnapshotV4`]
+const V4: StateVersion = 4;
+
+/// Returns the version of the snapshot and the payload following the header.
+///
+/// Binary snapshots aren't self-describing, so unlike JSON ones they can't be upgraded:
+/// [`CompactSnapshot`] is the layout of [`STATE_VERSION`] only. Bumping the version means
+/// keeping the previous layout around to decode older files.
+fn check_header(data: &[u8], tag: u8) -> anyhow::Result<(StateVersion, &[u8])> {
+    let Some((header, payload)) = data.split_at_checked(HEADER_LEN) else {
+        bail!("truncated snapshot header");
+    };
+    if !header.starts_with(MAGIC) || header[MAGIC.len()] != tag {
+        bail!("not a {} snapshot", SnapshotFormat::detect(data).as_str());
+    }
+
+    let version = StateVersion::from_le_bytes(header[MAGIC.len() + 1..].try_into()?);
+    if version > STATE_VERSION {
+        return Err(StateVersionError::TooNew { found: version }.into());
+    }
+    if version < V3 {
+        return Err(StateVersionError::MigrationFailed {
+            from: version,
+            reason: format!("binary snapshots older than version {V3} can't be upgraded"),
+        }
+        .into());
+    }
+
+    Ok((version, payload))
+}
+
+/// [`Snapshot`] for formats which aren't self-describing: no skipped fields, and decimals
+/// as their 16 byte representation rather than strings.
+#[derive(Serialize, Deserialize)]
+struct CompactSnapshot {
+    clients: Vec<CompactClient>,
+    transactions: Vec<CompactTransaction>,
+    seq: u64,
+    cursor: Option<u64>,
+    offsets: SourceOffsets,
+    recorded: u64,
+}
+
+/// [`CompactSnapshot`] of version 4, before the age of transactions was kept
+#[derive(Serialize, Deserialize)]
+struct CompactSnapshotV4 {
+    clients: Vec<CompactClient>,
+    transactions: Vec<CompactTransactionV4>,
+    seq: u64,
+    cursor: Option<u64>,
+    offsets: SourceOffsets,
+}
+
+impl From<CompactSnapshotV4> for CompactSnapshot {
+    fn from(snapshot: CompactSnapshotV4) -> Self {
+        Self {
+            clients: snapshot.clients,
+            transactions: snapshot
+                .transactions
+                .into_iter()
+                .map(CompactTransaction::from)
+                .collect(),
+            seq: snapshot.seq,
+            cursor: snapshot.cursor,
+            offsets: snapshot.offsets,
+            recorded: 0,
+        }
+    }
+}
+
+/// [`CompactSnapshot`] of version 3, before the offsets were added
+#[derive(Serialize, Deserialize)]
+struct CompactSnapshotV3 {
+    clients: Vec<CompactClient>,
+    transactions: Vec<CompactTransactionV4>,
+    seq: u64,
+    cursor: Option<u64>,
+}
+
+impl From<CompactSnapshotV3> for CompactSnapshotV4 {
+    fn from(snapshot: CompactSnapshotV3) -> Self {
+        Self {
+            clients: snapshot.clients,
+            transactions: snapshot.transactions,
+            seq: snapshot.seq,
+            cursor: snapshot.cursor,
+            offsets: SourceOffsets::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactClient {
+    id: ClientId,
+    available: [u8; 16],
+    held: [u8; 16],
+    frozen: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactTransaction {
+    client: ClientId,
+    tx: TransactionId,
+    amount: [u8; 16],
+    disputed: bool,
+    charged_back: bool,
+    seq: u64,
+    disputes: u32,
+    recorded_at: u64,
+    created_at: Option<Timestamp>,
+}
+
+/// [`CompactTransaction`] of versions 3 and 4, before its age was kept
+#[derive(Serialize, Deserialize)]
+struct CompactTransactionV4 {
+    client: ClientId,
+    tx: TransactionId,
+    amount: [u8; 16],
+    disputed: bool,
+    charged_back: bool,
+    seq: u64,
+    disputes: u32,
+}
+
+impl From<CompactTransactionV4> for CompactTransaction {
+    fn from(transaction: CompactTransactionV4) -> Self {
+        Self {
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            disputed: transaction.disputed,
+            charged_back: transaction.charged_back,
+            seq: transaction.seq,
+            disputes: transaction.disputes,
+            recorded_at: 0,
+            created_at: None,
+        }
+    }
+}
+
+impl From<&Snapshot> for CompactSnapshot {
+    fn from(snapshot: &Snapshot) -> Self {
+        Self {
+            clients: snapshot
+                .clients
+                .iter()
+                .map(|client| CompactClient {
+                    id: client.id,
+                    available: client.available.serialize(),
+                    held: client.held.serialize(),
+                    frozen: client.frozen,
+                })
+                .collect(),
+            transactions: snapshot
+                .transactions
+                .iter()
+                .map(|transaction| CompactTransaction {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                    amount: transaction.amount.serialize(),
+                    disputed: transaction.disputed,
+                    charged_back: transaction.charged_back,
+                    seq: transaction.seq,
+                    disputes: transaction.disputes,
+                    recorded_at: transaction.recorded_at,
+                    created_at: transaction.created_at,
+                })
+                .collect(),
+            seq: snapshot.seq,
+            cursor: snapshot.cursor,
+            offsets: snapshot.offsets.clone(),
+            recorded: snapshot.recorded,
+        }
+    }
+}
+
+impl From<CompactSnapshot> for Snapshot {
+    fn from(snapshot: CompactSnapshot) -> Self {
+        Self {
+            clients: snapshot
+                .clients
+                .into_iter()
+                .map(|client| ClientSnapshot {
+                    id: client.id,
+                    available: Decimal::deserialize(client.available),
+                    held: Decimal::deserialize(client.held),
+                    frozen: client.frozen,
+                })
+                .collect(),
+            transactions: snapshot
+                .transactions
+                .into_iter()
+                .map(|transaction| TransactionSnapshot {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                    amount: Decimal::deserialize(transaction.amount),
+                    disputed: transaction.disputed,
+                    charged_back: transaction.charged_back,
+                    seq: transaction.seq,
+                    disputes: transaction.disputes,
+                    recorded_at: transaction.recorded_at,
+                    created_at: transaction.created_at,
+                })
+                .collect(),
+            seq: snapshot.seq,
+            recorded: snapshot.recorded,
+            cursor: snapshot.cursor,
+            offsets: snapshot.offsets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            clients: vec![ClientSnapshot {
+                id: 1,
+                available: dec!(1.5),
+                held: dec!(-0.0001),
+                frozen: true,
+            }],
+            transactions: vec![TransactionSnapshot {
+                client: 1,
+                tx: 7,
+                amount: dec!(12345678901234.5678),
+                disputed: true,
+                charged_back: false,
+                seq: 3,
+                disputes: 2,
+                recorded_at: 5,
+                created_at: Some(1_700_000_000),
+            }],
+            seq: 4,
+            recorded: 6,
+            cursor: None,
+            offsets: [("kafka:transactions".to_owned(), r#"{"0":41}"#.to_owned())].into(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for format in [
+            SnapshotFormat::Json,
+            SnapshotFormat::Bincode,
+            SnapshotFormat::Postcard,
+        ] {
+            let data = format.codec().encode(&snapshot()).unwrap();
+            assert_eq!(SnapshotFormat::detect(&data), format);
+            assert_eq!(decode(&data).unwrap(), snapshot(), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn binary_is_compact() {
+        let json = JsonCodec.encode(&snapshot()).unwrap();
+        let postcard = PostcardCodec.encode(&snapshot()).unwrap();
+        assert!(postcard.len() * 2 < json.len());
+    }
+
+    #[test]
+    fn err_binary_version() {
+        let mut data = BincodeCodec.encode(&snapshot()).unwrap();
+        data[MAGIC.len() + 1..HEADER_LEN].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+
+        assert_eq!(
+            decode(&data)
+                .unwrap_err()
+                .downcast::<StateVersionError>()
+                .unwrap(),
+            StateVersionError::TooNew {
+                found: STATE_VERSION + 1
+            }
+        );
+        assert_eq!(
+            PostcardCodec.decode(&data).unwrap_err().to_string(),
+            "not a bincode snapshot"
+        );
+    }
+
+    #[test]
+    fn decodes_version_3() {
+        let v3 = CompactSnapshotV3 {
+            clients: Vec::new(),
+            transactions: Vec::new(),
+            seq: 4,
+            cursor: Some(7),
+        };
+        let mut data = header(POSTCARD_TAG);
+        data[MAGIC.len() + 1..].copy_from_slice(&V3.to_le_bytes());
+        let data = postcard::to_extend(&v3, data).unwrap();
+
+        let snapshot = decode(&data).unwrap();
+        assert_eq!(snapshot.cursor, Some(7));
+        assert!(snapshot.offsets.is_empty());
+    }
+
+    #[test]
+    fn decodes_version_4() {
+        let v4 = CompactSnapshotV4 {
+            clients: Vec::new(),
+            transactions: vec![CompactTransactionV4 {
+                client: 1,
+                tx: 7,
+                amount: dec!(10).serialize(),
+                disputed: false,
+                charged_back: false,
+                seq: 3,
+                disputes: 0,
+            }],
+            seq: 4,
+            cursor: None,
+            offsets: SourceOffsets::new(),
+        };
+        let mut data = header(BINCODE_TAG);
+        data[MAGIC.len() + 1..].copy_from_slice(&V4.to_le_bytes());
+        bincode::serde::encode_into_std_write(&v4, &mut data, bincode::config::standard()).unwrap();
+
+        let snapshot = decode(&data).unwrap();
+        assert_eq!(snapshot.recorded, 0);
+        assert_eq!(snapshot.transactions[0].seq, 3);
+        assert_eq!(snapshot.transactions[0].created_at, None);
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("postcard".parse(), Ok(SnapshotFormat::Postcard));
+        assert!("yaml".parse::<SnapshotFormat>().is_err());
+    }
+}
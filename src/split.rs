@@ -0,0 +1,184 @@
+//! Splitting of huge inputs into shards of whole clients, which can be processed in
+//! parallel and merged with [`crate::memory_processor::InMemoryTransactionDb::merge`].
+
+use std::{collections::HashSet, num::NonZeroU16, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::ClientId;
+
+/// The shard of `client` out of `shards`.
+///
+/// A multiplicative hash of the id rather than [`std::hash::Hash`], whose output may change
+/// between Rust versions, so a client always lands in the same shard. It also spreads
+/// sequentially allocated ids evenly.
+pub fn shard_of(client: ClientId, shards: NonZeroU16) -> u16 {
+    let hash = u32::from(client).wrapping_mul(0x9E37_79B9) >> 16;
+    // Lossless, the remainder is smaller than `shards`
+    (hash % u32::from(shards.get())) as u16
+}
+
+/// What a [`split_csv`] run wrote, eg to hand its shards to parallel runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitManifest {
+    pub input: PathBuf,
+    pub records: u64,
+    pub shards: Vec<ShardEntry>,
+}
+
+/// A shard of a [`SplitManifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardEntry {
+    pub path: PathBuf,
+    pub records: u64,
+    pub clients: usize,
+}
+
+/// Number of records and clients [`split_csv`] wrote to a shard
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    pub records: u64,
+    pub clients: usize,
+}
+
+/// Copies every record of `reader` to the shard of its client (see [`shard_of`]), one
+/// writer per shard. All the events of a client end up in the same shard, in their original
+/// order, so disputes still find their deposits.
+///
+/// The header, if any, is written to every shard, and tells which column is the client's.
+/// Without one, it's the second column, as when processing.
+pub fn split_csv<R, W>(
+    mut reader: csv::Reader<R>,
+    shards: &mut [csv::Writer<W>],
+) -> anyhow::Result<Vec<ShardStats>>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    let Some(count) = u16::try_from(shards.len()).ok().and_then(NonZeroU16::new) else {
+        anyhow::bail!("can't split into {} shards", shards.len());
+    };
+
+    let column = if reader.has_headers() {
+        let headers = reader.byte_headers()?;
+        for shard in shards.iter_mut() {
+            shard.write_byte_record(headers)?;
+        }
+        headers
+            .iter()
+            .position(|header| header == b"client")
+            .ok_or_else(|| anyhow::anyhow!("the input has no client column"))?
+    } else {
+        1
+    };
+
+    let mut records = vec![0; shards.len()];
+    let mut clients = vec![HashSet::new(); shards.len()];
+    let mut record = csv::ByteRecord::new();
+    let mut total = 0;
+    while reader.read_byte_record(&mut record)? {
+        total += 1;
+
+        let field = record.get(column).unwrap_or_default();
+        let client: ClientId = std::str::from_utf8(field)
+            .ok()
+            .and_then(|field| field.trim().parse().ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "invalid client {:?} in record {total}",
+                    String::from_utf8_lossy(field)
+                )
+            })?;
+
+        let shard = usize::from(shard_of(client, count));
+        shards[shard].write_byte_record(&record)?;
+        records[shard] += 1;
+        clients[shard].insert(client);
+    }
+    for shard in shards.iter_mut() {
+        shard.flush()?;
+    }
+
+    Ok(records
+        .into_iter()
+        .zip(clients)
+        .map(|(records, clients)| ShardStats {
+            records,
+            clients: clients.len(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::csv::CsvDialect;
+
+    fn shards(count: u16) -> NonZeroU16 {
+        NonZeroU16::new(count).unwrap()
+    }
+
+    #[test]
+    fn stable_and_spread() {
+        assert_eq!(shard_of(1, shards(1)), 0);
+        assert_eq!(shard_of(42, shards(8)), shard_of(42, shards(8)));
+
+        let mut sizes = [0u32; 8];
+        for client in 0..=ClientId::MAX {
+            sizes[usize::from(shard_of(client, shards(8)))] += 1;
+        }
+        assert!(
+            sizes.iter().all(|&size| size.abs_diff(8192) < 82),
+            "{sizes:?}"
+        );
+    }
+
+    #[test]
+    fn keeps_clients_together() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1\n\
+            deposit,2,2,2\n\
+            withdrawal,1,3,0.5\n\
+            dispute,2,2,\n\
+            dispute,1,1,\n";
+        let dialect = CsvDialect::default();
+        let mut writers: Vec<_> = (0..2).map(|_| dialect.writer(Vec::new())).collect();
+        let stats = split_csv(dialect.reader(input.as_bytes()), &mut writers).unwrap();
+
+        let outputs: Vec<String> = writers
+            .into_iter()
+            .map(|writer| String::from_utf8(writer.into_inner().unwrap()).unwrap())
+            .collect();
+        let (first, second) = (
+            usize::from(shard_of(1, shards(2))),
+            usize::from(shard_of(2, shards(2))),
+        );
+        assert_ne!(first, second);
+        assert_eq!(
+            outputs[first],
+            "type,client,tx,amount\ndeposit,1,1,1\nwithdrawal,1,3,0.5\ndispute,1,1,\n"
+        );
+        assert_eq!(
+            outputs[second],
+            "type,client,tx,amount\ndeposit,2,2,2\ndispute,2,2,\n"
+        );
+        assert_eq!(
+            stats[first],
+            ShardStats {
+                records: 3,
+                clients: 1
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_client() {
+        let mut writers = vec![CsvDialect::default().writer(Vec::new())];
+        let err = split_csv(
+            CsvDialect::default().reader("type,client,tx\ndeposit,x,1\n".as_bytes()),
+            &mut writers,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid client \"x\" in record 1"));
+    }
+}
@@ -0,0 +1,451 @@
+use std::{fmt::Display, io::Write, str::FromStr};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::{
+    Serialize, Serializer,
+    ser::{self, Impossible, SerializeStruct},
+};
+use serde_json::Value;
+
+use crate::{amount, export::OutputWriter};
+
+/// What [`SqlWriter`] does with rows whose key is already in the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SqlStatement {
+    /// Plain `INSERT`s, which fail on existing keys if the table has a unique key
+    Insert,
+    /// `INSERT ... ON CONFLICT (<key>) DO UPDATE`, as understood by PostgreSQL, SQLite and
+    /// DuckDB. The key columns need a unique constraint
+    Upsert { key: Vec<String> },
+}
+
+/// Writes every row as an SQL statement on its own line, eg to load the client balances
+/// straight into a warehouse with `psql -f`.
+///
+/// The fields of the rows become the columns, in order. Integers and amounts (serialized
+/// with [`amount::serialize`]) are written as numeric literals, strings are quoted.
+pub struct SqlWriter<W: Write> {
+    writer: W,
+    table: String,
+    statement: SqlStatement,
+}
+
+impl<W: Write> SqlWriter<W> {
+    /// Fails if `table` isn't an identifier, optionally qualified by a schema (eg
+    /// `reporting.balances`), since it's written as is.
+    pub fn new(writer: W, table: &str, statement: SqlStatement) -> anyhow::Result<Self> {
+        if !table.split('.').all(is_identifier) {
+            bail!("invalid SQL table name {table:?}");
+        }
+        if let SqlStatement::Upsert { key } = &statement
+            && (key.is_empty() || !key.iter().all(|column| is_identifier(column)))
+        {
+            bail!("invalid SQL upsert key {key:?}");
+        }
+
+        Ok(Self {
+            writer,
+            table: table.to_owned(),
+            statement,
+        })
+    }
+}
+
+impl<W: Write> OutputWriter for SqlWriter<W> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        let Literal::Row(row) = row.serialize(LiteralSerializer)? else {
+            bail!("only structs can be written as SQL");
+        };
+
+        let (columns, values): (Vec<&str>, Vec<String>) = row.into_iter().unzip();
+        write!(
+            self.writer,
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.table,
+            columns.join(", "),
+            values.join(", ")
+        )?;
+
+        if let SqlStatement::Upsert { key } = &self.statement {
+            let updates: Vec<String> = columns
+                .iter()
+                .filter(|column| !key.iter().any(|key| key == *column))
+                .map(|column| format!("{column} = excluded.{column}"))
+                .collect();
+            if updates.is_empty() {
+                write!(self.writer, " ON CONFLICT ({}) DO NOTHING", key.join(", "))?;
+            } else {
+                write!(
+                    self.writer,
+                    " ON CONFLICT ({}) DO UPDATE SET {}",
+                    key.join(", "),
+                    updates.join(", ")
+                )?;
+            }
+        }
+        self.writer.write_all(b";\n")?;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct LiteralError(String);
+
+impl ser::Error for LiteralError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl LiteralError {
+    fn unsupported(what: &str) -> Self {
+        Self(format!("{what} can't be written as SQL"))
+    }
+}
+
+/// A value serialized by [`LiteralSerializer`]
+enum Literal {
+    Value(String),
+    /// The columns of a struct, and the literals of their values
+    Row(Vec<(&'static str, String)>),
+}
+
+fn quote(string: &str) -> Literal {
+    Literal::Value(format!("'{}'", string.replace('\'', "''")))
+}
+
+/// Serializes a row into SQL literals, going by the types of its fields rather than by what
+/// their values look like: a string holding a number stays a string.
+struct LiteralSerializer;
+
+impl LiteralSerializer {
+    fn number(number: impl Display) -> Result<Literal, LiteralError> {
+        Ok(Literal::Value(number.to_string()))
+    }
+}
+
+impl Serializer for LiteralSerializer {
+    type Ok = Literal;
+    type Error = LiteralError;
+    type SerializeSeq = Impossible<Literal, LiteralError>;
+    type SerializeTuple = Impossible<Literal, LiteralError>;
+    type SerializeTupleStruct = Impossible<Literal, LiteralError>;
+    type SerializeTupleVariant = Impossible<Literal, LiteralError>;
+    type SerializeMap = Impossible<Literal, LiteralError>;
+    type SerializeStruct = RowSerializer;
+    type SerializeStructVariant = Impossible<Literal, LiteralError>;
+
+    fn serialize_bool(self, value: bool) -> Result<Literal, LiteralError> {
+        Ok(Literal::Value(
+            if value { "TRUE" } else { "FALSE" }.to_owned(),
+        ))
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<Literal, LiteralError> {
+        Self::number(value)
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<Literal, LiteralError> {
+        self.serialize_f64(value.into())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<Literal, LiteralError> {
+        match value.is_finite() {
+            true => Self::number(value),
+            false => self.serialize_none(),
+        }
+    }
+
+    fn serialize_char(self, value: char) -> Result<Literal, LiteralError> {
+        Ok(quote(value.encode_utf8(&mut [0; 4])))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Literal, LiteralError> {
+        Ok(quote(value))
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<Literal, LiteralError> {
+        Err(LiteralError::unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<Literal, LiteralError> {
+        Ok(Literal::Value("NULL".to_owned()))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Literal, LiteralError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Literal, LiteralError> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Literal, LiteralError> {
+        self.serialize_none()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Literal, LiteralError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Literal, LiteralError> {
+        if name == amount::NEWTYPE
+            && let Ok(Value::String(text)) = serde_json::to_value(value)
+            && Decimal::from_str(&text).is_ok()
+        {
+            return Ok(Literal::Value(text));
+        }
+
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Literal, LiteralError> {
+        Err(LiteralError::unsupported("enums with data"))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, LiteralError> {
+        Err(LiteralError::unsupported("sequences"))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, LiteralError> {
+        Err(LiteralError::unsupported("tuples"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, LiteralError> {
+        Err(LiteralError::unsupported("tuple structs"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, LiteralError> {
+        Err(LiteralError::unsupported("enums with data"))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, LiteralError> {
+        Err(LiteralError::unsupported("maps"))
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, LiteralError> {
+        Ok(RowSerializer(Vec::with_capacity(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, LiteralError> {
+        Err(LiteralError::unsupported("enums with data"))
+    }
+}
+
+/// The fields of a struct serialized by [`LiteralSerializer`]
+struct RowSerializer(Vec<(&'static str, String)>);
+
+impl SerializeStruct for RowSerializer {
+    type Ok = Literal;
+    type Error = LiteralError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), LiteralError> {
+        match value.serialize(LiteralSerializer)? {
+            Literal::Value(literal) => self.0.push((key, literal)),
+            Literal::Row(_) => return Err(LiteralError::unsupported("nested structs")),
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Literal, LiteralError> {
+        Ok(Literal::Row(self.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        csv::{ClientRow, TenantClientRow},
+        schema::{OutputColumn, OutputSchema, SchemaWriter},
+    };
+
+    #[derive(Serialize)]
+    struct Memo {
+        client: u16,
+        memo: String,
+        reference: Option<String>,
+    }
+
+    fn row() -> ClientRow {
+        ClientRow {
+            client: 7,
+            available: dec!(1.5),
+            held: dec!(0),
+            total: dec!(1.5),
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn inserts() {
+        let mut output = Vec::new();
+        let mut writer = SqlWriter::new(&mut output, "balances", SqlStatement::Insert).unwrap();
+        writer.write_row(&row()).unwrap();
+        writer
+            .write_row(&TenantClientRow {
+                tenant: Some(3),
+                client: 1,
+                available: dec!(-2),
+                held: dec!(2),
+                total: dec!(0),
+                locked: true,
+            })
+            .unwrap();
+        writer
+            .write_row(&Memo {
+                client: 1,
+                memo: "o'hare".to_owned(),
+                reference: None,
+            })
+            .unwrap();
+        writer
+            .write_row(&Memo {
+                client: 2,
+                memo: "1.5".to_owned(),
+                reference: Some("007".to_owned()),
+            })
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "INSERT INTO balances (client, available, held, total, locked) VALUES (7, 1.5000, 0.0000, 1.5000, FALSE);
+INSERT INTO balances (tenant, client, available, held, total, locked) VALUES (3, 1, -2.0000, 2.0000, 0.0000, TRUE);
+INSERT INTO balances (client, memo, reference) VALUES (1, 'o''hare', NULL);
+INSERT INTO balances (client, memo, reference) VALUES (2, '1.5', '007');
+"
+        );
+    }
+
+    #[test]
+    fn upserts() {
+        let mut output = Vec::new();
+        let statement = SqlStatement::Upsert {
+            key: vec!["client".to_owned()],
+        };
+        let mut writer = SqlWriter::new(&mut output, "reporting.balances", statement).unwrap();
+        writer.write_row(&row()).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "INSERT INTO reporting.balances (client, available, held, total, locked) \
+VALUES (7, 1.5000, 0.0000, 1.5000, FALSE) ON CONFLICT (client) DO UPDATE SET available = excluded.available, \
+held = excluded.held, total = excluded.total, locked = excluded.locked;\n"
+        );
+    }
+
+    #[test]
+    fn keeps_amounts_numeric_with_an_output_schema() {
+        let mut output = Vec::new();
+        let writer = SqlWriter::new(&mut output, "balances", SqlStatement::Insert).unwrap();
+        let schema = OutputSchema::new(vec![OutputColumn::Total, OutputColumn::Client]).unwrap();
+        let mut writer = SchemaWriter::new(writer, schema);
+        writer.write_row(&row()).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "INSERT INTO balances (total, client) VALUES (1.5000, 7);\n"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for table in ["", "balances; DROP TABLE users", "1balances", "reporting."] {
+            assert!(SqlWriter::new(Vec::new(), table, SqlStatement::Insert).is_err());
+        }
+        let statement = SqlStatement::Upsert { key: vec![] };
+        assert!(SqlWriter::new(Vec::new(), "balances", statement).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! A consumer applying the events of an SQS queue.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use aws_config::BehaviorVersion;
+use aws_sdk_sqs::Client;
+use tokio::runtime::Runtime;
+use tracing::{error, info};
+
+use crate::{
+    drain::DrainSignal,
+    metrics::RunMetrics,
+    stream::{Disposition, PayloadFormat, PendingAcks, SaveState, apply_message},
+    transaction::TransactionProcessor,
+};
+
+/// Messages received at once, the most SQS allows
+const BATCH: i32 = 10;
+/// Longest a receive may wait for messages, the most SQS allows
+const MAX_WAIT: Duration = Duration::from_secs(20);
+
+/// Consumes events from an SQS queue and applies them, deleting messages only once their events
+/// were applied (or rejected by the rules) and the state they were applied to was saved.
+///
+/// Delivery is at least once: messages which weren't deleted, eg because of a crash, are
+/// received again once their visibility timeout expired. Deposits and withdrawals the saved
+/// state already includes are then rejected as duplicates, so they aren't applied twice. Only FIFO
+/// queues keep the events of a client in order, with the client as message group.
+pub struct SqsSource {
+    runtime: Runtime,
+    client: Client,
+    queue_url: String,
+    format: PayloadFormat,
+}
+
+impl SqsSource {
+    /// Connects with the default AWS configuration (environment, profile or instance role).
+    pub fn connect(queue_url: &str, format: PayloadFormat) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("failed to start the AWS runtime")?;
+        let config = runtime.block_on(aws_config::defaults(BehaviorVersion::latest()).load());
+
+        Ok(Self {
+            runtime,
+            client: Client::new(&config),
+            queue_url: queue_url.to_owned(),
+            format,
+        })
+    }
+
+    /// Applies the events of the queue to `db` until `drain` is requested, long polling for
+    /// `poll` (in whole seconds, at least one).
+    ///
+    /// Messages which don't decode are logged and left in the queue, so its redrive policy
+    /// moves them to the dead-letter queue once they were received too many times. A storage
+    /// error makes its message visible again right away, and stops the run. The messages of
+    /// every receive are deleted once `save` saved `db`.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        save: &mut SaveState<'_, DB>,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+        let wait = poll.clamp(Duration::from_secs(1), MAX_WAIT).as_secs() as i32;
+
+        while !drain.is_draining() {
+            let output = self
+                .runtime
+                .block_on(
+                    self.client
+                        .receive_message()
+                        .queue_url(&self.queue_url)
+                        .max_number_of_messages(BATCH)
+                        .wait_time_seconds(wait)
+                        .send(),
+                )
+                .with_context(|| format!("failed to receive from {}", self.queue_url))?;
+
+            let mut deletes = PendingAcks::new();
+            let mut failed = None;
+            for message in output.messages() {
+                let position = format!("message {}", message.message_id().unwrap_or("?"));
+                let receipt = message
+                    .receipt_handle()
+                    .with_context(|| format!("{position} has no receipt handle"))?;
+                let payload = message.body().unwrap_or_default().as_bytes();
+
+                match apply_message(db, self.format, payload, &mut metrics) {
+                    Disposition::Done => deletes.push((position, receipt)),
+                    Disposition::Invalid(err) => error!("Leaving {position}: {err:#}"),
+                    Disposition::Retry(err) => {
+                        let release = self
+                            .client
+                            .change_message_visibility()
+                            .queue_url(&self.queue_url)
+                            .receipt_handle(receipt)
+                            .visibility_timeout(0)
+                            .send();
+                        self.runtime
+                            .block_on(release)
+                            .with_context(|| format!("failed to release {position}"))?;
+                        failed = Some(err.context(format!("failed to apply {position}")));
+                        break;
+                    }
+                }
+            }
+
+            // The messages before a failed one are done with too
+            deletes.flush(db, save, |(position, receipt)| {
+                let delete = self
+                    .client
+                    .delete_message()
+                    .queue_url(&self.queue_url)
+                    .receipt_handle(receipt)
+                    .send();
+                self.runtime
+                    .block_on(delete)
+                    .with_context(|| format!("failed to delete {position}"))?;
+                Ok(())
+            })?;
+            if let Some(err) = failed {
+                return Err(err);
+            }
+        }
+        info!("Draining, undeleted messages will be received again");
+
+        Ok(metrics)
+    }
+}
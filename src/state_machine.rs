@@ -0,0 +1,397 @@
+//! The account and transaction lifecycles, encoded as data.
+//!
+//! The processors look transitions up in these tables to decide whether an event is
+//! allowed, and the `describe` subcommand renders the same tables as diagrams, so the
+//! documentation can't drift from the behaviour.
+
+use std::fmt::{self, Write};
+
+use crate::transaction::{ClientId, TransactionError, TransactionId};
+
+/// Lifecycle of a deposit or withdrawal once it's recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum TransactionStatus {
+    Settled,
+    Disputed,
+    ChargedBack,
+}
+
+impl TransactionStatus {
+    /// The status described by the flags stored on transaction records
+    pub fn from_flags(disputed: bool, charged_back: bool) -> Self {
+        match (disputed, charged_back) {
+            (_, true) => Self::ChargedBack,
+            (true, false) => Self::Disputed,
+            (false, false) => Self::Settled,
+        }
+    }
+
+    /// `(disputed, charged_back)`
+    pub fn flags(self) -> (bool, bool) {
+        match self {
+            Self::Settled => (false, false),
+            Self::Disputed => (true, false),
+            Self::ChargedBack => (true, true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeEvent {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Frozen,
+}
+
+impl AccountStatus {
+    pub fn from_frozen(frozen: bool) -> Self {
+        if frozen { Self::Frozen } else { Self::Active }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountEvent {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Admin operation lifting a freeze
+    Unlock,
+    /// Admin operation correcting the available balance
+    Adjustment,
+}
+
+impl From<DisputeEvent> for AccountEvent {
+    fn from(event: DisputeEvent) -> Self {
+        match event {
+            DisputeEvent::Dispute => Self::Dispute,
+            DisputeEvent::Resolve => Self::Resolve,
+            DisputeEvent::Chargeback => Self::Chargeback,
+        }
+    }
+}
+
+/// A condition checked by the processor before taking a transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guard {
+    /// The available balance covers the withdrawal
+    SufficientFunds,
+    /// The transaction is recent enough to be disputed (see the dispute window)
+    WithinDisputeWindow,
+    /// The transaction wasn't disputed too many times already (see [`MaxDisputeCount`])
+    BelowDisputeLimit,
+}
+
+impl Guard {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::SufficientFunds => "available >= amount",
+            Self::WithinDisputeWindow => "within dispute window",
+            Self::BelowDisputeLimit => "disputes < max",
+        }
+    }
+}
+
+/// How many times a transaction can be disputed.
+///
+/// Resolving a dispute settles the transaction again, so without a limit it can go through
+/// any number of dispute/resolve cycles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MaxDisputeCount {
+    #[default]
+    Unlimited,
+    Limited(u32),
+}
+
+impl MaxDisputeCount {
+    /// Whether a transaction already disputed `disputes` times can be disputed again
+    pub fn allows(self, disputes: u32) -> bool {
+        match self {
+            Self::Unlimited => true,
+            Self::Limited(max) => disputes < max,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Transition<S: 'static, E: 'static> {
+    pub from: S,
+    pub event: E,
+    pub to: S,
+    /// All of them must hold for the transition to be taken
+    pub guards: &'static [Guard],
+}
+
+impl<S, E> Transition<S, E> {
+    pub fn is_guarded_by(&self, guard: Guard) -> bool {
+        self.guards.contains(&guard)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StateMachine<S: 'static, E: 'static> {
+    pub name: &'static str,
+    pub initial: S,
+    pub states: &'static [S],
+    pub transitions: &'static [Transition<S, E>],
+}
+
+impl<S, E> StateMachine<S, E>
+where
+    S: Copy + PartialEq + fmt::Debug,
+    E: Copy + PartialEq + fmt::Debug,
+{
+    /// The transition taken by `event` in state `from`, or `None` if the event isn't
+    /// allowed in that state.
+    pub fn transition(&self, from: S, event: E) -> Option<&Transition<S, E>> {
+        self.transitions
+            .iter()
+            .find(|transition| transition.from == from && transition.event == event)
+    }
+
+    fn edge_label(transition: &Transition<S, E>) -> String {
+        if transition.guards.is_empty() {
+            return format!("{:?}", transition.event);
+        }
+
+        let guards: Vec<_> = transition
+            .guards
+            .iter()
+            .map(|guard| guard.label())
+            .collect();
+        format!("{:?} [{}]", transition.event, guards.join(" && "))
+    }
+
+    /// Renders the machine as a Graphviz digraph.
+    pub fn to_dot(&self) -> String {
+        let mut dot = format!("digraph {} {{\n", self.name);
+        let _ = writeln!(dot, "    __start [shape=point];");
+        let _ = writeln!(dot, "    __start -> {:?};", self.initial);
+        for state in self.states {
+            let _ = writeln!(dot, "    {state:?} [shape=box];");
+        }
+        for transition in self.transitions {
+            let _ = writeln!(
+                dot,
+                "    {:?} -> {:?} [label=\"{}\"];",
+                transition.from,
+                transition.to,
+                Self::edge_label(transition)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the machine as a Mermaid state diagram.
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = format!("---\ntitle: {}\n---\nstateDiagram-v2\n", self.name);
+        let _ = writeln!(mermaid, "    [*] --> {:?}", self.initial);
+        for transition in self.transitions {
+            let _ = writeln!(
+                mermaid,
+                "    {:?} --> {:?}: {}",
+                transition.from,
+                transition.to,
+                Self::edge_label(transition)
+            );
+        }
+        mermaid
+    }
+}
+
+pub const TRANSACTION_MACHINE: StateMachine<TransactionStatus, DisputeEvent> = StateMachine {
+    name: "transaction",
+    initial: TransactionStatus::Settled,
+    states: &[
+        TransactionStatus::Settled,
+        TransactionStatus::Disputed,
+        TransactionStatus::ChargedBack,
+    ],
+    transitions: &[
+        Transition {
+            from: TransactionStatus::Settled,
+            event: DisputeEvent::Dispute,
+            to: TransactionStatus::Disputed,
+            guards: &[Guard::WithinDisputeWindow, Guard::BelowDisputeLimit],
+        },
+        Transition {
+            from: TransactionStatus::Disputed,
+            event: DisputeEvent::Resolve,
+            to: TransactionStatus::Settled,
+            guards: &[],
+        },
+        Transition {
+            from: TransactionStatus::Disputed,
+            event: DisputeEvent::Chargeback,
+            to: TransactionStatus::ChargedBack,
+            guards: &[],
+        },
+    ],
+};
+
+pub const ACCOUNT_MACHINE: StateMachine<AccountStatus, AccountEvent> = StateMachine {
+    name: "account",
+    initial: AccountStatus::Active,
+    states: &[AccountStatus::Active, AccountStatus::Frozen],
+    transitions: &[
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Deposit,
+            to: AccountStatus::Active,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Withdrawal,
+            to: AccountStatus::Active,
+            guards: &[Guard::SufficientFunds],
+        },
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Dispute,
+            to: AccountStatus::Active,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Resolve,
+            to: AccountStatus::Active,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Chargeback,
+            to: AccountStatus::Frozen,
+            guards: &[],
+        },
+        // Disputes already in flight when the account is frozen can still be settled
+        Transition {
+            from: AccountStatus::Frozen,
+            event: AccountEvent::Dispute,
+            to: AccountStatus::Frozen,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Frozen,
+            event: AccountEvent::Resolve,
+            to: AccountStatus::Frozen,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Frozen,
+            event: AccountEvent::Chargeback,
+            to: AccountStatus::Frozen,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Frozen,
+            event: AccountEvent::Unlock,
+            to: AccountStatus::Active,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Active,
+            event: AccountEvent::Adjustment,
+            to: AccountStatus::Active,
+            guards: &[],
+        },
+        Transition {
+            from: AccountStatus::Frozen,
+            event: AccountEvent::Adjustment,
+            to: AccountStatus::Frozen,
+            guards: &[],
+        },
+    ],
+};
+
+/// Looks up a dispute-related event in [`TRANSACTION_MACHINE`], returning the transition
+/// or the error describing why it isn't allowed.
+pub fn dispute_transition(
+    status: TransactionStatus,
+    event: DisputeEvent,
+    client_id: ClientId,
+    transaction_id: TransactionId,
+) -> Result<&'static Transition<TransactionStatus, DisputeEvent>, TransactionError> {
+    TRANSACTION_MACHINE
+        .transition(status, event)
+        .ok_or(match (status, event) {
+            (TransactionStatus::ChargedBack, _) => TransactionError::AlreadyChargedBack {
+                client_id,
+                transaction_id,
+            },
+            (_, DisputeEvent::Dispute) => TransactionError::AlreadyDisputed {
+                client_id,
+                transaction_id,
+            },
+            _ => TransactionError::NotDisputed {
+                client_id,
+                transaction_id,
+            },
+        })
+}
+
+/// Looks up an account-level event in [`ACCOUNT_MACHINE`]. Only frozen accounts reject
+/// events.
+pub fn account_transition(
+    status: AccountStatus,
+    event: AccountEvent,
+    client_id: ClientId,
+) -> Result<&'static Transition<AccountStatus, AccountEvent>, TransactionError> {
+    ACCOUNT_MACHINE
+        .transition(status, event)
+        .ok_or(TransactionError::AccountFrozen { client_id })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn charged_back_is_terminal() {
+        for event in [
+            DisputeEvent::Dispute,
+            DisputeEvent::Resolve,
+            DisputeEvent::Chargeback,
+        ] {
+            assert_eq!(
+                dispute_transition(TransactionStatus::ChargedBack, event, 1, 2).unwrap_err(),
+                TransactionError::AlreadyChargedBack {
+                    client_id: 1,
+                    transaction_id: 2
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn flags_roundtrip() {
+        for status in TRANSACTION_MACHINE.states {
+            let (disputed, charged_back) = status.flags();
+            assert_eq!(
+                TransactionStatus::from_flags(disputed, charged_back),
+                *status
+            );
+        }
+    }
+
+    #[test]
+    fn mermaid() {
+        let mermaid = TRANSACTION_MACHINE.to_mermaid();
+        assert!(mermaid.contains("[*] --> Settled"));
+        assert!(
+            mermaid.contains(
+                "Settled --> Disputed: Dispute [within dispute window && disputes < max]"
+            )
+        );
+        assert!(mermaid.contains("Disputed --> ChargedBack: Chargeback"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Versioning of the on-disk state format.
+//!
+//! Snapshot files, checkpoints and backups carry a `state_version` header. Files written by
+//! older releases are upgraded step by step through [`MIGRATIONS`] when they're read, so they
+//! load instead of failing to decode, and files written by newer releases are rejected with
+//! a clear error rather than silently misread.
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+
+pub type StateVersion = u32;
+
+/// Version written by this build.
+///
+/// - 1: the original layout, files without a `state_version` header
+/// - 2: adds sequence numbers (`seq` on the snapshot and on transactions) and
+///   `charged_back` on transactions
+/// - 3: adds the number of times each transaction was disputed (`disputes`)
+/// - 4: adds the positions in their sources of the applied events (`offsets`)
+/// - 5: adds the age of transactions (`recorded` on the snapshot, `recorded_at` and
+///   `created_at` on transactions)
+pub const STATE_VERSION: StateVersion = 5;
+
+/// Version assumed for files without a header
+const UNVERSIONED: StateVersion = 1;
+
+const VERSION_FIELD: &str = "state_version";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum StateVersionError {
+    #[error(
+        "state format version {found} was written by a newer release, this one supports up to {STATE_VERSION}"
+    )]
+    TooNew { found: StateVersion },
+
+    #[error("invalid state format version: {0}")]
+    Invalid(String),
+
+    #[error("migrating state from version {from} failed: {reason}")]
+    MigrationFailed { from: StateVersion, reason: String },
+}
+
+/// Upgrades a snapshot from version `from` to `from + 1`.
+pub struct Migration {
+    pub from: StateVersion,
+    pub description: &'static str,
+    pub migrate: fn(&mut Map<String, Value>) -> Result<(), String>,
+}
+
+/// Every migration, in order. Each one upgrades the JSON object of a
+/// [`crate::snapshot::Snapshot`].
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "add sequence numbers and chargeback flags",
+        migrate: |snapshot| {
+            snapshot.entry("seq").or_insert(Value::from(0));
+            for transaction in transactions(snapshot)? {
+                transaction.entry("seq").or_insert(Value::from(0));
+                transaction
+                    .entry("charged_back")
+                    .or_insert(Value::Bool(false));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 2,
+        description: "count disputes per transaction",
+        migrate: |snapshot| {
+            // Only open disputes are known, older disputes that were resolved are lost
+            for transaction in transactions(snapshot)? {
+                let disputed = transaction.get("disputed").and_then(Value::as_bool);
+                transaction
+                    .entry("disputes")
+                    .or_insert(Value::from(disputed.unwrap_or(false) as u32));
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        from: 3,
+        description: "track the positions in the sources",
+        migrate: |snapshot| {
+            snapshot
+                .entry("offsets")
+                .or_insert(Value::Object(Map::new()));
+            Ok(())
+        },
+    },
+    Migration {
+        from: 4,
+        description: "keep the age of transactions",
+        migrate: |snapshot| {
+            // The ages weren't saved, so transactions count as recorded right before anything
+            // applied after the snapshot, and their dispute window starts when it's restored
+            snapshot.entry("recorded").or_insert(Value::from(0));
+            for transaction in transactions(snapshot)? {
+                transaction.entry("recorded_at").or_insert(Value::from(0));
+            }
+            Ok(())
+        },
+    },
+];
+
+fn transactions(snapshot: &mut Map<String, Value>) -> Result<Vec<&mut Map<String, Value>>, String> {
+    match snapshot.get_mut("transactions") {
+        None => Ok(Vec::new()),
+        Some(Value::Array(transactions)) => transactions
+            .iter_mut()
+            .map(|transaction| {
+                transaction
+                    .as_object_mut()
+                    .ok_or_else(|| "transaction is not an object".to_owned())
+            })
+            .collect(),
+        Some(_) => Err("`transactions` is not an array".to_owned()),
+    }
+}
+
+/// Serializes `state` with a `state_version` header.
+pub fn to_versioned_json<T: Serialize>(state: &T) -> serde_json::Result<Vec<u8>> {
+    #[derive(Serialize)]
+    struct Versioned<'a, T> {
+        state_version: StateVersion,
+        #[serde(flatten)]
+        state: &'a T,
+    }
+
+    serde_json::to_vec(&Versioned {
+        state_version: STATE_VERSION,
+        state,
+    })
+}
+
+/// Reads the `state_version` header of `file` and upgrades the snapshot found at the JSON
+/// pointer `snapshot_at` (`""` if the file is the snapshot itself) to [`STATE_VERSION`].
+pub fn upgrade(mut file: Value, snapshot_at: &str) -> Result<Value, StateVersionError> {
+    let version = match file.get(VERSION_FIELD) {
+        None => UNVERSIONED,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| StateVersion::try_from(version).ok())
+            .ok_or_else(|| StateVersionError::Invalid(version.to_string()))?,
+    };
+
+    if version > STATE_VERSION {
+        return Err(StateVersionError::TooNew { found: version });
+    }
+
+    if version < STATE_VERSION {
+        let snapshot = file
+            .pointer_mut(snapshot_at)
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| StateVersionError::MigrationFailed {
+                from: version,
+                reason: format!("no snapshot object at `{snapshot_at}`"),
+            })?;
+
+        for migration in MIGRATIONS
+            .iter()
+            .filter(|migration| migration.from >= version)
+        {
+            (migration.migrate)(snapshot).map_err(|reason| StateVersionError::MigrationFailed {
+                from: migration.from,
+                reason,
+            })?;
+        }
+    }
+
+    if let Some(file) = file.as_object_mut() {
+        file.insert(VERSION_FIELD.to_owned(), Value::from(STATE_VERSION));
+    }
+
+    Ok(file)
+}
+
+/// Decodes a file written by [`to_versioned_json`], upgrading it first if it was written by
+/// an older release. See [`upgrade`] for `snapshot_at`.
+pub fn from_versioned_json<T: DeserializeOwned>(
+    data: &[u8],
+    snapshot_at: &str,
+) -> anyhow::Result<T> {
+    let file = serde_json::from_slice(data)?;
+    Ok(serde_json::from_value(upgrade(file, snapshot_at)?)?)
+}
+
+/// Checks the version stored by a persistent backend. Their records are upgraded lazily
+/// when they're decoded, so only versions from newer releases are rejected.
+pub fn check_stored(version: Option<StateVersion>) -> Result<(), StateVersionError> {
+    match version {
+        Some(found) if found > STATE_VERSION => Err(StateVersionError::TooNew { found }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::snapshot::{Snapshot, TransactionSnapshot};
+
+    #[test]
+    fn migrations_are_contiguous() {
+        for (migration, from) in MIGRATIONS.iter().zip(UNVERSIONED..) {
+            assert_eq!(migration.from, from, "{}", migration.description);
+        }
+        assert_eq!(
+            MIGRATIONS.last().map(|migration| migration.from + 1),
+            Some(STATE_VERSION)
+        );
+    }
+
+    #[test]
+    fn upgrades_unversioned_snapshot() {
+        let v1 = serde_json::json!({
+            "clients": [{"id": 1, "available": "5", "held": "10", "frozen": false}],
+            "transactions": [{"client": 1, "tx": 7, "amount": "10", "disputed": true}],
+        });
+
+        let snapshot: Snapshot = serde_json::from_value(upgrade(v1, "").unwrap()).unwrap();
+        assert_eq!(
+            snapshot.transactions,
+            vec![TransactionSnapshot {
+                client: 1,
+                tx: 7,
+                amount: dec!(10),
+                disputed: true,
+                charged_back: false,
+                seq: 0,
+                disputes: 1,
+                recorded_at: 0,
+                created_at: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn err_newer_version() {
+        let file = serde_json::json!({"state_version": STATE_VERSION + 1, "clients": []});
+        assert_eq!(
+            upgrade(file, ""),
+            Err(StateVersionError::TooNew {
+                found: STATE_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let snapshot = Snapshot {
+            seq: 4,
+            ..Default::default()
+        };
+        let json = to_versioned_json(&snapshot).unwrap();
+        let file: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(file[VERSION_FIELD], STATE_VERSION);
+
+        let read: Snapshot = serde_json::from_value(upgrade(file, "").unwrap()).unwrap();
+        assert_eq!(read, snapshot);
+    }
+}
@@ -0,0 +1,305 @@
+//! The broker-agnostic part of consuming events from a message broker: decoding payloads,
+//! applying them, and telling the consumer what to do with the message.
+
+use std::time::Instant;
+
+use anyhow::Context;
+use tracing::{error, info};
+
+use crate::{
+    csv::TransactionRow,
+    cursor::{Cursor, CursorError, CursorProcessor},
+    i18n::{Localize, Message},
+    metrics::RunMetrics,
+    transaction::{TransactionError, TransactionEvent, TransactionProcessor},
+};
+
+/// How the events are encoded in message payloads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// A JSON object with the same fields as the CSV columns
+    #[default]
+    Json,
+    /// A datum of [`crate::avro::TRANSACTION_SCHEMA`] in the Confluent wire format
+    #[cfg(feature = "avro")]
+    Avro,
+}
+
+impl PayloadFormat {
+    pub fn decode(self, payload: &[u8]) -> anyhow::Result<TransactionRow> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(payload)?),
+            #[cfg(feature = "avro")]
+            Self::Avro => crate::avro::decode_message(payload),
+        }
+    }
+}
+
+/// What a consumer should do with a message once [`apply_message`] is done with it.
+#[derive(Debug)]
+pub enum Disposition {
+    /// The event was applied, or rejected by the rules of the engine, which would reject it
+    /// again. Either way it's done with, and its offset can be committed.
+    Done,
+    /// Applying the event failed for a reason which may go away, eg a storage error. It
+    /// should be delivered again.
+    Retry(anyhow::Error),
+    /// The message can never be applied, eg its payload doesn't decode. It should be set
+    /// aside rather than delivered again.
+    Invalid(anyhow::Error),
+}
+
+/// Decodes an event from `payload` and applies it to `db`, recording it in `metrics`.
+///
+/// Rejected events are logged like when processing files, and are [`Disposition::Done`]:
+/// they're part of the input as much as applied ones are.
+pub fn apply_message<DB: TransactionProcessor>(
+    db: &mut DB,
+    format: PayloadFormat,
+    payload: &[u8],
+    metrics: &mut RunMetrics,
+) -> Disposition {
+    apply_payload(format, payload, metrics, |event| {
+        db.process_transaction_event(event)
+    })
+}
+
+/// Like [`apply_message`], for sources whose messages are at increasing positions, eg the
+/// sequences of a NATS stream: a message at or before the cursor of `db` is already included
+/// in its state, it's [`Disposition::Done`] without being decoded or applied again.
+pub fn apply_message_at<DB: TransactionProcessor>(
+    db: &mut CursorProcessor<DB>,
+    cursor: Cursor,
+    format: PayloadFormat,
+    payload: &[u8],
+    metrics: &mut RunMetrics,
+) -> Disposition {
+    if let Some(last) = db.cursor()
+        && cursor <= last
+    {
+        info!("Skipping the message at {cursor}, the state already includes it");
+        return Disposition::Done;
+    }
+
+    apply_payload(format, payload, metrics, |event| {
+        match db.apply(cursor, event) {
+            Ok(()) => Ok(()),
+            Err(CursorError::Rejected(err)) => Err(err),
+            Err(CursorError::AlreadyApplied { .. }) => unreachable!("checked above"),
+        }
+    })
+}
+
+fn apply_payload<F>(
+    format: PayloadFormat,
+    payload: &[u8],
+    metrics: &mut RunMetrics,
+    apply: F,
+) -> Disposition
+where
+    F: FnOnce(TransactionEvent) -> Result<(), TransactionError>,
+{
+    let started = Instant::now();
+    let event = match format
+        .decode(payload)
+        .and_then(|row| Ok(TransactionEvent::try_from(row)?))
+        .context("invalid event")
+    {
+        Ok(event) => event,
+        Err(err) => return Disposition::Invalid(err),
+    };
+    let client = event.client();
+    let kind = event.kind();
+
+    info!("Processing transaction event: {:?}", event);
+    let result = apply(event);
+    metrics.record((None, client), kind, started.elapsed(), &result);
+    match result {
+        Ok(()) => Disposition::Done,
+        Err(err @ TransactionError::Storage(_)) => Disposition::Retry(err.into()),
+        Err(err) => {
+            error!(
+                "{}",
+                Message::new("transaction-error").arg("error", err.message())
+            );
+            Disposition::Done
+        }
+    }
+}
+
+/// Saves the state events were applied to, eg to the `--state` file of a consumer. Doesn't
+/// save anything if the state isn't kept.
+pub type SaveState<'a, DB> = dyn FnMut(&DB) -> anyhow::Result<()> + 'a;
+
+/// Acknowledgements of messages held back until the state their events were applied to is
+/// saved: a broker drops the messages it had acknowledged, so a state restored after a crash
+/// has to include them.
+#[derive(Debug)]
+pub struct PendingAcks<T> {
+    pending: Vec<T>,
+}
+
+impl<T> Default for PendingAcks<T> {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T> PendingAcks<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Holds back the acknowledgement `ack` of a message which is done with
+    pub fn push(&mut self, ack: T) {
+        self.pending.push(ack);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Saves `db`, then sends the pending acknowledgements with `ack`.
+    ///
+    /// ## Errors
+    ///
+    /// If saving fails, in which case nothing is acknowledged, or if an acknowledgement
+    /// fails. The messages left unacknowledged are delivered again.
+    pub fn flush<DB, A>(
+        &mut self,
+        db: &DB,
+        save: &mut SaveState<'_, DB>,
+        mut ack: A,
+    ) -> anyhow::Result<()>
+    where
+        A: FnMut(T) -> anyhow::Result<()>,
+    {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        save(db).context("failed to save the state before acknowledging")?;
+        for pending in self.pending.drain(..) {
+            ack(pending)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    #[test]
+    fn dispositions() {
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+        let mut apply = |payload: &str| {
+            apply_message(
+                &mut db,
+                PayloadFormat::Json,
+                payload.as_bytes(),
+                &mut metrics,
+            )
+        };
+
+        assert!(matches!(
+            apply(r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#),
+            Disposition::Done
+        ));
+        // Rejected, but done with
+        assert!(matches!(
+            apply(r#"{"type":"withdrawal","client":1,"tx":2,"amount":"5"}"#),
+            Disposition::Done
+        ));
+        assert!(matches!(apply("not json"), Disposition::Invalid(_)));
+        assert!(matches!(
+            apply(r#"{"type":"refund","client":1,"tx":3}"#),
+            Disposition::Invalid(_)
+        ));
+
+        assert_eq!(db.client(1).unwrap().available, dec!(2.5));
+        assert_eq!(metrics.latencies.count(), 2);
+    }
+
+    #[test]
+    fn messages_before_the_cursor_are_done() {
+        let mut db = CursorProcessor::since(InMemoryTransactionDb::new(), Some(2));
+        let mut metrics = RunMetrics::new();
+        let mut apply = |cursor, payload: &str| {
+            apply_message_at(
+                &mut db,
+                cursor,
+                PayloadFormat::Json,
+                payload.as_bytes(),
+                &mut metrics,
+            )
+        };
+
+        // Included in the state already, it isn't even decoded
+        assert!(matches!(apply(2, "not json"), Disposition::Done));
+        assert!(matches!(
+            apply(3, r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#),
+            Disposition::Done
+        ));
+        assert!(matches!(
+            apply(3, r#"{"type":"deposit","client":1,"tx":2,"amount":"2.5"}"#),
+            Disposition::Done
+        ));
+        assert!(matches!(apply(4, "not json"), Disposition::Invalid(_)));
+
+        assert_eq!(db.cursor(), Some(3));
+        assert_eq!(db.inner().client(1).unwrap().available, dec!(2.5));
+        assert_eq!(metrics.latencies.count(), 1);
+    }
+
+    #[test]
+    fn acks_wait_for_the_state_to_be_saved() {
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+        let mut saved = None;
+        let mut acked = Vec::new();
+        let mut acks = PendingAcks::new();
+
+        for (id, tx) in [("1-0", 1), ("2-0", 2)] {
+            let payload = format!(r#"{{"type":"deposit","client":1,"tx":{tx},"amount":"1"}}"#);
+            let disposition = apply_message(
+                &mut db,
+                PayloadFormat::Json,
+                payload.as_bytes(),
+                &mut metrics,
+            );
+            assert!(matches!(disposition, Disposition::Done));
+            acks.push(id);
+        }
+        // A crash now would lose nothing: the broker delivers both again
+        assert_eq!(acks.len(), 2);
+        assert!(acked.is_empty());
+
+        let mut failing = |_: &InMemoryTransactionDb| Err(anyhow::anyhow!("disk full"));
+        assert!(acks.flush(&db, &mut failing, |_| unreachable!()).is_err());
+
+        let mut save = |db: &InMemoryTransactionDb| {
+            saved = db.client(1).map(|client| client.available);
+            Ok(())
+        };
+        acks.flush(&db, &mut save, |id| {
+            acked.push(id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(saved, Some(dec!(2)));
+        assert_eq!(acked, ["1-0", "2-0"]);
+        assert!(acks.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use std::{io::Write, str::FromStr};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::export::OutputWriter;
+
+/// Buffers rows and writes them as an aligned table with box-drawing borders once done,
+/// for eyeballing the client balances in a terminal.
+///
+/// The fields of the first row become the columns. Numbers (and strings holding decimals,
+/// like amounts) are aligned to the right, everything else to the left.
+pub struct TableWriter<W: Write> {
+    writer: W,
+    rows: Vec<Map<String, Value>>,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            rows: Vec::new(),
+        }
+    }
+
+    fn border(
+        &mut self,
+        widths: &[usize],
+        left: char,
+        middle: char,
+        right: char,
+    ) -> anyhow::Result<()> {
+        let segments: Vec<String> = widths.iter().map(|width| "─".repeat(width + 2)).collect();
+        writeln!(
+            self.writer,
+            "{left}{}{right}",
+            segments.join(&middle.to_string())
+        )?;
+
+        Ok(())
+    }
+
+    fn line(&mut self, widths: &[usize], cells: &[Cell]) -> anyhow::Result<()> {
+        for (cell, &width) in cells.iter().zip(widths) {
+            if cell.numeric {
+                write!(self.writer, "│ {:>width$} ", cell.text)?;
+            } else {
+                write!(self.writer, "│ {:<width$} ", cell.text)?;
+            }
+        }
+        writeln!(self.writer, "│")?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> OutputWriter for TableWriter<W> {
+    fn write_row<T: Serialize>(&mut self, row: &T) -> anyhow::Result<()> {
+        match serde_json::to_value(row)? {
+            Value::Object(row) => self.rows.push(row),
+            _ => bail!("only structs can be written as a table"),
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let names: Vec<&String> = self
+            .rows
+            .first()
+            .map(|row| row.keys().collect())
+            .unwrap_or_default();
+        if names.is_empty() {
+            return Ok(self.writer.flush()?);
+        }
+
+        let cells: Vec<Vec<Cell>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                names
+                    .iter()
+                    .map(|name| Cell::new(row.get(*name).unwrap_or(&Value::Null)))
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = names
+            .iter()
+            .enumerate()
+            .map(|(column, name)| {
+                cells
+                    .iter()
+                    .map(|row| row[column].width())
+                    .chain([name.chars().count()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let header: Vec<Cell> = names
+            .iter()
+            .map(|name| Cell {
+                text: name.to_string(),
+                numeric: false,
+            })
+            .collect();
+
+        self.border(&widths, '┌', '┬', '┐')?;
+        self.line(&widths, &header)?;
+        self.border(&widths, '├', '┼', '┤')?;
+        for row in &cells {
+            self.line(&widths, row)?;
+        }
+        self.border(&widths, '└', '┴', '┘')?;
+        self.rows.clear();
+
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// A formatted value and how to align it
+struct Cell {
+    text: String,
+    numeric: bool,
+}
+
+impl Cell {
+    fn new(value: &Value) -> Self {
+        match value {
+            Value::Null => Self {
+                text: String::new(),
+                numeric: false,
+            },
+            Value::Number(number) => Self {
+                text: number.to_string(),
+                numeric: true,
+            },
+            Value::String(string) => Self {
+                text: string.clone(),
+                numeric: Decimal::from_str(string).is_ok(),
+            },
+            other => Self {
+                text: other.to_string(),
+                numeric: false,
+            },
+        }
+    }
+
+    /// Width in characters, which is close enough for ids, amounts and flags
+    fn width(&self) -> usize {
+        self.text.chars().count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::csv::ClientRow;
+
+    #[test]
+    fn aligned_table() {
+        let mut output = Vec::new();
+        let mut writer = TableWriter::new(&mut output);
+        writer
+            .write_row(&ClientRow {
+                client: 1,
+                available: dec!(1234.5),
+                held: dec!(0),
+                total: dec!(1234.5),
+                locked: false,
+            })
+            .unwrap();
+        writer
+            .write_row(&ClientRow {
+                client: 65535,
+                available: dec!(-3),
+                held: dec!(3),
+                total: dec!(0),
+                locked: true,
+            })
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+┌────────┬───────────┬────────┬───────────┬────────┐
+│ client │ available │ held   │ total     │ locked │
+├────────┼───────────┼────────┼───────────┼────────┤
+│      1 │ 1234.5000 │ 0.0000 │ 1234.5000 │ false  │
+│  65535 │   -3.0000 │ 3.0000 │    0.0000 │ true   │
+└────────┴───────────┴────────┴───────────┴────────┘
+"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let mut output = Vec::new();
+        TableWriter::new(&mut output).finish().unwrap();
+        assert!(output.is_empty());
+    }
+}
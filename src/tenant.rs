@@ -0,0 +1,554 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{Clock, Timestamp},
+    memory_processor::{FrozenClients, InMemoryTransactionDb},
+    snapshot::{Snapshot, StateStore},
+    transaction::{
+        ClientId, ClientInformation, EventKind, Metadata, TransactionError, TransactionEvent,
+        TransactionId, TransactionProcessor,
+    },
+};
+
+pub type TenantId = u16;
+
+/// A [`TransactionEvent`] addressed to the ledger of a tenant (eg a partner institution).
+///
+/// Events without a tenant go to the default ledger, so single-tenant inputs keep working
+/// unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    #[serde(flatten)]
+    pub event: TransactionEvent,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+}
+
+impl From<TransactionEvent> for TenantEvent {
+    fn from(event: TransactionEvent) -> Self {
+        Self {
+            tenant: None,
+            event,
+            metadata: Metadata::new(),
+        }
+    }
+}
+
+/// Something which can apply [`TenantEvent`]s.
+///
+/// Every [`TransactionProcessor`] is one, with a single (default) ledger: events for any
+/// other tenant are rejected with [`TransactionError::UnknownTenant`].
+pub trait TenantProcessor {
+    fn process_tenant_event(&mut self, event: TenantEvent) -> Result<(), TransactionError>;
+
+    /// See [`TransactionProcessor::follow_event_time`], the time is the same for every ledger
+    fn follow_event_time(&mut self, timestamp: Timestamp);
+}
+
+impl<DB: TransactionProcessor> TenantProcessor for DB {
+    fn process_tenant_event(&mut self, event: TenantEvent) -> Result<(), TransactionError> {
+        match event.tenant {
+            None => self.process_annotated_event(event.event, &event.metadata),
+            Some(tenant) => Err(TransactionError::UnknownTenant { tenant }),
+        }
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        TransactionProcessor::follow_event_time(self, timestamp)
+    }
+}
+
+/// What a tenant used the engine for, to bill partners for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantUsage {
+    /// Events processed for the tenant, including rejected ones
+    pub events: u64,
+    pub rejected: u64,
+    /// Deposits and withdrawals recorded, which is what the transaction history stores
+    pub transactions: u64,
+    /// Clients currently in the ledger
+    pub clients: u64,
+    /// Calls made through an API on behalf of the tenant, see
+    /// [`TenantDb::record_api_call`]
+    pub api_calls: u64,
+    /// Bytes the ledger currently takes, see [`StorageFootprint`]. `None` if its DB can't
+    /// tell.
+    pub storage_bytes: Option<u64>,
+}
+
+/// How much storage a DB takes, for the usage of the tenant it holds the ledger of.
+pub trait StorageFootprint {
+    /// Approximate number of bytes taken by the state, `None` if the DB can't tell, eg
+    /// because its files are shared with other data.
+    fn storage_bytes(&self) -> Option<u64>;
+}
+
+impl<C: Clock> StorageFootprint for InMemoryTransactionDb<C> {
+    fn storage_bytes(&self) -> Option<u64> {
+        Some(self.memory_usage() as u64)
+    }
+}
+
+/// Calls made through an API, per tenant, see [`TenantUsage::api_calls`].
+///
+/// Clones share the same counts, so the servers in front of a [`TenantDb`] can count the
+/// calls they answer without taking its lock.
+#[derive(Debug, Clone, Default)]
+pub struct ApiCalls(Arc<Mutex<BTreeMap<Option<TenantId>, u64>>>);
+
+impl ApiCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a call made on behalf of `tenant`, `None` being the default ledger
+    pub fn record_api_call(&self, tenant: Option<TenantId>) {
+        let mut calls = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        *calls.entry(tenant).or_default() += 1;
+    }
+
+    pub fn get(&self, tenant: Option<TenantId>) -> u64 {
+        let calls = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        calls.get(&tenant).copied().unwrap_or_default()
+    }
+
+    fn tenants(&self) -> Vec<Option<TenantId>> {
+        let calls = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        calls.keys().copied().collect()
+    }
+}
+
+type LedgerFactory<DB> = Box<dyn FnMut(Option<TenantId>) -> Result<DB, TransactionError> + Send>;
+
+/// Isolated ledgers, one per tenant plus the default one, each held in its own DB.
+///
+/// Client and transaction ids only have to be unique within a tenant: the same client id
+/// under two tenants refers to two unrelated accounts. Ledgers are created the first time
+/// an event for their tenant comes in.
+pub struct TenantDb<DB> {
+    default: DB,
+    tenants: BTreeMap<TenantId, DB>,
+    factory: LedgerFactory<DB>,
+    /// Keyed by tenant, `None` being the default ledger. `clients`, `api_calls` and
+    /// `storage_bytes` aren't tracked here.
+    usage: BTreeMap<Option<TenantId>, TenantUsage>,
+    api_calls: ApiCalls,
+}
+
+impl<DB: TransactionProcessor + Default + 'static> TenantDb<DB> {
+    /// Ledgers are created with [`Default`], which suits the in-memory DB.
+    pub fn new() -> Self {
+        Self {
+            default: DB::default(),
+            tenants: BTreeMap::new(),
+            factory: Box::new(|_| Ok(DB::default())),
+            usage: BTreeMap::new(),
+            api_calls: ApiCalls::new(),
+        }
+    }
+}
+
+impl<DB: TransactionProcessor + Default + 'static> Default for TenantDb<DB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<DB: TransactionProcessor> TenantDb<DB> {
+    /// Creates ledgers with `factory`, eg to give every tenant its own database file. It's
+    /// called right away for the default ledger (`None`).
+    pub fn with_factory<F>(mut factory: F) -> Result<Self, TransactionError>
+    where
+        F: FnMut(Option<TenantId>) -> Result<DB, TransactionError> + Send + 'static,
+    {
+        Ok(Self {
+            default: factory(None)?,
+            tenants: BTreeMap::new(),
+            factory: Box::new(factory),
+            usage: BTreeMap::new(),
+            api_calls: ApiCalls::new(),
+        })
+    }
+
+    /// The ledger of a tenant, or `None` if it didn't receive any events yet.
+    pub fn ledger(&self, tenant: Option<TenantId>) -> Option<&DB> {
+        match tenant {
+            None => Some(&self.default),
+            Some(tenant) => self.tenants.get(&tenant),
+        }
+    }
+
+    fn ledger_mut(&mut self, tenant: Option<TenantId>) -> Result<&mut DB, TransactionError> {
+        let Some(tenant) = tenant else {
+            return Ok(&mut self.default);
+        };
+
+        if !self.tenants.contains_key(&tenant) {
+            let ledger = (self.factory)(Some(tenant))?;
+            self.tenants.insert(tenant, ledger);
+        }
+
+        Ok(self
+            .tenants
+            .get_mut(&tenant)
+            .expect("ledger was just created"))
+    }
+
+    /// Tenants with a ledger, in ascending order. The default ledger isn't included.
+    pub fn tenants(&self) -> impl Iterator<Item = TenantId> + '_ {
+        self.tenants.keys().copied()
+    }
+
+    /// The clients of a single tenant.
+    pub fn clients_iter(
+        &self,
+        tenant: Option<TenantId>,
+    ) -> impl Iterator<Item = ClientInformation> {
+        self.ledger(tenant)
+            .into_iter()
+            .flat_map(|ledger| ledger.clients_iter())
+    }
+
+    /// The clients of every ledger, tagged with their tenant. The default ledger comes
+    /// first, then tenants in ascending order.
+    pub fn all_clients(&self) -> impl Iterator<Item = (Option<TenantId>, ClientInformation)> {
+        let default = self.default.clients_iter().map(|client| (None, client));
+        let tenants = self.tenants.iter().flat_map(|(&tenant, ledger)| {
+            ledger
+                .clients_iter()
+                .map(move |client| (Some(tenant), client))
+        });
+
+        default.chain(tenants)
+    }
+
+    /// Counts an API call made on behalf of `tenant` towards its usage.
+    pub fn record_api_call(&self, tenant: Option<TenantId>) {
+        self.api_calls.record_api_call(tenant);
+    }
+
+    /// The API calls counted towards the usage of the tenants, for the servers in front of
+    /// the DB to count their calls with.
+    pub fn api_calls(&self) -> ApiCalls {
+        self.api_calls.clone()
+    }
+}
+
+impl<DB: TransactionProcessor + StorageFootprint> TenantDb<DB> {
+    /// Usage of a single tenant so far.
+    pub fn usage(&self, tenant: Option<TenantId>) -> TenantUsage {
+        TenantUsage {
+            clients: self.clients_iter(tenant).count() as u64,
+            api_calls: self.api_calls.get(tenant),
+            storage_bytes: self.ledger(tenant).and_then(DB::storage_bytes),
+            ..self.usage.get(&tenant).copied().unwrap_or_default()
+        }
+    }
+
+    /// Usage of every tenant which used the engine, the default ledger first.
+    pub fn usage_report(&self) -> impl Iterator<Item = (Option<TenantId>, TenantUsage)> + '_ {
+        let tenants: BTreeSet<_> = self
+            .usage
+            .keys()
+            .copied()
+            .chain(self.api_calls.tenants())
+            .collect();
+
+        tenants
+            .into_iter()
+            .map(|tenant| (tenant, self.usage(tenant)))
+    }
+}
+
+impl<DB: TransactionProcessor> TenantProcessor for TenantDb<DB> {
+    fn process_tenant_event(&mut self, event: TenantEvent) -> Result<(), TransactionError> {
+        let kind = event.event.kind();
+        let result = self
+            .ledger_mut(event.tenant)?
+            .process_annotated_event(event.event, &event.metadata);
+
+        let usage = self.usage.entry(event.tenant).or_default();
+        usage.events += 1;
+        match result {
+            Ok(()) if matches!(kind, EventKind::Deposit | EventKind::Withdrawal) => {
+                usage.transactions += 1
+            }
+            Ok(()) => {}
+            Err(_) => usage.rejected += 1,
+        }
+
+        result
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        TransactionProcessor::follow_event_time(&mut self.default, timestamp);
+        for ledger in self.tenants.values_mut() {
+            TransactionProcessor::follow_event_time(ledger, timestamp);
+        }
+    }
+}
+
+/// The default ledger of a [`TenantDb`] as a [`TransactionProcessor`], for front-ends which
+/// don't route events by tenant, eg the daemons. Events are counted towards the usage of the
+/// default tenant, the servers in front of it count their calls with
+/// [`SingleLedger::api_calls`].
+pub struct SingleLedger<DB>(TenantDb<DB>);
+
+impl<DB: TransactionProcessor> SingleLedger<DB> {
+    pub fn new(db: DB) -> Self {
+        Self(TenantDb {
+            default: db,
+            tenants: BTreeMap::new(),
+            factory: Box::new(|tenant| {
+                Err(TransactionError::UnknownTenant {
+                    tenant: tenant.unwrap_or_default(),
+                })
+            }),
+            usage: BTreeMap::new(),
+            api_calls: ApiCalls::new(),
+        })
+    }
+
+    /// The ledger, with the usage of the default tenant
+    pub fn tenants(&self) -> &TenantDb<DB> {
+        &self.0
+    }
+
+    pub fn api_calls(&self) -> ApiCalls {
+        self.0.api_calls()
+    }
+
+    fn apply(&mut self, event: TransactionEvent) -> Result<(), TransactionError> {
+        self.process_annotated_event(event, &Metadata::new())
+    }
+}
+
+impl<DB: TransactionProcessor> TransactionProcessor for SingleLedger<DB> {
+    fn process_transaction_event(
+        &mut self,
+        event: TransactionEvent,
+    ) -> Result<(), TransactionError> {
+        self.apply(event)
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        event: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        self.0.process_tenant_event(TenantEvent {
+            tenant: None,
+            event,
+            metadata: metadata.clone(),
+        })
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        TenantProcessor::follow_event_time(&mut self.0, timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.0.default.last_seq()
+    }
+
+    fn deposit(
+        &mut self,
+        tx: TransactionId,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(TransactionEvent::Deposit { tx, client, amount })
+    }
+
+    fn withdrawal(
+        &mut self,
+        tx: TransactionId,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        self.apply(TransactionEvent::Withdrawal { tx, client, amount })
+    }
+
+    fn dispute(&mut self, tx: TransactionId, client: ClientId) -> Result<(), TransactionError> {
+        self.apply(TransactionEvent::Dispute { tx, client })
+    }
+
+    fn resolve(&mut self, tx: TransactionId, client: ClientId) -> Result<(), TransactionError> {
+        self.apply(TransactionEvent::Resolve { tx, client })
+    }
+
+    fn chargeback(&mut self, tx: TransactionId, client: ClientId) -> Result<(), TransactionError> {
+        self.apply(TransactionEvent::Chargeback { tx, client })
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        self.0.default.clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.0.default.client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        self.0.default.closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        self.0.default.frozen_clients()
+    }
+}
+
+impl<DB: StateStore> StateStore for SingleLedger<DB> {
+    fn snapshot(&self) -> anyhow::Result<Snapshot> {
+        self.0.default.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        self.0.default.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn deposit(tenant: Option<TenantId>, tx: u32, client: u16) -> TenantEvent {
+        TenantEvent {
+            tenant,
+            event: TransactionEvent::Deposit {
+                tx,
+                client,
+                amount: dec!(10),
+            },
+            metadata: Metadata::new(),
+        }
+    }
+
+    #[test]
+    fn tenants_are_isolated() {
+        let mut db = TenantDb::<InMemoryTransactionDb>::new();
+        db.process_tenant_event(deposit(None, 1, 1)).unwrap();
+        db.process_tenant_event(deposit(Some(7), 1, 1)).unwrap();
+        db.process_tenant_event(deposit(Some(3), 1, 1)).unwrap();
+
+        // Same tx id under another tenant isn't a duplicate, but within one it is
+        assert_eq!(
+            db.process_tenant_event(deposit(Some(7), 1, 1)),
+            Err(TransactionError::DuplicateTransaction {
+                client_id: 1,
+                transaction_id: 1
+            })
+        );
+
+        db.process_tenant_event(TenantEvent {
+            tenant: Some(7),
+            event: TransactionEvent::Dispute { tx: 1, client: 1 },
+            metadata: Metadata::new(),
+        })
+        .unwrap();
+
+        assert_eq!(db.tenants().collect::<Vec<_>>(), vec![3, 7]);
+        assert_eq!(db.clients_iter(None).next().unwrap().held, dec!(0));
+        assert_eq!(db.clients_iter(Some(7)).next().unwrap().held, dec!(10));
+        assert_eq!(db.clients_iter(Some(4)).count(), 0);
+
+        let tenants: Vec<_> = db.all_clients().map(|(tenant, _)| tenant).collect();
+        assert_eq!(tenants, vec![None, Some(3), Some(7)]);
+    }
+
+    #[test]
+    fn usage_per_tenant() {
+        let mut db = TenantDb::<InMemoryTransactionDb>::new();
+        db.process_tenant_event(deposit(Some(2), 1, 1)).unwrap();
+        db.process_tenant_event(deposit(Some(2), 2, 3)).unwrap();
+        db.process_tenant_event(deposit(Some(2), 2, 3)).unwrap_err();
+        db.process_tenant_event(TenantEvent {
+            tenant: Some(2),
+            event: TransactionEvent::Dispute { tx: 1, client: 1 },
+            metadata: Metadata::new(),
+        })
+        .unwrap();
+        db.process_tenant_event(deposit(None, 1, 1)).unwrap();
+        db.record_api_call(Some(5));
+
+        assert_eq!(
+            db.usage(Some(2)),
+            TenantUsage {
+                events: 4,
+                rejected: 1,
+                transactions: 2,
+                clients: 2,
+                api_calls: 0,
+                storage_bytes: Some(db.ledger(Some(2)).unwrap().memory_usage() as u64),
+            }
+        );
+
+        let tenants: Vec<_> = db.usage_report().map(|(tenant, _)| tenant).collect();
+        assert_eq!(tenants, vec![None, Some(2), Some(5)]);
+        assert_eq!(db.usage(Some(5)).api_calls, 1);
+    }
+
+    #[test]
+    fn err_unknown_tenant() {
+        let mut db = InMemoryTransactionDb::new();
+        db.process_tenant_event(deposit(None, 1, 1)).unwrap();
+        assert_eq!(
+            db.process_tenant_event(deposit(Some(2), 2, 1)),
+            Err(TransactionError::UnknownTenant { tenant: 2 })
+        );
+    }
+
+    #[test]
+    fn tenant_is_optional_in_json() {
+        let event: TenantEvent =
+            serde_json::from_str(r#"{"type":"dispute","tx":1,"client":2}"#).unwrap();
+        assert_eq!(event.tenant, None);
+
+        let event: TenantEvent =
+            serde_json::from_str(r#"{"tenant":5,"type":"dispute","tx":1,"client":2}"#).unwrap();
+        assert_eq!(event.tenant, Some(5));
+        assert_eq!(event.event, TransactionEvent::Dispute { tx: 1, client: 2 });
+    }
+
+    #[test]
+    fn single_ledger() {
+        let mut db = SingleLedger::new(InMemoryTransactionDb::new());
+        let api_calls = db.api_calls();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        api_calls.record_api_call(None);
+        db.withdrawal(2, 1, dec!(20)).unwrap_err();
+        api_calls.record_api_call(None);
+
+        assert_eq!(db.last_seq(), 1);
+        assert_eq!(db.client(1).unwrap().available, dec!(10));
+        let usage = db.tenants().usage(None);
+        assert_eq!(
+            (
+                usage.events,
+                usage.rejected,
+                usage.transactions,
+                usage.api_calls
+            ),
+            (2, 1, 1, 2)
+        );
+        assert_eq!(
+            db.tenants().usage_report().count(),
+            1,
+            "only the default ledger"
+        );
+    }
+}
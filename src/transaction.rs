@@ -1,9 +1,18 @@
+use std::collections::BTreeMap;
+
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{clock::Timestamp, memory_processor::FrozenClients, tenant::TenantId};
 
 pub type TransactionId = u32;
 pub type ClientId = u16;
 
-#[derive(Debug)]
+/// Extra columns an event was read with, eg a `reference` or `memo`, by column name
+pub type Metadata = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum TransactionEvent {
     Deposit {
         tx: TransactionId,
@@ -33,7 +42,43 @@ pub enum TransactionEvent {
     },
 }
 
-#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+/// The type of a [`TransactionEvent`], without its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 5] = [
+        Self::Deposit,
+        Self::Withdrawal,
+        Self::Dispute,
+        Self::Resolve,
+        Self::Chargeback,
+    ];
+
+    /// Same as the `type` column of the input
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+        }
+    }
+}
+
+/// Why an event was rejected, or couldn't be applied.
+///
+/// Serializable so remote engines can report it as it is, see
+/// [`crate::remote::RemoteTransactionProcessor`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum TransactionError {
     #[error("client {client_id} does not exist")]
     ClientNotFound { client_id: ClientId },
@@ -51,6 +96,15 @@ pub enum TransactionError {
     #[error("client {client_id}'s account is frozen")]
     AccountFrozen { client_id: ClientId },
 
+    #[error("client {client_id}'s account is not frozen")]
+    NotFrozen { client_id: ClientId },
+
+    #[error("client {client_id}'s account is closed")]
+    AccountClosed { client_id: ClientId },
+
+    #[error("client {client_id}'s account can't be closed while funds are held")]
+    FundsHeld { client_id: ClientId },
+
     #[error("transaction {transaction_id} is already disputed")]
     AlreadyDisputed {
         client_id: ClientId,
@@ -63,19 +117,50 @@ pub enum TransactionError {
         transaction_id: TransactionId,
     },
 
+    #[error("transaction {transaction_id} was already charged back")]
+    AlreadyChargedBack {
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    },
+
     #[error("transaction {transaction_id} does not exist")]
     TransactionNotFound {
         client_id: ClientId,
         transaction_id: TransactionId,
     },
 
+    #[error("transaction {transaction_id} is too old to be disputed")]
+    DisputeWindowExpired {
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    },
+
+    #[error("transaction {transaction_id} was already disputed the maximum number of times")]
+    DisputeLimitReached {
+        client_id: ClientId,
+        transaction_id: TransactionId,
+    },
+
     #[error("duplicate transaction {transaction_id}")]
     DuplicateTransaction {
         client_id: ClientId,
         transaction_id: TransactionId,
     },
+
+    #[error("memory limit of {limit} bytes reached")]
+    CapacityExceeded { limit: usize },
+
+    #[error("tenant {tenant} does not exist")]
+    UnknownTenant { tenant: TenantId },
+
+    #[error("client {client_id} matches screening list entry {entry}")]
+    Sanctioned { client_id: ClientId, entry: String },
+
+    #[error("storage error: {0}")]
+    Storage(String),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ClientInformation {
     pub id: ClientId,
     pub available: Decimal,
@@ -100,6 +185,31 @@ pub trait TransactionProcessor {
         }
     }
 
+    /// Same as [`TransactionProcessor::process_transaction_event`], for an event read with
+    /// `metadata`. It doesn't affect processing, but decorators writing audit trails or
+    /// journals include it, and pass it on to the DB they wrap.
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        let _ = metadata;
+        self.process_transaction_event(transaction)
+    }
+
+    /// Called with the timestamp of the event about to be applied, if it has one, so DBs
+    /// whose time-based rules follow event timestamps move their clock there, see
+    /// [`crate::clock::Clock::follow`]. Decorators pass it on to the DB they wrap.
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        let _ = timestamp;
+    }
+
+    /// Sequence number of the last applied event, `0` if none was applied yet.
+    ///
+    /// Every successfully applied event gets the next number, so downstream consumers
+    /// have a total order of events without gaps. Rejected events don't get one.
+    fn last_seq(&self) -> u64;
+
     /// Called to process the `deposit` event.
     ///
     /// If user client not exist, this should lazily create the client.
@@ -143,6 +253,7 @@ pub trait TransactionProcessor {
     /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
     /// - If the transaction does not exist, returns [`TransactionError::TransactionNotFound`]
     /// - If the transaction is already disputed, returns [`TransactionError::AlreadyDisptuted`]
+    /// - If the transaction was charged back, returns [`TransactionError::AlreadyChargedBack`]
     fn dispute(
         &mut self,
         transaction_id: TransactionId,
@@ -158,6 +269,7 @@ pub trait TransactionProcessor {
     /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
     /// - If the transaction does not exist, returns [`TransactionError::TransactionNotFound`]
     /// - If the transation is not disputed, returns [`TransactionError::NotDisputed`]
+    /// - If the transaction was charged back, returns [`TransactionError::AlreadyChargedBack`]
     fn resolve(
         &mut self,
         transaction_id: TransactionId,
@@ -174,6 +286,7 @@ pub trait TransactionProcessor {
     /// - If the client does not exist, returns [`TransactionError::ClientNotFound`]
     /// - If the transaction does not exist, returns [`TransactionError::TransactionNotFound`]
     /// - If the transation is not disputed, returns [`TransactionError::NotDisputed`]
+    /// - If the transaction was charged back, returns [`TransactionError::AlreadyChargedBack`]
     fn chargeback(
         &mut self,
         transaction_id: TransactionId,
@@ -186,4 +299,170 @@ pub trait TransactionProcessor {
     /// in prod, but it's also not very likely. But it's a take home task, so
     /// c'est la vie.
     fn clients_iter(&self) -> impl Iterator<Item = ClientInformation>;
+
+    /// Looks up a single client.
+    ///
+    /// The default implementation scans [`TransactionProcessor::clients_iter`], backends
+    /// with keyed storage should override it.
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        self.clients_iter().find(|client| client.id == client_id)
+    }
+
+    /// Looks up the balances a client's account was closed with, once it was moved out of
+    /// the DB by [`crate::memory_processor::InMemoryTransactionDb::close_account`]. DBs which
+    /// don't close accounts have none.
+    ///
+    /// ## Errors
+    /// - If reading the account archive fails, returns [`TransactionError::Storage`]
+    fn closed_client(
+        &self,
+        _client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        Ok(None)
+    }
+
+    /// An O(1) copy of the clients, which can be read without holding on to the DB, eg to
+    /// export them while events keep being applied. `None` for DBs which can't take one
+    /// cheaply, their clients have to be read with [`TransactionProcessor::clients_iter`].
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        None
+    }
+
+    /// Looks up a client, falling back to the closed accounts, for the APIs answering about
+    /// any client.
+    ///
+    /// ## Errors
+    /// - If the client does not exist and was never closed, returns
+    ///   [`TransactionError::ClientNotFound`]
+    /// - If reading the account archive fails, returns [`TransactionError::Storage`]
+    fn find_client(&self, client_id: ClientId) -> Result<ClientInformation, TransactionError> {
+        match self.client(client_id) {
+            Some(client) => Ok(client),
+            None => self
+                .closed_client(client_id)?
+                .ok_or(TransactionError::ClientNotFound { client_id }),
+        }
+    }
+}
+
+/// Lets decorators wrap a DB they only borrow, eg to finish their output while the DB is
+/// still wrapped by another decorator.
+impl<DB: TransactionProcessor + ?Sized> TransactionProcessor for &mut DB {
+    fn process_transaction_event(
+        &mut self,
+        transaction: TransactionEvent,
+    ) -> Result<(), TransactionError> {
+        (**self).process_transaction_event(transaction)
+    }
+
+    fn process_annotated_event(
+        &mut self,
+        transaction: TransactionEvent,
+        metadata: &Metadata,
+    ) -> Result<(), TransactionError> {
+        (**self).process_annotated_event(transaction, metadata)
+    }
+
+    fn follow_event_time(&mut self, timestamp: Timestamp) {
+        (**self).follow_event_time(timestamp)
+    }
+
+    fn last_seq(&self) -> u64 {
+        (**self).last_seq()
+    }
+
+    fn deposit(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        (**self).deposit(transaction_id, client_id, amount)
+    }
+
+    fn withdrawal(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Result<(), TransactionError> {
+        (**self).withdrawal(transaction_id, client_id, amount)
+    }
+
+    fn dispute(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        (**self).dispute(transaction_id, client_id)
+    }
+
+    fn resolve(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        (**self).resolve(transaction_id, client_id)
+    }
+
+    fn chargeback(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), TransactionError> {
+        (**self).chargeback(transaction_id, client_id)
+    }
+
+    fn clients_iter(&self) -> impl Iterator<Item = ClientInformation> {
+        (**self).clients_iter()
+    }
+
+    fn client(&self, client_id: ClientId) -> Option<ClientInformation> {
+        (**self).client(client_id)
+    }
+
+    fn closed_client(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Option<ClientInformation>, TransactionError> {
+        (**self).closed_client(client_id)
+    }
+
+    fn frozen_clients(&self) -> Option<FrozenClients> {
+        (**self).frozen_clients()
+    }
+}
+
+impl TransactionEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::Deposit { .. } => EventKind::Deposit,
+            Self::Withdrawal { .. } => EventKind::Withdrawal,
+            Self::Dispute { .. } => EventKind::Dispute,
+            Self::Resolve { .. } => EventKind::Resolve,
+            Self::Chargeback { .. } => EventKind::Chargeback,
+        }
+    }
+
+    /// The client the event belongs to
+    pub fn client(&self) -> ClientId {
+        match self {
+            Self::Deposit { client, .. }
+            | Self::Withdrawal { client, .. }
+            | Self::Dispute { client, .. }
+            | Self::Resolve { client, .. }
+            | Self::Chargeback { client, .. } => *client,
+        }
+    }
+
+    /// The transaction the event creates or refers to
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            Self::Deposit { tx, .. }
+            | Self::Withdrawal { tx, .. }
+            | Self::Dispute { tx, .. }
+            | Self::Resolve { tx, .. }
+            | Self::Chargeback { tx, .. } => *tx,
+        }
+    }
 }
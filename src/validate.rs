@@ -0,0 +1,227 @@
+//! Checking the rows of an input before running it, without applying them to anything.
+
+use std::fmt;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    amount::DECIMAL_PLACES,
+    csv::{CsvDecodeError, TransactionReader},
+    transaction::TransactionEvent,
+};
+
+/// What's wrong with a row
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// The row doesn't parse, eg its client isn't a number or it has too many columns
+    Malformed {
+        reason: String,
+    },
+    UnknownType {
+        transaction_type: String,
+    },
+    /// A deposit or withdrawal without an amount
+    MissingAmount {
+        transaction_type: String,
+    },
+    /// An amount with more decimal places than balances are kept with, which would be
+    /// rounded
+    Precision {
+        amount: Decimal,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed { reason } => write!(f, "malformed row: {reason}"),
+            Self::UnknownType { transaction_type } => {
+                write!(f, "unknown transaction type {transaction_type:?}")
+            }
+            Self::MissingAmount { transaction_type } => {
+                write!(f, "{transaction_type} without an amount")
+            }
+            Self::Precision { amount } => write!(
+                f,
+                "amount {amount} has more than {DECIMAL_PLACES} decimal places"
+            ),
+        }
+    }
+}
+
+/// A row with a problem
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RowIssue {
+    /// Line of the row in the input, starting at 1 with the header
+    pub line: u64,
+    #[serde(flatten)]
+    pub problem: Problem,
+}
+
+impl fmt::Display for RowIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.problem)
+    }
+}
+
+/// The problems found in an input by [`validate`]
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Validation {
+    /// Data rows read
+    pub rows: u64,
+    pub issues: Vec<RowIssue>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Reads every row of `csv_reader` and reports the ones which a run would stop at, or
+/// couldn't apply as they're written.
+///
+/// Unlike a run, this goes on after the first invalid row, so a file can be checked in one
+/// go. Nothing is applied, so rows which would only be rejected by the engine (eg a
+/// withdrawal without the funds for it) aren't reported.
+///
+/// ## Errors
+/// - If the input can't be read, or its header is invalid with
+///   [`crate::csv::CsvDialect::strict_headers`]
+pub fn validate<R: std::io::Read>(
+    csv_reader: impl Into<TransactionReader<R>>,
+) -> anyhow::Result<Validation> {
+    let mut csv_reader = csv_reader.into();
+    csv_reader.read_headers()?;
+
+    let mut validation = Validation::default();
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let line = csv_reader.position().line();
+        match csv_reader.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => {}
+            Err(err) => match err.downcast::<csv::Error>() {
+                // Eg a row with more columns than the others, the next ones can still be read
+                Ok(err) if !matches!(err.kind(), csv::ErrorKind::Io(_)) => {
+                    validation.rows += 1;
+                    let line = err.position().map_or(line, csv::Position::line);
+                    validation.issues.push(RowIssue {
+                        line,
+                        problem: Problem::Malformed {
+                            reason: err.to_string(),
+                        },
+                    });
+                    continue;
+                }
+                Ok(err) => return Err(err.into()),
+                Err(err) => return Err(err),
+            },
+        }
+
+        validation.rows += 1;
+        let line = record.position().map_or(line, csv::Position::line);
+        if let Some(problem) = check(&csv_reader, &record) {
+            validation.issues.push(RowIssue { line, problem });
+        }
+    }
+
+    Ok(validation)
+}
+
+fn check<R: std::io::Read>(
+    csv_reader: &TransactionReader<R>,
+    record: &csv::ByteRecord,
+) -> Option<Problem> {
+    let row = match csv_reader.decode(record) {
+        Ok(row) => row,
+        Err(err) => {
+            return Some(Problem::Malformed {
+                reason: format!("{err:#}"),
+            });
+        }
+    };
+
+    let transaction_type = row.transaction_type.clone();
+    match TransactionEvent::try_from(row) {
+        Ok(
+            TransactionEvent::Deposit { amount, .. } | TransactionEvent::Withdrawal { amount, .. },
+        ) if amount.normalize().scale() > DECIMAL_PLACES => Some(Problem::Precision { amount }),
+        Ok(_) => None,
+        Err(CsvDecodeError::UnknownType(transaction_type)) => {
+            Some(Problem::UnknownType { transaction_type })
+        }
+        Err(CsvDecodeError::MissingAmount) => Some(Problem::MissingAmount { transaction_type }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::csv::CsvDialect;
+
+    fn validate_csv(input: &str) -> Validation {
+        validate(
+            CsvDialect::default()
+                .transaction_reader(input.as_bytes())
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_every_invalid_row() {
+        let validation = validate_csv(
+            "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,one,2,10\n\
+            refund,1,3,10\n\
+            withdrawal,1,4,\n\
+            deposit,1,5,1.23456\n\
+            deposit,1,6,1.23450\n\
+            deposit,1,7,1,extra\n\
+            dispute,1,1,\n",
+        );
+
+        assert_eq!(validation.rows, 8);
+        let lines: Vec<_> = validation.issues.iter().map(|issue| issue.line).collect();
+        assert_eq!(lines, [3, 4, 5, 6, 8]);
+        assert!(matches!(
+            validation.issues[0].problem,
+            Problem::Malformed { .. }
+        ));
+        assert_eq!(
+            validation.issues[1].problem,
+            Problem::UnknownType {
+                transaction_type: "refund".to_owned()
+            }
+        );
+        assert_eq!(
+            validation.issues[2].problem,
+            Problem::MissingAmount {
+                transaction_type: "withdrawal".to_owned()
+            }
+        );
+        assert_eq!(
+            validation.issues[3].problem,
+            Problem::Precision {
+                amount: dec!(1.23456)
+            }
+        );
+        assert_eq!(
+            validation.issues[3].to_string(),
+            "line 6: amount 1.23456 has more than 4 decimal places"
+        );
+    }
+
+    #[test]
+    fn accepts_valid_inputs() {
+        let validation = validate_csv("type,client,tx,amount\ndeposit,1,1,10.5\ndispute,1,1,\n");
+        assert!(validation.is_valid());
+        assert_eq!(validation.rows, 2);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{collections::BTreeMap, fmt};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    snapshot::{Snapshot, StateStore},
+    state_machine::TransactionStatus,
+    transaction::{ClientId, TransactionId},
+};
+
+/// An inconsistency between the stored client balances and the transaction journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// The stored balances don't add up to what the client's transactions imply
+    Balance {
+        client: ClientId,
+        expected_available: Decimal,
+        available: Decimal,
+        expected_held: Decimal,
+        held: Decimal,
+        /// The client's transactions the expected balances were computed from
+        transactions: Vec<TransactionId>,
+    },
+
+    /// A transaction references a client which isn't stored
+    UnknownClient { client: ClientId, tx: TransactionId },
+
+    /// A transaction was charged back, but its client isn't frozen
+    NotFrozen { client: ClientId, tx: TransactionId },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Balance {
+                client,
+                expected_available,
+                available,
+                expected_held,
+                held,
+                transactions,
+            } => write!(
+                f,
+                "client {client}: available is {available} (expected {expected_available}), held is {held} (expected {expected_held}), from transactions {transactions:?}"
+            ),
+            Self::UnknownClient { client, tx } => {
+                write!(
+                    f,
+                    "client {client}: transaction {tx} belongs to an unknown client"
+                )
+            }
+            Self::NotFrozen { client, tx } => write!(
+                f,
+                "client {client}: transaction {tx} was charged back but the account isn't frozen"
+            ),
+        }
+    }
+}
+
+/// Recomputes every client's available and held balances from the transaction journal and
+/// compares them to the stored ones.
+///
+/// Settled transactions count towards the available balance, disputed ones towards the
+/// held balance, and charged back ones towards neither. This only holds if the journal is
+/// complete: stores which compacted or archived transactions will report discrepancies.
+pub fn verify_snapshot(snapshot: &Snapshot) -> Vec<Discrepancy> {
+    #[derive(Default)]
+    struct Expected {
+        available: Decimal,
+        held: Decimal,
+        transactions: Vec<TransactionId>,
+    }
+
+    let clients: BTreeMap<_, _> = snapshot
+        .clients
+        .iter()
+        .map(|client| (client.id, client))
+        .collect();
+    let mut expected: BTreeMap<ClientId, Expected> = BTreeMap::new();
+    let mut discrepancies = Vec::new();
+
+    for transaction in &snapshot.transactions {
+        let Some(client) = clients.get(&transaction.client) else {
+            discrepancies.push(Discrepancy::UnknownClient {
+                client: transaction.client,
+                tx: transaction.tx,
+            });
+            continue;
+        };
+
+        let balances = expected.entry(transaction.client).or_default();
+        balances.transactions.push(transaction.tx);
+
+        match TransactionStatus::from_flags(transaction.disputed, transaction.charged_back) {
+            TransactionStatus::Settled => balances.available += transaction.amount,
+            TransactionStatus::Disputed => balances.held += transaction.amount,
+            TransactionStatus::ChargedBack if !client.frozen => {
+                discrepancies.push(Discrepancy::NotFrozen {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                });
+            }
+            TransactionStatus::ChargedBack => {}
+        }
+    }
+
+    for client in clients.values() {
+        let balances = expected.remove(&client.id).unwrap_or_default();
+        if balances.available != client.available || balances.held != client.held {
+            let mut transactions = balances.transactions;
+            transactions.sort_unstable();
+
+            discrepancies.push(Discrepancy::Balance {
+                client: client.id,
+                expected_available: balances.available,
+                available: client.available,
+                expected_held: balances.held,
+                held: client.held,
+                transactions,
+            });
+        }
+    }
+
+    discrepancies
+}
+
+/// Runs [`verify_snapshot`] over the current state of `store`.
+pub fn verify<S: StateStore + ?Sized>(store: &S) -> anyhow::Result<Vec<Discrepancy>> {
+    let mut snapshot = store.snapshot()?;
+    snapshot.normalize();
+
+    Ok(verify_snapshot(&snapshot))
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        memory_processor::InMemoryTransactionDb, snapshot::TransactionSnapshot,
+        transaction::TransactionProcessor,
+    };
+
+    #[test]
+    fn consistent_state() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.withdrawal(2, 1, dec!(3)).unwrap();
+        db.deposit(3, 1, dec!(5)).unwrap();
+        db.dispute(3, 1).unwrap();
+        db.deposit(4, 2, dec!(1)).unwrap();
+        db.dispute(4, 2).unwrap();
+        db.chargeback(4, 2).unwrap();
+
+        assert_eq!(verify(&db).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn reports_discrepancies() {
+        let mut db = InMemoryTransactionDb::new();
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.deposit(2, 1, dec!(5)).unwrap();
+
+        let mut snapshot = db.snapshot().unwrap();
+        snapshot.clients[0].available = dec!(14);
+        snapshot.transactions.push(TransactionSnapshot {
+            client: 9,
+            tx: 3,
+            amount: dec!(1),
+            disputed: false,
+            charged_back: false,
+            seq: 3,
+            disputes: 0,
+            recorded_at: 0,
+            created_at: None,
+        });
+
+        assert_eq!(
+            verify_snapshot(&snapshot),
+            vec![
+                Discrepancy::UnknownClient { client: 9, tx: 3 },
+                Discrepancy::Balance {
+                    client: 1,
+                    expected_available: dec!(15),
+                    available: dec!(14),
+                    expected_held: dec!(0),
+                    held: dec!(0),
+                    transactions: vec![1, 2],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Continuous processing of CSV files as they grow, eg a file other systems keep appending
+//! to, or a spool directory they drop hourly transaction files into.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    csv::{CsvDialect, apply_row},
+    drain::DrainSignal,
+    encryption::{self, EncryptionKey},
+    metrics::RunMetrics,
+    transaction::TransactionProcessor,
+};
+
+/// Most bytes read from a file at once, and so the longest a row may be
+pub const CHUNK: usize = 8 * 1024 * 1024;
+
+/// How far a [`Watcher`] got in the files it read.
+///
+/// Meant to be saved along the state the rows were applied to: restarting from both resumes
+/// right after the last applied row of every file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchProgress {
+    /// Offset of the byte after the last row applied (or rejected) per file
+    #[serde(default)]
+    pub offsets: BTreeMap<PathBuf, u64>,
+}
+
+impl WatchProgress {
+    /// Loads the progress at `path`, or an empty one if there isn't one yet.
+    pub fn load(path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = encryption::read_file(path, key)?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("failed to decode watch progress {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path, key: Option<&EncryptionKey>) -> anyhow::Result<()> {
+        encryption::write_file(path, &serde_json::to_vec(self)?, key)
+    }
+}
+
+/// Applies the rows of a CSV file as they're appended to it, or of every file of a
+/// directory, including files which show up later.
+///
+/// Files of a directory are read in the order of their names, so files named after the
+/// time they cover are applied in order, and hidden files (eg `.part` files being copied in,
+/// or on Windows files with the hidden attribute) are left out. Only complete lines are applied: a row is read once its newline is, so
+/// rows can't span several lines. Rows which don't decode are logged and skipped.
+///
+/// A file which shrank was replaced, eg rotated, and is read again from its start.
+pub struct Watcher {
+    path: PathBuf,
+    dialect: CsvDialect,
+    progress: WatchProgress,
+    /// Header line of the files being read, with its newline
+    headers: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Watcher {
+    /// Watches `path`, a file or a directory, starting from `progress`.
+    ///
+    /// ## Errors
+    /// - If `dialect` ends records with something other than newlines
+    pub fn new(path: &Path, dialect: CsvDialect, progress: WatchProgress) -> anyhow::Result<Self> {
+        if dialect
+            .terminator
+            .is_some_and(|terminator| terminator != b'\n')
+        {
+            bail!("only newline terminated records can be watched");
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            dialect,
+            progress,
+            headers: BTreeMap::new(),
+        })
+    }
+
+    pub fn progress(&self) -> &WatchProgress {
+        &self.progress
+    }
+
+    /// Starts over from `progress`, eg the one saved along the state being resumed
+    pub fn resume(&mut self, progress: WatchProgress) {
+        self.progress = progress;
+        self.headers.clear();
+    }
+
+    /// The files to read, by name
+    fn files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        if !self.path.is_dir() {
+            return Ok(vec![self.path.clone()]);
+        }
+
+        let mut files = Vec::new();
+        let entries = fs::read_dir(&self.path)
+            .with_context(|| format!("failed to list {}", self.path.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            if !is_hidden(&entry)? && entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    /// Applies the rows added to the watched files since the last poll, returns whether there
+    /// were any.
+    pub fn poll<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        metrics: &mut RunMetrics,
+    ) -> anyhow::Result<bool> {
+        let mut read_any = false;
+        for file in self.files()? {
+            // A file the previous pass listed may be gone since
+            if file.exists() {
+                read_any |= self
+                    .read_file(&file, db, metrics)
+                    .with_context(|| format!("failed to read {}", file.display()))?;
+            }
+        }
+
+        Ok(read_any)
+    }
+
+    /// Applies the rows of `path` after its offset, up to [`CHUNK`] bytes of them
+    fn read_file<DB: TransactionProcessor>(
+        &mut self,
+        path: &Path,
+        db: &mut DB,
+        metrics: &mut RunMetrics,
+    ) -> anyhow::Result<bool> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut offset = self.progress.offsets.get(path).copied().unwrap_or(0);
+        if len < offset {
+            warn!("{} shrank, reading it again from the start", path.display());
+            self.headers.remove(path);
+            offset = 0;
+        }
+        if len == offset {
+            return Ok(false);
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = Vec::new();
+        file.take(CHUNK as u64).read_to_end(&mut chunk)?;
+        let Some(end) = chunk.iter().rposition(|&byte| byte == b'\n') else {
+            if chunk.len() == CHUNK {
+                bail!("row at byte {offset} is longer than {CHUNK} bytes");
+            }
+            // The row is still being written
+            return Ok(false);
+        };
+        chunk.truncate(end + 1);
+
+        // Rows are decoded after the header, read again from the start of the file when
+        // resuming
+        let mut header = Vec::new();
+        if self.dialect.headers {
+            if offset == 0 {
+                let len = chunk.iter().position(|&byte| byte == b'\n').unwrap_or(end) + 1;
+                self.headers
+                    .insert(path.to_owned(), chunk.drain(..len).collect());
+                offset = len as u64;
+            } else if !self.headers.contains_key(path) {
+                let mut line = Vec::new();
+                BufReader::new(File::open(path)?).read_until(b'\n', &mut line)?;
+                self.headers.insert(path.to_owned(), line);
+            }
+            header = self.headers[path].clone();
+        }
+
+        let mut reader = self
+            .dialect
+            .transaction_reader(header.as_slice().chain(chunk.as_slice()))?;
+        let mut record = csv::ByteRecord::new();
+        // The positions of the reader are offsets in the header followed by the chunk
+        let base = offset - header.len() as u64;
+        loop {
+            let start = offset;
+            let applied = match reader.read_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => reader
+                    .decode(&record)
+                    .and_then(|row| apply_row(db, row, Instant::now(), metrics)),
+                Err(err) => Err(err),
+            };
+            offset = base + reader.position().byte();
+            if let Err(err) = applied {
+                error!(
+                    "Skipping the row of {} at byte {start}: {err:#}",
+                    path.display()
+                );
+            }
+        }
+        self.progress.offsets.insert(path.to_owned(), offset);
+
+        Ok(true)
+    }
+
+    /// Applies the rows of the watched files to `db` until `drain` is requested, waiting
+    /// `poll` when none had new rows.
+    pub fn run<DB: TransactionProcessor>(
+        &mut self,
+        db: &mut DB,
+        drain: &DrainSignal,
+        poll: Duration,
+    ) -> anyhow::Result<RunMetrics> {
+        let mut metrics = RunMetrics::new();
+
+        while !drain.is_draining() {
+            if !self.poll(db, &mut metrics)? {
+                thread::sleep(poll);
+            }
+        }
+        info!("Draining, the progress is where the last applied rows are");
+
+        Ok(metrics)
+    }
+}
+
+/// Whether the file of `entry` is hidden, one with the hidden or system attribute (eg a
+/// partial download, or `desktop.ini`). Dot files aren't hidden on Windows.
+#[cfg(windows)]
+fn is_hidden(entry: &fs::DirEntry) -> std::io::Result<bool> {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+    let attributes = entry.metadata()?.file_attributes();
+    Ok(attributes & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0)
+}
+
+/// Whether the file of `entry` is hidden, a dot file
+#[cfg(not(windows))]
+fn is_hidden(entry: &fs::DirEntry) -> std::io::Result<bool> {
+    Ok(entry.file_name().as_encoded_bytes().starts_with(b"."))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::memory_processor::InMemoryTransactionDb;
+
+    fn append(path: &Path, text: &str) {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn available(db: &InMemoryTransactionDb, client: u16) -> rust_decimal::Decimal {
+        db.client(client).unwrap().available
+    }
+
+    #[test]
+    fn tails_a_growing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.csv");
+        let mut watcher =
+            Watcher::new(&path, CsvDialect::default(), WatchProgress::default()).unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+
+        append(&path, "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,");
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(10));
+        // Only the complete rows were applied
+        assert_eq!(watcher.progress().offsets[&path], 37);
+        assert!(!watcher.poll(&mut db, &mut metrics).unwrap());
+
+        append(&path, "5\nbogus,1,3,1\nwithdrawal,1,4,3\n");
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(12));
+
+        // Resuming reads the header again, and skips what was applied
+        let progress = watcher.progress().clone();
+        append(&path, "deposit,1,5,1\n");
+        let mut watcher = Watcher::new(&path, CsvDialect::default(), progress).unwrap();
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(13));
+        assert_eq!(
+            watcher.progress().offsets[&path],
+            fs::metadata(&path).unwrap().len()
+        );
+    }
+
+    /// Dot files are only hidden outside of Windows
+    #[cfg(not(windows))]
+    #[test]
+    fn reads_the_files_of_a_directory_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher =
+            Watcher::new(dir.path(), CsvDialect::default(), WatchProgress::default()).unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+        assert!(!watcher.poll(&mut db, &mut metrics).unwrap());
+
+        append(
+            &dir.path().join("2024-01-01T01.csv"),
+            "type,client,tx,amount\nwithdrawal,1,2,4\n",
+        );
+        append(
+            &dir.path().join("2024-01-01T00.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10\n",
+        );
+        append(
+            &dir.path().join(".2024-01-01T02.csv.part"),
+            "type,client,tx,amount\ndeposit,1,3,100\n",
+        );
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(6));
+
+        fs::rename(
+            dir.path().join(".2024-01-01T02.csv.part"),
+            dir.path().join("2024-01-01T02.csv"),
+        )
+        .unwrap();
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(106));
+        assert_eq!(watcher.progress().offsets.len(), 3);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn leaves_out_hidden_and_system_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = "type,client,tx,amount\n";
+        append(
+            &dir.path().join("2024-01-01T00.csv"),
+            &format!("{header}deposit,1,1,10\n"),
+        );
+        // Not hidden on Windows
+        append(
+            &dir.path().join(".2024-01-01T01.csv"),
+            &format!("{header}deposit,1,2,1\n"),
+        );
+        for (tx, attribute) in [(3, "+h"), (4, "+s")] {
+            let path = dir.path().join(format!("2024-01-01T0{tx}.csv"));
+            append(&path, &format!("{header}deposit,1,{tx},100\n"));
+            let status = std::process::Command::new("attrib")
+                .arg(attribute)
+                .arg(&path)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let mut watcher =
+            Watcher::new(dir.path(), CsvDialect::default(), WatchProgress::default()).unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+        assert!(watcher.poll(&mut db, &mut metrics).unwrap());
+        assert_eq!(available(&db, 1), dec!(11));
+        assert_eq!(watcher.progress().offsets.len(), 2);
+    }
+
+    #[test]
+    fn rereads_replaced_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.csv");
+        let mut watcher =
+            Watcher::new(&path, CsvDialect::default(), WatchProgress::default()).unwrap();
+        let mut db = InMemoryTransactionDb::new();
+        let mut metrics = RunMetrics::new();
+
+        append(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,10\n",
+        );
+        watcher.poll(&mut db, &mut metrics).unwrap();
+        fs::write(&path, "type,client,tx,amount\ndeposit,1,3,1\n").unwrap();
+        watcher.poll(&mut db, &mut metrics).unwrap();
+        assert_eq!(available(&db, 1), dec!(21));
+
+        let dialect = CsvDialect {
+            terminator: Some(b';'),
+            ..CsvDialect::default()
+        };
+        assert!(Watcher::new(&path, dialect, WatchProgress::default()).is_err());
+    }
+}
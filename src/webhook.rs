@@ -0,0 +1,382 @@
+//! Webhook notifications of the events a risk team has to hear about right away: accounts
+//! frozen, disputes opened and chargebacks.
+//!
+//! A [`WebhookPublisher`] is a [`Publisher`] of a [`crate::publish::PublishingProcessor`]:
+//! it picks the [`ClientUpdate`]s of those events and POSTs them to an endpoint, as a
+//! [`Notification`].
+
+use std::{
+    num::NonZeroU32,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{
+    publish::{ClientUpdate, Publisher},
+    transaction::ClientId,
+};
+
+/// An event a webhook can be notified of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    AccountFrozen,
+    DisputeOpened,
+    Chargeback,
+}
+
+impl WebhookEvent {
+    pub const ALL: [Self; 3] = [Self::AccountFrozen, Self::DisputeOpened, Self::Chargeback];
+
+    /// The event `update` notifies of, if any
+    pub fn of(update: &ClientUpdate) -> Option<Self> {
+        match update {
+            ClientUpdate::AccountFrozen { .. } => Some(Self::AccountFrozen),
+            ClientUpdate::Balances { event_type, .. } => match event_type.as_str() {
+                "dispute" => Some(Self::DisputeOpened),
+                "chargeback" => Some(Self::Chargeback),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The body POSTed to a webhook: the event, and the update it was picked from
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification<'a> {
+    pub event: WebhookEvent,
+    #[serde(flatten)]
+    pub update: &'a ClientUpdate,
+}
+
+/// How often and how patiently a notification is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts at delivering a notification, including the first one
+    pub attempts: NonZeroU32,
+    /// Wait before the first retry, doubled after every failed retry
+    pub backoff: Duration,
+    /// Longest wait between two attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: NonZeroU32::new(5).unwrap(),
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after failed attempt `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Why a delivery failed
+#[derive(thiserror::Error, Debug)]
+pub enum DeliveryError {
+    /// The endpoint may take it later, eg it's unreachable or overloaded
+    #[error("{0}")]
+    Retryable(String),
+    /// The endpoint won't ever take it, eg it rejects the request as invalid
+    #[error("{0}")]
+    Permanent(String),
+}
+
+/// How notifications get to the endpoint
+pub trait WebhookTransport: Send + 'static {
+    fn post(&mut self, body: &[u8]) -> Result<(), DeliveryError>;
+}
+
+/// POSTs notifications as JSON over HTTP(S).
+pub struct HttpTransport {
+    agent: ureq::Agent,
+    url: String,
+}
+
+impl HttpTransport {
+    /// Gives up on an attempt after `timeout`
+    pub fn new(url: &str, timeout: Duration) -> Self {
+        let config = ureq::Agent::config_builder()
+            .timeout_global(Some(timeout))
+            .build();
+        Self {
+            agent: ureq::Agent::new_with_config(config),
+            url: url.to_owned(),
+        }
+    }
+}
+
+impl WebhookTransport for HttpTransport {
+    fn post(&mut self, body: &[u8]) -> Result<(), DeliveryError> {
+        let result = self
+            .agent
+            .post(&self.url)
+            .header("content-type", "application/json")
+            .send(body);
+        match result {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::StatusCode(status)) if status == 429 || status >= 500 => Err(
+                DeliveryError::Retryable(format!("{} answered {status}", self.url)),
+            ),
+            Err(ureq::Error::StatusCode(status)) => Err(DeliveryError::Permanent(format!(
+                "{} answered {status}",
+                self.url
+            ))),
+            Err(err) => Err(DeliveryError::Retryable(format!("{}: {err}", self.url))),
+        }
+    }
+}
+
+enum Job {
+    Deliver(Vec<u8>),
+    /// Answered once everything queued before was delivered or given up on
+    Flush(mpsc::Sender<()>),
+}
+
+/// Delivers the notifications of the [`WebhookEvent`]s it's configured for, in order, on a
+/// background thread so retries don't hold up processing.
+///
+/// A notification which still fails after the attempts of the [`RetryPolicy`], or which the
+/// endpoint rejects for good, is logged with its body and dropped: notifications are alerts,
+/// the updates themselves aren't lost. Dropping the publisher waits for the queued
+/// notifications to be delivered.
+pub struct WebhookPublisher {
+    events: Vec<WebhookEvent>,
+    jobs: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl WebhookPublisher {
+    pub fn new(
+        transport: impl WebhookTransport,
+        events: &[WebhookEvent],
+        policy: RetryPolicy,
+    ) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let worker = thread::spawn(move || deliver_all(transport, &queue, policy));
+
+        Self {
+            events: events.to_vec(),
+            jobs: Some(jobs),
+            worker: Some(worker),
+        }
+    }
+
+    fn send(&self, job: Job) -> anyhow::Result<()> {
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send(job).ok())
+            .context("the webhook delivery thread stopped")
+    }
+}
+
+impl Publisher for WebhookPublisher {
+    fn publish(&mut self, _: ClientId, payload: &[u8]) -> anyhow::Result<()> {
+        let update: ClientUpdate = serde_json::from_slice(payload)?;
+        let Some(event) = WebhookEvent::of(&update).filter(|event| self.events.contains(event))
+        else {
+            return Ok(());
+        };
+
+        let body = serde_json::to_vec(&Notification {
+            event,
+            update: &update,
+        })?;
+        self.send(Job::Deliver(body))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let (done, delivered) = mpsc::channel();
+        self.send(Job::Flush(done))?;
+        delivered
+            .recv()
+            .context("the webhook delivery thread stopped")
+    }
+}
+
+impl Drop for WebhookPublisher {
+    fn drop(&mut self) {
+        // Ends the delivery thread once it went through the queue
+        self.jobs.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn deliver_all(
+    mut transport: impl WebhookTransport,
+    queue: &mpsc::Receiver<Job>,
+    policy: RetryPolicy,
+) {
+    for job in queue {
+        match job {
+            Job::Deliver(body) => deliver(&mut transport, &body, policy),
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn deliver(transport: &mut impl WebhookTransport, body: &[u8], policy: RetryPolicy) {
+    let body_text = || String::from_utf8_lossy(body);
+    for attempt in 1..=policy.attempts.get() {
+        match transport.post(body) {
+            Ok(()) => return,
+            Err(DeliveryError::Retryable(err)) if attempt < policy.attempts.get() => {
+                let backoff = policy.backoff(attempt);
+                warn!("Failed to deliver a webhook notification, retrying in {backoff:?}: {err}");
+                thread::sleep(backoff);
+            }
+            Err(err) => {
+                error!(
+                    "Gave up on webhook notification {} after {attempt} attempts: {err}",
+                    body_text()
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{
+        memory_processor::InMemoryTransactionDb, publish::PublishingProcessor,
+        transaction::TransactionProcessor,
+    };
+
+    /// Fails the first `failures` attempts of every notification, for good if `permanent`
+    #[derive(Clone)]
+    struct FlakyTransport {
+        failures: u32,
+        permanent: bool,
+        attempts: Arc<Mutex<u32>>,
+        delivered: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    impl FlakyTransport {
+        fn new(failures: u32, permanent: bool) -> Self {
+            Self {
+                failures,
+                permanent,
+                attempts: Arc::default(),
+                delivered: Arc::default(),
+            }
+        }
+
+        fn delivered(&self) -> Vec<serde_json::Value> {
+            self.delivered.lock().unwrap().clone()
+        }
+    }
+
+    impl WebhookTransport for FlakyTransport {
+        fn post(&mut self, body: &[u8]) -> Result<(), DeliveryError> {
+            let mut attempts = self.attempts.lock().unwrap();
+            *attempts += 1;
+            if *attempts <= self.failures {
+                let err = "unavailable".to_owned();
+                return Err(match self.permanent {
+                    true => DeliveryError::Permanent(err),
+                    false => DeliveryError::Retryable(err),
+                });
+            }
+            *attempts = 0;
+
+            self.delivered
+                .lock()
+                .unwrap()
+                .push(serde_json::from_slice(body).unwrap());
+            Ok(())
+        }
+    }
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts: NonZeroU32::new(attempts).unwrap(),
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn notifies_of_the_configured_events() {
+        let transport = FlakyTransport::new(2, false);
+        let publisher = WebhookPublisher::new(
+            transport.clone(),
+            &[WebhookEvent::AccountFrozen, WebhookEvent::DisputeOpened],
+            policy(3),
+        );
+        let mut db = PublishingProcessor::new(InMemoryTransactionDb::new(), publisher);
+
+        db.deposit(1, 1, dec!(10)).unwrap();
+        db.dispute(1, 1).unwrap();
+        db.chargeback(1, 1).unwrap();
+        db.flush().unwrap();
+
+        let delivered = transport.delivered();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0]["event"], "dispute-opened");
+        assert_eq!(delivered[0]["held"], "10.0000");
+        assert_eq!(delivered[1]["event"], "account-frozen");
+        assert_eq!(delivered[1]["tx"], 1);
+    }
+
+    #[test]
+    fn gives_up() {
+        let retried = FlakyTransport::new(3, false);
+        let mut publisher = WebhookPublisher::new(retried.clone(), &WebhookEvent::ALL, policy(3));
+        let rejected = FlakyTransport::new(1, true);
+        let mut other = WebhookPublisher::new(rejected.clone(), &WebhookEvent::ALL, policy(3));
+
+        let update = serde_json::to_vec(&ClientUpdate::AccountFrozen {
+            seq: 3,
+            client: 1,
+            tx: 1,
+        })
+        .unwrap();
+        for publisher in [&mut publisher, &mut other] {
+            publisher.publish(1, &update).unwrap();
+            publisher.publish(1, &update).unwrap();
+            publisher.flush().unwrap();
+        }
+
+        // The first notification failed all its attempts, the second one went through
+        assert_eq!(retried.delivered().len(), 1);
+        // The first notification was rejected right away
+        assert_eq!(rejected.delivered().len(), 1);
+    }
+
+    #[test]
+    fn backs_off() {
+        let policy = RetryPolicy {
+            attempts: NonZeroU32::new(10).unwrap(),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{fmt, io::BufRead, time::Instant};
+
+use rust_decimal::Decimal;
+use serde::{
+    Deserialize, Deserializer,
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+};
+
+use crate::{
+    clock::Timestamp,
+    csv::{TransactionRow, apply_row, drained, write_clients},
+    drain::DrainSignal,
+    export::OutputWriter,
+    metrics::RunMetrics,
+    tenant::TenantId,
+    transaction::{ClientId, TransactionId, TransactionProcessor},
+};
+
+/// A `<tx>` element, with the CSV columns as attributes
+#[derive(Debug, Deserialize)]
+struct XmlTransaction {
+    #[serde(rename = "@type")]
+    transaction_type: String,
+    #[serde(rename = "@client")]
+    client: ClientId,
+    #[serde(rename = "@tx")]
+    tx: TransactionId,
+    #[serde(rename = "@amount", default)]
+    amount: Option<Decimal>,
+    #[serde(rename = "@tenant", default)]
+    tenant: Option<TenantId>,
+    #[serde(rename = "@timestamp", default)]
+    timestamp: Option<Timestamp>,
+    #[serde(rename = "@sequence", default)]
+    sequence: Option<u64>,
+}
+
+impl From<XmlTransaction> for TransactionRow {
+    fn from(transaction: XmlTransaction) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            tenant: transaction.tenant,
+            timestamp: transaction.timestamp,
+            sequence: transaction.sequence,
+            metadata: Default::default(),
+        }
+    }
+}
+
+/// Same as [`crate::csv::csv_processor`], but reads an XML document of `<tx>` elements
+/// carrying the CSV columns as attributes:
+///
+/// ```xml
+/// <transactions>
+///   <tx type="deposit" client="1" tx="1" amount="1.5"/>
+///   <tx type="dispute" client="1" tx="1"/>
+/// </transactions>
+/// ```
+///
+/// The name of the root element doesn't matter, and other elements in it are skipped.
+/// Transactions are applied as they're read, so the document is never fully in memory.
+pub fn xml_processor<R, O, DB>(reader: R, output: O, db: &mut DB) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    xml_processor_draining(reader, output, db, &DrainSignal::new())
+}
+
+/// Same as [`xml_processor`], but stops at the first transaction after `drain` was
+/// requested, leaving the rest of the document unread, and writes the clients as they are
+/// then.
+pub fn xml_processor_draining<R, O, DB>(
+    reader: R,
+    output: O,
+    db: &mut DB,
+    drain: &DrainSignal,
+) -> anyhow::Result<RunMetrics>
+where
+    R: BufRead,
+    O: OutputWriter,
+    DB: TransactionProcessor,
+{
+    let mut metrics = RunMetrics::new();
+    let mut deserializer = quick_xml::de::Deserializer::from_reader(reader);
+    let result = Document {
+        db: &mut *db,
+        metrics: &mut metrics,
+        drain,
+    }
+    .deserialize(&mut deserializer);
+    // Stopping early is an error for the deserializer, which expects the whole document
+    if metrics.drained_at.is_none() {
+        result?;
+    }
+
+    write_clients(output, db)?;
+
+    Ok(metrics)
+}
+
+/// Visits the root element, applying the `<tx>` elements as they're deserialized.
+struct Document<'a, DB> {
+    db: &'a mut DB,
+    metrics: &'a mut RunMetrics,
+    drain: &'a DrainSignal,
+}
+
+impl<'de, DB: TransactionProcessor> DeserializeSeed<'de> for Document<'_, DB> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, DB: TransactionProcessor> Visitor<'de> for Document<'_, DB> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an element of <tx> elements")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut applied = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != "tx" {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+
+            applied = map.next_value_seed(Transactions {
+                db: &mut *self.db,
+                metrics: &mut *self.metrics,
+                drain: self.drain,
+                applied,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Visits a run of `<tx>` elements, returning how many transactions were read in total.
+struct Transactions<'a, DB> {
+    db: &'a mut DB,
+    metrics: &'a mut RunMetrics,
+    drain: &'a DrainSignal,
+    /// Transactions read before this run
+    applied: u64,
+}
+
+impl<'de, DB: TransactionProcessor> DeserializeSeed<'de> for Transactions<'_, DB> {
+    type Value = u64;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<u64, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, DB: TransactionProcessor> Visitor<'de> for Transactions<'_, DB> {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("<tx> elements")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<u64, A::Error> {
+        let mut number = self.applied;
+        loop {
+            if drained(self.drain, self.metrics) {
+                return Err(de::Error::custom("drained"));
+            }
+            let started = Instant::now();
+            let Some(transaction) = seq.next_element::<XmlTransaction>().map_err(|err| {
+                de::Error::custom(format_args!("invalid transaction {}: {err}", number + 1))
+            })?
+            else {
+                return Ok(number);
+            };
+            number += 1;
+
+            apply_row(self.db, transaction.into(), started, self.metrics)
+                .map_err(|err| de::Error::custom(format_args!("transaction {number}: {err:#}")))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::dec;
+
+    use super::*;
+    use crate::{csv::ClientRow, memory_processor::InMemoryTransactionDb};
+
+    fn process(input: &str) -> anyhow::Result<Vec<ClientRow>> {
+        let mut output = Vec::new();
+        let mut db = InMemoryTransactionDb::new();
+        xml_processor(
+            input.as_bytes(),
+            csv::Writer::from_writer(&mut output),
+            &mut db,
+        )?;
+
+        let mut clients: Vec<ClientRow> = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+        clients.sort_by_key(|client| client.client);
+        Ok(clients)
+    }
+
+    #[test]
+    fn transactions() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<transactions>
+  <!-- exported nightly -->
+  <tx type="deposit" client="1" tx="1" amount="10.5"/>
+  <tx type="deposit" client="2" tx="2" amount="3" timestamp="1700000000"></tx>
+  <batch id="7"/>
+  <tx type="withdrawal" client="1" tx="3" amount="0.5"/>
+  <tx type="dispute" client="2" tx="2"/>
+</transactions>
+"#;
+
+        assert_eq!(
+            process(input).unwrap(),
+            vec![
+                ClientRow {
+                    client: 1,
+                    available: dec!(10),
+                    held: dec!(0),
+                    total: dec!(10),
+                    locked: false,
+                },
+                ClientRow {
+                    client: 2,
+                    available: dec!(0),
+                    held: dec!(3),
+                    total: dec!(3),
+                    locked: false,
+                },
+            ]
+        );
+        assert_eq!(process("<transactions/>").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn errors() {
+        let input = r#"<transactions>
+  <tx type="deposit" client="1" tx="1" amount="1"/>
+  <tx type="deposit" client="x" tx="2" amount="1"/>
+</transactions>"#;
+        let err = process(input).unwrap_err().to_string();
+        assert!(err.starts_with("invalid transaction 2:"), "{err}");
+
+        let input = r#"<transactions><tx type="refund" client="1" tx="1"/></transactions>"#;
+        let err = process(input).unwrap_err().to_string();
+        assert!(err.starts_with("transaction 1:"), "{err}");
+    }
+
+    #[test]
+    fn stops_when_drained() {
+        let input = r#"<transactions>
+  <tx type="deposit" client="x" tx="1" amount="1"/>
+</transactions>"#;
+        let drain = DrainSignal::new();
+        drain.drain();
+
+        let mut db = InMemoryTransactionDb::new();
+        let metrics = xml_processor_draining(
+            input.as_bytes(),
+            csv::Writer::from_writer(Vec::new()),
+            &mut db,
+            &drain,
+        )
+        .unwrap();
+        assert_eq!(metrics.drained_at, Some(0));
+    }
+}
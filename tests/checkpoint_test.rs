@@ -0,0 +1,231 @@
+use std::{error::Error, io::Cursor, num::NonZeroU64};
+
+use csv::ReaderBuilder;
+use octopussy::{
+    checkpoint::{Checkpoint, CheckpointConfig, InputFingerprint},
+    csv::{csv_processor, csv_processor_checkpointed, csv_processor_checkpointed_seeking},
+    drain::DrainSignal,
+    encryption::{self, EncryptionKey},
+    memory_processor::InMemoryTransactionDb,
+};
+
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,2,2,
+deposit,1,4,1.0
+resolve,2,2,
+withdrawal,2,5,1.0
+";
+
+fn reader(input: &str) -> csv::Reader<&[u8]> {
+    ReaderBuilder::default()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes())
+}
+
+fn run(input: &str, config: Option<&CheckpointConfig>) -> Result<String, Box<dyn Error>> {
+    let mut output = Vec::new();
+    {
+        let writer = csv::WriterBuilder::default().from_writer(&mut output);
+        let mut db = InMemoryTransactionDb::new();
+        match config {
+            Some(config) => csv_processor_checkpointed(reader(input), writer, &mut db, config)?,
+            None => csv_processor(reader(input), writer, &mut db)?,
+        };
+    }
+
+    let mut lines: Vec<_> = String::from_utf8(output)?
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    Ok(lines.join("\n"))
+}
+
+#[test]
+fn resume_from_checkpoint() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(2).unwrap(),
+        key: None,
+        drain: None,
+        input: None,
+    };
+
+    // Simulate a run that died after the first four rows
+    let partial: String = INPUT.lines().take(5).map(|l| format!("{l}\n")).collect();
+    run(&partial, Some(&config))?;
+
+    let checkpoint = Checkpoint::load(&config.path, None)?.unwrap();
+    assert_eq!(checkpoint.records, 4);
+    assert_eq!(checkpoint.byte_offset, partial.len() as u64);
+
+    // Resuming over the full input skips the applied rows instead of rejecting them as
+    // duplicates, and ends up in the same state as a single uninterrupted run
+    assert_eq!(run(INPUT, Some(&config))?, run(INPUT, None)?);
+    assert_eq!(Checkpoint::load(&config.path, None)?.unwrap().records, 7);
+
+    Ok(())
+}
+
+#[test]
+fn skipped_rows_are_not_parsed() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(100).unwrap(),
+        key: None,
+        drain: None,
+        input: None,
+    };
+
+    run("type,client,tx,amount\ndeposit,1,1,10.0\n", Some(&config))?;
+
+    // The first row would fail to decode if it was processed again
+    let output = run(
+        "type,client,tx,amount\nbogus,1,1,\ndeposit,1,2,1.0\n",
+        Some(&config),
+    )?;
+    assert_eq!(
+        output,
+        "1,11.0000,0.0000,11.0000,false\nclient,available,held,total,locked"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn encrypted_checkpoint() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let key = EncryptionKey::from_bytes([7; 32]);
+    let config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(2).unwrap(),
+        key: Some(key.clone()),
+        drain: None,
+        input: None,
+    };
+
+    let partial: String = INPUT.lines().take(5).map(|l| format!("{l}\n")).collect();
+    run(&partial, Some(&config))?;
+    assert!(encryption::is_encrypted(&std::fs::read(&config.path)?));
+    assert!(Checkpoint::load(&config.path, None).is_err());
+
+    assert_eq!(run(INPUT, Some(&config))?, run(INPUT, None)?);
+    assert_eq!(
+        Checkpoint::load(&config.path, Some(&key))?.unwrap().records,
+        7
+    );
+
+    Ok(())
+}
+
+#[test]
+fn drain_and_resume() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let drain = DrainSignal::new();
+    let mut config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(100).unwrap(),
+        key: None,
+        drain: Some(drain.clone()),
+        input: None,
+    };
+
+    // Drained before the run started, it stops right after the first row
+    drain.drain();
+    let mut output = Vec::new();
+    let metrics = csv_processor_checkpointed(
+        reader(INPUT),
+        csv::Writer::from_writer(&mut output),
+        &mut InMemoryTransactionDb::new(),
+        &config,
+    )?;
+    assert_eq!(metrics.drained_at, Some(1));
+    assert!(output.is_empty());
+    assert_eq!(Checkpoint::load(&config.path, None)?.unwrap().records, 1);
+
+    config.drain = None;
+    assert_eq!(run(INPUT, Some(&config))?, run(INPUT, None)?);
+
+    Ok(())
+}
+
+#[test]
+fn seek_to_checkpoint() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(2).unwrap(),
+        key: None,
+        drain: None,
+        input: None,
+    };
+    let seeking = |input: &str| -> Result<String, Box<dyn Error>> {
+        let mut output = Vec::new();
+        let reader = ReaderBuilder::default()
+            .has_headers(true)
+            .from_reader(Cursor::new(input.as_bytes().to_vec()));
+        csv_processor_checkpointed_seeking(
+            reader,
+            csv::Writer::from_writer(&mut output),
+            &mut InMemoryTransactionDb::new(),
+            &config,
+        )?;
+        Ok(String::from_utf8(output)?)
+    };
+
+    let partial: String = INPUT.lines().take(3).map(|l| format!("{l}\n")).collect();
+    seeking(&partial)?;
+    let checkpoint = Checkpoint::load(&config.path, None)?.unwrap();
+    assert_eq!((checkpoint.records, checkpoint.line), (2, 4));
+
+    // The two rows covered by the checkpoint are now a single line of the same length: it
+    // isn't read at all, where reading through it would skip the row after it too
+    let header_len = "type,client,tx,amount\n".len();
+    let covered = partial.len() - header_len;
+    let input = format!(
+        "{}{}\n{}",
+        &INPUT[..header_len],
+        "x".repeat(covered - 1),
+        &INPUT[partial.len()..]
+    );
+    let mut lines: Vec<_> = seeking(&input)?.lines().map(String::from).collect();
+    lines.sort();
+    assert_eq!(lines.join("\n"), run(INPUT, None)?);
+    assert_eq!(Checkpoint::load(&config.path, None)?.unwrap().records, 7);
+
+    Ok(())
+}
+
+#[test]
+fn err_checkpoint_of_another_input() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("input.csv");
+    let partial: String = INPUT.lines().take(5).map(|l| format!("{l}\n")).collect();
+    std::fs::write(&path, &partial)?;
+    let mut config = CheckpointConfig {
+        path: dir.path().join("checkpoint.json"),
+        every: NonZeroU64::new(2).unwrap(),
+        key: None,
+        drain: None,
+        input: Some(InputFingerprint::of(&path)?),
+    };
+    run(&partial, Some(&config))?;
+    assert_eq!(
+        Checkpoint::load(&config.path, None)?.unwrap().input,
+        config.input
+    );
+
+    // Resumed on the same file, but with other rows in it since
+    std::fs::write(&path, INPUT)?;
+    config.input = Some(InputFingerprint::of(&path)?);
+    let err = run(INPUT, Some(&config)).unwrap_err();
+    assert!(err.to_string().starts_with("can't resume from"));
+
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use std::error::Error;
+
+use octopussy::{
+    csv::{ClientRow, CsvDialect, DecimalFormat, HeaderError, csv_processor},
+    memory_processor::InMemoryTransactionDb,
+};
+use rust_decimal::dec;
+
+fn process(input: &str, dialect: CsvDialect) -> Result<Vec<ClientRow>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    csv_processor(
+        dialect.transaction_reader(input.as_bytes())?,
+        csv::Writer::from_writer(&mut output),
+        &mut InMemoryTransactionDb::new(),
+    )?;
+
+    let mut clients = csv::Reader::from_reader(output.as_slice())
+        .deserialize()
+        .collect::<Result<Vec<ClientRow>, _>>()?;
+    clients.sort_by_key(|client| client.client);
+    Ok(clients)
+}
+
+fn client(client: u16, available: rust_decimal::Decimal) -> ClientRow {
+    ClientRow {
+        client,
+        available,
+        held: dec!(0),
+        total: available,
+        locked: false,
+    }
+}
+
+#[test]
+fn tab_separated() -> Result<(), Box<dyn Error>> {
+    let input = "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\ndeposit\t2\t2\t2\n";
+    let dialect = CsvDialect {
+        delimiter: b'\t',
+        ..CsvDialect::default()
+    };
+
+    assert_eq!(
+        process(input, dialect)?,
+        vec![client(1, dec!(1.5)), client(2, dec!(2))]
+    );
+    Ok(())
+}
+
+#[test]
+fn semicolon_separated() -> Result<(), Box<dyn Error>> {
+    let input =
+        "\"type\";\"client\";\"tx\";\"amount\"\r\n\"deposit\";1;1;3\r\nwithdrawal;1;2;1.25\r\n";
+    let dialect = CsvDialect {
+        delimiter: b';',
+        ..CsvDialect::default()
+    };
+
+    assert_eq!(process(input, dialect)?, vec![client(1, dec!(1.75))]);
+    Ok(())
+}
+
+#[test]
+fn quotes_and_terminator() -> Result<(), Box<dyn Error>> {
+    // Quotes are part of the fields, and records end with `|` rather than newlines
+    let input = "type,client,tx,amount|deposit,1,1,4|\"deposit,1,2,5|";
+    let dialect = CsvDialect {
+        quote: None,
+        terminator: Some(b'|'),
+        ..CsvDialect::default()
+    };
+
+    let err = process(input, dialect).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("unknown transaction event type \"deposit")
+    );
+
+    let dialect = CsvDialect {
+        terminator: Some(b'|'),
+        ..CsvDialect::default()
+    };
+    assert!(process("type,client,tx,amount|deposit,1,1,4|", dialect).is_ok());
+    Ok(())
+}
+
+#[test]
+fn headerless() -> Result<(), Box<dyn Error>> {
+    let input = "deposit,1,1,10\ndeposit,2,2,3\nwithdrawal,1,3,4\ndispute,2,2\ndispute,1,1,\n";
+    let dialect = CsvDialect {
+        headers: false,
+        ..CsvDialect::default()
+    };
+
+    assert_eq!(
+        process(input, dialect)?,
+        vec![
+            ClientRow {
+                client: 1,
+                available: dec!(-4),
+                held: dec!(10),
+                total: dec!(6),
+                locked: false,
+            },
+            ClientRow {
+                client: 2,
+                available: dec!(0),
+                held: dec!(3),
+                total: dec!(3),
+                locked: false,
+            },
+        ]
+    );
+
+    // A header row is just an invalid transaction then
+    assert!(process("type,client,tx,amount\ndeposit,1,1,10\n", dialect).is_err());
+    Ok(())
+}
+
+fn header_error(header: &str) -> Option<HeaderError> {
+    let dialect = CsvDialect {
+        strict_headers: true,
+        ..CsvDialect::default()
+    };
+    let input = format!("{header}\ndeposit,1,1,1\n");
+
+    dialect
+        .checked_reader(input.as_bytes())
+        .err()
+        .map(|err| err.downcast().unwrap())
+}
+
+#[test]
+fn strict_headers() {
+    assert_eq!(header_error("type,client,tx,amount"), None);
+    assert_eq!(header_error("type, client, tx, amount, timestamp"), None);
+    assert_eq!(header_error("type,client,tx,amount,tenant,timestamp"), None);
+
+    assert_eq!(
+        header_error("type,client,tx,amount,memo"),
+        Some(HeaderError::Unknown(vec!["memo".to_owned()]))
+    );
+    assert_eq!(
+        header_error("type,client,transaction"),
+        Some(HeaderError::Unknown(vec!["transaction".to_owned()]))
+    );
+    assert_eq!(
+        header_error("type,client,tx"),
+        Some(HeaderError::Missing(vec!["amount"]))
+    );
+    assert_eq!(
+        header_error("type,client,tx,amount,client"),
+        Some(HeaderError::Duplicate(vec!["client".to_owned()]))
+    );
+
+    let err = header_error("client,type,tx,amount").unwrap();
+    assert_eq!(
+        err.to_string(),
+        "columns out of order in the header: found client,type,tx,amount, expected type,client,tx,amount"
+    );
+}
+
+#[test]
+fn lenient_headers() -> Result<(), Box<dyn Error>> {
+    // Extra columns are ignored and columns are read by name without `strict_headers`
+    let input = "client,memo,type,tx,amount\n1,hi,deposit,1,2\n";
+    assert_eq!(
+        process(input, CsvDialect::default())?,
+        vec![client(1, dec!(2))]
+    );
+    Ok(())
+}
+
+const EUROPEAN: DecimalFormat = DecimalFormat {
+    decimal_separator: b',',
+    thousands_separator: Some(b'.'),
+};
+
+#[test]
+fn decimal_format() {
+    assert_eq!(EUROPEAN.normalize(b"1.234,56").as_ref(), b"1234.56");
+    assert_eq!(EUROPEAN.normalize(b"-0,5").as_ref(), b"-0.5");
+
+    let swiss = DecimalFormat {
+        decimal_separator: b'.',
+        thousands_separator: Some(b'\''),
+    };
+    assert_eq!(swiss.normalize(b"1'000'000.25").as_ref(), b"1000000.25");
+
+    // A decimal point isn't taken as the decimal separator when it's a comma
+    let comma = DecimalFormat {
+        decimal_separator: b',',
+        thousands_separator: None,
+    };
+    assert!(
+        String::from_utf8(comma.normalize(b"1.5").into_owned())
+            .unwrap()
+            .parse::<rust_decimal::Decimal>()
+            .is_err()
+    );
+}
+
+#[test]
+fn european_amounts() -> Result<(), Box<dyn Error>> {
+    let input = "type;client;tx;amount\ndeposit;1;1;1.234,56\nwithdrawal;1;2;0,5\ndispute;1;1;\n";
+    let dialect = CsvDialect {
+        delimiter: b';',
+        decimal: EUROPEAN,
+        ..CsvDialect::default()
+    };
+
+    assert_eq!(
+        process(input, dialect)?,
+        vec![ClientRow {
+            client: 1,
+            available: dec!(-0.5),
+            held: dec!(1234.56),
+            total: dec!(1234.06),
+            locked: false,
+        }]
+    );
+
+    // Quoted, with the default delimiter, and without a header
+    let input = "deposit,2,1,\"2.000,5\"\n";
+    let dialect = CsvDialect {
+        headers: false,
+        decimal: EUROPEAN,
+        ..CsvDialect::default()
+    };
+    assert_eq!(process(input, dialect)?, vec![client(2, dec!(2000.5))]);
+    Ok(())
+}
+
+#[test]
+fn same_separators() {
+    let dialect = CsvDialect {
+        decimal: DecimalFormat {
+            decimal_separator: b',',
+            thousands_separator: Some(b','),
+        },
+        ..CsvDialect::default()
+    };
+    assert!(dialect.transaction_reader("".as_bytes()).is_err());
+}
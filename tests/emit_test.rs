@@ -0,0 +1,99 @@
+use std::{error::Error, num::NonZeroU64, time::Duration};
+
+use csv::ReaderBuilder;
+use octopussy::{
+    csv::{EmitPolicy, csv_processor_draining, csv_processor_emitting},
+    drain::DrainSignal,
+    memory_processor::InMemoryTransactionDb,
+};
+
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,2,2,
+deposit,1,4,1.0
+";
+
+fn run(policy: EmitPolicy) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut output = Vec::new();
+    {
+        let reader = ReaderBuilder::default()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(INPUT.as_bytes());
+        let writer = csv::WriterBuilder::default().from_writer(&mut output);
+        let mut db = InMemoryTransactionDb::new();
+        csv_processor_emitting(reader, writer, &mut db, policy)?;
+    }
+
+    let mut lines: Vec<_> = String::from_utf8(output)?
+        .lines()
+        .skip(1)
+        .map(String::from)
+        .collect();
+    // Both clients are written every time, in no particular order
+    for emission in lines.chunks_mut(2) {
+        emission.sort();
+    }
+    Ok(lines)
+}
+
+#[test]
+fn emits_every_few_rows() -> Result<(), Box<dyn Error>> {
+    let lines = run(EmitPolicy {
+        every: NonZeroU64::new(2),
+        interval: None,
+    })?;
+
+    assert_eq!(
+        lines,
+        [
+            // After 2 rows
+            "1,10.0000,0.0000,10.0000,false",
+            "2,5.0000,0.0000,5.0000,false",
+            // After 4 rows
+            "1,7.5000,0.0000,7.5000,false",
+            "2,0.0000,5.0000,5.0000,false",
+            // At the end
+            "1,8.5000,0.0000,8.5000,false",
+            "2,0.0000,5.0000,5.0000,false",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn emits_only_at_the_end_by_default() -> Result<(), Box<dyn Error>> {
+    assert_eq!(run(EmitPolicy::default())?.len(), 2);
+    // Every row comes in well within the interval
+    let lines = run(EmitPolicy {
+        every: None,
+        interval: Some(Duration::from_secs(3600)),
+    })?;
+    assert_eq!(lines.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn writes_the_clients_when_drained() -> Result<(), Box<dyn Error>> {
+    let drain = DrainSignal::new();
+    drain.drain();
+
+    let mut output = Vec::new();
+    let reader = ReaderBuilder::default()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(INPUT.as_bytes());
+    let writer = csv::WriterBuilder::default().from_writer(&mut output);
+    let mut db = InMemoryTransactionDb::new();
+    let metrics = csv_processor_draining(reader, writer, &mut db, EmitPolicy::default(), &drain)?;
+
+    // Stopped right after the first row
+    assert_eq!(metrics.drained_at, Some(1));
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+
+use octopussy::{
+    csv::{ClientRow, csv_processor},
+    handoff::{HandoffError, check_sequence, first_csv_sequence},
+    memory_processor::InMemoryTransactionDb,
+    snapshot::{SnapshotFile, StateStore},
+};
+use rust_decimal::dec;
+
+const MONDAY: &str = "type,client,tx,amount,sequence
+deposit,1,1,10,1
+deposit,2,2,5,2
+withdrawal,1,3,2.5,3
+";
+
+const TUESDAY: &str = "type,client,tx,amount,sequence
+dispute,2,2,,4
+deposit,1,4,1,5
+";
+
+const WEDNESDAY: &str = "type,client,tx,amount,sequence
+chargeback,2,2,,6
+";
+
+/// Runs a day of the chain from the state saved by the previous one, as `--state-in` and
+/// `--state-out` do.
+fn run_day(input: &str, state: &mut SnapshotFile) -> Result<Vec<ClientRow>, Box<dyn Error>> {
+    let mut previous = state.snapshot()?;
+    check_sequence(
+        previous.cursor,
+        first_csv_sequence(csv::Reader::from_reader(input.as_bytes()))?,
+    )?;
+    let cursor = previous.cursor.take();
+
+    let mut db = InMemoryTransactionDb::new();
+    db.restore(previous)?;
+
+    let mut output = Vec::new();
+    let metrics = csv_processor(
+        csv::Reader::from_reader(input.as_bytes()),
+        csv::Writer::from_writer(&mut output),
+        &mut db,
+    )?;
+
+    let mut snapshot = db.snapshot()?;
+    snapshot.cursor = metrics.last_sequence.or(cursor);
+    state.restore(snapshot)?;
+
+    let mut clients = csv::Reader::from_reader(output.as_slice())
+        .deserialize()
+        .collect::<Result<Vec<ClientRow>, _>>()?;
+    clients.sort_by_key(|client| client.client);
+    Ok(clients)
+}
+
+#[test]
+fn nightly_chain() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut state = SnapshotFile::new(dir.path().join("state.json"));
+
+    run_day(MONDAY, &mut state)?;
+    assert_eq!(state.snapshot()?.cursor, Some(3));
+
+    // Tuesday disputes a deposit of Monday, which only works with Monday's history
+    let clients = run_day(TUESDAY, &mut state)?;
+    assert_eq!(clients[1].held, dec!(5));
+    assert_eq!(clients[0].available, dec!(8.5));
+    assert_eq!(state.snapshot()?.cursor, Some(5));
+
+    // Replaying Tuesday is caught before anything is applied
+    let err = run_day(TUESDAY, &mut state).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<HandoffError>(),
+        Some(&HandoffError::Gap {
+            previous: 5,
+            found: 4
+        })
+    );
+    assert_eq!(state.snapshot()?.cursor, Some(5));
+
+    let clients = run_day(WEDNESDAY, &mut state)?;
+    assert_eq!(
+        clients[1],
+        ClientRow {
+            client: 2,
+            available: dec!(0),
+            held: dec!(0),
+            total: dec!(0),
+            locked: true,
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn skipped_input() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let mut state = SnapshotFile::new(dir.path().join("state.json"));
+
+    run_day(MONDAY, &mut state)?;
+    let err = run_day(WEDNESDAY, &mut state).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the input starts at sequence 6, but the previous run ended at 3, expected 4"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use octopussy::{
+    csv::{CsvDialect, csv_processor},
+    delta_stream::DeltaStreamProcessor,
+    memory_processor::InMemoryTransactionDb,
+    replication::{ChangeRecord, ChangeStreamProcessor},
+    screening::{
+        FileScreener, ScreeningAction, ScreeningPolicy, ScreeningProcessor, ScreeningRecord,
+    },
+    transaction::Metadata,
+};
+
+const INPUT: &str = "type,client,tx,amount,reference,memo
+deposit,1,1,10,INV-1,first
+deposit,2,2,5,INV-2,
+dispute,1,1,,CASE-9,customer called
+";
+
+fn lines<T: serde::de::DeserializeOwned>(output: &[u8]) -> Vec<T> {
+    serde_json::Deserializer::from_slice(output)
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+/// Runs [`INPUT`] through the decorators in the order of the CLI, returns the change
+/// stream and the screening audit trail.
+fn process(metadata: bool) -> (Vec<ChangeRecord>, Vec<ScreeningRecord>) {
+    let screener =
+        FileScreener::from_reader(csv::Reader::from_reader("client,entry\n".as_bytes())).unwrap();
+    let policy = ScreeningPolicy {
+        large_transaction: None,
+        on_match: ScreeningAction::Freeze,
+    };
+    let db = ScreeningProcessor::new(InMemoryTransactionDb::new(), screener, policy, Vec::new());
+    let mut db = DeltaStreamProcessor::new(ChangeStreamProcessor::new(db, Vec::new()), Vec::new());
+
+    let dialect = CsvDialect {
+        metadata,
+        ..CsvDialect::default()
+    };
+    csv_processor(
+        dialect.transaction_reader(INPUT.as_bytes()).unwrap(),
+        csv::Writer::from_writer(Vec::new()),
+        &mut db,
+    )
+    .unwrap();
+
+    let (db, _) = db.into_inner();
+    let (db, changes) = db.into_inner();
+    let (_, audit) = db.into_inner();
+    (lines(&changes), lines(&audit))
+}
+
+fn metadata(pairs: &[(&str, &str)]) -> Metadata {
+    pairs
+        .iter()
+        .map(|(column, value)| (column.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn kept_in_change_stream_and_audit_trail() {
+    let (changes, audit) = process(true);
+
+    assert_eq!(
+        changes
+            .iter()
+            .map(|record| record.metadata.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            metadata(&[("memo", "first"), ("reference", "INV-1")]),
+            // Empty values are left out
+            metadata(&[("reference", "INV-2")]),
+            metadata(&[("memo", "customer called"), ("reference", "CASE-9")]),
+        ]
+    );
+    assert_eq!(
+        audit
+            .iter()
+            .map(|record| record.metadata.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            metadata(&[("memo", "first"), ("reference", "INV-1")]),
+            metadata(&[("reference", "INV-2")]),
+        ]
+    );
+}
+
+#[test]
+fn ignored_by_default() {
+    let (changes, audit) = process(false);
+
+    assert_eq!(changes.len(), 3);
+    assert!(changes.iter().all(|record| record.metadata.is_empty()));
+    assert!(audit.iter().all(|record| record.metadata.is_empty()));
+
+    // And not written out at all
+    let json = serde_json::to_string(&changes[0]).unwrap();
+    assert!(!json.contains("metadata"));
+}
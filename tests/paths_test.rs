@@ -0,0 +1,97 @@
+use std::{error::Error, fs, path::Path};
+
+use octopussy::{
+    backend::{BackendSpec, BackendSpecError},
+    encryption,
+    memory_processor::InMemoryTransactionDb,
+    snapshot::{SnapshotFile, StateStore},
+    snapshot_codec::SnapshotFormat,
+    transaction::TransactionProcessor,
+};
+use rust_decimal::dec;
+
+#[test]
+fn replaces_existing_files() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("state.json");
+
+    encryption::write_file(&path, b"first", None)?;
+    encryption::write_file(&path, b"second", None)?;
+
+    assert_eq!(fs::read(&path)?, b"second");
+    assert!(!encryption::tmp_path(&path).exists());
+    Ok(())
+}
+
+#[test]
+fn paths_with_spaces_and_unicode() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let nested = dir.path().join("Mis documentos").join("año 2024");
+    fs::create_dir_all(&nested)?;
+    let path = nested.join("état final.json");
+
+    let mut db = InMemoryTransactionDb::new();
+    db.deposit(1, 1, dec!(10))?;
+    let spec: BackendSpec = format!("memory:{}", path.display()).parse()?;
+    assert_eq!(spec.location(), path);
+
+    let mut store = spec.open(None, SnapshotFormat::Json)?;
+    store.restore(db.snapshot()?)?;
+    assert_eq!(SnapshotFile::new(&path).snapshot()?, db.snapshot()?);
+    Ok(())
+}
+
+#[test]
+fn windows_backend_specs() {
+    let spec: BackendSpec = r"memory:C:\Users\analyst\state.json".parse().unwrap();
+    assert_eq!(spec.location(), Path::new(r"C:\Users\analyst\state.json"));
+    assert_eq!(spec.to_string(), r"memory:C:\Users\analyst\state.json");
+
+    // The kind is missing, the drive letter isn't one
+    assert_eq!(
+        r"C:\Users\analyst\state.json".parse::<BackendSpec>(),
+        Err(BackendSpecError::Malformed(
+            r"C:\Users\analyst\state.json".to_owned()
+        ))
+    );
+}
+
+/// Windows refuses to replace a file another process has open, as virus scanners and the
+/// search indexer do for a moment after every write
+#[cfg(windows)]
+#[test]
+fn replaces_files_open_elsewhere() -> Result<(), Box<dyn Error>> {
+    use std::{thread, time::Duration};
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("state.json");
+    encryption::write_file(&path, b"first", None)?;
+
+    let reader = fs::File::open(&path)?;
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(reader);
+    });
+
+    encryption::write_file(&path, b"second", None)?;
+    release.join().unwrap();
+
+    assert_eq!(fs::read(&path)?, b"second");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn replaces_files_open_elsewhere() -> Result<(), Box<dyn Error>> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("state.json");
+    encryption::write_file(&path, b"first", None)?;
+
+    // Readers keep seeing the file they opened
+    let reader = fs::File::open(&path)?;
+    encryption::write_file(&path, b"second", None)?;
+
+    assert_eq!(std::io::read_to_string(reader)?, "first");
+    assert_eq!(fs::read(&path)?, b"second");
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::error::Error;
+
+use csv::ReaderBuilder;
+use octopussy::{
+    csv::{csv_processor, csv_processor_multi_tenant, write_usage_report},
+    memory_processor::InMemoryTransactionDb,
+    tenant::TenantDb,
+};
+
+const INPUT: &str = "type,client,tx,amount,tenant
+deposit,1,1,10.0,
+deposit,1,1,20.0,2
+deposit,1,1,30.0,1
+dispute,1,1,,2
+withdrawal,1,2,5.0,
+";
+
+fn reader(input: &str) -> csv::Reader<&[u8]> {
+    ReaderBuilder::default()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes())
+}
+
+#[test]
+fn ledgers_per_tenant() -> Result<(), Box<dyn Error>> {
+    let mut output = Vec::new();
+    {
+        let writer = csv::WriterBuilder::default().from_writer(&mut output);
+        let mut db = TenantDb::<InMemoryTransactionDb>::new();
+        csv_processor_multi_tenant(reader(INPUT), writer, &mut db)?;
+    }
+
+    assert_eq!(
+        String::from_utf8(output)?,
+        "tenant,client,available,held,total,locked
+,1,5.0000,0.0000,5.0000,false
+1,1,30.0000,0.0000,30.0000,false
+2,1,0.0000,20.0000,20.0000,false
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn single_ledger_rejects_tenant_rows() -> Result<(), Box<dyn Error>> {
+    let mut output = Vec::new();
+    {
+        let writer = csv::WriterBuilder::default().from_writer(&mut output);
+        let mut db = InMemoryTransactionDb::new();
+        csv_processor(reader(INPUT), writer, &mut db)?;
+    }
+
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn usage_report() -> Result<(), Box<dyn Error>> {
+    let mut db = TenantDb::<InMemoryTransactionDb>::new();
+    csv_processor_multi_tenant(
+        reader(INPUT),
+        csv::Writer::from_writer(std::io::sink()),
+        &mut db,
+    )?;
+
+    let mut output = Vec::new();
+    write_usage_report(csv::Writer::from_writer(&mut output), &db)?;
+
+    let bytes = |tenant| db.usage(tenant).storage_bytes.unwrap();
+    assert_eq!(
+        String::from_utf8(output)?,
+        format!(
+            "tenant,events,rejected,transactions,clients,api_calls,storage_bytes
+,2,0,2,1,0,{}
+1,1,0,1,1,0,{}
+2,2,0,1,1,0,{}
+",
+            bytes(None),
+            bytes(Some(1)),
+            bytes(Some(2))
+        )
+    );
+    assert!(bytes(None) > bytes(Some(1)));
+
+    Ok(())
+}